@@ -0,0 +1,87 @@
+//! Compare the receive throughput of a plain `recv_from` loop against [`Receiver`].
+//!
+//! Run with `cargo bench -p bitcrawler-core --features recvmmsg` to benchmark the batched path,
+//! without the feature both sides use `recv_from`.
+//!
+//! Each round first fills the socket buffer, then measures how long it takes to drain it, so that
+//! the sender speed does not bias the result.
+
+use std::{
+    io,
+    net::{Ipv4Addr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bitcrawler_core::transport::{
+    DEFAULT_BATCH_SIZE, DEFAULT_DATAGRAM_SIZE, Receiver, SocketConfig, bind_socket,
+};
+
+const ROUNDS: usize = 500;
+const DATAGRAMS_PER_ROUND: usize = 200;
+/// Typical size of a find_node response with 8 nodes.
+const PAYLOAD_SIZE: usize = 260;
+
+fn bench<F>(name: &str, mut receive: F)
+where
+    F: FnMut(&UdpSocket) -> io::Result<usize>,
+{
+    let (socket, _) = bind_socket(&SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into())).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(20)))
+        .unwrap();
+    let destination = socket.local_addr().unwrap();
+    let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let payload = [0x42u8; PAYLOAD_SIZE];
+
+    let mut received = 0;
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        for _ in 0..DATAGRAMS_PER_ROUND {
+            sender.send_to(&payload, destination).unwrap();
+        }
+        let start = Instant::now();
+        let mut last = start;
+        loop {
+            match receive(&socket) {
+                Ok(count) => {
+                    received += count;
+                    last = Instant::now();
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(e) => panic!("receive failed: {}", e),
+            }
+        }
+        // Do not account for the final timeout.
+        elapsed += last - start;
+    }
+
+    println!(
+        "{:<24} {:>8} datagrams in {:>8.3?} ({:>10.0} datagrams/s)",
+        name,
+        received,
+        elapsed,
+        received as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let mut buffer = vec![0u8; DEFAULT_DATAGRAM_SIZE];
+    bench("recv_from", |socket| {
+        socket.recv_from(&mut buffer).map(|_| 1)
+    });
+
+    let mut receiver = Receiver::new(DEFAULT_BATCH_SIZE);
+    let name = if Receiver::is_batched() {
+        "Receiver (recvmmsg)"
+    } else {
+        "Receiver (recv_from)"
+    };
+    bench(name, |socket| receiver.receive(socket, |_, _| {}));
+}
@@ -0,0 +1,293 @@
+//! Fetch the metadata of a torrent from a magnet link, and write it as a `.torrent` file.
+//!
+//! The peers of the info hash are found with a `get_peers` lookup, then asked for the metadata
+//! over the peer wire protocol (BEP 3) with the extension protocol (BEP 10) and its
+//! `ut_metadata` extension (BEP 9). The metadata is checked against the info hash before it is
+//! parsed and written to `<info hash>.torrent`.
+//!
+//! ```sh
+//! cargo run -p bitcrawler-core --example fetch_magnet -- 'magnet:?xt=urn:btih:...'
+//! ```
+//!
+//! The lookup starts from the default bootstrap nodes, or from the `host:port` nodes given
+//! after the magnet link. The lookup and the fetch each have a deadline: the lookup stops at its
+//! deadline (see `LookupOptions::deadline`), and the peers are tried until the deadline of the
+//! fetch, which bounds every connection and read.
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use bitcrawler_core::{
+    crawler::DEFAULT_BOOTSTRAP_NODES,
+    keyspace::TargetGenerator,
+    metainfo::Metainfo,
+    node::{DhtNode, LookupOptions, NodeConfig, lookup_peers},
+    proto::{
+        bencode::{self, BencodeValue},
+        kademlia::{Id160, InfoHash},
+    },
+    transport::SocketConfig,
+};
+
+/// Number of peers to find before trying them.
+const MAX_PEERS: usize = 30;
+/// Time the lookup of the peers may take.
+const LOOKUP_DEADLINE: Duration = Duration::from_secs(30);
+/// Time the fetch of the metadata may take, over all the peers tried.
+const FETCH_DEADLINE: Duration = Duration::from_secs(60);
+/// Time a peer may take to accept the connection, or to send a message.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of a metadata piece (BEP 9).
+const PIECE_SIZE: usize = 16 * 1024;
+/// Largest metadata accepted.
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+/// Largest peer wire message accepted.
+const MAX_MESSAGE_SIZE: usize = PIECE_SIZE + 1024;
+/// Id of the peer wire messages of the extension protocol.
+const EXTENDED: u8 = 20;
+/// Id of the extension handshake among the extended messages.
+const EXTENDED_HANDSHAKE: u8 = 0;
+/// Id we give to `ut_metadata`: the peer sends its `ut_metadata` messages with it.
+const UT_METADATA: u8 = 1;
+
+/// Get the info hash of a magnet link: its `xt=urn:btih:` parameter, in hex or base32.
+fn parse_magnet(uri: &str) -> Option<Id160> {
+    let query = uri.strip_prefix("magnet:?")?;
+    query
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("xt=urn:btih:"))?
+        .parse()
+        .ok()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Get the integer of `key` in a bencoded dictionary.
+fn dict_integer(value: &BencodeValue, key: &[u8]) -> Option<i128> {
+    value.get(key)?.as_integer()
+}
+
+/// Read a length-prefixed peer wire message, empty for a keep-alive.
+fn read_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(invalid_data("message too long"));
+    }
+    let mut message = vec![0; length];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Send an extended message (BEP 10).
+fn send_extended(stream: &mut TcpStream, id: u8, payload: &BencodeValue) -> io::Result<()> {
+    let payload = bencode::encode(payload);
+    let mut message = Vec::with_capacity(6 + payload.len());
+    message.extend_from_slice(&(2 + payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(&[EXTENDED, id]);
+    message.extend_from_slice(&payload);
+    stream.write_all(&message)
+}
+
+/// Get the time left until `deadline`, at most `PEER_TIMEOUT`, or a `TimedOut` error once it
+/// passed.
+fn time_left(deadline: Instant) -> io::Result<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline reached"));
+    }
+    Ok(left.min(PEER_TIMEOUT))
+}
+
+/// Ask `peer` for the metadata of `info_hash`, giving up at `deadline`.
+fn fetch_metadata(
+    peer: SocketAddr,
+    info_hash: &Id160,
+    peer_id: &Id160,
+    deadline: Instant,
+) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&peer, time_left(deadline)?)?;
+    stream.set_write_timeout(Some(time_left(deadline)?))?;
+    stream.set_read_timeout(Some(time_left(deadline)?))?;
+
+    // Handshake (BEP 3), announcing the extension protocol (BEP 10).
+    let mut handshake = Vec::with_capacity(68);
+    handshake.push(19);
+    handshake.extend_from_slice(b"BitTorrent protocol");
+    handshake.extend_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0]);
+    handshake.extend_from_slice(info_hash.as_bytes());
+    handshake.extend_from_slice(peer_id.as_bytes());
+    stream.write_all(&handshake)?;
+    let mut reply = [0; 68];
+    stream.read_exact(&mut reply)?;
+    if reply[..20] != handshake[..20] || reply[28..48] != info_hash.as_bytes()[..] {
+        return Err(invalid_data("unexpected handshake"));
+    }
+    if reply[25] & 0x10 == 0 {
+        return Err(invalid_data("no extension protocol"));
+    }
+    let extensions = BencodeValue::from_dict(vec![(
+        "m",
+        BencodeValue::from_dict(vec![(
+            "ut_metadata",
+            BencodeValue::from_integer(UT_METADATA),
+        )]),
+    )]);
+    send_extended(&mut stream, EXTENDED_HANDSHAKE, &extensions)?;
+
+    let mut metadata = Vec::new();
+    // Pieces of the metadata received so far.
+    let mut received = Vec::new();
+    loop {
+        stream.set_read_timeout(Some(time_left(deadline)?))?;
+        let message = read_message(&mut stream)?;
+        // Other messages (bitfield, have...) are ignored.
+        if message.len() < 2 || message[0] != EXTENDED {
+            continue;
+        }
+        let payload = &message[2..];
+        let (length, header) =
+            bencode::decode_prefix(&payload).map_err(|e| invalid_data(e.message()))?;
+        match message[1] {
+            EXTENDED_HANDSHAKE => {
+                let remote_id = header
+                    .get(b"m")
+                    .and_then(|m| dict_integer(m, b"ut_metadata"))
+                    .and_then(|id| u8::try_from(id).ok())
+                    .filter(|&id| id != 0)
+                    .ok_or_else(|| invalid_data("no ut_metadata extension"))?;
+                let size = dict_integer(&header, b"metadata_size")
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+                    .ok_or_else(|| invalid_data("invalid metadata size"))?;
+                metadata = vec![0; size];
+                received = vec![false; size.div_ceil(PIECE_SIZE)];
+                for piece in 0..received.len() {
+                    let request = BencodeValue::from_dict(vec![
+                        ("msg_type", BencodeValue::from_integer(0)),
+                        ("piece", BencodeValue::from_integer(piece as u64)),
+                    ]);
+                    send_extended(&mut stream, remote_id, &request)?;
+                }
+            }
+            UT_METADATA if !metadata.is_empty() => {
+                match dict_integer(&header, b"msg_type") {
+                    Some(1) => {}
+                    Some(2) => return Err(invalid_data("metadata request rejected")),
+                    _ => continue,
+                }
+                let piece = dict_integer(&header, b"piece")
+                    .and_then(|piece| usize::try_from(piece).ok())
+                    .filter(|&piece| piece < received.len())
+                    .ok_or_else(|| invalid_data("invalid metadata piece"))?;
+                let start = piece * PIECE_SIZE;
+                let data = &payload[length..];
+                let end = (start + PIECE_SIZE).min(metadata.len());
+                if data.len() != end - start {
+                    return Err(invalid_data("invalid metadata piece length"));
+                }
+                metadata[start..end].copy_from_slice(data);
+                received[piece] = true;
+                if received.iter().all(|&received| received) {
+                    return Ok(metadata);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let uri = args.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: fetch_magnet <magnet URI> [host:port...]",
+        )
+    })?;
+    let mut bootstrap_nodes: Vec<String> = args.collect();
+    if bootstrap_nodes.is_empty() {
+        bootstrap_nodes = DEFAULT_BOOTSTRAP_NODES
+            .iter()
+            .map(|node| node.to_string())
+            .collect();
+    }
+    let info_hash = parse_magnet(&uri)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid magnet URI"))?;
+    let id = TargetGenerator::new().random_id();
+    let mut config = NodeConfig::new(id);
+    config.socket = SocketConfig::new(([0, 0, 0, 0], 0).into());
+    let mut node = DhtNode::bind(config)?;
+
+    let contacts: Vec<SocketAddr> = bootstrap_nodes
+        .iter()
+        .filter_map(|node| node.to_socket_addrs().ok())
+        .flatten()
+        .collect();
+    let options = LookupOptions {
+        max_peers: Some(MAX_PEERS),
+        deadline: Some(LOOKUP_DEADLINE),
+        hedge_after: Some(Duration::from_millis(500)),
+        ..LookupOptions::default()
+    };
+    let lookup = lookup_peers(&mut node, info_hash, &contacts, &options)?;
+    println!(
+        "{} peers found ({} of {} queries answered, {:?})",
+        lookup.peers.len(),
+        lookup.answered,
+        lookup.queried,
+        lookup.end
+    );
+
+    let deadline = Instant::now() + FETCH_DEADLINE;
+    for peer in lookup.peers {
+        let info = match fetch_metadata(peer, &info_hash, &id, deadline) {
+            Ok(info) => info,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && Instant::now() >= deadline => {
+                println!("{}: {}", peer, e);
+                break;
+            }
+            Err(e) => {
+                println!("{}: {}", peer, e);
+                continue;
+            }
+        };
+        // The info hash is the SHA-1 of the `info` dictionary as received.
+        let metainfo = match Metainfo::verify(&info, &InfoHash::from(info_hash)) {
+            Ok(metainfo) => metainfo,
+            Err(e) => {
+                println!("{}: invalid metadata ({})", peer, e);
+                continue;
+            }
+        };
+        // The torrent file is written with the metadata as received: re-encoding it would change
+        // the info hash if it is not in canonical form.
+        if !metainfo.canonical {
+            println!("{}: metadata not in canonical form, kept as received", peer);
+        }
+        let path = format!("{}.torrent", info_hash);
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend_from_slice(&info);
+        torrent.push(b'e');
+        fs::write(&path, torrent)?;
+        println!(
+            "{:?} ({} files, {} bytes) written to {}",
+            metainfo.display_name(),
+            metainfo.logical_files().count(),
+            metainfo.content_length(),
+            path
+        );
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no peer sent the metadata",
+    ))
+}
@@ -0,0 +1,237 @@
+//! A minimal DHT node answering the queries of other nodes.
+//!
+//! It joins the DHT through the bootstrap nodes, keeps the nodes it hears from in a routing
+//! table, and answers `ping`, `find_node`, `get_peers` and `announce_peer` queries (BEP 5):
+//! `get_peers` replies carry a token, checked when the node announces, and unknown methods or
+//! bad tokens get an error reply. The `get_peers` replies are kept small enough not to be
+//! fragmented, with a random subset of the peers when they do not all fit. The nodes at
+//! private or reserved addresses are only returned to nodes of the same local network.
+//!
+//! The announced peers are kept in a file given as second argument, if any: read on start and
+//! written every few seconds, so that a restarted responder still returns them.
+//!
+//! ```sh
+//! cargo run -p bitcrawler-core --example responder -- 0.0.0.0:6881 peers.txt
+//! ```
+
+use std::{
+    env, io,
+    net::{SocketAddr, SocketAddrV4, ToSocketAddrs},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bitcrawler_core::{
+    crawler::DEFAULT_BOOTSTRAP_NODES,
+    keyspace::TargetGenerator,
+    node::{DhtNode, DhtResponse, NodeConfig, NodeEvent, is_bogon, reply_nodes},
+    proto::{
+        bencode::{self, BencodeValue},
+        kademlia::{Id160, Node, RoutingTable},
+        krpc::{
+            ErrorCode, ErrorMessage, Query, QueryType, ResponseType,
+            node_info::BittorrentNodeInfoV4,
+        },
+    },
+    responder::{DEFAULT_MAX_REPLY_SIZE, PeerStore, PeerStoreConfig, ReplyShaper, Tokens},
+    transport::SocketConfig,
+};
+
+/// Number of nodes returned by `find_node` and `get_peers` replies.
+const NODES_PER_REPLY: usize = 8;
+
+struct Responder {
+    node: DhtNode,
+    id: Id160,
+    table: RoutingTable<SocketAddr, Id160>,
+    tokens: Tokens,
+    shaper: ReplyShaper,
+    peers: PeerStore,
+}
+
+impl Responder {
+    fn handle_event(&mut self, event: NodeEvent) -> io::Result<()> {
+        match event {
+            NodeEvent::Query { source, query } => self.answer(source, query),
+            NodeEvent::Response {
+                query, response, ..
+            } => {
+                // Walk toward our own id, to fill the buckets close to it: follow the nodes
+                // closer to it than the sender.
+                if let ResponseType::FindNode(find_node) = response.get_response_type() {
+                    let sender = *find_node.get_id();
+                    self.learn(sender, query.destination);
+                    let nodes = find_node.get_nodes();
+                    for (id, address) in reply_nodes(nodes, self.id, sender, query.destination) {
+                        let closer = id.distance(&self.id) < sender.distance(&self.id);
+                        if closer && self.table.get(&id).is_none() {
+                            let _ = self.node.find_node(address, self.id);
+                        }
+                    }
+                }
+                Ok(())
+            }
+            NodeEvent::Error { .. } | NodeEvent::Timeout { .. } | NodeEvent::Duplicate { .. } => {
+                Ok(())
+            }
+        }
+    }
+
+    fn answer(&mut self, source: SocketAddr, query: Query<Id160>) -> io::Result<()> {
+        let tid = query.get_transaction_id().clone();
+        let now = Instant::now();
+        let reply = match query.get_query() {
+            QueryType::Ping(ping) => {
+                self.learn(*ping.get_id(), source);
+                DhtResponse::new_ping(tid, self.id).to_bencoded()
+            }
+            QueryType::FindNode(find_node) => {
+                self.learn(*find_node.get_id(), source);
+                let nodes = self.closest(find_node.get_target(), source);
+                DhtResponse::new_find_node(tid, self.id, nodes).to_bencoded()
+            }
+            QueryType::GetPeers(get_peers) => {
+                self.learn(*get_peers.get_id(), source);
+                let info_hash = get_peers.get_info_hash();
+                let token = self.tokens.issue(source.ip(), now);
+                // Peers if any are known, the closest nodes otherwise or if they do not fit.
+                let nodes = self.closest(info_hash, source);
+                let peers = self.peers.peers(info_hash, SystemTime::now());
+                self.shaper
+                    .get_peers(tid, self.id, Some(token.as_bytes().into()), nodes, &peers)
+                    .to_bencoded()
+            }
+            QueryType::AnnouncePeer(announce) => {
+                if !self
+                    .tokens
+                    .verify(source.ip(), announce.get_token().as_ref(), now)
+                {
+                    ErrorMessage::new(tid, ErrorCode::ProtocolError, "Bad token".into())
+                        .to_bencoded()
+                } else {
+                    self.learn(*announce.get_id(), source);
+                    if let SocketAddr::V4(source) = source
+                        && let Some(port) = announce.peer_port(source.port())
+                    {
+                        let peer = SocketAddrV4::new(*source.ip(), port.get());
+                        self.peers
+                            .announce(*announce.get_info_hash(), peer, SystemTime::now());
+                    }
+                    DhtResponse::new_ping(tid, self.id).to_bencoded()
+                }
+            }
+            QueryType::Unknown { .. } => {
+                ErrorMessage::new(tid, ErrorCode::MethodUnknown, "Method Unknown".into())
+                    .to_bencoded()
+            }
+        };
+        self.reply(source, &reply);
+        Ok(())
+    }
+
+    fn reply(&mut self, destination: SocketAddr, reply: &BencodeValue) {
+        // A reply refused by the traffic limits is simply not sent.
+        let _ = self.node.send_to(&bencode::encode(reply), destination);
+    }
+
+    /// Record a node that just talked to us.
+    fn learn(&mut self, id: Id160, address: SocketAddr) {
+        let now = Instant::now();
+        if !self.table.mark_seen(&id, now) {
+            let mut node = Node::new(id, vec![address]);
+            node.mark_seen(now);
+            self.table.insert_at(node, now);
+        }
+    }
+
+    /// Get the IPv4 nodes of the routing table closest to `target`, to return to `requester`.
+    /// Bogon addresses are only returned to a requester on one too.
+    fn closest(&self, target: &Id160, requester: SocketAddr) -> Vec<BittorrentNodeInfoV4<Id160>> {
+        let local_requester = is_bogon(requester.ip());
+        let mut nodes: Vec<BittorrentNodeInfoV4<Id160>> = self
+            .table
+            .buckets()
+            .iter()
+            .flat_map(|bucket| bucket.iter())
+            .filter(|node| {
+                local_requester
+                    || node
+                        .addresses()
+                        .iter()
+                        .all(|address| !is_bogon(address.ip()))
+            })
+            .filter_map(|node| match node.addresses().first()? {
+                SocketAddr::V4(address) => Some(BittorrentNodeInfoV4 {
+                    node_id: *node.id(),
+                    ip: address.ip().octets(),
+                    port: address.port(),
+                }),
+                SocketAddr::V6(_) => None,
+            })
+            .collect();
+        nodes.sort_by_key(|node| node.node_id.distance(target));
+        nodes.truncate(NODES_PER_REPLY);
+        nodes
+    }
+}
+
+fn main() -> io::Result<()> {
+    let bind: SocketAddr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "0.0.0.0:6881".to_string())
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid bind address"))?;
+    let peers_path = env::args().nth(2).map(PathBuf::from);
+    let peers = match &peers_path {
+        Some(path) => PeerStore::read(path, PeerStoreConfig::default(), SystemTime::now())?,
+        None => PeerStore::default(),
+    };
+    let id = TargetGenerator::new().random_id();
+    let mut config = NodeConfig::new(id);
+    config.socket = SocketConfig::new(bind);
+    let seed = config.seed;
+    let mut responder = Responder {
+        node: DhtNode::bind(config)?,
+        id,
+        table: RoutingTable::new(id),
+        tokens: Tokens::new(Instant::now()),
+        shaper: ReplyShaper::new(DEFAULT_MAX_REPLY_SIZE, seed),
+        peers,
+    };
+    println!("Answering on {}", responder.node.local_addr()?);
+    if !responder.peers.is_empty() {
+        println!(
+            "{} peers of {} info hashes read",
+            responder.peers.len(),
+            responder.peers.info_hashes()
+        );
+    }
+
+    for address in DEFAULT_BOOTSTRAP_NODES
+        .iter()
+        .filter_map(|node| node.to_socket_addrs().ok())
+        .flatten()
+    {
+        let _ = responder.node.find_node(address, id);
+    }
+    let mut events = Vec::new();
+    let mut last_report = Instant::now();
+    loop {
+        responder.node.poll(&mut events)?;
+        for event in events.drain(..) {
+            responder.handle_event(event)?;
+        }
+        if last_report.elapsed() >= Duration::from_secs(10) {
+            last_report = Instant::now();
+            responder.peers.expire(SystemTime::now());
+            if let Some(path) = &peers_path {
+                responder.peers.write(path)?;
+            }
+            println!(
+                "{} nodes in the routing table, peers of {} info hashes",
+                responder.table.len(),
+                responder.peers.info_hashes()
+            );
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! A minimal HTTP server to control a running crawl, see [`AdminServer`].
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::kademlia::InfoHash;
+use serde_json::{Value, json};
+
+use crate::crawler::CrawlerHandle;
+
+/// Largest request head (request line and headers) read.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// Time a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval between two checks for new connections and for the end of the crawl.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Serves a small HTTP/1.1 API to control a running crawl through its [`CrawlerHandle`]:
+///
+/// | Request | Effect |
+/// | --- | --- |
+/// | `GET /snapshot` | [`CrawlSnapshot`](crate::crawler::CrawlSnapshot) of the crawl |
+/// | `GET /routing-table` | the [routing table](CrawlerHandle::routing_table) of the crawler |
+/// | `POST /pause`, `POST /resume` | [pauses](CrawlerHandle::pause) or resumes the crawl |
+/// | `POST /lookup?target=<info hash>` | [looks up](CrawlerHandle::lookup) the info hash (hex or base32) |
+///
+/// The replies are JSON. There is no authentication: bind the server to a loopback or private
+/// address.
+pub struct AdminServer {
+    listener: TcpListener,
+    handle: CrawlerHandle,
+}
+
+impl AdminServer {
+    /// Listen on `address` for the requests on the crawl of `handle`.
+    pub fn bind(address: SocketAddr, handle: CrawlerHandle) -> io::Result<AdminServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(AdminServer { listener, handle })
+    }
+
+    /// Get the address the server listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Run the server on a new thread, named `bitcrawler-admin`.
+    pub fn spawn(self) -> JoinHandle<io::Result<()>> {
+        thread::Builder::new()
+            .name("bitcrawler-admin".to_string())
+            .spawn(move || self.serve())
+            .expect("failed to spawn the admin thread")
+    }
+
+    /// Serve the requests one at a time, until the crawl stops.
+    ///
+    /// A client failing (e.g. too slow to send its request) is dropped, only errors of the
+    /// listener are returned.
+    pub fn serve(&self) -> io::Result<()> {
+        while self.handle.is_running() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = self.answer(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let (status, body) = match read_request_line(&mut stream)? {
+            Some(line) => {
+                let mut parts = line.split(' ');
+                match (parts.next(), parts.next()) {
+                    (Some(method), Some(target)) => self.route(method, target),
+                    _ => error(400, "malformed request"),
+                }
+            }
+            None => error(400, "malformed request"),
+        };
+        let body = body.to_string();
+        let reason = match status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            reason,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+
+    /// Handle a request, returns the status code and the body of the reply.
+    fn route(&self, method: &str, target: &str) -> (u16, Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let expected = match path {
+            "/snapshot" | "/routing-table" => "GET",
+            "/pause" | "/resume" | "/lookup" => "POST",
+            _ => return error(404, "unknown endpoint"),
+        };
+        if method != expected {
+            return error(405, "method not allowed");
+        }
+        match path {
+            "/snapshot" => match serde_json::to_value(self.handle.snapshot()) {
+                Ok(snapshot) => (200, snapshot),
+                Err(e) => error(500, &e.to_string()),
+            },
+            "/routing-table" => {
+                let now = Instant::now();
+                let nodes: Vec<Value> = self
+                    .handle
+                    .routing_table()
+                    .iter()
+                    .map(|entry| {
+                        json!({
+                            "bucket": entry.bucket,
+                            "id": entry.id.to_string(),
+                            "address": entry.address.to_string(),
+                            "last_seen_secs_ago": entry
+                                .last_seen
+                                .map(|seen| now.saturating_duration_since(seen).as_secs()),
+                            "rtt_ms": entry.rtt.map(|rtt| rtt.as_millis() as u64),
+                        })
+                    })
+                    .collect();
+                (200, Value::Array(nodes))
+            }
+            "/pause" => {
+                self.handle.pause();
+                (200, json!({ "paused": true }))
+            }
+            "/resume" => {
+                self.handle.resume();
+                (200, json!({ "paused": false }))
+            }
+            _ => {
+                let target = query
+                    .split('&')
+                    .find_map(|parameter| parameter.strip_prefix("target="))
+                    .and_then(|target| target.parse::<InfoHash>().ok());
+                match target {
+                    Some(target) => {
+                        self.handle.lookup(target);
+                        (202, json!({ "target": target.to_string() }))
+                    }
+                    None => error(400, "expected a target info hash, in hexadecimal or base32"),
+                }
+            }
+        }
+    }
+}
+
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (status, json!({ "error": message }))
+}
+
+/// Read the head of a request, returns its first line if it is well-formed.
+fn read_request_line(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.lines().next().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcrawler_proto::kademlia::Id160;
+
+    use super::*;
+    use crate::{
+        crawler::{Crawler, CrawlerConfig},
+        testutil::FakeNode,
+        transport::SocketConfig,
+    };
+
+    fn request(address: SocketAddr, request: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} HTTP/1.1\r\nHost: localhost\r\n\r\n", request).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_admin_server() {
+        let silent = FakeNode::bind(Id160([2; 20])).unwrap();
+        let mut config = CrawlerConfig::new(Id160([0; 20]));
+        config.node.socket = SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        config.node.poll_timeout = Duration::from_millis(10);
+        config.bootstrap_nodes = vec![silent.address().to_string()];
+        let (handle, crawler) = Crawler::bind(config).unwrap().spawn();
+        let server = AdminServer::bind((Ipv4Addr::LOCALHOST, 0).into(), handle.clone()).unwrap();
+        let address = server.local_addr().unwrap();
+        let server = server.spawn();
+
+        assert_eq!(
+            request(address, "POST /pause"),
+            (200, json!({ "paused": true }))
+        );
+        assert!(handle.is_paused());
+        let (status, snapshot) = request(address, "GET /snapshot");
+        assert_eq!(status, 200);
+        assert_eq!(snapshot["paused"], json!(true));
+        assert_eq!(request(address, "POST /resume").0, 200);
+        assert!(!handle.is_paused());
+        assert_eq!(request(address, "GET /routing-table"), (200, json!([])));
+
+        let target = "00".repeat(19) + "ff";
+        assert_eq!(
+            request(address, &format!("POST /lookup?target={}", target)),
+            (202, json!({ "target": target }))
+        );
+        assert_eq!(request(address, "POST /lookup?target=zz").0, 400);
+        assert_eq!(request(address, "GET /lookup").0, 405);
+        assert_eq!(request(address, "GET /nothing").0, 404);
+
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+use std::f64::consts::LN_2;
+
+/// Magic bytes prefixed to a serialized `BloomFilter`.
+const BLOOM_MAGIC: &[u8; 4] = b"BCBF";
+/// Version of the serialized `BloomFilter` layout.
+const BLOOM_VERSION: u8 = 1;
+/// Size of the serialized header (magic, version, hash count, bit count, item count).
+const BLOOM_HEADER_LEN: usize = 4 + 1 + 4 + 8 + 8;
+/// Upper bound on the number of hash functions, enough for a false-positive rate of 1e-19.
+const MAX_NUM_HASHES: u32 = 64;
+
+/// A classic Bloom filter over byte strings.
+///
+/// The filter answers "definitely not seen" or "probably seen": `contains` never returns
+/// `false` for an inserted item, but may return `true` for an item that was never inserted.
+/// The probability of such a false positive is controlled at construction time through
+/// [`BloomFilter::with_rate`].
+///
+/// Hashing is stable across builds and platforms (FNV-1a mixed with a SplitMix64 finalizer),
+/// so a serialized filter can be reloaded by a later run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    count: u64,
+    // Number of bits set.
+    ones: u64,
+}
+
+impl BloomFilter {
+    /// Create a new filter sized for `expected_items` with the given target false-positive rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `expected_items` - The number of items the filter is expected to hold.
+    /// * `false_positive_rate` - The target false-positive probability, in `(0, 1)`.
+    ///
+    /// Fails if `false_positive_rate` is not strictly between 0 and 1.
+    pub fn with_rate(
+        expected_items: usize,
+        false_positive_rate: f64,
+    ) -> Result<BloomFilter, &'static str> {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err("Invalid bloom filter false positive rate");
+        }
+        let n = expected_items.max(1) as f64;
+        let num_bits = (-(n * false_positive_rate.ln()) / (LN_2 * LN_2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / n) * LN_2)
+            .round()
+            .clamp(1.0, MAX_NUM_HASHES as f64) as u32;
+        Ok(BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            count: 0,
+            ones: 0,
+        })
+    }
+
+    /// Insert an item into the filter.
+    ///
+    /// Returns true if the item was (probably) not present before, otherwise false.
+    pub fn insert(&mut self, item: &[u8]) -> bool {
+        let mut inserted = false;
+        for index in self.indices(item) {
+            let (word, mask) = (index / 64, 1u64 << (index % 64));
+            if self.bits[word as usize] & mask == 0 {
+                self.bits[word as usize] |= mask;
+                self.ones += 1;
+                inserted = true;
+            }
+        }
+        if inserted {
+            self.count += 1;
+        }
+        inserted
+    }
+
+    /// Check if the item is (probably) in the filter.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.indices(item)
+            .all(|index| self.bits[(index / 64) as usize] & (1u64 << (index % 64)) != 0)
+    }
+
+    /// Get the number of distinct items inserted so far (up to false positives on insert).
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    /// Check if no item was inserted in the filter.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Remove all items from the filter, keeping its dimensions.
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+        self.count = 0;
+        self.ones = 0;
+    }
+
+    /// Get the size of the bit array.
+    pub fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    /// Get the number of hash functions applied to each item.
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Estimate the current false-positive probability given the number of inserted items.
+    ///
+    /// Once the filter holds more items than it was sized for, this value rises above the
+    /// rate requested at construction.
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        let k = self.num_hashes as f64;
+        let exponent = -k * self.count as f64 / self.num_bits as f64;
+        (1.0 - exponent.exp()).powf(k)
+    }
+
+    /// Get the current false-positive probability, measured from the fraction of bits set.
+    ///
+    /// Unlike [`BloomFilter::estimated_false_positive_rate`], it stays accurate when the filter
+    /// is overfilled (inserts colliding with earlier items are not counted as items).
+    pub fn false_positive_rate(&self) -> f64 {
+        (self.ones as f64 / self.num_bits as f64).powi(self.num_hashes as i32)
+    }
+
+    /// Serialize the filter to bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(BLOOM_HEADER_LEN + self.bits.len() * 8);
+        data.extend_from_slice(BLOOM_MAGIC);
+        data.push(BLOOM_VERSION);
+        data.extend_from_slice(&self.num_hashes.to_be_bytes());
+        data.extend_from_slice(&self.num_bits.to_be_bytes());
+        data.extend_from_slice(&self.count.to_be_bytes());
+        for word in &self.bits {
+            data.extend_from_slice(&word.to_be_bytes());
+        }
+        data
+    }
+
+    /// Deserialize a filter previously produced by [`BloomFilter::to_bytes`].
+    pub fn try_from_bytes(data: &[u8]) -> Result<BloomFilter, &'static str> {
+        if data.len() < BLOOM_HEADER_LEN || &data[0..4] != BLOOM_MAGIC {
+            return Err("Invalid bloom filter header");
+        }
+        if data[4] != BLOOM_VERSION {
+            return Err("Unsupported bloom filter version");
+        }
+        let num_hashes = u32::from_be_bytes(data[5..9].try_into().unwrap());
+        let num_bits = u64::from_be_bytes(data[9..17].try_into().unwrap());
+        let count = u64::from_be_bytes(data[17..25].try_into().unwrap());
+        let words = &data[BLOOM_HEADER_LEN..];
+        if num_hashes == 0 || num_hashes > MAX_NUM_HASHES {
+            return Err("Invalid bloom filter hash count");
+        }
+        if num_bits == 0 || words.len() as u64 != num_bits.div_ceil(64) * 8 {
+            return Err("Invalid bloom filter dimensions");
+        }
+        let bits: Vec<u64> = words
+            .chunks_exact(8)
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let ones = bits.iter().map(|word| word.count_ones() as u64).sum();
+        Ok(BloomFilter {
+            bits,
+            num_bits,
+            num_hashes,
+            count,
+            ones,
+        })
+    }
+
+    /// Compute the bit indices of an item (Kirsch-Mitzenmacher double hashing).
+    fn indices(&self, item: &[u8]) -> impl Iterator<Item = u64> + use<> {
+        let h1 = stable_hash(item, 0);
+        let h2 = stable_hash(item, 0x9e37_79b9_7f4a_7c15) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+/// A seeded 64-bit hash that is stable across builds (unlike `DefaultHasher`).
+pub(crate) fn stable_hash(item: &[u8], seed: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for &byte in item {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    // SplitMix64 finalizer, FNV alone is weak on the low bits.
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inserted_items_are_contained() {
+        let mut filter = BloomFilter::with_rate(1000, 0.01).unwrap();
+        // An insert may collide with earlier items, but only at about the target rate.
+        let inserted = (0u32..1000)
+            .filter(|i| filter.insert(&i.to_be_bytes()))
+            .count();
+        assert!(inserted > 970);
+        for i in 0u32..1000 {
+            assert!(filter.contains(&i.to_be_bytes()));
+        }
+        assert!(!filter.insert(&42u32.to_be_bytes()));
+    }
+
+    #[test]
+    fn test_false_positive_rate_is_near_target() {
+        let mut filter = BloomFilter::with_rate(10_000, 0.01).unwrap();
+        for i in 0u32..10_000 {
+            filter.insert(&i.to_be_bytes());
+        }
+        let false_positives = (10_000u32..110_000)
+            .filter(|i| filter.contains(&i.to_be_bytes()))
+            .count();
+        // 1% target over 100k probes, leave room for variance.
+        assert!(
+            false_positives < 2_000,
+            "{} false positives",
+            false_positives
+        );
+        assert!(filter.estimated_false_positive_rate() < 0.02);
+        let measured = false_positives as f64 / 100_000.0;
+        assert!((filter.false_positive_rate() - measured).abs() < 0.005);
+    }
+
+    #[test]
+    fn test_roundtrip_bytes() {
+        let mut filter = BloomFilter::with_rate(100, 0.001).unwrap();
+        filter.insert(b"spam");
+        filter.insert(b"eggs");
+        let restored = BloomFilter::try_from_bytes(&filter.to_bytes()).unwrap();
+        assert_eq!(restored, filter);
+        assert!(restored.contains(b"spam"));
+        assert_eq!(restored.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_rate() {
+        for rate in [0.0, 1.0, -0.5, 2.0, f64::NAN] {
+            assert_eq!(
+                BloomFilter::with_rate(10, rate),
+                Err("Invalid bloom filter false positive rate")
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_bytes() {
+        assert!(BloomFilter::try_from_bytes(b"").is_err());
+        let mut data = BloomFilter::with_rate(10, 0.1).unwrap().to_bytes();
+        data.pop();
+        assert!(BloomFilter::try_from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn test_invalid_hash_count() {
+        let data = BloomFilter::with_rate(10, 0.1).unwrap().to_bytes();
+        for num_hashes in [0, MAX_NUM_HASHES + 1, u32::MAX] {
+            let mut corrupted = data.clone();
+            corrupted[5..9].copy_from_slice(&num_hashes.to_be_bytes());
+            assert_eq!(
+                BloomFilter::try_from_bytes(&corrupted),
+                Err("Invalid bloom filter hash count")
+            );
+        }
+        let filter = BloomFilter::with_rate(10, 1e-300).unwrap();
+        assert_eq!(filter.num_hashes(), MAX_NUM_HASHES);
+        assert!(BloomFilter::try_from_bytes(&filter.to_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,338 @@
+//! The reserved IPv4 and IPv6 ranges (bogons), where no node of the DHT can be reached from
+//! the internet.
+//!
+//! [`BogonFilter`] holds them in a [`PrefixMap`], a longest-prefix-match table: like a public
+//! suffix list, a range can carve an exception out of a wider one (e.g. the global unicast
+//! range `2000::/3` out of the otherwise unrouted IPv6 space), and the most specific range
+//! containing an address decides. [`is_bogon`](crate::node::is_bogon) checks an address
+//! against the built-in table.
+
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    sync::LazyLock,
+};
+
+use crate::limits::IpPrefix;
+
+/// A map from network prefixes to values, looked up by longest prefix match.
+///
+/// The prefixes are stored in a binary trie, one bit per level, so a lookup walks at most 32
+/// (IPv4) or 128 (IPv6) nodes whatever the number of prefixes. IPv4-mapped IPv6 addresses are
+/// looked up as IPv4 ones.
+#[derive(Debug, Clone)]
+pub struct PrefixMap<V> {
+    // The roots of the IPv4 and IPv6 tries are the first two nodes.
+    nodes: Vec<TrieNode<V>>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct TrieNode<V> {
+    children: [Option<u32>; 2],
+    value: Option<V>,
+}
+
+impl<V> TrieNode<V> {
+    fn new() -> TrieNode<V> {
+        TrieNode {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+/// Index of the root of the trie of `ip`, and the bits of `ip` left-aligned in a `u128`.
+fn key(ip: IpAddr) -> (usize, u128) {
+    match ip {
+        IpAddr::V4(ip) => (0, (u32::from(ip) as u128) << 96),
+        IpAddr::V6(ip) => (1, u128::from(ip)),
+    }
+}
+
+/// Bit `depth` of a key, from the most significant one.
+fn bit(key: u128, depth: u8) -> usize {
+    ((key >> (127 - depth)) & 1) as usize
+}
+
+impl<V> PrefixMap<V> {
+    /// Create an empty map.
+    pub fn new() -> PrefixMap<V> {
+        PrefixMap {
+            nodes: vec![TrieNode::new(), TrieNode::new()],
+            len: 0,
+        }
+    }
+
+    /// Map `prefix` to `value`, returns the value it was mapped to before if any.
+    pub fn insert(&mut self, prefix: IpPrefix, value: V) -> Option<V> {
+        let (mut index, key) = key(prefix.address());
+        for depth in 0..prefix.length() {
+            let next = bit(key, depth);
+            index = match self.nodes[index].children[next] {
+                Some(child) => child as usize,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(TrieNode::new());
+                    self.nodes[index].children[next] = Some(child as u32);
+                    child
+                }
+            };
+        }
+        let previous = self.nodes[index].value.replace(value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    /// Get the value `prefix` itself is mapped to.
+    pub fn get(&self, prefix: &IpPrefix) -> Option<&V> {
+        let (mut index, key) = key(prefix.address());
+        for depth in 0..prefix.length() {
+            index = self.nodes[index].children[bit(key, depth)]? as usize;
+        }
+        self.nodes[index].value.as_ref()
+    }
+
+    /// Get the longest prefix containing `ip`, and its value.
+    pub fn longest_match(&self, ip: IpAddr) -> Option<(IpPrefix, &V)> {
+        let ip = ip.to_canonical();
+        let (mut index, key) = key(ip);
+        let width = if ip.is_ipv4() { 32 } else { 128 };
+        let mut found = None;
+        for depth in 0..=width {
+            if let Some(value) = &self.nodes[index].value {
+                found = Some((depth, value));
+            }
+            if depth == width {
+                break;
+            }
+            match self.nodes[index].children[bit(key, depth)] {
+                Some(child) => index = child as usize,
+                None => break,
+            }
+        }
+        found.map(|(length, value)| (IpPrefix::containing(ip, length), value))
+    }
+
+    /// Get the number of prefixes in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the map holds no prefix.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<V> Default for PrefixMap<V> {
+    fn default() -> Self {
+        PrefixMap::new()
+    }
+}
+
+/// Why an address is a bogon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BogonKind {
+    /// "This network" (`0.0.0.0/8`) or the unspecified address (`::`).
+    Unspecified,
+    /// Private networks (RFC 1918) and IPv6 unique local addresses (`fc00::/7`).
+    Private,
+    /// Shared address space of carrier-grade NATs (RFC 6598).
+    Shared,
+    /// Loopback.
+    Loopback,
+    /// Link-local.
+    LinkLocal,
+    /// IETF protocol assignments (`192.0.0.0/24`).
+    ProtocolAssignments,
+    /// Documentation (RFC 5737 and RFC 3849).
+    Documentation,
+    /// Benchmarking (RFC 2544).
+    Benchmarking,
+    /// Multicast.
+    Multicast,
+    /// Reserved, broadcast, or outside of the routed IPv6 space (`2000::/3`).
+    Reserved,
+}
+
+impl Display for BogonKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BogonKind::Unspecified => "unspecified",
+            BogonKind::Private => "private",
+            BogonKind::Shared => "shared",
+            BogonKind::Loopback => "loopback",
+            BogonKind::LinkLocal => "link-local",
+            BogonKind::ProtocolAssignments => "protocol assignments",
+            BogonKind::Documentation => "documentation",
+            BogonKind::Benchmarking => "benchmarking",
+            BogonKind::Multicast => "multicast",
+            BogonKind::Reserved => "reserved",
+        })
+    }
+}
+
+/// The built-in table: the reserved ranges, and the routed ranges inside them (`None`).
+const RESERVED_RANGES: &[(&str, Option<BogonKind>)] = &[
+    ("0.0.0.0/8", Some(BogonKind::Unspecified)),
+    ("10.0.0.0/8", Some(BogonKind::Private)),
+    ("100.64.0.0/10", Some(BogonKind::Shared)),
+    ("127.0.0.0/8", Some(BogonKind::Loopback)),
+    ("169.254.0.0/16", Some(BogonKind::LinkLocal)),
+    ("172.16.0.0/12", Some(BogonKind::Private)),
+    ("192.0.0.0/24", Some(BogonKind::ProtocolAssignments)),
+    ("192.0.2.0/24", Some(BogonKind::Documentation)),
+    ("192.168.0.0/16", Some(BogonKind::Private)),
+    ("198.18.0.0/15", Some(BogonKind::Benchmarking)),
+    ("198.51.100.0/24", Some(BogonKind::Documentation)),
+    ("203.0.113.0/24", Some(BogonKind::Documentation)),
+    ("224.0.0.0/4", Some(BogonKind::Multicast)),
+    ("240.0.0.0/4", Some(BogonKind::Reserved)),
+    ("::/0", Some(BogonKind::Reserved)),
+    ("::/128", Some(BogonKind::Unspecified)),
+    ("::1/128", Some(BogonKind::Loopback)),
+    ("2000::/3", None),
+    ("2001:db8::/32", Some(BogonKind::Documentation)),
+    ("fc00::/7", Some(BogonKind::Private)),
+    ("fe80::/10", Some(BogonKind::LinkLocal)),
+    ("ff00::/8", Some(BogonKind::Multicast)),
+];
+
+static BUILTIN: LazyLock<BogonFilter> = LazyLock::new(BogonFilter::new);
+
+/// The reserved address ranges, checked by longest prefix match (see the [module](self)
+/// documentation).
+///
+/// [`BogonFilter::new`] starts from the built-in table, which [`BogonFilter::insert`] and
+/// [`BogonFilter::allow`] extend, e.g. to allow a private network known to be reachable.
+#[derive(Debug, Clone)]
+pub struct BogonFilter {
+    ranges: PrefixMap<Option<BogonKind>>,
+}
+
+impl BogonFilter {
+    /// Create a filter with the built-in table.
+    pub fn new() -> BogonFilter {
+        let mut filter = BogonFilter::empty();
+        for (prefix, kind) in RESERVED_RANGES {
+            let prefix = prefix.parse().expect("valid built-in prefix");
+            filter.ranges.insert(prefix, *kind);
+        }
+        filter
+    }
+
+    /// Create a filter without any range.
+    pub fn empty() -> BogonFilter {
+        BogonFilter {
+            ranges: PrefixMap::new(),
+        }
+    }
+
+    /// Get the filter with the built-in table, shared by the whole process.
+    pub fn builtin() -> &'static BogonFilter {
+        &BUILTIN
+    }
+
+    /// Mark the addresses of `prefix` as bogons of the given kind.
+    pub fn insert(&mut self, prefix: IpPrefix, kind: BogonKind) {
+        self.ranges.insert(prefix, Some(kind));
+    }
+
+    /// Allow the addresses of `prefix`, unless a longer prefix inside it is a bogon.
+    pub fn allow(&mut self, prefix: IpPrefix) {
+        self.ranges.insert(prefix, None);
+    }
+
+    /// Get why `ip` is a bogon, `None` if it is not one.
+    pub fn kind(&self, ip: IpAddr) -> Option<BogonKind> {
+        self.ranges.longest_match(ip).and_then(|(_, kind)| *kind)
+    }
+
+    /// Check if `ip` is a bogon.
+    pub fn is_bogon(&self, ip: IpAddr) -> bool {
+        self.kind(ip).is_some()
+    }
+}
+
+impl Default for BogonFilter {
+    fn default() -> Self {
+        BogonFilter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_map() {
+        let prefix = |s: &str| s.parse::<IpPrefix>().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut map = PrefixMap::new();
+        assert_eq!(map.longest_match(ip("10.1.2.3")), None);
+        assert_eq!(map.insert(prefix("10.0.0.0/8"), 1), None);
+        assert_eq!(map.insert(prefix("10.1.0.0/16"), 2), None);
+        assert_eq!(map.insert(prefix("10.1.0.0/16"), 3), Some(2));
+        assert_eq!(map.insert(prefix("0.0.0.0/0"), 0), None);
+        assert_eq!(map.insert(prefix("::/0"), 6), None);
+        assert_eq!(map.len(), 4);
+
+        assert_eq!(
+            map.longest_match(ip("10.1.2.3")),
+            Some((prefix("10.1.0.0/16"), &3))
+        );
+        assert_eq!(
+            map.longest_match(ip("10.2.0.1")),
+            Some((prefix("10.0.0.0/8"), &1))
+        );
+        assert_eq!(
+            map.longest_match(ip("::ffff:10.2.0.1")),
+            Some((prefix("10.0.0.0/8"), &1))
+        );
+        assert_eq!(map.longest_match(ip("1.2.3.4")).unwrap().1, &0);
+        assert_eq!(map.longest_match(ip("2001:db8::1")).unwrap().1, &6);
+        assert_eq!(map.get(&prefix("10.1.0.0/16")), Some(&3));
+        assert_eq!(map.get(&prefix("10.1.0.0/15")), None);
+
+        // Host routes are the deepest entries.
+        map.insert(prefix("10.1.2.3"), 4);
+        assert_eq!(map.longest_match(ip("10.1.2.3")).unwrap().1, &4);
+        assert_eq!(map.longest_match(ip("10.1.2.4")).unwrap().1, &3);
+    }
+
+    #[test]
+    fn test_bogon_filter() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let filter = BogonFilter::builtin();
+        assert_eq!(filter.kind(ip("192.168.1.1")), Some(BogonKind::Private));
+        assert_eq!(filter.kind(ip("fd12::1")), Some(BogonKind::Private));
+        assert_eq!(filter.kind(ip("::1")), Some(BogonKind::Loopback));
+        assert_eq!(filter.kind(ip("::2")), Some(BogonKind::Reserved));
+        assert_eq!(
+            filter.kind(ip("255.255.255.255")),
+            Some(BogonKind::Reserved)
+        );
+        // The documentation range is carved out of the global unicast one.
+        assert_eq!(
+            filter.kind(ip("2001:db8::1")),
+            Some(BogonKind::Documentation)
+        );
+        assert_eq!(filter.kind(ip("2001:db9::1")), None);
+        assert_eq!(filter.kind(ip("8.8.8.8")), None);
+
+        let mut filter = BogonFilter::new();
+        filter.allow("192.168.1.0/24".parse().unwrap());
+        filter.insert("198.51.0.0/16".parse().unwrap(), BogonKind::Reserved);
+        assert!(!filter.is_bogon(ip("192.168.1.7")));
+        assert!(filter.is_bogon(ip("192.168.2.7")));
+        assert_eq!(filter.kind(ip("198.51.0.1")), Some(BogonKind::Reserved));
+        assert_eq!(
+            filter.kind(ip("198.51.100.1")),
+            Some(BogonKind::Documentation)
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::rng::Rng;
+
+/// Reliability of a bootstrap node without history.
+const PRIOR_RELIABILITY: f64 = 0.5;
+/// Weight of the last outcome in the reliability of a bootstrap node.
+const OUTCOME_WEIGHT: f64 = 0.3;
+/// Weight of the last round-trip time in the round-trip time of a bootstrap node.
+const RTT_WEIGHT: f64 = 0.3;
+/// Lowest selection weight: the nodes that failed so far still get a chance, e.g. to recover
+/// when every bootstrap node failed in the previous runs.
+const MIN_WEIGHT: f64 = 0.02;
+
+/// Selection of the bootstrap nodes, see [`BootstrapHistory`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapConfig {
+    /// File the success of the bootstrap nodes is kept in across runs, read on bind and
+    /// written when the crawl stops. Without it, the history only covers the current run.
+    pub history: Option<PathBuf>,
+    /// Maximum number of bootstrap nodes pinged per round, the most successful first. All of
+    /// them by default.
+    pub per_round: usize,
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        BootstrapConfig {
+            history: None,
+            per_round: usize::MAX,
+        }
+    }
+}
+
+/// Past outcomes of the pings sent to a bootstrap node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BootstrapRecord {
+    /// Pings answered.
+    pub successes: u64,
+    /// Pings left unanswered.
+    pub failures: u64,
+    /// Moving average of the outcomes of the pings (1 answered, 0 unanswered), the recent ones
+    /// weighing the most.
+    pub reliability: f64,
+    /// Moving average of the round-trip time of the pings answered.
+    pub rtt: Option<Duration>,
+    /// Last time a ping was answered, in seconds since the Unix epoch.
+    pub last_success: Option<u64>,
+}
+
+impl Default for BootstrapRecord {
+    fn default() -> Self {
+        BootstrapRecord {
+            successes: 0,
+            failures: 0,
+            reliability: PRIOR_RELIABILITY,
+            rtt: None,
+            last_success: None,
+        }
+    }
+}
+
+impl BootstrapRecord {
+    /// Get the selection weight of the node: its reliability, lowered by its round-trip time
+    /// (halved at one second).
+    pub fn weight(&self) -> f64 {
+        let rtt = self.rtt.map(|rtt| rtt.as_secs_f64()).unwrap_or_default();
+        (self.reliability / (1.0 + rtt)).max(MIN_WEIGHT)
+    }
+}
+
+/// Success of the bootstrap nodes across runs, to ping the reliable and fast ones first.
+///
+/// The history is a text file, one node per line: the `host:port` of the node as configured,
+/// the pings answered and left unanswered, the reliability, the round-trip time in
+/// milliseconds and the last success (`-` if unknown). `#` starts a comment. The history of
+/// the bootstrap nodes of a private overlay starts with a `domain <name>` line, see
+/// [`BootstrapHistory::domain`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BootstrapHistory {
+    records: BTreeMap<String, BootstrapRecord>,
+    domain: Option<String>,
+}
+
+impl BootstrapHistory {
+    /// Create an empty history.
+    pub fn new() -> BootstrapHistory {
+        BootstrapHistory::default()
+    }
+
+    /// Read the history at `path`. A missing file is an empty history.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<BootstrapHistory> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BootstrapHistory::new()),
+            Err(e) => return Err(e),
+        };
+        let mut records = BTreeMap::new();
+        let mut domain = None;
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(("domain", name)) = line.split_once(char::is_whitespace) {
+                domain = Some(name.trim().to_string());
+                continue;
+            }
+            let (host, record) = parse_record(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {} ({:?})", number + 1, e, line),
+                )
+            })?;
+            records.insert(host, record);
+        }
+        Ok(BootstrapHistory { records, domain })
+    }
+
+    /// Write the history to `path`, replacing the file only once it is fully written.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(
+            writer,
+            "# host successes failures reliability rtt_ms last_success"
+        )?;
+        if let Some(domain) = &self.domain {
+            writeln!(writer, "domain {}", domain)?;
+        }
+        let or_dash = |value: Option<u64>| value.map_or("-".to_string(), |v| v.to_string());
+        for (host, record) in &self.records {
+            writeln!(
+                writer,
+                "{} {} {} {:.4} {} {}",
+                host,
+                record.successes,
+                record.failures,
+                record.reliability,
+                or_dash(record.rtt.map(|rtt| rtt.as_millis() as u64)),
+                or_dash(record.last_success)
+            )?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Get the name of the private overlay the bootstrap nodes belong to, `None` for the
+    /// public DHT: the history of one DHT is not used to bootstrap another.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Set the name of the private overlay the bootstrap nodes belong to, see
+    /// [`BootstrapHistory::domain`].
+    pub fn set_domain(&mut self, domain: Option<String>) {
+        self.domain = domain;
+    }
+
+    /// Get the record of `host`, if it was ever pinged.
+    pub fn get(&self, host: &str) -> Option<&BootstrapRecord> {
+        self.records.get(host)
+    }
+
+    /// Get the records, by host.
+    pub fn records(&self) -> impl Iterator<Item = (&str, &BootstrapRecord)> {
+        self.records
+            .iter()
+            .map(|(host, record)| (host.as_str(), record))
+    }
+
+    /// Get the selection weight of `host`, the weight of a node without history if it was
+    /// never pinged.
+    pub fn weight(&self, host: &str) -> f64 {
+        self.get(host).copied().unwrap_or_default().weight()
+    }
+
+    /// Record a ping to `host` answered in `rtt` at `now`.
+    pub fn record_success(&mut self, host: &str, rtt: Duration, now: SystemTime) {
+        let record = self.records.entry(host.to_string()).or_default();
+        record.successes += 1;
+        record.reliability += (1.0 - record.reliability) * OUTCOME_WEIGHT;
+        record.rtt = Some(match record.rtt {
+            Some(average) => average.mul_f64(1.0 - RTT_WEIGHT) + rtt.mul_f64(RTT_WEIGHT),
+            None => rtt,
+        });
+        record.last_success = now
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|time| time.as_secs());
+    }
+
+    /// Record a ping to `host` left unanswered.
+    pub fn record_failure(&mut self, host: &str) {
+        let record = self.records.entry(host.to_string()).or_default();
+        record.failures += 1;
+        record.reliability -= record.reliability * OUTCOME_WEIGHT;
+    }
+
+    /// Pick up to `count` of `hosts` at random, weighted by [`BootstrapHistory::weight`],
+    /// without picking an entry twice. The hosts without history get an average weight, and no
+    /// host gets a zero weight.
+    pub fn select<R: Rng>(&self, hosts: &[String], count: usize, rng: &mut R) -> Vec<String> {
+        // Weighted sampling without replacement (Efraimidis-Spirakis): the largest keys of
+        // ln(u) / weight, for u uniform in (0, 1].
+        let mut keyed: Vec<(f64, &String)> = hosts
+            .iter()
+            .map(|host| {
+                let uniform = 1.0 - rng.unit();
+                (uniform.ln() / self.weight(host), host)
+            })
+            .collect();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+        keyed
+            .into_iter()
+            .take(count)
+            .map(|(_, host)| host.clone())
+            .collect()
+    }
+}
+
+/// Parse a line of a history file.
+fn parse_record(line: &str) -> Result<(String, BootstrapRecord), &'static str> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [host, successes, failures, reliability, rtt, last_success] = fields[..] else {
+        return Err("expected 6 fields");
+    };
+    let optional = |field: &str| match field {
+        "-" => Ok(None),
+        field => field.parse().map(Some),
+    };
+    let reliability: f64 = reliability.parse().map_err(|_| "invalid reliability")?;
+    if !(0.0..=1.0).contains(&reliability) {
+        return Err("reliability out of range");
+    }
+    let record = BootstrapRecord {
+        successes: successes.parse().map_err(|_| "invalid successes")?,
+        failures: failures.parse().map_err(|_| "invalid failures")?,
+        reliability,
+        rtt: optional(rtt)
+            .map_err(|_| "invalid rtt")?
+            .map(Duration::from_millis),
+        last_success: optional(last_success).map_err(|_| "invalid last success")?,
+    };
+    Ok((host.to_string(), record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+
+    #[test]
+    fn test_bootstrap_history() {
+        let now = SystemTime::now();
+        let mut history = BootstrapHistory::new();
+        for _ in 0..10 {
+            history.record_success("fast:6881", Duration::from_millis(50), now);
+            history.record_success("slow:6881", Duration::from_secs(2), now);
+            history.record_failure("dead:6881");
+        }
+        assert!(history.weight("fast:6881") > history.weight("slow:6881"));
+        assert!(history.weight("new:6881") > history.weight("dead:6881"));
+        assert_eq!(history.weight("dead:6881"), MIN_WEIGHT);
+
+        let hosts: Vec<String> = ["dead:6881", "slow:6881", "fast:6881", "new:6881"]
+            .iter()
+            .map(|host| host.to_string())
+            .collect();
+        let mut rng = SplitMix64::with_seed(1);
+        let mut picked = BTreeMap::<String, u32>::new();
+        for _ in 0..1000 {
+            let selected = history.select(&hosts, 2, &mut rng);
+            assert_eq!(selected.len(), 2);
+            assert_ne!(selected[0], selected[1]);
+            for host in selected {
+                *picked.entry(host).or_default() += 1;
+            }
+        }
+        // Every node gets a chance, the reliable and fast ones the most.
+        assert!(picked["fast:6881"] > picked["slow:6881"]);
+        assert!(picked["slow:6881"] > picked["dead:6881"]);
+        assert!(picked["dead:6881"] > 0);
+        assert_eq!(history.select(&hosts, 10, &mut rng).len(), 4);
+
+        let path = std::env::temp_dir().join(format!("bootstrap-{}.txt", std::process::id()));
+        history.write(&path).unwrap();
+        let read = BootstrapHistory::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.records().count(), 3);
+        let (fast, read_fast) = (history.get("fast:6881"), read.get("fast:6881"));
+        assert_eq!(read_fast.map(|r| r.successes), fast.map(|r| r.successes));
+        assert_eq!(
+            read_fast.and_then(|r| r.rtt),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(read.get("dead:6881").map(|r| r.rtt), Some(None));
+        assert_eq!(read.domain(), None);
+        assert!(
+            BootstrapHistory::read(&path)
+                .unwrap()
+                .records()
+                .next()
+                .is_none()
+        );
+
+        history.set_domain(Some("lab".into()));
+        history.write(&path).unwrap();
+        let read = BootstrapHistory::read(&path).unwrap();
+        assert_eq!(read.domain(), Some("lab"));
+        assert_eq!(read.records().count(), 3);
+
+        fs::write(&path, "fast:6881 1 0 2.0 - -\n").unwrap();
+        let error = BootstrapHistory::read(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use bitcrawler_proto::kademlia::Id160;
+
+/// Number of distinct nodes remembered per peer: the confidence of the peers returned by
+/// more nodes saturates at it.
+pub const MAX_PEER_REPORTERS: u32 = 16;
+
+/// Number of peers looked at to pick the one forgotten when the tracker is full.
+const EVICTION_SAMPLE: usize = 64;
+
+/// Configuration of a [`PeerCorroboration`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerConfidenceConfig {
+    /// Number of distinct nodes that must have returned a peer for an info hash before it is
+    /// reported to the sinks, unless a handshake with it succeeded (see
+    /// [`CrawlerHandle::confirm_peer`]). `1` reports every peer.
+    ///
+    /// [`CrawlerHandle::confirm_peer`]: super::CrawlerHandle::confirm_peer
+    pub min_reporters: u32,
+    /// Maximum number of peers (of all the info hashes) tracked. The least corroborated ones
+    /// are forgotten beyond it.
+    pub capacity: usize,
+}
+
+impl Default for PeerConfidenceConfig {
+    fn default() -> Self {
+        PeerConfidenceConfig {
+            min_reporters: 1,
+            capacity: 1 << 16,
+        }
+    }
+}
+
+/// How much a peer returned for an info hash can be trusted, see [`PeerCorroboration`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerConfidence {
+    /// Number of distinct nodes, by IP address, that returned the peer for the info hash, up
+    /// to [`MAX_PEER_REPORTERS`].
+    pub reporters: u32,
+    /// A handshake with the peer for the info hash succeeded.
+    pub handshake: bool,
+}
+
+impl PeerConfidence {
+    /// Check if the peer is corroborated enough to be reported with `config`.
+    pub fn meets(&self, config: &PeerConfidenceConfig) -> bool {
+        self.handshake || self.reporters >= config.min_reporters
+    }
+}
+
+/// Counters of a [`PeerCorroboration`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerConfidenceStats {
+    /// Peers tracked.
+    pub peers: usize,
+    /// Peers returned, over all the replies.
+    pub observed: u64,
+    /// Peers returned that were held back, not corroborated enough.
+    pub suppressed: u64,
+    /// Handshakes reported.
+    pub confirmed: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Corroboration {
+    // Distinct addresses of the nodes that returned the peer.
+    reporters: Vec<IpAddr>,
+    handshake: bool,
+}
+
+impl Corroboration {
+    fn confidence(&self) -> PeerConfidence {
+        PeerConfidence {
+            reporters: self.reporters.len() as u32,
+            handshake: self.handshake,
+        }
+    }
+}
+
+/// Scores the peers returned in `get_peers` replies by corroboration: the number of distinct
+/// nodes that returned each of them for an info hash, and whether a handshake with it
+/// succeeded.
+///
+/// A poisoned reply makes up its peers, no other node returns them: holding back the peers
+/// returned by a single node (see [`PeerConfidenceConfig::min_reporters`]) keeps most of this
+/// junk out of the sinks. The reporters are told apart by IP address, as their ids and ports
+/// cost nothing to make up. The peers are kept in bounded memory.
+#[derive(Debug, Clone)]
+pub struct PeerCorroboration {
+    config: PeerConfidenceConfig,
+    peers: HashMap<(Id160, SocketAddr), Corroboration>,
+    stats: PeerConfidenceStats,
+}
+
+impl PeerCorroboration {
+    /// Create a tracker without peers.
+    pub fn new(config: PeerConfidenceConfig) -> PeerCorroboration {
+        PeerCorroboration {
+            config,
+            peers: HashMap::new(),
+            stats: PeerConfidenceStats::default(),
+        }
+    }
+
+    /// Get the configuration of the tracker.
+    pub fn config(&self) -> &PeerConfidenceConfig {
+        &self.config
+    }
+
+    /// Replace the threshold of the tracker, the peers are kept.
+    pub fn set_config(&mut self, config: PeerConfidenceConfig) {
+        self.config = config;
+    }
+
+    /// Record that the node at `source` returned `peer` for `info_hash`, and get the
+    /// confidence of the peer.
+    pub fn observe(
+        &mut self,
+        info_hash: Id160,
+        source: IpAddr,
+        peer: SocketAddr,
+    ) -> PeerConfidence {
+        self.stats.observed += 1;
+        let corroboration = self.entry(info_hash, peer);
+        if !corroboration.reporters.contains(&source)
+            && corroboration.reporters.len() < MAX_PEER_REPORTERS as usize
+        {
+            corroboration.reporters.push(source);
+        }
+        let confidence = corroboration.confidence();
+        if !confidence.meets(&self.config) {
+            self.stats.suppressed += 1;
+        }
+        confidence
+    }
+
+    /// Record that a handshake with `peer` for `info_hash` succeeded: the peer is reported
+    /// whatever the number of nodes that returned it.
+    pub fn confirm(&mut self, info_hash: Id160, peer: SocketAddr) {
+        self.stats.confirmed += 1;
+        self.entry(info_hash, peer).handshake = true;
+    }
+
+    /// Get the confidence of `peer` for `info_hash`, the default one if it is not tracked.
+    pub fn confidence(&self, info_hash: &Id160, peer: &SocketAddr) -> PeerConfidence {
+        self.peers
+            .get(&(*info_hash, *peer))
+            .map(Corroboration::confidence)
+            .unwrap_or_default()
+    }
+
+    /// Get the counters of the tracker.
+    pub fn stats(&self) -> PeerConfidenceStats {
+        PeerConfidenceStats {
+            peers: self.peers.len(),
+            ..self.stats
+        }
+    }
+
+    /// Get the entry of `peer` for `info_hash`, making room for it if needed.
+    fn entry(&mut self, info_hash: Id160, peer: SocketAddr) -> &mut Corroboration {
+        let key = (info_hash, peer);
+        if !self.peers.contains_key(&key) && self.peers.len() >= self.config.capacity.max(1) {
+            // The least corroborated of a sample, not to scan every peer on each insert.
+            let evicted = self
+                .peers
+                .iter()
+                .take(EVICTION_SAMPLE)
+                .min_by_key(|(_, corroboration)| {
+                    (corroboration.handshake, corroboration.reporters.len())
+                })
+                .map(|(key, _)| *key);
+            if let Some(evicted) = evicted {
+                self.peers.remove(&evicted);
+            }
+        }
+        self.peers.entry(key).or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_corroboration() {
+        let mut peers = PeerCorroboration::new(PeerConfidenceConfig {
+            min_reporters: 2,
+            capacity: 2,
+        });
+        let info_hash = Id160([1; 20]);
+        let peer: SocketAddr = "198.51.100.1:51413".parse().unwrap();
+        let node = |i: u8| IpAddr::from([192, 0, 2, i]);
+
+        let confidence = peers.observe(info_hash, node(1), peer);
+        assert_eq!(
+            confidence,
+            PeerConfidence {
+                reporters: 1,
+                handshake: false,
+            }
+        );
+        assert!(!confidence.meets(peers.config()));
+        // The same node again does not corroborate the peer.
+        assert_eq!(peers.observe(info_hash, node(1), peer).reporters, 1);
+        let confidence = peers.observe(info_hash, node(2), peer);
+        assert_eq!(confidence.reporters, 2);
+        assert!(confidence.meets(peers.config()));
+        // The peer is only corroborated for its info hash.
+        assert_eq!(peers.observe(Id160([2; 20]), node(2), peer).reporters, 1);
+
+        // A handshake makes up for the reporters.
+        let other: SocketAddr = "198.51.100.2:6881".parse().unwrap();
+        peers.confirm(info_hash, other);
+        assert!(peers.confidence(&info_hash, &other).meets(peers.config()));
+        // The least corroborated peer was forgotten to make room.
+        assert_eq!(
+            peers.confidence(&Id160([2; 20]), &peer),
+            PeerConfidence::default()
+        );
+        assert_eq!(peers.confidence(&info_hash, &peer).reporters, 2);
+
+        for i in 0..20 {
+            peers.observe(info_hash, node(i), peer);
+        }
+        assert_eq!(
+            peers.confidence(&info_hash, &peer).reporters,
+            MAX_PEER_REPORTERS
+        );
+        assert_eq!(
+            peers.stats(),
+            PeerConfidenceStats {
+                peers: 2,
+                observed: 24,
+                suppressed: 3,
+                confirmed: 1,
+            }
+        );
+    }
+}
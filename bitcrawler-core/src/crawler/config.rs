@@ -0,0 +1,593 @@
+use std::{error::Error, fmt, io};
+
+use super::{CrawlerConfig, DEFAULT_BOOTSTRAP_NODES, MAX_PEER_REPORTERS};
+use crate::transport::MAX_DSCP;
+
+/// A problem found in a [`CrawlerConfig`] by [`CrawlerConfig::validate`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The setting must not be zero.
+    Zero {
+        /// Path of the setting, e.g. `node.limits.max_in_flight`.
+        field: &'static str,
+    },
+    /// The setting is out of its valid range.
+    OutOfRange {
+        /// Path of the setting.
+        field: &'static str,
+        /// The invalid value.
+        value: String,
+        /// The valid range, e.g. `between 0 and 1`.
+        expected: &'static str,
+    },
+    /// The list (or string) must not be empty.
+    Empty {
+        /// Path of the setting.
+        field: &'static str,
+    },
+    /// The setting must not be greater than another one.
+    GreaterThan {
+        /// Path of the setting.
+        field: &'static str,
+        /// Path of the setting it must not exceed.
+        limit: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Zero { field } => write!(f, "{} must not be zero", field),
+            ConfigError::Empty { field } => write!(f, "{} must not be empty", field),
+            ConfigError::OutOfRange {
+                field,
+                value,
+                expected,
+            } => write!(f, "{} is {}, expected {}", field, value, expected),
+            ConfigError::GreaterThan { field, limit } => {
+                write!(f, "{} must not be greater than {}", field, limit)
+            }
+        }
+    }
+}
+
+/// Every problem found in a [`CrawlerConfig`], see [`CrawlerConfig::validate`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigErrors(Vec<ConfigError>);
+
+impl ConfigErrors {
+    /// Get the problems, in the order of the settings.
+    pub fn errors(&self) -> &[ConfigError] {
+        &self.0
+    }
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration: ")?;
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigErrors {}
+
+impl From<ConfigErrors> for io::Error {
+    fn from(errors: ConfigErrors) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, errors)
+    }
+}
+
+/// A setting that differs between two [`CrawlerConfig`]s, see [`CrawlerConfig::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigChange {
+    /// Path of the setting, e.g. `node.limits.max_in_flight`.
+    pub field: &'static str,
+    /// The previous value, as debug-formatted.
+    pub old: String,
+    /// The new value, as debug-formatted.
+    pub new: String,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.old, self.new)
+    }
+}
+
+impl CrawlerConfig {
+    /// Check the settings, listing every problem found rather than the first one.
+    ///
+    /// [`Crawler::bind`](super::Crawler::bind) refuses a configuration that does not pass.
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = Vec::new();
+        let mut nonzero = |field, zero: bool| {
+            if zero {
+                errors.push(ConfigError::Zero { field });
+            }
+        };
+        let node = &self.node;
+        nonzero("node.socket.sockets", node.socket.sockets == 0);
+        nonzero(
+            "node.socket.recv_buffer_size",
+            node.socket.recv_buffer_size == Some(0),
+        );
+        nonzero(
+            "node.socket.send_buffer_size",
+            node.socket.send_buffer_size == Some(0),
+        );
+        nonzero("node.socket.ttl", node.socket.ttl == Some(0));
+        nonzero("node.poll_timeout", node.poll_timeout.is_zero());
+        let limits = &node.limits;
+        nonzero(
+            "node.limits.max_queries_per_node_per_day",
+            limits.max_queries_per_node_per_day == Some(0),
+        );
+        nonzero(
+            "node.limits.max_bytes_per_second",
+            limits.max_bytes_per_second == Some(0),
+        );
+        nonzero(
+            "node.limits.family_shares",
+            limits
+                .family_shares
+                .is_some_and(|shares| shares.ipv4 == 0 || shares.ipv6 == 0),
+        );
+        nonzero("node.limits.max_in_flight", limits.max_in_flight == Some(0));
+        nonzero(
+            "node.limits.max_in_flight_per_destination",
+            limits.max_in_flight_per_destination == Some(0),
+        );
+        nonzero(
+            "node.receive_queue.capacity",
+            node.receive_queue.is_some_and(|queue| queue.capacity == 0),
+        );
+        nonzero(
+            "node.receive_queue.memory_budget",
+            node.receive_queue
+                .is_some_and(|queue| queue.memory_budget == Some(0)),
+        );
+        nonzero("node.tokens.lifetime", node.tokens.lifetime.is_zero());
+        nonzero("node.tokens.capacity", node.tokens.capacity == 0);
+        nonzero("bootstrap.per_round", self.bootstrap.per_round == 0);
+        nonzero("tick_interval", self.tick_interval.is_zero());
+        nonzero(
+            "tick_budget",
+            self.tick_budget.is_some_and(|budget| budget.is_zero()),
+        );
+        nonzero("lookup_budget", self.lookup_budget == 0);
+        nonzero("seen.expected_items", self.seen.expected_items == 0);
+        nonzero(
+            "info_hashes.expected_items",
+            self.info_hashes.expected_items == 0,
+        );
+        nonzero("identities.window", self.identities.window.is_zero());
+        nonzero("identities.capacity", self.identities.capacity == 0);
+        nonzero("port_rewrites.capacity", self.port_rewrites.capacity == 0);
+        nonzero("spoofing.flag_score", self.spoofing.flag_score == 0);
+        nonzero("spoofing.capacity", self.spoofing.capacity == 0);
+        nonzero(
+            "peer_confidence.capacity",
+            self.peer_confidence.capacity == 0,
+        );
+        nonzero(
+            "inbound_queries.window",
+            self.inbound_queries.window.is_zero(),
+        );
+        if let Some(probe) = &self.probe {
+            nonzero("probe.max_queued", probe.max_queued == 0);
+            nonzero("probe.timeout", probe.timeout.is_zero());
+        }
+        nonzero(
+            "max_duration",
+            self.max_duration.is_some_and(|duration| duration.is_zero()),
+        );
+        nonzero("max_nodes", self.max_nodes == Some(0));
+        nonzero(
+            "idle_timeout",
+            self.idle_timeout.is_some_and(|timeout| timeout.is_zero()),
+        );
+
+        if let (Some(per_destination), Some(total)) =
+            (limits.max_in_flight_per_destination, limits.max_in_flight)
+            && per_destination > total
+        {
+            errors.push(ConfigError::GreaterThan {
+                field: "node.limits.max_in_flight_per_destination",
+                limit: "node.limits.max_in_flight",
+            });
+        }
+        if node.backoff.initial_backoff > node.backoff.max_backoff {
+            errors.push(ConfigError::GreaterThan {
+                field: "node.backoff.initial_backoff",
+                limit: "node.backoff.max_backoff",
+            });
+        }
+        if let Some(dscp) = node.socket.dscp
+            && dscp > MAX_DSCP
+        {
+            errors.push(ConfigError::OutOfRange {
+                field: "node.socket.dscp",
+                value: dscp.to_string(),
+                expected: "between 0 and 63",
+            });
+        }
+        if !(1..=160).contains(&self.spoofing.min_shared_bits) {
+            errors.push(ConfigError::OutOfRange {
+                field: "spoofing.min_shared_bits",
+                value: self.spoofing.min_shared_bits.to_string(),
+                expected: "between 1 and 160",
+            });
+        }
+        // No peer would ever reach more reporters than are remembered.
+        if !(1..=MAX_PEER_REPORTERS).contains(&self.peer_confidence.min_reporters) {
+            errors.push(ConfigError::OutOfRange {
+                field: "peer_confidence.min_reporters",
+                value: self.peer_confidence.min_reporters.to_string(),
+                expected: "between 1 and 16",
+            });
+        }
+        if let Some(overlay) = &self.overlay {
+            if overlay.name.is_empty() {
+                errors.push(ConfigError::Empty {
+                    field: "overlay.name",
+                });
+            } else if overlay
+                .name
+                .contains(|c: char| c.is_whitespace() || c == '#')
+            {
+                errors.push(ConfigError::OutOfRange {
+                    field: "overlay.name",
+                    value: format!("{:?}", overlay.name),
+                    expected: "a name without whitespace or '#'",
+                });
+            }
+            if overlay.bootstrap_nodes.is_empty() {
+                errors.push(ConfigError::Empty {
+                    field: "overlay.bootstrap_nodes",
+                });
+            }
+            // Bootstrapping from the public DHT would mix its nodes in.
+            for node in &overlay.bootstrap_nodes {
+                if DEFAULT_BOOTSTRAP_NODES.contains(&node.as_str()) {
+                    errors.push(ConfigError::OutOfRange {
+                        field: "overlay.bootstrap_nodes",
+                        value: node.clone(),
+                        expected: "a node of the overlay, not of the public DHT",
+                    });
+                }
+            }
+            if overlay
+                .secret
+                .as_ref()
+                .is_some_and(|secret| secret.is_empty())
+            {
+                errors.push(ConfigError::Empty {
+                    field: "overlay.secret",
+                });
+            }
+        }
+        for (field, rate) in [
+            ("seen.false_positive_rate", self.seen.false_positive_rate),
+            (
+                "info_hashes.false_positive_rate",
+                self.info_hashes.false_positive_rate,
+            ),
+        ] {
+            if !(rate > 0.0 && rate < 1.0) {
+                errors.push(ConfigError::OutOfRange {
+                    field,
+                    value: rate.to_string(),
+                    expected: "between 0 and 1, exclusive",
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    /// Get this configuration with the settings of `other` a running crawler can apply, see
+    /// [`CrawlerHandle::reload`](super::CrawlerHandle::reload).
+    pub(super) fn with_reloadable(&self, other: &CrawlerConfig) -> CrawlerConfig {
+        let mut config = self.clone();
+        config.node.limits = other.node.limits.clone();
+        config.node.duplicates = other.node.duplicates;
+        config.bootstrap_nodes = other.bootstrap_nodes.clone();
+        config.bootstrap.per_round = other.bootstrap.per_round;
+        if let (Some(overlay), Some(other)) = (&mut config.overlay, &other.overlay)
+            && overlay.name == other.name
+        {
+            overlay.bootstrap_nodes = other.bootstrap_nodes.clone();
+        }
+        config.lookup_target = other.lookup_target;
+        config.lookup_budget = other.lookup_budget;
+        config.scrape = other.scrape;
+        config.tick_interval = other.tick_interval;
+        config.pings_per_tick = other.pings_per_tick;
+        config.tick_budget = other.tick_budget;
+        config.malformed_dump = other.malformed_dump.clone();
+        config.spoofing = other.spoofing.clone();
+        config.peer_confidence = other.peer_confidence.clone();
+        config.probe = other.probe.clone();
+        config.max_duration = other.max_duration;
+        config.max_nodes = other.max_nodes;
+        config.idle_timeout = other.idle_timeout;
+        config
+    }
+
+    /// List the settings that differ from `other`, e.g. to log what a reload changes.
+    ///
+    /// The traffic limits are compared one by one, the other nested settings as a whole. The
+    /// geolocation is only compared by presence, and the cancellation token not at all.
+    pub fn diff(&self, other: &CrawlerConfig) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        let mut compare = |field, old: &dyn fmt::Debug, new: &dyn fmt::Debug| {
+            let (old, new) = (format!("{:?}", old), format!("{:?}", new));
+            if old != new {
+                changes.push(ConfigChange { field, old, new });
+            }
+        };
+        let (node, other_node) = (&self.node, &other.node);
+        compare("node.socket", &node.socket, &other_node.socket);
+        compare("node.node_id", &node.node_id, &other_node.node_id);
+        compare(
+            "node.query_timeout",
+            &node.query_timeout,
+            &other_node.query_timeout,
+        );
+        compare(
+            "node.poll_timeout",
+            &node.poll_timeout,
+            &other_node.poll_timeout,
+        );
+        compare("node.quirks", &node.quirks, &other_node.quirks);
+        compare(
+            "node.malformed_samples",
+            &node.malformed_samples,
+            &other_node.malformed_samples,
+        );
+        compare(
+            "node.malformed_logs_per_minute",
+            &node.malformed_logs_per_minute,
+            &other_node.malformed_logs_per_minute,
+        );
+        let (limits, other_limits) = (&node.limits, &other_node.limits);
+        compare(
+            "node.limits.max_queries",
+            &limits.max_queries,
+            &other_limits.max_queries,
+        );
+        compare(
+            "node.limits.max_queries_per_node_per_day",
+            &limits.max_queries_per_node_per_day,
+            &other_limits.max_queries_per_node_per_day,
+        );
+        compare(
+            "node.limits.max_bytes_per_second",
+            &limits.max_bytes_per_second,
+            &other_limits.max_bytes_per_second,
+        );
+        compare(
+            "node.limits.family_shares",
+            &limits.family_shares,
+            &other_limits.family_shares,
+        );
+        compare(
+            "node.limits.max_in_flight",
+            &limits.max_in_flight,
+            &other_limits.max_in_flight,
+        );
+        compare(
+            "node.limits.max_in_flight_per_destination",
+            &limits.max_in_flight_per_destination,
+            &other_limits.max_in_flight_per_destination,
+        );
+        compare(
+            "node.limits.opt_out",
+            &limits.opt_out,
+            &other_limits.opt_out,
+        );
+        compare("node.message", &node.message, &other_node.message);
+        compare("node.extensions", &node.extensions, &other_node.extensions);
+        compare("node.backoff", &node.backoff, &other_node.backoff);
+        compare(
+            "node.receive_queue",
+            &node.receive_queue,
+            &other_node.receive_queue,
+        );
+        compare("node.tokens", &node.tokens, &other_node.tokens);
+        compare("node.wiretap", &node.wiretap, &other_node.wiretap);
+        compare("node.replay", &node.replay, &other_node.replay);
+        compare("node.seed", &node.seed, &other_node.seed);
+        compare("node.duplicates", &node.duplicates, &other_node.duplicates);
+        compare(
+            "bootstrap_nodes",
+            &self.bootstrap_nodes,
+            &other.bootstrap_nodes,
+        );
+        compare("bootstrap", &self.bootstrap, &other.bootstrap);
+        compare("overlay", &self.overlay, &other.overlay);
+        compare("lookup_target", &self.lookup_target, &other.lookup_target);
+        compare("lookup_budget", &self.lookup_budget, &other.lookup_budget);
+        compare("scrape", &self.scrape, &other.scrape);
+        compare("tick_interval", &self.tick_interval, &other.tick_interval);
+        compare(
+            "pings_per_tick",
+            &self.pings_per_tick,
+            &other.pings_per_tick,
+        );
+        compare("tick_budget", &self.tick_budget, &other.tick_budget);
+        compare(
+            "geo_lookup",
+            &self.geo_lookup.is_some(),
+            &other.geo_lookup.is_some(),
+        );
+        compare(
+            "malformed_dump",
+            &self.malformed_dump,
+            &other.malformed_dump,
+        );
+        compare("seen", &self.seen, &other.seen);
+        compare("info_hashes", &self.info_hashes, &other.info_hashes);
+        compare("identities", &self.identities, &other.identities);
+        compare("port_rewrites", &self.port_rewrites, &other.port_rewrites);
+        compare("spoofing", &self.spoofing, &other.spoofing);
+        compare("peer_ports", &self.peer_ports, &other.peer_ports);
+        compare(
+            "peer_confidence",
+            &self.peer_confidence,
+            &other.peer_confidence,
+        );
+        compare(
+            "inbound_queries",
+            &self.inbound_queries,
+            &other.inbound_queries,
+        );
+        compare("probe", &self.probe, &other.probe);
+        compare("max_duration", &self.max_duration, &other.max_duration);
+        compare("max_nodes", &self.max_nodes, &other.max_nodes);
+        compare("idle_timeout", &self.idle_timeout, &other.idle_timeout);
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bitcrawler_proto::kademlia::Id160;
+
+    use super::*;
+    use crate::crawler::OverlayConfig;
+
+    #[test]
+    fn test_validate() {
+        let mut config = CrawlerConfig::new(Id160([1; 20]));
+        assert_eq!(config.validate(), Ok(()));
+
+        config.tick_interval = Duration::ZERO;
+        config.node.limits.max_bytes_per_second = Some(0);
+        config.node.limits.max_in_flight = Some(4);
+        config.node.limits.max_in_flight_per_destination = Some(8);
+        config.seen.false_positive_rate = 1.5;
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            errors.errors(),
+            [
+                ConfigError::Zero {
+                    field: "node.limits.max_bytes_per_second"
+                },
+                ConfigError::Zero {
+                    field: "tick_interval"
+                },
+                ConfigError::GreaterThan {
+                    field: "node.limits.max_in_flight_per_destination",
+                    limit: "node.limits.max_in_flight",
+                },
+                ConfigError::OutOfRange {
+                    field: "seen.false_positive_rate",
+                    value: "1.5".into(),
+                    expected: "between 0 and 1, exclusive",
+                },
+            ]
+        );
+        assert_eq!(
+            errors.to_string(),
+            "invalid configuration: node.limits.max_bytes_per_second must not be zero; \
+             tick_interval must not be zero; node.limits.max_in_flight_per_destination must \
+             not be greater than node.limits.max_in_flight; seen.false_positive_rate is 1.5, \
+             expected between 0 and 1, exclusive"
+        );
+        let error = io::Error::from(errors);
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let mut config = CrawlerConfig::new(Id160([1; 20]));
+        let mut overlay = OverlayConfig::new("my lab", vec![]);
+        overlay.secret = Some("".into());
+        config.overlay = Some(overlay);
+        assert_eq!(
+            config.validate().unwrap_err().errors(),
+            [
+                ConfigError::OutOfRange {
+                    field: "overlay.name",
+                    value: "\"my lab\"".into(),
+                    expected: "a name without whitespace or '#'",
+                },
+                ConfigError::Empty {
+                    field: "overlay.bootstrap_nodes"
+                },
+                ConfigError::Empty {
+                    field: "overlay.secret"
+                },
+            ]
+        );
+        config.overlay = Some(OverlayConfig::new(
+            "lab",
+            vec![DEFAULT_BOOTSTRAP_NODES[0].to_string()],
+        ));
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            format!(
+                "invalid configuration: overlay.bootstrap_nodes is {}, expected a node of the \
+                 overlay, not of the public DHT",
+                DEFAULT_BOOTSTRAP_NODES[0]
+            )
+        );
+
+        let mut config = CrawlerConfig::new(Id160([1; 20]));
+        config.peer_confidence.min_reporters = MAX_PEER_REPORTERS + 1;
+        assert_eq!(
+            config.validate().unwrap_err().errors(),
+            [ConfigError::OutOfRange {
+                field: "peer_confidence.min_reporters",
+                value: "17".into(),
+                expected: "between 1 and 16",
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let config = CrawlerConfig::new(Id160([1; 20]));
+        assert_eq!(config.diff(&config.clone()), vec![]);
+
+        let mut reloaded = config.clone();
+        reloaded.node.limits.max_bytes_per_second = Some(100_000);
+        reloaded.tick_interval = Duration::from_secs(5);
+        let changes = config.diff(&reloaded);
+        assert_eq!(
+            changes,
+            vec![
+                ConfigChange {
+                    field: "node.limits.max_bytes_per_second",
+                    old: "None".into(),
+                    new: "Some(100000)".into(),
+                },
+                ConfigChange {
+                    field: "tick_interval",
+                    old: "2s".into(),
+                    new: "5s".into(),
+                },
+            ]
+        );
+        assert_eq!(changes[1].to_string(), "tick_interval: 2s -> 5s");
+
+        let mut reloaded = config.clone();
+        reloaded.peer_ports.reject_privileged = true;
+        let fields: Vec<_> = config
+            .diff(&reloaded)
+            .into_iter()
+            .map(|change| change.field)
+            .collect();
+        assert_eq!(fields, ["peer_ports"]);
+    }
+}
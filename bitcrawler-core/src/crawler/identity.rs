@@ -0,0 +1,426 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bitcrawler_proto::kademlia::Id160;
+
+/// Configuration of an [`IdentityTracker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityConfig {
+    /// Time over which the id changes of an endpoint are counted.
+    pub window: Duration,
+    /// Number of id changes within the window tolerated from an endpoint (e.g. a restart, or
+    /// another node reusing the port behind a NAT). One more change flags the endpoint.
+    pub max_changes: usize,
+    /// Maximum number of endpoints tracked. The least recently seen ones are forgotten beyond
+    /// it.
+    pub capacity: usize,
+    /// File the history of the endpoints is kept in across runs, read on bind and written when
+    /// the crawl stops (see [`IdentityTracker::read`]). Without it, the history only covers the
+    /// current run.
+    pub history: Option<PathBuf>,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        IdentityConfig {
+            window: Duration::from_secs(60 * 60),
+            max_changes: 2,
+            capacity: 1 << 18,
+            history: None,
+        }
+    }
+}
+
+/// How the id reported by an endpoint compares to the previous ones, see
+/// [`IdentityTracker::observe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityCheck {
+    /// First id seen from the endpoint.
+    New,
+    /// Same id as the last time.
+    Same,
+    /// Another id than the last time, but the endpoint did not change its id too often.
+    Changed { previous: Id160 },
+    /// The endpoint changes its id too often: its ids cannot be trusted.
+    Flagged,
+}
+
+/// History of the ids reported by an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointHistory {
+    /// Last id reported.
+    pub id: Id160,
+    /// When the endpoint was first seen.
+    pub first_seen: Instant,
+    /// When the endpoint was last seen.
+    pub last_seen: Instant,
+    /// Number of id changes since the endpoint is tracked.
+    pub changes: u64,
+    // Time of the last changes, within the window (at most `max_changes + 1` of them).
+    recent_changes: VecDeque<Instant>,
+    stamp: u64,
+}
+
+impl EndpointHistory {
+    /// Check if the endpoint changed its id too often lately.
+    pub fn is_flagged(&self, config: &IdentityConfig, now: Instant) -> bool {
+        self.recent_changes
+            .iter()
+            .filter(|&&change| now.saturating_duration_since(change) < config.window)
+            .count()
+            > config.max_changes
+    }
+}
+
+/// Counters of an [`IdentityTracker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IdentityStats {
+    /// Endpoints tracked.
+    pub endpoints: usize,
+    /// Id changes seen, over all the endpoints.
+    pub id_changes: u64,
+    /// Replies received from endpoints flagged at the time.
+    pub flagged_replies: u64,
+}
+
+/// Tracks the node id each endpoint (IP address and port) reports over time, to spot the
+/// endpoints that change their id often: a node does not, an endpoint spoofing ids (e.g. to
+/// collect the traffic of several parts of the keyspace) does.
+///
+/// The history is kept in bounded memory, the least recently seen endpoints are forgotten
+/// first. It can be kept across runs in a file, see [`IdentityConfig::history`].
+#[derive(Debug, Clone)]
+pub struct IdentityTracker {
+    config: IdentityConfig,
+    endpoints: HashMap<SocketAddr, EndpointHistory>,
+    // Endpoints by recency stamp, oldest first.
+    by_stamp: BTreeMap<u64, SocketAddr>,
+    next_stamp: u64,
+    stats: IdentityStats,
+}
+
+impl IdentityTracker {
+    /// Create an empty tracker.
+    pub fn new(config: IdentityConfig) -> IdentityTracker {
+        IdentityTracker {
+            config,
+            endpoints: HashMap::new(),
+            by_stamp: BTreeMap::new(),
+            next_stamp: 0,
+            stats: IdentityStats::default(),
+        }
+    }
+
+    /// Record that `address` reported the id `id`, and check it against its history.
+    pub fn observe(&mut self, address: SocketAddr, id: Id160, now: Instant) -> IdentityCheck {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        let check = match self.endpoints.get_mut(&address) {
+            Some(history) => {
+                self.by_stamp.remove(&history.stamp);
+                history.stamp = stamp;
+                history.last_seen = now;
+                let previous = history.id;
+                if previous != id {
+                    history.id = id;
+                    history.changes += 1;
+                    self.stats.id_changes += 1;
+                    history.recent_changes.push_back(now);
+                    while history.recent_changes.len() > self.config.max_changes + 1 {
+                        history.recent_changes.pop_front();
+                    }
+                }
+                if history.is_flagged(&self.config, now) {
+                    self.stats.flagged_replies += 1;
+                    IdentityCheck::Flagged
+                } else if previous != id {
+                    IdentityCheck::Changed { previous }
+                } else {
+                    IdentityCheck::Same
+                }
+            }
+            None => {
+                self.insert(
+                    address,
+                    EndpointHistory {
+                        id,
+                        first_seen: now,
+                        last_seen: now,
+                        changes: 0,
+                        recent_changes: VecDeque::new(),
+                        stamp,
+                    },
+                );
+                return IdentityCheck::New;
+            }
+        };
+        self.by_stamp.insert(stamp, address);
+        check
+    }
+
+    /// Track a new endpoint, forgetting the least recently seen one if the tracker is full.
+    fn insert(&mut self, address: SocketAddr, history: EndpointHistory) {
+        if self.endpoints.len() >= self.config.capacity.max(1)
+            && let Some((_, oldest)) = self.by_stamp.pop_first()
+        {
+            self.endpoints.remove(&oldest);
+        }
+        self.by_stamp.insert(history.stamp, address);
+        self.endpoints.insert(address, history);
+    }
+
+    /// Get the history of `address`, if it is tracked.
+    pub fn get(&self, address: &SocketAddr) -> Option<&EndpointHistory> {
+        self.endpoints.get(address)
+    }
+
+    /// Check if `address` changed its id too often lately.
+    pub fn is_flagged(&self, address: &SocketAddr, now: Instant) -> bool {
+        self.endpoints
+            .get(address)
+            .is_some_and(|history| history.is_flagged(&self.config, now))
+    }
+
+    /// Get the counters of the tracker.
+    pub fn stats(&self) -> IdentityStats {
+        IdentityStats {
+            endpoints: self.endpoints.len(),
+            ..self.stats
+        }
+    }
+
+    /// Read the history written at `path` by [`IdentityTracker::write`]. A missing file is an
+    /// empty history.
+    ///
+    /// The times are kept as wall-clock times in the file: the changes of the previous runs
+    /// still count within the window. Only the last `capacity` endpoints seen are kept.
+    pub fn read<P: AsRef<Path>>(path: P, config: IdentityConfig) -> io::Result<IdentityTracker> {
+        let mut tracker = IdentityTracker::new(config);
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(tracker),
+            Err(e) => return Err(e),
+        };
+        let clock = Clock::now();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (address, mut history) = parse_endpoint(line, &clock).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {} ({:?})", number + 1, e, line),
+                )
+            })?;
+            history.stamp = tracker.next_stamp;
+            tracker.next_stamp += 1;
+            tracker.insert(address, history);
+        }
+        Ok(tracker)
+    }
+
+    /// Write the history to `path`, the least recently seen endpoints first, replacing the file
+    /// only once it is fully written.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let clock = Clock::now();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(
+            writer,
+            "# address id changes first_seen last_seen recent_changes"
+        )?;
+        for address in self.by_stamp.values() {
+            let history = &self.endpoints[address];
+            let recent: Vec<String> = history
+                .recent_changes
+                .iter()
+                .map(|&change| clock.unix(change).to_string())
+                .collect();
+            writeln!(
+                writer,
+                "{} {} {} {} {} {}",
+                address,
+                history.id,
+                history.changes,
+                clock.unix(history.first_seen),
+                clock.unix(history.last_seen),
+                if recent.is_empty() {
+                    "-".to_string()
+                } else {
+                    recent.join(",")
+                }
+            )?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// The monotonic and wall clocks at the same time, to convert the times of a history.
+struct Clock {
+    instant: Instant,
+    unix: u64,
+}
+
+impl Clock {
+    fn now() -> Clock {
+        let unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Clock {
+            instant: Instant::now(),
+            unix: unix.as_secs(),
+        }
+    }
+
+    /// Get `instant` in seconds since the Unix epoch.
+    fn unix(&self, instant: Instant) -> u64 {
+        let ago = self.instant.saturating_duration_since(instant);
+        self.unix.saturating_sub(ago.as_secs())
+    }
+
+    /// Get the instant of `unix` seconds since the Unix epoch, `None` if it is before the
+    /// earliest instant of the monotonic clock (e.g. the last boot).
+    fn instant(&self, unix: u64) -> Option<Instant> {
+        let ago = Duration::from_secs(self.unix.saturating_sub(unix));
+        self.instant.checked_sub(ago)
+    }
+}
+
+/// Parse a line of a history file. The times the monotonic clock cannot tell are too old to
+/// matter: the changes are dropped, and the other times become the current time.
+fn parse_endpoint(
+    line: &str,
+    clock: &Clock,
+) -> Result<(SocketAddr, EndpointHistory), &'static str> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [address, id, changes, first_seen, last_seen, recent_changes] = fields[..] else {
+        return Err("expected 6 fields");
+    };
+    let time = |field: &str| field.parse().map(|unix| clock.instant(unix));
+    let recent_changes = match recent_changes {
+        "-" => VecDeque::new(),
+        changes => changes
+            .split(',')
+            .filter_map(|change| time(change).transpose())
+            .collect::<Result<_, _>>()
+            .map_err(|_| "invalid recent changes")?,
+    };
+    let history = EndpointHistory {
+        id: id.parse()?,
+        first_seen: time(first_seen)
+            .map_err(|_| "invalid first seen")?
+            .unwrap_or(clock.instant),
+        last_seen: time(last_seen)
+            .map_err(|_| "invalid last seen")?
+            .unwrap_or(clock.instant),
+        changes: changes.parse().map_err(|_| "invalid changes")?,
+        recent_changes,
+        stamp: 0,
+    };
+    Ok((address.parse().map_err(|_| "invalid address")?, history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frequent_id_changes_are_flagged() {
+        let start = Instant::now();
+        let mut tracker = IdentityTracker::new(IdentityConfig {
+            window: Duration::from_secs(60),
+            max_changes: 1,
+            capacity: 2,
+            history: None,
+        });
+        let a: SocketAddr = ([192, 0, 2, 1], 6881).into();
+        let id = |i: u8| Id160([i; 20]);
+
+        assert_eq!(tracker.observe(a, id(1), start), IdentityCheck::New);
+        assert_eq!(tracker.observe(a, id(1), start), IdentityCheck::Same);
+        // A restart is tolerated.
+        assert_eq!(
+            tracker.observe(a, id(2), start),
+            IdentityCheck::Changed { previous: id(1) }
+        );
+        assert!(!tracker.is_flagged(&a, start));
+        assert_eq!(tracker.observe(a, id(3), start), IdentityCheck::Flagged);
+        // The endpoint stays flagged while its changes are recent, even with a stable id.
+        assert_eq!(tracker.observe(a, id(3), start), IdentityCheck::Flagged);
+        let later = start + Duration::from_secs(61);
+        assert!(!tracker.is_flagged(&a, later));
+        assert_eq!(tracker.observe(a, id(3), later), IdentityCheck::Same);
+        assert_eq!(tracker.get(&a).unwrap().changes, 2);
+
+        // The least recently seen endpoint is forgotten first.
+        let b: SocketAddr = ([192, 0, 2, 2], 6881).into();
+        let c: SocketAddr = ([192, 0, 2, 3], 6881).into();
+        tracker.observe(b, id(4), later);
+        tracker.observe(a, id(3), later);
+        tracker.observe(c, id(5), later);
+        assert!(tracker.get(&b).is_none());
+        assert_eq!(
+            tracker.stats(),
+            IdentityStats {
+                endpoints: 2,
+                id_changes: 2,
+                flagged_replies: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_history_across_runs() {
+        let path =
+            std::env::temp_dir().join(format!("bitcrawler-identities-{}.txt", std::process::id()));
+        let config = IdentityConfig {
+            max_changes: 1,
+            capacity: 2,
+            ..IdentityConfig::default()
+        };
+        let now = Instant::now();
+        let mut tracker = IdentityTracker::new(config.clone());
+        let a: SocketAddr = ([192, 0, 2, 1], 6881).into();
+        let b: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let c: SocketAddr = ([192, 0, 2, 3], 6881).into();
+        for i in 1..=3 {
+            tracker.observe(a, Id160([i; 20]), now);
+        }
+        tracker.observe(b, Id160([4; 20]), now);
+        tracker.write(&path).unwrap();
+
+        // The endpoint flagged in the previous run is still flagged.
+        let mut read = IdentityTracker::read(&path, config.clone()).unwrap();
+        let now = Instant::now();
+        assert!(read.is_flagged(&a, now));
+        assert_eq!(read.get(&a).unwrap().changes, 2);
+        assert_eq!(read.get(&b).unwrap().id, Id160([4; 20]));
+        // The least recently seen endpoint is still forgotten first.
+        read.observe(c, Id160([5; 20]), now);
+        assert!(read.get(&a).is_none());
+        assert!(read.get(&b).is_some());
+
+        fs::write(&path, "192.0.2.1:6881 nope 0 0 0 -").unwrap();
+        let error = IdentityTracker::read(&path, config.clone()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            IdentityTracker::read(&path, config)
+                .unwrap()
+                .stats()
+                .endpoints,
+            0
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::Path,
+    str::FromStr,
+};
+
+use bitcrawler_proto::{
+    bencode::{self, BencodeValue},
+    consts::{COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN, ID_LEN},
+    kademlia::Id160,
+    krpc::peer_info::CompactPeerInfo,
+};
+
+use crate::node::dict_value;
+
+/// Format of the DHT state file of another client, see [`import_dht_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateFormat {
+    /// The DHT state of libtorrent (qBittorrent, Deluge...): a dictionary with a `node-id`
+    /// (a string, or a list of id and address strings since libtorrent 1.2) and `nodes` and
+    /// `nodes6` lists of compact addresses, on its own or under the `dht state` key of the
+    /// session state.
+    Libtorrent,
+    /// The `dht.dat` of Transmission: a dictionary with an `id` and `nodes` and `nodes6`
+    /// strings of concatenated compact addresses.
+    Transmission,
+}
+
+impl StateFormat {
+    /// Get the name of the format.
+    pub fn name(self) -> &'static str {
+        match self {
+            StateFormat::Libtorrent => "libtorrent",
+            StateFormat::Transmission => "transmission",
+        }
+    }
+
+    /// Detect the format of the decoded state file `state`, `None` if it is neither.
+    pub fn detect(state: &BencodeValue) -> Option<StateFormat> {
+        if libtorrent_state(state).is_some() {
+            return Some(StateFormat::Libtorrent);
+        }
+        match (dict_value(state, b"id"), dict_value(state, b"nodes")) {
+            (Some(BencodeValue::ByteString(_)), Some(BencodeValue::ByteString(_)) | None) => {
+                Some(StateFormat::Transmission)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for StateFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for StateFormat {
+    type Err = &'static str;
+
+    /// Parse `libtorrent` or `transmission`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "libtorrent" => Ok(StateFormat::Libtorrent),
+            "transmission" => Ok(StateFormat::Transmission),
+            _ => Err("expected libtorrent or transmission"),
+        }
+    }
+}
+
+/// The nodes of the DHT state file of another client, to warm-start a crawl from, see
+/// [`import_dht_state`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedState {
+    /// Format of the file.
+    pub format: StateFormat,
+    /// Id of the node of the client, if the file has a valid one.
+    pub node_id: Option<Id160>,
+    /// Addresses of the nodes the client knew, IPv4 first. The files keep no id for them.
+    pub nodes: Vec<SocketAddr>,
+    /// Entries skipped because they are not a compact address.
+    pub skipped: usize,
+}
+
+/// Read the DHT state file of another client at `path` (see [`StateFormat`]), to seed the
+/// contacts of a crawler with its nodes (see [`Crawler::add_contacts`]).
+///
+/// The format is detected from the content unless `format` is set. The files keep the
+/// addresses of the nodes but not their ids: the nodes join the routing table once they
+/// answer, as any contact. The invalid entries are skipped and counted.
+///
+/// [`Crawler::add_contacts`]: super::Crawler::add_contacts
+pub fn import_dht_state<P: AsRef<Path>>(
+    path: P,
+    format: Option<StateFormat>,
+) -> io::Result<ImportedState> {
+    let data = fs::read(path)?;
+    parse_dht_state(&data, format).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parse the content of a DHT state file, see [`import_dht_state`].
+pub fn parse_dht_state(
+    data: &[u8],
+    format: Option<StateFormat>,
+) -> Result<ImportedState, &'static str> {
+    let (_, state) = bencode::decode(&data).map_err(|e| e.message())?;
+    let format = match format {
+        Some(format) => format,
+        None => StateFormat::detect(&state).ok_or("unknown DHT state format")?,
+    };
+    let mut imported = ImportedState {
+        format,
+        node_id: None,
+        nodes: Vec::new(),
+        skipped: 0,
+    };
+    match format {
+        StateFormat::Libtorrent => {
+            let state = libtorrent_state(&state).ok_or("not a libtorrent DHT state")?;
+            // A list of the ids of the node per interface since libtorrent 1.2, each followed
+            // by the address of the interface.
+            let node_id = match dict_value(state, b"node-id") {
+                Some(BencodeValue::List(ids)) => ids.first(),
+                node_id => node_id,
+            };
+            imported.node_id = match node_id {
+                Some(BencodeValue::ByteString(id)) => id
+                    .as_ref()
+                    .get(..ID_LEN)
+                    .and_then(|id| Id160::try_from(id).ok()),
+                _ => None,
+            };
+            for key in [&b"nodes"[..], b"nodes6"] {
+                let Some(BencodeValue::List(nodes)) = dict_value(state, key) else {
+                    continue;
+                };
+                for node in nodes {
+                    match node {
+                        BencodeValue::ByteString(node) => match read_address(node.as_ref()) {
+                            Some(address) => imported.nodes.push(address),
+                            None => imported.skipped += 1,
+                        },
+                        _ => imported.skipped += 1,
+                    }
+                }
+            }
+        }
+        StateFormat::Transmission => {
+            imported.node_id = match dict_value(&state, b"id") {
+                Some(BencodeValue::ByteString(id)) => Id160::try_from(id.as_ref()).ok(),
+                Some(_) => None,
+                None => return Err("not a Transmission DHT state"),
+            };
+            for (key, len) in [
+                (&b"nodes"[..], COMPACT_PEER_V4_LEN),
+                (b"nodes6", COMPACT_PEER_V6_LEN),
+            ] {
+                let Some(BencodeValue::ByteString(nodes)) = dict_value(&state, key) else {
+                    continue;
+                };
+                let chunks = nodes.as_ref().chunks_exact(len);
+                imported.skipped += usize::from(!chunks.remainder().is_empty());
+                imported.nodes.extend(chunks.filter_map(read_address));
+            }
+        }
+    }
+    Ok(imported)
+}
+
+/// Get the dictionary of a libtorrent DHT state: the state itself, or the `dht state` of a
+/// session state.
+fn libtorrent_state(state: &BencodeValue) -> Option<&BencodeValue> {
+    let state = dict_value(state, b"dht state").unwrap_or(state);
+    dict_value(state, b"node-id").map(|_| state)
+}
+
+/// Read a compact address of either family, by its length.
+fn read_address(data: &[u8]) -> Option<SocketAddr> {
+    match data.len() {
+        COMPACT_PEER_V4_LEN => SocketAddrV4::try_read_compact_peer_info(data)
+            .ok()
+            .map(|(_, address)| address.into()),
+        COMPACT_PEER_V6_LEN => SocketAddrV6::try_read_compact_peer_info(data)
+            .ok()
+            .map(|(_, address)| address.into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn string(bytes: &[u8]) -> BencodeValue {
+        BencodeValue::ByteString(bytes.to_vec().into())
+    }
+
+    #[test]
+    fn test_import_dht_state() {
+        let v4: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        let compact = |address: &SocketAddr| match address {
+            SocketAddr::V4(address) => address.write_compact_peer_info(),
+            SocketAddr::V6(address) => address.write_compact_peer_info(),
+        };
+
+        // libtorrent 1.2 and later, as saved in the session state.
+        let libtorrent = BencodeValue::from_dict(vec![(
+            "dht state",
+            BencodeValue::from_dict(vec![
+                (
+                    "node-id",
+                    BencodeValue::from_list(vec![string(&[[1; 20], [0; 20]].concat()[..24])]),
+                ),
+                (
+                    "nodes",
+                    BencodeValue::from_list(vec![string(&compact(&v4)), string(b"short")]),
+                ),
+                (
+                    "nodes6",
+                    BencodeValue::from_list(vec![string(&compact(&v6))]),
+                ),
+            ]),
+        )]);
+        let libtorrent = bencode::encode(&libtorrent);
+        let imported = parse_dht_state(&libtorrent, None).unwrap();
+        assert_eq!(
+            imported,
+            ImportedState {
+                format: StateFormat::Libtorrent,
+                node_id: Some(Id160([1; 20])),
+                nodes: vec![v4, v6],
+                skipped: 1,
+            }
+        );
+        // libtorrent 1.1, on its own.
+        let legacy = BencodeValue::from_dict(vec![
+            ("node-id", string(&[2; 20])),
+            (
+                "nodes",
+                BencodeValue::from_list(vec![string(&compact(&v4))]),
+            ),
+        ]);
+        let imported = parse_dht_state(&bencode::encode(&legacy), None).unwrap();
+        assert_eq!(imported.node_id, Some(Id160([2; 20])));
+        assert_eq!(imported.nodes, [v4]);
+
+        // Transmission, with a partial address at the end of `nodes`.
+        let transmission = BencodeValue::from_dict(vec![
+            ("id", string(&[3; 20])),
+            (
+                "nodes",
+                string(&[compact(&v4), compact(&v4), vec![0; 3]].concat()),
+            ),
+            ("nodes6", string(&compact(&v6))),
+        ]);
+        let path = env::temp_dir().join(format!("bitcrawler-dht-{}.dat", process::id()));
+        fs::write(&path, bencode::encode(&transmission)).unwrap();
+        let imported = import_dht_state(&path, None).unwrap();
+        assert_eq!(
+            imported,
+            ImportedState {
+                format: StateFormat::Transmission,
+                node_id: Some(Id160([3; 20])),
+                nodes: vec![v4, v4, v6],
+                skipped: 1,
+            }
+        );
+        // A forced format must match the content.
+        let error = import_dht_state(&path, Some(StateFormat::Libtorrent)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            parse_dht_state(b"d5:nodeslee", None),
+            Err("unknown DHT state format")
+        );
+        assert!(parse_dht_state(b"not bencode", None).is_err());
+        assert_eq!("transmission".parse(), Ok(StateFormat::Transmission));
+        assert!("utorrent".parse::<StateFormat>().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{
+    consts::K,
+    kademlia::{Id160, Target},
+};
+
+use crate::node::DhtNode;
+
+/// Number of nodes a triggered lookup starts from.
+pub(super) const LOOKUP_START_NODES: usize = K;
+/// Time after which a triggered lookup is forgotten, its late replies are crawled as usual.
+const LOOKUP_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A lookup triggered through [`CrawlerHandle::lookup`](super::CrawlerHandle::lookup): walks
+/// toward its target alongside the crawl, with `find_node` queries for a node id and `get_peers`
+/// queries for an info hash.
+///
+/// Each node that answers is followed by asking the nodes it returns that are closer to the
+/// target than itself, within a budget of packets (the queries sent and the replies received,
+/// see [`CrawlerConfig::lookup_budget`](super::CrawlerConfig::lookup_budget)).
+#[derive(Debug, Clone)]
+pub(super) struct TriggeredLookup {
+    target: Target,
+    queried: HashSet<SocketAddr>,
+    started: Instant,
+    budget: usize,
+    packets: usize,
+}
+
+impl TriggeredLookup {
+    pub(super) fn new(target: Target, budget: usize, now: Instant) -> TriggeredLookup {
+        TriggeredLookup {
+            target,
+            queried: HashSet::new(),
+            started: now,
+            budget,
+            packets: 0,
+        }
+    }
+
+    pub(super) fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Check if `address` was asked by the lookup.
+    pub(super) fn has_queried(&self, address: &SocketAddr) -> bool {
+        self.queried.contains(address)
+    }
+
+    /// Get the number of packets the lookup cost so far.
+    pub(super) fn packets(&self) -> usize {
+        self.packets
+    }
+
+    /// Pick the nodes to ask among `addresses`, not asked yet, within the packet budget.
+    pub(super) fn next<I>(&mut self, addresses: I) -> Vec<SocketAddr>
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let mut next = Vec::new();
+        for address in addresses {
+            if self.packets >= self.budget {
+                break;
+            }
+            if self.queried.insert(address) {
+                self.packets += 1;
+                next.push(address);
+            }
+        }
+        next
+    }
+
+    /// Count the reply of `sender`, and pick the nodes to ask among the `nodes` it returned:
+    /// the ones closer to the target than the sender.
+    pub(super) fn follow(
+        &mut self,
+        sender: &Id160,
+        nodes: &[(Id160, SocketAddr)],
+    ) -> Vec<SocketAddr> {
+        self.packets += 1;
+        let target = *self.target.id();
+        let distance = sender.distance(&target);
+        let closer = nodes
+            .iter()
+            .filter(|(id, _)| id.distance(&target) < distance)
+            .map(|(_, address)| *address);
+        self.next(closer)
+    }
+
+    /// Check if the lookup is over: out of packets, or past its lifetime.
+    pub(super) fn is_over(&self, now: Instant) -> bool {
+        self.packets >= self.budget
+            || now.saturating_duration_since(self.started) >= LOOKUP_LIFETIME
+    }
+}
+
+/// Send the query of a lookup toward `target` to `destination`.
+pub(super) fn send_lookup_query(
+    node: &mut DhtNode,
+    destination: SocketAddr,
+    target: Target,
+) -> io::Result<()> {
+    match target {
+        Target::Node(id) => node.find_node(destination, id),
+        Target::InfoHash(info_hash) => node.get_peers(destination, info_hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_follow() {
+        let now = Instant::now();
+        let mut lookup = TriggeredLookup::new(Target::InfoHash(Id160([0; 20])), 64, now);
+        let address = |i: u8| SocketAddr::from(([192, 0, 2, i], 6881));
+        assert_eq!(
+            lookup.next([address(1), address(2)]),
+            [address(1), address(2)]
+        );
+        assert!(lookup.has_queried(&address(1)));
+
+        let node = |i: u8| (Id160([i; 20]), address(i));
+        // Only the nodes closer than the sender, not asked yet, are followed.
+        assert_eq!(
+            lookup.follow(&Id160([4; 20]), &[node(2), node(3), node(5)]),
+            [address(3)]
+        );
+
+        // Three queries sent, one reply received.
+        assert_eq!(lookup.packets(), 4);
+
+        assert!(!lookup.is_over(now));
+        assert!(lookup.is_over(now + LOOKUP_LIFETIME));
+        let next = lookup.next((0..=255).map(|port| SocketAddr::from(([198, 51, 100, 1], port))));
+        assert_eq!(next.len(), 64 - 4);
+        assert!(lookup.is_over(now));
+        assert_eq!(lookup.next([address(9)]), []);
+    }
+}
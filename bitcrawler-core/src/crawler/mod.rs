@@ -0,0 +1,1957 @@
+//! The crawler engine: discovers DHT nodes by pinging known contacts and asking the nodes that
+//! answer for more nodes.
+
+mod bootstrap;
+mod confidence;
+mod config;
+mod identity;
+mod import;
+mod lookup;
+mod overlay;
+mod probe;
+mod rewrite;
+mod seen;
+mod snapshot;
+mod spoofing;
+mod stop;
+mod summary;
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bitcrawler_proto::{
+    kademlia::{Id160, Node, PUBLIC_MAX_NODES_PER_HOST, RoutingTable, Target},
+    krpc::{PortPolicy, QueryType, ResponseType, query::QUERY_TYPE_PING},
+};
+
+use crate::{
+    indexer::ScrapeFilter,
+    node::{DhtNode, DuplicatePolicy, NodeConfig, NodeEvent, is_bogon, reply_nodes},
+    responder::{InboundQueryConfig, InboundQueryStats, QueryKind},
+    sink::{CrawlEvent, DiscoveredPeer, EventCounts, EventStream, Sink},
+    transport::SocketReport,
+    watchdog::Heartbeat,
+};
+pub use bootstrap::*;
+pub use confidence::*;
+pub use config::*;
+pub use identity::*;
+pub use import::*;
+use lookup::{LOOKUP_START_NODES, TriggeredLookup, send_lookup_query};
+pub use overlay::*;
+pub use probe::*;
+pub use rewrite::*;
+pub use seen::*;
+pub use snapshot::*;
+pub use spoofing::*;
+pub use stop::*;
+pub use summary::*;
+
+/// Bootstrap nodes used when no contact is known.
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &["77.234.80.66:29822"];
+
+/// Interval between two updates of the progress read by [`CrawlerHandle::snapshot`]: an update
+/// reads the drop counters of the sockets and takes the progress lock, too costly for every
+/// datagram.
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Resolves the country of an IP address, to break crawl statistics down per country.
+pub trait GeoLookup: Send + Sync {
+    /// Get the country code (e.g. ISO 3166-1 alpha-2) of `ip`, if known.
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// Configuration of a [`Crawler`].
+#[derive(Clone)]
+pub struct CrawlerConfig {
+    /// Node the crawler queries from.
+    pub node: NodeConfig,
+    /// Nodes (`host:port`) contacted when no other contact is known. Ignored in a private
+    /// overlay, see [`CrawlerConfig::overlay`].
+    pub bootstrap_nodes: Vec<String>,
+    /// Selection of the bootstrap nodes pinged, by their past success.
+    pub bootstrap: BootstrapConfig,
+    /// Private DHT to crawl instead of the public one, if any: the crawl starts from the
+    /// bootstrap nodes of the overlay.
+    ///
+    /// With a shared secret, the messages of the node carry the admission tags of the overlay
+    /// (see [`OverlayAdmission`]), only the nodes holding it are answered and learned, and the
+    /// routing table only takes the nodes of the overlay (see [`RoutingTable::set_domain`]).
+    /// Without one, the overlay is only kept apart by its contacts: the crawler must not be
+    /// given nodes of the public DHT (see [`Crawler::add_contacts`]).
+    pub overlay: Option<OverlayConfig>,
+    /// Info hash of the `get_peers` queries sent to the nodes that answer a ping.
+    pub lookup_target: Id160,
+    /// Packets (queries sent and replies received) a lookup triggered through
+    /// [`CrawlerHandle::lookup`] may cost, so that a lookup in a pathological region of the
+    /// keyspace cannot take over the traffic of the crawl.
+    pub lookup_budget: usize,
+    /// Ask for the swarm size Bloom filters of BEP 33 in the `get_peers` queries, reported as
+    /// [`CrawlEvent::ScrapeReceived`].
+    pub scrape: bool,
+    /// Interval between two rounds of pings.
+    pub tick_interval: Duration,
+    /// Maximum number of contacts pinged per round.
+    pub pings_per_tick: usize,
+    /// Time a round may spend sending its pings (e.g. stalled by the traffic limits of the
+    /// node, or resolving the bootstrap nodes), the contacts left are pinged in the next
+    /// rounds. Not limited if `None`.
+    pub tick_budget: Option<Duration>,
+    /// Country resolution, enables the per-country counts of [`CrawlSnapshot`].
+    pub geo_lookup: Option<Arc<dyn GeoLookup>>,
+    /// File the malformed datagram samples are dumped to, rewritten on the ticks where new
+    /// malformed datagrams were received (see [`MalformedLog::dump`]).
+    ///
+    /// [`MalformedLog::dump`]: crate::node::MalformedLog::dump
+    pub malformed_dump: Option<PathBuf>,
+    /// Memory budget and precision of the set of the node ids seen.
+    pub seen: SeenSetConfig,
+    /// Memory budget and precision of the set of the info hashes seen (in the peers found, the
+    /// scrapes, and the `get_peers` and `announce_peer` queries received).
+    pub info_hashes: SeenSetConfig,
+    /// Tracking of the ids reported by each endpoint, see [`IdentityTracker`]. The ids an
+    /// endpoint changing its id too often claims for itself are ignored, the nodes it returns
+    /// are contacted after the others, and its node in the routing table is the last one a
+    /// lookup starts from.
+    pub identities: IdentityConfig,
+    /// Comparison of the addresses nodes reply from with the addresses other nodes give for
+    /// them, see [`PortRewriteTracker`].
+    pub port_rewrites: PortRewriteConfig,
+    /// Detection of the senders returning node ids made up next to the target of the query,
+    /// see [`SpoofingDetector`].
+    pub spoofing: SpoofingConfig,
+    /// Ports of the peers reported in [`CrawlEvent::PeersFound`], the others are dropped (port
+    /// 0 always is).
+    pub peer_ports: PortPolicy,
+    /// Corroboration the peers need to be reported in [`CrawlEvent::PeersFound`], see
+    /// [`PeerCorroboration`]. The confidence of each peer is reported along with it.
+    pub peer_confidence: PeerConfidenceConfig,
+    /// Grouping of the queries received by source prefix, see
+    /// [`CrawlSnapshot::inbound_queries`].
+    pub inbound_queries: InboundQueryConfig,
+    /// Probing of the discovered nodes for the DHT extensions they support, reported as
+    /// [`CrawlEvent::CapabilitiesProbed`] and in [`CrawlSnapshot::capabilities`]. Disabled if
+    /// `None`.
+    pub probe: Option<ProbeConfig>,
+    /// Maximum duration of [`Crawler::run`]. The queries of the crawl time out at the end of
+    /// the run at the latest (see [`DhtNode::set_deadline`]).
+    pub max_duration: Option<Duration>,
+    /// Number of distinct nodes seen (see [`CrawlSnapshot::nodes_seen`]) after which the crawl
+    /// stops.
+    pub max_nodes: Option<usize>,
+    /// Stop the crawl once no contact is left to ping and no node was discovered for this
+    /// long: the crawl only goes back to the bootstrap nodes, the reachable part of the DHT
+    /// is exhausted.
+    pub idle_timeout: Option<Duration>,
+    /// Token stopping the crawl once cancelled, like [`CrawlerHandle::stop`] but shareable
+    /// with other components.
+    pub cancel: Option<CancellationToken>,
+}
+
+impl CrawlerConfig {
+    /// Create a configuration with the default settings for the given node id.
+    ///
+    /// The crawler listens on 0.0.0.0:6881 and looks up a random-looking fixed target. It does
+    /// not answer queries, so its queries are flagged read-only (BEP 43).
+    pub fn new(node_id: Id160) -> CrawlerConfig {
+        let mut node = NodeConfig::new(node_id);
+        node.message.read_only = true;
+        CrawlerConfig {
+            node,
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            bootstrap: BootstrapConfig::default(),
+            overlay: None,
+            lookup_target: Id160([
+                0x00, 0xab, 0xb5, 0xd1, 0x2f, 0xb0, 0x3c, 0x7e, 0xe2, 0x88, 0x76, 0x78, 0x9c, 0x43,
+                0xeb, 0xe2, 0x6d, 0x36, 0xe0, 0xa1,
+            ]),
+            lookup_budget: 128,
+            scrape: false,
+            tick_interval: Duration::from_secs(2),
+            pings_per_tick: 40,
+            tick_budget: None,
+            geo_lookup: None,
+            malformed_dump: None,
+            seen: SeenSetConfig::default(),
+            info_hashes: SeenSetConfig {
+                memory_budget: 8 << 20,
+                expected_items: 1_000_000,
+                ..SeenSetConfig::default()
+            },
+            identities: IdentityConfig::default(),
+            port_rewrites: PortRewriteConfig::default(),
+            spoofing: SpoofingConfig::default(),
+            peer_ports: PortPolicy::default(),
+            peer_confidence: PeerConfidenceConfig::default(),
+            inbound_queries: InboundQueryConfig::default(),
+            probe: None,
+            max_duration: None,
+            max_nodes: None,
+            idle_timeout: None,
+            cancel: None,
+        }
+    }
+
+    /// Get the bootstrap nodes in use: those of the overlay if the crawl runs in one, the
+    /// [`CrawlerConfig::bootstrap_nodes`] otherwise.
+    pub fn bootstrap_hosts(&self) -> &[String] {
+        match &self.overlay {
+            Some(overlay) => &overlay.bootstrap_nodes,
+            None => &self.bootstrap_nodes,
+        }
+    }
+}
+
+/// State shared by a [`Crawler`] and its [`CrawlerHandle`]s.
+struct Shared {
+    running: AtomicBool,
+    paused: AtomicBool,
+    progress: Mutex<Progress>,
+    // Configuration in effect, reloads included.
+    config: Mutex<CrawlerConfig>,
+    requests: Mutex<Requests>,
+}
+
+/// Changes queued by the [`CrawlerHandle`]s, applied by the crawler loop.
+#[derive(Default)]
+struct Requests {
+    config: Option<CrawlerConfig>,
+    sinks: Option<SinkReplacement>,
+    lookups: Vec<Target>,
+    // Peers a handshake succeeded with, by info hash.
+    confirmed_peers: Vec<(Id160, SocketAddr)>,
+}
+
+/// Opens the new sinks of the crawler, see [`CrawlerHandle::replace_sinks_with`].
+type OpenSinks = Box<dyn FnOnce() -> io::Result<Vec<Box<dyn Sink>>> + Send>;
+
+/// New sinks of the crawler, see [`CrawlerHandle::replace_sinks`].
+enum SinkReplacement {
+    Sinks(Vec<Box<dyn Sink>>),
+    // Opened once the previous sinks are closed.
+    Open(OpenSinks),
+}
+
+/// A cloneable handle to observe and stop a running [`Crawler`].
+#[derive(Clone)]
+pub struct CrawlerHandle {
+    shared: Arc<Shared>,
+}
+
+impl CrawlerHandle {
+    /// Get a snapshot of the progress of the crawl, updated every 100 ms while it runs.
+    pub fn snapshot(&self) -> CrawlSnapshot {
+        let snapshot = self
+            .shared
+            .progress
+            .lock()
+            .expect("crawler progress lock poisoned")
+            .snapshot(Instant::now());
+        CrawlSnapshot {
+            paused: self.is_paused(),
+            ..snapshot
+        }
+    }
+
+    /// Get the routing table of the crawler: the nodes that answered, closest to the id of the
+    /// crawler first. It is published on every round of pings.
+    ///
+    /// The nodes at bogon addresses (see [`is_bogon`]) are left out, unless the crawler is
+    /// bound to a bogon address itself, e.g. to crawl a local network.
+    pub fn routing_table(&self) -> Vec<RoutingEntry> {
+        self.shared
+            .progress
+            .lock()
+            .expect("crawler progress lock poisoned")
+            .routing_table
+            .clone()
+    }
+
+    /// Pause the crawl: no contact is pinged until [`CrawlerHandle::resume`]. The queries in
+    /// flight are still handled, and triggered lookups still run.
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resume a paused crawl.
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Check if the crawl is paused.
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::Relaxed)
+    }
+
+    /// Look up `target` alongside the crawl: ask the nodes of the routing table closest to it
+    /// (or the bootstrap nodes), then the closer nodes they return.
+    ///
+    /// A node id ([`Target::Node`]) is looked up with `find_node` queries, an
+    /// [`InfoHash`](bitcrawler_proto::kademlia::InfoHash) ([`Target::InfoHash`]) with
+    /// `get_peers` queries: the peers found are reported to the sinks as
+    /// [`CrawlEvent::PeersFound`]. The nodes discovered are crawled as usual.
+    pub fn lookup<T: Into<Target>>(&self, target: T) {
+        self.shared
+            .requests
+            .lock()
+            .expect("crawler requests lock poisoned")
+            .lookups
+            .push(target.into());
+    }
+
+    /// Report that a handshake with `peer` for `info_hash` succeeded, e.g. while fetching the
+    /// metadata of the info hash (see [`Indexer::mark_fetched_from`]): the peer is reported to
+    /// the sinks whenever it is returned from then on, and with a higher confidence, see
+    /// [`PeerCorroboration::confirm`].
+    ///
+    /// [`Indexer::mark_fetched_from`]: crate::indexer::Indexer::mark_fetched_from
+    pub fn confirm_peer(&self, info_hash: Id160, peer: SocketAddr) {
+        self.shared
+            .requests
+            .lock()
+            .expect("crawler requests lock poisoned")
+            .confirmed_peers
+            .push((info_hash, peer));
+    }
+
+    /// Ask the crawler to stop, [`Crawler::run`] returns shortly after.
+    pub fn stop(&self) {
+        self.shared.running.store(false, Ordering::Relaxed);
+    }
+
+    /// Check if the crawler has not been asked to stop.
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::Relaxed)
+    }
+
+    /// Apply a new configuration to the running crawl, e.g. a configuration file read again
+    /// on `SIGHUP`, and get the settings it changes.
+    ///
+    /// Only the settings that do not need a new socket or a new crawl state are applied: the
+    /// traffic limits, opt-out list and duplicate policy of the node, the bootstrap nodes
+    /// (those of the overlay too, unless the overlay is another one), the lookup target and
+    /// scraping, the pace of the pings, the malformed datagram dump, the thresholds of the
+    /// spoofing detection and of the peer corroboration, the capability probes (their target
+    /// follows the lookup target unless set), and the stop conditions
+    /// (maximum duration, still counted from the start of the crawl, maximum number of nodes
+    /// and idle timeout). The other settings are kept as they are.
+    /// The contacts, the nodes seen and the queries in flight are kept.
+    ///
+    /// Nothing is applied if `config` is invalid, see [`CrawlerConfig::validate`].
+    pub fn reload(&self, config: CrawlerConfig) -> Result<Vec<ConfigChange>, ConfigErrors> {
+        config.validate()?;
+        let mut current = self
+            .shared
+            .config
+            .lock()
+            .expect("crawler config lock poisoned");
+        let reloaded = current.with_reloadable(&config);
+        let changes = current.diff(&reloaded);
+        if !changes.is_empty() {
+            *current = reloaded.clone();
+            self.shared
+                .requests
+                .lock()
+                .expect("crawler requests lock poisoned")
+                .config = Some(reloaded);
+        }
+        Ok(changes)
+    }
+
+    /// Get the configuration in effect, reloads included.
+    pub fn config(&self) -> CrawlerConfig {
+        self.shared
+            .config
+            .lock()
+            .expect("crawler config lock poisoned")
+            .clone()
+    }
+
+    /// Replace the sinks of the running crawler, e.g. to write the discoveries to another
+    /// file. The current sinks are flushed and dropped first; the events of the crawl go to
+    /// `sinks` from the next iteration of the crawler loop on.
+    ///
+    /// The new sinks are opened before the current ones are closed: to write to the same
+    /// files, see [`CrawlerHandle::replace_sinks_with`].
+    pub fn replace_sinks(&self, sinks: Vec<Box<dyn Sink>>) {
+        self.shared
+            .requests
+            .lock()
+            .expect("crawler requests lock poisoned")
+            .sinks = Some(SinkReplacement::Sinks(sinks));
+    }
+
+    /// Replace the sinks of the running crawler by the ones `open` returns, as
+    /// [`CrawlerHandle::replace_sinks`], opening them on the crawler thread once the current
+    /// sinks are flushed and dropped: the new sinks may reopen the files of the current ones.
+    ///
+    /// An error returned by `open` stops the crawl, as an error of a sink does.
+    pub fn replace_sinks_with<F>(&self, open: F)
+    where
+        F: FnOnce() -> io::Result<Vec<Box<dyn Sink>>> + Send + 'static,
+    {
+        self.shared
+            .requests
+            .lock()
+            .expect("crawler requests lock poisoned")
+            .sinks = Some(SinkReplacement::Open(Box::new(open)));
+    }
+}
+
+/// Crawl state owned by the crawler loop.
+struct State {
+    config: CrawlerConfig,
+    contacts: VecDeque<SocketAddr>,
+    // Contacts learned from flagged endpoints, only pinged when no other contact is left.
+    suspect_contacts: VecDeque<SocketAddr>,
+    seen: SeenSet,
+    identities: IdentityTracker,
+    port_rewrites: PortRewriteTracker,
+    spoofing: SpoofingDetector,
+    peers: PeerCorroboration,
+    // Nodes that answered, and the lookups triggered through the handles.
+    table: RoutingTable<SocketAddr, Id160>,
+    // Bound to a bogon address, e.g. a test network on the loopback: the nodes at bogon
+    // addresses are reachable, and kept in the routing table.
+    local_network: bool,
+    lookups: Vec<TriggeredLookup>,
+    prober: Option<CapabilityProber>,
+    bootstrap: BootstrapHistory,
+    // Bootstrap nodes waiting for the answer to a ping, by address.
+    bootstrap_pings: HashMap<SocketAddr, String>,
+    // Totals of the crawl, published as they are.
+    inbound_queries: InboundQueryStats,
+    info_hashes: SeenSet,
+    events: EventCounts,
+    timeouts: u64,
+    error_replies: BTreeMap<i64, u64>,
+    // Packets of the triggered lookups that are over.
+    lookup_packets: u64,
+    // Counters accumulated since the last publication to the shared progress.
+    queries_sent: u64,
+    responses_received: u64,
+    nodes_discovered: u64,
+    // Time a node was last seen for the first time (or the crawl started).
+    last_discovery: Instant,
+    icmp_errors: u64,
+    countries: HashMap<String, u64>,
+    client_versions: HashMap<String, u64>,
+    // Number of malformed datagrams when the samples were last dumped.
+    malformed_dumped: u64,
+}
+
+/// Crawls the DHT from a single [`DhtNode`], see [`Crawler::run`].
+///
+/// The crawler pings its contacts (or the bootstrap nodes when it has none), asks every node
+/// that answers for the nodes close to [`CrawlerConfig::lookup_target`], and adds the nodes it
+/// did not see yet to its contacts. Discoveries are reported to the [`Sink`]s of the crawler.
+///
+/// The endpoints that change their id too often (see [`IdentityTracker`]) are not trusted: the
+/// ids they report for themselves are ignored, and the nodes they report are contacted last.
+pub struct Crawler {
+    node: DhtNode,
+    state: State,
+    sinks: Vec<Box<dyn Sink>>,
+    shared: Arc<Shared>,
+    // Beaten on every iteration of the crawler loop.
+    heartbeat: Heartbeat,
+}
+
+impl Crawler {
+    /// Bind the socket of the crawler.
+    ///
+    /// In a private overlay with a shared secret, the admission of the overlay is added to
+    /// the extensions of the node, see [`CrawlerConfig::overlay`]. A bootstrap history (see
+    /// [`BootstrapConfig::history`]) of another DHT than the crawled one is an error.
+    pub fn bind(config: CrawlerConfig) -> io::Result<Crawler> {
+        config.validate()?;
+        let domain = config.overlay.as_ref().map(|overlay| overlay.name.clone());
+        let mut bootstrap = match &config.bootstrap.history {
+            Some(path) => match BootstrapHistory::read(path) {
+                Ok(history) if history.domain() == domain.as_deref() => history,
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("the bootstrap history {:?} is of another DHT", path),
+                    ));
+                }
+                // The history only orders the bootstrap nodes, a new one is written on stop.
+                #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+                Err(e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(?path, error = %e, "failed to read the bootstrap history");
+                    BootstrapHistory::new()
+                }
+            },
+            None => BootstrapHistory::new(),
+        };
+        bootstrap.set_domain(domain);
+        let identities = match &config.identities.history {
+            Some(path) => match IdentityTracker::read(path, config.identities.clone()) {
+                Ok(identities) => identities,
+                // Like the bootstrap history, a new one is written on stop.
+                #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+                Err(e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(?path, error = %e, "failed to read the identity history");
+                    IdentityTracker::new(config.identities.clone())
+                }
+            },
+            None => IdentityTracker::new(config.identities.clone()),
+        };
+        let mut node_config = config.node.clone();
+        let mut table = RoutingTable::new(config.node.node_id);
+        if let Some(overlay) = &config.overlay
+            && let Some(admission) = overlay.admission()
+        {
+            node_config.extensions.push(Arc::new(admission));
+            // Only the nodes holding the secret get an answer through: they are of the overlay.
+            table.set_domain(Some(overlay.domain()));
+        }
+        let node = DhtNode::bind(node_config)?;
+        let local_ip = node.local_addr()?.ip();
+        // The nodes of a local network (e.g. a test cluster) may share their host.
+        let local_network = is_bogon(local_ip) && !local_ip.is_unspecified();
+        if !local_network {
+            table.set_max_nodes_per_host(Some(PUBLIC_MAX_NODES_PER_HOST));
+        }
+        let shared = Arc::new(Shared {
+            running: AtomicBool::new(true),
+            paused: AtomicBool::new(false),
+            progress: Mutex::new(Progress::new(Instant::now(), config.geo_lookup.is_some())),
+            config: Mutex::new(config.clone()),
+            requests: Mutex::new(Requests::default()),
+        });
+        // The false-positive rates were validated with the configuration.
+        let new_seen_set = |config: &SeenSetConfig| {
+            SeenSet::new(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+        Ok(Crawler {
+            node,
+            state: State {
+                seen: new_seen_set(&config.seen)?,
+                info_hashes: new_seen_set(&config.info_hashes)?,
+                events: EventCounts::default(),
+                timeouts: 0,
+                lookup_packets: 0,
+                error_replies: BTreeMap::new(),
+                identities,
+                port_rewrites: PortRewriteTracker::new(config.port_rewrites.clone()),
+                spoofing: SpoofingDetector::new(config.spoofing.clone()),
+                peers: PeerCorroboration::new(config.peer_confidence.clone()),
+                inbound_queries: InboundQueryStats::new(
+                    config.inbound_queries.clone(),
+                    Instant::now(),
+                ),
+                table,
+                local_network,
+                lookups: Vec::new(),
+                prober: config
+                    .probe
+                    .clone()
+                    .map(|probe| CapabilityProber::new(probe, config.lookup_target)),
+                bootstrap,
+                bootstrap_pings: HashMap::new(),
+                config,
+                contacts: VecDeque::new(),
+                suspect_contacts: VecDeque::new(),
+                queries_sent: 0,
+                responses_received: 0,
+                nodes_discovered: 0,
+                last_discovery: Instant::now(),
+                icmp_errors: 0,
+                countries: HashMap::new(),
+                client_versions: HashMap::new(),
+                malformed_dumped: 0,
+            },
+            sinks: Vec::new(),
+            shared,
+            heartbeat: Heartbeat::new("crawler"),
+        })
+    }
+
+    /// Get the socket settings applied on bind (to the first socket).
+    pub fn socket_report(&self) -> &SocketReport {
+        self.node.socket_report()
+    }
+
+    /// Get the socket settings applied on bind to every socket, see
+    /// [`SocketConfig::sockets`](crate::transport::SocketConfig::sockets).
+    pub fn socket_reports(&self) -> &[SocketReport] {
+        self.node.socket_reports()
+    }
+
+    /// Add nodes to contact, e.g. the node list of a previous run.
+    pub fn add_contacts<I>(&mut self, contacts: I)
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        self.state.contacts.extend(contacts);
+        self.publish();
+    }
+
+    /// Add a sink receiving the discoveries of the crawler.
+    pub fn add_sink<S: Sink + 'static>(&mut self, sink: S) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Get the events of the crawl as a stream, buffering up to `capacity` events, see
+    /// [`event_stream`](crate::sink::event_stream).
+    pub fn event_stream(&mut self, capacity: usize) -> EventStream {
+        let (sink, stream) = crate::sink::event_stream(capacity);
+        self.add_sink(sink);
+        stream
+    }
+
+    /// Get the heartbeats of the loops of the crawl, to watch them with a
+    /// [`Watchdog`](crate::watchdog::Watchdog): the crawler loop, the receive thread of the
+    /// node and the threads of the [`QueuedSink`](crate::sink::QueuedSink)s, once added.
+    ///
+    /// The sinks set later through [`CrawlerHandle::replace_sinks`] are not included.
+    pub fn heartbeats(&self) -> Vec<Heartbeat> {
+        let mut heartbeats = vec![self.heartbeat.clone()];
+        heartbeats.extend(self.node.receive_heartbeat());
+        heartbeats.extend(self.sinks.iter().filter_map(|sink| sink.heartbeat()));
+        heartbeats
+    }
+
+    /// Get a handle to observe and stop the crawler.
+    pub fn handle(&self) -> CrawlerHandle {
+        CrawlerHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Run the crawler on a new thread, named `bitcrawler-crawler`.
+    pub fn spawn(mut self) -> (CrawlerHandle, JoinHandle<io::Result<()>>) {
+        let handle = self.handle();
+        let thread = thread::Builder::new()
+            .name("bitcrawler-crawler".to_string())
+            .spawn(move || self.run())
+            .expect("failed to spawn the crawler thread");
+        (handle, thread)
+    }
+
+    /// Crawl until stopped through a [`CrawlerHandle`] or the [`CrawlerConfig::cancel`] token,
+    /// until the query cap of the [`TrafficLimits`](crate::limits::TrafficLimits) of the node
+    /// is reached, or until a stop condition of the configuration is met
+    /// ([`CrawlerConfig::max_duration`], [`CrawlerConfig::max_nodes`] or
+    /// [`CrawlerConfig::idle_timeout`]). A dry run, replaying the traffic of a previous crawl
+    /// (see [`NodeConfig::replay`]), stops at the end of the recording. The reason is reported
+    /// as [`CrawlSnapshot::stop_reason`].
+    ///
+    /// Returns an error if the socket or a sink fails. The sinks (and the wiretap file of the
+    /// node) are flushed on every tick and before returning.
+    pub fn run(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("crawler", id = %self.node.id()).entered();
+        let result = self.crawl();
+        let flushed = self.flush();
+        let saved = match &self.state.config.bootstrap.history {
+            Some(path) => self.state.bootstrap.write(path),
+            None => Ok(()),
+        };
+        let saved = saved.and(match &self.state.config.identities.history {
+            Some(path) => self.state.identities.write(path),
+            None => Ok(()),
+        });
+        self.publish();
+        self.heartbeat.stop();
+        result.and(flushed).and(saved)
+    }
+
+    fn crawl(&mut self) -> io::Result<()> {
+        let mut events = Vec::new();
+        let mut last_tick: Option<Instant> = None;
+        let mut last_publish = Instant::now();
+        let started = Instant::now();
+        let end_of =
+            |config: &CrawlerConfig| config.max_duration.map(|duration| started + duration);
+        let mut end = end_of(&self.state.config);
+        self.node.set_deadline(end);
+        self.publish();
+        let mut reason = StopReason::Stopped;
+        while self.shared.running.load(Ordering::Relaxed) {
+            self.heartbeat.beat();
+            if self.apply_requests()? {
+                end = end_of(&self.state.config);
+                self.node.set_deadline(end);
+            }
+            if let Some(stop) = self.stop_condition(end) {
+                self.shared.running.store(false, Ordering::Relaxed);
+                reason = stop;
+                break;
+            }
+            self.node.poll(&mut events)?;
+            for event in events.drain(..) {
+                self.handle_event(event)?;
+            }
+
+            if last_tick.is_none_or(|tick| tick.elapsed() >= self.state.config.tick_interval) {
+                last_tick = Some(Instant::now());
+                self.tick()?;
+            }
+            if last_publish.elapsed() >= PUBLISH_INTERVAL {
+                last_publish = Instant::now();
+                self.publish();
+            }
+        }
+        self.shared
+            .progress
+            .lock()
+            .expect("crawler progress lock poisoned")
+            .stop_reason = Some(reason);
+        Ok(())
+    }
+
+    /// Get the stop condition met, if any, given the `end` of the run.
+    fn stop_condition(&self, end: Option<Instant>) -> Option<StopReason> {
+        let state = &self.state;
+        let now = Instant::now();
+        let idle = state.contacts.is_empty() && state.suspect_contacts.is_empty();
+        StopState {
+            exhausted: self.node.is_exhausted(),
+            ended: end.is_some_and(|end| now >= end),
+            in_flight: self.node.in_flight(),
+            nodes_seen: state.seen.len(),
+            idle: idle.then(|| now.saturating_duration_since(state.last_discovery)),
+        }
+        .reason(&state.config)
+    }
+
+    /// Apply the changes queued by the handles, returns `true` if the configuration changed.
+    fn apply_requests(&mut self) -> io::Result<bool> {
+        let Requests {
+            config,
+            sinks,
+            lookups,
+            confirmed_peers,
+        } = std::mem::take(
+            &mut *self
+                .shared
+                .requests
+                .lock()
+                .expect("crawler requests lock poisoned"),
+        );
+        for target in lookups {
+            self.start_lookup(target);
+        }
+        for (info_hash, peer) in confirmed_peers {
+            self.state.peers.confirm(info_hash, peer);
+        }
+        if let Some(replacement) = sinks {
+            for sink in &mut self.sinks {
+                sink.flush()?;
+            }
+            self.sinks = match replacement {
+                SinkReplacement::Sinks(sinks) => sinks,
+                SinkReplacement::Open(open) => {
+                    self.sinks.clear();
+                    open()?
+                }
+            };
+        }
+        let Some(config) = config else {
+            return Ok(false);
+        };
+        self.node.set_limits(config.node.limits.clone());
+        self.node.set_duplicate_policy(config.node.duplicates);
+        self.state.spoofing.set_config(config.spoofing.clone());
+        self.state.peers.set_config(config.peer_confidence.clone());
+        match (&mut self.state.prober, &config.probe) {
+            (Some(prober), Some(probe)) => prober.set_config(probe.clone(), config.lookup_target),
+            (prober, probe) => {
+                *prober = probe
+                    .clone()
+                    .map(|probe| CapabilityProber::new(probe, config.lookup_target));
+            }
+        }
+        self.state.config = config;
+        Ok(true)
+    }
+
+    fn start_lookup(&mut self, target: Target) {
+        let mut start: Vec<SocketAddr> = self
+            .state
+            .table
+            .closest(target, 2 * LOOKUP_START_NODES)
+            .into_iter()
+            .filter_map(|node| node.addresses().first().copied())
+            .collect();
+        // The nodes at endpoints changing their id too often only start a lookup when the
+        // table has too few other nodes.
+        let now = Instant::now();
+        start.sort_by_key(|address| self.state.identities.is_flagged(address, now));
+        start.truncate(LOOKUP_START_NODES);
+        if start.is_empty() {
+            start = self
+                .state
+                .config
+                .bootstrap_hosts()
+                .iter()
+                .filter_map(|node| node.to_socket_addrs().ok())
+                .flatten()
+                .collect();
+        }
+        let budget = self.state.config.lookup_budget;
+        let mut lookup = TriggeredLookup::new(target, budget, Instant::now());
+        for address in lookup.next(start) {
+            self.send(|node| send_lookup_query(node, address, target));
+        }
+        self.state.lookups.push(lookup);
+    }
+
+    /// Record a node that answered in the routing table, unless it is at a bogon address and
+    /// the crawler is not itself in a local network. The node is tagged with the domain of
+    /// the table, only set in an overlay with a shared secret: the answers reaching here
+    /// carried its admission tag.
+    fn learn(&mut self, id: Id160, address: SocketAddr, rtt: Duration) {
+        if !self.state.local_network && is_bogon(address.ip()) {
+            return;
+        }
+        let now = Instant::now();
+        let table = &mut self.state.table;
+        if table.get(&id).is_none() {
+            let mut node = Node::new(id, vec![address]);
+            if let Some(domain) = table.domain() {
+                node = node.with_domain(domain);
+            }
+            table.insert_at(node, now);
+        }
+        table.mark_seen(&id, now);
+        table.record_rtt(&id, rtt);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("flush", sinks = self.sinks.len()).entered();
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        self.node.flush_wiretap()
+    }
+
+    fn emit(&mut self, event: CrawlEvent) -> io::Result<()> {
+        self.state.events.add(&event);
+        match &event {
+            CrawlEvent::NodeDiscovered { .. } | CrawlEvent::CapabilitiesProbed { .. } => {}
+            CrawlEvent::PeersFound { info_hash, .. }
+            | CrawlEvent::PeersRequested { info_hash, .. }
+            | CrawlEvent::PeerAnnounced { info_hash, .. }
+            | CrawlEvent::ScrapeReceived { info_hash, .. } => {
+                self.state.info_hashes.insert(*info_hash);
+            }
+        }
+        for sink in &mut self.sinks {
+            sink.handle(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Send a query with `send`, the query is just lost if sending fails. Returns `true` if
+    /// it was sent.
+    fn send<F>(&mut self, send: F) -> bool
+    where
+        F: FnOnce(&mut DhtNode) -> io::Result<()>,
+    {
+        // A failed send backs its destination off (see `SendGuard`), it is not an error of the
+        // crawl; pending ICMP errors (IP_RECVERR) may also surface on any send.
+        let sent = send(&mut self.node).is_ok();
+        if sent {
+            self.state.queries_sent += 1;
+        }
+        sent
+    }
+
+    /// Send the probes the rate allows, and report the probes complete or timed out.
+    fn probe(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let mut probed = Vec::new();
+        while let Some(prober) = &mut self.state.prober
+            && let Some((_, address)) = prober.next_probe(now)
+        {
+            let target = prober.target();
+            for extension in [
+                Extension::SampleInfohashes,
+                Extension::Scrape,
+                Extension::Storage,
+            ] {
+                let sent = self.send(|node| match extension {
+                    Extension::SampleInfohashes => node.sample_infohashes(address, target),
+                    Extension::Scrape => node.scrape(address, target),
+                    Extension::Storage => node.get_item(address, target),
+                });
+                if !sent && let Some(prober) = &mut self.state.prober {
+                    // Not sent, e.g. refused by the traffic limits: no answer will come.
+                    probed.extend(prober.record(address, extension, Support::NoAnswer));
+                }
+            }
+        }
+        if let Some(prober) = &mut self.state.prober {
+            probed.extend(prober.expire(now));
+        }
+        for (id, address, capabilities) in probed {
+            self.emit(CrawlEvent::CapabilitiesProbed {
+                id,
+                address,
+                capabilities,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Record a node id, and report it the first time it is seen.
+    fn discover(&mut self, id: Id160, address: SocketAddr) -> io::Result<bool> {
+        if !self.state.seen.insert(id) {
+            return Ok(false);
+        }
+        self.state.nodes_discovered += 1;
+        self.state.last_discovery = Instant::now();
+        if let Some(country) = self
+            .state
+            .config
+            .geo_lookup
+            .as_ref()
+            .and_then(|lookup| lookup.country(address.ip()))
+        {
+            *self.state.countries.entry(country).or_default() += 1;
+        }
+        if let Some(prober) = &mut self.state.prober {
+            prober.enqueue(id, address);
+        }
+        self.emit(CrawlEvent::NodeDiscovered { id, address })?;
+        Ok(true)
+    }
+
+    /// Record the outcome of a ping sent to a bootstrap node in the bootstrap history.
+    fn record_bootstrap(&mut self, event: &NodeEvent) {
+        let (query, rtt) = match event {
+            NodeEvent::Response { query, rtt, .. } => (query, Some(*rtt)),
+            NodeEvent::Timeout { query } => (query, None),
+            NodeEvent::Error { .. } | NodeEvent::Query { .. } | NodeEvent::Duplicate { .. } => {
+                return;
+            }
+        };
+        if query.query_type != QUERY_TYPE_PING {
+            return;
+        }
+        let Some(host) = self.state.bootstrap_pings.remove(&query.destination) else {
+            return;
+        };
+        match rtt {
+            Some(rtt) => self
+                .state
+                .bootstrap
+                .record_success(&host, rtt, SystemTime::now()),
+            None => self.state.bootstrap.record_failure(&host),
+        }
+    }
+
+    fn handle_event(&mut self, event: NodeEvent) -> io::Result<()> {
+        self.record_bootstrap(&event);
+        if let Some(prober) = &mut self.state.prober
+            && let Some((id, address, capabilities)) = prober.observe(&event)
+        {
+            self.emit(CrawlEvent::CapabilitiesProbed {
+                id,
+                address,
+                capabilities,
+            })?;
+        }
+        let (query, response, rtt) = match event {
+            NodeEvent::Response {
+                query,
+                response,
+                rtt,
+            } => (query, response, rtt),
+            NodeEvent::Query { source, query } => {
+                self.state.inbound_queries.record(
+                    source.ip(),
+                    QueryKind::of(query.get_query()),
+                    Instant::now(),
+                );
+                if let Some(version) = &query.get_options().version {
+                    let version = client_version_label(version.as_ref());
+                    *self.state.client_versions.entry(version).or_default() += 1;
+                }
+                match query.get_query() {
+                    QueryType::GetPeers(get_peers) => {
+                        self.state.info_hashes.insert(*get_peers.get_info_hash());
+                    }
+                    QueryType::AnnouncePeer(announce) => {
+                        self.state.info_hashes.insert(*announce.get_info_hash());
+                    }
+                    _ => {}
+                }
+                return Ok(());
+            }
+            NodeEvent::Error { error, .. } => {
+                *self
+                    .state
+                    .error_replies
+                    .entry(error.code.code())
+                    .or_default() += 1;
+                return Ok(());
+            }
+            NodeEvent::Timeout { .. } => {
+                self.state.timeouts += 1;
+                return Ok(());
+            }
+            NodeEvent::Duplicate { id, rtt, .. } => {
+                if self.state.config.node.duplicates == DuplicatePolicy::RefineRtt
+                    && let Some(id) = id
+                {
+                    self.state.table.record_rtt(&id, rtt);
+                }
+                return Ok(());
+            }
+        };
+        self.state.responses_received += 1;
+        let source = query.destination;
+        let sender_id = match response.get_response_type() {
+            ResponseType::Ping(ping) => ping.get_id(),
+            ResponseType::FindNode(find_node) => find_node.get_id(),
+            ResponseType::GetPeers(get_peers) => get_peers.get_id(),
+            ResponseType::Raw(_) => return Ok(()),
+        };
+        let flagged = self
+            .state
+            .identities
+            .observe(source, *sender_id, Instant::now())
+            == IdentityCheck::Flagged;
+        if !flagged {
+            self.state.port_rewrites.observe_reply(*sender_id, source);
+            self.learn(*sender_id, source, rtt);
+        }
+        let (sender_id, nodes, peers) = match response.get_response_type() {
+            ResponseType::Ping(ping) => {
+                if !flagged {
+                    self.discover(*ping.get_id(), source)?;
+                    // The node is available, ask it for other nodes.
+                    let target = self.state.config.lookup_target;
+                    if self.state.config.scrape {
+                        self.send(|node| node.scrape(source, target));
+                    } else {
+                        self.send(|node| node.get_peers(source, target));
+                    }
+                }
+                return Ok(());
+            }
+            ResponseType::FindNode(find_node) => {
+                (find_node.get_id(), find_node.get_nodes(), &[][..])
+            }
+            ResponseType::GetPeers(get_peers) => (
+                get_peers.get_id(),
+                get_peers.get_nodes(),
+                get_peers.get_peers(),
+            ),
+            ResponseType::Raw(_) => return Ok(()),
+        };
+
+        let mut nodes = reply_nodes(nodes, self.node.id(), *sender_id, source);
+        if let Some(target) = query.target
+            && self.state.spoofing.observe(source, &target, &nodes) == SpoofingCheck::Flagged
+            && self.state.config.spoofing.drop_flagged
+        {
+            nodes.clear();
+        }
+        for &(id, address) in &nodes {
+            if !flagged {
+                self.state.port_rewrites.observe_claim(id, address);
+            }
+            if self.discover(id, address)? {
+                if flagged {
+                    self.state.suspect_contacts.push_back(address);
+                } else {
+                    self.state.contacts.push_back(address);
+                }
+            }
+        }
+        if let (Some(info_hash), ResponseType::GetPeers(get_peers)) =
+            (query.target, response.get_response_type())
+            && let Some(seeds) = get_peers
+                .get_seeds_filter()
+                .and_then(ScrapeFilter::from_bytes)
+            && let Some(scraped_peers) = get_peers
+                .get_peers_filter()
+                .and_then(ScrapeFilter::from_bytes)
+        {
+            self.emit(CrawlEvent::ScrapeReceived {
+                info_hash,
+                source,
+                seeds,
+                peers: scraped_peers,
+            })?;
+        }
+        if let Some(target) = query.target
+            && let Some(lookup) = self
+                .state
+                .lookups
+                .iter_mut()
+                .find(|lookup| lookup.target().id() == &target && lookup.has_queried(&source))
+        {
+            let target = lookup.target();
+            for address in lookup.follow(sender_id, &nodes) {
+                self.send(|node| send_lookup_query(node, address, target));
+            }
+        }
+        let peer_ports = self.state.config.peer_ports;
+        let peers: Vec<_> = peers
+            .iter()
+            .filter(|peer| peer_ports.check(peer.port()).is_some())
+            .collect();
+        if let Some(info_hash) = query.target.filter(|_| !peers.is_empty()) {
+            let seen_at = SystemTime::now();
+            let token_available = match response.get_response_type() {
+                ResponseType::GetPeers(get_peers) => get_peers.get_token().is_some(),
+                _ => false,
+            };
+            let corroboration = &mut self.state.peers;
+            let peers: Vec<_> = peers
+                .into_iter()
+                .filter_map(|peer| {
+                    let addr = SocketAddr::V4(*peer);
+                    let confidence = corroboration.observe(info_hash, source.ip(), addr);
+                    confidence
+                        .meets(corroboration.config())
+                        .then_some(DiscoveredPeer {
+                            addr,
+                            source_node: *sender_id,
+                            info_hash,
+                            seen_at,
+                            token_available,
+                            confidence,
+                        })
+                })
+                .collect();
+            if !peers.is_empty() {
+                self.emit(CrawlEvent::PeersFound {
+                    info_hash,
+                    source,
+                    peers,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ping the next contacts (or the bootstrap nodes) unless paused, publish the routing table,
+    /// then flush the sinks.
+    fn tick(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("tick").entered();
+        let budget_end = self
+            .state
+            .config
+            .tick_budget
+            .map(|budget| Instant::now() + budget);
+        let spent = || budget_end.is_some_and(|end| Instant::now() >= end);
+        if self.shared.paused.load(Ordering::Relaxed) {
+            // Nothing new is pinged.
+        } else if self.state.contacts.is_empty() && self.state.suspect_contacts.is_empty() {
+            let hosts = self.state.bootstrap.select(
+                self.state.config.bootstrap_hosts(),
+                self.state.config.bootstrap.per_round,
+                self.node.rng(),
+            );
+            for host in hosts {
+                if spent() {
+                    break;
+                }
+                let addresses = host.to_socket_addrs().into_iter().flatten();
+                for address in addresses {
+                    if self.send(|node| node.ping(address)) {
+                        self.state.bootstrap_pings.insert(address, host.clone());
+                    }
+                }
+            }
+        } else {
+            for _ in 0..self.state.config.pings_per_tick {
+                if spent() {
+                    break;
+                }
+                let contact = self
+                    .state
+                    .contacts
+                    .pop_front()
+                    .or_else(|| self.state.suspect_contacts.pop_front());
+                match contact {
+                    Some(contact) => {
+                        self.send(|node| node.ping(contact));
+                    }
+                    None => break,
+                }
+            }
+        }
+        self.state.icmp_errors += self
+            .node
+            .drain_socket_errors()
+            .map(|errors| errors.len() as u64)
+            .unwrap_or_default();
+        let malformed = self.node.malformed();
+        if let Some(path) = &self.state.config.malformed_dump
+            && malformed.total() != self.state.malformed_dumped
+        {
+            malformed.dump(path)?;
+            self.state.malformed_dumped = malformed.total();
+        }
+        let now = Instant::now();
+        let lookup_packets = &mut self.state.lookup_packets;
+        self.state.lookups.retain(|lookup| {
+            let over = lookup.is_over(now);
+            if over {
+                *lookup_packets += lookup.packets() as u64;
+            }
+            !over
+        });
+        self.probe()?;
+        self.publish_routing_table();
+        self.flush()
+    }
+
+    fn publish_routing_table(&mut self) {
+        let own_id = self.node.id();
+        let mut entries: Vec<RoutingEntry> = self
+            .state
+            .table
+            .buckets()
+            .iter()
+            .enumerate()
+            .flat_map(|(bucket, nodes)| nodes.iter().map(move |node| (bucket, node)))
+            .filter_map(|(bucket, node)| {
+                Some(RoutingEntry {
+                    bucket,
+                    id: *node.id(),
+                    address: *node.addresses().first()?,
+                    last_seen: node.last_seen(),
+                    rtt: node.rtt(),
+                })
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.id.distance(&own_id));
+        self.shared
+            .progress
+            .lock()
+            .expect("crawler progress lock poisoned")
+            .routing_table = entries;
+    }
+
+    /// Move the counters accumulated by the loop to the shared progress.
+    fn publish(&mut self) {
+        let now = Instant::now();
+        let dropped = self.node.dropped_datagrams().ok().flatten();
+        let state = &mut self.state;
+        let mut progress = self
+            .shared
+            .progress
+            .lock()
+            .expect("crawler progress lock poisoned");
+        let second = progress.second(now);
+        progress.nodes_seen = state.seen.estimate();
+        progress.frontier_depth = state.contacts.len() + state.suspect_contacts.len();
+        progress.in_flight_queries = self.node.in_flight();
+        progress
+            .queries_sent
+            .record(second, std::mem::take(&mut state.queries_sent));
+        progress
+            .responses_received
+            .record(second, std::mem::take(&mut state.responses_received));
+        progress
+            .nodes_discovered
+            .record(second, std::mem::take(&mut state.nodes_discovered));
+        progress.icmp_errors += std::mem::take(&mut state.icmp_errors);
+        progress.identities = state.identities.stats();
+        progress.port_rewrites = state.port_rewrites.stats();
+        progress.spoofing = state.spoofing.stats();
+        progress.peer_confidence = state.peers.stats();
+        if let Some(countries) = &mut progress.countries {
+            for (country, count) in state.countries.drain() {
+                *countries.entry(country).or_default() += count;
+            }
+        }
+        progress.count_client_versions(state.client_versions.drain());
+        progress.inbound_queries = state.inbound_queries.report(now).clone();
+        progress.info_hashes_seen = state.info_hashes.estimate();
+        progress.records = state.events;
+        progress.timeouts = state.timeouts;
+        progress.lookup_packets = state.lookup_packets
+            + state
+                .lookups
+                .iter()
+                .map(|lookup| lookup.packets() as u64)
+                .sum::<u64>();
+        progress.capabilities = state.prober.as_ref().map(CapabilityProber::stats);
+        progress.error_replies.clone_from(&state.error_replies);
+        progress.received = *self.node.receive_stats();
+        progress.dropped_datagrams = dropped;
+        progress.malformed_datagrams = self.node.malformed().total();
+        progress.traffic = *self.node.traffic_audit();
+        progress.send_failures = *self.node.send_failures();
+        progress.unmatched_replies = *self.node.unmatched_replies();
+        progress.receive_queue = self.node.receive_queue_stats();
+        progress.sink_queues = self
+            .sinks
+            .iter()
+            .filter_map(|sink| sink.queue_stats())
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::{Ipv4Addr, SocketAddrV4},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+    };
+
+    use bitcrawler_proto::{
+        kademlia::InfoHash,
+        krpc::{MessageExtensions, Query, QueryType, node_info::BittorrentNodeInfoV4},
+    };
+
+    use super::*;
+    use crate::{
+        node::{DhtResponse, PendingQuery},
+        pipeline::{OverflowPolicy, QueueConfig},
+        sink::QueuedSink,
+        testutil::{FakeNode, ServingNode},
+        transport::{ReplayConfig, ReplaySpeed, SocketConfig},
+    };
+
+    /// Get the configuration of a crawler bound to an ephemeral loopback port.
+    fn local_config() -> CrawlerConfig {
+        let mut config = CrawlerConfig::new(Id160([0; 20]));
+        config.node.socket = SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        config
+    }
+
+    struct FixedCountry;
+
+    impl GeoLookup for FixedCountry {
+        fn country(&self, _ip: IpAddr) -> Option<String> {
+            Some("FR".to_string())
+        }
+    }
+
+    /// Answer pings, and get_peers with `nodes` (which are never contacted).
+    fn fake_node(id: Id160, nodes: Vec<BittorrentNodeInfoV4<Id160>>) -> ServingNode {
+        FakeNode::bind(id).unwrap().serve(move |query| {
+            let tid = query.get_transaction_id().clone();
+            let response = match query.get_query() {
+                QueryType::Ping(_) => DhtResponse::new_ping(tid, id),
+                // A get_peers reply without token nor values is shaped like a find_node one.
+                QueryType::GetPeers(_) => DhtResponse::new_find_node(tid, id, nodes.clone()),
+                _ => return None,
+            };
+            Some(response.to_bencoded())
+        })
+    }
+
+    #[test]
+    fn test_crawl_snapshot() {
+        // The node on port 0 cannot be contacted, it is not discovered.
+        let discovered: Vec<_> = (1..=4u8)
+            .map(|i| BittorrentNodeInfoV4 {
+                node_id: Id160([i; 20]),
+                ip: [127, 0, 0, 1],
+                port: if i == 4 { 0 } else { 9 },
+            })
+            .collect();
+        let fake = fake_node(Id160([0xff; 20]), discovered);
+        let bootstrap = fake.address().to_string();
+
+        let mut config = local_config();
+        config.bootstrap_nodes = vec![bootstrap];
+        config.tick_interval = Duration::from_millis(20);
+        config.pings_per_tick = 0;
+        config.geo_lookup = Some(Arc::new(FixedCountry));
+        let mut crawler = Crawler::bind(config).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        crawler.add_sink(events.clone());
+        let (handle, crawler) = crawler.spawn();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let snapshot = loop {
+            let snapshot = handle.snapshot();
+            if snapshot.nodes_seen == 4 || Instant::now() > deadline {
+                break snapshot;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        fake.stop();
+
+        assert_eq!(snapshot.nodes_seen, 4);
+        assert_eq!(snapshot.frontier_depth, 3);
+        assert!(snapshot.rates.nodes_discovered > 0.0);
+        assert!(snapshot.rates.queries_sent > 0.0);
+        assert_eq!(
+            snapshot.countries,
+            Some([("FR".to_string(), 4)].into_iter().collect())
+        );
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(events.contains(&CrawlEvent::NodeDiscovered {
+            id: Id160([2; 20]),
+            address: (Ipv4Addr::LOCALHOST, 9).into(),
+        }));
+
+        // The totals of the run, as reported on shutdown.
+        let summary = CrawlSummary::from(&handle.snapshot());
+        assert_eq!(summary.records.nodes_discovered, 4);
+        assert_eq!(summary.unique_nodes, 4);
+        assert!(summary.datagrams_received >= 2);
+        assert!(summary.bytes_received > summary.datagrams_received);
+        assert!(summary.datagrams_sent >= summary.queries_sent && summary.queries_sent >= 2);
+        assert_eq!(summary.errors.malformed_datagrams, 0);
+    }
+
+    #[test]
+    fn test_queued_pipeline() {
+        let discovered = vec![BittorrentNodeInfoV4 {
+            node_id: Id160([1; 20]),
+            ip: [127, 0, 0, 1],
+            port: 9,
+        }];
+        let fake = fake_node(Id160([0xff; 20]), discovered);
+        let bootstrap = fake.address().to_string();
+
+        let mut config = local_config();
+        config.node.receive_queue = Some(QueueConfig::new(16, OverflowPolicy::DropNewest));
+        config.bootstrap_nodes = vec![bootstrap];
+        config.tick_interval = Duration::from_millis(20);
+        config.pings_per_tick = 0;
+        let mut crawler = Crawler::bind(config).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        crawler.add_sink(
+            QueuedSink::spawn(events.clone(), QueueConfig::new(16, OverflowPolicy::Block)).unwrap(),
+        );
+        let (handle, crawler) = crawler.spawn();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.snapshot().nodes_seen < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        fake.stop();
+
+        // The sink queue was flushed when the crawl stopped.
+        assert_eq!(events.lock().unwrap().len(), 2);
+        let snapshot = handle.snapshot();
+        let receive_queue = snapshot.receive_queue.unwrap();
+        assert!(receive_queue.pushed >= 2);
+        assert_eq!(receive_queue.dropped, 0);
+        assert_eq!(snapshot.sink_queues.len(), 1);
+        assert_eq!(snapshot.sink_queues[0].pushed, 2);
+    }
+
+    #[test]
+    fn test_id_spoofing_endpoint_is_flagged() {
+        // Answers every query with a new id.
+        let mut next_id = 0u8;
+        let fake = FakeNode::bind(Id160([0; 20])).unwrap().serve(move |query| {
+            let tid = query.get_transaction_id().clone();
+            next_id += 1;
+            let id = Id160([next_id; 20]);
+            let response = match query.get_query() {
+                QueryType::Ping(_) => DhtResponse::new_ping(tid, id),
+                QueryType::GetPeers(_) => DhtResponse::new_find_node(tid, id, Vec::new()),
+                _ => return None,
+            };
+            Some(response.to_bencoded())
+        });
+        let bootstrap = fake.address().to_string();
+
+        let mut config = local_config();
+        config.bootstrap_nodes = vec![bootstrap];
+        config.tick_interval = Duration::from_millis(10);
+        config.identities.max_changes = 1;
+        let mut crawler = Crawler::bind(config).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        crawler.add_sink(events.clone());
+        let (handle, crawler) = crawler.spawn();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.snapshot().identities.flagged_replies < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        fake.stop();
+
+        // Only the id of the first reply was trusted.
+        let snapshot = handle.snapshot();
+        assert!(snapshot.identities.flagged_replies >= 3);
+        assert_eq!(snapshot.identities.endpoints, 1);
+        assert_eq!(snapshot.nodes_seen, 1);
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_private_overlay() {
+        let public = FakeNode::bind(Id160([0x11; 20])).unwrap();
+        let overlay_id = Id160([0xee; 20]);
+        // Seals its ping replies for the overlay, but not its get_peers replies.
+        let admission =
+            MessageExtensions::new().with(OverlayAdmission::new("lab", &"secret".into()));
+        let leaked = vec![BittorrentNodeInfoV4 {
+            node_id: Id160([1; 20]),
+            ip: [127, 0, 0, 1],
+            port: 9,
+        }];
+        let admitted = Arc::new(AtomicUsize::new(0));
+        let fake = FakeNode::bind(overlay_id).unwrap().serve_messages({
+            let admitted = admitted.clone();
+            move |message| {
+                if admission.parse(message).is_ok() {
+                    admitted.fetch_add(1, Ordering::Relaxed);
+                }
+                let query = Query::<Id160>::try_from_bencoded(message).ok()?;
+                let tid = query.get_transaction_id().clone();
+                let mut response = match query.get_query() {
+                    QueryType::Ping(_) => DhtResponse::new_ping(tid, overlay_id).to_bencoded(),
+                    QueryType::GetPeers(_) => {
+                        DhtResponse::new_find_node(tid, overlay_id, leaked.clone()).to_bencoded()
+                    }
+                    _ => return None,
+                };
+                if matches!(query.get_query(), QueryType::Ping(_)) {
+                    admission.encode(&mut response);
+                }
+                Some(response)
+            }
+        });
+        let bootstrap = fake.address().to_string();
+
+        let mut config = local_config();
+        config.bootstrap_nodes = vec![public.address().to_string()];
+        let mut overlay = OverlayConfig::new("lab", vec![bootstrap]);
+        overlay.secret = Some("secret".into());
+        config.overlay = Some(overlay);
+        config.tick_interval = Duration::from_millis(20);
+        let (handle, crawler) = Crawler::bind(config).unwrap().spawn();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.snapshot().malformed_datagrams == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        fake.stop();
+
+        // The unsealed reply was rejected, the nodes it held were not discovered.
+        let snapshot = handle.snapshot();
+        assert!(snapshot.malformed_datagrams >= 1);
+        assert_eq!(snapshot.nodes_seen, 1);
+        let table = handle.routing_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].id, overlay_id);
+        // Every query was sealed, and none went to the public DHT.
+        assert!(admitted.load(Ordering::Relaxed) >= 2);
+        assert!(public.recv_from(Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_overlay_history_of_another_dht() {
+        let history =
+            std::env::temp_dir().join(format!("overlay-{}.bootstrap", std::process::id()));
+        let mut public = BootstrapHistory::new();
+        public.record_failure("127.0.0.1:9");
+        public.write(&history).unwrap();
+        let mut config = local_config();
+        config.overlay = Some(OverlayConfig::new("lab", vec!["127.0.0.1:9".to_string()]));
+        config.bootstrap.history = Some(history.clone());
+        let error = Crawler::bind(config).err().unwrap();
+        fs::remove_file(&history).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("bitcrawler-wiretap-{}", std::process::id()));
+        let discovered: Vec<_> = (1..=3u8)
+            .map(|i| BittorrentNodeInfoV4 {
+                node_id: Id160([i; 20]),
+                ip: [127, 0, 0, 1],
+                port: 9,
+            })
+            .collect();
+        let fake = fake_node(Id160([0xff; 20]), discovered);
+        let bootstrap = fake.address().to_string();
+
+        let mut config = local_config();
+        config.node.wiretap = Some(path.clone());
+        config.bootstrap_nodes = vec![bootstrap];
+        config.tick_interval = Duration::from_millis(20);
+        config.pings_per_tick = 0;
+        let mut crawler = Crawler::bind(config.clone()).unwrap();
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        crawler.add_sink(recorded.clone());
+        let (handle, crawler) = crawler.spawn();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.snapshot().nodes_seen < 4 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        fake.stop();
+
+        // The fake node is gone: the replay alone yields the same discoveries, and ends with
+        // the recording.
+        config.node.wiretap = None;
+        config.node.replay = Some(ReplayConfig {
+            path: path.clone(),
+            speed: ReplaySpeed::Fast,
+        });
+        let mut crawler = Crawler::bind(config).unwrap();
+        let replayed = Arc::new(Mutex::new(Vec::new()));
+        crawler.add_sink(replayed.clone());
+        crawler.run().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recorded.lock().unwrap().len(), 4);
+        assert_eq!(*replayed.lock().unwrap(), *recorded.lock().unwrap());
+        assert!(!crawler.handle().is_running());
+    }
+
+    #[test]
+    fn test_query_cap_stops_crawl() {
+        let silent = FakeNode::bind(Id160([0x11; 20])).unwrap();
+        let mut config = local_config();
+        config.node.poll_timeout = Duration::from_millis(10);
+        config.node.limits.max_queries = Some(1);
+        config.bootstrap_nodes = vec![silent.address().to_string(); 2];
+        let mut crawler = Crawler::bind(config).unwrap();
+        let handle = crawler.handle();
+
+        // Returns by itself once the single query is sent.
+        crawler.run().unwrap();
+        assert!(!handle.is_running());
+        let traffic = handle.snapshot().traffic;
+        assert_eq!(traffic.queries_sent, 1);
+        assert_eq!(traffic.query_cap_reached, 1);
+    }
+
+    #[test]
+    fn test_reload() {
+        let silent = FakeNode::bind(Id160([0x11; 20])).unwrap();
+        let mut config = local_config();
+        config.node.poll_timeout = Duration::from_millis(10);
+        // A single round of pings.
+        config.tick_interval = Duration::from_secs(60);
+        config.bootstrap_nodes = vec![silent.address().to_string()];
+        let mut crawler = Crawler::bind(config).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        crawler.add_sink(events.clone());
+        let (handle, crawler) = crawler.spawn();
+
+        // The previous sinks are dropped once the new ones are installed.
+        handle.replace_sinks(vec![Box::new(Vec::new())]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Arc::strong_count(&events) > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Arc::strong_count(&events), 1);
+        // Or before the new ones are opened.
+        let reopened = events.clone();
+        let (opened, open) = mpsc::channel();
+        handle.replace_sinks_with(move || {
+            opened.send(Arc::strong_count(&reopened)).unwrap();
+            Ok(vec![Box::new(reopened) as Box<dyn Sink>])
+        });
+        let count = open.recv_timeout(Duration::from_secs(5)).unwrap();
+        // The test and the closure hold the events, the replaced sinks do not anymore.
+        assert_eq!(count, 2);
+
+        let mut invalid = handle.config();
+        invalid.tick_interval = Duration::ZERO;
+        invalid.node.limits.max_queries = Some(1);
+        assert!(handle.reload(invalid).is_err());
+        assert_eq!(handle.config().node.limits.max_queries, None);
+
+        // The socket is kept, the query cap is applied: the crawl already sent its first ping,
+        // it stops.
+        let mut reloaded = handle.config();
+        reloaded.node.socket = SocketConfig::new((Ipv4Addr::LOCALHOST, 1).into());
+        reloaded.node.limits.max_queries = Some(1);
+        assert_eq!(
+            handle.reload(reloaded).unwrap(),
+            vec![ConfigChange {
+                field: "node.limits.max_queries",
+                old: "None".into(),
+                new: "Some(1)".into(),
+            }]
+        );
+        crawler.join().unwrap().unwrap();
+        assert!(!handle.is_running());
+        assert_eq!(handle.snapshot().traffic.queries_sent, 1);
+    }
+
+    #[test]
+    fn test_duplicates_refine_rtt() {
+        let mut config = local_config();
+        config.node.duplicates = DuplicatePolicy::RefineRtt;
+        let mut crawler = Crawler::bind(config).unwrap();
+        let id = Id160([1; 20]);
+        let address = SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), 6881));
+        crawler.learn(id, address, Duration::from_millis(80));
+        let now = Instant::now();
+        let duplicate = NodeEvent::Duplicate {
+            query: PendingQuery {
+                query_type: b"ping".to_vec(),
+                destination: address,
+                target: None,
+                sent_at: now,
+                deadline: now,
+            },
+            id: Some(id),
+            rtt: Duration::from_millis(160),
+        };
+
+        // The round-trip time of the duplicate is one more sample.
+        crawler.handle_event(duplicate.clone()).unwrap();
+        let rtt = |crawler: &Crawler| crawler.state.table.get(&id).unwrap().rtt();
+        assert_eq!(rtt(&crawler), Some(Duration::from_millis(90)));
+        // Not with the other policies.
+        crawler.state.config.node.duplicates = DuplicatePolicy::Report;
+        crawler.handle_event(duplicate).unwrap();
+        assert_eq!(rtt(&crawler), Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    fn test_nodes_per_host_limited_on_public_networks() {
+        let learn_host = |crawler: &mut Crawler, ip: Ipv4Addr| {
+            for i in 1..=3u8 {
+                let address = SocketAddr::from((ip, 6880 + i as u16));
+                crawler.learn(Id160([i; 20]), address, Duration::from_millis(50));
+            }
+            crawler.state.table.len()
+        };
+        // A local test cluster shares its host.
+        let mut crawler = Crawler::bind(local_config()).unwrap();
+        assert_eq!(learn_host(&mut crawler, Ipv4Addr::LOCALHOST), 3);
+
+        let mut config = CrawlerConfig::new(Id160([0; 20]));
+        config.node.socket = SocketConfig::new((Ipv4Addr::UNSPECIFIED, 0).into());
+        let mut crawler = Crawler::bind(config).unwrap();
+        assert_eq!(
+            learn_host(&mut crawler, Ipv4Addr::new(1, 2, 3, 4)),
+            PUBLIC_MAX_NODES_PER_HOST
+        );
+    }
+
+    #[test]
+    fn test_triggered_lookup_while_paused() {
+        // Closer to the target than the fake node: followed by the lookup.
+        let closer = BittorrentNodeInfoV4 {
+            node_id: Id160([1; 20]),
+            ip: [127, 0, 0, 1],
+            port: 9,
+        };
+        let fake = fake_node(Id160([0xff; 20]), vec![closer]);
+        let bootstrap = fake.address();
+
+        let mut config = CrawlerConfig::new(Id160([0x80; 20]));
+        config.node.socket = SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        config.node.poll_timeout = Duration::from_millis(10);
+        config.bootstrap_nodes = vec![bootstrap.to_string()];
+        config.tick_interval = Duration::from_millis(20);
+        let crawler = Crawler::bind(config).unwrap();
+        let handle = crawler.handle();
+        // Nothing is pinged, only the lookup queries.
+        handle.pause();
+        handle.lookup(InfoHash(Id160([0; 20])));
+        let (handle, crawler) = crawler.spawn();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.routing_table().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        fake.stop();
+
+        let snapshot = handle.snapshot();
+        let table = handle.routing_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].id, Id160([0xff; 20]));
+        assert_eq!(table[0].address, bootstrap);
+        assert!(table[0].last_seen.is_some());
+        assert!(snapshot.paused);
+        assert_eq!(snapshot.routing_table_nodes, 1);
+        // The bootstrap node, then the closer node it returned.
+        assert_eq!(snapshot.traffic.queries_sent, 2);
+        // The two queries and the reply of the bootstrap node.
+        assert_eq!(snapshot.lookup_packets, 3);
+    }
+
+    #[test]
+    fn test_uncorroborated_peers_held_back() {
+        let peer: SocketAddrV4 = "192.0.2.7:51413".parse().unwrap();
+        // Returns the same peer to every get_peers, the only node to return it.
+        let id = Id160([0xff; 20]);
+        let fake = FakeNode::bind(id).unwrap().serve(move |query| {
+            let tid = query.get_transaction_id().clone();
+            let response = match query.get_query() {
+                QueryType::Ping(_) => DhtResponse::new_ping(tid, id),
+                QueryType::GetPeers(_) => {
+                    DhtResponse::new_get_peers(tid, id, None, Vec::new(), vec![peer])
+                }
+                _ => return None,
+            };
+            Some(response.to_bencoded())
+        });
+        let bootstrap = fake.address().to_string();
+
+        let mut config = local_config();
+        config.bootstrap_nodes = vec![bootstrap];
+        config.tick_interval = Duration::from_millis(20);
+        config.pings_per_tick = 0;
+        config.peer_confidence.min_reporters = 2;
+        let info_hash = config.lookup_target;
+        let mut crawler = Crawler::bind(config).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        crawler.add_sink(events.clone());
+        let (handle, crawler) = crawler.spawn();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.snapshot().peer_confidence.suppressed == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let found = |events: &[CrawlEvent]| {
+            events
+                .iter()
+                .any(|event| matches!(event, CrawlEvent::PeersFound { .. }))
+        };
+        // Returned by a single node, the peer is held back.
+        assert_ne!(handle.snapshot().peer_confidence.suppressed, 0);
+        assert!(!found(&events.lock().unwrap()));
+
+        // Once a handshake succeeded, the peer is reported the next time it is returned.
+        handle.confirm_peer(info_hash, SocketAddr::V4(peer));
+        handle.lookup(InfoHash(info_hash));
+        while !found(&events.lock().unwrap()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop();
+        crawler.join().unwrap().unwrap();
+        fake.stop();
+
+        let events = events.lock().unwrap();
+        let peers: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                CrawlEvent::PeersFound { peers, .. } => Some(peers),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        // Returned to the lookup, and maybe to the crawl again.
+        assert!(!peers.is_empty());
+        assert!(peers.iter().all(|found| found.addr == SocketAddr::V4(peer)
+            && found.confidence
+                == PeerConfidence {
+                    reporters: 1,
+                    handshake: true,
+                }));
+        assert_eq!(handle.snapshot().peer_confidence.confirmed, 1);
+    }
+
+    #[test]
+    fn test_max_duration_stops_crawl() {
+        let silent = FakeNode::bind(Id160([0x11; 20])).unwrap();
+        let mut config = local_config();
+        config.node.poll_timeout = Duration::from_millis(10);
+        config.bootstrap_nodes = vec![silent.address().to_string()];
+        config.max_duration = Some(Duration::from_millis(300));
+        let history = std::env::temp_dir().join(format!("crawl-{}.bootstrap", std::process::id()));
+        config.bootstrap.history = Some(history.clone());
+        let bootstrap = config.bootstrap_nodes[0].clone();
+        let mut crawler = Crawler::bind(config).unwrap();
+        let handle = crawler.handle();
+
+        let start = Instant::now();
+        crawler.run().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(!handle.is_running());
+        // The ping of the bootstrap node timed out with the run, not after the query timeout.
+        assert_eq!(handle.snapshot().in_flight_queries, 0);
+        // The unanswered ping is kept for the next runs.
+        let record = BootstrapHistory::read(&history)
+            .unwrap()
+            .get(&bootstrap)
+            .copied();
+        fs::remove_file(&history).unwrap();
+        assert_eq!(record.map(|record| record.failures), Some(1));
+        assert_eq!(handle.snapshot().stop_reason, Some(StopReason::MaxDuration));
+    }
+
+    #[test]
+    fn test_stop_conditions() {
+        let config = |bootstrap: SocketAddr| {
+            let mut config = local_config();
+            config.node.poll_timeout = Duration::from_millis(10);
+            config.bootstrap_nodes = vec![bootstrap.to_string()];
+            config.tick_interval = Duration::from_millis(20);
+            config.max_duration = Some(Duration::from_secs(5));
+            config
+        };
+        let stop_reason = |config: CrawlerConfig| {
+            let mut crawler = Crawler::bind(config).unwrap();
+            crawler.run().unwrap();
+            crawler.handle().snapshot().stop_reason
+        };
+
+        let discovered: Vec<_> = (1..=3u8)
+            .map(|i| BittorrentNodeInfoV4 {
+                node_id: Id160([i; 20]),
+                ip: [127, 0, 0, 1],
+                port: 9,
+            })
+            .collect();
+        let fake = fake_node(Id160([0xff; 20]), discovered);
+        let mut max_nodes = config(fake.address());
+        max_nodes.max_nodes = Some(2);
+        assert_eq!(stop_reason(max_nodes), Some(StopReason::MaxNodes));
+        fake.stop();
+
+        // Nothing answers: no contact is left and nothing is discovered.
+        let silent = FakeNode::bind(Id160([0x11; 20])).unwrap();
+        let silent = silent.address();
+        let mut idle = config(silent);
+        idle.idle_timeout = Some(Duration::from_millis(200));
+        let start = Instant::now();
+        assert_eq!(stop_reason(idle), Some(StopReason::Idle));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let mut cancelled = config(silent);
+        let token = CancellationToken::new();
+        cancelled.cancel = Some(token.clone());
+        token.cancel();
+        assert_eq!(stop_reason(cancelled), Some(StopReason::Cancelled));
+
+        let mut crawler = Crawler::bind(config(silent)).unwrap();
+        let handle = crawler.handle();
+        assert_eq!(handle.snapshot().stop_reason, None);
+        handle.stop();
+        crawler.run().unwrap();
+        assert_eq!(handle.snapshot().stop_reason, Some(StopReason::Stopped));
+    }
+}
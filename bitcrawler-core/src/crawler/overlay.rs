@@ -0,0 +1,256 @@
+use std::{fmt, hash::Hasher};
+
+use bitcrawler_proto::{
+    bencode::{BencodeDict, BencodeValue},
+    kademlia::Domain,
+    krpc::MessageExtension,
+};
+use siphasher::{
+    sip::SipHasher24,
+    sip128::{Hasher128, SipHasher24 as SipHasher128},
+};
+
+use crate::secret::ct_eq;
+
+/// Key of the admission tag added to the messages of an overlay, see [`OverlayAdmission`].
+const ADMISSION_FIELD: &str = "adm";
+
+/// The secret shared by the nodes of a private overlay, see [`OverlayConfig::secret`].
+///
+/// Equality is checked in constant time, and the secret does not end up in logs. With the
+/// `zeroize` feature, it is wiped from memory when dropped.
+#[derive(Clone)]
+pub struct OverlaySecret(Vec<u8>);
+
+impl OverlaySecret {
+    /// Get the length of the secret.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check if the secret is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Vec<u8>> for OverlaySecret {
+    fn from(bytes: Vec<u8>) -> OverlaySecret {
+        OverlaySecret(bytes)
+    }
+}
+
+impl From<&str> for OverlaySecret {
+    fn from(secret: &str) -> OverlaySecret {
+        OverlaySecret(secret.as_bytes().to_vec())
+    }
+}
+
+impl PartialEq for OverlaySecret {
+    fn eq(&self, other: &OverlaySecret) -> bool {
+        ct_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for OverlaySecret {}
+
+/// Only the length is shown.
+impl fmt::Debug for OverlaySecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OverlaySecret({} bytes)", self.0.len())
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for OverlaySecret {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+/// A private DHT the crawler runs in instead of the public one: an isolated routing domain
+/// with its own bootstrap nodes, see [`CrawlerConfig::overlay`].
+///
+/// [`CrawlerConfig::overlay`]: super::CrawlerConfig::overlay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverlayConfig {
+    /// Name of the overlay, the same on every node of it: the routing domain of the nodes
+    /// ([`Domain::named`]) and the admission tags derive from it. Neither empty nor holding
+    /// whitespace or `#`.
+    pub name: String,
+    /// Nodes (`host:port`) of the overlay contacted when no other contact is known, in place
+    /// of the bootstrap nodes of the public DHT.
+    pub bootstrap_nodes: Vec<String>,
+    /// Secret shared by the nodes of the overlay: the messages sent carry a tag derived from
+    /// it, and the messages received without a valid one are rejected as malformed (see
+    /// [`OverlayAdmission`]), so the nodes of the public DHT are never answered nor learned.
+    /// Only with it are the nodes learned tagged with the domain of the overlay. Without it,
+    /// the overlay is only kept apart by its bootstrap nodes and the contacts given.
+    pub secret: Option<OverlaySecret>,
+}
+
+impl OverlayConfig {
+    /// Create an overlay without a shared secret.
+    pub fn new(name: &str, bootstrap_nodes: Vec<String>) -> OverlayConfig {
+        OverlayConfig {
+            name: name.to_string(),
+            bootstrap_nodes,
+            secret: None,
+        }
+    }
+
+    /// Get the routing domain of the nodes of the overlay.
+    pub fn domain(&self) -> Domain {
+        Domain::named(&self.name)
+    }
+
+    /// Get the admission of the messages of the overlay, if it has a shared secret.
+    pub fn admission(&self) -> Option<OverlayAdmission> {
+        self.secret
+            .as_ref()
+            .map(|secret| OverlayAdmission::new(&self.name, secret))
+    }
+}
+
+/// Admission of the messages of a private overlay, as a [`MessageExtension`]: every message
+/// sent carries an `adm` field, a keyed hash (SipHash-2-4) of its kind and transaction id,
+/// and the messages received without the right one are rejected.
+///
+/// The key derives from the name of the overlay and its shared secret. The tag keeps the
+/// nodes outside of the overlay (e.g. the public DHT) out, it does not authenticate the
+/// messages: their other fields are not covered, and a tag can be replayed with its
+/// transaction id.
+pub struct OverlayAdmission {
+    key: [u8; 16],
+}
+
+impl OverlayAdmission {
+    /// Create the admission of the overlay `name` with the shared `secret`.
+    pub fn new(name: &str, secret: &OverlaySecret) -> OverlayAdmission {
+        let mut hasher = SipHasher128::new();
+        hasher.write(b"bitcrawler overlay admission");
+        hasher.write_usize(name.len());
+        hasher.write(name.as_bytes());
+        hasher.write(&secret.0);
+        OverlayAdmission {
+            key: hasher.finish128().as_bytes(),
+        }
+    }
+
+    /// Get the tag of `message`, `None` if it has no kind or transaction id.
+    fn tag(&self, message: &BencodeDict) -> Option<[u8; 8]> {
+        let kind = string_field(message, b"y")?;
+        let transaction_id = string_field(message, b"t")?;
+        let mut hasher = SipHasher24::new_with_key(&self.key);
+        hasher.write(kind);
+        hasher.write(transaction_id);
+        Some(hasher.finish().to_le_bytes())
+    }
+}
+
+impl MessageExtension for OverlayAdmission {
+    fn on_encode(&self, message: &mut BencodeDict) {
+        let Some(tag) = self.tag(message) else {
+            return;
+        };
+        message.retain(|(key, _)| key.as_ref() != ADMISSION_FIELD.as_bytes());
+        message.push((
+            ADMISSION_FIELD.into(),
+            BencodeValue::ByteString(tag.to_vec().into()),
+        ));
+    }
+
+    fn on_parse(&self, message: &BencodeDict) -> Result<(), &'static str> {
+        let Some(tag) = string_field(message, ADMISSION_FIELD.as_bytes()) else {
+            return Err("Missing 'adm' field");
+        };
+        match self.tag(message) {
+            Some(expected) if ct_eq(&expected, tag) => Ok(()),
+            _ => Err("Invalid 'adm' field"),
+        }
+    }
+}
+
+/// The key is not shown.
+impl fmt::Debug for OverlayAdmission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OverlayAdmission(..)")
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for OverlayAdmission {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.key);
+    }
+}
+
+/// Get the string value of `key` in `message`.
+fn string_field<'a>(message: &'a BencodeDict, key: &[u8]) -> Option<&'a [u8]> {
+    message.iter().find_map(|(k, value)| match value {
+        BencodeValue::ByteString(value) if k.as_ref() == key => Some(value.as_ref()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcrawler_proto::krpc::MessageExtensions;
+
+    use super::*;
+
+    fn string(value: &str) -> BencodeValue {
+        BencodeValue::ByteString(value.into())
+    }
+
+    #[test]
+    fn test_overlay_admission() {
+        let mut overlay = OverlayConfig::new("lab", vec!["192.0.2.1:6881".into()]);
+        assert!(overlay.admission().is_none());
+        overlay.secret = Some("hunter2".into());
+        assert_eq!(overlay.domain(), Domain::named("lab"));
+        assert_eq!(
+            format!("{:?}", overlay.secret),
+            "Some(OverlaySecret(7 bytes))"
+        );
+        let admission = MessageExtensions::new().with(overlay.admission().unwrap());
+        let ping = || {
+            BencodeValue::from_dict(vec![
+                ("t", string("aa")),
+                ("y", string("q")),
+                ("q", string("ping")),
+            ])
+        };
+
+        let mut message = ping();
+        assert_eq!(admission.parse(&message), Err("Missing 'adm' field"));
+        admission.encode(&mut message);
+        assert_eq!(admission.parse(&message), Ok(()));
+        // Sealing again replaces the tag.
+        let mut sealed = message.clone();
+        admission.encode(&mut sealed);
+        assert_eq!(sealed, message);
+
+        // Another secret, or another overlay with the same secret.
+        let other = |name: &str, secret: &str| {
+            MessageExtensions::new().with(OverlayAdmission::new(name, &secret.into()))
+        };
+        assert_eq!(
+            other("lab", "hunter3").parse(&message),
+            Err("Invalid 'adm' field")
+        );
+        assert_eq!(
+            other("lab2", "hunter2").parse(&message),
+            Err("Invalid 'adm' field")
+        );
+        assert_eq!(other("lab", "hunter2").parse(&message), Ok(()));
+
+        // The tag is bound to the transaction id.
+        if let BencodeValue::Dict(dict) = &mut message
+            && let Some((_, t)) = dict.iter_mut().find(|(key, _)| key.as_ref() == b"t")
+        {
+            *t = string("bb");
+        }
+        assert_eq!(admission.parse(&message), Err("Invalid 'adm' field"));
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{
+    kademlia::Id160,
+    krpc::{
+        ResponseType, SampleDeviation, SampleInfohashes, TryFromArguments,
+        query::{QUERY_TYPE_GET, QUERY_TYPE_GET_PEERS, QUERY_TYPE_SAMPLE_INFOHASHES},
+    },
+};
+
+use crate::{node::NodeEvent, ratelimit::TokenBucket};
+
+/// Configuration of a [`CapabilityProber`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeConfig {
+    /// Average number of nodes probed per second (3 queries each).
+    pub rate: f64,
+    /// Maximum number of nodes probed in a burst.
+    pub burst: u32,
+    /// Maximum number of discovered nodes waiting for their probe. The nodes discovered past
+    /// it are not probed.
+    pub max_queued: usize,
+    /// Time after which the queries of a probe left unanswered count as
+    /// [`Support::NoAnswer`].
+    pub timeout: Duration,
+    /// Target of the probe queries, [`CrawlerConfig::lookup_target`] if `None`.
+    ///
+    /// Nodes only return the scrape filters (BEP 33) for the info hashes they store peers for:
+    /// a popular info hash finds more of the nodes supporting it. With a crawl that does not
+    /// scrape, a target other than the lookup target of the crawl keeps its `get_peers`
+    /// replies from being taken for the probe ones.
+    ///
+    /// [`CrawlerConfig::lookup_target`]: super::CrawlerConfig::lookup_target
+    pub target: Option<Id160>,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            rate: 1.0,
+            burst: 4,
+            max_queued: 1024,
+            timeout: Duration::from_secs(10),
+            target: None,
+        }
+    }
+}
+
+/// A DHT extension probed by a [`CapabilityProber`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    /// `sample_infohashes` queries (BEP 51).
+    SampleInfohashes,
+    /// Swarm size filters in the `get_peers` replies (BEP 33).
+    Scrape,
+    /// `get` queries of the storage of arbitrary data (BEP 44).
+    Storage,
+}
+
+impl Extension {
+    /// Get the extension probed by a query of method `query_type`.
+    fn of(query_type: &[u8]) -> Option<Extension> {
+        match query_type {
+            QUERY_TYPE_SAMPLE_INFOHASHES => Some(Extension::SampleInfohashes),
+            QUERY_TYPE_GET_PEERS => Some(Extension::Scrape),
+            QUERY_TYPE_GET => Some(Extension::Storage),
+            _ => None,
+        }
+    }
+}
+
+/// Support of an extension by a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Support {
+    /// The reply has the fields of the extension.
+    Supported,
+    /// The node replied with an error, or without the fields of the extension.
+    Unsupported,
+    /// The node did not reply in time.
+    NoAnswer,
+}
+
+/// Support of the probed extensions by a node, see [`CrawlEvent::CapabilitiesProbed`].
+///
+/// [`CrawlEvent::CapabilitiesProbed`]: crate::sink::CrawlEvent::CapabilitiesProbed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeCapabilities {
+    /// `sample_infohashes` queries (BEP 51).
+    pub sample_infohashes: Support,
+    /// Swarm size filters in the `get_peers` replies (BEP 33).
+    pub scrape: Support,
+    /// `get` queries (BEP 44).
+    pub storage: Support,
+}
+
+/// Number of nodes per [`Support`] of an extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SupportCounts {
+    /// Nodes supporting the extension.
+    pub supported: u64,
+    /// Nodes not supporting it.
+    pub unsupported: u64,
+    /// Nodes that did not answer.
+    pub no_answer: u64,
+}
+
+impl SupportCounts {
+    fn add(&mut self, support: Support) {
+        match support {
+            Support::Supported => self.supported += 1,
+            Support::Unsupported => self.unsupported += 1,
+            Support::NoAnswer => self.no_answer += 1,
+        }
+    }
+
+    /// Get the share of the nodes that answered supporting the extension, `0` before any.
+    pub fn adoption(&self) -> f64 {
+        let answered = self.supported + self.unsupported;
+        if answered == 0 {
+            0.0
+        } else {
+            self.supported as f64 / answered as f64
+        }
+    }
+}
+
+/// Counters of a [`CapabilityProber`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CapabilityStats {
+    /// Nodes probed, with a result.
+    pub nodes_probed: u64,
+    /// Nodes not probed because the queue was full, see [`ProbeConfig::max_queued`].
+    pub skipped: u64,
+    /// Support of `sample_infohashes` (BEP 51).
+    pub sample_infohashes: SupportCounts,
+    /// Support of the scrape filters (BEP 33).
+    pub scrape: SupportCounts,
+    /// Support of `get` (BEP 44).
+    pub storage: SupportCounts,
+    /// Deviations from BEP 51 of the `sample_infohashes` replies.
+    pub sample_deviations: SampleDeviationCounts,
+}
+
+/// Number of `sample_infohashes` replies (BEP 51) per [`SampleDeviation`] from the BEP, to
+/// tell which shapes the clients actually send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SampleDeviationCounts {
+    /// Replies parsed.
+    pub replies: u64,
+    /// Replies with at least one deviation.
+    pub nonconforming: u64,
+    pub missing_interval: u64,
+    pub invalid_interval: u64,
+    pub missing_num: u64,
+    pub invalid_num: u64,
+    pub num_below_samples: u64,
+    pub missing_samples: u64,
+    pub invalid_samples: u64,
+    pub samples_as_list: u64,
+    pub truncated_samples: u64,
+}
+
+impl SampleDeviationCounts {
+    /// Count a reply with `deviations`.
+    pub fn add(&mut self, deviations: &[SampleDeviation]) {
+        self.replies += 1;
+        if !deviations.is_empty() {
+            self.nonconforming += 1;
+        }
+        for &deviation in deviations {
+            *self.count_mut(deviation) += 1;
+        }
+    }
+
+    /// Get the number of replies with `deviation`.
+    pub fn get(&self, deviation: SampleDeviation) -> u64 {
+        let mut counts = *self;
+        *counts.count_mut(deviation)
+    }
+
+    /// Iterate over the deviations seen and their number of replies.
+    pub fn iter(&self) -> impl Iterator<Item = (SampleDeviation, u64)> + '_ {
+        SampleDeviation::ALL
+            .into_iter()
+            .map(|deviation| (deviation, self.get(deviation)))
+            .filter(|(_, count)| *count > 0)
+    }
+
+    fn count_mut(&mut self, deviation: SampleDeviation) -> &mut u64 {
+        match deviation {
+            SampleDeviation::MissingInterval => &mut self.missing_interval,
+            SampleDeviation::InvalidInterval => &mut self.invalid_interval,
+            SampleDeviation::MissingNum => &mut self.missing_num,
+            SampleDeviation::InvalidNum => &mut self.invalid_num,
+            SampleDeviation::NumBelowSamples => &mut self.num_below_samples,
+            SampleDeviation::MissingSamples => &mut self.missing_samples,
+            SampleDeviation::InvalidSamples => &mut self.invalid_samples,
+            SampleDeviation::SamplesAsList => &mut self.samples_as_list,
+            SampleDeviation::TruncatedSamples => &mut self.truncated_samples,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Probe {
+    id: Id160,
+    // Target of the queries, kept if the target of the prober changes meanwhile.
+    target: Id160,
+    deadline: Instant,
+    sample_infohashes: Option<Support>,
+    scrape: Option<Support>,
+    storage: Option<Support>,
+}
+
+impl Probe {
+    fn result(&mut self, extension: Extension) -> &mut Option<Support> {
+        match extension {
+            Extension::SampleInfohashes => &mut self.sample_infohashes,
+            Extension::Scrape => &mut self.scrape,
+            Extension::Storage => &mut self.storage,
+        }
+    }
+
+    /// Get the capabilities once every result is known, or with the missing ones unanswered.
+    fn capabilities(&self, complete: bool) -> Option<NodeCapabilities> {
+        let missing = (!complete).then_some(Support::NoAnswer);
+        Some(NodeCapabilities {
+            sample_infohashes: self.sample_infohashes.or(missing)?,
+            scrape: self.scrape.or(missing)?,
+            storage: self.storage.or(missing)?,
+        })
+    }
+}
+
+/// Probes the newly discovered nodes for the DHT extensions they support, at a bounded rate.
+///
+/// Each node gets a `sample_infohashes` query (BEP 51), a `get_peers` asking for the scrape
+/// filters (BEP 33) and a `get` (BEP 44), all toward [`ProbeConfig::target`]. The prober only
+/// keeps the books: the caller sends the queries of [`CapabilityProber::next_probe`], and
+/// feeds the events of the node to [`CapabilityProber::observe`].
+#[derive(Debug, Clone)]
+pub struct CapabilityProber {
+    config: ProbeConfig,
+    target: Id160,
+    bucket: TokenBucket,
+    queue: VecDeque<(Id160, SocketAddr)>,
+    probes: HashMap<SocketAddr, Probe>,
+    stats: CapabilityStats,
+}
+
+impl CapabilityProber {
+    /// Create a prober probing toward `target` unless the configuration sets one.
+    pub fn new(config: ProbeConfig, target: Id160) -> CapabilityProber {
+        CapabilityProber {
+            target: config.target.unwrap_or(target),
+            bucket: TokenBucket::new(config.rate, config.burst),
+            config,
+            queue: VecDeque::new(),
+            probes: HashMap::new(),
+            stats: CapabilityStats::default(),
+        }
+    }
+
+    /// Apply a new configuration, e.g. after a reload, probing toward `target` unless it sets
+    /// one. The nodes already queued stay, and the probes in flight keep their target and
+    /// timeout.
+    pub fn set_config(&mut self, config: ProbeConfig, target: Id160) {
+        if (config.rate, config.burst) != (self.config.rate, self.config.burst) {
+            self.bucket = TokenBucket::new(config.rate, config.burst);
+        }
+        self.target = config.target.unwrap_or(target);
+        self.config = config;
+    }
+
+    /// Get the target of the probe queries.
+    pub fn target(&self) -> Id160 {
+        self.target
+    }
+
+    /// Queue a newly discovered node for probing, returns `false` if the queue is full.
+    pub fn enqueue(&mut self, id: Id160, address: SocketAddr) -> bool {
+        if self.queue.len() >= self.config.max_queued {
+            self.stats.skipped += 1;
+            return false;
+        }
+        self.queue.push_back((id, address));
+        true
+    }
+
+    /// Get the next node to probe at `now`, if the rate allows it. The probe is waited for
+    /// from then on.
+    pub fn next_probe(&mut self, now: Instant) -> Option<(Id160, SocketAddr)> {
+        if self.queue.is_empty() || !self.bucket.try_take(now) {
+            return None;
+        }
+        let (id, address) = self.queue.pop_front()?;
+        self.probes.insert(
+            address,
+            Probe {
+                id,
+                target: self.target,
+                deadline: now + self.config.timeout,
+                sample_infohashes: None,
+                scrape: None,
+                storage: None,
+            },
+        );
+        Some((id, address))
+    }
+
+    /// Record an event of the node, returns the capabilities of the node probed if its probe
+    /// is complete.
+    ///
+    /// The `sample_infohashes` replies are parsed tolerantly (see [`SampleInfohashes`]): a
+    /// reply with samples supports the extension whatever its other deviations from BEP 51,
+    /// counted in [`CapabilityStats::sample_deviations`].
+    pub fn observe(&mut self, event: &NodeEvent) -> Option<(Id160, SocketAddr, NodeCapabilities)> {
+        let query = match event {
+            NodeEvent::Response { query, .. }
+            | NodeEvent::Error { query, .. }
+            | NodeEvent::Timeout { query } => query,
+            NodeEvent::Query { .. } | NodeEvent::Duplicate { .. } => return None,
+        };
+        // Only the queries of a probe, toward the target it was sent with.
+        let target = self.probes.get(&query.destination)?.target;
+        if query.target != Some(target) {
+            return None;
+        }
+        let support = match event {
+            NodeEvent::Response {
+                query, response, ..
+            } => {
+                let supported = match response.get_response_type() {
+                    ResponseType::GetPeers(get_peers) => {
+                        get_peers.get_seeds_filter().is_some()
+                            && get_peers.get_peers_filter().is_some()
+                    }
+                    ResponseType::Raw(args) => match Extension::of(&query.query_type) {
+                        Some(Extension::SampleInfohashes) => {
+                            match SampleInfohashes::<Id160>::try_from_arguments(args) {
+                                Ok(reply) => {
+                                    self.stats.sample_deviations.add(reply.deviations());
+                                    !reply.deviations().iter().any(|deviation| {
+                                        matches!(
+                                            deviation,
+                                            SampleDeviation::MissingSamples
+                                                | SampleDeviation::InvalidSamples
+                                        )
+                                    })
+                                }
+                                Err(_) => false,
+                            }
+                        }
+                        // The field required in the replies of the extension.
+                        Some(Extension::Storage) => {
+                            args.iter().any(|(k, _)| k.as_ref() == b"token")
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                if supported {
+                    Support::Supported
+                } else {
+                    Support::Unsupported
+                }
+            }
+            NodeEvent::Error { .. } => Support::Unsupported,
+            NodeEvent::Timeout { .. } => Support::NoAnswer,
+            NodeEvent::Query { .. } | NodeEvent::Duplicate { .. } => return None,
+        };
+        let extension = Extension::of(&query.query_type)?;
+        self.record(query.destination, extension, support)
+    }
+
+    /// Record the support of `extension` by the node probed at `address`, returns its
+    /// capabilities if its probe is complete.
+    ///
+    /// A node found supporting the extension stays so, e.g. when another `get_peers` reply of
+    /// the node, without the filters, is taken for the probe one.
+    pub fn record(
+        &mut self,
+        address: SocketAddr,
+        extension: Extension,
+        support: Support,
+    ) -> Option<(Id160, SocketAddr, NodeCapabilities)> {
+        let probe = self.probes.get_mut(&address)?;
+        let result = probe.result(extension);
+        if *result != Some(Support::Supported) {
+            *result = Some(support);
+        }
+        let capabilities = probe.capabilities(true)?;
+        let probe = self.probes.remove(&address)?;
+        Some(self.complete(probe.id, address, capabilities))
+    }
+
+    /// Complete the probes past their timeout at `now`, the queries left unanswered count as
+    /// [`Support::NoAnswer`].
+    pub fn expire(&mut self, now: Instant) -> Vec<(Id160, SocketAddr, NodeCapabilities)> {
+        let expired: Vec<SocketAddr> = self
+            .probes
+            .iter()
+            .filter(|(_, probe)| now >= probe.deadline)
+            .map(|(address, _)| *address)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|address| {
+                let probe = self.probes.remove(&address)?;
+                let capabilities = probe.capabilities(false)?;
+                Some(self.complete(probe.id, address, capabilities))
+            })
+            .collect()
+    }
+
+    /// Get the counters of the prober.
+    pub fn stats(&self) -> CapabilityStats {
+        self.stats
+    }
+
+    fn complete(
+        &mut self,
+        id: Id160,
+        address: SocketAddr,
+        capabilities: NodeCapabilities,
+    ) -> (Id160, SocketAddr, NodeCapabilities) {
+        self.stats.nodes_probed += 1;
+        self.stats
+            .sample_infohashes
+            .add(capabilities.sample_infohashes);
+        self.stats.scrape.add(capabilities.scrape);
+        self.stats.storage.add(capabilities.storage);
+        (id, address, capabilities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_capability_prober() {
+        let now = Instant::now();
+        let config = ProbeConfig {
+            rate: 0.0,
+            burst: 2,
+            max_queued: 3,
+            ..ProbeConfig::default()
+        };
+        let mut prober = CapabilityProber::new(config, Id160([7; 20]));
+        let address = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        for port in 1..=4 {
+            prober.enqueue(Id160([port as u8; 20]), address(port));
+        }
+        // Two probes in the burst, the third waits for a token that never comes.
+        assert_eq!(prober.next_probe(now), Some((Id160([1; 20]), address(1))));
+        assert_eq!(prober.next_probe(now), Some((Id160([2; 20]), address(2))));
+        assert_eq!(prober.next_probe(now), None);
+
+        use Extension::*;
+        use Support::*;
+        assert_eq!(prober.record(address(1), SampleInfohashes, Supported), None);
+        assert_eq!(prober.record(address(1), Scrape, Supported), None);
+        // A reply without filters does not undo a reply with them.
+        assert_eq!(prober.record(address(1), Scrape, Unsupported), None);
+        let capabilities = NodeCapabilities {
+            sample_infohashes: Supported,
+            scrape: Supported,
+            storage: Unsupported,
+        };
+        assert_eq!(
+            prober.record(address(1), Storage, Unsupported),
+            Some((Id160([1; 20]), address(1), capabilities))
+        );
+        // Not probed (anymore).
+        assert_eq!(prober.record(address(1), Storage, Supported), None);
+        assert_eq!(prober.record(address(3), Storage, Supported), None);
+
+        assert_eq!(prober.record(address(2), Storage, Supported), None);
+        assert!(prober.expire(now).is_empty());
+        let expired = prober.expire(now + Duration::from_secs(10));
+        assert_eq!(
+            expired,
+            [(
+                Id160([2; 20]),
+                address(2),
+                NodeCapabilities {
+                    sample_infohashes: NoAnswer,
+                    scrape: NoAnswer,
+                    storage: Supported,
+                }
+            )]
+        );
+
+        let stats = prober.stats();
+        assert_eq!(stats.nodes_probed, 2);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.storage.adoption(), 0.5);
+        assert_eq!(stats.sample_infohashes.adoption(), 1.0);
+        assert_eq!(stats.scrape.no_answer, 1);
+    }
+
+    #[test]
+    fn test_sample_infohashes_deviations() {
+        use bitcrawler_proto::bencode::BencodeValue;
+
+        use crate::node::{DhtResponse, PendingQuery};
+
+        let target = Id160([7; 20]);
+        let mut prober = CapabilityProber::new(ProbeConfig::default(), target);
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        prober.enqueue(Id160([1; 20]), address);
+        let now = Instant::now();
+        prober.next_probe(now).unwrap();
+
+        let query = PendingQuery {
+            query_type: QUERY_TYPE_SAMPLE_INFOHASHES.to_vec(),
+            destination: address,
+            target: Some(target),
+            sent_at: now,
+            deadline: now + Duration::from_secs(10),
+        };
+        let reply = |samples: BencodeValue| NodeEvent::Response {
+            query: query.clone(),
+            response: DhtResponse::custom(
+                "aa",
+                vec![
+                    ("id".into(), BencodeValue::ByteString(vec![1; 20].into())),
+                    ("samples".into(), samples),
+                ],
+            ),
+            rtt: Duration::from_millis(10),
+        };
+        // Samples without `interval` nor `num`, and a partial info hash: still supported.
+        assert_eq!(
+            prober.observe(&reply(BencodeValue::ByteString(vec![2; 30].into()))),
+            None
+        );
+        assert_eq!(
+            prober.probes[&address].sample_infohashes,
+            Some(Support::Supported)
+        );
+        // Samples of the wrong type.
+        prober.observe(&reply(BencodeValue::Integer(0)));
+
+        let deviations = prober.stats().sample_deviations;
+        assert_eq!((deviations.replies, deviations.nonconforming), (2, 2));
+        assert_eq!(deviations.get(SampleDeviation::MissingInterval), 2);
+        assert_eq!(
+            deviations.iter().collect::<Vec<_>>(),
+            [
+                (SampleDeviation::MissingInterval, 2),
+                (SampleDeviation::MissingNum, 2),
+                (SampleDeviation::InvalidSamples, 1),
+                (SampleDeviation::TruncatedSamples, 1),
+            ]
+        );
+
+        // A new target applies to the next probes, the probe in flight keeps its own.
+        let other = Id160([8; 20]);
+        prober.set_config(ProbeConfig::default(), other);
+        assert_eq!(prober.target(), other);
+        let timeout = NodeEvent::Timeout {
+            query: PendingQuery {
+                query_type: QUERY_TYPE_GET.to_vec(),
+                ..query.clone()
+            },
+        };
+        assert_eq!(prober.observe(&timeout), None);
+        assert_eq!(prober.probes[&address].storage, Some(Support::NoAnswer));
+    }
+}
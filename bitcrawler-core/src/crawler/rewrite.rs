@@ -0,0 +1,228 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+};
+
+use bitcrawler_proto::kademlia::Id160;
+
+/// Configuration of a [`PortRewriteTracker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRewriteConfig {
+    /// Maximum number of node ids tracked. The least recently seen ones are forgotten beyond
+    /// it.
+    pub capacity: usize,
+}
+
+impl Default for PortRewriteConfig {
+    fn default() -> Self {
+        PortRewriteConfig { capacity: 1 << 18 }
+    }
+}
+
+/// How the address a node replied from compares to the address other nodes give for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressCheck {
+    /// Only one of the two addresses is known yet.
+    Unknown,
+    /// Both addresses are the same.
+    Same,
+    /// Same IP address, another port: a middlebox (NAT) rewrites the source port of the node.
+    PortRewritten,
+    /// Another IP address: the node moved, or one of the two is not the node.
+    OtherAddress,
+}
+
+impl AddressCheck {
+    fn compare(observed: SocketAddr, claimed: SocketAddr) -> AddressCheck {
+        if observed == claimed {
+            AddressCheck::Same
+        } else if observed.ip().to_canonical() == claimed.ip().to_canonical() {
+            AddressCheck::PortRewritten
+        } else {
+            AddressCheck::OtherAddress
+        }
+    }
+}
+
+/// Counters of a [`PortRewriteTracker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PortRewriteStats {
+    /// Node ids tracked.
+    pub nodes: usize,
+    /// Comparisons of an observed address with a claimed one.
+    pub compared: u64,
+    /// Comparisons where only the port differed.
+    pub port_rewrites: u64,
+    /// Comparisons where the IP address differed.
+    pub other_addresses: u64,
+}
+
+impl PortRewriteStats {
+    /// Get the share of the comparisons where only the port differed, `0` before any.
+    pub fn port_rewrite_ratio(&self) -> f64 {
+        if self.compared == 0 {
+            0.0
+        } else {
+            self.port_rewrites as f64 / self.compared as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NodeAddresses {
+    // Address the node replied from.
+    observed: Option<SocketAddr>,
+    // Address given for the node in the compact node info of other nodes.
+    claimed: Option<SocketAddr>,
+    stamp: u64,
+}
+
+/// Compares the address each node replies from (the UDP source of its replies) with the
+/// address other nodes give for it in their compact node infos.
+///
+/// A node behind a NAT that rewrites its source port is reachable at the rewritten port only
+/// while the mapping lasts: the counts tell how common that is, and
+/// [`PortRewriteTracker::preferred_address`] which address to keep for a node (e.g. to insert
+/// it in a routing table).
+///
+/// The addresses are kept in bounded memory, the least recently seen nodes are forgotten first.
+#[derive(Debug, Clone)]
+pub struct PortRewriteTracker {
+    config: PortRewriteConfig,
+    nodes: HashMap<Id160, NodeAddresses>,
+    // Nodes by recency stamp, oldest first.
+    by_stamp: BTreeMap<u64, Id160>,
+    next_stamp: u64,
+    stats: PortRewriteStats,
+}
+
+impl PortRewriteTracker {
+    /// Create an empty tracker.
+    pub fn new(config: PortRewriteConfig) -> PortRewriteTracker {
+        PortRewriteTracker {
+            config,
+            nodes: HashMap::new(),
+            by_stamp: BTreeMap::new(),
+            next_stamp: 0,
+            stats: PortRewriteStats::default(),
+        }
+    }
+
+    /// Record that the node `id` replied from `source`.
+    pub fn observe_reply(&mut self, id: Id160, source: SocketAddr) -> AddressCheck {
+        let entry = self.entry(id);
+        entry.observed = Some(source);
+        let check = entry.claimed.map_or(AddressCheck::Unknown, |claimed| {
+            AddressCheck::compare(source, claimed)
+        });
+        self.count(check);
+        check
+    }
+
+    /// Record that another node gave `address` for the node `id`.
+    pub fn observe_claim(&mut self, id: Id160, address: SocketAddr) -> AddressCheck {
+        let entry = self.entry(id);
+        entry.claimed = Some(address);
+        let check = entry.observed.map_or(AddressCheck::Unknown, |observed| {
+            AddressCheck::compare(observed, address)
+        });
+        self.count(check);
+        check
+    }
+
+    /// Get the address to contact the node `id` at: the one it replied from if known, the
+    /// address other nodes give for it otherwise.
+    pub fn preferred_address(&self, id: &Id160) -> Option<SocketAddr> {
+        self.nodes
+            .get(id)
+            .and_then(|node| node.observed.or(node.claimed))
+    }
+
+    /// Get the counters of the tracker.
+    pub fn stats(&self) -> PortRewriteStats {
+        PortRewriteStats {
+            nodes: self.nodes.len(),
+            ..self.stats
+        }
+    }
+
+    fn entry(&mut self, id: Id160) -> &mut NodeAddresses {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        if let Some(node) = self.nodes.get(&id) {
+            self.by_stamp.remove(&node.stamp);
+        } else if self.nodes.len() >= self.config.capacity.max(1)
+            && let Some((_, oldest)) = self.by_stamp.pop_first()
+        {
+            self.nodes.remove(&oldest);
+        }
+        self.by_stamp.insert(stamp, id);
+        let node = self.nodes.entry(id).or_insert(NodeAddresses {
+            observed: None,
+            claimed: None,
+            stamp,
+        });
+        node.stamp = stamp;
+        node
+    }
+
+    fn count(&mut self, check: AddressCheck) {
+        match check {
+            AddressCheck::Unknown => return,
+            AddressCheck::Same => {}
+            AddressCheck::PortRewritten => self.stats.port_rewrites += 1,
+            AddressCheck::OtherAddress => self.stats.other_addresses += 1,
+        }
+        self.stats.compared += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_rewrites() {
+        let mut tracker = PortRewriteTracker::new(PortRewriteConfig { capacity: 2 });
+        let id = |i: u8| Id160([i; 20]);
+        let address = |host: u8, port: u16| SocketAddr::from(([192, 0, 2, host], port));
+
+        assert_eq!(
+            tracker.observe_claim(id(1), address(1, 6881)),
+            AddressCheck::Unknown
+        );
+        assert_eq!(tracker.preferred_address(&id(1)), Some(address(1, 6881)));
+        assert_eq!(
+            tracker.observe_reply(id(1), address(1, 40000)),
+            AddressCheck::PortRewritten
+        );
+        // The address the node replied from is preferred.
+        assert_eq!(tracker.preferred_address(&id(1)), Some(address(1, 40000)));
+        assert_eq!(
+            tracker.observe_claim(id(1), address(1, 40000)),
+            AddressCheck::Same
+        );
+        assert_eq!(
+            tracker.observe_claim(id(1), address(2, 40000)),
+            AddressCheck::OtherAddress
+        );
+
+        // The least recently seen node is forgotten first.
+        tracker.observe_reply(id(2), address(3, 6881));
+        tracker.observe_claim(id(1), address(1, 40000));
+        tracker.observe_reply(id(3), address(4, 6881));
+        assert_eq!(tracker.preferred_address(&id(2)), None);
+        let stats = tracker.stats();
+        assert_eq!(
+            stats,
+            PortRewriteStats {
+                nodes: 2,
+                compared: 4,
+                port_rewrites: 1,
+                other_addresses: 1,
+            }
+        );
+        assert_eq!(stats.port_rewrite_ratio(), 0.25);
+    }
+}
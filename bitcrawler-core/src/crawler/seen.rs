@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bitcrawler_proto::kademlia::Id160;
+
+use crate::bloom::BloomFilter;
+
+/// Approximate memory used by an id of the exact tier of a [`SeenSet`] (the id and its
+/// recency in two maps, with their overhead).
+const EXACT_ENTRY_COST: usize = 96;
+
+/// Configuration of a [`SeenSet`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeenSetConfig {
+    /// Memory used by the exact tier, in bytes. The least recently seen ids are moved to the
+    /// Bloom filter beyond it.
+    pub memory_budget: usize,
+    /// Number of ids the Bloom filter is sized for.
+    pub expected_items: usize,
+    /// Target false-positive rate of the Bloom filter, i.e. the probability of taking a new id
+    /// for an already seen one.
+    pub false_positive_rate: f64,
+}
+
+impl Default for SeenSetConfig {
+    fn default() -> Self {
+        SeenSetConfig {
+            memory_budget: 64 << 20,
+            expected_items: 10_000_000,
+            false_positive_rate: 0.001,
+        }
+    }
+}
+
+/// Number of distinct ids seen by a [`SeenSet`], with error bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SeenEstimate {
+    /// Ids known to be distinct: the set never takes a seen id for a new one, so this is a lower
+    /// bound.
+    pub counted: u64,
+    /// Best estimate, accounting for the new ids taken for seen ones by the Bloom filter.
+    pub estimate: f64,
+    /// Upper bound (three standard deviations above the estimate).
+    pub upper: f64,
+}
+
+/// The set of the node ids seen by a crawl, in bounded memory.
+///
+/// The recently seen ids are kept exactly, up to [`SeenSetConfig::memory_budget`]; the least
+/// recently seen ones are then moved to a Bloom filter. A seen id is always recognized, but a
+/// new id may be taken for a seen one once the filter is in use (see [`SeenSet::estimate`]).
+#[derive(Debug, Clone)]
+pub struct SeenSet {
+    // Recency stamp of the ids of the exact tier, and the ids by stamp (oldest first).
+    recent: HashMap<Id160, u64>,
+    by_stamp: BTreeMap<u64, Id160>,
+    next_stamp: u64,
+    capacity: usize,
+    older: BloomFilter,
+    counted: u64,
+    // Expected number of new ids taken for seen ones by the filter.
+    expected_missed: f64,
+}
+
+impl SeenSet {
+    /// Create an empty set. The Bloom filter is allocated up front.
+    ///
+    /// Fails if the false-positive rate is not between 0 and 1.
+    pub fn new(config: &SeenSetConfig) -> Result<SeenSet, &'static str> {
+        Ok(SeenSet {
+            recent: HashMap::new(),
+            by_stamp: BTreeMap::new(),
+            next_stamp: 0,
+            capacity: (config.memory_budget / EXACT_ENTRY_COST).max(1),
+            older: BloomFilter::with_rate(config.expected_items, config.false_positive_rate)?,
+            counted: 0,
+            expected_missed: 0.0,
+        })
+    }
+
+    /// Record an id, returns true if it was not seen before.
+    ///
+    /// May return false for a new id once older ids were moved to the Bloom filter.
+    pub fn insert(&mut self, id: Id160) -> bool {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        if let Some(previous) = self.recent.insert(id, stamp) {
+            self.by_stamp.remove(&previous);
+            self.by_stamp.insert(stamp, id);
+            return false;
+        }
+        // Ids found in the filter move back to the exact tier too: they are likely to be seen
+        // again soon.
+        self.by_stamp.insert(stamp, id);
+        let new = if self.older.is_empty() {
+            true
+        } else if self.older.contains(id.as_bytes()) {
+            false
+        } else {
+            // Every new id accepted stands for p / (1 - p) new ids rejected on average.
+            let rate = self.older.false_positive_rate().min(0.99);
+            self.expected_missed += rate / (1.0 - rate);
+            true
+        };
+        if new {
+            self.counted += 1;
+        }
+        while self.recent.len() > self.capacity {
+            let (_, oldest) = self.by_stamp.pop_first().expect("stamps out of sync");
+            self.recent.remove(&oldest);
+            self.older.insert(oldest.as_bytes());
+        }
+        new
+    }
+
+    /// Check if an id was (probably) seen.
+    pub fn contains(&self, id: &Id160) -> bool {
+        self.recent.contains_key(id) || self.older.contains(id.as_bytes())
+    }
+
+    /// Get the number of ids reported as new by [`SeenSet::insert`].
+    pub fn len(&self) -> usize {
+        self.counted as usize
+    }
+
+    /// Check if no id was inserted.
+    pub fn is_empty(&self) -> bool {
+        self.counted == 0
+    }
+
+    /// Get the number of ids kept exactly.
+    pub fn exact_len(&self) -> usize {
+        self.recent.len()
+    }
+
+    /// Estimate the number of distinct ids seen.
+    pub fn estimate(&self) -> SeenEstimate {
+        let estimate = self.counted as f64 + self.expected_missed;
+        SeenEstimate {
+            counted: self.counted,
+            estimate,
+            // The missed ids are roughly Poisson distributed.
+            upper: estimate + 3.0 * self.expected_missed.sqrt(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(i: u32) -> Id160 {
+        let mut id = [0u8; 20];
+        id[16..].copy_from_slice(&i.to_be_bytes());
+        Id160(id)
+    }
+
+    #[test]
+    fn test_exact_within_budget() {
+        let mut seen = SeenSet::new(&SeenSetConfig::default()).unwrap();
+        assert!(seen.insert(id(1)));
+        assert!(seen.insert(id(2)));
+        assert!(!seen.insert(id(1)));
+        assert!(seen.contains(&id(2)));
+        assert!(!seen.contains(&id(3)));
+        assert_eq!(seen.len(), 2);
+        let estimate = seen.estimate();
+        assert_eq!(estimate.counted, 2);
+        assert_eq!(estimate.estimate, 2.0);
+        assert_eq!(estimate.upper, 2.0);
+    }
+
+    #[test]
+    fn test_old_ids_move_to_the_filter() {
+        let mut seen = SeenSet::new(&SeenSetConfig {
+            memory_budget: 10 * EXACT_ENTRY_COST,
+            expected_items: 1000,
+            false_positive_rate: 0.01,
+        })
+        .unwrap();
+        for i in 0..100 {
+            assert!(seen.insert(id(i)));
+        }
+        assert_eq!(seen.exact_len(), 10);
+        // Seen ids are always recognized, whichever the tier.
+        for i in 0..100 {
+            assert!(seen.contains(&id(i)));
+            assert!(!seen.insert(id(i)));
+        }
+        assert_eq!(seen.len(), 100);
+    }
+
+    #[test]
+    fn test_estimate_bounds_the_true_count() {
+        // A filter overfilled 5 times, so many new ids are taken for seen ones.
+        let mut seen = SeenSet::new(&SeenSetConfig {
+            memory_budget: 100 * EXACT_ENTRY_COST,
+            expected_items: 1000,
+            false_positive_rate: 0.05,
+        })
+        .unwrap();
+        const DISTINCT: u32 = 5000;
+        for i in 0..DISTINCT {
+            seen.insert(id(i));
+            // Some ids are seen again.
+            if i % 3 == 0 {
+                seen.insert(id(i / 2));
+            }
+        }
+        let estimate = seen.estimate();
+        assert!(estimate.counted < DISTINCT as u64);
+        assert!(estimate.counted as f64 <= estimate.estimate);
+        assert!(
+            (DISTINCT as f64) <= estimate.upper,
+            "{:?} for {} distinct ids",
+            estimate,
+            DISTINCT
+        );
+        // The estimate is within 10% of the truth, the count is off by almost 40%.
+        assert!((DISTINCT as f64 - estimate.estimate).abs() < 0.1 * DISTINCT as f64);
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{hex::Hex, kademlia::Id160};
+
+use super::{
+    CapabilityStats, IdentityStats, PeerConfidenceStats, PortRewriteStats, SeenEstimate,
+    SpoofingStats, StopReason,
+};
+use crate::{
+    limits::TrafficAudit,
+    node::{ReceiveStats, UnmatchedReplies},
+    pipeline::QueueStats,
+    responder::InboundQueryReport,
+    sink::EventCounts,
+    transport::SendFailureStats,
+};
+
+/// Length of the window used to compute rates, in seconds.
+const RATE_WINDOW_SECONDS: u64 = 60;
+
+/// Maximum number of client versions counted in a [`CrawlSnapshot`]: the `v` field is chosen
+/// by the sender, the versions seen once the limit is reached are counted as
+/// [`OTHER_CLIENT_VERSIONS`].
+pub const MAX_CLIENT_VERSIONS: usize = 256;
+/// Label of the client versions not counted on their own, see [`MAX_CLIENT_VERSIONS`].
+pub const OTHER_CLIENT_VERSIONS: &str = "other";
+
+/// Point-in-time view of the progress of a crawl, see [`CrawlerHandle::snapshot`].
+///
+/// [`CrawlerHandle::snapshot`]: super::CrawlerHandle::snapshot
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CrawlSnapshot {
+    /// Time elapsed since the crawler started.
+    pub uptime: Duration,
+    /// Number of distinct node ids seen so far (a lower bound, see `nodes_seen_estimate`).
+    pub nodes_seen: usize,
+    /// Number of distinct node ids seen so far, accounting for the ids the bounded seen-set
+    /// may have missed.
+    pub nodes_seen_estimate: SeenEstimate,
+    /// Number of distinct info hashes seen so far, see
+    /// [`CrawlerConfig::info_hashes`](super::CrawlerConfig::info_hashes).
+    pub info_hashes_seen: SeenEstimate,
+    /// Number of discovered nodes waiting to be contacted.
+    pub frontier_depth: usize,
+    /// Number of queries sent and not answered (nor timed out) yet.
+    pub in_flight_queries: usize,
+    /// Rates averaged over the last minute.
+    pub rates: CrawlRates,
+    /// Number of nodes seen per country code, only set when a [`GeoLookup`] is configured.
+    ///
+    /// [`GeoLookup`]: super::GeoLookup
+    pub countries: Option<BTreeMap<String, u64>>,
+    /// Number of queries received per client version (see [`client_version_label`]). The
+    /// queries without a version are not counted.
+    pub client_versions: BTreeMap<String, u64>,
+    /// Queries received over the last complete window, per kind and source prefix (see
+    /// [`CrawlerConfig::inbound_queries`](super::CrawlerConfig::inbound_queries)).
+    pub inbound_queries: InboundQueryReport,
+    /// Events handed to the sinks, per kind.
+    pub records: EventCounts,
+    /// Queries that were not answered in time.
+    pub timeouts: u64,
+    /// Packets (queries sent and replies received) of the lookups triggered through
+    /// [`CrawlerHandle::lookup`](super::CrawlerHandle::lookup).
+    pub lookup_packets: u64,
+    /// Support of the DHT extensions by the probed nodes, if probing is enabled (see
+    /// [`CrawlerConfig::probe`](super::CrawlerConfig::probe)).
+    pub capabilities: Option<CapabilityStats>,
+    /// Error replies received, per error code (see
+    /// [`ErrorCode`](bitcrawler_proto::krpc::ErrorCode)).
+    pub error_replies: BTreeMap<i64, u64>,
+    /// Datagrams received by the node, malformed ones included.
+    pub received: ReceiveStats,
+    /// Datagrams dropped by the kernel because the receive buffer was full, if supported.
+    pub dropped_datagrams: Option<u64>,
+    /// ICMP errors (e.g. port unreachable) reported by the socket.
+    pub icmp_errors: u64,
+    /// Datagrams received that could not be parsed.
+    pub malformed_datagrams: u64,
+    /// Replies matching no pending query: duplicates and unsolicited ones.
+    pub unmatched_replies: UnmatchedReplies,
+    /// Traffic sent, and refused by the traffic limits.
+    pub traffic: TrafficAudit,
+    /// Sends that failed, or were skipped while their destination was backing off.
+    pub send_failures: SendFailureStats,
+    /// Queue between the receive thread and the crawler, if the node has one (see
+    /// [`NodeConfig::receive_queue`](crate::node::NodeConfig::receive_queue)).
+    pub receive_queue: Option<QueueStats>,
+    /// Queues in front of the sinks that have one (see [`QueuedSink`]), in the order the sinks
+    /// were added.
+    ///
+    /// [`QueuedSink`]: crate::sink::QueuedSink
+    pub sink_queues: Vec<QueueStats>,
+    /// Id changes of the endpoints that replied, see
+    /// [`IdentityTracker`](super::IdentityTracker).
+    pub identities: IdentityStats,
+    /// Addresses nodes reply from compared with the addresses other nodes give for them, see
+    /// [`PortRewriteTracker`](super::PortRewriteTracker).
+    pub port_rewrites: PortRewriteStats,
+    /// Replies returning node ids suspiciously close to their target, see
+    /// [`SpoofingDetector`](super::SpoofingDetector).
+    pub spoofing: SpoofingStats,
+    /// Peers returned, and held back for lack of corroboration, see
+    /// [`PeerCorroboration`](super::PeerCorroboration).
+    pub peer_confidence: PeerConfidenceStats,
+    /// Number of nodes in the routing table of the crawler, see
+    /// [`CrawlerHandle::routing_table`](super::CrawlerHandle::routing_table).
+    pub routing_table_nodes: usize,
+    /// The crawl is paused, see [`CrawlerHandle::pause`](super::CrawlerHandle::pause).
+    pub paused: bool,
+    /// Why the crawl stopped, `None` while it runs.
+    pub stop_reason: Option<StopReason>,
+}
+
+/// A node of the routing table of a crawler, see
+/// [`CrawlerHandle::routing_table`](super::CrawlerHandle::routing_table).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutingEntry {
+    /// Index of the bucket of the node.
+    pub bucket: usize,
+    /// Id of the node.
+    pub id: Id160,
+    /// Address the node answered from.
+    pub address: SocketAddr,
+    /// When the node last answered.
+    pub last_seen: Option<Instant>,
+    /// Round-trip time of the queries to the node, smoothed.
+    pub rtt: Option<Duration>,
+}
+
+/// Per-second rates of a crawl, averaged over the last minute.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CrawlRates {
+    /// Queries sent per second.
+    pub queries_sent: f64,
+    /// Responses received per second.
+    pub responses_received: f64,
+    /// New nodes discovered per second.
+    pub nodes_discovered: f64,
+}
+
+/// Get the label of a client version (the `v` field of a message): the 2 letters of the client
+/// followed by the version bytes in hexadecimal (e.g. `UTb523`), or the whole field in
+/// hexadecimal if it does not start with 2 letters.
+pub fn client_version_label(version: &[u8]) -> String {
+    match version {
+        [a, b, rest @ ..] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+            format!("{}{}{}", *a as char, *b as char, Hex(rest))
+        }
+        _ => Hex(version).to_string(),
+    }
+}
+
+/// Counts events in one-second slots over the last [`RATE_WINDOW_SECONDS`] seconds.
+#[derive(Debug, Clone)]
+pub(crate) struct RateWindow {
+    // (second, count) pairs, indexed by second modulo the window length.
+    slots: [(u64, u64); RATE_WINDOW_SECONDS as usize],
+}
+
+impl RateWindow {
+    pub(crate) fn new() -> RateWindow {
+        RateWindow {
+            slots: [(0, 0); RATE_WINDOW_SECONDS as usize],
+        }
+    }
+
+    /// Record `count` events that happened during `second` (counted from the start).
+    pub(crate) fn record(&mut self, second: u64, count: u64) {
+        let slot = &mut self.slots[(second % RATE_WINDOW_SECONDS) as usize];
+        if slot.0 != second {
+            *slot = (second, 0);
+        }
+        slot.1 += count;
+    }
+
+    /// Average number of events per second over the window ending at `second`.
+    ///
+    /// During the first minute, the average only covers the elapsed seconds.
+    pub(crate) fn per_second(&self, second: u64) -> f64 {
+        let oldest = second.saturating_sub(RATE_WINDOW_SECONDS - 1);
+        let total: u64 = self
+            .slots
+            .iter()
+            .filter(|(slot_second, _)| (oldest..=second).contains(slot_second))
+            .map(|(_, count)| count)
+            .sum();
+        total as f64 / (second - oldest + 1) as f64
+    }
+}
+
+/// Progress of a crawl, shared between the crawler and its handles.
+pub(crate) struct Progress {
+    pub(crate) started: Instant,
+    pub(crate) nodes_seen: SeenEstimate,
+    pub(crate) info_hashes_seen: SeenEstimate,
+    pub(crate) frontier_depth: usize,
+    pub(crate) in_flight_queries: usize,
+    pub(crate) queries_sent: RateWindow,
+    pub(crate) responses_received: RateWindow,
+    pub(crate) nodes_discovered: RateWindow,
+    pub(crate) countries: Option<HashMap<String, u64>>,
+    pub(crate) client_versions: HashMap<String, u64>,
+    pub(crate) inbound_queries: InboundQueryReport,
+    pub(crate) records: EventCounts,
+    pub(crate) timeouts: u64,
+    pub(crate) lookup_packets: u64,
+    pub(crate) capabilities: Option<CapabilityStats>,
+    pub(crate) error_replies: BTreeMap<i64, u64>,
+    pub(crate) received: ReceiveStats,
+    pub(crate) dropped_datagrams: Option<u64>,
+    pub(crate) icmp_errors: u64,
+    pub(crate) malformed_datagrams: u64,
+    pub(crate) unmatched_replies: UnmatchedReplies,
+    pub(crate) traffic: TrafficAudit,
+    pub(crate) send_failures: SendFailureStats,
+    pub(crate) receive_queue: Option<QueueStats>,
+    pub(crate) sink_queues: Vec<QueueStats>,
+    pub(crate) identities: IdentityStats,
+    pub(crate) port_rewrites: PortRewriteStats,
+    pub(crate) spoofing: SpoofingStats,
+    pub(crate) peer_confidence: PeerConfidenceStats,
+    pub(crate) routing_table: Vec<RoutingEntry>,
+    pub(crate) stop_reason: Option<StopReason>,
+}
+
+impl Progress {
+    pub(crate) fn new(started: Instant, with_countries: bool) -> Progress {
+        Progress {
+            started,
+            nodes_seen: SeenEstimate::default(),
+            info_hashes_seen: SeenEstimate::default(),
+            frontier_depth: 0,
+            in_flight_queries: 0,
+            queries_sent: RateWindow::new(),
+            responses_received: RateWindow::new(),
+            nodes_discovered: RateWindow::new(),
+            countries: with_countries.then(HashMap::new),
+            client_versions: HashMap::new(),
+            inbound_queries: InboundQueryReport::default(),
+            records: EventCounts::default(),
+            timeouts: 0,
+            lookup_packets: 0,
+            capabilities: None,
+            error_replies: BTreeMap::new(),
+            received: ReceiveStats::default(),
+            dropped_datagrams: None,
+            icmp_errors: 0,
+            malformed_datagrams: 0,
+            unmatched_replies: UnmatchedReplies::default(),
+            traffic: TrafficAudit::default(),
+            send_failures: SendFailureStats::default(),
+            receive_queue: None,
+            sink_queues: Vec::new(),
+            identities: IdentityStats::default(),
+            port_rewrites: PortRewriteStats::default(),
+            spoofing: SpoofingStats::default(),
+            peer_confidence: PeerConfidenceStats::default(),
+            routing_table: Vec::new(),
+            stop_reason: None,
+        }
+    }
+
+    /// Add queries received per client version, see [`MAX_CLIENT_VERSIONS`].
+    pub(crate) fn count_client_versions<I>(&mut self, counts: I)
+    where
+        I: IntoIterator<Item = (String, u64)>,
+    {
+        for (version, count) in counts {
+            let full = self.client_versions.len() >= MAX_CLIENT_VERSIONS;
+            let version = if full && !self.client_versions.contains_key(&version) {
+                OTHER_CLIENT_VERSIONS.to_string()
+            } else {
+                version
+            };
+            *self.client_versions.entry(version).or_default() += count;
+        }
+    }
+
+    /// Second (since the start) that `now` falls in, used to index the rate windows.
+    pub(crate) fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs()
+    }
+
+    pub(crate) fn snapshot(&self, now: Instant) -> CrawlSnapshot {
+        let second = self.second(now);
+        CrawlSnapshot {
+            uptime: now.saturating_duration_since(self.started),
+            nodes_seen: self.nodes_seen.counted as usize,
+            nodes_seen_estimate: self.nodes_seen,
+            info_hashes_seen: self.info_hashes_seen,
+            frontier_depth: self.frontier_depth,
+            in_flight_queries: self.in_flight_queries,
+            rates: CrawlRates {
+                queries_sent: self.queries_sent.per_second(second),
+                responses_received: self.responses_received.per_second(second),
+                nodes_discovered: self.nodes_discovered.per_second(second),
+            },
+            countries: self
+                .countries
+                .as_ref()
+                .map(|countries| countries.iter().map(|(k, v)| (k.clone(), *v)).collect()),
+            client_versions: self
+                .client_versions
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            inbound_queries: self.inbound_queries.clone(),
+            records: self.records,
+            timeouts: self.timeouts,
+            lookup_packets: self.lookup_packets,
+            capabilities: self.capabilities,
+            error_replies: self.error_replies.clone(),
+            received: self.received,
+            dropped_datagrams: self.dropped_datagrams,
+            icmp_errors: self.icmp_errors,
+            malformed_datagrams: self.malformed_datagrams,
+            unmatched_replies: self.unmatched_replies,
+            traffic: self.traffic,
+            send_failures: self.send_failures,
+            receive_queue: self.receive_queue,
+            sink_queues: self.sink_queues.clone(),
+            identities: self.identities,
+            port_rewrites: self.port_rewrites,
+            spoofing: self.spoofing,
+            peer_confidence: self.peer_confidence,
+            routing_table_nodes: self.routing_table.len(),
+            paused: false,
+            stop_reason: self.stop_reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_window() {
+        let mut window = RateWindow::new();
+        window.record(0, 10);
+        window.record(1, 20);
+        assert_eq!(window.per_second(1), 15.0);
+
+        window.record(59, 30);
+        assert_eq!(window.per_second(59), 1.0);
+        // The first two seconds fall out of the window, and slot 0 gets reused.
+        window.record(60, 60);
+        assert_eq!(window.per_second(61), 1.5);
+        assert_eq!(window.per_second(200), 0.0);
+    }
+
+    #[test]
+    fn test_client_versions() {
+        assert_eq!(client_version_label(b"UT\xb5\x23"), "UTb523");
+        assert_eq!(client_version_label(b"bc\x00\x01"), "bc0001");
+        assert_eq!(client_version_label(b"\x01\x02\x03"), "010203");
+        assert_eq!(client_version_label(b"L"), "4c");
+
+        let mut progress = Progress::new(Instant::now(), false);
+        let versions = (0..MAX_CLIENT_VERSIONS as u32 + 10).map(|i| (format!("{}", i), 1));
+        progress.count_client_versions(versions);
+        progress.count_client_versions([("0".to_string(), 2), ("new".to_string(), 1)]);
+        let snapshot = progress.snapshot(Instant::now());
+        assert_eq!(snapshot.client_versions.len(), MAX_CLIENT_VERSIONS + 1);
+        assert_eq!(snapshot.client_versions["0"], 3);
+        assert_eq!(snapshot.client_versions[OTHER_CLIENT_VERSIONS], 11);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_serialization() {
+        let mut progress = Progress::new(Instant::now(), true);
+        progress.nodes_seen = SeenEstimate {
+            counted: 12,
+            estimate: 12.5,
+            upper: 14.0,
+        };
+        progress
+            .countries
+            .as_mut()
+            .unwrap()
+            .insert("FR".to_string(), 3);
+        let snapshot = progress.snapshot(Instant::now());
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["nodes_seen"], 12);
+        assert_eq!(json["nodes_seen_estimate"]["upper"], 14.0);
+        assert_eq!(json["countries"]["FR"], 3);
+        assert_eq!(
+            serde_json::from_value::<CrawlSnapshot>(json).unwrap(),
+            snapshot
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use bitcrawler_proto::kademlia::Id160;
+
+/// Number of bits of a node id.
+const ID_BITS: u32 = 160;
+
+/// Number of senders looked at to pick the one forgotten when the detector is full.
+const EVICTION_SAMPLE: usize = 64;
+
+/// Configuration of a [`SpoofingDetector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpoofingConfig {
+    /// Number of leading bits a node id returned must share with the target of the query to
+    /// be suspicious. With a few tens of millions of nodes, the closest node of the whole DHT
+    /// to a random target shares about 25 bits with it: a reply holding several ids much
+    /// closer was made up for the target.
+    pub min_shared_bits: u32,
+    /// Score at which a sender is flagged: each suspicious node id it returned adds 1.
+    pub flag_score: u64,
+    /// Drop the nodes returned by the flagged senders: they are neither discovered nor
+    /// followed by the lookups. Otherwise the flagged senders are only counted.
+    pub drop_flagged: bool,
+    /// Maximum number of senders scored. Beyond it, the lowest score of a sample of the senders
+    /// is forgotten.
+    pub capacity: usize,
+}
+
+impl Default for SpoofingConfig {
+    fn default() -> Self {
+        SpoofingConfig {
+            min_shared_bits: 40,
+            flag_score: 8,
+            drop_flagged: false,
+            capacity: 1 << 16,
+        }
+    }
+}
+
+/// How a reply compares to its target, see [`SpoofingDetector::observe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpoofingCheck {
+    /// No suspicious node id, from a sender not flagged.
+    Clean,
+    /// Node ids suspiciously close to the target, from a sender not flagged yet.
+    Suspicious { close_nodes: usize },
+    /// The sender returned too many suspicious node ids: its replies cannot be trusted.
+    Flagged,
+}
+
+/// Counters of a [`SpoofingDetector`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpoofingStats {
+    /// Senders scored.
+    pub senders: usize,
+    /// Replies checked.
+    pub replies: u64,
+    /// Replies holding suspicious node ids.
+    pub suspicious_replies: u64,
+    /// Suspicious node ids, over all the replies.
+    pub close_nodes: u64,
+    /// Replies from senders flagged at the time.
+    pub flagged_replies: u64,
+    /// Replies from flagged senders whose nodes were dropped, see
+    /// [`SpoofingConfig::drop_flagged`].
+    pub dropped_replies: u64,
+}
+
+/// Scores the senders of `find_node` (and `get_peers`) replies by the node ids they return
+/// close to the target of the query, to spot the neighbor spoofing of routing table
+/// poisoning: the sender makes up ids next to the target, so that the lookups for it end
+/// on nodes it controls.
+///
+/// An id equal to the target is not suspicious, it is the node looked up. The scores are
+/// kept in bounded memory, only for the senders that returned suspicious ids.
+#[derive(Debug, Clone)]
+pub struct SpoofingDetector {
+    config: SpoofingConfig,
+    scores: HashMap<SocketAddr, u64>,
+    stats: SpoofingStats,
+}
+
+impl SpoofingDetector {
+    /// Create a detector without scores.
+    pub fn new(config: SpoofingConfig) -> SpoofingDetector {
+        SpoofingDetector {
+            config,
+            scores: HashMap::new(),
+            stats: SpoofingStats::default(),
+        }
+    }
+
+    /// Get the configuration of the detector.
+    pub fn config(&self) -> &SpoofingConfig {
+        &self.config
+    }
+
+    /// Replace the thresholds of the detector, the scores are kept.
+    pub fn set_config(&mut self, config: SpoofingConfig) {
+        self.config = config;
+    }
+
+    /// Score `sender` by the `nodes` it returned for `target`, and check the reply.
+    pub fn observe(
+        &mut self,
+        sender: SocketAddr,
+        target: &Id160,
+        nodes: &[(Id160, SocketAddr)],
+    ) -> SpoofingCheck {
+        self.stats.replies += 1;
+        let close_nodes = nodes
+            .iter()
+            .filter(|(id, _)| {
+                id != target && ID_BITS - target.log2_distance(id) >= self.config.min_shared_bits
+            })
+            .count();
+        if close_nodes > 0 {
+            self.stats.suspicious_replies += 1;
+            self.stats.close_nodes += close_nodes as u64;
+            if !self.scores.contains_key(&sender)
+                && self.scores.len() >= self.config.capacity.max(1)
+            {
+                // The lowest score of a sample, not to scan every sender on each insert.
+                let lowest = self
+                    .scores
+                    .iter()
+                    .take(EVICTION_SAMPLE)
+                    .min_by_key(|(_, score)| **score);
+                if let Some((&lowest, _)) = lowest {
+                    self.scores.remove(&lowest);
+                }
+            }
+            *self.scores.entry(sender).or_default() += close_nodes as u64;
+        }
+        if self.is_flagged(&sender) {
+            self.stats.flagged_replies += 1;
+            if self.config.drop_flagged {
+                self.stats.dropped_replies += 1;
+            }
+            SpoofingCheck::Flagged
+        } else if close_nodes > 0 {
+            SpoofingCheck::Suspicious { close_nodes }
+        } else {
+            SpoofingCheck::Clean
+        }
+    }
+
+    /// Get the score of `sender`, `0` if it is not scored.
+    pub fn score(&self, sender: &SocketAddr) -> u64 {
+        self.scores.get(sender).copied().unwrap_or(0)
+    }
+
+    /// Check if `sender` returned too many suspicious node ids.
+    pub fn is_flagged(&self, sender: &SocketAddr) -> bool {
+        self.score(sender) >= self.config.flag_score
+    }
+
+    /// Get the counters of the detector.
+    pub fn stats(&self) -> SpoofingStats {
+        SpoofingStats {
+            senders: self.scores.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_ids_flag_the_sender() {
+        let mut detector = SpoofingDetector::new(SpoofingConfig {
+            min_shared_bits: 40,
+            flag_score: 3,
+            drop_flagged: true,
+            capacity: 2,
+        });
+        let target = Id160([0x55; 20]);
+        // An id sharing `bits` leading bits with the target.
+        let near = |bits: usize| {
+            let mut id = target;
+            id.0[bits / 8] ^= 0x80 >> (bits % 8);
+            (id, SocketAddr::from(([192, 0, 2, 100], 6881)))
+        };
+        let a: SocketAddr = ([192, 0, 2, 1], 6881).into();
+        let b: SocketAddr = ([192, 0, 2, 2], 6881).into();
+
+        // Far ids, and the target itself.
+        let honest = [near(8), near(24), (target, a)];
+        assert_eq!(detector.observe(a, &target, &honest), SpoofingCheck::Clean);
+        assert_eq!(
+            detector.observe(a, &target, &[near(40), near(100), near(30)]),
+            SpoofingCheck::Suspicious { close_nodes: 2 }
+        );
+        assert!(!detector.is_flagged(&a));
+        assert_eq!(
+            detector.observe(a, &target, &[near(159)]),
+            SpoofingCheck::Flagged
+        );
+        // A flagged sender stays flagged, even with honest replies.
+        assert_eq!(
+            detector.observe(a, &target, &honest),
+            SpoofingCheck::Flagged
+        );
+        assert_eq!(detector.score(&a), 3);
+
+        // Raising the threshold clears the flag, the score is kept.
+        detector.set_config(SpoofingConfig {
+            flag_score: 4,
+            ..detector.config().clone()
+        });
+        assert!(!detector.is_flagged(&a));
+
+        // The lowest score is forgotten first.
+        let c: SocketAddr = ([192, 0, 2, 3], 6881).into();
+        detector.observe(b, &target, &[near(50)]);
+        detector.observe(c, &target, &[near(50)]);
+        assert_eq!((detector.score(&a), detector.score(&b)), (3, 0));
+        assert_eq!(
+            detector.stats(),
+            SpoofingStats {
+                senders: 2,
+                replies: 6,
+                suspicious_replies: 4,
+                close_nodes: 5,
+                flagged_replies: 2,
+                dropped_replies: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use super::CrawlerConfig;
+
+/// Why a crawl stopped, see [`CrawlSnapshot::stop_reason`](super::CrawlSnapshot::stop_reason).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StopReason {
+    /// Asked to stop through a [`CrawlerHandle`](super::CrawlerHandle).
+    Stopped,
+    /// The [`CrawlerConfig::cancel`](super::CrawlerConfig::cancel) token was cancelled.
+    Cancelled,
+    /// The crawl ran for [`CrawlerConfig::max_duration`](super::CrawlerConfig::max_duration).
+    MaxDuration,
+    /// The crawl saw [`CrawlerConfig::max_nodes`](super::CrawlerConfig::max_nodes) nodes.
+    MaxNodes,
+    /// No contact was left to ping and no node was discovered for
+    /// [`CrawlerConfig::idle_timeout`](super::CrawlerConfig::idle_timeout).
+    Idle,
+    /// The query cap of the node is reached, or the recording it replays is over (see
+    /// [`DhtNode::is_exhausted`](crate::node::DhtNode::is_exhausted)).
+    Exhausted,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StopReason::Stopped => "stopped",
+            StopReason::Cancelled => "cancelled",
+            StopReason::MaxDuration => "maximum duration reached",
+            StopReason::MaxNodes => "maximum number of nodes reached",
+            StopReason::Idle => "no contact left",
+            StopReason::Exhausted => "query cap reached or replay over",
+        })
+    }
+}
+
+/// The state of a crawl its stop conditions are checked against, see [`StopState::reason`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct StopState {
+    /// The node cannot send queries anymore.
+    pub(super) exhausted: bool,
+    /// The end of the run (see [`CrawlerConfig::max_duration`]) is reached.
+    pub(super) ended: bool,
+    /// Number of queries in flight.
+    pub(super) in_flight: usize,
+    /// Number of distinct nodes seen.
+    pub(super) nodes_seen: usize,
+    /// Time since the last discovery once no contact is left to ping, `None` while some are.
+    pub(super) idle: Option<Duration>,
+}
+
+impl StopState {
+    /// Get the stop condition of `config` met, if any, the first one in the order of
+    /// [`StopReason`] (but [`StopReason::Stopped`], which is checked by the crawler loop).
+    pub(super) fn reason(&self, config: &CrawlerConfig) -> Option<StopReason> {
+        if config
+            .cancel
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+        {
+            Some(StopReason::Cancelled)
+        } else if self.exhausted {
+            Some(StopReason::Exhausted)
+        } else if self.ended && self.in_flight == 0 {
+            // The queries in flight expire by the end of the run, on the next poll.
+            Some(StopReason::MaxDuration)
+        } else if config.max_nodes.is_some_and(|max| self.nodes_seen >= max) {
+            Some(StopReason::MaxNodes)
+        } else if config
+            .idle_timeout
+            .is_some_and(|timeout| self.idle.is_some_and(|idle| idle >= timeout))
+        {
+            Some(StopReason::Idle)
+        } else {
+            None
+        }
+    }
+}
+
+/// A cloneable flag to cancel crawls from outside, e.g. to stop several crawlers (or other
+/// components polling it) at once. See [`CrawlerConfig::cancel`](super::CrawlerConfig::cancel).
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a token not cancelled yet.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancel the token, and so every crawl it was given to. It cannot be reset.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Check if the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcrawler_proto::kademlia::Id160;
+
+    use super::*;
+
+    fn config() -> CrawlerConfig {
+        let mut config = CrawlerConfig::new(Id160([0; 20]));
+        config.max_nodes = Some(10);
+        config.idle_timeout = Some(Duration::from_secs(30));
+        config
+    }
+
+    #[test]
+    fn test_no_stop_condition() {
+        let config = config();
+        assert_eq!(StopState::default().reason(&config), None);
+        // Below every limit.
+        let state = StopState {
+            in_flight: 3,
+            nodes_seen: 9,
+            idle: Some(Duration::from_secs(29)),
+            ..StopState::default()
+        };
+        assert_eq!(state.reason(&config), None);
+        // Without limits, nothing stops the crawl but the node.
+        let unlimited = CrawlerConfig::new(Id160([0; 20]));
+        let state = StopState {
+            nodes_seen: usize::MAX,
+            idle: Some(Duration::MAX),
+            ..StopState::default()
+        };
+        assert_eq!(state.reason(&unlimited), None);
+    }
+
+    #[test]
+    fn test_each_stop_condition() {
+        let mut cancelled = config();
+        let token = CancellationToken::new();
+        cancelled.cancel = Some(token.clone());
+        assert_eq!(StopState::default().reason(&cancelled), None);
+        token.cancel();
+        assert_eq!(
+            StopState::default().reason(&cancelled),
+            Some(StopReason::Cancelled)
+        );
+
+        let config = config();
+        let exhausted = StopState {
+            exhausted: true,
+            ..StopState::default()
+        };
+        assert_eq!(exhausted.reason(&config), Some(StopReason::Exhausted));
+
+        let ended = StopState {
+            ended: true,
+            ..StopState::default()
+        };
+        assert_eq!(ended.reason(&config), Some(StopReason::MaxDuration));
+        // The run is over once the queries in flight expire.
+        let waiting = StopState {
+            in_flight: 1,
+            ..ended
+        };
+        assert_eq!(waiting.reason(&config), None);
+
+        let max_nodes = StopState {
+            nodes_seen: 10,
+            ..StopState::default()
+        };
+        assert_eq!(max_nodes.reason(&config), Some(StopReason::MaxNodes));
+
+        let idle = StopState {
+            idle: Some(Duration::from_secs(30)),
+            ..StopState::default()
+        };
+        assert_eq!(idle.reason(&config), Some(StopReason::Idle));
+    }
+
+    #[test]
+    fn test_stop_conditions_order() {
+        let mut config = config();
+        let token = CancellationToken::new();
+        config.cancel = Some(token.clone());
+        let mut state = StopState {
+            exhausted: true,
+            ended: true,
+            in_flight: 0,
+            nodes_seen: 10,
+            idle: Some(Duration::from_secs(30)),
+        };
+        let mut reasons = Vec::new();
+        token.cancel();
+        reasons.push(state.reason(&config));
+        config.cancel = None;
+        reasons.push(state.reason(&config));
+        state.exhausted = false;
+        reasons.push(state.reason(&config));
+        // Waiting for the queries in flight lets the other conditions stop the crawl first.
+        state.in_flight = 1;
+        reasons.push(state.reason(&config));
+        state.nodes_seen = 0;
+        reasons.push(state.reason(&config));
+        state.idle = None;
+        reasons.push(state.reason(&config));
+        assert_eq!(
+            reasons,
+            vec![
+                Some(StopReason::Cancelled),
+                Some(StopReason::Exhausted),
+                Some(StopReason::MaxDuration),
+                Some(StopReason::MaxNodes),
+                Some(StopReason::Idle),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cancellation_token_is_shared() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+        // It cannot be reset, and new clones are cancelled too.
+        clone.cancel();
+        assert!(token.clone().is_cancelled());
+    }
+}
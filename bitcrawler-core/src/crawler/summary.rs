@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+
+use bitcrawler_proto::krpc::ErrorCode;
+
+use super::{CrawlSnapshot, StopReason};
+use crate::sink::EventCounts;
+
+/// Totals of a crawl, e.g. reported when it stops to the orchestrator of a fleet of crawlers.
+///
+/// Built from the last [`CrawlSnapshot`] of the crawl. With the `serde` feature, it serializes
+/// to a flat, stable shape (durations in seconds, error codes labeled) meant for machines.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CrawlSummary {
+    /// Duration of the crawl, in seconds.
+    pub runtime_seconds: f64,
+    /// Datagrams received.
+    pub datagrams_received: u64,
+    /// Bytes received.
+    pub bytes_received: u64,
+    /// Datagrams sent (queries and replies).
+    pub datagrams_sent: u64,
+    /// Bytes sent.
+    pub bytes_sent: u64,
+    /// Queries sent.
+    pub queries_sent: u64,
+    /// Distinct node ids seen (the estimate, see [`CrawlSnapshot::nodes_seen_estimate`]).
+    pub unique_nodes: u64,
+    /// Distinct info hashes seen (the estimate, see [`CrawlSnapshot::info_hashes_seen`]).
+    pub unique_info_hashes: u64,
+    /// Events handed to the sinks, per kind.
+    pub records: EventCounts,
+    /// Errors, per category.
+    pub errors: ErrorCounts,
+    /// Why the crawl stopped, `None` if it was still running.
+    pub stop_reason: Option<StopReason>,
+}
+
+/// Errors of a crawl per category, see [`CrawlSummary::errors`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorCounts {
+    /// Datagrams received that could not be parsed.
+    pub malformed_datagrams: u64,
+    /// Datagrams dropped by the kernel because the receive buffer was full (0 if unsupported).
+    pub dropped_datagrams: u64,
+    /// ICMP errors reported by the socket.
+    pub icmp_errors: u64,
+    /// Sends that failed, whatever the cause.
+    pub send_failures: u64,
+    /// Datagrams not sent because of the traffic limits (opt-out, per-node, bandwidth and
+    /// query caps).
+    pub refused_sends: u64,
+    /// Queries not answered in time.
+    pub timeouts: u64,
+    /// Error replies received, per error code (e.g. `"202 Server Error"`).
+    pub error_replies: BTreeMap<String, u64>,
+    /// Events dropped by the full queues of the sinks.
+    pub dropped_records: u64,
+}
+
+impl From<&CrawlSnapshot> for CrawlSummary {
+    fn from(snapshot: &CrawlSnapshot) -> Self {
+        let traffic = &snapshot.traffic;
+        CrawlSummary {
+            runtime_seconds: snapshot.uptime.as_secs_f64(),
+            datagrams_received: snapshot.received.datagrams,
+            bytes_received: snapshot.received.bytes,
+            datagrams_sent: traffic.datagrams_sent,
+            bytes_sent: traffic.bytes_sent,
+            queries_sent: traffic.queries_sent,
+            unique_nodes: snapshot.nodes_seen_estimate.estimate.round() as u64,
+            unique_info_hashes: snapshot.info_hashes_seen.estimate.round() as u64,
+            records: snapshot.records,
+            errors: ErrorCounts {
+                malformed_datagrams: snapshot.malformed_datagrams,
+                dropped_datagrams: snapshot.dropped_datagrams.unwrap_or_default(),
+                icmp_errors: snapshot.icmp_errors,
+                send_failures: snapshot.send_failures.destination_failures
+                    + snapshot.send_failures.transient_failures,
+                refused_sends: traffic.opted_out
+                    + traffic.node_cap_reached
+                    + traffic.bandwidth_exceeded
+                    + traffic.query_cap_reached,
+                timeouts: snapshot.timeouts,
+                error_replies: snapshot
+                    .error_replies
+                    .iter()
+                    .map(|(code, count)| (ErrorCode::from(*code).to_string(), *count))
+                    .collect(),
+                dropped_records: snapshot.sink_queues.iter().map(|queue| queue.dropped).sum(),
+            },
+            stop_reason: snapshot.stop_reason,
+        }
+    }
+}
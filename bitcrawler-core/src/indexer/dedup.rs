@@ -0,0 +1,331 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::bloom::BloomFilter;
+
+/// Configuration of an [`InfoHashFilter`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoHashFilterConfig {
+    /// Number of info hashes the filter is sized for.
+    pub expected_items: usize,
+    /// Target false-positive rate, i.e. the probability of skipping a never-fetched hash.
+    pub false_positive_rate: f64,
+    /// Number of journal entries after which the journal is folded into the snapshot.
+    pub compact_every: usize,
+    /// If set, every hash is reported as needing a fetch (the filter is still updated).
+    pub force_refetch: bool,
+}
+
+impl Default for InfoHashFilterConfig {
+    fn default() -> Self {
+        InfoHashFilterConfig {
+            expected_items: 10_000_000,
+            false_positive_rate: 0.001,
+            compact_every: 10_000,
+            force_refetch: false,
+        }
+    }
+}
+
+/// A persistent, probabilistic record of the info hashes whose metadata was already fetched.
+///
+/// The filter lets the indexer skip hashes processed in a previous run without keeping every
+/// hash in memory. A false positive means a hash is wrongly skipped, its probability is bounded
+/// by [`InfoHashFilterConfig::false_positive_rate`] as long as the filter is not overfilled.
+///
+/// On disk, the filter is stored as a snapshot (`<path>`) and an append-only journal
+/// (`<path>.journal`) of hashes marked since the last snapshot. The journal is folded back into
+/// the snapshot every [`InfoHashFilterConfig::compact_every`] entries, or on
+/// [`InfoHashFilter::compact`].
+pub struct InfoHashFilter {
+    filter: BloomFilter,
+    config: InfoHashFilterConfig,
+    path: Option<PathBuf>,
+    journal: Option<BufWriter<File>>,
+    journal_len: usize,
+}
+
+impl InfoHashFilter {
+    /// Create a filter that only lives in memory.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the false-positive rate is not between 0
+    /// and 1.
+    pub fn in_memory(config: InfoHashFilterConfig) -> io::Result<InfoHashFilter> {
+        Ok(InfoHashFilter {
+            filter: new_filter(&config)?,
+            config,
+            path: None,
+            journal: None,
+            journal_len: 0,
+        })
+    }
+
+    /// Open (or create) a filter persisted at the given path.
+    ///
+    /// An existing snapshot keeps its original dimensions, the sizing options of `config` only
+    /// apply when a new filter is created. Pending journal entries are replayed, and only
+    /// compacted once they reach [`InfoHashFilterConfig::compact_every`].
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        config: InfoHashFilterConfig,
+    ) -> io::Result<InfoHashFilter> {
+        let path = path.as_ref().to_path_buf();
+        let (filter, created) = match fs::read(&path) {
+            Ok(data) => {
+                let filter = BloomFilter::try_from_bytes(&data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                (filter, false)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (new_filter(&config)?, true),
+            Err(e) => return Err(e),
+        };
+        let mut this = InfoHashFilter {
+            filter,
+            config,
+            path: Some(path),
+            journal: None,
+            journal_len: 0,
+        };
+        this.journal_len = this.replay_journal()?;
+        if created || this.journal_len >= this.config.compact_every {
+            this.compact()?;
+        } else if let Some(path) = &this.path {
+            let journal = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(journal_path(path))?;
+            this.journal = Some(BufWriter::new(journal));
+        }
+        Ok(this)
+    }
+
+    /// Check if the metadata of the given info hash should be fetched.
+    ///
+    /// Returns false if the hash was (probably) already fetched, unless
+    /// [`InfoHashFilterConfig::force_refetch`] is set.
+    pub fn should_fetch(&self, info_hash: &[u8]) -> bool {
+        self.config.force_refetch || !self.filter.contains(info_hash)
+    }
+
+    /// Record that the metadata of the given info hash was fetched.
+    ///
+    /// Returns true if the hash was not already recorded, and an error if the hash is longer than
+    /// 255 bytes and cannot be journaled.
+    pub fn mark_fetched(&mut self, info_hash: &[u8]) -> io::Result<bool> {
+        let length = u8::try_from(info_hash.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Info hash longer than 255 bytes",
+            )
+        })?;
+        if !self.filter.insert(info_hash) {
+            return Ok(false);
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.write_all(&[length])?;
+            journal.write_all(info_hash)?;
+            journal.flush()?;
+            self.journal_len += 1;
+            if self.journal_len >= self.config.compact_every {
+                self.compact()?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Enable or disable the forced refetch of already seen hashes.
+    pub fn set_force_refetch(&mut self, force_refetch: bool) {
+        self.config.force_refetch = force_refetch;
+    }
+
+    /// Write a fresh snapshot of the filter and truncate the journal.
+    ///
+    /// This is a no-op for in-memory filters.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        // Write to a temporary file first so a crash never leaves a half-written snapshot.
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, self.filter.to_bytes())?;
+        fs::rename(&tmp_path, &path)?;
+        let journal = File::create(journal_path(&path))?;
+        self.journal = Some(BufWriter::new(journal));
+        self.journal_len = 0;
+        Ok(())
+    }
+
+    /// Get the number of distinct info hashes recorded.
+    pub fn len(&self) -> usize {
+        self.filter.len()
+    }
+
+    /// Check if no info hash was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.filter.is_empty()
+    }
+
+    /// Estimate the current probability of wrongly skipping a hash.
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        self.filter.estimated_false_positive_rate()
+    }
+
+    /// Replay the hashes recorded in the journal since the last snapshot.
+    ///
+    /// Returns the number of replayed entries. A truncated trailing record comes from an
+    /// interrupted write, it is cut off so new entries are appended after the last whole one.
+    fn replay_journal(&mut self) -> io::Result<usize> {
+        let path = match &self.path {
+            Some(path) => journal_path(path),
+            None => return Ok(0),
+        };
+        let mut data = Vec::new();
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        file.read_to_end(&mut data)?;
+        let mut i = 0;
+        let mut entries = 0;
+        while i < data.len() {
+            let length = data[i] as usize;
+            if i + 1 + length > data.len() {
+                file.set_len(i as u64)?;
+                break;
+            }
+            self.filter.insert(&data[i + 1..i + 1 + length]);
+            i += 1 + length;
+            entries += 1;
+        }
+        Ok(entries)
+    }
+}
+
+/// Create an empty filter sized by `config`.
+fn new_filter(config: &InfoHashFilterConfig) -> io::Result<BloomFilter> {
+    BloomFilter::with_rate(config.expected_items, config.false_positive_rate)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".journal");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "bitcrawler-dedup-{}-{}.bloom",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(journal_path(&path));
+        path
+    }
+
+    fn small_config() -> InfoHashFilterConfig {
+        InfoHashFilterConfig {
+            expected_items: 1000,
+            false_positive_rate: 0.001,
+            compact_every: 3,
+            force_refetch: false,
+        }
+    }
+
+    #[test]
+    fn test_in_memory_filter() {
+        let mut filter = InfoHashFilter::in_memory(small_config()).unwrap();
+        assert!(filter.should_fetch(&[1; 20]));
+        assert!(filter.mark_fetched(&[1; 20]).unwrap());
+        assert!(!filter.mark_fetched(&[1; 20]).unwrap());
+        assert!(!filter.should_fetch(&[1; 20]));
+        filter.set_force_refetch(true);
+        assert!(filter.should_fetch(&[1; 20]));
+    }
+
+    #[test]
+    fn test_invalid_rate() {
+        let config = InfoHashFilterConfig {
+            false_positive_rate: 1.0,
+            ..small_config()
+        };
+        let error = InfoHashFilter::in_memory(config.clone()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let path = temp_path("invalid-rate");
+        let error = InfoHashFilter::open(&path, config).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_persisted_across_runs() {
+        let path = temp_path("persist");
+        {
+            let mut filter = InfoHashFilter::open(&path, small_config()).unwrap();
+            // 5 entries: one compaction at 3, 2 left in the journal.
+            for i in 0..5u8 {
+                filter.mark_fetched(&[i; 20]).unwrap();
+            }
+        }
+        let filter = InfoHashFilter::open(&path, small_config()).unwrap();
+        for i in 0..5u8 {
+            assert!(!filter.should_fetch(&[i; 20]));
+        }
+        assert!(filter.should_fetch(&[42; 20]));
+        assert_eq!(filter.len(), 5);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(journal_path(&path));
+    }
+
+    #[test]
+    fn test_truncated_journal_record_is_ignored() {
+        let path = temp_path("truncated");
+        InfoHashFilter::open(&path, small_config()).unwrap();
+        fs::write(journal_path(&path), [20, 1, 2, 3]).unwrap();
+        let filter = InfoHashFilter::open(&path, small_config()).unwrap();
+        assert!(filter.is_empty());
+        assert_eq!(fs::read(journal_path(&path)).unwrap(), Vec::<u8>::new());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(journal_path(&path));
+    }
+
+    #[test]
+    fn test_open_keeps_short_journal() {
+        let path = temp_path("short");
+        {
+            let mut filter = InfoHashFilter::open(&path, small_config()).unwrap();
+            filter.mark_fetched(&[1; 20]).unwrap();
+        }
+        let snapshot = fs::read(&path).unwrap();
+        {
+            let mut filter = InfoHashFilter::open(&path, small_config()).unwrap();
+            // The snapshot is not rewritten for a journal below the compaction threshold.
+            assert_eq!(fs::read(&path).unwrap(), snapshot);
+            filter.mark_fetched(&[2; 20]).unwrap();
+        }
+        assert_eq!(fs::read(journal_path(&path)).unwrap().len(), 2 * 21);
+        let filter = InfoHashFilter::open(&path, small_config()).unwrap();
+        assert!(!filter.should_fetch(&[1; 20]));
+        assert!(!filter.should_fetch(&[2; 20]));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(journal_path(&path));
+    }
+
+    #[test]
+    fn test_oversized_hash_is_rejected() {
+        let mut filter = InfoHashFilter::in_memory(small_config()).unwrap();
+        let error = filter.mark_fetched(&[1; 256]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(filter.should_fetch(&[1; 256]));
+    }
+}
@@ -0,0 +1,187 @@
+//! Tracking of the info hashes surfaced by a crawl, and of those whose metadata was fetched.
+
+mod dedup;
+mod swarm;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+};
+
+use bitcrawler_proto::kademlia::Id160;
+
+use crate::{
+    crawler::CrawlerHandle,
+    sink::{CrawlEvent, DiscoveredPeer, Sink},
+};
+pub use dedup::*;
+pub use swarm::*;
+
+/// An info hash waiting for its metadata to be fetched, with the peers known to have it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingInfoHash {
+    pub info_hash: Id160,
+    /// Peers of the info hash, each as first returned.
+    pub peers: Vec<DiscoveredPeer>,
+}
+
+/// Queues the info hashes found by a crawl whose metadata still has to be fetched.
+///
+/// The indexer is a [`Sink`]: every info hash returned with peers is queued, unless the
+/// [`InfoHashFilter`] reports it as already fetched. Peers found for an info hash already queued
+/// are merged into its entry, a peer returned by several nodes is kept as first returned.
+///
+/// The indexer also estimates the size of the swarms it sees, see [`SwarmTracker`].
+pub struct Indexer {
+    filter: InfoHashFilter,
+    queue: VecDeque<Id160>,
+    peers: HashMap<Id160, Vec<DiscoveredPeer>>,
+    swarms: SwarmTracker,
+}
+
+impl Indexer {
+    /// Create an indexer skipping the info hashes recorded in `filter`.
+    pub fn new(filter: InfoHashFilter) -> Indexer {
+        Indexer {
+            filter,
+            queue: VecDeque::new(),
+            peers: HashMap::new(),
+            swarms: SwarmTracker::default(),
+        }
+    }
+
+    /// Get the next info hash to fetch, in discovery order.
+    pub fn next_pending(&mut self) -> Option<PendingInfoHash> {
+        let info_hash = self.queue.pop_front()?;
+        let peers = self.peers.remove(&info_hash).unwrap_or_default();
+        Some(PendingInfoHash { info_hash, peers })
+    }
+
+    /// Get the number of info hashes waiting to be fetched.
+    pub fn pending_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Record that the metadata of `info_hash` was fetched, so it is not queued again.
+    ///
+    /// Returns false if the info hash was (probably) already recorded.
+    pub fn mark_fetched(&mut self, info_hash: &Id160) -> io::Result<bool> {
+        self.filter.mark_fetched(info_hash.as_bytes())
+    }
+
+    /// Record that the metadata of `info_hash` was fetched from `peer`, see
+    /// [`Indexer::mark_fetched`], and report the handshake with the peer to `crawler` (see
+    /// [`CrawlerHandle::confirm_peer`]): the peer is trusted from then on, however few nodes
+    /// return it.
+    pub fn mark_fetched_from(
+        &mut self,
+        info_hash: &Id160,
+        peer: SocketAddr,
+        crawler: &CrawlerHandle,
+    ) -> io::Result<bool> {
+        crawler.confirm_peer(*info_hash, peer);
+        self.mark_fetched(info_hash)
+    }
+
+    /// Get the filter of the fetched info hashes.
+    pub fn filter(&self) -> &InfoHashFilter {
+        &self.filter
+    }
+
+    /// Get the estimated size of the swarm of `info_hash`, if it was seen.
+    pub fn swarm_estimate(&self, info_hash: &Id160) -> Option<SwarmEstimate> {
+        self.swarms.estimate(info_hash)
+    }
+
+    /// Get the swarm sizes estimated so far.
+    pub fn swarms(&self) -> &SwarmTracker {
+        &self.swarms
+    }
+}
+
+impl Sink for Indexer {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        self.swarms.handle(event)?;
+        if let CrawlEvent::PeersFound {
+            info_hash, peers, ..
+        } = event
+        {
+            if peers.is_empty() || !self.filter.should_fetch(info_hash.as_bytes()) {
+                return Ok(());
+            }
+            let known = self.peers.entry(*info_hash).or_insert_with(|| {
+                self.queue.push_back(*info_hash);
+                Vec::new()
+            });
+            for peer in peers {
+                if !known.iter().any(|known| known.addr == peer.addr) {
+                    known.push(*peer);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, SystemTime},
+    };
+
+    use super::*;
+    use crate::crawler::PeerConfidence;
+
+    #[test]
+    fn test_indexer_queues_unfetched_info_hashes() {
+        let mut indexer = Indexer::new(
+            InfoHashFilter::in_memory(InfoHashFilterConfig {
+                expected_items: 1000,
+                ..InfoHashFilterConfig::default()
+            })
+            .unwrap(),
+        );
+        let fetched = Id160([1; 20]);
+        let fresh = Id160([2; 20]);
+        indexer.mark_fetched(&fetched).unwrap();
+
+        let source: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let peer = |info_hash, port, seen_secs| DiscoveredPeer {
+            addr: SocketAddr::from(([192, 0, 2, 2], port)),
+            source_node: Id160([port as u8; 20]),
+            info_hash,
+            seen_at: start + Duration::from_secs(seen_secs),
+            token_available: port == 1,
+            confidence: PeerConfidence::default(),
+        };
+        for (info_hash, peers) in [
+            (fetched, vec![peer(fetched, 1, 0)]),
+            (fresh, vec![peer(fresh, 1, 0)]),
+            (fresh, vec![peer(fresh, 1, 5), peer(fresh, 2, 5)]),
+        ] {
+            indexer
+                .handle(&CrawlEvent::PeersFound {
+                    info_hash,
+                    source,
+                    peers,
+                })
+                .unwrap();
+        }
+
+        assert_eq!(indexer.pending_len(), 1);
+        assert_eq!(
+            indexer.next_pending(),
+            Some(PendingInfoHash {
+                info_hash: fresh,
+                peers: vec![peer(fresh, 1, 0), peer(fresh, 2, 5)],
+            })
+        );
+        assert_eq!(indexer.next_pending(), None);
+        // Every info hash seen counts for the swarm sizes, fetched or not.
+        assert_eq!(indexer.swarm_estimate(&fresh).unwrap().observed_peers, 1);
+        assert_eq!(indexer.swarms().len(), 2);
+    }
+}
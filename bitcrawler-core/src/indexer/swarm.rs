@@ -0,0 +1,387 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use bitcrawler_proto::{consts::SCRAPE_FILTER_LEN, kademlia::Id160};
+
+use crate::sink::{CrawlEvent, Sink};
+
+/// Size in bytes of the Bloom filters of BEP 33.
+pub const SCRAPE_FILTER_SIZE: usize = SCRAPE_FILTER_LEN;
+/// Number of bits of a BEP 33 filter.
+const FILTER_BITS: f64 = (SCRAPE_FILTER_SIZE * 8) as f64;
+
+/// A Bloom filter of the IP addresses of a swarm, sent by a node in reply to a scrape (BEP 33).
+///
+/// The filters of several nodes are merged with [`ScrapeFilter::union`]: the estimate of the
+/// union counts the peers known to any of them once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeFilter(Box<[u8; SCRAPE_FILTER_SIZE]>);
+
+impl ScrapeFilter {
+    /// Read a filter, `None` if it is not [`SCRAPE_FILTER_SIZE`] bytes long.
+    pub fn from_bytes(bytes: &[u8]) -> Option<ScrapeFilter> {
+        Some(ScrapeFilter(Box::new(bytes.try_into().ok()?)))
+    }
+
+    /// Get the bytes of the filter.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    /// Add the addresses of `other` to the filter.
+    pub fn union(&mut self, other: &ScrapeFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *byte |= other;
+        }
+    }
+
+    /// Estimate the number of addresses in the filter.
+    pub fn estimate(&self) -> f64 {
+        size_for_zero_bits(self.zero_bits())
+    }
+
+    /// Get the range the number of addresses in the filter lies in with a good confidence (two
+    /// standard deviations of the number of bits left unset).
+    pub fn bounds(&self) -> (f64, f64) {
+        let zeros = self.zero_bits();
+        let p = zeros / FILTER_BITS;
+        let deviation = 2.0 * (FILTER_BITS * p * (1.0 - p)).sqrt();
+        (
+            size_for_zero_bits((zeros + deviation).min(FILTER_BITS)),
+            size_for_zero_bits(zeros - deviation),
+        )
+    }
+
+    fn zero_bits(&self) -> f64 {
+        self.0.iter().map(|byte| byte.count_zeros()).sum::<u32>() as f64
+    }
+}
+
+/// Number of addresses that leave `zeros` bits unset in a filter, with the 2 hash functions
+/// of BEP 33. A full filter is taken for a filter with one bit left.
+fn size_for_zero_bits(zeros: f64) -> f64 {
+    let zeros = zeros.max(1.0);
+    (zeros / FILTER_BITS).ln() / (2.0 * (1.0 - 1.0 / FILTER_BITS).ln())
+}
+
+/// How much a [`SwarmEstimate`] can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Confidence {
+    /// Only the peers seen are counted: the swarm is probably larger.
+    Low,
+    /// The filters of one or two nodes were merged.
+    Medium,
+    /// The filters of several nodes were merged.
+    High,
+}
+
+/// Estimated size of the swarm of an info hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwarmEstimate {
+    pub info_hash: Id160,
+    /// Distinct peer IP addresses seen, in `get_peers` replies or announces.
+    pub observed_peers: usize,
+    /// Distinct peer IP addresses announced to us (a subset of the observed ones).
+    pub announced_peers: usize,
+    /// Number of nodes whose scrape filters were merged.
+    pub scrapes: usize,
+    /// Estimated number of seeds, from the merged scrape filters.
+    pub scraped_seeds: Option<f64>,
+    /// Estimated number of downloading peers, from the merged scrape filters.
+    pub scraped_peers: Option<f64>,
+    /// Best estimate of the number of peers (seeds included).
+    pub estimate: f64,
+    /// Lower bound of the number of peers.
+    pub low: f64,
+    /// Upper bound of the number of peers, unknown without scrape.
+    pub high: Option<f64>,
+    pub confidence: Confidence,
+}
+
+/// Configuration of a [`SwarmTracker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwarmConfig {
+    /// Maximum number of info hashes tracked, the following ones are ignored.
+    pub max_info_hashes: usize,
+    /// Maximum number of peer addresses kept per info hash. Past it, the observed peers are no
+    /// longer counted, the scrapes still are.
+    pub max_peers_per_info_hash: usize,
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        SwarmConfig {
+            max_info_hashes: 1 << 16,
+            max_peers_per_info_hash: 1 << 13,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Swarm {
+    observed: HashSet<IpAddr>,
+    announced: HashSet<IpAddr>,
+    scraped_by: HashSet<SocketAddr>,
+    seeds: Option<ScrapeFilter>,
+    peers: Option<ScrapeFilter>,
+}
+
+/// Estimates the size of the swarms of the info hashes seen by a crawl (or a honeypot).
+///
+/// The tracker is a [`Sink`]: it counts the distinct peers returned by `get_peers` replies and
+/// the peers announced to us, which are a lower bound of the swarm, and merges the Bloom filters
+/// of the scrapes (BEP 33, see [`CrawlerConfig::scrape`](crate::crawler::CrawlerConfig::scrape)),
+/// which estimate the peers known to the nodes responsible for the info hash.
+#[derive(Debug, Clone)]
+pub struct SwarmTracker {
+    config: SwarmConfig,
+    swarms: HashMap<Id160, Swarm>,
+}
+
+impl SwarmTracker {
+    /// Create an empty tracker.
+    pub fn new(config: SwarmConfig) -> SwarmTracker {
+        SwarmTracker {
+            config,
+            swarms: HashMap::new(),
+        }
+    }
+
+    fn swarm(&mut self, info_hash: Id160) -> Option<&mut Swarm> {
+        if !self.swarms.contains_key(&info_hash) && self.swarms.len() >= self.config.max_info_hashes
+        {
+            return None;
+        }
+        Some(self.swarms.entry(info_hash).or_default())
+    }
+
+    /// Record peers of `info_hash`, `announced` if they announced themselves to us.
+    pub fn record_peers<I>(&mut self, info_hash: Id160, peers: I, announced: bool)
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let capacity = self.config.max_peers_per_info_hash;
+        let Some(swarm) = self.swarm(info_hash) else {
+            return;
+        };
+        for peer in peers {
+            if swarm.observed.len() < capacity || swarm.observed.contains(&peer) {
+                swarm.observed.insert(peer);
+                if announced {
+                    swarm.announced.insert(peer);
+                }
+            }
+        }
+    }
+
+    /// Merge the scrape filters sent by `source` for `info_hash`.
+    pub fn record_scrape(
+        &mut self,
+        info_hash: Id160,
+        source: SocketAddr,
+        seeds: &ScrapeFilter,
+        peers: &ScrapeFilter,
+    ) {
+        let Some(swarm) = self.swarm(info_hash) else {
+            return;
+        };
+        swarm.scraped_by.insert(source);
+        for (merged, filter) in [(&mut swarm.seeds, seeds), (&mut swarm.peers, peers)] {
+            match merged {
+                Some(merged) => merged.union(filter),
+                None => *merged = Some(filter.clone()),
+            }
+        }
+    }
+
+    /// Get the estimated size of the swarm of `info_hash`, if it was seen.
+    pub fn estimate(&self, info_hash: &Id160) -> Option<SwarmEstimate> {
+        let swarm = self.swarms.get(info_hash)?;
+        let observed = swarm.observed.len() as f64;
+        let scrapes = swarm.scraped_by.len();
+        let scraped = match (&swarm.seeds, &swarm.peers) {
+            (Some(seeds), Some(peers)) => Some((seeds, peers)),
+            _ => None,
+        };
+        let (estimate, low, high) = match scraped {
+            Some((seeds, peers)) => {
+                let (seeds_low, seeds_high) = seeds.bounds();
+                let (peers_low, peers_high) = peers.bounds();
+                (
+                    (seeds.estimate() + peers.estimate()).max(observed),
+                    (seeds_low + peers_low).max(observed),
+                    Some((seeds_high + peers_high).max(observed)),
+                )
+            }
+            None => (observed, observed, None),
+        };
+        Some(SwarmEstimate {
+            info_hash: *info_hash,
+            observed_peers: swarm.observed.len(),
+            announced_peers: swarm.announced.len(),
+            scrapes,
+            scraped_seeds: scraped.map(|(seeds, _)| seeds.estimate()),
+            scraped_peers: scraped.map(|(_, peers)| peers.estimate()),
+            estimate,
+            low,
+            high,
+            confidence: match scrapes {
+                0 => Confidence::Low,
+                1..=2 => Confidence::Medium,
+                _ => Confidence::High,
+            },
+        })
+    }
+
+    /// Get the estimated sizes of all the swarms seen, in no particular order.
+    pub fn estimates(&self) -> impl Iterator<Item = SwarmEstimate> + '_ {
+        self.swarms
+            .keys()
+            .filter_map(|info_hash| self.estimate(info_hash))
+    }
+
+    /// Get the number of info hashes tracked.
+    pub fn len(&self) -> usize {
+        self.swarms.len()
+    }
+
+    /// Check if no info hash is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.swarms.is_empty()
+    }
+}
+
+impl Default for SwarmTracker {
+    fn default() -> Self {
+        SwarmTracker::new(SwarmConfig::default())
+    }
+}
+
+impl Sink for SwarmTracker {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        match event {
+            CrawlEvent::PeersFound {
+                info_hash, peers, ..
+            } => self.record_peers(*info_hash, peers.iter().map(|peer| peer.addr.ip()), false),
+            CrawlEvent::PeerAnnounced {
+                info_hash, peer, ..
+            } => self.record_peers(*info_hash, [peer.ip()], true),
+            CrawlEvent::ScrapeReceived {
+                info_hash,
+                source,
+                seeds,
+                peers,
+            } => self.record_scrape(*info_hash, *source, seeds, peers),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::{crawler::PeerConfidence, sink::DiscoveredPeer};
+
+    /// A filter with the first `bits` bits set.
+    fn filter_with(bits: usize) -> ScrapeFilter {
+        let mut bytes = [0u8; SCRAPE_FILTER_SIZE];
+        for bit in 0..bits {
+            bytes[bit / 8] |= 1 << (bit % 8);
+        }
+        ScrapeFilter::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_scrape_filter_estimate() {
+        assert!(ScrapeFilter::from_bytes(&[0; 255]).is_none());
+        assert_eq!(filter_with(0).estimate(), 0.0);
+        // 100 addresses set about 190 bits with 2 hash functions.
+        let filter = filter_with(190);
+        assert!(
+            (filter.estimate() - 100.0).abs() < 2.0,
+            "{}",
+            filter.estimate()
+        );
+        let (low, high) = filter.bounds();
+        assert!(low < 100.0 && high > 100.0);
+        // A full filter is bounded.
+        assert!(filter_with(2048).estimate() < 8000.0);
+
+        let mut union = filter_with(100);
+        union.union(&filter_with(190));
+        assert_eq!(union, filter_with(190));
+    }
+
+    #[test]
+    fn test_swarm_estimates() {
+        let mut tracker = SwarmTracker::new(SwarmConfig {
+            max_info_hashes: 1,
+            max_peers_per_info_hash: 3,
+        });
+        let info_hash = Id160([1; 20]);
+        let source: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let peer = |i: u8| SocketAddr::from(([198, 51, 100, i], 51413));
+        tracker
+            .handle(&CrawlEvent::PeersFound {
+                info_hash,
+                source,
+                // Two ports of the same host are one peer.
+                peers: [peer(1), peer(2), SocketAddr::from(([198, 51, 100, 2], 1))]
+                    .map(|addr| DiscoveredPeer {
+                        addr,
+                        source_node: Id160([9; 20]),
+                        info_hash,
+                        seen_at: SystemTime::now(),
+                        token_available: true,
+                        confidence: PeerConfidence::default(),
+                    })
+                    .to_vec(),
+            })
+            .unwrap();
+        tracker
+            .handle(&CrawlEvent::PeerAnnounced {
+                info_hash,
+                source,
+                peer: peer(3),
+            })
+            .unwrap();
+        // The tracker is full, both for the peers and the info hashes.
+        tracker.record_peers(info_hash, [peer(4).ip()], false);
+        tracker.record_peers(Id160([2; 20]), [peer(1).ip()], false);
+        assert_eq!(tracker.len(), 1);
+
+        let estimate = tracker.estimate(&info_hash).unwrap();
+        assert_eq!((estimate.observed_peers, estimate.announced_peers), (3, 1));
+        assert_eq!(
+            (estimate.estimate, estimate.low, estimate.high),
+            (3.0, 3.0, None)
+        );
+        assert_eq!(estimate.confidence, Confidence::Low);
+
+        for (i, bits) in [(1, 100), (2, 190), (3, 190)] {
+            tracker
+                .handle(&CrawlEvent::ScrapeReceived {
+                    info_hash,
+                    source: SocketAddr::from(([192, 0, 2, i], 6881)),
+                    seeds: filter_with(bits),
+                    peers: filter_with(0),
+                })
+                .unwrap();
+        }
+        let estimate = tracker.estimate(&info_hash).unwrap();
+        assert_eq!(estimate.scrapes, 3);
+        assert_eq!(estimate.confidence, Confidence::High);
+        assert_eq!(estimate.scraped_peers, Some(0.0));
+        assert!((estimate.estimate - 100.0).abs() < 2.0);
+        assert!(estimate.low <= estimate.estimate);
+        assert!(estimate.high.unwrap() >= estimate.estimate);
+        assert_eq!(tracker.estimates().count(), 1);
+    }
+}
@@ -0,0 +1,231 @@
+//! Random lookup targets within a part of the keyspace, e.g. to refresh a bucket of a routing
+//! table or to sweep the whole DHT.
+
+use bitcrawler_proto::kademlia::Id160;
+
+use crate::rng::{Rng, SplitMix64};
+
+/// The ids sharing their first `bits` bits with a prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Keyspace {
+    prefix: Id160,
+    bits: u32,
+}
+
+impl Keyspace {
+    /// The whole keyspace.
+    pub const ALL: Keyspace = Keyspace {
+        prefix: Id160([0; 20]),
+        bits: 0,
+    };
+
+    /// Create the range of the ids starting with the first `bits` bits of `prefix` (at most 160).
+    pub fn new(prefix: Id160, bits: u32) -> Keyspace {
+        let bits = bits.min(Id160::LEN as u32 * 8);
+        Keyspace {
+            prefix: Id160([0; 20]).with_prefix(&prefix, bits),
+            bits,
+        }
+    }
+
+    /// Create the range of the bucket `index` of a routing table around `local_id`: the ids
+    /// sharing exactly `index` leading bits with it (see
+    /// [`Xorable::bucket_index`](bitcrawler_proto::kademlia::Xorable::bucket_index)).
+    ///
+    /// The bucket 160 only holds `local_id` itself.
+    pub fn bucket(local_id: &Id160, index: u32) -> Keyspace {
+        let mut prefix = *local_id;
+        if let Some(byte) = prefix.0.get_mut(index as usize / 8) {
+            *byte ^= 0x80 >> (index % 8);
+        }
+        Keyspace::new(prefix, index + 1)
+    }
+
+    /// Get the first id of the range.
+    pub fn prefix(&self) -> &Id160 {
+        &self.prefix
+    }
+
+    /// Get the number of fixed leading bits.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Check if `id` is within the range.
+    pub fn contains(&self, id: &Id160) -> bool {
+        id.with_prefix(&self.prefix, self.bits) == *id
+    }
+}
+
+/// Generates random ids, e.g. lookup targets.
+///
+/// The ids are drawn from an [`Rng`], a [`SplitMix64`] by default: the targets only have to be
+/// spread over the keyspace, not unpredictable.
+#[derive(Debug, Clone)]
+pub struct TargetGenerator<R = SplitMix64> {
+    rng: R,
+}
+
+impl TargetGenerator {
+    /// Create a generator with a random seed.
+    pub fn new() -> TargetGenerator {
+        TargetGenerator::with_rng(SplitMix64::new())
+    }
+
+    /// Create a generator producing the same ids for the same seed.
+    pub fn with_seed(seed: u64) -> TargetGenerator {
+        TargetGenerator::with_rng(SplitMix64::with_seed(seed))
+    }
+}
+
+impl<R: Rng> TargetGenerator<R> {
+    /// Create a generator drawing from `rng`, e.g. the generator of a node (see
+    /// [`DhtNode::rng`](crate::node::DhtNode::rng)).
+    pub fn with_rng(rng: R) -> TargetGenerator<R> {
+        TargetGenerator { rng }
+    }
+
+    /// Get a random id, uniformly distributed over the whole keyspace.
+    pub fn random_id(&mut self) -> Id160 {
+        self.rng.random_id()
+    }
+
+    /// Get a random id uniformly distributed within `keyspace`.
+    ///
+    /// All the bits after the prefix are random, not only the first one.
+    pub fn target_in(&mut self, keyspace: &Keyspace) -> Id160 {
+        self.random_id()
+            .with_prefix(&keyspace.prefix, keyspace.bits)
+    }
+
+    /// Get `count` random ids within `keyspace`, spread evenly over it.
+    ///
+    /// The keyspace is cut into `count` consecutive slices of (nearly) the same size, and one id
+    /// is picked uniformly within each slice: unlike independent draws, no part of the keyspace
+    /// is left out by chance.
+    pub fn spread_in(&mut self, keyspace: &Keyspace, count: usize) -> Vec<Id160> {
+        let free_bits = Id160::LEN as u32 * 8 - keyspace.bits;
+        // Number of bits after the prefix used to select a slice.
+        let depth = (count.max(1) as u64)
+            .next_power_of_two()
+            .trailing_zeros()
+            .min(free_bits)
+            .min(32);
+        let slots = 1u64 << depth;
+        (0..count as u64)
+            .map(|i| {
+                let start = i * slots / count as u64;
+                let end = ((i + 1) * slots / count as u64).max(start + 1);
+                let slot = start + self.rng.next_u64() % (end - start);
+                let mut id = self.target_in(keyspace);
+                for bit in 0..depth {
+                    let position = (keyspace.bits + bit) as usize;
+                    let mask = 0x80 >> (position % 8);
+                    if slot >> (depth - 1 - bit) & 1 == 1 {
+                        id.0[position / 8] |= mask;
+                    } else {
+                        id.0[position / 8] &= !mask;
+                    }
+                }
+                id
+            })
+            .collect()
+    }
+}
+
+impl Default for TargetGenerator {
+    fn default() -> Self {
+        TargetGenerator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcrawler_proto::kademlia::Xorable;
+
+    use super::*;
+
+    /// Get the 4 bits following the first `bits` bits of `id`.
+    fn nibble_after(id: &Id160, bits: u32) -> usize {
+        let value = u32::from_be_bytes(id.0[bits as usize / 8..][..4].try_into().unwrap());
+        (value << (bits % 8) >> 28) as usize
+    }
+
+    /// Pearson's chi-squared statistic of `counts` against a uniform distribution.
+    fn chi_squared(counts: &[usize]) -> f64 {
+        let total: usize = counts.iter().sum();
+        let expected = total as f64 / counts.len() as f64;
+        counts
+            .iter()
+            .map(|&count| (count as f64 - expected).powi(2) / expected)
+            .sum()
+    }
+
+    #[test]
+    fn test_bucket_keyspace() {
+        let local = Id160([0x5a; 20]);
+        for index in [0, 7, 8, 13, 159] {
+            let bucket = Keyspace::bucket(&local, index);
+            let mut generator = TargetGenerator::with_seed(index as u64);
+            for _ in 0..100 {
+                let target = generator.target_in(&bucket);
+                assert!(bucket.contains(&target));
+                assert_eq!(local.bucket_index(&target), index as usize);
+            }
+        }
+        let last = Keyspace::bucket(&local, 160);
+        assert_eq!(last.bits(), 160);
+        assert_eq!(TargetGenerator::new().target_in(&last), local);
+        assert!(Keyspace::ALL.contains(&local));
+    }
+
+    #[test]
+    fn test_targets_are_uniform() {
+        const SAMPLES: usize = 16_000;
+        // Critical value of the chi-squared distribution with 15 degrees of freedom, p = 0.001.
+        const CRITICAL: f64 = 37.7;
+
+        let keyspace = Keyspace::new(Id160([0xc3; 20]), 13);
+        let mut generator = TargetGenerator::with_seed(42);
+        let mut first_nibble = [0; 16];
+        let mut last_nibble = [0; 16];
+        let mut bit_counts = [0; 160];
+        for _ in 0..SAMPLES {
+            let target = generator.target_in(&keyspace);
+            first_nibble[nibble_after(&target, 13)] += 1;
+            last_nibble[(target.0[19] & 0x0f) as usize] += 1;
+            for (bit, count) in bit_counts.iter_mut().enumerate() {
+                *count += (target.0[bit / 8] >> (7 - bit % 8) & 1) as usize;
+            }
+        }
+        assert!(chi_squared(&first_nibble) < CRITICAL, "{:?}", first_nibble);
+        assert!(chi_squared(&last_nibble) < CRITICAL, "{:?}", last_nibble);
+        // Every free bit is set about half of the time (within 5 standard deviations).
+        for &count in &bit_counts[13..] {
+            assert!(count.abs_diff(SAMPLES / 2) < 5 * 63, "{:?}", bit_counts);
+        }
+    }
+
+    #[test]
+    fn test_spread_covers_every_slice() {
+        let keyspace = Keyspace::bucket(&Id160([0; 20]), 3);
+        let mut generator = TargetGenerator::with_seed(7);
+        for _ in 0..100 {
+            let targets = generator.spread_in(&keyspace, 16);
+            assert!(targets.iter().all(|target| keyspace.contains(target)));
+            let mut slices: Vec<usize> = targets
+                .iter()
+                .map(|target| nibble_after(target, keyspace.bits()))
+                .collect();
+            slices.sort();
+            assert_eq!(slices, (0..16).collect::<Vec<_>>());
+        }
+
+        // Fewer targets than slices, and more targets than ids.
+        let targets = generator.spread_in(&keyspace, 5);
+        assert_eq!(targets.len(), 5);
+        assert!(targets.windows(2).all(|pair| pair[0] < pair[1]));
+        let tiny = Keyspace::new(Id160([0; 20]), 159);
+        assert_eq!(generator.spread_in(&tiny, 4).len(), 4);
+    }
+}
@@ -0,0 +1,91 @@
+//! Crawling the BitTorrent DHT as a library.
+//!
+//! - [`crawler::Crawler`] runs a crawl, observable through a [`crawler::CrawlerHandle`].
+//! - [`node::DhtNode`] sends queries and matches the replies, to build other tools on the DHT.
+//!   [`node::ReadOnlyNode`] only sends queries, for crawls that must stay passive.
+//! - [`sink::Sink`]s receive what a crawl discovers, e.g. [`sink::NodeArchiveSink`] archives the
+//!   nodes to a file and [`indexer::Indexer`] queues the info hashes to fetch. The events can
+//!   also be consumed as a [`sink::EventStream`], from a thread or an async task.
+//! - [`metainfo::Metainfo`] parses the metadata fetched for an info hash.
+//! - [`responder::Honeypot`] attracts the announces of chosen info hashes, for measurements.
+//! - `admin::AdminServer` (`admin` feature) controls a running crawl over HTTP.
+//! - `stats::StatsDatabase` (`sqlite` feature) keeps per-minute statistics of the crawls.
+//! - [`watchdog::Watchdog`] reports (or restarts) the components of a crawl that stall.
+//! - `testutil::MessageGenerator` (`testutil` feature) generates random valid KRPC messages, to
+//!   fuzz the layers above the parser and for property tests.
+//!
+//! The protocol layer (bencode, KRPC messages, routing table) is re-exported as [`proto`].
+//!
+//! The default `crawler` feature builds everything. Without it, the `node` feature builds the
+//! node alone (`node`, `transport`, `limits`, `bogon`, `metainfo` and `sim` modules), and neither
+//! feature only the I/O-free helpers (e.g. `bloom`, `keyspace`, `pipeline`).
+//!
+//! The crawl runs on plain threads, not on an async runtime: the crawler loop, the receive
+//! thread of the node, a thread per [`sink::QueuedSink`], the watchdog, and the honeypot and
+//! admin server when used, each named `bitcrawler-<loop>` (e.g. `bitcrawler-receive`) for
+//! debuggers and profilers. With the `tracing` feature, each loop runs in a `tracing` span of
+//! the same name (`crawler`, `receive`, `sink`...), with `tick`, `flush` and `lookup` spans
+//! inside, for the subscriber the application installs to show where the time goes. The
+//! library reports the problems it gets past (e.g. the malformed datagrams received) as
+//! `tracing` events rather than on the standard error. Without the feature, neither the spans
+//! nor the events are compiled in.
+//!
+//! ```no_run
+//! # #[cfg(feature = "crawler")]
+//! # fn main() -> std::io::Result<()> {
+//! use std::{thread, time::Duration};
+//!
+//! use bitcrawler_core::{
+//!     crawler::{Crawler, CrawlerConfig},
+//!     proto::kademlia::Id160,
+//!     sink::NodeListSink,
+//! };
+//!
+//! let mut crawler = Crawler::bind(CrawlerConfig::new(Id160([1; 20])))?;
+//! crawler.add_sink(NodeListSink::create("nodes.txt")?);
+//! let (handle, thread) = crawler.spawn();
+//! thread::sleep(Duration::from_secs(60));
+//! println!("{} nodes seen", handle.snapshot().nodes_seen);
+//! handle.stop();
+//! thread.join().unwrap()?;
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "crawler"))]
+//! # fn main() {}
+//! ```
+
+pub use bitcrawler_proto as proto;
+
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod bloom;
+#[cfg(feature = "node")]
+pub mod bogon;
+#[cfg(feature = "crawler")]
+pub mod crawler;
+#[cfg(feature = "crawler")]
+pub mod indexer;
+pub mod keyspace;
+#[cfg(feature = "node")]
+pub mod limits;
+#[cfg(feature = "node")]
+pub mod metainfo;
+#[cfg(feature = "node")]
+pub mod node;
+pub mod pipeline;
+pub mod ratelimit;
+#[cfg(feature = "crawler")]
+pub mod responder;
+pub mod rng;
+pub mod secret;
+#[cfg(feature = "node")]
+pub mod sim;
+#[cfg(feature = "crawler")]
+pub mod sink;
+#[cfg(feature = "sqlite")]
+pub mod stats;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+#[cfg(feature = "node")]
+pub mod transport;
+pub mod watchdog;
@@ -0,0 +1,851 @@
+//! Politeness controls: caps on the traffic of a run, and the opt-out list of the operator.
+//!
+//! The limits are enforced by [`DhtNode`](crate::node::DhtNode) on everything it sends, so they
+//! hold for every tool built on it. A refused datagram is reported as an error wrapping a
+//! [`Refusal`], and counted in a [`TrafficAudit`].
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::ratelimit::TokenBucket;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Largest datagram sent (an Ethernet MTU), the least burst of a bandwidth budget: a smaller
+/// one would refuse such a datagram forever.
+const MAX_DATAGRAM_SIZE: u32 = 1500;
+
+/// Caps on the traffic of a node, all unlimited by default.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TrafficLimits {
+    /// Maximum number of queries sent during the run. Once reached, no query is sent anymore
+    /// and the crawl stops.
+    pub max_queries: Option<u64>,
+    /// Maximum number of queries sent to a host (IP address) per day. The days are counted
+    /// from the start of the run, not from midnight: the counts are reset every 24 hours.
+    pub max_queries_per_node_per_day: Option<u32>,
+    /// Maximum number of bytes sent per second (queries and replies).
+    pub max_bytes_per_second: Option<u32>,
+    /// Split of `max_bytes_per_second` into a budget per address family, so that the scarcer
+    /// IPv6 nodes are not crowded out by the IPv4 ones. One budget is shared by both families
+    /// if `None`, and the split has no effect without a bandwidth cap.
+    pub family_shares: Option<FamilyShares>,
+    /// Maximum number of queries waiting for an answer at once. Beyond it, queries fail fast
+    /// until some are answered or time out.
+    pub max_in_flight: Option<usize>,
+    /// Maximum number of queries waiting for an answer from the same destination (IP address
+    /// and port) at once, e.g. `2` to keep an aggressive lookup from flooding a node.
+    pub max_in_flight_per_destination: Option<usize>,
+    /// Hosts that must never be contacted.
+    pub opt_out: OptOutList,
+}
+
+/// Address family of a destination, IPv4-mapped IPv6 addresses being IPv4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Get the family of `ip`.
+    pub fn of(ip: IpAddr) -> AddressFamily {
+        match ip.to_canonical() {
+            IpAddr::V4(_) => AddressFamily::Ipv4,
+            IpAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+}
+
+/// Weights of the address families in a bandwidth cap, see
+/// [`TrafficLimits::family_shares`]: `3:1` gives IPv4 three quarters of the bytes per second
+/// and IPv6 the last quarter, whatever the other family leaves unused. Neither weight is zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FamilyShares {
+    pub ipv4: u32,
+    pub ipv6: u32,
+}
+
+impl FamilyShares {
+    /// Get the share of `bytes_per_second` of `family`, `0` if both weights are zero.
+    pub fn rate(&self, family: AddressFamily, bytes_per_second: u32) -> u32 {
+        let total = u64::from(self.ipv4) + u64::from(self.ipv6);
+        let weight = match family {
+            AddressFamily::Ipv4 => self.ipv4,
+            AddressFamily::Ipv6 => self.ipv6,
+        };
+        (u64::from(bytes_per_second) * u64::from(weight))
+            .checked_div(total)
+            .unwrap_or(0) as u32
+    }
+}
+
+impl FromStr for FamilyShares {
+    type Err = &'static str;
+
+    /// Parse `ipv4:ipv6` weights, e.g. `3:1`, both nonzero.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ipv4, ipv6) = s.split_once(':').ok_or("Invalid family shares")?;
+        let shares = FamilyShares {
+            ipv4: ipv4.parse().map_err(|_| "Invalid IPv4 share")?,
+            ipv6: ipv6.parse().map_err(|_| "Invalid IPv6 share")?,
+        };
+        if shares.ipv4 == 0 || shares.ipv6 == 0 {
+            return Err("Invalid family shares");
+        }
+        Ok(shares)
+    }
+}
+
+impl Display for FamilyShares {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.ipv4, self.ipv6)
+    }
+}
+
+/// A network prefix, e.g. `192.0.2.0/24` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IpPrefix {
+    address: IpAddr,
+    length: u8,
+}
+
+impl IpPrefix {
+    /// Create a prefix of `length` bits, returns `None` if the length is too long for the
+    /// address family.
+    pub fn new(address: IpAddr, length: u8) -> Option<IpPrefix> {
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        (length <= max).then_some(IpPrefix { address, length })
+    }
+
+    /// Get the prefix of `length` bits containing `ip`, e.g. its /16 for an IPv4 address. The
+    /// length is capped to the address family, and IPv4-mapped IPv6 addresses are IPv4.
+    pub fn containing(ip: IpAddr, length: u8) -> IpPrefix {
+        let (address, length) = match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let length = length.min(32);
+                let mask = u32::MAX.checked_shl(32 - length as u32).unwrap_or(0);
+                (IpAddr::from(Ipv4Addr::from(u32::from(ip) & mask)), length)
+            }
+            IpAddr::V6(ip) => {
+                let length = length.min(128);
+                let mask = u128::MAX.checked_shl(128 - length as u32).unwrap_or(0);
+                (IpAddr::from(Ipv6Addr::from(u128::from(ip) & mask)), length)
+            }
+        };
+        IpPrefix { address, length }
+    }
+
+    /// Get the address of the prefix, as given (the bits past the length are not cleared).
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// Get the length of the prefix, in bits.
+    pub fn length(&self) -> u8 {
+        self.length
+    }
+
+    /// Check if `ip` belongs to the prefix.
+    ///
+    /// IPv4-mapped IPv6 addresses match IPv4 prefixes.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(prefix), IpAddr::V4(ip)) => {
+                common_bits(u32::from(prefix) as u128, u32::from(ip) as u128, 32) >= self.length
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(ip)) => {
+                common_bits(u128::from(prefix), u128::from(ip), 128) >= self.length
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Number of leading bits shared by two `width`-bit integers.
+fn common_bits(a: u128, b: u128, width: u32) -> u8 {
+    let diff = (a ^ b) << (128 - width);
+    diff.leading_zeros().min(width) as u8
+}
+
+impl FromStr for IpPrefix {
+    type Err = &'static str;
+
+    /// Parse `address/length`, a bare address is a prefix of a single host.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, length) = match s.split_once('/') {
+            Some((address, length)) => (address, Some(length)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| "Invalid prefix address")?;
+        let length = match length {
+            Some(length) => length.parse().map_err(|_| "Invalid prefix length")?,
+            None if address.is_ipv4() => 32,
+            None => 128,
+        };
+        IpPrefix::new(address, length).ok_or("Invalid prefix length")
+    }
+}
+
+impl Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.length)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for IpPrefix {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for IpPrefix {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Hosts that asked not to be contacted.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OptOutList {
+    prefixes: Vec<IpPrefix>,
+}
+
+impl OptOutList {
+    /// Create a list from the given prefixes.
+    pub fn new(prefixes: Vec<IpPrefix>) -> OptOutList {
+        OptOutList { prefixes }
+    }
+
+    /// Read a list from a file: one prefix per line, empty lines and `#` comments are ignored.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<OptOutList> {
+        let content = fs::read_to_string(path)?;
+        let mut prefixes = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let prefix = line.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {} ({:?})", number + 1, e, line),
+                )
+            })?;
+            prefixes.push(prefix);
+        }
+        Ok(OptOutList { prefixes })
+    }
+
+    /// Get the prefixes of the list.
+    pub fn prefixes(&self) -> &[IpPrefix] {
+        &self.prefixes
+    }
+
+    /// Check if `ip` opted out.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.prefixes.iter().any(|prefix| prefix.contains(ip))
+    }
+}
+
+/// Why a datagram was not sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// The destination is in the opt-out list.
+    OptedOut,
+    /// The destination already received its daily share of queries.
+    NodeCapReached,
+    /// Sending would exceed the bandwidth cap.
+    BandwidthExceeded,
+    /// The run already sent its maximum number of queries.
+    QueryCapReached,
+    /// Too many queries are waiting for an answer.
+    InFlightCapReached,
+    /// Too many queries are waiting for an answer from the destination.
+    DestinationBusy,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Refusal::OptedOut => "destination opted out",
+            Refusal::NodeCapReached => "daily query cap of the destination reached",
+            Refusal::BandwidthExceeded => "bandwidth cap exceeded",
+            Refusal::QueryCapReached => "query cap of the run reached",
+            Refusal::InFlightCapReached => "too many queries in flight",
+            Refusal::DestinationBusy => "too many queries in flight to the destination",
+        })
+    }
+}
+
+impl Error for Refusal {}
+
+impl From<Refusal> for io::Error {
+    fn from(refusal: Refusal) -> io::Error {
+        io::Error::other(refusal)
+    }
+}
+
+/// Audit counters of the [`TrafficLimits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrafficAudit {
+    /// Queries sent.
+    pub queries_sent: u64,
+    /// Datagrams sent (queries and replies).
+    pub datagrams_sent: u64,
+    /// Bytes sent (queries and replies).
+    pub bytes_sent: u64,
+    /// Datagrams not sent to an opted-out host.
+    pub opted_out: u64,
+    /// Queries not sent because of the per-node daily cap.
+    pub node_cap_reached: u64,
+    /// Datagrams not sent because of the bandwidth cap.
+    pub bandwidth_exceeded: u64,
+    /// Queries not sent because of the query cap of the run.
+    pub query_cap_reached: u64,
+    /// Queries not sent because of the in-flight cap.
+    pub in_flight_cap_reached: u64,
+    /// Queries not sent because of the per-destination in-flight cap.
+    pub destination_busy: u64,
+    /// Traffic sent to IPv4 destinations.
+    pub ipv4: FamilyTraffic,
+    /// Traffic sent to IPv6 destinations.
+    pub ipv6: FamilyTraffic,
+}
+
+impl TrafficAudit {
+    /// Get the traffic sent to `family`.
+    pub fn family(&self, family: AddressFamily) -> &FamilyTraffic {
+        match family {
+            AddressFamily::Ipv4 => &self.ipv4,
+            AddressFamily::Ipv6 => &self.ipv6,
+        }
+    }
+
+    fn family_mut(&mut self, family: AddressFamily) -> &mut FamilyTraffic {
+        match family {
+            AddressFamily::Ipv4 => &mut self.ipv4,
+            AddressFamily::Ipv6 => &mut self.ipv6,
+        }
+    }
+}
+
+/// Audit counters of the traffic of an address family, see [`TrafficAudit::family`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FamilyTraffic {
+    /// Datagrams sent (queries and replies).
+    pub datagrams_sent: u64,
+    /// Bytes sent (queries and replies).
+    pub bytes_sent: u64,
+    /// Datagrams not sent because of the bandwidth cap (of the family, with
+    /// [`TrafficLimits::family_shares`]).
+    pub bandwidth_exceeded: u64,
+}
+
+impl FamilyTraffic {
+    /// Get the average throughput over `elapsed`, in bytes per second.
+    pub fn throughput(&self, elapsed: Duration) -> f64 {
+        if elapsed.is_zero() {
+            0.0
+        } else {
+            self.bytes_sent as f64 / elapsed.as_secs_f64()
+        }
+    }
+}
+
+/// The bandwidth budget of a [`TrafficPolicy`]: shared by the address families, or one per
+/// family.
+#[derive(Debug, Clone)]
+enum Bandwidth {
+    Shared(TokenBucket),
+    PerFamily {
+        ipv4: TokenBucket,
+        ipv6: TokenBucket,
+    },
+}
+
+impl Bandwidth {
+    /// Get the budget enforcing `limits`, if they cap the bandwidth.
+    fn new(limits: &TrafficLimits) -> Option<Bandwidth> {
+        // Allow bursts of one second worth of traffic, and of a datagram at least: the rate (or
+        // a share of it) may be smaller than a datagram.
+        let bucket = |rate: u32| TokenBucket::new(rate as f64, rate.max(MAX_DATAGRAM_SIZE));
+        let rate = limits.max_bytes_per_second?;
+        Some(match limits.family_shares {
+            Some(shares) => Bandwidth::PerFamily {
+                ipv4: bucket(shares.rate(AddressFamily::Ipv4, rate)),
+                ipv6: bucket(shares.rate(AddressFamily::Ipv6, rate)),
+            },
+            None => Bandwidth::Shared(bucket(rate)),
+        })
+    }
+
+    fn bucket(&mut self, family: AddressFamily) -> &mut TokenBucket {
+        match (self, family) {
+            (Bandwidth::Shared(bucket), _) => bucket,
+            (Bandwidth::PerFamily { ipv4, .. }, AddressFamily::Ipv4) => ipv4,
+            (Bandwidth::PerFamily { ipv6, .. }, AddressFamily::Ipv6) => ipv6,
+        }
+    }
+}
+
+/// Enforces [`TrafficLimits`], see [`TrafficPolicy::check`].
+#[derive(Debug, Clone)]
+pub struct TrafficPolicy {
+    limits: TrafficLimits,
+    started: Instant,
+    // Queries sent per host during `day` (counted from the start).
+    day: u64,
+    per_node: HashMap<IpAddr, u32>,
+    bandwidth: Option<Bandwidth>,
+    audit: TrafficAudit,
+}
+
+impl TrafficPolicy {
+    /// Create a policy enforcing `limits`, starting at `now`.
+    pub fn new(limits: TrafficLimits, now: Instant) -> TrafficPolicy {
+        let bandwidth = Bandwidth::new(&limits);
+        TrafficPolicy {
+            limits,
+            started: now,
+            day: 0,
+            per_node: HashMap::new(),
+            bandwidth,
+            audit: TrafficAudit::default(),
+        }
+    }
+
+    /// Check if `size` bytes can be sent to `destination` at `now`, and account for them.
+    ///
+    /// `is_query` tells if the datagram is a query (subject to the query caps) or a reply.
+    /// This is [`TrafficPolicy::admit`] followed by [`TrafficPolicy::record_sent`], for a send
+    /// that cannot fail.
+    pub fn check(
+        &mut self,
+        destination: SocketAddr,
+        size: usize,
+        is_query: bool,
+        now: Instant,
+    ) -> Result<(), Refusal> {
+        self.admit(destination, size, is_query, now)?;
+        self.record_sent(destination, size, is_query);
+        Ok(())
+    }
+
+    /// Check if `size` bytes can be sent to `destination` at `now`, without accounting them as
+    /// sent: [`TrafficPolicy::record_sent`] does once the datagram actually left.
+    ///
+    /// The bandwidth budget is still spent by an admitted datagram.
+    pub fn admit(
+        &mut self,
+        destination: SocketAddr,
+        size: usize,
+        is_query: bool,
+        now: Instant,
+    ) -> Result<(), Refusal> {
+        let result = self.try_check(destination, size, is_query, now);
+        if let Err(refusal) = result {
+            if refusal == Refusal::BandwidthExceeded {
+                let family = AddressFamily::of(destination.ip());
+                self.audit.family_mut(family).bandwidth_exceeded += 1;
+            }
+            self.count(refusal);
+        }
+        result
+    }
+
+    /// Account for `size` bytes sent to `destination`, after [`TrafficPolicy::admit`].
+    pub fn record_sent(&mut self, destination: SocketAddr, size: usize, is_query: bool) {
+        let host = destination.ip().to_canonical();
+        let traffic = self.audit.family_mut(AddressFamily::of(host));
+        traffic.datagrams_sent += 1;
+        traffic.bytes_sent += size as u64;
+        self.audit.datagrams_sent += 1;
+        self.audit.bytes_sent += size as u64;
+        if is_query {
+            self.audit.queries_sent += 1;
+            if self.limits.max_queries_per_node_per_day.is_some() {
+                *self.per_node.entry(host).or_default() += 1;
+            }
+        }
+    }
+
+    /// Check if one more query can be sent while `in_flight` queries wait for an answer,
+    /// `to_destination` of them from the destination of the query.
+    ///
+    /// Nothing is accounted for an accepted query: [`TrafficPolicy::check`] does when it is
+    /// sent.
+    pub fn check_in_flight(
+        &mut self,
+        in_flight: usize,
+        to_destination: usize,
+    ) -> Result<(), Refusal> {
+        let result = if self
+            .limits
+            .max_in_flight
+            .is_some_and(|max| in_flight >= max)
+        {
+            Err(Refusal::InFlightCapReached)
+        } else if self
+            .limits
+            .max_in_flight_per_destination
+            .is_some_and(|max| to_destination >= max)
+        {
+            Err(Refusal::DestinationBusy)
+        } else {
+            Ok(())
+        };
+        if let Err(refusal) = result {
+            self.count(refusal);
+        }
+        result
+    }
+
+    /// Check if a cap on the queries in flight is set, see [`TrafficPolicy::check_in_flight`].
+    pub fn limits_in_flight(&self) -> bool {
+        self.limits.max_in_flight.is_some() || self.limits.max_in_flight_per_destination.is_some()
+    }
+
+    fn count(&mut self, refusal: Refusal) {
+        match refusal {
+            Refusal::OptedOut => self.audit.opted_out += 1,
+            Refusal::NodeCapReached => self.audit.node_cap_reached += 1,
+            Refusal::BandwidthExceeded => self.audit.bandwidth_exceeded += 1,
+            Refusal::QueryCapReached => self.audit.query_cap_reached += 1,
+            Refusal::InFlightCapReached => self.audit.in_flight_cap_reached += 1,
+            Refusal::DestinationBusy => self.audit.destination_busy += 1,
+        }
+    }
+
+    fn try_check(
+        &mut self,
+        destination: SocketAddr,
+        size: usize,
+        is_query: bool,
+        now: Instant,
+    ) -> Result<(), Refusal> {
+        let host = destination.ip().to_canonical();
+        if self.limits.opt_out.contains(host) {
+            return Err(Refusal::OptedOut);
+        }
+        if is_query && self.is_exhausted() {
+            return Err(Refusal::QueryCapReached);
+        }
+
+        let day = now.saturating_duration_since(self.started).as_secs() / DAY.as_secs();
+        if day != self.day {
+            self.day = day;
+            self.per_node.clear();
+        }
+        let node_cap = self.limits.max_queries_per_node_per_day;
+        if is_query
+            && node_cap.is_some_and(|cap| self.per_node.get(&host).copied().unwrap_or(0) >= cap)
+        {
+            return Err(Refusal::NodeCapReached);
+        }
+
+        if let Some(bandwidth) = &mut self.bandwidth
+            && !bandwidth
+                .bucket(AddressFamily::of(host))
+                .try_take_n(size.min(u32::MAX as usize) as u32, now)
+        {
+            return Err(Refusal::BandwidthExceeded);
+        }
+        Ok(())
+    }
+
+    /// Check if the query cap of the run is reached: no query will be sent anymore.
+    pub fn is_exhausted(&self) -> bool {
+        self.limits
+            .max_queries
+            .is_some_and(|max| self.audit.queries_sent >= max)
+    }
+
+    /// Get the audit counters.
+    pub fn audit(&self) -> &TrafficAudit {
+        &self.audit
+    }
+
+    /// Get the limits enforced.
+    pub fn limits(&self) -> &TrafficLimits {
+        &self.limits
+    }
+
+    /// Enforce `limits` from now on, e.g. after a configuration reload.
+    ///
+    /// The counters are kept: the queries already sent count toward the new caps, and the
+    /// queries sent to each host today toward the new per-host cap (if a per-host cap was
+    /// already set, hosts are not counted otherwise). The bandwidth budgets start over if the
+    /// rate or its split between the families changes.
+    pub fn set_limits(&mut self, limits: TrafficLimits) {
+        if limits.max_bytes_per_second != self.limits.max_bytes_per_second
+            || limits.family_shares != self.limits.family_shares
+        {
+            self.bandwidth = Bandwidth::new(&limits);
+        }
+        if limits.max_queries_per_node_per_day.is_none() {
+            self.per_node.clear();
+        }
+        self.limits = limits;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_ip_prefix() {
+        let prefix: IpPrefix = "192.0.2.0/24".parse().unwrap();
+        assert!(prefix.contains(Ipv4Addr::new(192, 0, 2, 200).into()));
+        assert!(prefix.contains(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().into()));
+        assert!(!prefix.contains(Ipv4Addr::new(192, 0, 3, 1).into()));
+        assert!(!prefix.contains(Ipv6Addr::LOCALHOST.into()));
+
+        let prefix: IpPrefix = "2001:db8::/32".parse().unwrap();
+        assert!(prefix.contains("2001:db8:1::1".parse::<IpAddr>().unwrap()));
+        assert!(!prefix.contains("2001:db9::1".parse::<IpAddr>().unwrap()));
+
+        let host: IpPrefix = "198.51.100.7".parse().unwrap();
+        assert_eq!(host.to_string(), "198.51.100.7/32");
+        assert!(
+            "0.0.0.0/0"
+                .parse::<IpPrefix>()
+                .unwrap()
+                .contains(host.address)
+        );
+        assert!("192.0.2.0/33".parse::<IpPrefix>().is_err());
+
+        let mapped = Ipv4Addr::new(198, 51, 100, 7).to_ipv6_mapped();
+        assert_eq!(
+            IpPrefix::containing(mapped.into(), 16).to_string(),
+            "198.51.0.0/16"
+        );
+        assert_eq!(
+            IpPrefix::containing(host.address, 0).to_string(),
+            "0.0.0.0/0"
+        );
+        let ip: IpAddr = "2001:db8:1::1".parse().unwrap();
+        assert_eq!(
+            IpPrefix::containing(ip, 200).to_string(),
+            "2001:db8:1::1/128"
+        );
+        assert_eq!(IpPrefix::containing(ip, 32), prefix);
+    }
+
+    #[test]
+    fn test_traffic_policy() {
+        let now = Instant::now();
+        let limits = TrafficLimits {
+            max_queries: Some(3),
+            max_queries_per_node_per_day: Some(2),
+            max_bytes_per_second: Some(1000),
+            max_in_flight: Some(4),
+            max_in_flight_per_destination: Some(2),
+            opt_out: OptOutList::new(vec!["10.0.0.0/8".parse().unwrap()]),
+            ..TrafficLimits::default()
+        };
+        let mut policy = TrafficPolicy::new(limits, now);
+        let a = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let b = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 2), 6881));
+
+        assert_eq!(
+            policy.check((Ipv4Addr::new(10, 1, 2, 3), 1).into(), 10, true, now),
+            Err(Refusal::OptedOut)
+        );
+        assert_eq!(policy.check(a, 10, true, now), Ok(()));
+        assert_eq!(policy.check(a, 10, true, now), Ok(()));
+        // Another port of the same host.
+        assert_eq!(
+            policy.check((a.ip(), 1).into(), 10, true, now),
+            Err(Refusal::NodeCapReached)
+        );
+        // Replies are not queries.
+        assert_eq!(policy.check(a, 10, false, now), Ok(()));
+        assert_eq!(
+            policy.check(b, 2000, true, now),
+            Err(Refusal::BandwidthExceeded)
+        );
+        assert_eq!(policy.check(b, 10, true, now), Ok(()));
+        assert!(policy.is_exhausted());
+        assert_eq!(
+            policy.check(b, 10, true, now),
+            Err(Refusal::QueryCapReached)
+        );
+        // The next day, the per-node caps are reset but not the cap of the run.
+        assert_eq!(
+            policy.check(a, 10, true, now + DAY),
+            Err(Refusal::QueryCapReached)
+        );
+
+        assert_eq!(policy.check_in_flight(3, 1), Ok(()));
+        assert_eq!(policy.check_in_flight(3, 2), Err(Refusal::DestinationBusy));
+        assert_eq!(
+            policy.check_in_flight(4, 0),
+            Err(Refusal::InFlightCapReached)
+        );
+
+        assert_eq!(
+            *policy.audit(),
+            TrafficAudit {
+                queries_sent: 3,
+                datagrams_sent: 4,
+                bytes_sent: 40,
+                opted_out: 1,
+                node_cap_reached: 1,
+                bandwidth_exceeded: 1,
+                query_cap_reached: 2,
+                in_flight_cap_reached: 1,
+                destination_busy: 1,
+                ipv4: FamilyTraffic {
+                    datagrams_sent: 4,
+                    bytes_sent: 40,
+                    bandwidth_exceeded: 1,
+                },
+                ipv6: FamilyTraffic::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_family_shares() {
+        let shares: FamilyShares = "3:1".parse().unwrap();
+        assert_eq!(shares, FamilyShares { ipv4: 3, ipv6: 1 });
+        assert_eq!(shares.to_string(), "3:1");
+        assert_eq!(shares.rate(AddressFamily::Ipv4, 1000), 750);
+        assert_eq!(shares.rate(AddressFamily::Ipv6, 1000), 250);
+        assert!("0:0".parse::<FamilyShares>().is_err());
+        assert!("1:0".parse::<FamilyShares>().is_err());
+        assert!("3".parse::<FamilyShares>().is_err());
+
+        let now = Instant::now();
+        let limits = TrafficLimits {
+            max_bytes_per_second: Some(8000),
+            family_shares: Some(shares),
+            ..TrafficLimits::default()
+        };
+        let mut policy = TrafficPolicy::new(limits, now);
+        let v4 = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let mapped = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 2).to_ipv6_mapped(), 6881));
+        let v6 = SocketAddr::from(("2001:db8::1".parse::<Ipv6Addr>().unwrap(), 6881));
+        // IPv4 uses up its budget, the IPv6 one is left untouched.
+        assert_eq!(policy.check(v4, 5600, true, now), Ok(()));
+        assert_eq!(
+            policy.check(mapped, 500, true, now),
+            Err(Refusal::BandwidthExceeded)
+        );
+        assert_eq!(policy.check(v6, 2000, true, now), Ok(()));
+        assert_eq!(
+            policy.check(v6, 1, true, now),
+            Err(Refusal::BandwidthExceeded)
+        );
+
+        let audit = policy.audit();
+        assert_eq!(
+            (audit.ipv4.bytes_sent, audit.ipv4.bandwidth_exceeded),
+            (5600, 1)
+        );
+        assert_eq!(audit.family(AddressFamily::Ipv6).bytes_sent, 2000);
+        assert_eq!(audit.bandwidth_exceeded, 2);
+        assert_eq!(audit.ipv6.throughput(Duration::from_secs(10)), 200.0);
+
+        // Without the split, the families share one budget.
+        policy.set_limits(TrafficLimits {
+            max_bytes_per_second: Some(1000),
+            ..TrafficLimits::default()
+        });
+        assert_eq!(policy.check(v4, 1400, true, now), Ok(()));
+        assert_eq!(
+            policy.check(v6, 200, true, now),
+            Err(Refusal::BandwidthExceeded)
+        );
+        // A budget smaller than a datagram still lets full datagrams through.
+        policy.set_limits(TrafficLimits {
+            max_bytes_per_second: Some(100),
+            ..TrafficLimits::default()
+        });
+        assert_eq!(policy.check(v4, 1500, true, now), Ok(()));
+
+        // An uneven split: the IPv6 share is smaller than a datagram, yet full datagrams get
+        // through at its rate.
+        policy.set_limits(TrafficLimits {
+            max_bytes_per_second: Some(4000),
+            family_shares: Some("19:1".parse().unwrap()),
+            ..TrafficLimits::default()
+        });
+        assert_eq!(policy.check(v6, 1400, true, now), Ok(()));
+        assert_eq!(
+            policy.check(v6, 1400, true, now + Duration::from_secs(1)),
+            Err(Refusal::BandwidthExceeded)
+        );
+        assert_eq!(
+            policy.check(v6, 1400, true, now + Duration::from_secs(8)),
+            Ok(())
+        );
+        assert_eq!(policy.check(v4, 3800, true, now), Ok(()));
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let now = Instant::now();
+        let limits = TrafficLimits {
+            max_queries: Some(10),
+            max_queries_per_node_per_day: Some(3),
+            ..TrafficLimits::default()
+        };
+        let mut policy = TrafficPolicy::new(limits, now);
+        let a = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 6881));
+        assert_eq!(policy.check(a, 10, true, now), Ok(()));
+        assert_eq!(policy.check(a, 10, true, now), Ok(()));
+
+        // The queries already sent count toward the new caps.
+        policy.set_limits(TrafficLimits {
+            max_queries: Some(10),
+            max_queries_per_node_per_day: Some(2),
+            opt_out: OptOutList::new(vec!["198.51.100.0/24".parse().unwrap()]),
+            ..TrafficLimits::default()
+        });
+        assert_eq!(policy.limits().max_queries_per_node_per_day, Some(2));
+        assert_eq!(policy.check(a, 10, true, now), Err(Refusal::NodeCapReached));
+        assert_eq!(
+            policy.check((Ipv4Addr::new(198, 51, 100, 1), 1).into(), 10, true, now),
+            Err(Refusal::OptedOut)
+        );
+        policy.set_limits(TrafficLimits {
+            max_queries: Some(2),
+            ..TrafficLimits::default()
+        });
+        assert!(policy.is_exhausted());
+        assert_eq!(policy.audit().queries_sent, 2);
+    }
+
+    #[test]
+    fn test_read_opt_out_list() {
+        let path = std::env::temp_dir().join(format!("bitcrawler-opt-out-{}", std::process::id()));
+        fs::write(&path, "# opt-out\n192.0.2.0/24\n\n2001:db8::/32 # lab\n").unwrap();
+        let list = OptOutList::read(&path).unwrap();
+        fs::write(&path, "192.0.2.0/24\nnot a prefix\n").unwrap();
+        let error = OptOutList::read(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(list.prefixes().len(), 2);
+        assert!(list.contains(Ipv4Addr::new(192, 0, 2, 9).into()));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2:"));
+    }
+}
@@ -0,0 +1,531 @@
+//! Parsing of the metadata of a torrent: the `info` dictionary of a metainfo file, also fetched
+//! from peers with BEP 9, e.g. for the info hashes queued by the
+//! [`Indexer`](crate::indexer::Indexer).
+//!
+//! The v1 (BEP 3), v2 (BEP 52) and hybrid layouts are all exposed as the same list of
+//! [`FileEntry`], see [`Metainfo::files`].
+
+mod name;
+mod v2;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    ops::Range,
+};
+
+use bitcrawler_proto::{
+    bencode::{self, BencodeValue},
+    kademlia::InfoHash,
+};
+
+use crate::node::dict_value;
+pub use name::*;
+pub use v2::*;
+
+/// Prefix of the names of the padding files created by clients predating BEP 47.
+const LEGACY_PADDING_PREFIX: &[u8] = b"_____padding_file_";
+/// Directory holding the padding files, by convention (BEP 47).
+const PADDING_DIRECTORY: &[u8] = b".pad";
+
+/// Why an `info` dictionary was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetainfoError {
+    /// The data is not a bencoded dictionary, or is followed by trailing bytes.
+    InvalidBencode,
+    /// A required field is missing.
+    MissingField(&'static str),
+    /// A field has the wrong type or an impossible value.
+    InvalidField(&'static str),
+    /// The SHA-1 of the `info` dictionary is not the expected info hash.
+    InfoHashMismatch,
+}
+
+impl Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetainfoError::InvalidBencode => f.write_str("invalid bencoded dictionary"),
+            MetainfoError::MissingField(field) => write!(f, "missing field {:?}", field),
+            MetainfoError::InvalidField(field) => write!(f, "invalid field {:?}", field),
+            MetainfoError::InfoHashMismatch => f.write_str("info hash mismatch"),
+        }
+    }
+}
+
+impl Error for MetainfoError {}
+
+/// File attributes of BEP 47 (the `attr` field).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileAttributes {
+    /// `p`: the file only pads the previous one up to a piece boundary, and holds zeros.
+    pub padding: bool,
+    /// `x`: the file is executable.
+    pub executable: bool,
+    /// `h`: the file is hidden.
+    pub hidden: bool,
+    /// `l`: the file is a symbolic link.
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    /// Parse an `attr` string, unknown attributes are ignored.
+    pub fn parse(attr: &[u8]) -> FileAttributes {
+        FileAttributes {
+            padding: attr.contains(&b'p'),
+            executable: attr.contains(&b'x'),
+            hidden: attr.contains(&b'h'),
+            symlink: attr.contains(&b'l'),
+        }
+    }
+}
+
+/// A file of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileEntry {
+    /// Path of the file within the torrent directory, one component per element, as found in
+    /// the metadata (not necessarily UTF-8). A single-file torrent has one file, named after
+    /// the torrent.
+    pub path: Vec<Vec<u8>>,
+    /// Path from the `path.utf-8` field, if any, see [`FileEntry::display_path`].
+    pub path_utf8: Option<Vec<Vec<u8>>>,
+    /// Size of the file, in bytes.
+    pub length: u64,
+    /// Position of the file in the data of the torrent (all the files laid end to end).
+    pub offset: u64,
+    /// Attributes of the file.
+    pub attributes: FileAttributes,
+    /// Root of the Merkle tree of the file (v2 and hybrid torrents), `None` for empty files
+    /// and padding files.
+    pub pieces_root: Option<PiecesRoot>,
+}
+
+impl FileEntry {
+    /// Check if the file is a padding file: with the `p` attribute, or named like the padding
+    /// files of older clients (`_____padding_file_*`, or in the `.pad` directory).
+    pub fn is_padding(&self) -> bool {
+        self.attributes.padding
+            || self
+                .path
+                .first()
+                .is_some_and(|first| first == PADDING_DIRECTORY && self.path.len() > 1)
+            || self
+                .path
+                .last()
+                .is_some_and(|name| name.starts_with(LEGACY_PADDING_PREFIX))
+    }
+
+    /// Get the range of the pieces holding (part of) the file. Empty for an empty file.
+    pub fn pieces(&self, piece_length: u64) -> Range<u64> {
+        if self.length == 0 {
+            let piece = self.offset / piece_length;
+            return piece..piece;
+        }
+        self.offset / piece_length..(self.offset + self.length).div_ceil(piece_length)
+    }
+
+    /// Check if the file starts on a piece boundary, so that its pieces are not shared with the
+    /// previous file.
+    pub fn is_piece_aligned(&self, piece_length: u64) -> bool {
+        self.offset.is_multiple_of(piece_length)
+    }
+}
+
+/// The `info` dictionary of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metainfo {
+    /// Layout(s) of the torrent.
+    pub version: MetaVersion,
+    /// Name of the torrent: the file name of a single-file torrent, the directory name
+    /// otherwise. As found in the metadata, not necessarily UTF-8.
+    pub name: Vec<u8>,
+    /// Name from the `name.utf-8` field, if any, see [`Metainfo::display_name`].
+    pub name_utf8: Option<Vec<u8>>,
+    /// Size of a piece, in bytes.
+    pub piece_length: u64,
+    /// Number of pieces: of the v1 layout if any, otherwise the sum of the pieces of every file
+    /// (the v2 pieces never span two files).
+    pub piece_count: u64,
+    /// Whether the torrent is private (BEP 27).
+    pub private: bool,
+    /// The files, padding files included, in the order of the data.
+    ///
+    /// The v1 layout is used when there is one: the files of a hybrid torrent come from the
+    /// `files` list, with the `pieces root` of their v2 counterpart. The files of a v2 torrent
+    /// are placed as in a hybrid torrent, each starting on a piece boundary.
+    pub files: Vec<FileEntry>,
+    /// Whether the `info` dictionary was in canonical encoding (see
+    /// [`is_canonical_encoding`](bencode::is_canonical_encoding)). If not, re-encoding it
+    /// changes its info hash: it must be stored as received.
+    pub canonical: bool,
+}
+
+impl Metainfo {
+    /// Parse a bencoded `info` dictionary, e.g. the metadata fetched from a peer.
+    ///
+    /// The parsing is lenient about the encoding (e.g. unsorted keys), see
+    /// [`Metainfo::canonical`].
+    pub fn parse(info: &[u8]) -> Result<Metainfo, MetainfoError> {
+        let (read, value) = bencode::decode(&info).map_err(|_| MetainfoError::InvalidBencode)?;
+        if read != info.len() {
+            return Err(MetainfoError::InvalidBencode);
+        }
+        let mut metainfo = Metainfo::from_bencoded(&value)?;
+        metainfo.canonical = bencode::is_canonical_encoding(info);
+        Ok(metainfo)
+    }
+
+    /// Parse a bencoded `info` dictionary fetched for `info_hash`, checking that it hashes to it.
+    ///
+    /// The hash is computed over `info` as received, not over a re-encoding of the decoded
+    /// dictionary, so that non-canonical metadata is still matched to its info hash.
+    pub fn verify(info: &[u8], info_hash: &InfoHash) -> Result<Metainfo, MetainfoError> {
+        if Metainfo::info_hash(info) != *info_hash {
+            return Err(MetainfoError::InfoHashMismatch);
+        }
+        Metainfo::parse(info)
+    }
+
+    /// Compute the (v1) info hash of a bencoded `info` dictionary: the SHA-1 of its bytes.
+    pub fn info_hash(info: &[u8]) -> InfoHash {
+        InfoHash::from(sha1_smol::Sha1::from(info).digest().bytes())
+    }
+
+    /// Parse a decoded `info` dictionary.
+    ///
+    /// The original bytes are unknown: [`Metainfo::canonical`] only tells whether the keys are
+    /// sorted and unique.
+    pub fn from_bencoded(info: &BencodeValue) -> Result<Metainfo, MetainfoError> {
+        if !matches!(info, BencodeValue::Dict(_)) {
+            return Err(MetainfoError::InvalidBencode);
+        }
+        let name = bytes_field(info, b"name", "name")?.to_vec();
+        let name_utf8 = match dict_value(info, b"name.utf-8") {
+            Some(BencodeValue::ByteString(name)) => Some(name.as_bytes().to_vec()),
+            _ => None,
+        };
+        let piece_length = integer_field(info, b"piece length", "piece length")?;
+        if piece_length == 0 {
+            return Err(MetainfoError::InvalidField("piece length"));
+        }
+        let private = matches!(dict_value(info, b"private"), Some(BencodeValue::Integer(1)));
+
+        let v1 = match dict_value(info, b"pieces") {
+            Some(_) => Some(parse_v1(info, &name, name_utf8.as_deref())?),
+            None => None,
+        };
+        let v2 = match dict_value(info, b"meta version") {
+            None => None,
+            Some(BencodeValue::Integer(2)) => {
+                if !piece_length.is_power_of_two() || piece_length < V2_MIN_PIECE_LENGTH {
+                    return Err(MetainfoError::InvalidField("piece length"));
+                }
+                let tree = dict_value(info, b"file tree")
+                    .ok_or(MetainfoError::MissingField("file tree"))?;
+                Some(parse_file_tree(tree, piece_length)?)
+            }
+            Some(_) => return Err(MetainfoError::InvalidField("meta version")),
+        };
+
+        let (version, piece_count, files) = match (v1, v2) {
+            (Some((pieces, files)), None) => (MetaVersion::V1, pieces, files),
+            (None, Some(files)) => {
+                let pieces = files
+                    .iter()
+                    .map(|file| file.length.div_ceil(piece_length))
+                    .sum();
+                (MetaVersion::V2, pieces, files)
+            }
+            (Some((pieces, mut files)), Some(v2_files)) => {
+                let roots: HashMap<&[Vec<u8>], Option<PiecesRoot>> = v2_files
+                    .iter()
+                    .map(|file| (file.path.as_slice(), file.pieces_root))
+                    .collect();
+                for file in files.iter_mut().filter(|file| !file.is_padding()) {
+                    // A single-file hybrid torrent names its file after the torrent in both
+                    // layouts.
+                    file.pieces_root = *roots
+                        .get(file.path.as_slice())
+                        .ok_or(MetainfoError::InvalidField("file tree"))?;
+                }
+                (MetaVersion::Hybrid, pieces, files)
+            }
+            (None, None) => return Err(MetainfoError::MissingField("pieces")),
+        };
+
+        Ok(Metainfo {
+            version,
+            name,
+            name_utf8,
+            piece_length,
+            piece_count,
+            private,
+            files,
+            canonical: info.is_canonical(),
+        })
+    }
+
+    /// Get the files without the padding files, i.e. the files a user would see.
+    pub fn logical_files(&self) -> impl Iterator<Item = &FileEntry> {
+        self.files.iter().filter(|file| !file.is_padding())
+    }
+
+    /// Get the size of the data of the torrent, padding included.
+    pub fn padded_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    /// Get the size of the files without the padding.
+    pub fn content_length(&self) -> u64 {
+        self.logical_files().map(|file| file.length).sum()
+    }
+
+    /// Check if every file (but the empty ones) starts on a piece boundary, so that each piece
+    /// belongs to a single file and files can be verified (or deduplicated) independently.
+    pub fn is_piece_aligned(&self) -> bool {
+        self.logical_files()
+            .all(|file| file.length == 0 || file.is_piece_aligned(self.piece_length))
+    }
+}
+
+/// Parse the v1 layout of an `info` dictionary: the number of pieces, and the files.
+fn parse_v1(
+    info: &BencodeValue,
+    name: &[u8],
+    name_utf8: Option<&[u8]>,
+) -> Result<(u64, Vec<FileEntry>), MetainfoError> {
+    let pieces = bytes_field(info, b"pieces", "pieces")?;
+    if !pieces.len().is_multiple_of(20) {
+        return Err(MetainfoError::InvalidField("pieces"));
+    }
+    let files = match dict_value(info, b"files") {
+        Some(BencodeValue::List(entries)) => {
+            let mut files = Vec::with_capacity(entries.len());
+            let mut offset = 0u64;
+            for entry in entries {
+                let file = parse_file(entry, offset)?;
+                offset = offset
+                    .checked_add(file.length)
+                    .ok_or(MetainfoError::InvalidField("length"))?;
+                files.push(file);
+            }
+            files
+        }
+        Some(_) => return Err(MetainfoError::InvalidField("files")),
+        None => vec![FileEntry {
+            path: vec![name.to_vec()],
+            path_utf8: name_utf8.map(|name| vec![name.to_vec()]),
+            length: integer_field(info, b"length", "length")?,
+            offset: 0,
+            attributes: attributes(info),
+            pieces_root: None,
+        }],
+    };
+    Ok(((pieces.len() / 20) as u64, files))
+}
+
+/// Parse an entry of the `files` list, starting at `offset` in the data of the torrent.
+fn parse_file(entry: &BencodeValue, offset: u64) -> Result<FileEntry, MetainfoError> {
+    let length = integer_field(entry, b"length", "length")?;
+    let path = match dict_value(entry, b"path") {
+        Some(path) => parse_path(path).ok_or(MetainfoError::InvalidField("path"))?,
+        None => return Err(MetainfoError::MissingField("path")),
+    };
+    // Broken alternative paths are not worth rejecting the torrent.
+    let path_utf8 = dict_value(entry, b"path.utf-8").and_then(parse_path);
+    Ok(FileEntry {
+        path,
+        path_utf8,
+        length,
+        offset,
+        attributes: attributes(entry),
+        pieces_root: None,
+    })
+}
+
+/// Parse a non-empty list of path components.
+fn parse_path(path: &BencodeValue) -> Option<Vec<Vec<u8>>> {
+    match path {
+        BencodeValue::List(components) if !components.is_empty() => components
+            .iter()
+            .map(|component| match component {
+                BencodeValue::ByteString(component) => Some(component.as_bytes().to_vec()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn attributes(dict: &BencodeValue) -> FileAttributes {
+    match dict_value(dict, b"attr") {
+        Some(BencodeValue::ByteString(attr)) => FileAttributes::parse(attr.as_bytes()),
+        _ => FileAttributes::default(),
+    }
+}
+
+fn bytes_field<'a>(
+    dict: &'a BencodeValue,
+    key: &[u8],
+    field: &'static str,
+) -> Result<&'a [u8], MetainfoError> {
+    match dict_value(dict, key) {
+        Some(BencodeValue::ByteString(value)) => Ok(value.as_bytes()),
+        Some(_) => Err(MetainfoError::InvalidField(field)),
+        None => Err(MetainfoError::MissingField(field)),
+    }
+}
+
+fn integer_field(
+    dict: &BencodeValue,
+    key: &[u8],
+    field: &'static str,
+) -> Result<u64, MetainfoError> {
+    match dict_value(dict, key) {
+        Some(value) => value
+            .as_u64_checked()
+            .map_err(|_| MetainfoError::InvalidField(field)),
+        None => Err(MetainfoError::MissingField(field)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &[&str], length: i64, attr: Option<&str>) -> BencodeValue {
+        let mut dict = vec![
+            ("length", BencodeValue::from_integer(length)),
+            (
+                "path",
+                BencodeValue::from_list(
+                    path.iter()
+                        .map(|component| BencodeValue::from_string(component.to_string()))
+                        .collect(),
+                ),
+            ),
+        ];
+        if let Some(attr) = attr {
+            dict.push(("attr", BencodeValue::from_string(attr.to_string())));
+        }
+        BencodeValue::from_dict(dict)
+    }
+
+    fn info(files: Vec<BencodeValue>, pieces: usize) -> Vec<u8> {
+        let mut info = BencodeValue::from_dict(vec![
+            ("name", BencodeValue::from_string("album".to_string())),
+            ("piece length", BencodeValue::from_integer(16384)),
+            (
+                "pieces",
+                BencodeValue::ByteString(vec![0u8; 20 * pieces].as_slice().into()),
+            ),
+            ("files", BencodeValue::from_list(files)),
+        ]);
+        info.sort_keys();
+        bencode::encode(&info)
+    }
+
+    #[test]
+    fn test_padding_files() {
+        let metainfo = Metainfo::parse(&info(
+            vec![
+                file(&["01.flac"], 10000, None),
+                file(&[".pad", "6384"], 6384, Some("p")),
+                file(&["02.flac"], 20000, Some("x")),
+                file(&["_____padding_file_0"], 12768, None),
+                file(&["cover.jpg"], 100, None),
+            ],
+            4,
+        ))
+        .unwrap();
+        assert_eq!(metainfo.name, b"album");
+        assert_eq!(metainfo.piece_count, 4);
+        assert_eq!(metainfo.files.len(), 5);
+        let logical: Vec<&[u8]> = metainfo
+            .logical_files()
+            .map(|file| file.path[0].as_slice())
+            .collect();
+        assert_eq!(logical, vec![&b"01.flac"[..], b"02.flac", b"cover.jpg"]);
+        assert_eq!(metainfo.padded_length(), 49252);
+        assert_eq!(metainfo.content_length(), 30100);
+        assert!(metainfo.is_piece_aligned());
+
+        let files: Vec<&FileEntry> = metainfo.logical_files().collect();
+        assert!(files[1].attributes.executable);
+        assert_eq!(files[1].offset, 16384);
+        assert_eq!(files[1].pieces(16384), 1..3);
+        assert_eq!(files[2].offset, 49152);
+        assert_eq!(files[2].pieces(16384), 3..4);
+    }
+
+    #[test]
+    fn test_unaligned_and_single_file() {
+        let metainfo = Metainfo::parse(&info(
+            vec![file(&["a"], 10000, None), file(&["b"], 10000, None)],
+            2,
+        ))
+        .unwrap();
+        assert!(!metainfo.is_piece_aligned());
+        // The pieces are shared at the boundary.
+        assert_eq!(metainfo.files[0].pieces(16384), 0..1);
+        assert_eq!(metainfo.files[1].pieces(16384), 0..2);
+
+        let single = bencode::encode(&BencodeValue::from_dict(vec![
+            ("length", BencodeValue::from_integer(5)),
+            ("name", BencodeValue::from_string("a.txt".to_string())),
+            ("piece length", BencodeValue::from_integer(16384)),
+            (
+                "pieces",
+                BencodeValue::ByteString(vec![0u8; 20].as_slice().into()),
+            ),
+        ]));
+        let metainfo = Metainfo::parse(&single).unwrap();
+        assert_eq!(metainfo.files.len(), 1);
+        assert_eq!(metainfo.files[0].path, vec![b"a.txt".to_vec()]);
+        assert_eq!(metainfo.content_length(), 5);
+    }
+
+    #[test]
+    fn test_invalid_info() {
+        assert_eq!(Metainfo::parse(b"i1e"), Err(MetainfoError::InvalidBencode));
+        assert_eq!(
+            Metainfo::parse(b"d4:name1:ae"),
+            Err(MetainfoError::MissingField("piece length"))
+        );
+        let negative = info(vec![file(&["a"], -1, None)], 1);
+        assert_eq!(
+            Metainfo::parse(&negative),
+            Err(MetainfoError::InvalidField("length"))
+        );
+    }
+
+    #[test]
+    fn test_non_canonical_info_hash() {
+        // Unsorted keys and an integer with a leading zero: re-encoding changes the bytes.
+        let info: &[u8] = b"d4:name5:a.txt6:lengthi05e12:piece lengthi16384e6:pieces20:\
+                            aaaaaaaaaaaaaaaaaaaae";
+        let info_hash = InfoHash::from(sha1_smol::Sha1::from(info).digest().bytes());
+        assert_eq!(Metainfo::info_hash(info), info_hash);
+        let metainfo = Metainfo::verify(info, &info_hash).unwrap();
+        assert!(!metainfo.canonical);
+        assert_eq!(metainfo.content_length(), 5);
+
+        let (_, value) = bencode::decode(&info).unwrap();
+        let mut reencoded = value.clone();
+        reencoded.sort_keys();
+        let reencoded = bencode::encode(&reencoded);
+        assert_ne!(Metainfo::info_hash(&reencoded), info_hash);
+        assert_eq!(
+            Metainfo::verify(&reencoded, &info_hash),
+            Err(MetainfoError::InfoHashMismatch)
+        );
+        assert!(
+            Metainfo::verify(&reencoded, &Metainfo::info_hash(&reencoded))
+                .unwrap()
+                .canonical
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::borrow::Cow;
+
+use super::{FileEntry, Metainfo};
+
+/// Decode a name found in metadata, preferring its `.utf-8` variant.
+///
+/// A valid UTF-8 `.utf-8` variant wins, then a valid UTF-8 `raw` name; otherwise the invalid
+/// sequences are replaced with U+FFFD (in the `.utf-8` variant if any), so the result is always
+/// printable while the raw bytes stay available to whoever needs them.
+pub fn decode_name<'a>(raw: &'a [u8], utf8: Option<&'a [u8]>) -> Cow<'a, str> {
+    if let Some(name) = utf8.and_then(|name| std::str::from_utf8(name).ok()) {
+        return Cow::Borrowed(name);
+    }
+    if let Ok(name) = std::str::from_utf8(raw) {
+        return Cow::Borrowed(name);
+    }
+    String::from_utf8_lossy(utf8.unwrap_or(raw))
+}
+
+impl Metainfo {
+    /// Get the name of the torrent as text, see [`decode_name`].
+    pub fn display_name(&self) -> Cow<'_, str> {
+        decode_name(&self.name, self.name_utf8.as_deref())
+    }
+}
+
+impl FileEntry {
+    /// Get the path of the file as text, one component per element, see [`decode_name`].
+    ///
+    /// The `path.utf-8` variant is only used if it has as many components as the path.
+    pub fn display_path(&self) -> Vec<Cow<'_, str>> {
+        let utf8 = self
+            .path_utf8
+            .as_ref()
+            .filter(|utf8| utf8.len() == self.path.len());
+        self.path
+            .iter()
+            .enumerate()
+            .map(|(i, raw)| decode_name(raw, utf8.map(|utf8| utf8[i].as_slice())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcrawler_proto::bencode::{self, BencodeValue};
+
+    use super::*;
+
+    #[test]
+    fn test_decode_name() {
+        assert_eq!(decode_name(b"plain", None), "plain");
+        assert_eq!(decode_name(b"raw", Some("été".as_bytes())), "été");
+        // Invalid `.utf-8` variants are skipped for a valid raw name.
+        assert_eq!(decode_name(b"raw", Some(b"\xff")), "raw");
+        // Latin-1 bytes are not silently dropped.
+        assert_eq!(decode_name(b"caf\xe9", None), "caf\u{fffd}");
+        assert_eq!(decode_name(b"\xe9", Some(b"a\xff")), "a\u{fffd}");
+    }
+
+    #[test]
+    fn test_display_names() {
+        let string = |bytes: &[u8]| BencodeValue::ByteString(bytes.into());
+        let file = |path: Vec<&[u8]>, utf8: Option<Vec<&[u8]>>| {
+            let mut dict = vec![
+                ("length", BencodeValue::from_integer(1)),
+                (
+                    "path",
+                    BencodeValue::from_list(path.into_iter().map(string).collect()),
+                ),
+            ];
+            if let Some(utf8) = utf8 {
+                dict.push((
+                    "path.utf-8",
+                    BencodeValue::from_list(utf8.into_iter().map(string).collect()),
+                ));
+            }
+            BencodeValue::from_dict(dict)
+        };
+        let mut info = BencodeValue::from_dict(vec![
+            ("name", string(b"M\xfasica")),
+            ("name.utf-8", string("Música".as_bytes())),
+            ("piece length", BencodeValue::from_integer(16384)),
+            ("pieces", string(&[0; 20])),
+            (
+                "files",
+                BencodeValue::from_list(vec![
+                    file(
+                        vec![b"\xc4\xe3\xba\xc3", b"a.mp3"],
+                        Some(vec!["你好".as_bytes(), b"a.mp3"]),
+                    ),
+                    file(vec![b"\xff.txt"], None),
+                    // A `path.utf-8` that does not match the path is ignored.
+                    file(vec![b"b.txt"], Some(vec![b"x", b"y"])),
+                ]),
+            ),
+        ]);
+        info.sort_keys();
+        let metainfo = Metainfo::parse(&bencode::encode(&info)).unwrap();
+
+        assert_eq!(metainfo.display_name(), "Música");
+        assert_eq!(metainfo.name, b"M\xfasica");
+        let paths: Vec<Vec<Cow<str>>> = metainfo
+            .files
+            .iter()
+            .map(|file| file.display_path())
+            .collect();
+        assert_eq!(paths[0], vec!["你好", "a.mp3"]);
+        assert_eq!(paths[1], vec!["\u{fffd}.txt"]);
+        assert_eq!(paths[2], vec!["b.txt"]);
+        // The raw bytes are kept.
+        assert_eq!(metainfo.files[1].path, vec![b"\xff.txt".to_vec()]);
+    }
+}
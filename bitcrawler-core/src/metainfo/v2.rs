@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+
+use bitcrawler_proto::bencode::{self, BencodeValue};
+
+use super::{FileEntry, Metainfo, MetainfoError, attributes, bytes_field, integer_field};
+use crate::node::dict_value;
+
+/// Size of the SHA-256 hashes of BEP 52.
+pub const V2_HASH_LEN: usize = 32;
+/// Smallest piece size allowed by BEP 52 (16 KiB), the size of the leaves of the Merkle trees.
+pub const V2_MIN_PIECE_LENGTH: u64 = 16 << 10;
+
+/// Maximum depth of a `file tree`, deeper trees are rejected rather than recursed into.
+const MAX_TREE_DEPTH: usize = 256;
+
+/// Root of the Merkle tree of the content of a file (BEP 52 `pieces root`).
+pub type PiecesRoot = [u8; V2_HASH_LEN];
+
+/// Version of the metainfo format of a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MetaVersion {
+    /// BEP 3 only: a `pieces` string and a `files` list (or a single file).
+    V1,
+    /// BEP 52 only: a `file tree`, one Merkle tree per file.
+    V2,
+    /// Both layouts, describing the same files (the v1 files are padded to piece boundaries).
+    Hybrid,
+}
+
+impl MetaVersion {
+    /// Check if the torrent has v1 pieces, i.e. is identified by a SHA-1 info hash.
+    pub fn has_v1(&self) -> bool {
+        matches!(self, MetaVersion::V1 | MetaVersion::Hybrid)
+    }
+
+    /// Check if the torrent has v2 Merkle trees, i.e. is identified by a SHA-256 info hash.
+    pub fn has_v2(&self) -> bool {
+        matches!(self, MetaVersion::V2 | MetaVersion::Hybrid)
+    }
+}
+
+/// Parse the `file tree` of a v2 `info` dictionary.
+///
+/// The files are listed in the order of the tree (sorted by path), each starting on a piece
+/// boundary, as they are laid out in a hybrid torrent.
+pub(super) fn parse_file_tree(
+    tree: &BencodeValue,
+    piece_length: u64,
+) -> Result<Vec<FileEntry>, MetainfoError> {
+    let mut files = Vec::new();
+    let mut path = Vec::new();
+    walk_tree(tree, &mut path, &mut files)?;
+    let mut offset = 0u64;
+    for file in &mut files {
+        file.offset = offset;
+        offset = file
+            .length
+            .div_ceil(piece_length)
+            .checked_mul(piece_length)
+            .and_then(|length| offset.checked_add(length))
+            .ok_or(MetainfoError::InvalidField("length"))?;
+    }
+    Ok(files)
+}
+
+fn walk_tree(
+    node: &BencodeValue,
+    path: &mut Vec<Vec<u8>>,
+    files: &mut Vec<FileEntry>,
+) -> Result<(), MetainfoError> {
+    let BencodeValue::Dict(entries) = node else {
+        return Err(MetainfoError::InvalidField("file tree"));
+    };
+    if path.len() > MAX_TREE_DEPTH {
+        return Err(MetainfoError::InvalidField("file tree"));
+    }
+    for (name, child) in entries {
+        if name.as_bytes().is_empty() {
+            // A file: its properties are under an empty key.
+            if path.is_empty() {
+                return Err(MetainfoError::InvalidField("file tree"));
+            }
+            files.push(parse_leaf(child, path.clone())?);
+        } else {
+            path.push(name.as_bytes().to_vec());
+            walk_tree(child, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+fn parse_leaf(leaf: &BencodeValue, path: Vec<Vec<u8>>) -> Result<FileEntry, MetainfoError> {
+    let length = integer_field(leaf, b"length", "length")?;
+    let pieces_root = match dict_value(leaf, b"pieces root") {
+        Some(_) => Some(
+            bytes_field(leaf, b"pieces root", "pieces root")?
+                .try_into()
+                .map_err(|_| MetainfoError::InvalidField("pieces root"))?,
+        ),
+        None if length > 0 => return Err(MetainfoError::MissingField("pieces root")),
+        None => None,
+    };
+    Ok(FileEntry {
+        path,
+        path_utf8: None,
+        length,
+        offset: 0,
+        attributes: attributes(leaf),
+        pieces_root,
+    })
+}
+
+/// The `piece layers` of a v2 torrent: for each file larger than a piece, the hashes of its
+/// pieces, by root of its Merkle tree.
+///
+/// They are stored next to the `info` dictionary in a torrent file, not in the metadata
+/// exchanged with peers.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PieceLayers {
+    layers: HashMap<PiecesRoot, Vec<[u8; V2_HASH_LEN]>>,
+}
+
+impl PieceLayers {
+    /// Parse a `piece layers` dictionary.
+    pub fn from_bencoded(value: &BencodeValue) -> Result<PieceLayers, MetainfoError> {
+        let BencodeValue::Dict(entries) = value else {
+            return Err(MetainfoError::InvalidField("piece layers"));
+        };
+        let mut layers = HashMap::with_capacity(entries.len());
+        for (root, hashes) in entries {
+            let root = root
+                .as_ref()
+                .try_into()
+                .map_err(|_| MetainfoError::InvalidField("piece layers"))?;
+            let hashes = match hashes {
+                BencodeValue::ByteString(hashes)
+                    if hashes.as_bytes().len().is_multiple_of(V2_HASH_LEN) =>
+                {
+                    hashes
+                        .as_bytes()
+                        .chunks_exact(V2_HASH_LEN)
+                        .map(|hash| hash.try_into().expect("exact chunk"))
+                        .collect()
+                }
+                _ => return Err(MetainfoError::InvalidField("piece layers")),
+            };
+            layers.insert(root, hashes);
+        }
+        Ok(PieceLayers { layers })
+    }
+
+    /// Get the piece hashes of the file with the given Merkle root.
+    pub fn get(&self, root: &PiecesRoot) -> Option<&[[u8; V2_HASH_LEN]]> {
+        self.layers.get(root).map(Vec::as_slice)
+    }
+
+    /// Get the number of files with a layer.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Check if no file has a layer.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Check that every file larger than a piece has a layer of the right size (files of a
+    /// single piece are verified with their root alone).
+    pub fn validate(&self, metainfo: &Metainfo) -> Result<(), MetainfoError> {
+        for file in metainfo.logical_files() {
+            let Some(root) = &file.pieces_root else {
+                continue;
+            };
+            if file.length <= metainfo.piece_length {
+                continue;
+            }
+            let expected = file.length.div_ceil(metainfo.piece_length) as usize;
+            match self.get(root) {
+                Some(hashes) if hashes.len() == expected => {}
+                Some(_) => return Err(MetainfoError::InvalidField("piece layers")),
+                None => return Err(MetainfoError::MissingField("piece layers")),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A torrent file: the `info` dictionary, and the `piece layers` of v2 torrents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Torrent {
+    /// The `info` dictionary.
+    pub info: Metainfo,
+    /// The piece hashes of the v2 files, empty for a v1 torrent.
+    pub piece_layers: PieceLayers,
+}
+
+impl Torrent {
+    /// Parse a bencoded torrent file, and check its piece layers against its files.
+    pub fn parse(data: &[u8]) -> Result<Torrent, MetainfoError> {
+        let (read, value) = bencode::decode(&data).map_err(|_| MetainfoError::InvalidBencode)?;
+        if read != data.len() || !matches!(value, BencodeValue::Dict(_)) {
+            return Err(MetainfoError::InvalidBencode);
+        }
+        let info = Metainfo::from_bencoded(
+            dict_value(&value, b"info").ok_or(MetainfoError::MissingField("info"))?,
+        )?;
+        let piece_layers = match dict_value(&value, b"piece layers") {
+            Some(layers) => PieceLayers::from_bencoded(layers)?,
+            None => PieceLayers::default(),
+        };
+        if info.version.has_v2() {
+            piece_layers.validate(&info)?;
+        }
+        Ok(Torrent { info, piece_layers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcrawler_proto::bencode::BencodeString;
+
+    use super::*;
+
+    const PIECE: i64 = 16384;
+
+    fn leaf(length: i64, root: u8) -> BencodeValue {
+        let mut leaf = vec![("length", BencodeValue::from_integer(length))];
+        if length > 0 {
+            leaf.push((
+                "pieces root",
+                BencodeValue::ByteString(vec![root; 32].as_slice().into()),
+            ));
+        }
+        BencodeValue::from_dict(vec![("", BencodeValue::from_dict(leaf))])
+    }
+
+    /// A tree with `a.txt` (1.5 pieces), `dir/b.txt` (1 piece) and `dir/empty`.
+    fn file_tree() -> BencodeValue {
+        BencodeValue::from_dict(vec![
+            ("a.txt", leaf(PIECE + PIECE / 2, 1)),
+            (
+                "dir",
+                BencodeValue::from_dict(vec![("b.txt", leaf(PIECE, 2)), ("empty", leaf(0, 0))]),
+            ),
+        ])
+    }
+
+    fn info(fields: Vec<(&str, BencodeValue)>) -> BencodeValue {
+        let mut info = BencodeValue::from_dict(
+            [
+                ("name", BencodeValue::from_string("torrent".to_string())),
+                ("piece length", BencodeValue::from_integer(PIECE)),
+            ]
+            .into_iter()
+            .chain(fields)
+            .collect(),
+        );
+        info.sort_keys();
+        info
+    }
+
+    fn v1_file(path: &[&str], length: i64, attr: Option<&str>) -> BencodeValue {
+        let mut dict = vec![
+            ("length", BencodeValue::from_integer(length)),
+            (
+                "path",
+                BencodeValue::from_list(
+                    path.iter()
+                        .map(|component| BencodeValue::from_string(component.to_string()))
+                        .collect(),
+                ),
+            ),
+        ];
+        if let Some(attr) = attr {
+            dict.push(("attr", BencodeValue::from_string(attr.to_string())));
+        }
+        BencodeValue::from_dict(dict)
+    }
+
+    fn paths(metainfo: &Metainfo) -> Vec<String> {
+        metainfo
+            .logical_files()
+            .map(|file| String::from_utf8(file.path.join(&b'/')).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_v2_file_tree() {
+        let metainfo = Metainfo::from_bencoded(&info(vec![
+            ("meta version", BencodeValue::from_integer(2)),
+            ("file tree", file_tree()),
+        ]))
+        .unwrap();
+        assert_eq!(metainfo.version, MetaVersion::V2);
+        assert_eq!(paths(&metainfo), vec!["a.txt", "dir/b.txt", "dir/empty"]);
+        let offsets: Vec<u64> = metainfo.files.iter().map(|file| file.offset).collect();
+        assert_eq!(offsets, vec![0, 2 * PIECE as u64, 3 * PIECE as u64]);
+        assert_eq!(metainfo.piece_count, 3);
+        assert_eq!(metainfo.files[0].pieces_root, Some([1; 32]));
+        assert_eq!(metainfo.files[2].pieces_root, None);
+        assert!(metainfo.is_piece_aligned());
+
+        // The piece size of v2 torrents is a power of two of at least 16 KiB.
+        let mut invalid = info(vec![
+            ("meta version", BencodeValue::from_integer(2)),
+            ("file tree", file_tree()),
+        ]);
+        if let BencodeValue::Dict(dict) = &mut invalid {
+            for (key, value) in dict.iter_mut() {
+                if key.as_ref() == b"piece length" {
+                    *value = BencodeValue::from_integer(20000);
+                }
+            }
+        }
+        assert_eq!(
+            Metainfo::from_bencoded(&invalid),
+            Err(MetainfoError::InvalidField("piece length"))
+        );
+    }
+
+    #[test]
+    fn test_hybrid() {
+        let metainfo = Metainfo::from_bencoded(&info(vec![
+            ("meta version", BencodeValue::from_integer(2)),
+            ("file tree", file_tree()),
+            (
+                "files",
+                BencodeValue::from_list(vec![
+                    v1_file(&["a.txt"], PIECE + PIECE / 2, None),
+                    v1_file(&[".pad", "8192"], PIECE / 2, Some("p")),
+                    v1_file(&["dir", "b.txt"], PIECE, None),
+                    v1_file(&["dir", "empty"], 0, None),
+                ]),
+            ),
+            (
+                "pieces",
+                BencodeValue::ByteString(vec![0u8; 3 * 20].as_slice().into()),
+            ),
+        ]))
+        .unwrap();
+        assert_eq!(metainfo.version, MetaVersion::Hybrid);
+        assert!(metainfo.version.has_v1() && metainfo.version.has_v2());
+        assert_eq!(metainfo.files.len(), 4);
+        assert_eq!(paths(&metainfo), vec!["a.txt", "dir/b.txt", "dir/empty"]);
+        let roots: Vec<_> = metainfo
+            .logical_files()
+            .map(|file| file.pieces_root)
+            .collect();
+        assert_eq!(roots, vec![Some([1; 32]), Some([2; 32]), None]);
+        assert_eq!(metainfo.piece_count, 3);
+
+        // Both layouts must describe the same files.
+        let mismatch = info(vec![
+            ("meta version", BencodeValue::from_integer(2)),
+            ("file tree", file_tree()),
+            (
+                "files",
+                BencodeValue::from_list(vec![v1_file(&["other"], PIECE, None)]),
+            ),
+            (
+                "pieces",
+                BencodeValue::ByteString(vec![0u8; 20].as_slice().into()),
+            ),
+        ]);
+        assert_eq!(
+            Metainfo::from_bencoded(&mismatch),
+            Err(MetainfoError::InvalidField("file tree"))
+        );
+    }
+
+    #[test]
+    fn test_torrent_piece_layers() {
+        let torrent = |layers: Vec<(BencodeString, BencodeValue)>| {
+            let mut torrent = BencodeValue::Dict(vec![
+                (
+                    "info".into(),
+                    info(vec![
+                        ("meta version", BencodeValue::from_integer(2)),
+                        ("file tree", file_tree()),
+                    ]),
+                ),
+                ("piece layers".into(), BencodeValue::Dict(layers)),
+            ]);
+            torrent.sort_keys();
+            bencode::encode(&torrent)
+        };
+        // Only `a.txt` spans several pieces.
+        let layer = |count: usize| {
+            (
+                vec![1; 32].into(),
+                BencodeValue::ByteString(vec![7u8; 32 * count].as_slice().into()),
+            )
+        };
+
+        let parsed = Torrent::parse(&torrent(vec![layer(2)])).unwrap();
+        assert_eq!(parsed.piece_layers.len(), 1);
+        assert_eq!(parsed.piece_layers.get(&[1; 32]), Some(&[[7; 32]; 2][..]));
+        assert_eq!(parsed.piece_layers.get(&[2; 32]), None);
+        assert_eq!(
+            Torrent::parse(&torrent(vec![layer(3)])),
+            Err(MetainfoError::InvalidField("piece layers"))
+        );
+        assert_eq!(
+            Torrent::parse(&torrent(vec![])),
+            Err(MetainfoError::MissingField("piece layers"))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::rng::{Rng, SplitMix64};
+
+/// Counters of the faults injected by a [`FaultInjector`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Datagrams dropped.
+    pub dropped: u64,
+    /// Datagrams held back before being handled.
+    pub delayed: u64,
+    /// Datagrams with a corrupted byte.
+    pub corrupted: u64,
+}
+
+/// Faults injected in the datagrams a [`DhtNode`](super::DhtNode) receives, to check that it
+/// recovers from them (see [`DhtNode::faults`](super::DhtNode::faults)): the queries whose
+/// replies are lost or mangled must time out, not stay in flight forever.
+///
+/// Only built with the `chaos` feature, meant for the tests. No fault is injected by default.
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    drop_next: usize,
+    delay: Duration,
+    corrupt_rate: f64,
+    rng: SplitMix64,
+    // Held back datagrams, in the order they are due.
+    delayed: VecDeque<(Instant, SocketAddr, Vec<u8>)>,
+    stats: FaultStats,
+}
+
+impl FaultInjector {
+    pub(super) fn new(seed: Option<u64>) -> FaultInjector {
+        FaultInjector {
+            drop_next: 0,
+            delay: Duration::ZERO,
+            corrupt_rate: 0.0,
+            rng: SplitMix64::from_seed(seed),
+            delayed: VecDeque::new(),
+            stats: FaultStats::default(),
+        }
+    }
+
+    /// Drop the next `count` datagrams received.
+    pub fn drop_next(&mut self, count: usize) {
+        self.drop_next = count;
+    }
+
+    /// Hold back every datagram received for `delay` before handling it.
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Corrupt one random byte of the datagrams received, with probability `rate`.
+    pub fn set_corrupt_rate(&mut self, rate: f64) {
+        self.corrupt_rate = rate;
+    }
+
+    /// Stop injecting faults. The datagrams already held back are still delivered when due.
+    pub fn clear(&mut self) {
+        self.drop_next = 0;
+        self.delay = Duration::ZERO;
+        self.corrupt_rate = 0.0;
+    }
+
+    /// Get the counters of the faults injected.
+    pub fn stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    /// Pass a received datagram through the faults, then to `deliver` unless it is dropped or
+    /// held back.
+    pub(super) fn inject<F>(&mut self, data: &[u8], source: SocketAddr, now: Instant, deliver: F)
+    where
+        F: FnOnce(&[u8], SocketAddr),
+    {
+        if self.drop_next > 0 {
+            self.drop_next -= 1;
+            self.stats.dropped += 1;
+            return;
+        }
+        let mut data = data.to_vec();
+        if !data.is_empty() && self.rng.unit() < self.corrupt_rate {
+            let index = self.rng.below(data.len() as u64) as usize;
+            // A non-zero mask always changes the byte.
+            data[index] ^= (self.rng.below(255) + 1) as u8;
+            self.stats.corrupted += 1;
+        }
+        if self.delay > Duration::ZERO {
+            self.delayed.push_back((now + self.delay, source, data));
+            self.stats.delayed += 1;
+        } else {
+            deliver(&data, source);
+        }
+    }
+
+    /// Hand the held back datagrams due at `now` to `deliver`.
+    pub(super) fn release<F>(&mut self, now: Instant, mut deliver: F)
+    where
+        F: FnMut(&[u8], SocketAddr),
+    {
+        // The delay may have changed in between: deliver in order anyway.
+        while self.delayed.front().is_some_and(|(due, _, _)| *due <= now) {
+            let Some((_, source, data)) = self.delayed.pop_front() else {
+                break;
+            };
+            deliver(&data, source);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_faults() {
+        let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+        let now = Instant::now();
+        let mut faults = FaultInjector::new(Some(1));
+        let mut delivered = Vec::new();
+
+        faults.drop_next(2);
+        for i in 0..3 {
+            faults.inject(&[i], source, now, |data, _| delivered.push(data.to_vec()));
+        }
+        assert_eq!(delivered, [[2]]);
+
+        faults.set_corrupt_rate(1.0);
+        faults.inject(b"abc", source, now, |data, _| delivered.push(data.to_vec()));
+        assert_ne!(delivered[1], b"abc");
+        assert_eq!(delivered[1].len(), 3);
+
+        faults.clear();
+        faults.set_delay(Duration::from_millis(10));
+        faults.inject(b"late", source, now, |data, _| {
+            delivered.push(data.to_vec())
+        });
+        faults.release(now, |data, _| delivered.push(data.to_vec()));
+        assert_eq!(delivered.len(), 2);
+        faults.release(now + Duration::from_millis(10), |data, _| {
+            delivered.push(data.to_vec())
+        });
+        assert_eq!(delivered[2], b"late");
+        assert_eq!(
+            faults.stats(),
+            FaultStats {
+                dropped: 2,
+                delayed: 1,
+                corrupted: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{bencode::BencodeValue, kademlia::Id160};
+
+use super::{NodeEvent, PendingQuery, dict_value};
+
+/// Maximum number of answered queries remembered to recognize their duplicate replies.
+const MAX_ANSWERED: usize = 1 << 16;
+
+/// What a [`DhtNode`](super::DhtNode) does with the duplicate replies to its queries.
+///
+/// Some nodes answer twice (retransmits), and the network duplicates datagrams now and then:
+/// the second reply matches a query already settled. It is counted in the
+/// [`UnmatchedReplies`] of the node whatever the policy, apart from the replies matching no
+/// query at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DuplicatePolicy {
+    /// Drop the duplicates.
+    #[default]
+    Ignore,
+    /// Report the duplicates as [`NodeEvent::Duplicate`].
+    Report,
+    /// Report the duplicates, and take them as one more sample of the latency of the node:
+    /// the [`Crawler`](crate::crawler::Crawler) records the round-trip time of the first reply
+    /// in its routing table again.
+    RefineRtt,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = &'static str;
+
+    /// Parse `ignore`, `report` or `refine-rtt`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(DuplicatePolicy::Ignore),
+            "report" => Ok(DuplicatePolicy::Report),
+            "refine-rtt" => Ok(DuplicatePolicy::RefineRtt),
+            _ => Err("Invalid duplicate policy"),
+        }
+    }
+}
+
+/// Counters of the replies that match no pending query of a [`DhtNode`](super::DhtNode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnmatchedReplies {
+    /// Second replies to a query answered within the query timeout.
+    pub duplicates: u64,
+    /// Replies to no recent query of ours, or from another address than the one queried.
+    pub unsolicited: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Answered {
+    query: PendingQuery,
+    // Round-trip time of the first reply.
+    rtt: Duration,
+    expires: Instant,
+}
+
+/// The queries answered recently, to tell the duplicate replies from the unsolicited ones.
+#[derive(Debug, Clone)]
+pub(super) struct AnsweredQueries {
+    policy: DuplicatePolicy,
+    // Time an answered query is remembered.
+    window: Duration,
+    answered: HashMap<Vec<u8>, Answered>,
+    stats: UnmatchedReplies,
+}
+
+impl AnsweredQueries {
+    pub(super) fn new(policy: DuplicatePolicy, window: Duration) -> AnsweredQueries {
+        AnsweredQueries {
+            policy,
+            window,
+            answered: HashMap::new(),
+            stats: UnmatchedReplies::default(),
+        }
+    }
+
+    /// Remember that the query of `transaction_id` was answered after `rtt`.
+    pub(super) fn record(&mut self, transaction_id: &[u8], query: PendingQuery, rtt: Duration) {
+        if self.answered.len() >= MAX_ANSWERED {
+            return;
+        }
+        let expires = Instant::now() + self.window;
+        self.answered.insert(
+            transaction_id.to_vec(),
+            Answered {
+                query,
+                rtt,
+                expires,
+            },
+        );
+    }
+
+    /// Set what is done with the duplicates from now on.
+    pub(super) fn set_policy(&mut self, policy: DuplicatePolicy) {
+        self.policy = policy;
+    }
+
+    /// Check if a reply from `source` matching no pending query is a duplicate: it answers a
+    /// query answered within the window.
+    pub(super) fn is_duplicate(&self, transaction_id: &[u8], source: SocketAddr) -> bool {
+        self.answered.get(transaction_id).is_some_and(|answered| {
+            answered.query.destination == source && Instant::now() < answered.expires
+        })
+    }
+
+    /// Count a reply matching no pending query that is not a duplicate, see
+    /// [`AnsweredQueries::is_duplicate`].
+    pub(super) fn count_unsolicited(&mut self) {
+        self.stats.unsolicited += 1;
+    }
+
+    /// Handle a reply from `source` matching no pending query: count it, and get the event to
+    /// report if it is a duplicate and the policy reports them.
+    pub(super) fn unmatched(
+        &mut self,
+        transaction_id: &[u8],
+        source: SocketAddr,
+        message: &BencodeValue,
+    ) -> Option<NodeEvent> {
+        let now = Instant::now();
+        let answered = match self.answered.get(transaction_id) {
+            Some(answered) if answered.query.destination == source && now < answered.expires => {
+                answered
+            }
+            _ => {
+                self.stats.unsolicited += 1;
+                return None;
+            }
+        };
+        self.stats.duplicates += 1;
+        if self.policy == DuplicatePolicy::Ignore {
+            return None;
+        }
+        // Error replies carry no id.
+        let id = dict_value(message, b"r")
+            .and_then(|values| dict_value(values, b"id"))
+            .and_then(|id| match id {
+                BencodeValue::ByteString(id) => Id160::try_from(id.as_ref()).ok(),
+                _ => None,
+            });
+        Some(NodeEvent::Duplicate {
+            query: answered.query.clone(),
+            id,
+            rtt: answered.rtt,
+        })
+    }
+
+    /// Forget the queries answered before the window.
+    pub(super) fn expire(&mut self, now: Instant) {
+        self.answered.retain(|_, answered| now < answered.expires);
+    }
+
+    pub(super) fn stats(&self) -> &UnmatchedReplies {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcrawler_proto::bencode;
+
+    use super::*;
+    use crate::node::DhtResponse;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn address(port: u16) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, port).into()
+    }
+
+    fn query(destination: SocketAddr) -> PendingQuery {
+        let now = Instant::now();
+        PendingQuery {
+            query_type: b"ping".to_vec(),
+            destination,
+            target: None,
+            sent_at: now,
+            deadline: now + WINDOW,
+        }
+    }
+
+    fn reply(transaction_id: &str) -> BencodeValue {
+        DhtResponse::new_ping(transaction_id, Id160([2; 20])).to_bencoded()
+    }
+
+    #[test]
+    fn test_duplicate_reply() {
+        let mut answered = AnsweredQueries::new(DuplicatePolicy::Report, WINDOW);
+        let rtt = Duration::from_millis(40);
+        answered.record(b"aa", query(address(6881)), rtt);
+        assert!(answered.is_duplicate(b"aa", address(6881)));
+        // Every duplicate is reported, with the round-trip time of the first reply.
+        for _ in 0..2 {
+            let event = answered.unmatched(b"aa", address(6881), &reply("aa"));
+            let Some(NodeEvent::Duplicate {
+                query,
+                id,
+                rtt: first,
+            }) = event
+            else {
+                panic!("unexpected event {:?}", event);
+            };
+            assert_eq!(query.destination, address(6881));
+            assert_eq!(id, Some(Id160([2; 20])));
+            assert_eq!(first, rtt);
+        }
+        // Error replies carry no id.
+        let (_, error) = bencode::decode(&&b"d1:eli201e5:errore1:t2:aa1:y1:ee"[..]).unwrap();
+        let event = answered.unmatched(b"aa", address(6881), &error);
+        assert!(matches!(event, Some(NodeEvent::Duplicate { id: None, .. })));
+        assert_eq!(
+            *answered.stats(),
+            UnmatchedReplies {
+                duplicates: 3,
+                unsolicited: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_duplicates_counted_whatever_the_policy() {
+        let mut answered = AnsweredQueries::new(DuplicatePolicy::Ignore, WINDOW);
+        answered.record(b"aa", query(address(6881)), Duration::ZERO);
+        assert!(
+            answered
+                .unmatched(b"aa", address(6881), &reply("aa"))
+                .is_none()
+        );
+        answered.set_policy(DuplicatePolicy::RefineRtt);
+        assert!(
+            answered
+                .unmatched(b"aa", address(6881), &reply("aa"))
+                .is_some()
+        );
+        assert_eq!(answered.stats().duplicates, 2);
+    }
+
+    #[test]
+    fn test_distinct_sender_is_unsolicited() {
+        let mut answered = AnsweredQueries::new(DuplicatePolicy::Report, WINDOW);
+        answered.record(b"aa", query(address(6881)), Duration::ZERO);
+        // The transaction id of a query answered by another node, or of no query at all.
+        assert!(!answered.is_duplicate(b"aa", address(6882)));
+        assert!(!answered.is_duplicate(b"bb", address(6881)));
+        assert!(
+            answered
+                .unmatched(b"aa", address(6882), &reply("aa"))
+                .is_none()
+        );
+        assert!(
+            answered
+                .unmatched(b"bb", address(6881), &reply("bb"))
+                .is_none()
+        );
+        answered.count_unsolicited();
+        assert_eq!(
+            *answered.stats(),
+            UnmatchedReplies {
+                duplicates: 0,
+                unsolicited: 3,
+            }
+        );
+        // The query is still remembered for its own node.
+        assert!(answered.is_duplicate(b"aa", address(6881)));
+    }
+
+    #[test]
+    fn test_window_expiry() {
+        let window = Duration::from_millis(50);
+        let mut answered = AnsweredQueries::new(DuplicatePolicy::Report, window);
+        answered.record(b"aa", query(address(6881)), Duration::ZERO);
+        answered.record(b"bb", query(address(6881)), Duration::ZERO);
+
+        // Expiring before the end of the window keeps the queries.
+        answered.expire(Instant::now());
+        assert!(answered.is_duplicate(b"aa", address(6881)));
+        answered.expire(Instant::now() + window);
+        assert!(answered.answered.is_empty());
+        assert!(!answered.is_duplicate(b"aa", address(6881)));
+
+        // A reply after the window is unsolicited, even before the query is forgotten.
+        answered.record(b"cc", query(address(6881)), Duration::ZERO);
+        std::thread::sleep(window);
+        assert!(!answered.is_duplicate(b"cc", address(6881)));
+        assert!(
+            answered
+                .unmatched(b"cc", address(6881), &reply("cc"))
+                .is_none()
+        );
+        assert_eq!(answered.stats().unsolicited, 1);
+        assert_eq!(answered.answered.len(), 1);
+    }
+
+    #[test]
+    fn test_answered_queries_bounded() {
+        let mut answered = AnsweredQueries::new(DuplicatePolicy::Report, WINDOW);
+        for i in 0..=MAX_ANSWERED as u32 {
+            answered.record(&i.to_be_bytes(), query(address(6881)), Duration::ZERO);
+        }
+        assert_eq!(answered.answered.len(), MAX_ANSWERED);
+        assert!(!answered.is_duplicate(&(MAX_ANSWERED as u32).to_be_bytes(), address(6881)));
+    }
+
+    #[test]
+    fn test_parse_duplicate_policy() {
+        assert_eq!("ignore".parse(), Ok(DuplicatePolicy::Ignore));
+        assert_eq!("report".parse(), Ok(DuplicatePolicy::Report));
+        assert_eq!("refine-rtt".parse(), Ok(DuplicatePolicy::RefineRtt));
+        assert!("refine_rtt".parse::<DuplicatePolicy>().is_err());
+    }
+}
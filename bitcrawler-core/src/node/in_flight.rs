@@ -0,0 +1,128 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use super::PendingQuery;
+
+/// The queries of a [`DhtNode`](super::DhtNode) waiting for their reply, by transaction id.
+///
+/// The queries are also counted by destination and ordered by deadline, so that neither the
+/// caps checked on each send nor the timeouts of each poll go through all of them.
+#[derive(Debug, Default)]
+pub(super) struct InFlightQueries {
+    queries: HashMap<Vec<u8>, PendingQuery>,
+    by_destination: HashMap<SocketAddr, usize>,
+    deadlines: BTreeSet<(Instant, Vec<u8>)>,
+}
+
+impl InFlightQueries {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    /// Get the number of queries in flight to `destination`.
+    pub(super) fn to_destination(&self, destination: SocketAddr) -> usize {
+        self.by_destination.get(&destination).copied().unwrap_or(0)
+    }
+
+    pub(super) fn get(&self, transaction_id: &[u8]) -> Option<&PendingQuery> {
+        self.queries.get(transaction_id)
+    }
+
+    /// Add a query, replacing the one with the same transaction id if any.
+    pub(super) fn insert(&mut self, transaction_id: Vec<u8>, query: PendingQuery) {
+        self.remove(&transaction_id);
+        *self.by_destination.entry(query.destination).or_insert(0) += 1;
+        self.deadlines
+            .insert((query.deadline, transaction_id.clone()));
+        self.queries.insert(transaction_id, query);
+    }
+
+    pub(super) fn remove(&mut self, transaction_id: &[u8]) -> Option<PendingQuery> {
+        let query = self.queries.remove(transaction_id)?;
+        self.deadlines
+            .remove(&(query.deadline, transaction_id.to_vec()));
+        if let Some(count) = self.by_destination.get_mut(&query.destination) {
+            *count -= 1;
+            if *count == 0 {
+                self.by_destination.remove(&query.destination);
+            }
+        }
+        Some(query)
+    }
+
+    /// Bring the deadline of the queries due after `deadline` forward to it.
+    pub(super) fn cap_deadline(&mut self, deadline: Instant) {
+        let later = self.deadlines.split_off(&(deadline, Vec::new()));
+        for (_, transaction_id) in later {
+            if let Some(query) = self.queries.get_mut(&transaction_id) {
+                query.deadline = deadline;
+            }
+            self.deadlines.insert((deadline, transaction_id));
+        }
+    }
+
+    /// Remove the queries whose deadline is reached at `now`, soonest first.
+    pub(super) fn expire(&mut self, now: Instant) -> Vec<PendingQuery> {
+        let mut expired = Vec::new();
+        while let Some((deadline, _)) = self.deadlines.first()
+            && *deadline <= now
+        {
+            let (_, transaction_id) = self.deadlines.pop_first().expect("deadline vanished");
+            expired.extend(self.remove(&transaction_id));
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    fn query(port: u16, deadline: Instant) -> PendingQuery {
+        PendingQuery {
+            query_type: b"ping".to_vec(),
+            destination: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            target: None,
+            sent_at: deadline,
+            deadline,
+        }
+    }
+
+    #[test]
+    fn test_in_flight_index() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(10);
+        let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        let mut in_flight = InFlightQueries::new();
+        in_flight.insert(b"aa".to_vec(), query(1, now));
+        in_flight.insert(b"bb".to_vec(), query(1, later));
+        in_flight.insert(b"cc".to_vec(), query(2, later));
+        // The same transaction id replaces the query rather than counting twice.
+        in_flight.insert(b"cc".to_vec(), query(2, later));
+        assert_eq!(in_flight.len(), 3);
+        assert_eq!(in_flight.to_destination(destination), 2);
+
+        let expired = in_flight.expire(now);
+        assert_eq!(expired, vec![query(1, now)]);
+        assert_eq!(in_flight.to_destination(destination), 1);
+
+        assert!(in_flight.remove(b"bb").is_some());
+        assert_eq!(in_flight.to_destination(destination), 0);
+
+        let sooner = now + Duration::from_secs(5);
+        in_flight.cap_deadline(sooner);
+        assert_eq!(in_flight.get(b"cc").unwrap().deadline, sooner);
+        assert!(in_flight.expire(sooner - Duration::from_secs(1)).is_empty());
+        assert_eq!(in_flight.expire(sooner).len(), 1);
+        assert_eq!(in_flight.len(), 0);
+    }
+}
@@ -0,0 +1,1331 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{
+    kademlia::{DistanceHistogram, Id160, InfoHash},
+    krpc::{ErrorCode, Port, PortPolicy, ResponseType, query::QUERY_TYPE_ANNOUNCE_PEER},
+};
+
+use super::{DhtNode, NodeEvent, QueryNode, reply_nodes};
+
+/// Number of round-trip times a lookup needs before hedging on their 95th percentile.
+const MIN_RTT_SAMPLES: usize = 5;
+
+/// Default number of known nodes a lookup of a [`LookupScheduler`] starts from.
+pub const DEFAULT_LOOKUP_SEEDS: usize = 8;
+
+/// Default maximum number of nodes a [`LookupScheduler`] keeps.
+pub const DEFAULT_MAX_KNOWN_NODES: usize = 4096;
+
+/// Settings of a [`lookup_peers`], including when it stops.
+///
+/// The lookup stops on the first criterion met, and in any case once every node worth asking
+/// was asked. Criteria left to `None` are not checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupOptions {
+    /// Maximum number of queries in flight at once.
+    pub parallelism: usize,
+    /// Stop once this many distinct peers were found.
+    pub max_peers: Option<usize>,
+    /// Stop once the `n` nodes closest to the info hash (among the nodes that did not fail)
+    /// all answered: asking the others cannot find closer nodes.
+    pub stable_closest: Option<usize>,
+    /// Stop after this time, counted from the start of the lookup.
+    pub deadline: Option<Duration>,
+    /// Stop once the lookup sent and received this many packets (see
+    /// [`LookupResult::packets`]), so that a lookup in a pathological region of the keyspace
+    /// cannot spend the whole query budget of the node.
+    pub max_packets: Option<usize>,
+    /// Stop once the `n` nodes closest to the info hash all answered with a token, so that the
+    /// info hash can be announced to them (see [`DhtNode::announce_peer`]).
+    pub tokens_from_closest: Option<usize>,
+    /// Ports of the peers kept, the others are dropped (port 0 always is).
+    pub peer_ports: PortPolicy,
+    /// Hedge the slow queries: when the closest node in flight has not answered within the
+    /// 95th percentile of the round-trip times of the lookup, the next candidate is queried
+    /// without waiting for the timeout (beyond [`LookupOptions::parallelism`]). The delay is
+    /// used until enough round-trip times are known, `None` disables hedging.
+    pub hedge_after: Option<Duration>,
+    /// Record the timeline of the lookup in [`LookupResult::trace`].
+    pub trace: bool,
+}
+
+impl Default for LookupOptions {
+    fn default() -> Self {
+        LookupOptions {
+            parallelism: 3,
+            max_peers: None,
+            stable_closest: Some(8),
+            deadline: Some(Duration::from_secs(60)),
+            max_packets: None,
+            tokens_from_closest: None,
+            peer_ports: PortPolicy::default(),
+            hedge_after: None,
+            trace: false,
+        }
+    }
+}
+
+/// Why a lookup stopped, see [`LookupResult::end`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LookupEnd {
+    /// [`LookupOptions::max_peers`] peers were found.
+    PeersFound,
+    /// The closest nodes all answered, see [`LookupOptions::stable_closest`].
+    ClosestStable,
+    /// The deadline of the lookup passed.
+    Deadline,
+    /// The closest nodes all sent a token, see [`LookupOptions::tokens_from_closest`].
+    TokensFound,
+    /// The packet budget of the lookup is spent, see [`LookupOptions::max_packets`].
+    Budget,
+    /// No node was left to ask.
+    Exhausted,
+}
+
+/// A node that answered a lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupNode {
+    pub id: Id160,
+    pub address: SocketAddr,
+    /// Whether the node sent a token, kept in the [`TokenCache`](super::TokenCache) of the
+    /// node.
+    pub has_token: bool,
+}
+
+/// Result of [`lookup_peers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupResult {
+    pub info_hash: Id160,
+    /// Distinct peers found.
+    pub peers: Vec<SocketAddr>,
+    /// Nodes that answered, closest to the info hash first.
+    pub closest: Vec<LookupNode>,
+    /// Number of queries sent.
+    pub queried: usize,
+    /// Number of queries answered.
+    pub answered: usize,
+    /// Number of packets the lookup cost: the queries sent and the replies received, errors
+    /// included.
+    pub packets: usize,
+    /// Number of slow queries the next candidate was queried ahead of, see
+    /// [`LookupOptions::hedge_after`].
+    pub hedged: usize,
+    pub end: LookupEnd,
+    /// Timeline of the lookup, if [`LookupOptions::trace`] is set.
+    pub trace: Option<LookupTrace>,
+}
+
+/// Timeline of a lookup, to debug the slow or non-converging ones (and to compare hop counts
+/// in tests). With the `serde` feature, it serializes e.g. to JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LookupTrace {
+    pub info_hash: Id160,
+    /// Steps of the lookup, in the order they happened.
+    pub steps: Vec<LookupStep>,
+}
+
+/// A step of a lookup, see [`LookupTrace`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LookupStep {
+    /// Time since the start of the lookup.
+    pub elapsed: Duration,
+    /// Node the step is about.
+    pub address: SocketAddr,
+    /// Hops from the contacts to the node: 0 for a contact, 1 for a node returned by a
+    /// contact, and so on.
+    pub hop: u32,
+    pub kind: LookupStepKind,
+}
+
+/// What happened in a [`LookupStep`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LookupStepKind {
+    /// A `get_peers` query was sent to the node.
+    Queried,
+    /// The node answered, with `nodes` nodes and `peers` peers.
+    Answered {
+        id: Id160,
+        nodes: usize,
+        peers: usize,
+    },
+    /// The query could not be sent, timed out or got an error (or another reply than a
+    /// `get_peers` one).
+    Failed,
+    /// The node is slow to answer, the next candidate is queried meanwhile.
+    Hedged,
+    /// The node is the closest to the info hash of the nodes that answered so far.
+    Closest { id: Id160, distance: Id160 },
+}
+
+impl LookupTrace {
+    fn new(info_hash: Id160) -> LookupTrace {
+        LookupTrace {
+            info_hash,
+            steps: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, started: Instant, address: SocketAddr, hop: u32, kind: LookupStepKind) {
+        self.steps.push(LookupStep {
+            elapsed: started.elapsed(),
+            address,
+            hop,
+            kind,
+        });
+    }
+
+    /// Get the number of hops it took to reach the closest node that answered, `None` if no
+    /// node answered.
+    pub fn hops(&self) -> Option<u32> {
+        self.steps
+            .iter()
+            .rev()
+            .find(|step| matches!(step.kind, LookupStepKind::Closest { .. }))
+            .map(|step| step.hop)
+    }
+
+    /// Get the distribution of the distances to the info hash of the nodes that answered.
+    pub fn distances(&self) -> DistanceHistogram {
+        DistanceHistogram::new(&self.info_hash, self.answered().map(|(_, id)| id))
+    }
+
+    /// Get the distribution of the distances to the info hash of the nodes that answered, for
+    /// each hop (the contacts first): a converging lookup gets closer at every hop.
+    pub fn distances_by_hop(&self) -> Vec<DistanceHistogram> {
+        let mut histograms = Vec::new();
+        for (hop, id) in self.answered() {
+            let hop = hop as usize;
+            if histograms.len() <= hop {
+                histograms.resize(hop + 1, DistanceHistogram::new(&self.info_hash, []));
+            }
+            histograms[hop].add(&self.info_hash, id);
+        }
+        histograms
+    }
+
+    /// Iterate over the hop and the id of the nodes that answered.
+    fn answered(&self) -> impl Iterator<Item = (u32, &Id160)> {
+        self.steps.iter().filter_map(|step| match &step.kind {
+            LookupStepKind::Answered { id, .. } => Some((step.hop, id)),
+            _ => None,
+        })
+    }
+}
+
+/// What came of an `announce_peer` query of [`announce_to_closest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceOutcome {
+    /// The node answered the announce.
+    Accepted,
+    /// The node answered with an error, e.g. [`ErrorCode::ProtocolError`] for a token it does
+    /// not accept anymore.
+    Error(ErrorCode),
+    /// The announce was not answered in time.
+    Timeout,
+    /// The node sent no token in the lookup, so it was not announced to.
+    NoToken,
+    /// The announce could not be sent (see [`DhtNode::announce_peer`]).
+    NotSent,
+}
+
+/// An announce to one of the closest nodes, see [`AnnounceResult::announces`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeAnnounce {
+    pub node: LookupNode,
+    pub outcome: AnnounceOutcome,
+}
+
+/// Result of [`announce_to_closest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceResult {
+    /// The lookup that found the nodes and collected their tokens.
+    pub lookup: LookupResult,
+    /// Announces to the closest nodes of the lookup, closest to the info hash first.
+    pub announces: Vec<NodeAnnounce>,
+}
+
+impl AnnounceResult {
+    /// Get the number of nodes that accepted the announce.
+    pub fn accepted(&self) -> usize {
+        self.count(|outcome| outcome == AnnounceOutcome::Accepted)
+    }
+
+    /// Get the number of announces with the outcome `outcome` matches.
+    pub fn count(&self, outcome: impl Fn(AnnounceOutcome) -> bool) -> usize {
+        self.announces
+            .iter()
+            .filter(|announce| outcome(announce.outcome))
+            .count()
+    }
+
+    /// Get the share of the closest nodes that accepted the announce, between 0 and 1 (0 if
+    /// the lookup found no node).
+    pub fn success_rate(&self) -> f64 {
+        if self.announces.is_empty() {
+            return 0.0;
+        }
+        self.accepted() as f64 / self.announces.len() as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    Fresh,
+    Queried,
+    Answered { has_token: bool },
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    id: Id160,
+    address: SocketAddr,
+    state: CandidateState,
+    hop: u32,
+}
+
+/// A query in flight of a lookup.
+#[derive(Debug, Clone)]
+struct InFlight {
+    hop: u32,
+    sent_at: Instant,
+    // Distance of the destination to the info hash, `None` for a contact.
+    distance: Option<Id160>,
+    // The next candidate was queried, without waiting for this one.
+    hedged: bool,
+}
+
+/// Look up the peers of `info_hash`: ask the nodes of `contacts`, then iteratively the nodes
+/// they return that are closer to the info hash, with `get_peers` queries.
+///
+/// The lookup runs until one of the criteria of `options` is met. The node events received
+/// during the lookup are consumed, those of other queries included. Any [`QueryNode`] can
+/// look up, a [`ReadOnlyNode`](super::ReadOnlyNode) included.
+pub fn lookup_peers<N: QueryNode>(
+    node: &mut N,
+    info_hash: impl Into<InfoHash>,
+    contacts: &[SocketAddr],
+    options: &LookupOptions,
+) -> io::Result<LookupResult> {
+    let info_hash = info_hash.into().0;
+    run_lookup(node, info_hash, contacts, &[], options).map(|(result, _)| result)
+}
+
+/// Run a lookup as [`lookup_peers`], starting from the `seeds` (nodes of known id, closest
+/// first) too: the contacts are only asked once no query is in flight and no candidate is
+/// left. Returns the addresses of the nodes that failed to answer along with the result.
+///
+/// The [`LookupOptions::deadline`] is checked by the lookup between two polls of the node:
+/// the deadline of the node (see [`QueryNode::set_deadline`]) is shared with its other queries,
+/// and left alone. The queries of the lookup still in flight at its deadline time out as usual.
+fn run_lookup<N: QueryNode>(
+    node: &mut N,
+    info_hash: Id160,
+    contacts: &[SocketAddr],
+    seeds: &[(Id160, SocketAddr)],
+    options: &LookupOptions,
+) -> io::Result<(LookupResult, Vec<SocketAddr>)> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("lookup", %info_hash, seeds = seeds.len()).entered();
+    iterate_lookup(node, info_hash, contacts, seeds, options)
+}
+
+/// Ask the candidates of a lookup until it ends, see [`run_lookup`].
+fn iterate_lookup<N: QueryNode>(
+    node: &mut N,
+    info_hash: Id160,
+    contacts: &[SocketAddr],
+    seeds: &[(Id160, SocketAddr)],
+    options: &LookupOptions,
+) -> io::Result<(LookupResult, Vec<SocketAddr>)> {
+    let started = Instant::now();
+    let own_id = node.id();
+    // Contacts whose id is not known yet.
+    let mut unknown: VecDeque<SocketAddr> = contacts
+        .iter()
+        .filter(|contact| seeds.iter().all(|(_, address)| address != *contact))
+        .copied()
+        .collect();
+    // Candidates by distance to the info hash.
+    let mut candidates: BTreeMap<Id160, Candidate> = BTreeMap::new();
+    for &(id, address) in seeds {
+        candidates
+            .entry(id.distance(&info_hash))
+            .or_insert(Candidate {
+                id,
+                address,
+                state: CandidateState::Fresh,
+                hop: 0,
+            });
+    }
+    let mut failed = Vec::new();
+    let mut in_flight: HashMap<SocketAddr, InFlight> = HashMap::new();
+    let mut peers = Vec::new();
+    let mut seen_peers = HashSet::new();
+    let mut queried = 0;
+    let mut answered = 0;
+    let mut packets = 0;
+    let mut hedged = 0;
+    // Round-trip times of the answered queries, sorted.
+    let mut rtts: Vec<Duration> = Vec::new();
+    let mut events = Vec::new();
+    let mut trace = options.trace.then(|| LookupTrace::new(info_hash));
+
+    let end = loop {
+        if let Some(end) = check_end(
+            options,
+            &candidates,
+            peers.len(),
+            packets,
+            started,
+            in_flight.is_empty() && unknown.is_empty(),
+        ) {
+            break end;
+        }
+
+        // The closest query in flight (the contacts last) is hedged once it is slow.
+        if let Some(initial) = options.hedge_after {
+            let delay = percentile_95(&rtts).unwrap_or(initial);
+            let closest = in_flight
+                .iter_mut()
+                .filter(|(_, query)| !query.hedged)
+                .min_by_key(|(_, query)| (query.distance.is_none(), query.distance));
+            if let Some((address, query)) = closest
+                && query.sent_at.elapsed() >= delay
+            {
+                query.hedged = true;
+                hedged += 1;
+                if let Some(trace) = &mut trace {
+                    trace.push(started, *address, query.hop, LookupStepKind::Hedged);
+                }
+            }
+        }
+
+        // Ask the closest fresh candidates first, then the contacts. The hedged queries leave
+        // their slot to the next candidate.
+        let slots = options.parallelism.max(1) + in_flight.values().filter(|q| q.hedged).count();
+        while in_flight.len() < slots && options.max_packets.is_none_or(|max| packets < max) {
+            let closest = candidates
+                .iter_mut()
+                .find(|(_, candidate)| candidate.state == CandidateState::Fresh);
+            let (address, hop, distance) = match closest {
+                Some((distance, candidate)) => {
+                    candidate.state = CandidateState::Queried;
+                    (candidate.address, candidate.hop, Some(*distance))
+                }
+                // The contacts are a fallback once the seeds led nowhere.
+                None if !seeds.is_empty() && !in_flight.is_empty() => break,
+                None => match unknown.pop_front() {
+                    Some(address) => (address, 0, None),
+                    None => break,
+                },
+            };
+            if in_flight.contains_key(&address) {
+                continue;
+            }
+            let sent = node.get_peers(address, info_hash).is_ok();
+            if let Some(trace) = &mut trace {
+                let kind = if sent {
+                    LookupStepKind::Queried
+                } else {
+                    LookupStepKind::Failed
+                };
+                trace.push(started, address, hop, kind);
+            }
+            if !sent {
+                failed.push(address);
+            }
+            if sent {
+                let query = InFlight {
+                    hop,
+                    sent_at: Instant::now(),
+                    distance,
+                    hedged: false,
+                };
+                in_flight.insert(address, query);
+                queried += 1;
+                packets += 1;
+            } else if let Some(candidate) = candidates.values_mut().find(|candidate| {
+                candidate.address == address && candidate.state == CandidateState::Queried
+            }) {
+                candidate.state = CandidateState::Failed;
+            }
+        }
+        if in_flight.is_empty() {
+            break LookupEnd::Exhausted;
+        }
+
+        node.poll(&mut events)?;
+        for event in events.drain(..) {
+            let query = match &event {
+                NodeEvent::Response { query, .. }
+                | NodeEvent::Error { query, .. }
+                | NodeEvent::Timeout { query } => query,
+                NodeEvent::Query { .. } | NodeEvent::Duplicate { .. } => continue,
+            };
+            if query.target != Some(info_hash) {
+                continue;
+            }
+            let Some(InFlight { hop, .. }) = in_flight.remove(&query.destination) else {
+                continue;
+            };
+            if !matches!(event, NodeEvent::Timeout { .. }) {
+                packets += 1;
+            }
+            let address = query.destination;
+            let get_peers = match &event {
+                NodeEvent::Response { response, rtt, .. } => match response.get_response_type() {
+                    ResponseType::GetPeers(get_peers) => {
+                        let index = rtts.partition_point(|sample| sample <= rtt);
+                        rtts.insert(index, *rtt);
+                        Some(get_peers)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            let Some(get_peers) = get_peers else {
+                failed.push(address);
+                if let Some(trace) = &mut trace {
+                    trace.push(started, address, hop, LookupStepKind::Failed);
+                }
+                if let Some(candidate) = candidates.values_mut().find(|candidate| {
+                    candidate.address == address && candidate.state == CandidateState::Queried
+                }) {
+                    candidate.state = CandidateState::Failed;
+                }
+                continue;
+            };
+
+            answered += 1;
+            let id = *get_peers.get_id();
+            // The node may have answered with another id than the one it was known by.
+            candidates.retain(|_, candidate| {
+                candidate.address != address || candidate.state != CandidateState::Queried
+            });
+            let distance = id.distance(&info_hash);
+            if let Some(trace) = &mut trace {
+                let nodes = get_peers.get_nodes().len();
+                let peers = get_peers.get_peers().len();
+                let kind = LookupStepKind::Answered { id, nodes, peers };
+                trace.push(started, address, hop, kind);
+                // The candidates are sorted by distance: the first that answered is the closest.
+                let closest = candidates
+                    .iter()
+                    .find(|(_, candidate)| {
+                        matches!(candidate.state, CandidateState::Answered { .. })
+                    })
+                    .map(|(distance, _)| *distance);
+                if closest.is_none_or(|closest| distance < closest) {
+                    let kind = LookupStepKind::Closest { id, distance };
+                    trace.push(started, address, hop, kind);
+                }
+            }
+            candidates.insert(
+                distance,
+                Candidate {
+                    id,
+                    address,
+                    state: CandidateState::Answered {
+                        has_token: get_peers.get_token().is_some(),
+                    },
+                    hop,
+                },
+            );
+            for peer in get_peers.get_peers() {
+                if options.peer_ports.check(peer.port()).is_none() {
+                    continue;
+                }
+                let peer = SocketAddr::V4(*peer);
+                if seen_peers.insert(peer) {
+                    peers.push(peer);
+                }
+            }
+            for (id, address) in reply_nodes(get_peers.get_nodes(), own_id, id, address) {
+                candidates
+                    .entry(id.distance(&info_hash))
+                    .or_insert_with(|| Candidate {
+                        id,
+                        address,
+                        state: CandidateState::Fresh,
+                        hop: hop + 1,
+                    });
+            }
+        }
+    };
+
+    // Several candidates may share an address (e.g. a node that changed its id).
+    let mut addresses = HashSet::new();
+    let closest = candidates
+        .into_values()
+        .filter_map(|candidate| match candidate.state {
+            CandidateState::Answered { has_token } => Some(LookupNode {
+                id: candidate.id,
+                address: candidate.address,
+                has_token,
+            }),
+            _ => None,
+        })
+        .filter(|node| addresses.insert(node.address))
+        .collect();
+    let result = LookupResult {
+        info_hash,
+        peers,
+        closest,
+        queried,
+        answered,
+        packets,
+        hedged,
+        end,
+        trace,
+    };
+    Ok((result, failed))
+}
+
+/// Get the 95th percentile of the sorted round-trip times `rtts`, `None` until there are
+/// enough of them.
+fn percentile_95(rtts: &[Duration]) -> Option<Duration> {
+    (rtts.len() >= MIN_RTT_SAMPLES).then(|| rtts[(rtts.len() * 95).div_ceil(100) - 1])
+}
+
+/// Announce `info_hash` to the `k` nodes closest to it: look up its peers until these nodes
+/// sent their token (see [`LookupOptions::tokens_from_closest`], set to `k`), then send them
+/// `announce_peer` queries and wait for the replies.
+///
+/// The peer is announced on `port`, or on the port of the node if `None` (see
+/// [`DhtNode::announce_peer`]). The outcome of every announce is reported, so that the success
+/// rate of the announces can be measured. Like [`lookup_peers`], the node events received
+/// meanwhile are consumed.
+pub fn announce_to_closest(
+    node: &mut DhtNode,
+    info_hash: impl Into<InfoHash>,
+    port: Option<Port>,
+    k: usize,
+    contacts: &[SocketAddr],
+    options: &LookupOptions,
+) -> io::Result<AnnounceResult> {
+    let info_hash = info_hash.into().0;
+    let options = LookupOptions {
+        tokens_from_closest: Some(k),
+        ..options.clone()
+    };
+    let lookup = lookup_peers(node, info_hash, contacts, &options)?;
+    let mut announces: Vec<NodeAnnounce> = Vec::new();
+    // Announces in flight, by destination, with their index in `announces`.
+    let mut in_flight = HashMap::new();
+    for closest in lookup.closest.iter().take(k) {
+        let outcome = if !closest.has_token {
+            AnnounceOutcome::NoToken
+        } else {
+            match node.announce_peer(closest.address, closest.id, info_hash, port) {
+                Ok(()) => {
+                    in_flight.insert(closest.address, announces.len());
+                    // Settled once the reply, or the timeout, is received.
+                    AnnounceOutcome::Timeout
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => AnnounceOutcome::NoToken,
+                Err(_) => AnnounceOutcome::NotSent,
+            }
+        };
+        announces.push(NodeAnnounce {
+            node: closest.clone(),
+            outcome,
+        });
+    }
+
+    let mut events = Vec::new();
+    while !in_flight.is_empty() {
+        node.poll(&mut events)?;
+        for event in events.drain(..) {
+            let (query, outcome) = match &event {
+                NodeEvent::Response { query, .. } => (query, AnnounceOutcome::Accepted),
+                NodeEvent::Error { query, error } => (query, AnnounceOutcome::Error(error.code)),
+                NodeEvent::Timeout { query } => (query, AnnounceOutcome::Timeout),
+                NodeEvent::Query { .. } | NodeEvent::Duplicate { .. } => continue,
+            };
+            if query.query_type != QUERY_TYPE_ANNOUNCE_PEER || query.target != Some(info_hash) {
+                continue;
+            }
+            if let Some(index) = in_flight.remove(&query.destination) {
+                announces[index].outcome = outcome;
+            }
+        }
+    }
+    Ok(AnnounceResult { lookup, announces })
+}
+
+/// Runs the lookups of many info hashes, e.g. the queue of an indexer, sharing the nodes they
+/// discover: the nodes that answered a lookup are kept, and the next lookups start from the
+/// known nodes closest to their info hash before the contacts. The lookups of info hashes
+/// close to each other then skip the hops from the contacts down to their region of the
+/// keyspace, which cuts their packet cost.
+///
+/// The lookups run one after the other with the [`LookupOptions`] of the scheduler, as
+/// [`lookup_peers`] does (the node events received meanwhile are consumed). The nodes that
+/// fail to answer are forgotten, and so are the nodes farthest from the last info hash looked
+/// up beyond [`LookupScheduler::with_max_known`].
+#[derive(Debug, Clone)]
+pub struct LookupScheduler {
+    contacts: Vec<SocketAddr>,
+    options: LookupOptions,
+    seeds: usize,
+    max_known: usize,
+    // Nodes that answered a lookup, by id.
+    known: BTreeMap<Id160, SocketAddr>,
+}
+
+impl LookupScheduler {
+    /// Create a scheduler starting from `contacts`, without known nodes.
+    pub fn new(contacts: Vec<SocketAddr>, options: LookupOptions) -> LookupScheduler {
+        LookupScheduler {
+            contacts,
+            options,
+            seeds: DEFAULT_LOOKUP_SEEDS,
+            max_known: DEFAULT_MAX_KNOWN_NODES,
+            known: BTreeMap::new(),
+        }
+    }
+
+    /// Set the number of known nodes, the closest to its info hash, a lookup starts from
+    /// ([`DEFAULT_LOOKUP_SEEDS`] by default). With 0, every lookup starts from the contacts.
+    pub fn with_seeds(mut self, seeds: usize) -> LookupScheduler {
+        self.seeds = seeds;
+        self
+    }
+
+    /// Set the maximum number of nodes kept ([`DEFAULT_MAX_KNOWN_NODES`] by default).
+    pub fn with_max_known(mut self, max_known: usize) -> LookupScheduler {
+        self.max_known = max_known;
+        self
+    }
+
+    /// Get the number of nodes known from the previous lookups.
+    pub fn known_nodes(&self) -> usize {
+        self.known.len()
+    }
+
+    /// Look up the peers of every info hash of `targets`, and get the results in the same
+    /// order.
+    ///
+    /// The info hashes are looked up in keyspace order, so that each lookup starts from the
+    /// nodes found by the lookup of the closest info hash looked up before it.
+    pub fn batch<N: QueryNode, T: Copy + Into<InfoHash>>(
+        &mut self,
+        node: &mut N,
+        targets: &[T],
+    ) -> io::Result<Vec<LookupResult>> {
+        let targets: Vec<InfoHash> = targets.iter().map(|&target| target.into()).collect();
+        let mut order: Vec<usize> = (0..targets.len()).collect();
+        order.sort_by_key(|&index| targets[index]);
+        let mut results = vec![None; targets.len()];
+        for index in order {
+            results[index] = Some(self.lookup(node, targets[index])?);
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Look up the peers of `info_hash`, from the known nodes closest to it and the contacts.
+    pub fn lookup<N: QueryNode>(
+        &mut self,
+        node: &mut N,
+        info_hash: impl Into<InfoHash>,
+    ) -> io::Result<LookupResult> {
+        let info_hash = info_hash.into().0;
+        let mut seeds: Vec<(Id160, SocketAddr)> = self
+            .known
+            .iter()
+            .map(|(id, address)| (*id, *address))
+            .collect();
+        keep_closest(&mut seeds, &info_hash, self.seeds);
+        let (result, failed) = run_lookup(node, info_hash, &self.contacts, &seeds, &self.options)?;
+
+        let failed: HashSet<SocketAddr> = failed.into_iter().collect();
+        self.known.retain(|_, address| !failed.contains(address));
+        self.known.extend(
+            result
+                .closest
+                .iter()
+                .map(|closest| (closest.id, closest.address)),
+        );
+        if self.known.len() > self.max_known {
+            let mut known: Vec<(Id160, SocketAddr)> =
+                std::mem::take(&mut self.known).into_iter().collect();
+            keep_closest(&mut known, &info_hash, self.max_known);
+            self.known = known.into_iter().collect();
+        }
+        Ok(result)
+    }
+}
+
+/// Keep the `count` nodes of `nodes` closest to `target`, in no particular order.
+fn keep_closest(nodes: &mut Vec<(Id160, SocketAddr)>, target: &Id160, count: usize) {
+    if count == 0 {
+        nodes.clear();
+    } else if nodes.len() > count {
+        nodes.select_nth_unstable_by_key(count - 1, |(id, _)| id.distance(target));
+        nodes.truncate(count);
+    }
+}
+
+/// Check the termination criteria of `options`, `drained` if nothing is in flight or left in
+/// the contacts.
+fn check_end(
+    options: &LookupOptions,
+    candidates: &BTreeMap<Id160, Candidate>,
+    peers: usize,
+    packets: usize,
+    started: Instant,
+    drained: bool,
+) -> Option<LookupEnd> {
+    if options.max_peers.is_some_and(|max| peers >= max) {
+        return Some(LookupEnd::PeersFound);
+    }
+    if options.max_packets.is_some_and(|max| packets >= max) {
+        return Some(LookupEnd::Budget);
+    }
+    if options
+        .deadline
+        .is_some_and(|deadline| started.elapsed() >= deadline)
+    {
+        return Some(LookupEnd::Deadline);
+    }
+    // The `n` closest candidates that did not fail all satisfy `done`. Fewer candidates are
+    // enough once the lookup has no other node to hear from.
+    let closest_done = |n: usize, done: fn(CandidateState) -> bool| {
+        let mut count = 0;
+        for candidate in candidates
+            .values()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .take(n)
+        {
+            if !done(candidate.state) {
+                return false;
+            }
+            count += 1;
+        }
+        count > 0 && (count == n || drained)
+    };
+    if let Some(n) = options.tokens_from_closest
+        && closest_done(n, |state| {
+            state == CandidateState::Answered { has_token: true }
+        })
+    {
+        return Some(LookupEnd::TokensFound);
+    }
+    if let Some(n) = options.stable_closest
+        && closest_done(n, |state| matches!(state, CandidateState::Answered { .. }))
+    {
+        return Some(LookupEnd::ClosestStable);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        thread,
+    };
+
+    use bitcrawler_proto::krpc::{ErrorMessage, QueryType, node_info::BittorrentNodeInfoV4};
+
+    use super::*;
+    use crate::{
+        node::{DhtResponse, NodeConfig, ReadOnlyNode, tests::local_config},
+        testutil::{self, ServingNode},
+    };
+
+    /// A node answering the `get_peers` and `announce_peer` queries it receives.
+    struct FakeNode {
+        node: testutil::FakeNode,
+        /// Error to answer the `announce_peer` queries with, `None` to accept them.
+        announce_error: Option<ErrorCode>,
+        /// Leave the `announce_peer` queries unanswered.
+        ignore_announces: bool,
+        /// Time taken to answer a `get_peers` query.
+        delay: Duration,
+    }
+
+    impl FakeNode {
+        fn bind(id: Id160) -> FakeNode {
+            FakeNode {
+                node: testutil::FakeNode::bind(id).unwrap(),
+                announce_error: None,
+                ignore_announces: false,
+                delay: Duration::ZERO,
+            }
+        }
+
+        fn info(&self) -> BittorrentNodeInfoV4<Id160> {
+            self.node.info()
+        }
+
+        fn address(&self) -> SocketAddr {
+            self.node.address()
+        }
+
+        fn serve(
+            self,
+            token: bool,
+            nodes: Vec<BittorrentNodeInfoV4<Id160>>,
+            peers: Vec<SocketAddrV4>,
+        ) -> ServingNode {
+            let id = self.node.id();
+            self.node.serve(move |query| {
+                let tid = query.get_transaction_id().clone();
+                Some(match query.get_query() {
+                    QueryType::GetPeers(_) => {
+                        thread::sleep(self.delay);
+                        DhtResponse::new_get_peers(
+                            tid,
+                            id,
+                            token.then(|| b"token".to_vec().into()),
+                            nodes.clone(),
+                            peers.clone(),
+                        )
+                        .to_bencoded()
+                    }
+                    QueryType::AnnouncePeer(_) if self.ignore_announces => return None,
+                    QueryType::AnnouncePeer(_) => match self.announce_error {
+                        Some(code) => {
+                            ErrorMessage::new(tid, code, "bad token".into()).to_bencoded()
+                        }
+                        None => DhtResponse::new_ping(tid, id).to_bencoded(),
+                    },
+                    query => panic!("unexpected query {:?}", query),
+                })
+            })
+        }
+    }
+
+    fn id(first: u8) -> Id160 {
+        let mut id = [0; 20];
+        id[0] = first;
+        Id160(id)
+    }
+
+    /// Get the configuration of the node running the lookups, far from the info hashes.
+    fn lookup_config() -> NodeConfig {
+        let mut config = local_config(0);
+        config.node_id = id(0xff);
+        config.poll_timeout = Duration::from_millis(10);
+        config
+    }
+
+    fn local_node() -> DhtNode {
+        DhtNode::bind(lookup_config()).unwrap()
+    }
+
+    /// A bootstrap node far from the info hash returning two close nodes, which know the
+    /// peers and give tokens.
+    fn network(info_hash: Id160) -> (SocketAddr, Vec<ServingNode>) {
+        let far = FakeNode::bind(id(0x80));
+        let near = FakeNode::bind(info_hash.distance(&id(0x01)));
+        let nearest = FakeNode::bind(info_hash.distance(&id(0x00)));
+        let address = far.address();
+        let peer = |port| SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port);
+        let nodes = vec![near.info(), nearest.info()];
+        let threads = vec![
+            far.serve(false, nodes, vec![]),
+            near.serve(true, vec![], vec![peer(1), peer(2)]),
+            nearest.serve(true, vec![], vec![peer(2), peer(3)]),
+        ];
+        (address, threads)
+    }
+
+    #[test]
+    fn test_lookup_until_tokens() {
+        let info_hash = id(0x10);
+        let (bootstrap, threads) = network(info_hash);
+        let mut node = local_node();
+        let result = lookup_peers(
+            &mut node,
+            info_hash,
+            &[bootstrap],
+            &LookupOptions {
+                stable_closest: None,
+                tokens_from_closest: Some(2),
+                trace: true,
+                ..LookupOptions::default()
+            },
+        )
+        .unwrap();
+        let served: Vec<usize> = threads.into_iter().map(|t| t.stop()).collect();
+
+        assert_eq!(result.end, LookupEnd::TokensFound);
+        assert_eq!(served, [1, 1, 1]);
+        assert_eq!(result.queried, 3);
+        assert_eq!(result.answered, 3);
+        assert_eq!(result.packets, 6);
+        assert_eq!(result.peers.len(), 3);
+        let closest: Vec<(Id160, bool)> = result
+            .closest
+            .iter()
+            .map(|node| (node.id, node.has_token))
+            .collect();
+        assert_eq!(
+            closest,
+            [
+                (info_hash, true),
+                (info_hash.distance(&id(0x01)), true),
+                (id(0x80), false),
+            ]
+        );
+        // The tokens were kept by the node, to announce.
+        assert!(node.tokens().len() >= 2);
+
+        // The bootstrap node is a contact, the nodes it returned are one hop away.
+        let trace = result.trace.unwrap();
+        let count = |kind: fn(&LookupStepKind) -> bool| {
+            trace.steps.iter().filter(|step| kind(&step.kind)).count()
+        };
+        assert_eq!(count(|kind| *kind == LookupStepKind::Queried), 3);
+        assert_eq!(
+            count(|kind| matches!(kind, LookupStepKind::Answered { .. })),
+            3
+        );
+        assert_eq!(count(|kind| *kind == LookupStepKind::Failed), 0);
+        assert_eq!(trace.steps[0].address, bootstrap);
+        assert_eq!(
+            trace.steps[1].kind,
+            LookupStepKind::Answered {
+                id: id(0x80),
+                nodes: 2,
+                peers: 0
+            }
+        );
+        let closest: Vec<(u32, LookupStepKind)> = trace
+            .steps
+            .iter()
+            .filter(|step| matches!(step.kind, LookupStepKind::Closest { .. }))
+            .map(|step| (step.hop, step.kind.clone()))
+            .collect();
+        assert_eq!(
+            closest.first(),
+            Some(&(
+                0,
+                LookupStepKind::Closest {
+                    id: id(0x80),
+                    distance: id(0x90)
+                }
+            ))
+        );
+        assert_eq!(
+            closest.last(),
+            Some(&(
+                1,
+                LookupStepKind::Closest {
+                    id: info_hash,
+                    distance: id(0)
+                }
+            ))
+        );
+        assert_eq!(trace.hops(), Some(1));
+        // The nodes one hop away are all closer than the contact.
+        let distances = trace.distances();
+        assert_eq!((distances.len(), distances.min()), (3, Some(0)));
+        let by_hop = trace.distances_by_hop();
+        assert_eq!(by_hop.len(), 2);
+        assert_eq!((by_hop[0].len(), by_hop[1].len()), (1, 2));
+        assert!(by_hop[1].max() < by_hop[0].min());
+        assert!(trace.steps.is_sorted_by_key(|step| step.elapsed));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&trace).unwrap();
+            assert_eq!(serde_json::from_str::<LookupTrace>(&json).unwrap(), trace);
+        }
+    }
+
+    #[test]
+    fn test_read_only_lookup() {
+        let info_hash = id(0x10);
+        let (bootstrap, threads) = network(info_hash);
+        let mut node = ReadOnlyNode::bind(lookup_config()).unwrap();
+        let result = lookup_peers(
+            &mut node,
+            info_hash,
+            &[bootstrap],
+            &LookupOptions {
+                stable_closest: None,
+                tokens_from_closest: Some(2),
+                ..LookupOptions::default()
+            },
+        )
+        .unwrap();
+        let served: Vec<usize> = threads.into_iter().map(|t| t.stop()).collect();
+
+        // The same lookup as with a full node, the tokens are only reported.
+        assert_eq!(served, [1, 1, 1]);
+        assert_eq!(result.end, LookupEnd::TokensFound);
+        assert_eq!(result.peers.len(), 3);
+        assert_eq!(
+            result.closest.iter().filter(|node| node.has_token).count(),
+            2
+        );
+    }
+    #[test]
+    fn test_lookup_stops_on_peers() {
+        let info_hash = id(0x10);
+        let (bootstrap, threads) = network(info_hash);
+        let mut node = local_node();
+        let result = lookup_peers(
+            &mut node,
+            info_hash,
+            &[bootstrap],
+            &LookupOptions {
+                parallelism: 1,
+                max_peers: Some(2),
+                ..LookupOptions::default()
+            },
+        )
+        .unwrap();
+        let served: usize = threads.into_iter().map(|t| t.stop()).sum();
+
+        assert_eq!(result.end, LookupEnd::PeersFound);
+        assert_eq!(result.trace, None);
+        // One at a time, the nearest node is asked first and has enough peers.
+        assert_eq!(served, 2);
+        assert_eq!(result.peers.len(), 2);
+
+        // The budget stops the lookup once the bootstrap node answered.
+        let (bootstrap, threads) = network(info_hash);
+        let result = lookup_peers(
+            &mut node,
+            info_hash,
+            &[bootstrap],
+            &LookupOptions {
+                max_packets: Some(2),
+                ..LookupOptions::default()
+            },
+        )
+        .unwrap();
+        drop(threads);
+        assert_eq!(result.end, LookupEnd::Budget);
+        assert_eq!((result.queried, result.packets), (1, 2));
+
+        // Without contacts, the lookup has nothing to do.
+        let result = lookup_peers(&mut node, info_hash, &[], &LookupOptions::default()).unwrap();
+        assert_eq!(result.end, LookupEnd::Exhausted);
+        assert_eq!(result.queried, 0);
+
+        // The lookup stops at its deadline. The other queries of the node are not cut short.
+        let silent = testutil::FakeNode::bind(id(0x02)).unwrap();
+        node.ping(silent.address()).unwrap();
+        let started = Instant::now();
+        let result = lookup_peers(
+            &mut node,
+            info_hash,
+            &[silent.address()],
+            &LookupOptions {
+                deadline: Some(Duration::from_millis(100)),
+                ..LookupOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(result.end, LookupEnd::Deadline);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(node.deadline(), None);
+        let mut events = Vec::new();
+        node.poll(&mut events).unwrap();
+        assert_eq!(node.in_flight(), 2);
+    }
+
+    #[test]
+    fn test_lookup_hedging() {
+        let info_hash = id(0x10);
+        let far = FakeNode::bind(id(0x80));
+        let near = FakeNode::bind(info_hash.distance(&id(0x01)));
+        let mut slow = FakeNode::bind(info_hash.distance(&id(0x00)));
+        slow.delay = Duration::from_secs(1);
+        let bootstrap = far.address();
+        let slow_address = slow.address();
+        let peer = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1);
+        let nodes = vec![near.info(), slow.info()];
+        let threads = [
+            far.serve(false, nodes, vec![]),
+            near.serve(true, vec![], vec![peer]),
+            slow.serve(true, vec![], vec![peer]),
+        ];
+        let mut node = local_node();
+        let started = Instant::now();
+        let result = lookup_peers(
+            &mut node,
+            info_hash,
+            &[bootstrap],
+            &LookupOptions {
+                parallelism: 1,
+                max_peers: Some(1),
+                hedge_after: Some(Duration::from_millis(100)),
+                trace: true,
+                ..LookupOptions::default()
+            },
+        )
+        .unwrap();
+
+        // The slow nearest node was asked first, the near one answered meanwhile.
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(result.end, LookupEnd::PeersFound);
+        assert_eq!((result.queried, result.answered, result.hedged), (3, 2, 1));
+        let trace = result.trace.unwrap();
+        let hedged: Vec<_> = trace
+            .steps
+            .iter()
+            .filter(|step| step.kind == LookupStepKind::Hedged)
+            .map(|step| step.address)
+            .collect();
+        assert_eq!(hedged, [slow_address]);
+        for thread in threads {
+            thread.stop();
+        }
+
+        let ms = Duration::from_millis;
+        assert_eq!(percentile_95(&[ms(1), ms(2), ms(3), ms(4)]), None);
+        let rtts: Vec<_> = (1..=20).map(ms).collect();
+        assert_eq!(percentile_95(&rtts), Some(ms(19)));
+    }
+
+    #[test]
+    fn test_batch_lookup() {
+        // A bootstrap node far from the info hashes, returning the nodes of their region,
+        // which know each other.
+        let cluster = || {
+            let far = FakeNode::bind(id(0x80));
+            let near = [id(0x10), id(0x11), id(0x13)].map(FakeNode::bind);
+            let bootstrap = far.address();
+            let peer = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1);
+            let nodes: Vec<_> = near.iter().map(FakeNode::info).collect();
+            let mut threads = vec![far.serve(false, nodes.clone(), vec![])];
+            threads.extend(near.map(|node| node.serve(true, nodes.clone(), vec![peer])));
+            (bootstrap, threads)
+        };
+        let options = LookupOptions {
+            stable_closest: Some(2),
+            ..LookupOptions::default()
+        };
+        let targets = [id(0x12), id(0x10), id(0x11)];
+        let closest = |result: &LookupResult| -> Vec<Id160> {
+            result.closest.iter().take(2).map(|node| node.id).collect()
+        };
+        let mut node = local_node();
+
+        let (bootstrap, threads) = cluster();
+        let independent: Vec<LookupResult> = targets
+            .iter()
+            .map(|&target| lookup_peers(&mut node, target, &[bootstrap], &options).unwrap())
+            .collect();
+        let served: Vec<usize> = threads.into_iter().map(|t| t.stop()).collect();
+        assert_eq!(served, [3, 3, 3, 3]);
+        let queries: usize = served.iter().sum();
+
+        let (bootstrap, threads) = cluster();
+        let mut scheduler = LookupScheduler::new(vec![bootstrap], options.clone()).with_seeds(2);
+        let batch = scheduler.batch(&mut node, &targets).unwrap();
+        let served: Vec<usize> = threads.into_iter().map(|t| t.stop()).collect();
+        // Only the first lookup went through the bootstrap node, the next ones started from
+        // the nodes it found. Which nodes were seeded depends on how many replies each poll
+        // returned, only the total is checked.
+        assert_eq!(served[0], 1);
+        assert!(served.iter().sum::<usize>() < queries);
+        assert!(scheduler.known_nodes() >= 3);
+
+        // The same closest nodes and peers, in the order of the targets, for fewer packets.
+        assert_eq!(batch.len(), targets.len());
+        for (batch, independent) in batch.iter().zip(&independent) {
+            assert_eq!(batch.info_hash, independent.info_hash);
+            assert_eq!(closest(batch), closest(independent));
+            assert_eq!(batch.peers, independent.peers);
+            assert_eq!(batch.end, LookupEnd::ClosestStable);
+        }
+        let packets = |results: &[LookupResult]| -> usize {
+            results.iter().map(|result| result.packets).sum()
+        };
+        assert!(packets(&batch) < packets(&independent));
+
+        // The nodes that stopped answering are forgotten, the lookup falls back on the
+        // contacts. Only the closest known nodes are kept.
+        let mut config = lookup_config();
+        config.query_timeout = Duration::from_millis(100);
+        let mut node = DhtNode::bind(config).unwrap();
+        let mut scheduler = scheduler.with_seeds(DEFAULT_LOOKUP_SEEDS);
+        let result = scheduler.lookup(&mut node, id(0x10)).unwrap();
+        assert_eq!((result.answered, result.end), (0, LookupEnd::Exhausted));
+        assert_eq!(scheduler.known_nodes(), 0);
+        let (bootstrap, threads) = cluster();
+        let mut scheduler = LookupScheduler::new(vec![bootstrap], options).with_max_known(2);
+        scheduler.lookup(&mut node, id(0x10)).unwrap();
+        drop(threads);
+        assert_eq!(scheduler.known_nodes(), 2);
+    }
+
+    #[test]
+    fn test_announce_to_closest() {
+        let info_hash = id(0x10);
+        let far = FakeNode::bind(id(0x80));
+        let accepting = FakeNode::bind(info_hash.distance(&id(0x00)));
+        let mut rejecting = FakeNode::bind(info_hash.distance(&id(0x01)));
+        rejecting.announce_error = Some(ErrorCode::ProtocolError);
+        let mut silent = FakeNode::bind(info_hash.distance(&id(0x02)));
+        silent.ignore_announces = true;
+        let bootstrap = far.address();
+        let nodes = vec![accepting.info(), rejecting.info(), silent.info()];
+        let threads = vec![
+            far.serve(false, nodes, vec![]),
+            accepting.serve(true, vec![], vec![]),
+            rejecting.serve(true, vec![], vec![]),
+            silent.serve(true, vec![], vec![]),
+        ];
+
+        let mut config = lookup_config();
+        config.query_timeout = Duration::from_millis(200);
+        let mut node = DhtNode::bind(config).unwrap();
+        let result = announce_to_closest(
+            &mut node,
+            info_hash,
+            Port::new(6881),
+            4,
+            &[bootstrap],
+            &LookupOptions::default(),
+        )
+        .unwrap();
+        let served: Vec<usize> = threads.into_iter().map(|t| t.stop()).collect();
+
+        // The bootstrap node sends no token: the lookup ends once the closest nodes answered.
+        assert_eq!(result.lookup.end, LookupEnd::ClosestStable);
+        // The silent node got the announce too, it just did not answer it.
+        assert_eq!(served, [1, 2, 2, 1]);
+        let outcomes: Vec<(Id160, AnnounceOutcome)> = result
+            .announces
+            .iter()
+            .map(|announce| (announce.node.id, announce.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (info_hash, AnnounceOutcome::Accepted),
+                (
+                    info_hash.distance(&id(0x01)),
+                    AnnounceOutcome::Error(ErrorCode::ProtocolError)
+                ),
+                (info_hash.distance(&id(0x02)), AnnounceOutcome::Timeout),
+                (id(0x80), AnnounceOutcome::NoToken),
+            ]
+        );
+        assert_eq!(result.accepted(), 1);
+        assert_eq!(
+            result.count(|outcome| matches!(outcome, AnnounceOutcome::Error(_))),
+            1
+        );
+        assert_eq!(result.success_rate(), 0.25);
+        assert_eq!(node.in_flight(), 0);
+    }
+}
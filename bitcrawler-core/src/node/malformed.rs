@@ -0,0 +1,175 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bitcrawler_proto::hex::Hex;
+
+/// Default number of malformed datagrams kept by a [`MalformedLog`].
+pub const DEFAULT_MALFORMED_SAMPLES: usize = 64;
+/// Default number of malformed datagrams logged per minute by a [`MalformedLog`].
+pub const DEFAULT_MALFORMED_LOGS_PER_MINUTE: u32 = 10;
+
+const LOG_WINDOW: Duration = Duration::from_secs(60);
+
+/// A datagram that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedSample {
+    /// When the datagram was received.
+    pub received_at: SystemTime,
+    /// Sender of the datagram.
+    pub source: SocketAddr,
+    /// Why the datagram was rejected.
+    pub error: &'static str,
+    /// Raw content of the datagram.
+    pub data: Vec<u8>,
+}
+
+/// Keeps track of the datagrams a node could not parse.
+///
+/// Every malformed datagram is counted, and the last ones are kept in a ring buffer so that they
+/// can be dumped to disk (see [`MalformedLog::dump`]) to fix the parser later. Logging (a
+/// `tracing` warning, with the `tracing` feature) is rate-limited: past the limit, datagrams are
+/// only counted until the next minute, when the number of suppressed messages is logged.
+#[derive(Debug, Clone)]
+pub struct MalformedLog {
+    capacity: usize,
+    logs_per_minute: u32,
+    samples: VecDeque<MalformedSample>,
+    total: u64,
+    window_start: Option<Instant>,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl MalformedLog {
+    /// Create a log keeping up to `capacity` samples and logging up to `logs_per_minute`
+    /// messages per minute.
+    pub fn new(capacity: usize, logs_per_minute: u32) -> MalformedLog {
+        MalformedLog {
+            capacity,
+            logs_per_minute,
+            samples: VecDeque::with_capacity(capacity),
+            total: 0,
+            window_start: None,
+            logged: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Record a malformed datagram, returns true if it was logged.
+    pub fn record(&mut self, source: SocketAddr, data: &[u8], error: &'static str) -> bool {
+        self.total += 1;
+        if self.capacity > 0 {
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(MalformedSample {
+                received_at: SystemTime::now(),
+                source,
+                error,
+                data: data.to_vec(),
+            });
+        }
+
+        let now = Instant::now();
+        if self
+            .window_start
+            .is_none_or(|start| now.saturating_duration_since(start) >= LOG_WINDOW)
+        {
+            #[cfg(feature = "tracing")]
+            if self.suppressed > 0 {
+                tracing::warn!(
+                    suppressed = self.suppressed,
+                    "malformed datagrams not logged in the last minute"
+                );
+            }
+            self.window_start = Some(now);
+            self.logged = 0;
+            self.suppressed = 0;
+        }
+        if self.logged >= self.logs_per_minute {
+            self.suppressed += 1;
+            return false;
+        }
+        self.logged += 1;
+        #[cfg(feature = "tracing")]
+        tracing::warn!(%source, size = data.len(), error, "malformed datagram");
+        true
+    }
+
+    /// Get the number of malformed datagrams recorded so far.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Get the samples kept, oldest first.
+    pub fn samples(&self) -> impl Iterator<Item = &MalformedSample> {
+        self.samples.iter()
+    }
+
+    /// Write the samples kept to `path`, returns the number of samples written.
+    ///
+    /// The file is overwritten. Each line holds one sample: the reception time (seconds since
+    /// the Unix epoch), the source address, the error between double quotes, and the datagram
+    /// as hexadecimal.
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for sample in &self.samples {
+            let received_at = sample
+                .received_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            writeln!(
+                writer,
+                "{}.{:03} {} {:?} {}",
+                received_at.as_secs(),
+                received_at.subsec_millis(),
+                sample.source,
+                sample.error,
+                Hex(&sample.data)
+            )?;
+        }
+        writer.flush()?;
+        Ok(self.samples.len())
+    }
+}
+
+impl Default for MalformedLog {
+    fn default() -> MalformedLog {
+        MalformedLog::new(DEFAULT_MALFORMED_SAMPLES, DEFAULT_MALFORMED_LOGS_PER_MINUTE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::Ipv4Addr, process};
+
+    use super::*;
+
+    #[test]
+    fn test_malformed_log() {
+        let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+        let mut log = MalformedLog::new(2, 1);
+        assert!(log.record(source, b"d1:y", "Invalid dictionary"));
+        // Over the rate limit, still counted and sampled.
+        assert!(!log.record(source, b"\x00\x01", "Invalid value"));
+        assert!(!log.record(source, b"i1e", "Missing 'y' field"));
+        assert_eq!(log.total(), 3);
+        let errors: Vec<&str> = log.samples().map(|sample| sample.error).collect();
+        assert_eq!(errors, ["Invalid value", "Missing 'y' field"]);
+
+        let path = env::temp_dir().join(format!("bitcrawler-malformed-{}.txt", process::id()));
+        assert_eq!(log.dump(&path).unwrap(), 2);
+        let dump = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" 127.0.0.1:6881 \"Invalid value\" 0001"));
+        assert!(lines[1].ends_with(" \"Missing 'y' field\" 693165"));
+    }
+}
@@ -0,0 +1,1762 @@
+//! A DHT node: sends queries, matches the replies with them, and reports the queries of other
+//! nodes.
+
+#[cfg(feature = "chaos")]
+mod chaos;
+mod duplicates;
+mod in_flight;
+mod lookup;
+mod malformed;
+mod reachability;
+mod read_only;
+mod reply;
+mod tokens;
+
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{
+    bencode::{self, BencodeDict, BencodeString, BencodeValue},
+    consts::DEFAULT_PORT,
+    kademlia::Id160,
+    krpc::{
+        ENVELOPE_TRANSACTION_ID_LEN, ErrorMessage, MessageEnvelope, MessageExtensions, MessageKind,
+        MessageOptions, Port, Query, QueryTemplate, QueryType, QuirkDatabase, Response,
+        ResponseType, apply_shims, lint_datagram,
+        node_info::BittorrentNodeInfoV4,
+        peek_header,
+        query::{
+            QUERY_TYPE_ANNOUNCE_PEER, QUERY_TYPE_FIND_NODE, QUERY_TYPE_GET, QUERY_TYPE_GET_PEERS,
+            QUERY_TYPE_PING, QUERY_TYPE_SAMPLE_INFOHASHES,
+        },
+    },
+};
+
+use crate::{
+    limits::{TrafficAudit, TrafficLimits, TrafficPolicy},
+    pipeline::{QueueConfig, QueueStats},
+    rng::{Rng, SplitMix64},
+    transport::{
+        BackoffConfig, DEFAULT_BATCH_SIZE, Direction, Receiver, Replay, ReplayConfig,
+        SendFailureStats, SendGuard, SocketConfig, SocketError, SocketManager, SocketReport,
+        WiretapWriter,
+    },
+    watchdog::Heartbeat,
+};
+#[cfg(feature = "chaos")]
+pub use chaos::*;
+pub use duplicates::*;
+pub use lookup::*;
+pub use malformed::*;
+pub use reachability::*;
+pub use read_only::*;
+pub use reply::*;
+pub use tokens::*;
+
+use in_flight::InFlightQueries;
+
+/// Default time after which an unanswered query times out.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shortest time between two warnings about the nonconforming messages sent.
+const NONCONFORMING_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Client version sent in the `v` field of the queries: `bc` followed by the major and minor
+/// version of the crate.
+pub const CLIENT_VERSION: &[u8] = b"bc\x00\x01";
+
+/// Who built an outgoing message, see `DhtNode::lint_outgoing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    /// The node encoded it.
+    Node,
+    /// The caller gave its content, e.g. the arguments of a custom query or a raw reply.
+    Caller,
+}
+
+/// Response of the BitTorrent DHT over IPv4.
+pub type DhtResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+
+/// Configuration of a [`DhtNode`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeConfig {
+    /// Socket(s) of the node.
+    pub socket: SocketConfig,
+    /// Node id sent in every query.
+    pub node_id: Id160,
+    /// Time after which an unanswered query times out.
+    pub query_timeout: Duration,
+    /// Maximum time [`DhtNode::poll`] blocks waiting for a datagram.
+    pub poll_timeout: Duration,
+    /// Known quirks of the other clients, undone before parsing their messages (the messages
+    /// of the other clients are parsed strictly).
+    pub quirks: QuirkDatabase,
+    /// Number of malformed datagrams kept for inspection, see [`MalformedLog`].
+    pub malformed_samples: usize,
+    /// Maximum number of malformed datagrams logged per minute.
+    pub malformed_logs_per_minute: u32,
+    /// Caps on the traffic of the node, and hosts never to contact.
+    pub limits: TrafficLimits,
+    /// Top-level fields attached to every query sent (client version, read-only flag...).
+    pub message: MessageOptions,
+    /// Hooks on the messages sent and received (e.g. signatures of a private overlay), see
+    /// [`MessageExtension`](bitcrawler_proto::krpc::MessageExtension). The received messages
+    /// they reject are malformed.
+    pub extensions: MessageExtensions,
+    /// Backoff of the destinations sends fail to, see [`SendGuard`].
+    pub backoff: BackoffConfig,
+    /// Queue between a dedicated receive thread and [`DhtNode::poll`], see
+    /// [`SocketManager::spawn_receive_thread`]. The sockets are read by `poll` if `None`.
+    pub receive_queue: Option<QueueConfig>,
+    /// Expiry and capacity of the tokens kept from `get_peers` replies, see [`TokenCache`].
+    pub tokens: TokenCacheConfig,
+    /// Wiretap file every datagram received or sent by the node is recorded to, see
+    /// [`WiretapWriter`].
+    pub wiretap: Option<PathBuf>,
+    /// Recorded traffic the node consumes instead of its sockets (dry run): the datagrams
+    /// received in the recording are handed out by [`DhtNode::poll`], the queries sent in the
+    /// recording wait for their replies as if the node sent them, and nothing is sent on the
+    /// network. The node is then bound to an ephemeral loopback port, whatever
+    /// [`NodeConfig::socket`] says.
+    pub replay: Option<ReplayConfig>,
+    /// Seed of the random choices of the node (transaction ids), to reproduce a run. The seed
+    /// is random if `None`.
+    pub seed: Option<u64>,
+    /// What is done with the second replies to a query, see [`DuplicatePolicy`].
+    pub duplicates: DuplicatePolicy,
+}
+
+impl NodeConfig {
+    /// Create a configuration with the default settings for the given node id.
+    ///
+    /// The node listens on 0.0.0.0:6881.
+    pub fn new(node_id: Id160) -> NodeConfig {
+        NodeConfig {
+            socket: SocketConfig::new((std::net::Ipv4Addr::UNSPECIFIED, DEFAULT_PORT).into()),
+            node_id,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            poll_timeout: Duration::from_millis(100),
+            quirks: QuirkDatabase::new(),
+            malformed_samples: DEFAULT_MALFORMED_SAMPLES,
+            malformed_logs_per_minute: DEFAULT_MALFORMED_LOGS_PER_MINUTE,
+            limits: TrafficLimits::default(),
+            message: MessageOptions {
+                version: Some(CLIENT_VERSION.into()),
+                ..MessageOptions::default()
+            },
+            extensions: MessageExtensions::new(),
+            backoff: BackoffConfig::default(),
+            receive_queue: None,
+            tokens: TokenCacheConfig::default(),
+            wiretap: None,
+            replay: None,
+            seed: None,
+            duplicates: DuplicatePolicy::default(),
+        }
+    }
+}
+
+/// The `ping` and `find_node` queries of the node, encoded once: only their transaction id and
+/// target change from one query to the next.
+struct QueryTemplates {
+    ping: QueryTemplate,
+    find_node: QueryTemplate,
+}
+
+/// A query sent by the node, waiting for its reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingQuery {
+    /// Method of the query (`ping`, `find_node`...).
+    pub query_type: Vec<u8>,
+    /// Node the query was sent to.
+    pub destination: SocketAddr,
+    /// Target of `find_node` and `get_peers` queries.
+    pub target: Option<Id160>,
+    /// When the query was sent.
+    pub sent_at: Instant,
+    /// When the query times out: after the query timeout of the node, or at the deadline of
+    /// the node if sooner (see [`DhtNode::set_deadline`]).
+    pub deadline: Instant,
+}
+
+/// Something that happened to a [`DhtNode`], see [`DhtNode::poll`].
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    /// A node answered one of our queries.
+    ///
+    /// Replies to custom queries, and replies that do not have the shape expected for their
+    /// query, are reported as [`ResponseType::Raw`].
+    Response {
+        query: PendingQuery,
+        response: DhtResponse,
+        rtt: Duration,
+    },
+    /// A node replied to one of our queries with an error.
+    Error {
+        query: PendingQuery,
+        error: ErrorMessage,
+    },
+    /// A query was not answered in time.
+    Timeout { query: PendingQuery },
+    /// A node replied again to a query it already answered, reported with the
+    /// [`DuplicatePolicy::Report`] and [`DuplicatePolicy::RefineRtt`] policies.
+    Duplicate {
+        query: PendingQuery,
+        /// Id in the reply, `None` for an error reply.
+        id: Option<Id160>,
+        /// Round-trip time of the first reply. The duplicate comes later, its own time would
+        /// overestimate the latency of the node.
+        rtt: Duration,
+    },
+    /// Another node sent us a query.
+    Query {
+        source: SocketAddr,
+        query: Query<Id160>,
+    },
+}
+
+/// Counters of the datagrams received by a [`DhtNode`], malformed ones included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReceiveStats {
+    /// Datagrams received.
+    pub datagrams: u64,
+    /// Bytes received.
+    pub bytes: u64,
+}
+
+impl ReceiveStats {
+    fn record(&mut self, size: usize) {
+        self.datagrams += 1;
+        self.bytes += size as u64;
+    }
+}
+
+/// A node of the BitTorrent DHT, bound to one socket, or to several sockets the queries are
+/// spread over (see [`SocketConfig::sockets`]).
+///
+/// The node does not run by itself: queries are sent with [`DhtNode::ping`],
+/// [`DhtNode::find_node`] and [`DhtNode::get_peers`], then [`DhtNode::poll`] must be called
+/// repeatedly to receive the replies (and the queries of other nodes).
+///
+/// Everything sent goes through the [`TrafficLimits`] of the node: a datagram they refuse is
+/// not sent, and the send fails with an error wrapping a [`Refusal`](crate::limits::Refusal).
+/// The caps on the queries in flight fail fast the same way: the caller queues the query
+/// itself, or drops it.
+/// Send failures are blamed on their destination, which is then skipped for a while (see
+/// [`SendGuard`]): they are reported as errors, and never bring the node down.
+///
+/// A node replaying a recording (see [`NodeConfig::replay`]) goes through the same steps,
+/// traffic limits included, but drops what it would send.
+pub struct DhtNode {
+    config: NodeConfig,
+    sockets: SocketManager,
+    receiver: Receiver,
+    in_flight: InFlightQueries,
+    answered: AnsweredQueries,
+    envelope: MessageEnvelope,
+    // Built on the first query that uses them, dropped when the id changes.
+    templates: Option<QueryTemplates>,
+    malformed: MalformedLog,
+    received: ReceiveStats,
+    policy: TrafficPolicy,
+    sends: SendGuard,
+    tokens: TokenCache,
+    deadline: Option<Instant>,
+    wiretap: Option<WiretapWriter<BufWriter<File>>>,
+    replay: Option<Replay>,
+    rng: SplitMix64,
+    // Last warning about a nonconforming message sent (release builds only), and the messages
+    // not warned about since.
+    nonconforming_warned_at: Option<Instant>,
+    nonconforming_suppressed: u64,
+    #[cfg(feature = "chaos")]
+    faults: FaultInjector,
+}
+
+impl DhtNode {
+    /// Bind the socket(s) of the node.
+    pub fn bind(config: NodeConfig) -> io::Result<DhtNode> {
+        let replay = config.replay.as_ref().map(Replay::open).transpose()?;
+        let mut sockets = match replay {
+            // Nothing is read from the socket, nor sent: keep it out of reach.
+            Some(_) => SocketManager::bind(&SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into()))?,
+            None => SocketManager::bind(&config.socket)?,
+        };
+        sockets.set_read_timeout(config.poll_timeout)?;
+        if let Some(queue) = config.receive_queue
+            && replay.is_none()
+        {
+            sockets.spawn_receive_thread(queue)?;
+        }
+        let wiretap = config
+            .wiretap
+            .as_ref()
+            .map(WiretapWriter::create)
+            .transpose()?;
+        let malformed =
+            MalformedLog::new(config.malformed_samples, config.malformed_logs_per_minute);
+        let policy = TrafficPolicy::new(config.limits.clone(), Instant::now());
+        let sends = SendGuard::new(config.backoff.clone());
+        let tokens = TokenCache::new(config.tokens.clone());
+        let answered = AnsweredQueries::new(config.duplicates, config.query_timeout);
+        let mut rng = SplitMix64::from_seed(config.seed);
+        // Replies to transaction ids guessed from a counter starting at 0 are easy to spoof.
+        let envelope = MessageEnvelope::new(config.message.clone(), rng.next_u32())
+            .with_extensions(config.extensions.clone());
+        #[cfg(feature = "chaos")]
+        let faults = FaultInjector::new(config.seed);
+        Ok(DhtNode {
+            config,
+            sockets,
+            receiver: Receiver::new(DEFAULT_BATCH_SIZE),
+            in_flight: InFlightQueries::new(),
+            answered,
+            envelope,
+            templates: None,
+            malformed,
+            received: ReceiveStats::default(),
+            policy,
+            sends,
+            tokens,
+            deadline: None,
+            wiretap,
+            replay,
+            rng,
+            nonconforming_warned_at: None,
+            nonconforming_suppressed: 0,
+            #[cfg(feature = "chaos")]
+            faults,
+        })
+    }
+
+    /// Get the id of the node.
+    pub fn id(&self) -> Id160 {
+        self.config.node_id
+    }
+
+    /// Change the id sent in the following queries.
+    ///
+    /// Lets a node present different ids to different parts of the keyspace, see
+    /// [`Honeypot`](crate::responder::Honeypot).
+    pub fn set_id(&mut self, id: Id160) {
+        self.config.node_id = id;
+        self.templates = None;
+    }
+
+    /// Get the address the node is bound to (the address of its first socket).
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets.sockets()[0].local_addr()
+    }
+
+    /// Get the socket settings applied on bind (to the first socket).
+    pub fn socket_report(&self) -> &SocketReport {
+        &self.sockets.reports()[0]
+    }
+
+    /// Get the socket settings applied on bind to every socket.
+    pub fn socket_reports(&self) -> &[SocketReport] {
+        self.sockets.reports()
+    }
+
+    /// Get the datagrams the node could not parse.
+    pub fn malformed(&self) -> &MalformedLog {
+        &self.malformed
+    }
+
+    /// Get the number of queries waiting for a reply.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Set the time by which every query of the node must be settled, e.g. to bound the
+    /// duration of an operation made of several queries.
+    ///
+    /// The queries in flight, and the following ones, time out at the deadline at the latest;
+    /// once it is reached, sending a query fails with [`io::ErrorKind::TimedOut`].
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        if let Some(deadline) = deadline {
+            self.in_flight.cap_deadline(deadline);
+        }
+    }
+
+    /// Get the deadline of the node, see [`DhtNode::set_deadline`].
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Send a `ping` query.
+    pub fn ping(&mut self, destination: SocketAddr) -> io::Result<()> {
+        // The templates are encoded once, without the extensions.
+        if !self.config.extensions.is_empty() {
+            let id = self.config.node_id;
+            return self.send_query(destination, QUERY_TYPE_PING, None, |tid| {
+                Query::new_ping(tid, id)
+            });
+        }
+        let transaction_id = self.new_transaction_id()?;
+        let template = &mut self.templates().ping;
+        template.set_transaction_id(&transaction_id);
+        let query = template.as_bytes().to_vec();
+        self.send_encoded_query(
+            destination,
+            transaction_id,
+            &query,
+            QUERY_TYPE_PING,
+            None,
+            Origin::Node,
+        )
+    }
+
+    /// Send a `find_node` query.
+    pub fn find_node(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        if !self.config.extensions.is_empty() {
+            let id = self.config.node_id;
+            return self.send_query(destination, QUERY_TYPE_FIND_NODE, Some(target), |tid| {
+                Query::new_find_node(tid, id, target)
+            });
+        }
+        let transaction_id = self.new_transaction_id()?;
+        let template = &mut self.templates().find_node;
+        template.set_transaction_id(&transaction_id);
+        template.set_target(target.as_bytes());
+        let query = template.as_bytes().to_vec();
+        self.send_encoded_query(
+            destination,
+            transaction_id,
+            &query,
+            QUERY_TYPE_FIND_NODE,
+            Some(target),
+            Origin::Node,
+        )
+    }
+
+    /// Send a `get_peers` query.
+    pub fn get_peers(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()> {
+        let id = self.config.node_id;
+        self.send_query(destination, QUERY_TYPE_GET_PEERS, Some(info_hash), |tid| {
+            Query::new_get_peers(tid, id, info_hash)
+        })
+    }
+
+    /// Send a `get_peers` query asking for the Bloom filters of the swarm (scrape, BEP 33).
+    ///
+    /// Nodes supporting BEP 33 reply with the filters, see
+    /// [`GetPeers::get_seeds_filter`](bitcrawler_proto::krpc::response::GetPeers::get_seeds_filter).
+    pub fn scrape(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()> {
+        let id = self.config.node_id;
+        self.send_query(destination, QUERY_TYPE_GET_PEERS, Some(info_hash), |tid| {
+            Query::new_scrape(tid, id, info_hash)
+        })
+    }
+
+    /// Send a `sample_infohashes` query (BEP 51).
+    ///
+    /// The reply is reported as a raw response, with the `samples` of the node if it supports
+    /// the query.
+    pub fn sample_infohashes(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        self.send_targeted(destination, QUERY_TYPE_SAMPLE_INFOHASHES, target)
+    }
+
+    /// Send a `get` query of the storage of arbitrary data (BEP 44).
+    ///
+    /// The reply is reported as a raw response, with a write `token` if the node supports the
+    /// storage.
+    pub fn get_item(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        self.send_targeted(destination, QUERY_TYPE_GET, target)
+    }
+
+    /// Send a query of method `query_type` with the node id and `target` as arguments.
+    fn send_targeted(
+        &mut self,
+        destination: SocketAddr,
+        query_type: &[u8],
+        target: Id160,
+    ) -> io::Result<()> {
+        let args: BencodeDict = vec![
+            (
+                "id".into(),
+                BencodeValue::ByteString(self.config.node_id.as_bytes().to_vec().into()),
+            ),
+            (
+                "target".into(),
+                BencodeValue::ByteString(target.as_bytes().to_vec().into()),
+            ),
+        ];
+        self.send_query(destination, query_type, Some(target), |tid| {
+            Query::custom(tid, query_type, args)
+        })
+    }
+
+    /// Send an `announce_peer` query to the node `node_id`, with the token it sent in its reply
+    /// to a previous `get_peers` query (see [`DhtNode::tokens`]).
+    ///
+    /// The peer is announced on `port`, or on the port the query is sent from if `None`
+    /// (`implied_port`). Fails with [`io::ErrorKind::NotFound`] if no valid token from the node
+    /// is known.
+    pub fn announce_peer(
+        &mut self,
+        destination: SocketAddr,
+        node_id: Id160,
+        info_hash: Id160,
+        port: Option<Port>,
+    ) -> io::Result<()> {
+        let token = self
+            .tokens
+            .announce_token(node_id, destination, Instant::now())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no token from this node"))?;
+        let id = self.config.node_id;
+        // The port is only a fallback with implied_port, some nodes want a valid one anyway.
+        let fallback_port = Port::new(self.local_addr()?.port())
+            .ok_or_else(|| io::Error::other("socket bound to port 0"))?;
+        self.send_query(
+            destination,
+            QUERY_TYPE_ANNOUNCE_PEER,
+            Some(info_hash),
+            |tid| match port {
+                Some(port) => Query::new_announce_peer(tid, id, info_hash, port, token),
+                None => {
+                    Query::new_announce_peer_implied_port(tid, id, info_hash, fallback_port, token)
+                }
+            },
+        )
+    }
+
+    /// Send a query with an arbitrary method name, e.g. a vendor extension.
+    ///
+    /// The node id is not added to `args`, it must be given like in every query (see
+    /// [`lint_message`](bitcrawler_proto::krpc::lint_message)): in debug builds, a query
+    /// breaking the protocol fails with [`io::ErrorKind::InvalidInput`]. The reply is reported
+    /// as a raw response.
+    pub fn custom_query(
+        &mut self,
+        destination: SocketAddr,
+        name: &[u8],
+        args: BencodeDict,
+    ) -> io::Result<()> {
+        let (transaction_id, query) = self.encode_query(|tid| Query::custom(tid, name, args))?;
+        self.send_encoded_query(
+            destination,
+            transaction_id,
+            &query,
+            name,
+            None,
+            Origin::Caller,
+        )
+    }
+
+    fn send_query<F>(
+        &mut self,
+        destination: SocketAddr,
+        query_type: &[u8],
+        target: Option<Id160>,
+        build: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(BencodeString) -> Query<Id160>,
+    {
+        let (transaction_id, query) = self.encode_query(build)?;
+        self.send_encoded_query(
+            destination,
+            transaction_id,
+            &query,
+            query_type,
+            target,
+            Origin::Node,
+        )
+    }
+
+    /// Build a query with a new transaction id, and encode it with the options of the node.
+    fn encode_query<F>(&mut self, build: F) -> io::Result<(Vec<u8>, Vec<u8>)>
+    where
+        F: FnOnce(BencodeString) -> Query<Id160>,
+    {
+        self.check_deadline()?;
+        let query = self.envelope.query(build);
+        let transaction_id = query.get_transaction_id().as_ref().to_vec();
+        Ok((
+            transaction_id,
+            bencode::encode(&self.envelope.seal(query.to_bencoded())),
+        ))
+    }
+
+    /// Get the transaction id of a new query, unless the deadline of the node is reached.
+    fn new_transaction_id(&mut self) -> io::Result<Vec<u8>> {
+        self.check_deadline()?;
+        Ok(self.envelope.next_transaction_id().to_vec())
+    }
+
+    /// Fail once the deadline of the node is reached, see [`DhtNode::set_deadline`].
+    fn check_deadline(&self) -> io::Result<()> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "deadline of the node reached",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the query templates of the node, built on first use.
+    fn templates(&mut self) -> &mut QueryTemplates {
+        let (id, options) = (self.config.node_id, self.envelope.options());
+        self.templates.get_or_insert_with(|| QueryTemplates {
+            ping: QueryTemplate::ping(id, ENVELOPE_TRANSACTION_ID_LEN, options),
+            find_node: QueryTemplate::find_node(id, ENVELOPE_TRANSACTION_ID_LEN, options),
+        })
+    }
+
+    /// Send an encoded query, and wait for its reply.
+    fn send_encoded_query(
+        &mut self,
+        destination: SocketAddr,
+        transaction_id: Vec<u8>,
+        query: &[u8],
+        query_type: &[u8],
+        target: Option<Id160>,
+        origin: Origin,
+    ) -> io::Result<()> {
+        self.lint_outgoing(query, destination, origin)?;
+        let now = Instant::now();
+        // Destinations backing off are skipped before being charged to the traffic limits.
+        self.sends.check(destination, now)?;
+        if self.policy.limits_in_flight() {
+            let to_destination = self.in_flight.to_destination(destination);
+            self.policy
+                .check_in_flight(self.in_flight.len(), to_destination)?;
+        }
+        self.policy.admit(destination, query.len(), true, now)?;
+        if self.replay.is_some() {
+            // Only the queries of the recording are waited for.
+            self.policy.record_sent(destination, query.len(), true);
+            return Ok(());
+        }
+        self.sends
+            .send(self.sockets.query_socket(), query, destination, now)?;
+        // A failed send is not charged to the query caps.
+        self.policy.record_sent(destination, query.len(), true);
+        if let Some(wiretap) = &mut self.wiretap {
+            wiretap.record(Direction::Sent, destination, query);
+        }
+        let timeout = now + self.config.query_timeout;
+        self.in_flight.insert(
+            transaction_id,
+            PendingQuery {
+                query_type: query_type.to_vec(),
+                destination,
+                target,
+                sent_at: now,
+                deadline: self
+                    .deadline
+                    .map_or(timeout, |deadline| deadline.min(timeout)),
+            },
+        );
+        Ok(())
+    }
+
+    /// Send a raw datagram (e.g. the reply to a query) from the node socket.
+    ///
+    /// With several sockets, the datagram is sent from the socket `destination` was last heard
+    /// on, so that replies come from the port the query was sent to.
+    ///
+    /// The datagram is accounted as a reply by the traffic limits. It must be a KRPC message
+    /// following the protocol (see [`lint_datagram`]): in debug builds, a nonconforming one
+    /// fails with [`io::ErrorKind::InvalidInput`].
+    pub fn send_to(&mut self, data: &[u8], destination: SocketAddr) -> io::Result<()> {
+        self.lint_outgoing(data, destination, Origin::Caller)?;
+        let now = Instant::now();
+        self.sends.check(destination, now)?;
+        self.policy.admit(destination, data.len(), false, now)?;
+        if self.replay.is_some() {
+            self.policy.record_sent(destination, data.len(), false);
+            return Ok(());
+        }
+        self.sends.send(
+            self.sockets.reply_socket(destination),
+            data,
+            destination,
+            now,
+        )?;
+        self.policy.record_sent(destination, data.len(), false);
+        if let Some(wiretap) = &mut self.wiretap {
+            wiretap.record(Direction::Sent, destination, data);
+        }
+        Ok(())
+    }
+
+    /// Check a message about to be sent with [`lint_datagram`].
+    ///
+    /// In debug builds (the tests), a message encoded by the node breaking the protocol is a
+    /// bug of the encoder and panics, one built by the caller is rejected. In release builds,
+    /// it is sent anyway, with a warning (with the `tracing` feature) at most once a minute.
+    fn lint_outgoing(
+        &mut self,
+        datagram: &[u8],
+        destination: SocketAddr,
+        origin: Origin,
+    ) -> io::Result<()> {
+        let Err(e) = lint_datagram(datagram) else {
+            return Ok(());
+        };
+        if cfg!(debug_assertions) {
+            let message = format!(
+                "Nonconforming KRPC message to {}: {} ({})",
+                destination,
+                e,
+                String::from_utf8_lossy(datagram)
+            );
+            match origin {
+                Origin::Node => panic!("{}", message),
+                Origin::Caller => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+                }
+            }
+        }
+        let now = Instant::now();
+        if self
+            .nonconforming_warned_at
+            .is_some_and(|at| now.saturating_duration_since(at) < NONCONFORMING_WARN_INTERVAL)
+        {
+            self.nonconforming_suppressed += 1;
+            return Ok(());
+        }
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            %destination,
+            error = e,
+            suppressed = self.nonconforming_suppressed,
+            "nonconforming KRPC message sent"
+        );
+        self.nonconforming_warned_at = Some(now);
+        self.nonconforming_suppressed = 0;
+        Ok(())
+    }
+
+    /// Set what is done with the duplicate replies from now on, e.g. after a configuration
+    /// reload.
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.config.duplicates = policy;
+        self.answered.set_policy(policy);
+    }
+
+    /// Enforce new traffic limits (caps, bandwidth, opt-out list), e.g. after a configuration
+    /// reload. The queries in flight are kept, and the traffic already sent counts toward the
+    /// new caps, see [`TrafficPolicy::set_limits`].
+    pub fn set_limits(&mut self, limits: TrafficLimits) {
+        self.config.limits = limits.clone();
+        self.policy.set_limits(limits);
+    }
+
+    /// Get the audit counters of the traffic limits.
+    pub fn traffic_audit(&self) -> &TrafficAudit {
+        self.policy.audit()
+    }
+
+    /// Get the counters of the datagrams received (or replayed).
+    pub fn receive_stats(&self) -> &ReceiveStats {
+        &self.received
+    }
+
+    /// Check if the query cap of the run is reached, no query can be sent anymore, or if the
+    /// recording the node replays is over.
+    pub fn is_exhausted(&self) -> bool {
+        self.policy.is_exhausted() || self.replay.as_ref().is_some_and(Replay::is_finished)
+    }
+
+    /// Flush the wiretap file of the node, see [`NodeConfig::wiretap`].
+    ///
+    /// Returns the first error met while recording, if any.
+    pub fn flush_wiretap(&mut self) -> io::Result<()> {
+        match &mut self.wiretap {
+            Some(wiretap) => wiretap.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Receive the available datagrams, and time out the queries left unanswered.
+    ///
+    /// Blocks up to the poll timeout waiting for a datagram, then appends the resulting events
+    /// to `events` and returns their number. Replies that do not match a pending query (or come
+    /// from another address than the one queried) are dropped, and counted (see
+    /// [`DhtNode::unmatched_replies`]): the duplicates of a recent reply are reported if the
+    /// [`DuplicatePolicy`] of the node says so. Malformed datagrams are dropped too, after being
+    /// recorded in the [`MalformedLog`] of the node.
+    ///
+    /// When replaying a recording, the datagrams come from the recording instead, at its pace
+    /// (or as fast as possible), and the queries of the recording time out with the wall clock.
+    pub fn poll(&mut self, events: &mut Vec<NodeEvent>) -> io::Result<usize> {
+        let before = events.len();
+        if self.replay.is_some() {
+            self.replay_datagrams(events)?;
+        } else {
+            self.receive_datagrams(events)?;
+        }
+
+        let now = Instant::now();
+        // Keep the tokens of the get_peers replies, announce_peer must present them.
+        for event in &events[before..] {
+            if let NodeEvent::Response {
+                query, response, ..
+            } = event
+                && let ResponseType::GetPeers(get_peers) = response.get_response_type()
+                && let Some(token) = get_peers.get_token()
+            {
+                self.tokens.insert(
+                    *get_peers.get_id(),
+                    query.destination,
+                    token.as_ref().into(),
+                    now,
+                );
+            }
+        }
+        for query in self.in_flight.expire(now) {
+            events.push(NodeEvent::Timeout { query });
+        }
+        self.answered.expire(now);
+        Ok(events.len() - before)
+    }
+
+    /// Receive the available datagrams from the sockets.
+    fn receive_datagrams(&mut self, events: &mut Vec<NodeEvent>) -> io::Result<()> {
+        let in_flight = &mut self.in_flight;
+        let answered = &mut self.answered;
+        let malformed = &mut self.malformed;
+        let received_stats = &mut self.received;
+        let wiretap = &mut self.wiretap;
+        let config = &self.config;
+        #[cfg(feature = "chaos")]
+        let faults = &mut self.faults;
+        let received = self.sockets.receive(&mut self.receiver, |data, source| {
+            received_stats.record(data.len());
+            if let Some(wiretap) = wiretap {
+                wiretap.record(Direction::Received, source, data);
+            }
+            #[cfg(feature = "chaos")]
+            faults.inject(data, source, Instant::now(), |data, source| {
+                handle_datagram(in_flight, answered, malformed, config, events, data, source)
+            });
+            #[cfg(not(feature = "chaos"))]
+            handle_datagram(in_flight, answered, malformed, config, events, data, source);
+        });
+        #[cfg(feature = "chaos")]
+        self.faults.release(Instant::now(), |data, source| {
+            handle_datagram(
+                &mut self.in_flight,
+                &mut self.answered,
+                &mut self.malformed,
+                &self.config,
+                events,
+                data,
+                source,
+            )
+        });
+        match received {
+            Ok(_) => Ok(()),
+            // Timeouts, and ICMP errors surfaced by IP_RECVERR, are not failures of the node.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::ConnectionRefused
+                        | io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Take the datagrams due from the recording replayed by the node.
+    fn replay_datagrams(&mut self, events: &mut Vec<NodeEvent>) -> io::Result<()> {
+        let Some(replay) = &mut self.replay else {
+            return Ok(());
+        };
+        if replay.is_finished() {
+            // Like an idle socket, rather than a busy loop for the caller.
+            thread::sleep(self.config.poll_timeout);
+            return Ok(());
+        }
+        let mut timeout = self.config.poll_timeout;
+        for _ in 0..DEFAULT_BATCH_SIZE {
+            let Some(record) = replay.next(timeout)? else {
+                break;
+            };
+            timeout = Duration::ZERO;
+            match record.direction {
+                Direction::Received => {
+                    self.received.record(record.data.len());
+                    handle_datagram(
+                        &mut self.in_flight,
+                        &mut self.answered,
+                        &mut self.malformed,
+                        &self.config,
+                        events,
+                        &record.data,
+                        record.peer,
+                    )
+                }
+                Direction::Sent => {
+                    // Our replies to other nodes are not waited for.
+                    let Some((transaction_id, query_type, target)) = recorded_query(&record.data)
+                    else {
+                        continue;
+                    };
+                    let now = Instant::now();
+                    let timeout = now + self.config.query_timeout;
+                    self.in_flight.insert(
+                        transaction_id,
+                        PendingQuery {
+                            query_type,
+                            destination: record.peer,
+                            target,
+                            sent_at: now,
+                            deadline: self
+                                .deadline
+                                .map_or(timeout, |deadline| deadline.min(timeout)),
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the number of datagrams dropped by the kernel, see
+    /// [`SocketManager::dropped_datagrams`].
+    pub fn dropped_datagrams(&self) -> io::Result<Option<u64>> {
+        self.sockets.dropped_datagrams()
+    }
+
+    /// Get the counters of the receive queue, if the node has one (see
+    /// [`NodeConfig::receive_queue`]).
+    pub fn receive_queue_stats(&self) -> Option<QueueStats> {
+        self.sockets.receive_queue_stats()
+    }
+
+    /// Get the heartbeat of the receive thread, if the node has one (see
+    /// [`SocketManager::receive_heartbeat`]).
+    pub fn receive_heartbeat(&self) -> Option<Heartbeat> {
+        self.sockets.receive_heartbeat()
+    }
+
+    /// Drain the ICMP errors reported for the socket, see
+    /// [`SocketManager::drain_socket_errors`].
+    ///
+    /// Each error is recorded as a failure of its destination, like a failed send.
+    pub fn drain_socket_errors(&mut self) -> io::Result<Vec<SocketError>> {
+        let errors = self.sockets.drain_socket_errors()?;
+        let now = Instant::now();
+        for error in &errors {
+            self.sends.record_failure(error.destination, now);
+        }
+        Ok(errors)
+    }
+
+    /// Get the random generator of the node, seeded by [`NodeConfig::seed`].
+    ///
+    /// The other random choices of an operation (e.g. its lookup targets, see
+    /// [`TargetGenerator::with_rng`](crate::keyspace::TargetGenerator::with_rng)) can be drawn
+    /// from it, so that the whole run is reproduced from the seed of the node.
+    pub fn rng(&mut self) -> &mut SplitMix64 {
+        &mut self.rng
+    }
+
+    /// Get the envelope of the messages of the node: the options of its queries, to add to the
+    /// replies and errors answering the queries of other nodes (see
+    /// [`MessageEnvelope::reply`]).
+    pub fn envelope(&self) -> &MessageEnvelope {
+        &self.envelope
+    }
+
+    /// Get the faults injected in the datagrams received by the node.
+    #[cfg(feature = "chaos")]
+    pub fn faults(&mut self) -> &mut FaultInjector {
+        &mut self.faults
+    }
+
+    /// Get the tokens received in the `get_peers` replies, by node.
+    pub fn tokens(&self) -> &TokenCache {
+        &self.tokens
+    }
+
+    /// Get the counters of the failed sends.
+    pub fn send_failures(&self) -> &SendFailureStats {
+        self.sends.stats()
+    }
+
+    /// Get the counters of the replies that matched no pending query, duplicates included.
+    pub fn unmatched_replies(&self) -> &UnmatchedReplies {
+        self.answered.stats()
+    }
+}
+
+/// Turn a received datagram into an event, or record it as malformed.
+fn handle_datagram(
+    in_flight: &mut InFlightQueries,
+    answered: &mut AnsweredQueries,
+    malformed: &mut MalformedLog,
+    config: &NodeConfig,
+    events: &mut Vec<NodeEvent>,
+    data: &[u8],
+    source: SocketAddr,
+) {
+    let (quirks, extensions) = (&config.quirks, &config.extensions);
+    match parse_datagram(in_flight, answered, quirks, extensions, data, source) {
+        Ok(Some(event)) => events.push(event),
+        Ok(None) => {}
+        Err(error) => {
+            malformed.record(source, data, error);
+        }
+    }
+}
+
+/// Get the transaction id, method and target of a query found in a recording.
+fn recorded_query(data: &[u8]) -> Option<(Vec<u8>, Vec<u8>, Option<Id160>)> {
+    let (_, message) = bencode::decode(&data).ok()?;
+    let query = Query::<Id160>::try_from_bencoded(&message).ok()?;
+    let target = match query.get_query() {
+        QueryType::FindNode(find_node) => Some(*find_node.get_target()),
+        QueryType::GetPeers(get_peers) => Some(*get_peers.get_info_hash()),
+        QueryType::AnnouncePeer(announce_peer) => Some(*announce_peer.get_info_hash()),
+        QueryType::Ping(_) | QueryType::Unknown { .. } => None,
+    };
+    Some((
+        query.get_transaction_id().as_ref().to_vec(),
+        query.get_query().get_query_type().to_vec(),
+        target,
+    ))
+}
+
+/// Turn a datagram into an event, matching replies with the pending queries.
+///
+/// Returns `Ok(None)` for the replies that do not match a pending query (apart from the
+/// duplicates reported, see [`DuplicatePolicy`]), and an error for the datagrams that cannot be
+/// parsed.
+///
+/// The header of the replies is peeked at first (see [`peek_header`]): the unsolicited ones are
+/// counted without being decoded, so they are not checked any further. The other messages are
+/// checked by the `extensions` once decoded, and the shims of their quirks applied.
+fn parse_datagram(
+    in_flight: &mut InFlightQueries,
+    answered: &mut AnsweredQueries,
+    quirks: &QuirkDatabase,
+    extensions: &MessageExtensions,
+    data: &[u8],
+    source: SocketAddr,
+) -> Result<Option<NodeEvent>, &'static str> {
+    if let Ok(header) = peek_header(data)
+        && header.kind != MessageKind::Query
+        && in_flight
+            .get(header.transaction_id)
+            .is_none_or(|query| query.destination != source)
+        && !answered.is_duplicate(header.transaction_id, source)
+    {
+        answered.count_unsolicited();
+        return Ok(None);
+    }
+    let (read, mut message) = bencode::decode_prefix(&data).map_err(|e| e.message())?;
+    if read != data.len() {
+        return Err("Trailing data");
+    }
+    extensions.parse(&message)?;
+    if !quirks.is_empty() {
+        let quirks = quirks.lookup_message(&message);
+        apply_shims(&mut message, quirks);
+    }
+    let message_type = match dict_value(&message, b"y") {
+        Some(BencodeValue::ByteString(message_type)) => message_type.as_ref().to_vec(),
+        Some(_) => return Err("Invalid 'y' field"),
+        None => return Err("Missing 'y' field"),
+    };
+    if message_type == b"q" {
+        let query = Query::try_from_bencoded(&message)?;
+        return Ok(Some(NodeEvent::Query { source, query }));
+    }
+
+    let transaction_id = match dict_value(&message, b"t") {
+        Some(BencodeValue::ByteString(transaction_id)) => transaction_id.as_ref(),
+        Some(_) => return Err("Invalid 't' field"),
+        None => return Err("Missing 't' field"),
+    };
+    let query_type = match in_flight.get(transaction_id) {
+        Some(query) if query.destination == source => query.query_type.as_slice(),
+        _ => return Ok(answered.unmatched(transaction_id, source, &message)),
+    };
+    match message_type.as_slice() {
+        b"r" => {
+            let response = match query_type {
+                QUERY_TYPE_PING => DhtResponse::try_from_ping_bencoded(&message),
+                QUERY_TYPE_FIND_NODE => DhtResponse::try_from_findpeer_bencoded(&message),
+                QUERY_TYPE_GET_PEERS => DhtResponse::try_from_getpeers_bencoded(&message),
+                // announce_peer replies only carry the node id, like ping ones.
+                QUERY_TYPE_ANNOUNCE_PEER => DhtResponse::try_from_ping_bencoded(&message),
+                _ => DhtResponse::try_from_raw_bencoded(&message),
+            }
+            // Keep the replies we do not understand rather than losing them.
+            .or_else(|_| DhtResponse::try_from_raw_bencoded(&message))?;
+            let query = in_flight
+                .remove(transaction_id)
+                .expect("pending query vanished");
+            let rtt = query.sent_at.elapsed();
+            answered.record(transaction_id, query.clone(), rtt);
+            Ok(Some(NodeEvent::Response {
+                query,
+                response,
+                rtt,
+            }))
+        }
+        b"e" => {
+            let error = ErrorMessage::try_from_bencoded(&message)?;
+            let query = in_flight
+                .remove(transaction_id)
+                .expect("pending query vanished");
+            answered.record(transaction_id, query.clone(), query.sent_at.elapsed());
+            Ok(Some(NodeEvent::Error { query, error }))
+        }
+        _ => Err("Invalid message type"),
+    }
+}
+
+/// Get the value of `key` in a bencoded dictionary.
+pub(crate) fn dict_value<'a>(value: &'a BencodeValue, key: &[u8]) -> Option<&'a BencodeValue> {
+    value.get(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcrawler_proto::{
+        bencode::BencodeDict,
+        krpc::{ErrorCode, MessageExtension, Quirks},
+    };
+
+    use super::*;
+    use crate::{
+        limits::{OptOutList, Refusal},
+        pipeline::OverflowPolicy,
+        testutil::FakeNode,
+    };
+
+    /// Longest wait for a datagram sent to a fake node.
+    const REMOTE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Get the configuration of a node bound to an ephemeral loopback port.
+    pub(crate) fn local_config(id: u8) -> NodeConfig {
+        let mut config = NodeConfig::new(Id160([id; 20]));
+        config.socket = SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        config.poll_timeout = Duration::from_millis(50);
+        config
+    }
+
+    pub(crate) fn local_node(id: u8) -> DhtNode {
+        DhtNode::bind(local_config(id)).unwrap()
+    }
+
+    fn poll_until(node: &mut DhtNode, count: usize) -> Vec<NodeEvent> {
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while events.len() < count && Instant::now() < deadline {
+            node.poll(&mut events).unwrap();
+        }
+        events
+    }
+
+    #[test]
+    fn test_query_and_reply() {
+        let mut a = local_node(1);
+        let mut b = local_node(2);
+        a.ping(b.local_addr().unwrap()).unwrap();
+        a.get_peers(b.local_addr().unwrap(), Id160([3; 20]))
+            .unwrap();
+        assert_eq!(a.in_flight(), 2);
+
+        for event in poll_until(&mut b, 2) {
+            let (source, query) = match event {
+                NodeEvent::Query { source, query } => (source, query),
+                event => panic!("unexpected event {:?}", event),
+            };
+            let tid = query.get_transaction_id().clone();
+            let reply = match query.get_query() {
+                QueryType::Ping(_) => DhtResponse::new_ping(tid, b.id()).to_bencoded(),
+                _ => {
+                    ErrorMessage::new(tid, ErrorCode::ServerError, "busy".to_string()).to_bencoded()
+                }
+            };
+            b.send_to(&bencode::encode(&reply), source).unwrap();
+        }
+
+        let events = poll_until(&mut a, 2);
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            NodeEvent::Response { query, .. } if query.query_type == QUERY_TYPE_PING
+        )));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            NodeEvent::Error { query, error }
+                if query.target == Some(Id160([3; 20])) && error.code == ErrorCode::ServerError
+        )));
+        assert_eq!(a.in_flight(), 0);
+    }
+
+    /// Tags every message sent, and rejects the messages received without the tag.
+    struct Tag;
+
+    impl MessageExtension for Tag {
+        fn on_encode(&self, message: &mut BencodeDict) {
+            message.push(("tag".into(), BencodeValue::Integer(1)));
+        }
+
+        fn on_parse(&self, message: &BencodeDict) -> Result<(), &'static str> {
+            match message.iter().any(|(key, _)| key.as_ref() == b"tag") {
+                true => Ok(()),
+                false => Err("Missing tag"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_extensions() {
+        let mut config = local_config(1);
+        config.extensions = MessageExtensions::new().with(Tag);
+        let mut a = DhtNode::bind(config).unwrap();
+        let remote = FakeNode::bind(Id160([2; 20])).unwrap();
+        a.ping(remote.address()).unwrap();
+        a.find_node(remote.address(), Id160([3; 20])).unwrap();
+
+        // The queries are tagged, and the tagged reply only is accepted.
+        for i in 0..2 {
+            let (message, source) = remote.receive_message(REMOTE_TIMEOUT).unwrap();
+            assert_eq!(
+                dict_value(&message, b"tag"),
+                Some(&BencodeValue::Integer(1))
+            );
+            let query = Query::<Id160>::try_from_bencoded(&message).unwrap();
+            let tid = query.get_transaction_id().clone();
+            let mut reply = DhtResponse::new_ping(tid, remote.id()).to_bencoded();
+            if i == 0 {
+                a.envelope().extensions().encode(&mut reply);
+            }
+            remote.send_to(&bencode::encode(&reply), source).unwrap();
+        }
+        let events = poll_until(&mut a, 1);
+        assert!(matches!(
+            &events[..],
+            [NodeEvent::Response { query, .. }] if query.query_type == QUERY_TYPE_PING
+        ));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while a.malformed().total() < 1 && Instant::now() < deadline {
+            a.poll(&mut Vec::new()).unwrap();
+        }
+        let samples: Vec<&MalformedSample> = a.malformed().samples().collect();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].error, "Missing tag");
+    }
+
+    #[test]
+    fn test_duplicate_replies() {
+        for policy in [DuplicatePolicy::Ignore, DuplicatePolicy::Report] {
+            let mut config = local_config(1);
+            config.duplicates = policy;
+            let mut a = DhtNode::bind(config).unwrap();
+            let remote = FakeNode::bind(Id160([2; 20])).unwrap();
+            a.ping(remote.address()).unwrap();
+
+            let (message, source) = remote.receive_message(REMOTE_TIMEOUT).unwrap();
+            let query = Query::<Id160>::try_from_bencoded(&message).unwrap();
+            let reply = DhtResponse::new_ping(query.get_transaction_id().clone(), Id160([2; 20]));
+            let reply = bencode::encode(&reply.to_bencoded());
+            // The node answers twice, and replies to queries we never sent: the header of the
+            // last one is enough to count it, its truncated body is not read.
+            remote.send_to(&reply, source).unwrap();
+            remote.send_to(&reply, source).unwrap();
+            let unsolicited = DhtResponse::new_ping("zz", Id160([2; 20]));
+            remote
+                .send_to(&bencode::encode(&unsolicited.to_bencoded()), source)
+                .unwrap();
+            remote.send_to(b"d1:t2:yy1:y1:r1:rd2:id", source).unwrap();
+
+            let deadline = Instant::now() + Duration::from_secs(2);
+            let mut events = Vec::new();
+            while a.unmatched_replies().unsolicited < 2 && Instant::now() < deadline {
+                a.poll(&mut events).unwrap();
+            }
+            assert_eq!(
+                *a.unmatched_replies(),
+                UnmatchedReplies {
+                    duplicates: 1,
+                    unsolicited: 2
+                }
+            );
+            let NodeEvent::Response { rtt: first, .. } = events[0] else {
+                panic!("unexpected event {:?}", events[0]);
+            };
+            match policy {
+                DuplicatePolicy::Ignore => assert_eq!(events.len(), 1),
+                _ => {
+                    assert_eq!(events.len(), 2);
+                    let NodeEvent::Duplicate { query, id, rtt } = &events[1] else {
+                        panic!("unexpected event {:?}", events[1]);
+                    };
+                    assert_eq!(query.query_type, QUERY_TYPE_PING);
+                    assert_eq!(*id, Some(Id160([2; 20])));
+                    assert_eq!(*rtt, first);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_query_timeout() {
+        let mut config = local_config(1);
+        config.poll_timeout = Duration::from_millis(10);
+        config.query_timeout = Duration::ZERO;
+        let mut node = DhtNode::bind(config).unwrap();
+        let silent = FakeNode::bind(Id160([2; 20])).unwrap();
+        node.ping(silent.address()).unwrap();
+
+        let events = poll_until(&mut node, 1);
+        assert!(matches!(&events[..], [NodeEvent::Timeout { .. }]));
+        assert_eq!(node.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_seeded_transaction_ids() {
+        let silent = FakeNode::bind(Id160([2; 20])).unwrap();
+        let mut transaction_ids = Vec::new();
+        for _ in 0..2 {
+            let mut config = local_config(1);
+            config.seed = Some(42);
+            let mut node = DhtNode::bind(config).unwrap();
+            node.ping(silent.address()).unwrap();
+            let (message, _) = silent.receive_message(REMOTE_TIMEOUT).unwrap();
+            transaction_ids.push(dict_value(&message, b"t").cloned());
+            // The node draws its next choices from the same sequence.
+            let mut expected = SplitMix64::with_seed(42);
+            expected.next_u64();
+            assert_eq!(node.rng().next_u64(), expected.next_u64());
+        }
+        assert!(transaction_ids[0].is_some());
+        assert_eq!(transaction_ids[0], transaction_ids[1]);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut node = local_node(1);
+        let silent = FakeNode::bind(Id160([2; 20])).unwrap();
+        let start = Instant::now();
+        node.ping(silent.address()).unwrap();
+        // The query timeout is 10s, the deadline cuts it short.
+        node.set_deadline(Some(start + Duration::from_millis(200)));
+        node.ping(silent.address()).unwrap();
+
+        let events = poll_until(&mut node, 2);
+        assert!(matches!(
+            &events[..],
+            [NodeEvent::Timeout { .. }, NodeEvent::Timeout { .. }]
+        ));
+        assert!(start.elapsed() < DEFAULT_QUERY_TIMEOUT);
+        let error = node.ping(silent.address()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(node.in_flight(), 0);
+
+        node.set_deadline(None);
+        node.ping(silent.address()).unwrap();
+    }
+
+    #[test]
+    fn test_nonconforming_messages_rejected() {
+        let mut a = local_node(1);
+        let destination = local_node(2).local_addr().unwrap();
+        // The caller forgot the node id.
+        let args: BencodeDict = vec![(
+            "target".into(),
+            BencodeValue::ByteString(vec![1; 20].into()),
+        )];
+        let error = a
+            .custom_query(destination, b"vendor_info", args)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(a.in_flight(), 0);
+
+        let reply = b"d1:rd5:nodes5:abcdee1:t2:aa1:y1:re";
+        let error = a.send_to(reply, destination).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(a.traffic_audit().datagrams_sent, 0);
+    }
+
+    #[test]
+    fn test_custom_query_and_raw_reply() {
+        let mut a = local_node(1);
+        let mut b = local_node(2);
+        let args: BencodeDict = vec![("id".into(), BencodeValue::ByteString(vec![1; 20].into()))];
+        a.custom_query(b.local_addr().unwrap(), b"vendor_info", args)
+            .unwrap();
+
+        let (source, query) = match poll_until(&mut b, 1).pop() {
+            Some(NodeEvent::Query { source, query }) => (source, query),
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(query.get_query().get_query_type(), b"vendor_info");
+        // The options of the node are attached to every query.
+        assert_eq!(
+            query.get_options().version.as_ref().map(|v| v.as_ref()),
+            Some(CLIENT_VERSION)
+        );
+        let reply = DhtResponse::custom(
+            query.get_transaction_id().clone(),
+            vec![
+                ("id".into(), BencodeValue::ByteString(vec![2; 20].into())),
+                ("v".into(), BencodeValue::ByteString("XX01".into())),
+            ],
+        );
+        b.send_to(&bencode::encode(&reply.to_bencoded()), source)
+            .unwrap();
+
+        match poll_until(&mut a, 1).pop() {
+            Some(NodeEvent::Response {
+                query, response, ..
+            }) => {
+                assert_eq!(query.query_type, b"vendor_info");
+                assert!(matches!(
+                    response.get_response_type(),
+                    ResponseType::Raw(args) if args[1].0.as_ref() == b"v"
+                ));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_sample_infohashes() {
+        let mut a = local_node(1);
+        let mut b = local_node(2);
+        let target = Id160([9; 20]);
+        a.sample_infohashes(b.local_addr().unwrap(), target)
+            .unwrap();
+
+        let (source, query) = match poll_until(&mut b, 1).pop() {
+            Some(NodeEvent::Query { source, query }) => (source, query),
+            event => panic!("unexpected event {:?}", event),
+        };
+        match query.get_query() {
+            QueryType::Unknown { name, args } => {
+                assert_eq!(name.as_ref(), QUERY_TYPE_SAMPLE_INFOHASHES);
+                assert!(args.iter().any(|(key, value)| key.as_ref() == b"target"
+                    && *value == BencodeValue::ByteString(vec![9; 20].into())));
+            }
+            query => panic!("unexpected query {:?}", query),
+        }
+        let reply = DhtResponse::custom(
+            query.get_transaction_id().clone(),
+            vec![
+                ("id".into(), BencodeValue::ByteString(vec![2; 20].into())),
+                (
+                    "samples".into(),
+                    BencodeValue::ByteString(vec![3; 20].into()),
+                ),
+            ],
+        );
+        b.send_to(&bencode::encode(&reply.to_bencoded()), source)
+            .unwrap();
+
+        match poll_until(&mut a, 1).pop() {
+            Some(NodeEvent::Response { query, .. }) => {
+                assert_eq!(query.query_type, QUERY_TYPE_SAMPLE_INFOHASHES);
+                assert_eq!(query.target, Some(target));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_malformed_datagrams() {
+        let mut node = local_node(1);
+        let sender = FakeNode::bind(Id160([2; 20])).unwrap();
+        sender
+            .send_to(b"d1:y1:q1:t2:aa", node.local_addr().unwrap())
+            .unwrap();
+        sender
+            .send_to(b"d1:t2:aa1:y1:re", node.local_addr().unwrap())
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut events = Vec::new();
+        while node.malformed().total() < 1 && Instant::now() < deadline {
+            node.poll(&mut events).unwrap();
+        }
+        // The reply does not match any pending query, it is not malformed.
+        node.poll(&mut events).unwrap();
+        assert!(events.is_empty());
+        let samples: Vec<&MalformedSample> = node.malformed().samples().collect();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].source, sender.address());
+        assert_eq!(samples[0].data, b"d1:y1:q1:t2:aa");
+    }
+
+    #[test]
+    fn test_quirks() {
+        let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+        let quirks = QuirkDatabase::new().with("XY", Quirks::PORT_AS_STRING);
+        let announce = |version: &str| {
+            format!(
+                "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:port4:6881\
+                 5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:v4:{}1:y1:qe",
+                version
+            )
+        };
+        let mut in_flight = InFlightQueries::new();
+        let mut answered = AnsweredQueries::new(DuplicatePolicy::Ignore, DEFAULT_QUERY_TIMEOUT);
+        let mut parse = |quirks: &QuirkDatabase, version: &str, in_flight: &mut InFlightQueries| {
+            let data = announce(version);
+            let extensions = MessageExtensions::new();
+            parse_datagram(
+                in_flight,
+                &mut answered,
+                quirks,
+                &extensions,
+                data.as_bytes(),
+                source,
+            )
+        };
+        assert!(matches!(
+            parse(&quirks, "XY01", &mut in_flight),
+            Ok(Some(NodeEvent::Query { .. }))
+        ));
+        // Only the client with the quirk gets the leniency.
+        assert!(parse(&quirks, "UT01", &mut in_flight).is_err());
+        assert!(parse(&QuirkDatabase::new(), "XY01", &mut in_flight).is_err());
+    }
+
+    #[test]
+    fn test_traffic_limits() {
+        let mut config = local_config(1);
+        config.limits.max_queries = Some(1);
+        config.limits.opt_out = OptOutList::new(vec!["192.0.2.0/24".parse().unwrap()]);
+        let mut node = DhtNode::bind(config).unwrap();
+        let destination = node.local_addr().unwrap();
+
+        let error = node
+            .ping((Ipv4Addr::new(192, 0, 2, 1), 6881).into())
+            .unwrap_err();
+        assert_eq!(
+            error.get_ref().and_then(|e| e.downcast_ref::<Refusal>()),
+            Some(&Refusal::OptedOut)
+        );
+        node.ping(destination).unwrap();
+        assert!(node.is_exhausted());
+        assert!(node.ping(destination).is_err());
+        assert_eq!(node.in_flight(), 1);
+        assert_eq!(node.traffic_audit().queries_sent, 1);
+        assert_eq!(node.traffic_audit().query_cap_reached, 1);
+    }
+
+    #[test]
+    fn test_in_flight_caps() {
+        let silent = FakeNode::bind(Id160([2; 20])).unwrap();
+        let other = FakeNode::bind(Id160([3; 20])).unwrap();
+        let mut config = local_config(1);
+        config.limits.max_in_flight = Some(3);
+        config.limits.max_in_flight_per_destination = Some(2);
+        let mut node = DhtNode::bind(config).unwrap();
+        let refusal = |error: io::Error| {
+            error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<Refusal>())
+                .copied()
+        };
+
+        node.ping(silent.address()).unwrap();
+        node.ping(silent.address()).unwrap();
+        let error = node.ping(silent.address()).unwrap_err();
+        assert_eq!(refusal(error), Some(Refusal::DestinationBusy));
+        node.ping(other.address()).unwrap();
+        let error = node.ping(other.address()).unwrap_err();
+        assert_eq!(refusal(error), Some(Refusal::InFlightCapReached));
+        assert_eq!(node.in_flight(), 3);
+        let audit = node.traffic_audit();
+        assert_eq!(
+            (
+                audit.queries_sent,
+                audit.destination_busy,
+                audit.in_flight_cap_reached
+            ),
+            (3, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_queries_spread_over_sockets() {
+        let mut config = local_config(1);
+        config.socket.sockets = 2;
+        config.poll_timeout = Duration::from_millis(50);
+        let mut a = DhtNode::bind(config).unwrap();
+        let mut b = local_node(2);
+        assert_eq!(a.socket_reports().len(), 2);
+
+        a.ping(b.local_addr().unwrap()).unwrap();
+        a.ping(b.local_addr().unwrap()).unwrap();
+        let mut sources = Vec::new();
+        for event in poll_until(&mut b, 2) {
+            let NodeEvent::Query { source, query } = event else {
+                panic!("unexpected event {:?}", event);
+            };
+            sources.push(source.port());
+            let reply = DhtResponse::new_ping(query.get_transaction_id().clone(), b.id());
+            b.send_to(&bencode::encode(&reply.to_bencoded()), source)
+                .unwrap();
+        }
+        sources.sort();
+        let mut ports: Vec<u16> = a
+            .socket_reports()
+            .iter()
+            .map(|report| report.local_address.port())
+            .collect();
+        ports.sort();
+        assert_eq!(sources, ports);
+
+        // The replies are received on both sockets.
+        let events = poll_until(&mut a, 2);
+        assert_eq!(events.len(), 2);
+        assert!(
+            events
+                .iter()
+                .all(|event| matches!(event, NodeEvent::Response { .. }))
+        );
+    }
+
+    #[test]
+    fn test_tokens_kept_for_announces() {
+        let mut a = local_node(1);
+        let mut b = local_node(2);
+        let b_address = b.local_addr().unwrap();
+        let info_hash = Id160([3; 20]);
+        let error = a
+            .announce_peer(b_address, b.id(), info_hash, None)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        a.get_peers(b_address, info_hash).unwrap();
+        let Some(NodeEvent::Query { source, query }) = poll_until(&mut b, 1).pop() else {
+            panic!("no query received");
+        };
+        let reply = DhtResponse::new_get_peers(
+            query.get_transaction_id().clone(),
+            b.id(),
+            Some(b"secret".to_vec().into()),
+            Vec::new(),
+            vec![SocketAddrV4::new([192, 0, 2, 1].into(), 6881)],
+        );
+        b.send_to(&bencode::encode(&reply.to_bencoded()), source)
+            .unwrap();
+        assert_eq!(poll_until(&mut a, 1).len(), 1);
+        let now = Instant::now();
+        assert!(
+            a.tokens()
+                .get(b.id(), b_address, now)
+                .is_some_and(|token| token == b"secret".as_slice())
+        );
+        // The token is only presented to the node that issued it.
+        assert!(a.tokens().get(Id160([9; 20]), b_address, now).is_none());
+
+        a.announce_peer(b_address, b.id(), info_hash, Port::new(6882))
+            .unwrap();
+        let Some(NodeEvent::Query { query, .. }) = poll_until(&mut b, 1).pop() else {
+            panic!("no announce received");
+        };
+        let QueryType::AnnouncePeer(announce) = query.get_query() else {
+            panic!("unexpected query {:?}", query);
+        };
+        assert_eq!(announce.get_token().as_ref(), b"secret");
+        assert_eq!(announce.get_port(), Port::new(6882));
+        assert_eq!(*announce.get_info_hash(), info_hash);
+    }
+
+    #[test]
+    fn test_receive_queue() {
+        let mut config = local_config(2);
+        config.receive_queue = Some(QueueConfig::new(2, OverflowPolicy::DropNewest));
+        let mut b = DhtNode::bind(config).unwrap();
+        let mut a = local_node(1);
+        for _ in 0..5 {
+            a.ping(b.local_addr().unwrap()).unwrap();
+        }
+
+        // The socket is drained while b is busy, what does not fit in the queue is counted.
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while b.receive_queue_stats().unwrap().pushed < 5 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let events = poll_until(&mut b, 2);
+        assert_eq!(events.len(), 2);
+        let stats = b.receive_queue_stats().unwrap();
+        assert_eq!((stats.pushed, stats.dropped, stats.depth), (5, 3, 0));
+
+        // Replies still go through the queue.
+        let NodeEvent::Query { source, query } = &events[0] else {
+            panic!("unexpected event {:?}", events[0]);
+        };
+        let reply = DhtResponse::new_ping(query.get_transaction_id().clone(), b.id());
+        b.send_to(&bencode::encode(&reply.to_bencoded()), *source)
+            .unwrap();
+        assert!(matches!(
+            poll_until(&mut a, 1)[..],
+            [NodeEvent::Response { .. }]
+        ));
+        assert!(local_node(3).receive_queue_stats().is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_unreachable_destination_backs_off() {
+        let mut node = local_node(1);
+        let closed = FakeNode::bind(Id160([2; 20])).unwrap();
+        let destination = closed.address();
+        drop(closed);
+
+        // The port unreachable comes back asynchronously, the send itself succeeds.
+        node.ping(destination).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(node.drain_socket_errors().unwrap().len(), 1);
+        let error = node.ping(destination).unwrap_err();
+        assert!(
+            error
+                .get_ref()
+                .is_some_and(|e| e.downcast_ref::<crate::transport::BackingOff>().is_some())
+        );
+        // The query was not charged to the traffic limits.
+        assert_eq!(node.traffic_audit().queries_sent, 1);
+        assert_eq!(node.send_failures().destination_failures, 1);
+        assert_eq!(node.send_failures().backed_off, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_send_is_not_counted() {
+        let mut node = local_node(1);
+        // Linux refuses to send to port 0.
+        let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        assert!(node.ping(destination).is_err());
+        let reply = DhtResponse::new_ping("aa", node.id());
+        let error = node
+            .send_to(&bencode::encode(&reply.to_bencoded()), destination)
+            .unwrap_err();
+        assert_ne!(error.kind(), io::ErrorKind::InvalidInput);
+        let audit = node.traffic_audit();
+        assert_eq!((audit.queries_sent, audit.datagrams_sent), (0, 0));
+        assert_eq!(node.send_failures().destination_failures, 1);
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::{DhtNode, NodeEvent};
+
+/// Settings of [`reachability_test`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReachabilityConfig {
+    /// Maximum number of nodes asked for our external address.
+    pub max_probes: usize,
+    /// Time to wait for the replies, and for unsolicited queries, after the probes are sent.
+    pub listen_duration: Duration,
+}
+
+impl Default for ReachabilityConfig {
+    fn default() -> Self {
+        ReachabilityConfig {
+            max_probes: 8,
+            listen_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Likely kind of NAT in front of the node, see [`ReachabilityReport::nat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NatKind {
+    /// Nodes we never contacted sent us queries: the node is reachable, a responder is useful.
+    Open,
+    /// Every node saw the same external address: the mapping does not depend on the
+    /// destination (cone NAT, or no NAT), but no unsolicited query was received yet, so inbound
+    /// traffic may be filtered.
+    Cone,
+    /// Nodes saw different external addresses: the mapping depends on the destination
+    /// (symmetric NAT), other nodes cannot reach the address they learn from us.
+    Symmetric,
+    /// Not enough nodes reported our address to conclude.
+    Unknown,
+}
+
+impl NatKind {
+    /// Classify the NAT from the external addresses reported by the probed nodes (with the
+    /// number of nodes reporting each), and the number of hosts that sent unsolicited queries.
+    pub fn classify(external_addresses: &BTreeMap<SocketAddr, usize>, unsolicited: usize) -> Self {
+        let reports: usize = external_addresses.values().sum();
+        if unsolicited > 0 {
+            NatKind::Open
+        } else if external_addresses.len() > 1 {
+            NatKind::Symmetric
+        } else if reports >= 2 {
+            NatKind::Cone
+        } else {
+            NatKind::Unknown
+        }
+    }
+}
+
+/// Result of [`reachability_test`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReachabilityReport {
+    /// Address the node is bound to.
+    pub local_address: SocketAddr,
+    /// Number of nodes probed.
+    pub probed: usize,
+    /// Number of probed nodes that answered.
+    pub answered: usize,
+    /// External addresses reported by the nodes that answered (BEP 42 `ip` field), with the
+    /// number of nodes reporting each.
+    pub external_addresses: BTreeMap<SocketAddr, usize>,
+    /// Number of hosts we never contacted that sent us queries.
+    pub unsolicited_sources: usize,
+    /// Likely kind of NAT.
+    pub nat: NatKind,
+}
+
+impl ReachabilityReport {
+    /// Check if the external port (as seen by the other nodes) is the local port.
+    ///
+    /// `None` if no node reported our address.
+    pub fn port_preserved(&self) -> Option<bool> {
+        let port = self.local_address.port();
+        (!self.external_addresses.is_empty()).then(|| {
+            self.external_addresses
+                .keys()
+                .all(|address| address.port() == port)
+        })
+    }
+
+    /// Check if answering the queries of other nodes (responder mode) is worth it.
+    pub fn responder_worthwhile(&self) -> bool {
+        matches!(self.nat, NatKind::Open | NatKind::Cone)
+    }
+}
+
+/// Check how the node is reachable from the DHT.
+///
+/// Pings up to [`ReachabilityConfig::max_probes`] of `contacts`, collects the external address
+/// every node reports in its reply (BEP 42), then listens for queries from hosts that were not
+/// contacted. The node events received during the test are consumed.
+pub fn reachability_test(
+    node: &mut DhtNode,
+    contacts: &[SocketAddr],
+    config: &ReachabilityConfig,
+) -> io::Result<ReachabilityReport> {
+    let local_address = node.local_addr()?;
+    let mut probes = HashSet::new();
+    for contact in contacts {
+        if probes.len() >= config.max_probes {
+            break;
+        }
+        if !probes.contains(contact) && node.ping(*contact).is_ok() {
+            probes.insert(*contact);
+        }
+    }
+    let contacted: HashSet<_> = probes
+        .iter()
+        .map(|probe| probe.ip().to_canonical())
+        .collect();
+
+    let mut answered = 0;
+    let mut external_addresses = BTreeMap::new();
+    let mut unsolicited = HashSet::new();
+    let mut events = Vec::new();
+    let deadline = Instant::now() + config.listen_duration;
+    while Instant::now() < deadline {
+        node.poll(&mut events)?;
+        for event in events.drain(..) {
+            match event {
+                NodeEvent::Response { response, .. } => {
+                    answered += 1;
+                    if let Some(requester) = response.get_requester() {
+                        *external_addresses
+                            .entry(SocketAddr::V4(*requester))
+                            .or_default() += 1;
+                    }
+                }
+                NodeEvent::Query { source, .. } => {
+                    let host = source.ip().to_canonical();
+                    if !contacted.contains(&host) {
+                        unsolicited.insert(host);
+                    }
+                }
+                NodeEvent::Error { .. }
+                | NodeEvent::Timeout { .. }
+                | NodeEvent::Duplicate { .. } => {}
+            }
+        }
+    }
+
+    Ok(ReachabilityReport {
+        local_address,
+        probed: probes.len(),
+        answered,
+        nat: NatKind::classify(&external_addresses, unsolicited.len()),
+        external_addresses,
+        unsolicited_sources: unsolicited.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use bitcrawler_proto::{kademlia::Id160, krpc::QueryType};
+
+    use super::*;
+    use crate::{
+        node::{DhtResponse, tests::local_config},
+        testutil::{FakeNode, ServingNode},
+    };
+
+    /// Answer the pings, reporting `external` as the address of the requester.
+    fn probe_target(external: SocketAddrV4) -> ServingNode {
+        let node = FakeNode::bind(Id160([9; 20])).unwrap();
+        let id = node.id();
+        node.serve(move |query| {
+            assert!(matches!(query.get_query(), QueryType::Ping(_)));
+            let reply = DhtResponse::new_ping(query.get_transaction_id().clone(), id)
+                .with_requester(external);
+            Some(reply.to_bencoded())
+        })
+    }
+
+    #[test]
+    fn test_reachability_test() {
+        let external = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 40000);
+        let a = probe_target(external);
+        let b = probe_target(external);
+
+        let mut config = local_config(1);
+        config.poll_timeout = Duration::from_millis(10);
+        let mut node = DhtNode::bind(config).unwrap();
+        let report = reachability_test(
+            &mut node,
+            &[a.address(), b.address(), a.address()],
+            &ReachabilityConfig {
+                max_probes: 8,
+                listen_duration: Duration::from_millis(500),
+            },
+        )
+        .unwrap();
+        // Each target is probed once.
+        assert_eq!(a.stop(), 1);
+        assert_eq!(b.stop(), 1);
+
+        assert_eq!(report.probed, 2);
+        assert_eq!(report.answered, 2);
+        assert_eq!(report.external_addresses.get(&external.into()), Some(&2));
+        assert_eq!(report.unsolicited_sources, 0);
+        assert_eq!(report.nat, NatKind::Cone);
+        assert!(report.responder_worthwhile());
+        assert_eq!(report.port_preserved(), Some(false));
+    }
+
+    #[test]
+    fn test_classify_nat() {
+        let a: SocketAddr = (Ipv4Addr::new(203, 0, 113, 7), 40000).into();
+        let b: SocketAddr = (Ipv4Addr::new(203, 0, 113, 7), 40001).into();
+        let consistent = [(a, 3)].into_iter().collect();
+        let varying = [(a, 2), (b, 1)].into_iter().collect();
+        assert_eq!(NatKind::classify(&consistent, 0), NatKind::Cone);
+        assert_eq!(NatKind::classify(&varying, 0), NatKind::Symmetric);
+        assert_eq!(NatKind::classify(&varying, 2), NatKind::Open);
+        assert_eq!(
+            NatKind::classify(&[(a, 1)].into_iter().collect(), 0),
+            NatKind::Unknown
+        );
+    }
+}
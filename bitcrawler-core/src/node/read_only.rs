@@ -0,0 +1,242 @@
+use std::{io, net::SocketAddr, time::Instant};
+
+use bitcrawler_proto::kademlia::Id160;
+
+use super::{DhtNode, MalformedLog, NodeConfig, NodeEvent, ReceiveStats};
+use crate::limits::TrafficAudit;
+
+/// The queries a node can send without taking part in the DHT: the read side shared by
+/// [`DhtNode`] and [`ReadOnlyNode`], e.g. to run a lookup with either (see
+/// [`lookup_peers`](super::lookup_peers)).
+///
+/// `announce_peer` and custom queries are left out: they may store something on the other
+/// nodes.
+pub trait QueryNode {
+    /// Get the id of the node.
+    fn id(&self) -> Id160;
+
+    /// Get the number of queries waiting for their reply.
+    fn in_flight(&self) -> usize;
+
+    /// Set the time by which every query must be settled, see [`DhtNode::set_deadline`].
+    fn set_deadline(&mut self, deadline: Option<Instant>);
+
+    /// Get the deadline of the node, see [`DhtNode::set_deadline`].
+    fn deadline(&self) -> Option<Instant>;
+
+    /// Send a `ping` query.
+    fn ping(&mut self, destination: SocketAddr) -> io::Result<()>;
+
+    /// Send a `find_node` query.
+    fn find_node(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()>;
+
+    /// Send a `get_peers` query.
+    fn get_peers(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()>;
+
+    /// Send a `get_peers` query asking for the Bloom filters of the swarm (BEP 33).
+    fn scrape(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()>;
+
+    /// Send a `sample_infohashes` query (BEP 51).
+    fn sample_infohashes(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()>;
+
+    /// Send a `get` query of the storage of arbitrary data (BEP 44).
+    fn get_item(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()>;
+
+    /// Receive the available datagrams, and time out the queries left unanswered, see
+    /// [`DhtNode::poll`].
+    fn poll(&mut self, events: &mut Vec<NodeEvent>) -> io::Result<usize>;
+}
+
+impl QueryNode for DhtNode {
+    fn id(&self) -> Id160 {
+        DhtNode::id(self)
+    }
+
+    fn in_flight(&self) -> usize {
+        DhtNode::in_flight(self)
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        DhtNode::set_deadline(self, deadline);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        DhtNode::deadline(self)
+    }
+
+    fn ping(&mut self, destination: SocketAddr) -> io::Result<()> {
+        DhtNode::ping(self, destination)
+    }
+
+    fn find_node(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        DhtNode::find_node(self, destination, target)
+    }
+
+    fn get_peers(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()> {
+        DhtNode::get_peers(self, destination, info_hash)
+    }
+
+    fn scrape(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()> {
+        DhtNode::scrape(self, destination, info_hash)
+    }
+
+    fn sample_infohashes(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        DhtNode::sample_infohashes(self, destination, target)
+    }
+
+    fn get_item(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        DhtNode::get_item(self, destination, target)
+    }
+
+    fn poll(&mut self, events: &mut Vec<NodeEvent>) -> io::Result<usize> {
+        DhtNode::poll(self, events)
+    }
+}
+
+/// A node that only observes the DHT: it sends the queries of [`QueryNode`], and nothing else.
+///
+/// Unlike a [`DhtNode`], it has no way to send a datagram that is not one of these queries:
+/// the queries of other nodes are reported by [`QueryNode::poll`] but cannot be answered, and
+/// no `announce_peer` (nor custom query) can be sent. The tokens of the `get_peers` replies
+/// are not kept, and the node cannot be handed to a responder (which holds the peers
+/// announced to it in a `PeerStore`): the inner [`DhtNode`] is not reachable. Its queries are
+/// flagged read-only (BEP 43), so that the other nodes do not add it to their routing table.
+///
+/// The passive behavior of a crawl built on it is thus checked by the compiler, e.g. for an
+/// ethics review, rather than by the configuration.
+pub struct ReadOnlyNode {
+    node: DhtNode,
+}
+
+impl ReadOnlyNode {
+    /// Bind the socket(s) of the node.
+    ///
+    /// The read-only flag of `config` is set, and no token is kept (its token capacity is set
+    /// to 0), whatever `config` says.
+    pub fn bind(mut config: NodeConfig) -> io::Result<ReadOnlyNode> {
+        config.message.read_only = true;
+        config.tokens.capacity = 0;
+        Ok(ReadOnlyNode {
+            node: DhtNode::bind(config)?,
+        })
+    }
+
+    /// Get the local address of the (first) socket of the node.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.node.local_addr()
+    }
+
+    /// Check if no query can be sent anymore, see [`DhtNode::is_exhausted`].
+    pub fn is_exhausted(&self) -> bool {
+        self.node.is_exhausted()
+    }
+
+    /// Get the audit counters of the traffic limits.
+    pub fn traffic_audit(&self) -> &TrafficAudit {
+        self.node.traffic_audit()
+    }
+
+    /// Get the counters of the datagrams received (or replayed).
+    pub fn receive_stats(&self) -> &ReceiveStats {
+        self.node.receive_stats()
+    }
+
+    /// Get the malformed datagrams received by the node.
+    pub fn malformed(&self) -> &MalformedLog {
+        self.node.malformed()
+    }
+
+    /// Flush the wiretap file of the node, see [`NodeConfig::wiretap`].
+    pub fn flush_wiretap(&mut self) -> io::Result<()> {
+        self.node.flush_wiretap()
+    }
+}
+
+impl QueryNode for ReadOnlyNode {
+    fn id(&self) -> Id160 {
+        self.node.id()
+    }
+
+    fn in_flight(&self) -> usize {
+        self.node.in_flight()
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.node.set_deadline(deadline);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.node.deadline()
+    }
+
+    fn ping(&mut self, destination: SocketAddr) -> io::Result<()> {
+        self.node.ping(destination)
+    }
+
+    fn find_node(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        self.node.find_node(destination, target)
+    }
+
+    fn get_peers(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()> {
+        self.node.get_peers(destination, info_hash)
+    }
+
+    fn scrape(&mut self, destination: SocketAddr, info_hash: Id160) -> io::Result<()> {
+        self.node.scrape(destination, info_hash)
+    }
+
+    fn sample_infohashes(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        self.node.sample_infohashes(destination, target)
+    }
+
+    fn get_item(&mut self, destination: SocketAddr, target: Id160) -> io::Result<()> {
+        self.node.get_item(destination, target)
+    }
+
+    fn poll(&mut self, events: &mut Vec<NodeEvent>) -> io::Result<usize> {
+        self.node.poll(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bitcrawler_proto::{bencode, krpc::Query};
+
+    use super::*;
+    use crate::{node::tests::local_config, testutil::FakeNode};
+
+    #[test]
+    fn test_read_only_node() {
+        let mut config = local_config(1);
+        config.poll_timeout = Duration::from_millis(10);
+        config.message.read_only = false;
+        let mut node = ReadOnlyNode::bind(config).unwrap();
+        let remote = FakeNode::bind(Id160([2; 20])).unwrap();
+
+        // The queries are flagged read-only, whatever the configuration says.
+        node.ping(remote.address()).unwrap();
+        let (message, source) = remote.receive_message(Duration::from_millis(500)).unwrap();
+        let query = Query::<Id160>::try_from_bencoded(&message).unwrap();
+        assert!(query.get_options().read_only);
+        assert_eq!(node.in_flight(), 1);
+
+        // The queries of the other nodes are reported, and left unanswered.
+        let ping = Query::new_ping(b"aa".to_vec(), remote.id());
+        remote
+            .send_to(&bencode::encode(&ping.to_bencoded()), source)
+            .unwrap();
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while events.is_empty() && Instant::now() < deadline {
+            node.poll(&mut events).unwrap();
+        }
+        assert!(matches!(
+            &events[..],
+            [NodeEvent::Query { source, .. }] if *source == remote.address()
+        ));
+        assert!(remote.recv_from(Duration::from_millis(500)).is_err());
+        assert_eq!(node.receive_stats().datagrams, 1);
+    }
+}
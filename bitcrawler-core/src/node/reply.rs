@@ -0,0 +1,119 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+};
+
+use bitcrawler_proto::{kademlia::Id160, krpc::node_info::BittorrentNodeInfoV4};
+
+use crate::bogon::BogonFilter;
+
+/// Check if `ip` is a bogon: an address no node of the DHT can be reached at from the
+/// internet (unspecified, private, shared, loopback, link-local, documentation, benchmarking,
+/// multicast or reserved), in the built-in table of [`BogonFilter`]. IPv4-mapped addresses are
+/// checked as IPv4 ones.
+pub fn is_bogon(ip: IpAddr) -> bool {
+    BogonFilter::builtin().is_bogon(ip)
+}
+
+/// Get the nodes of a reply worth contacting, in their order in the reply.
+///
+/// Drops the nodes that cannot be contacted (port 0), our own id (`own_id`), the id of the
+/// node that sent the reply (`sender_id`, from `source`), the nodes given more than once
+/// (same id and address), and the bogon addresses (see [`is_bogon`]). Bogons are kept when
+/// the sender is itself on one, e.g. a node of a local network or of a test.
+pub fn reply_nodes(
+    nodes: &[BittorrentNodeInfoV4<Id160>],
+    own_id: Id160,
+    sender_id: Id160,
+    source: SocketAddr,
+) -> Vec<(Id160, SocketAddr)> {
+    let local_sender = is_bogon(source.ip());
+    let mut seen = HashSet::new();
+    nodes
+        .iter()
+        .filter(|node| node.node_id != own_id && node.node_id != sender_id)
+        .filter_map(|node| {
+            Some((
+                node.node_id,
+                SocketAddr::from((node.ip, node.get_port()?.get())),
+            ))
+        })
+        .filter(|(_, address)| local_sender || !is_bogon(address.ip()))
+        .filter(|node| seen.insert(*node))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_bogon() {
+        for ip in [
+            "0.1.2.3",
+            "10.0.0.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "172.31.255.255",
+            "192.0.0.8",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.19.0.1",
+            "203.0.113.7",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:10.0.0.1",
+            "fc00::1",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+        ] {
+            assert!(is_bogon(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "172.32.0.1",
+            "198.20.0.1",
+            "::ffff:8.8.8.8",
+            "2a01:4f8::1",
+        ] {
+            assert!(!is_bogon(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_reply_nodes() {
+        let node = |id: u8, ip: [u8; 4], port| BittorrentNodeInfoV4 {
+            node_id: Id160([id; 20]),
+            ip,
+            port,
+        };
+        let (own_id, sender_id) = (Id160([0; 20]), Id160([9; 20]));
+        let nodes = [
+            node(1, [1, 2, 3, 4], 6881),
+            node(0, [1, 2, 3, 5], 6881),
+            node(9, [1, 2, 3, 6], 6881),
+            node(2, [1, 2, 3, 7], 0),
+            node(1, [1, 2, 3, 4], 6881),
+            // Same id, another address: kept.
+            node(1, [1, 2, 3, 8], 6881),
+            node(3, [192, 168, 0, 2], 6881),
+        ];
+        let public: SocketAddr = "8.8.8.8:6881".parse().unwrap();
+        let expected = vec![
+            (Id160([1; 20]), "1.2.3.4:6881".parse().unwrap()),
+            (Id160([1; 20]), "1.2.3.8:6881".parse().unwrap()),
+        ];
+        assert_eq!(reply_nodes(&nodes, own_id, sender_id, public), expected);
+
+        // A sender of the local network may return its neighbors.
+        let local: SocketAddr = "192.168.0.1:6881".parse().unwrap();
+        let nodes = reply_nodes(&nodes, own_id, sender_id, local);
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[2].1, "192.168.0.2:6881".parse().unwrap());
+    }
+}
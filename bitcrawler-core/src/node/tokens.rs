@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{consts::TOKEN_MAX_AGE, kademlia::Id160, krpc::query::AnnounceToken};
+
+use crate::secret::Token;
+
+/// Default time a token received from a node is kept.
+///
+/// BEP 5 recommends accepting tokens up to 10 minutes old, but the issuer may have rotated its
+/// secret once already when it sent the token, so only half of that is safe.
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(TOKEN_MAX_AGE.as_secs() / 2);
+
+/// Settings of a [`TokenCache`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenCacheConfig {
+    /// Time after which a token is considered expired.
+    pub lifetime: Duration,
+    /// Maximum number of tokens kept. Past it, the oldest token is evicted.
+    pub capacity: usize,
+}
+
+impl Default for TokenCacheConfig {
+    fn default() -> Self {
+        TokenCacheConfig {
+            lifetime: DEFAULT_TOKEN_LIFETIME,
+            capacity: 1 << 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: Token,
+    received_at: Instant,
+}
+
+/// Tokens received in `get_peers` replies, to present in the following `announce_peer` queries.
+///
+/// A token is only valid for the node that issued it, from the address it was issued to, so
+/// the tokens are keyed by node id and address: a node that changed address (or another node
+/// behind the same address) does not get the token.
+#[derive(Debug, Clone)]
+pub struct TokenCache {
+    config: TokenCacheConfig,
+    tokens: HashMap<(Id160, SocketAddr), CachedToken>,
+}
+
+impl TokenCache {
+    /// Create an empty cache.
+    pub fn new(config: TokenCacheConfig) -> TokenCache {
+        TokenCache {
+            config,
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Record the token sent by the node `id` at `address`, replacing the previous one.
+    pub fn insert(&mut self, id: Id160, address: SocketAddr, token: Token, now: Instant) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        if self.tokens.len() >= self.config.capacity && !self.tokens.contains_key(&(id, address)) {
+            self.purge(now);
+            if self.tokens.len() >= self.config.capacity
+                && let Some(oldest) = self
+                    .tokens
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.received_at)
+                    .map(|(key, _)| *key)
+            {
+                self.tokens.remove(&oldest);
+            }
+        }
+        self.tokens.insert(
+            (id, address),
+            CachedToken {
+                token,
+                received_at: now,
+            },
+        );
+    }
+
+    /// Get the token of the node `id` at `address`, if it did not expire.
+    pub fn get(&self, id: Id160, address: SocketAddr, now: Instant) -> Option<&Token> {
+        self.tokens
+            .get(&(id, address))
+            .filter(|cached| !self.is_expired(cached, now))
+            .map(|cached| &cached.token)
+    }
+
+    /// Get the token of the node `id` at `address` to announce to it, if it did not expire.
+    pub fn announce_token(
+        &self,
+        id: Id160,
+        address: SocketAddr,
+        now: Instant,
+    ) -> Option<AnnounceToken> {
+        self.get(id, address, now)
+            .map(|token| AnnounceToken::from_raw(token.as_bytes()))
+    }
+
+    /// Forget the token of the node `id` at `address`, e.g. once it was rejected.
+    pub fn remove(&mut self, id: Id160, address: SocketAddr) -> Option<Token> {
+        self.tokens
+            .remove(&(id, address))
+            .map(|cached| cached.token)
+    }
+
+    /// Drop the expired tokens.
+    pub fn purge(&mut self, now: Instant) {
+        let lifetime = self.config.lifetime;
+        self.tokens
+            .retain(|_, cached| now.saturating_duration_since(cached.received_at) < lifetime);
+    }
+
+    /// Get the number of tokens kept, including the expired ones not purged yet.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Check if no token is kept.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    fn is_expired(&self, cached: &CachedToken, now: Instant) -> bool {
+        now.saturating_duration_since(cached.received_at) >= self.config.lifetime
+    }
+}
+
+impl Default for TokenCache {
+    fn default() -> Self {
+        TokenCache::new(TokenCacheConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_keyed_by_node_and_address() {
+        let start = Instant::now();
+        let mut cache = TokenCache::new(TokenCacheConfig {
+            lifetime: Duration::from_secs(60),
+            capacity: 2,
+        });
+        let a: SocketAddr = ([192, 0, 2, 1], 6881).into();
+        let b: SocketAddr = ([192, 0, 2, 2], 6881).into();
+        cache.insert(Id160([1; 20]), a, b"one".to_vec().into(), start);
+        assert_eq!(
+            cache.get(Id160([1; 20]), a, start),
+            Some(&b"one".to_vec().into())
+        );
+        // Same node from another address, another node from the same address.
+        assert_eq!(cache.get(Id160([1; 20]), b, start), None);
+        assert_eq!(cache.get(Id160([2; 20]), a, start), None);
+
+        let later = start + Duration::from_secs(30);
+        cache.insert(Id160([2; 20]), b, b"two".to_vec().into(), later);
+        // Full: the oldest token makes room.
+        cache.insert(Id160([3; 20]), b, b"three".to_vec().into(), later);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(Id160([1; 20]), a, later), None);
+
+        // Expired tokens are not returned, then purged.
+        let expired = later + Duration::from_secs(60);
+        assert_eq!(cache.get(Id160([2; 20]), b, expired), None);
+        cache.purge(expired);
+        assert!(cache.is_empty());
+    }
+}
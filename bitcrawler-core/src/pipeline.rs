@@ -0,0 +1,437 @@
+//! Bounded queues between the stages of a crawl: the receive loop, the decoding of the
+//! datagrams, and the sinks.
+//!
+//! Without them, a stage that falls behind (a slow sink, a burst of datagrams to decode) stalls
+//! the stages before it, up to the socket receive buffer, which then overflows silently. With a
+//! queue, the overflow is handled by an explicit [`OverflowPolicy`], and counted in the
+//! [`QueueStats`] of the queue, to tune its capacity.
+//!
+//! A queue may also have a memory budget, for items of very different sizes such as the
+//! datagrams.
+//!
+//! See [`NodeConfig::receive_queue`](crate::node::NodeConfig::receive_queue) and
+//! [`QueuedSink`](crate::sink::QueuedSink).
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// What a full queue does with a new item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverflowPolicy {
+    /// Wait for the consumer to make room: nothing is lost, the producer goes at the pace of the
+    /// consumer.
+    #[default]
+    Block,
+    /// Drop the new item.
+    DropNewest,
+    /// Drop the oldest queued item to make room for the new one.
+    DropOldest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = &'static str;
+
+    /// Parse `block`, `drop-newest` or `drop-oldest`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            _ => Err("Invalid overflow policy"),
+        }
+    }
+}
+
+/// Settings of a [`BoundedQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Maximum number of items queued.
+    pub capacity: usize,
+    /// What happens to the items pushed while the queue is full.
+    pub policy: OverflowPolicy,
+    /// Maximum memory of the queued items, in bytes as measured by the queue (see
+    /// [`BoundedQueue::with_size`]): the queue is also full once a new item would go past it.
+    /// An item larger than the budget is still queued in an empty queue.
+    pub memory_budget: Option<usize>,
+}
+
+impl QueueConfig {
+    /// Create the settings of a queue of `capacity` items (at least one).
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> QueueConfig {
+        QueueConfig {
+            capacity: capacity.max(1),
+            policy,
+            memory_budget: None,
+        }
+    }
+
+    /// Set the memory budget of the queue, see [`QueueConfig::memory_budget`].
+    pub fn with_memory_budget(mut self, bytes: usize) -> QueueConfig {
+        self.memory_budget = Some(bytes);
+        self
+    }
+}
+
+/// Counters of a [`BoundedQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueueStats {
+    /// Maximum number of items queued.
+    pub capacity: usize,
+    /// Number of items currently queued.
+    pub depth: usize,
+    /// Highest number of items queued so far.
+    pub max_depth: usize,
+    /// Items pushed, including the dropped ones.
+    pub pushed: u64,
+    /// Items dropped because the queue was full.
+    pub dropped: u64,
+    /// Times a producer waited for room, with [`OverflowPolicy::Block`].
+    pub blocked: u64,
+    /// Memory of the items currently queued, as measured by the queue.
+    pub bytes: usize,
+    /// Highest memory of the items queued so far.
+    pub max_bytes: usize,
+}
+
+struct Queued<T> {
+    item: T,
+    // Whether the item was forced in (see `BoundedQueue::force_push`).
+    forced: bool,
+    size: usize,
+}
+
+struct State<T> {
+    items: VecDeque<Queued<T>>,
+    closed: bool,
+    stats: QueueStats,
+}
+
+struct Inner<T> {
+    policy: OverflowPolicy,
+    memory_budget: Option<usize>,
+    size: fn(&T) -> usize,
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// A bounded multi-producer multi-consumer queue, shared by cloning it.
+///
+/// Once closed (by either side), pushes are refused and pops return the items left, then
+/// `None`.
+pub struct BoundedQueue<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for BoundedQueue<T> {
+    fn clone(&self) -> Self {
+        BoundedQueue {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for BoundedQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoundedQueue")
+            .field("policy", &self.inner.policy)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl<T> BoundedQueue<T> {
+    /// Create an empty queue, whose items take no memory: its memory budget is not checked.
+    pub fn new(config: QueueConfig) -> BoundedQueue<T> {
+        BoundedQueue::with_size(config, |_| 0)
+    }
+
+    /// Create an empty queue whose items take `size` bytes each, counted against the memory
+    /// budget of `config`, e.g. the capacity of datagrams.
+    pub fn with_size(config: QueueConfig, size: fn(&T) -> usize) -> BoundedQueue<T> {
+        let capacity = config.capacity.max(1);
+        BoundedQueue {
+            inner: Arc::new(Inner {
+                policy: config.policy,
+                memory_budget: config.memory_budget,
+                size,
+                state: Mutex::new(State {
+                    items: VecDeque::with_capacity(capacity),
+                    closed: false,
+                    stats: QueueStats {
+                        capacity,
+                        ..QueueStats::default()
+                    },
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.inner.state.lock().expect("queue lock poisoned")
+    }
+
+    /// Check if an item of `size` bytes does not fit in the queue.
+    fn is_full(&self, state: &State<T>, size: usize) -> bool {
+        state.items.len() >= state.stats.capacity
+            || self
+                .inner
+                .memory_budget
+                .is_some_and(|budget| !state.items.is_empty() && state.stats.bytes + size > budget)
+    }
+
+    /// Push an item, applying the overflow policy if the queue is full.
+    ///
+    /// Returns `false` if the queue is closed, the item is then dropped (without being counted).
+    pub fn push(&self, item: T) -> bool {
+        let size = (self.inner.size)(&item);
+        let mut state = self.lock();
+        if state.closed {
+            return false;
+        }
+        state.stats.pushed += 1;
+        if self.is_full(&state, size) {
+            match self.inner.policy {
+                OverflowPolicy::Block => {
+                    state.stats.blocked += 1;
+                    while !state.closed && self.is_full(&state, size) {
+                        state = self
+                            .inner
+                            .not_full
+                            .wait(state)
+                            .expect("queue lock poisoned");
+                    }
+                    if state.closed {
+                        return false;
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    state.stats.dropped += 1;
+                    return true;
+                }
+                OverflowPolicy::DropOldest => {
+                    // A large item may take the room of several old ones.
+                    while self.is_full(&state, size) {
+                        state.stats.dropped += 1;
+                        match state.items.iter().position(|queued| !queued.forced) {
+                            Some(oldest) => {
+                                let oldest = state.items.remove(oldest).expect("item vanished");
+                                state.stats.bytes -= oldest.size;
+                            }
+                            // Only forced items are queued, drop the new one instead.
+                            None => return true,
+                        }
+                    }
+                }
+            }
+        }
+        self.push_locked(&mut state, item, false, size);
+        true
+    }
+
+    /// Push an item past the capacity, e.g. a control message that must not be dropped: it is
+    /// not dropped by [`OverflowPolicy::DropOldest`] either, nor counted as pushed.
+    ///
+    /// Returns `false` if the queue is closed.
+    #[cfg_attr(not(feature = "crawler"), allow(dead_code))]
+    pub(crate) fn force_push(&self, item: T) -> bool {
+        let size = (self.inner.size)(&item);
+        let mut state = self.lock();
+        if state.closed {
+            return false;
+        }
+        self.push_locked(&mut state, item, true, size);
+        true
+    }
+
+    fn push_locked(&self, state: &mut State<T>, item: T, forced: bool, size: usize) {
+        state.items.push_back(Queued { item, forced, size });
+        state.stats.max_depth = state.stats.max_depth.max(state.items.len());
+        state.stats.bytes += size;
+        state.stats.max_bytes = state.stats.max_bytes.max(state.stats.bytes);
+        self.inner.not_empty.notify_one();
+    }
+
+    fn pop_locked(&self, state: &mut State<T>) -> Option<T> {
+        let queued = state.items.pop_front()?;
+        state.stats.bytes -= queued.size;
+        self.inner.not_full.notify_one();
+        Some(queued.item)
+    }
+
+    /// Pop the oldest item, waiting up to `timeout` for one (forever if `None`).
+    ///
+    /// Returns `None` on timeout, or once the queue is closed and empty.
+    pub fn pop(&self, timeout: Option<Duration>) -> Option<T> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.lock();
+        loop {
+            if let Some(item) = self.pop_locked(&mut state) {
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return None;
+                    }
+                    self.inner
+                        .not_empty
+                        .wait_timeout(state, left)
+                        .expect("queue lock poisoned")
+                        .0
+                }
+                None => self
+                    .inner
+                    .not_empty
+                    .wait(state)
+                    .expect("queue lock poisoned"),
+            };
+        }
+    }
+
+    /// Pop the oldest item if there is one, without waiting.
+    pub fn try_pop(&self) -> Option<T> {
+        self.pop_locked(&mut self.lock())
+    }
+
+    /// Close the queue: the following pushes are refused, and the producers waiting for room
+    /// and the consumers waiting for an item are released.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.inner.not_empty.notify_all();
+        self.inner.not_full.notify_all();
+    }
+
+    /// Check if the queue is closed.
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    /// Get the counters of the queue.
+    pub fn stats(&self) -> QueueStats {
+        let state = self.lock();
+        QueueStats {
+            depth: state.items.len(),
+            ..state.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_drop_policies() {
+        let queue = BoundedQueue::new(QueueConfig::new(2, OverflowPolicy::DropNewest));
+        for i in 0..4 {
+            assert!(queue.push(i));
+        }
+        assert_eq!(queue.try_pop(), Some(0));
+        assert_eq!(queue.try_pop(), Some(1));
+        assert_eq!(queue.try_pop(), None);
+        let stats = queue.stats();
+        assert_eq!((stats.pushed, stats.dropped, stats.max_depth), (4, 2, 2));
+
+        let queue = BoundedQueue::new(QueueConfig::new(2, OverflowPolicy::DropOldest));
+        for i in 0..4 {
+            assert!(queue.push(i));
+        }
+        assert_eq!(queue.stats().depth, 2);
+        assert_eq!(queue.try_pop(), Some(2));
+        assert_eq!(queue.try_pop(), Some(3));
+        assert_eq!(queue.stats().dropped, 2);
+
+        // Forced items go past the capacity, and are never dropped.
+        assert!(queue.force_push(4));
+        assert!(queue.force_push(5));
+        assert!(queue.force_push(6));
+        assert!(queue.push(7));
+        assert_eq!(queue.try_pop(), Some(4));
+        assert_eq!(queue.try_pop(), Some(5));
+        assert!(queue.push(8));
+        assert!(queue.push(9));
+        let items: Vec<_> = std::iter::from_fn(|| queue.try_pop()).collect();
+        assert_eq!(items, vec![6, 9]);
+        assert_eq!(queue.stats().dropped, 4);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let small = vec![0u8; 40];
+        let large = vec![0u8; 70];
+        let config = QueueConfig::new(8, OverflowPolicy::DropOldest)
+            .with_memory_budget(2 * small.len() + 10);
+        let queue = BoundedQueue::with_size(config, Vec::len);
+        assert!(queue.push(small.clone()));
+        assert!(queue.push(small.clone()));
+        assert_eq!(queue.stats().bytes, 2 * small.len());
+
+        // The large message takes the room of both small ones, alone it fits.
+        assert!(queue.push(large.clone()));
+        let stats = queue.stats();
+        assert_eq!((stats.depth, stats.dropped), (1, 2));
+        assert_eq!(stats.bytes, large.len());
+        assert_eq!(stats.max_bytes, 2 * small.len());
+        assert!(queue.push(small.clone()));
+        assert_eq!(queue.stats().dropped, 3);
+        assert_eq!(queue.try_pop(), Some(small));
+        assert_eq!(queue.stats().bytes, 0);
+
+        // Without a size function, the budget is not checked.
+        let queue = BoundedQueue::new(config);
+        assert!(queue.push(large.clone()));
+        assert!(queue.push(large));
+        assert_eq!(queue.stats().dropped, 0);
+    }
+
+    #[test]
+    fn test_block_and_close() {
+        let queue = BoundedQueue::new(QueueConfig::new(1, OverflowPolicy::Block));
+        assert!(queue.push(0));
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || (1..3).map(|i| queue.push(i)).collect::<Vec<_>>())
+        };
+        // The queue is full, the producer waits for room.
+        while queue.stats().blocked == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut items = Vec::new();
+        while items.len() < 3 {
+            items.extend(queue.pop(Some(Duration::from_secs(5))));
+        }
+        assert_eq!(producer.join().unwrap(), vec![true; 2]);
+        assert_eq!(items, vec![0, 1, 2]);
+        assert_eq!(queue.stats().dropped, 0);
+        assert_eq!(queue.pop(Some(Duration::from_millis(10))), None);
+
+        // Closing releases a blocked producer, and the queued items can still be read.
+        queue.push(3);
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.push(4))
+        };
+        thread::sleep(Duration::from_millis(20));
+        queue.close();
+        assert!(!producer.join().unwrap());
+        assert!(!queue.push(5));
+        assert_eq!(queue.pop(None), Some(3));
+        assert_eq!(queue.pop(None), None);
+    }
+}
@@ -0,0 +1,79 @@
+//! Rate limiting of the traffic sent by a node.
+
+use std::time::Instant;
+
+/// A token bucket: allows `rate` events per second on average, with bursts of up to `burst`
+/// events.
+///
+/// The bucket starts full.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a bucket refilled with `rate` tokens per second, holding up to `burst` tokens.
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        TokenBucket {
+            rate: rate.max(0.0),
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token if one is available at `now`.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.try_take_n(1, now)
+    }
+
+    /// Take `count` tokens if they are all available at `now`, e.g. one token per byte sent.
+    pub fn try_take_n(&mut self, count: u32, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= count as f64 {
+            self.tokens -= count as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the number of whole tokens available at `now`.
+    pub fn available(&mut self, now: Instant) -> u32 {
+        self.refill(now);
+        self.tokens as u32
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.last_refill = self.last_refill.max(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(10.0, 2);
+        let start = bucket.last_refill;
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+
+        // 10 tokens per second, one token every 100ms.
+        assert!(bucket.try_take(start + Duration::from_millis(100)));
+        assert!(!bucket.try_take(start + Duration::from_millis(150)));
+        // Capped to the burst size.
+        assert_eq!(bucket.available(start + Duration::from_secs(60)), 2);
+        assert!(!bucket.try_take_n(3, start + Duration::from_secs(60)));
+        assert!(bucket.try_take_n(2, start + Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,721 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{
+    bencode::{self, BencodeValue},
+    consts::K,
+    kademlia::Id160,
+    krpc::{
+        ErrorCode, PortPolicy, Query, QueryType, ResponseType, node_info::BittorrentNodeInfoV4,
+    },
+};
+
+use super::{
+    DEFAULT_MAX_REPLY_SIZE, GetPeersPolicy, InboundQueryConfig, InboundQueryReport,
+    InboundQueryStats, QueryKind, ReplyShaper, Tokens,
+};
+use crate::{
+    crawler::DEFAULT_BOOTSTRAP_NODES,
+    node::{DhtNode, DhtResponse, NodeConfig, NodeEvent, is_bogon, reply_nodes},
+    ratelimit::TokenBucket,
+    sink::{CrawlEvent, Sink},
+};
+
+/// Default number of leading bits shared by the advertised ids and their target.
+///
+/// With a few million nodes in the DHT, 32 common bits are enough to be among the closest nodes
+/// of the target.
+pub const DEFAULT_COMMON_BITS: u32 = 32;
+
+/// Number of nodes returned by `find_node` and `get_peers` replies.
+const NODES_PER_REPLY: usize = K;
+/// Number of recently seen nodes kept to answer `find_node` and `get_peers` queries.
+const KNOWN_NODES: usize = 1024;
+/// Number of remote nodes whose advertised id is remembered, to answer their pings.
+const ADVERTISED_IDS: usize = 4096;
+/// Interval between two flushes of the sinks.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Configuration of a [`Honeypot`].
+#[derive(Debug, Clone)]
+pub struct HoneypotConfig {
+    /// Node the honeypot answers from. Its id is used for the nodes that did not see any
+    /// advertised id.
+    pub node: NodeConfig,
+    /// Info hashes to attract the announces of.
+    pub targets: Vec<Id160>,
+    /// Number of leading bits an advertised id shares with its target.
+    pub common_bits: u32,
+    /// Nodes (`host:port`) contacted when no other node is known.
+    pub bootstrap_nodes: Vec<String>,
+    /// Average number of datagrams (queries and replies) sent per second.
+    pub send_rate: f64,
+    /// Maximum number of datagrams sent in a burst.
+    pub send_burst: u32,
+    /// Interval between two rounds of `find_node` queries toward the targets.
+    pub advertise_interval: Duration,
+    /// Number of nodes queried per target and round.
+    pub queries_per_target: usize,
+    /// Ports of the announced peers reported, see [`HoneypotStats::invalid_ports`].
+    pub peer_ports: PortPolicy,
+    /// Grouping of the queries received by source prefix, see
+    /// [`HoneypotHandle::inbound_queries`].
+    pub inbound_queries: InboundQueryConfig,
+    /// Fields of the `get_peers` replies. The honeypot knows no peer: with
+    /// [`GetPeersPolicy::Both`] the nodes come with an empty `values`.
+    pub get_peers: GetPeersPolicy,
+}
+
+impl HoneypotConfig {
+    /// Create a configuration with the default settings for the given node id and targets.
+    ///
+    /// The honeypot listens on 0.0.0.0:6881 and sends at most 20 datagrams per second.
+    pub fn new(node_id: Id160, targets: Vec<Id160>) -> HoneypotConfig {
+        HoneypotConfig {
+            node: NodeConfig::new(node_id),
+            targets,
+            common_bits: DEFAULT_COMMON_BITS,
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            send_rate: 20.0,
+            send_burst: 40,
+            advertise_interval: Duration::from_secs(60),
+            queries_per_target: 8,
+            peer_ports: PortPolicy::default(),
+            inbound_queries: InboundQueryConfig::default(),
+            get_peers: GetPeersPolicy::default(),
+        }
+    }
+}
+
+/// Counters of a [`Honeypot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HoneypotStats {
+    /// Queries received from other nodes.
+    pub queries_received: u64,
+    /// `get_peers` queries received.
+    pub peers_requested: u64,
+    /// `announce_peer` queries received with a valid token.
+    pub announces: u64,
+    /// `announce_peer` queries rejected because of their token.
+    pub invalid_tokens: u64,
+    /// `announce_peer` queries with a valid token, not reported because the port of the peer
+    /// is refused by [`HoneypotConfig::peer_ports`].
+    pub invalid_ports: u64,
+    /// Replies sent.
+    pub replies_sent: u64,
+    /// Queries sent.
+    pub queries_sent: u64,
+    /// Replies and queries not sent because of the rate limit.
+    pub rate_limited: u64,
+}
+
+/// State shared by a [`Honeypot`] and its [`HoneypotHandle`]s.
+struct Shared {
+    running: AtomicBool,
+    stats: Mutex<HoneypotStats>,
+    inbound_queries: Mutex<InboundQueryReport>,
+}
+
+/// A cloneable handle to observe and stop a running [`Honeypot`].
+#[derive(Clone)]
+pub struct HoneypotHandle {
+    shared: Arc<Shared>,
+}
+
+impl HoneypotHandle {
+    /// Get the counters of the honeypot.
+    pub fn stats(&self) -> HoneypotStats {
+        *self
+            .shared
+            .stats
+            .lock()
+            .expect("honeypot stats lock poisoned")
+    }
+
+    /// Get the queries received over the last complete window, per kind and source prefix.
+    pub fn inbound_queries(&self) -> InboundQueryReport {
+        self.shared
+            .inbound_queries
+            .lock()
+            .expect("honeypot stats lock poisoned")
+            .clone()
+    }
+
+    /// Ask the honeypot to stop, [`Honeypot::run`] returns shortly after.
+    pub fn stop(&self) {
+        self.shared.running.store(false, Ordering::Relaxed);
+    }
+
+    /// Check if the honeypot has not been asked to stop.
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::Relaxed)
+    }
+}
+
+/// A responder attracting the announces of a set of info hashes, for measurement studies.
+///
+/// The honeypot presents ids close to its targets: it queries the nodes close to each target
+/// with an id sharing [`HoneypotConfig::common_bits`] bits with it, and answers the queries
+/// about a target with the matching id, so that it ends up in the routing tables of the nodes
+/// around the target. It answers `get_peers` queries with a token and the closest nodes it
+/// knows, never with peers, then reports the `get_peers` and the valid `announce_peer` queries
+/// it receives to its [`Sink`]s.
+///
+/// Everything the honeypot sends (replies included) goes through a single token bucket, so its
+/// traffic never exceeds [`HoneypotConfig::send_rate`]. Queries are left unanswered past it.
+pub struct Honeypot {
+    node: DhtNode,
+    config: HoneypotConfig,
+    bucket: TokenBucket,
+    tokens: Tokens,
+    shaper: ReplyShaper,
+    // Recently seen nodes, most recent last.
+    known: VecDeque<BittorrentNodeInfoV4<Id160>>,
+    // Id last presented to each remote node.
+    advertised: HashMap<SocketAddr, Id160>,
+    // Keys of `advertised`, oldest first.
+    advertised_order: VecDeque<SocketAddr>,
+    stats: HoneypotStats,
+    inbound_queries: InboundQueryStats,
+    sinks: Vec<Box<dyn Sink>>,
+    shared: Arc<Shared>,
+}
+
+impl Honeypot {
+    /// Bind the socket of the honeypot.
+    pub fn bind(config: HoneypotConfig) -> io::Result<Honeypot> {
+        let node = DhtNode::bind(config.node.clone())?;
+        Ok(Honeypot {
+            node,
+            bucket: TokenBucket::new(config.send_rate, config.send_burst),
+            tokens: Tokens::new(Instant::now()),
+            shaper: ReplyShaper::new(DEFAULT_MAX_REPLY_SIZE, config.node.seed)
+                .with_policy(config.get_peers),
+            inbound_queries: InboundQueryStats::new(config.inbound_queries.clone(), Instant::now()),
+            config,
+            known: VecDeque::new(),
+            advertised: HashMap::new(),
+            advertised_order: VecDeque::new(),
+            stats: HoneypotStats::default(),
+            sinks: Vec::new(),
+            shared: Arc::new(Shared {
+                running: AtomicBool::new(true),
+                stats: Mutex::new(HoneypotStats::default()),
+                inbound_queries: Mutex::new(InboundQueryReport::default()),
+            }),
+        })
+    }
+
+    /// Get the address the honeypot is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.node.local_addr()
+    }
+
+    /// Add a sink receiving what the honeypot observes.
+    pub fn add_sink<S: Sink + 'static>(&mut self, sink: S) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Get a handle to observe and stop the honeypot.
+    pub fn handle(&self) -> HoneypotHandle {
+        HoneypotHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Run the honeypot on a new thread, named `bitcrawler-honeypot`.
+    pub fn spawn(mut self) -> (HoneypotHandle, JoinHandle<io::Result<()>>) {
+        let handle = self.handle();
+        let thread = thread::Builder::new()
+            .name("bitcrawler-honeypot".to_string())
+            .spawn(move || self.run())
+            .expect("failed to spawn the honeypot thread");
+        (handle, thread)
+    }
+
+    /// Get the id advertised to the nodes interested in `target`.
+    ///
+    /// The id shares its first bits with the closest configured target, the other bits are the
+    /// ones of the node id.
+    pub fn id_for(&self, target: &Id160) -> Id160 {
+        let base = self.config.node.node_id;
+        self.config
+            .targets
+            .iter()
+            .min_by_key(|candidate| candidate.distance(target))
+            .map_or(base, |closest| {
+                base.with_prefix(closest, self.config.common_bits)
+            })
+    }
+
+    /// Answer queries until stopped through a [`HoneypotHandle`].
+    ///
+    /// Returns an error if the socket or a sink fails. The sinks are flushed periodically and
+    /// before returning.
+    pub fn run(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("honeypot").entered();
+        let result = self.serve();
+        let flushed = self.flush();
+        self.publish();
+        result.and(flushed)
+    }
+
+    fn serve(&mut self) -> io::Result<()> {
+        let mut events = Vec::new();
+        let mut last_advertise = Instant::now();
+        let mut last_flush = Instant::now();
+        self.advertise();
+        while self.shared.running.load(Ordering::Relaxed) {
+            self.node.poll(&mut events)?;
+            for event in events.drain(..) {
+                self.handle_event(event)?;
+            }
+
+            if last_advertise.elapsed() >= self.config.advertise_interval {
+                last_advertise = Instant::now();
+                self.advertise();
+            }
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                last_flush = Instant::now();
+                self.flush()?;
+            }
+            self.publish();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, event: CrawlEvent) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.handle(&event)?;
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self) {
+        *self
+            .shared
+            .stats
+            .lock()
+            .expect("honeypot stats lock poisoned") = self.stats;
+        let report = self.inbound_queries.report(Instant::now()).clone();
+        *self
+            .shared
+            .inbound_queries
+            .lock()
+            .expect("honeypot stats lock poisoned") = report;
+    }
+
+    /// Query the nodes closest to each target, presenting the id advertised for it.
+    fn advertise(&mut self) {
+        let bootstrap: Vec<SocketAddr> = self
+            .config
+            .bootstrap_nodes
+            .iter()
+            .filter_map(|node| node.to_socket_addrs().ok())
+            .flatten()
+            .collect();
+        for target in self.config.targets.clone() {
+            let mut destinations: Vec<SocketAddr> = self
+                .closest_known(&target, self.config.queries_per_target)
+                .iter()
+                .map(|node| SocketAddr::from((node.ip, node.port)))
+                .collect();
+            if destinations.is_empty() {
+                destinations = bootstrap.clone();
+            }
+            for destination in destinations {
+                self.find_node(destination, target);
+            }
+        }
+    }
+
+    /// Send a `find_node` query toward `target`, if the rate limit allows it.
+    fn find_node(&mut self, destination: SocketAddr, target: Id160) {
+        if !self.bucket.try_take(Instant::now()) {
+            self.stats.rate_limited += 1;
+            return;
+        }
+        let id = self.id_for(&target);
+        self.node.set_id(id);
+        if self.node.find_node(destination, target).is_ok() {
+            self.stats.queries_sent += 1;
+            self.remember(destination, id);
+        }
+        self.node.set_id(self.config.node.node_id);
+    }
+
+    fn handle_event(&mut self, event: NodeEvent) -> io::Result<()> {
+        match event {
+            NodeEvent::Query { source, query } => self.handle_query(source, query),
+            NodeEvent::Response {
+                query, response, ..
+            } => {
+                let (sender_id, nodes) = match response.get_response_type() {
+                    ResponseType::FindNode(find_node) => {
+                        (find_node.get_id(), find_node.get_nodes())
+                    }
+                    ResponseType::GetPeers(get_peers) => {
+                        (get_peers.get_id(), get_peers.get_nodes())
+                    }
+                    _ => return Ok(()),
+                };
+                self.learn(*sender_id, query.destination);
+                let target = match query.target {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+                // Walk toward the target: follow the nodes closer to it than the sender.
+                let sender_distance = sender_id.distance(&target);
+                let own_id = self.config.node.node_id;
+                for (id, address) in reply_nodes(nodes, own_id, *sender_id, query.destination) {
+                    let closer = id.distance(&target) < sender_distance;
+                    if closer && !self.advertised.contains_key(&address) {
+                        self.find_node(address, target);
+                    }
+                }
+                Ok(())
+            }
+            NodeEvent::Error { .. } | NodeEvent::Timeout { .. } | NodeEvent::Duplicate { .. } => {
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_query(&mut self, source: SocketAddr, query: Query<Id160>) -> io::Result<()> {
+        self.stats.queries_received += 1;
+        let now = Instant::now();
+        self.inbound_queries
+            .record(source.ip(), QueryKind::of(query.get_query()), now);
+        let reply = match query.get_query() {
+            QueryType::Ping(ping) => {
+                self.learn(*ping.get_id(), source);
+                let id = self
+                    .advertised
+                    .get(&source)
+                    .copied()
+                    .unwrap_or(self.config.node.node_id);
+                let reply = self
+                    .node
+                    .envelope()
+                    .reply(&query, |tid| DhtResponse::new_ping(tid, id));
+                (id, reply)
+            }
+            QueryType::FindNode(find_node) => {
+                self.learn(*find_node.get_id(), source);
+                let id = self.id_for(find_node.get_target());
+                let nodes = self.nodes_for(find_node.get_target(), source);
+                let reply = self
+                    .node
+                    .envelope()
+                    .reply(&query, |tid| DhtResponse::new_find_node(tid, id, nodes));
+                (id, reply)
+            }
+            QueryType::GetPeers(get_peers) => {
+                self.learn(*get_peers.get_id(), source);
+                let info_hash = *get_peers.get_info_hash();
+                self.stats.peers_requested += 1;
+                self.emit(CrawlEvent::PeersRequested { info_hash, source })?;
+                let id = self.id_for(&info_hash);
+                let token = self.tokens.issue(source.ip(), now);
+                let nodes = self.nodes_for(&info_hash, source);
+                // Never return peers: the honeypot only observes.
+                let shaper = &mut self.shaper;
+                let reply = self.node.envelope().reply(&query, |tid| {
+                    shaper.get_peers(tid, id, Some(token.as_bytes().into()), nodes, &[])
+                });
+                (id, reply)
+            }
+            QueryType::AnnouncePeer(announce) => {
+                let info_hash = *announce.get_info_hash();
+                let id = self.id_for(&info_hash);
+                if self
+                    .tokens
+                    .verify(source.ip(), announce.get_token().as_ref(), now)
+                {
+                    self.learn(*announce.get_id(), source);
+                    self.stats.announces += 1;
+                    match announce
+                        .peer_port(source.port())
+                        .and_then(|port| self.config.peer_ports.check(port.get()))
+                    {
+                        Some(port) => self.emit(CrawlEvent::PeerAnnounced {
+                            info_hash,
+                            source,
+                            peer: SocketAddr::new(source.ip(), port.get()),
+                        })?,
+                        None => self.stats.invalid_ports += 1,
+                    }
+                    let reply = self
+                        .node
+                        .envelope()
+                        .reply(&query, |tid| DhtResponse::new_ping(tid, id));
+                    (id, reply)
+                } else {
+                    self.stats.invalid_tokens += 1;
+                    let error =
+                        self.node
+                            .envelope()
+                            .error(&query, ErrorCode::ProtocolError, "Bad token");
+                    (id, error)
+                }
+            }
+            QueryType::Unknown { .. } => {
+                let error =
+                    self.node
+                        .envelope()
+                        .error(&query, ErrorCode::MethodUnknown, "Method Unknown");
+                (self.config.node.node_id, error)
+            }
+        };
+        self.reply(source, reply.0, &reply.1);
+        Ok(())
+    }
+
+    /// Send a reply, if the rate limit allows it.
+    fn reply(&mut self, destination: SocketAddr, id: Id160, reply: &BencodeValue) {
+        if !self.bucket.try_take(Instant::now()) {
+            self.stats.rate_limited += 1;
+            return;
+        }
+        if self
+            .node
+            .send_to(&bencode::encode(reply), destination)
+            .is_ok()
+        {
+            self.stats.replies_sent += 1;
+            self.remember(destination, id);
+        }
+    }
+
+    /// Remember the id presented to a node, to answer its pings with the same id.
+    ///
+    /// Once [`ADVERTISED_IDS`] nodes are remembered, the oldest one is forgotten.
+    fn remember(&mut self, destination: SocketAddr, id: Id160) {
+        if self.advertised.insert(destination, id).is_some() {
+            return;
+        }
+        self.advertised_order.push_back(destination);
+        if self.advertised_order.len() > ADVERTISED_IDS
+            && let Some(oldest) = self.advertised_order.pop_front()
+        {
+            self.advertised.remove(&oldest);
+        }
+    }
+
+    /// Record a node that just talked to us, IPv6 nodes are ignored.
+    fn learn(&mut self, id: Id160, address: SocketAddr) {
+        let address = match address {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(address) => match address.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddrV4::new(ip, address.port()),
+                None => return,
+            },
+        };
+        self.known.retain(|node| node.node_id != id);
+        if self.known.len() >= KNOWN_NODES {
+            self.known.pop_front();
+        }
+        self.known.push_back(BittorrentNodeInfoV4 {
+            node_id: id,
+            ip: address.ip().octets(),
+            port: address.port(),
+        });
+    }
+
+    /// Get up to `count` known nodes, closest to `target` first.
+    fn closest_known(&self, target: &Id160, count: usize) -> Vec<BittorrentNodeInfoV4<Id160>> {
+        let mut nodes: Vec<_> = self.known.iter().cloned().collect();
+        nodes.sort_by_key(|node| node.node_id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Get the known nodes closest to `target` to return to `requester`. The nodes at bogon
+    /// addresses are only returned to a requester on one too, i.e. of the same local network.
+    fn nodes_for(&self, target: &Id160, requester: SocketAddr) -> Vec<BittorrentNodeInfoV4<Id160>> {
+        let local_requester = is_bogon(requester.ip());
+        let mut nodes: Vec<_> = self
+            .known
+            .iter()
+            .filter(|node| local_requester || !is_bogon(IpAddr::from(node.ip)))
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| node.node_id.distance(target));
+        nodes.truncate(NODES_PER_REPLY);
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcrawler_proto::{
+        kademlia::Xorable,
+        krpc::{Port, query::AnnounceToken},
+    };
+
+    use super::*;
+    use crate::{node::dict_value, testutil::FakeNode};
+
+    fn request(
+        client: &FakeNode,
+        honeypot: SocketAddr,
+        query: Query<Id160>,
+    ) -> Option<BencodeValue> {
+        client
+            .send_to(&bencode::encode(&query.to_bencoded()), honeypot)
+            .unwrap();
+        let (reply, _) = client.receive_message(Duration::from_millis(500)).ok()?;
+        Some(reply)
+    }
+
+    #[test]
+    fn test_remember_forgets_oldest() {
+        let mut config = HoneypotConfig::new(Id160([1; 20]), vec![]);
+        config.node.socket = crate::transport::SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        let mut honeypot = Honeypot::bind(config).unwrap();
+        let node = |port: u16| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        for port in 0..ADVERTISED_IDS as u16 {
+            honeypot.remember(node(port), Id160([2; 20]));
+        }
+        // Updating a remembered node does not make room.
+        honeypot.remember(node(0), Id160([3; 20]));
+        honeypot.remember(node(u16::MAX), Id160([4; 20]));
+        assert_eq!(honeypot.advertised.len(), ADVERTISED_IDS);
+        assert!(!honeypot.advertised.contains_key(&node(0)));
+        assert_eq!(honeypot.advertised.get(&node(1)), Some(&Id160([2; 20])));
+        assert_eq!(
+            honeypot.advertised.get(&node(u16::MAX)),
+            Some(&Id160([4; 20]))
+        );
+    }
+
+    #[test]
+    fn test_honeypot_observes_announces() {
+        let target = Id160([0xaa; 20]);
+        let mut config = HoneypotConfig::new(Id160([1; 20]), vec![target]);
+        config.node.socket = crate::transport::SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        config.node.poll_timeout = Duration::from_millis(10);
+        config.bootstrap_nodes = vec![];
+        // Three replies, then nothing.
+        config.send_rate = 0.0;
+        config.send_burst = 3;
+        let mut honeypot = Honeypot::bind(config).unwrap();
+        let address = honeypot.local_addr().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        honeypot.add_sink(events.clone());
+        let (handle, thread) = honeypot.spawn();
+
+        let client = FakeNode::bind(Id160([2; 20])).unwrap();
+        let client_id = client.id();
+
+        let reply = request(
+            &client,
+            address,
+            Query::new_get_peers("aa", client_id, target),
+        )
+        .expect("no get_peers reply");
+        // No peers are ever returned, only a token and an id close to the target.
+        let response = DhtResponse::try_from_raw_bencoded(&reply).unwrap();
+        let args = match response.get_response_type() {
+            ResponseType::Raw(args) => args,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let arg = |key: &[u8]| {
+            args.iter()
+                .find(|(k, _)| k.as_ref() == key)
+                .map(|(_, value)| value.clone())
+        };
+        let id = match arg(b"id") {
+            Some(BencodeValue::ByteString(id)) => Id160::try_from(id.as_ref()).unwrap(),
+            other => panic!("unexpected id {:?}", other),
+        };
+        assert!(id.bucket_index(&target) >= DEFAULT_COMMON_BITS as usize);
+        let token = match arg(b"token") {
+            Some(BencodeValue::ByteString(token)) => token,
+            other => panic!("unexpected token {:?}", other),
+        };
+        assert_eq!(arg(b"values"), None);
+
+        let reply = request(
+            &client,
+            address,
+            Query::new_announce_peer(
+                "bb",
+                client_id,
+                target,
+                Port::new(51413).unwrap(),
+                AnnounceToken::from_raw(token),
+            ),
+        )
+        .expect("no announce_peer reply");
+        assert!(
+            matches!(dict_value(&reply, b"y"), Some(BencodeValue::ByteString(y)) if y.as_ref() == b"r")
+        );
+
+        let reply = request(
+            &client,
+            address,
+            Query::new_announce_peer(
+                "cc",
+                client_id,
+                target,
+                Port::new(51413).unwrap(),
+                AnnounceToken::from_raw("forged"),
+            ),
+        )
+        .expect("no error reply");
+        assert!(
+            matches!(dict_value(&reply, b"y"), Some(BencodeValue::ByteString(y)) if y.as_ref() == b"e")
+        );
+
+        // The send budget is spent.
+        assert!(request(&client, address, Query::new_ping("dd", client_id)).is_none());
+
+        handle.stop();
+        thread.join().unwrap().unwrap();
+        let stats = handle.stats();
+        assert_eq!(stats.queries_received, 4);
+        assert_eq!(stats.replies_sent, 3);
+        assert_eq!(stats.rate_limited, 1);
+        assert_eq!(stats.announces, 1);
+        assert_eq!(stats.invalid_tokens, 1);
+        let client_address = client.address();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                CrawlEvent::PeersRequested {
+                    info_hash: target,
+                    source: client_address,
+                },
+                CrawlEvent::PeerAnnounced {
+                    info_hash: target,
+                    source: client_address,
+                    peer: SocketAddr::new(client_address.ip(), 51413),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! Answering the queries of other DHT nodes.
+
+mod honeypot;
+mod origins;
+mod peers;
+mod shaping;
+mod token;
+
+pub use honeypot::*;
+pub use origins::*;
+pub use peers::*;
+pub use shaping::*;
+pub use token::*;
@@ -0,0 +1,291 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{kademlia::NodeId, krpc::QueryType};
+
+use crate::limits::IpPrefix;
+
+/// Method of an inbound query, see [`QueryCounts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryKind {
+    /// A `ping` query.
+    Ping,
+    /// A `find_node` query.
+    FindNode,
+    /// A `get_peers` query.
+    GetPeers,
+    /// An `announce_peer` query.
+    AnnouncePeer,
+    /// A query with an unsupported method.
+    Other,
+}
+
+impl QueryKind {
+    /// Get the kind of `query`.
+    pub fn of<N: NodeId>(query: &QueryType<N>) -> QueryKind {
+        match query {
+            QueryType::Ping(_) => QueryKind::Ping,
+            QueryType::FindNode(_) => QueryKind::FindNode,
+            QueryType::GetPeers(_) => QueryKind::GetPeers,
+            QueryType::AnnouncePeer(_) => QueryKind::AnnouncePeer,
+            QueryType::Unknown { .. } => QueryKind::Other,
+        }
+    }
+}
+
+/// Number of inbound queries of each kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueryCounts {
+    /// `ping` queries.
+    pub ping: u64,
+    /// `find_node` queries.
+    pub find_node: u64,
+    /// `get_peers` queries.
+    pub get_peers: u64,
+    /// `announce_peer` queries.
+    pub announce_peer: u64,
+    /// Queries with an unsupported method.
+    pub other: u64,
+}
+
+impl QueryCounts {
+    /// Count a query of kind `kind`.
+    pub fn add(&mut self, kind: QueryKind) {
+        let count = match kind {
+            QueryKind::Ping => &mut self.ping,
+            QueryKind::FindNode => &mut self.find_node,
+            QueryKind::GetPeers => &mut self.get_peers,
+            QueryKind::AnnouncePeer => &mut self.announce_peer,
+            QueryKind::Other => &mut self.other,
+        };
+        *count += 1;
+    }
+
+    /// Get the number of queries of all kinds.
+    pub fn total(&self) -> u64 {
+        self.ping + self.find_node + self.get_peers + self.announce_peer + self.other
+    }
+}
+
+/// Settings of [`InboundQueryStats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboundQueryConfig {
+    /// Length of the prefixes the IPv4 sources are grouped by.
+    pub ipv4_prefix: u8,
+    /// Length of the prefixes the IPv6 sources are grouped by.
+    pub ipv6_prefix: u8,
+    /// Duration of the windows the queries are counted over.
+    pub window: Duration,
+    /// Number of prefixes reported, the most active ones.
+    pub top: usize,
+    /// Maximum number of prefixes counted in a window. The queries from the prefixes past it
+    /// are only counted in the totals, which bounds the memory used under a flood of spoofed
+    /// sources.
+    pub max_prefixes: usize,
+}
+
+impl Default for InboundQueryConfig {
+    /// Group by /16 (IPv4) and /32 (IPv6) over one-minute windows, and report the top 10.
+    fn default() -> Self {
+        InboundQueryConfig {
+            ipv4_prefix: 16,
+            ipv6_prefix: 32,
+            window: Duration::from_secs(60),
+            top: 10,
+            max_prefixes: 65536,
+        }
+    }
+}
+
+/// The queries received from a source prefix, see [`InboundQueryReport::top`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PrefixQueries {
+    /// The source prefix.
+    pub prefix: IpPrefix,
+    /// Queries received from it.
+    pub queries: QueryCounts,
+}
+
+/// Inbound queries over the last complete window, see [`InboundQueryStats::report`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InboundQueryReport {
+    /// Duration of the window, zero until the first window completes.
+    pub window: Duration,
+    /// Queries received in the window.
+    pub queries: QueryCounts,
+    /// Number of distinct source prefixes counted in the window.
+    pub prefixes: usize,
+    /// Queries from the prefixes not counted on their own, see
+    /// [`InboundQueryConfig::max_prefixes`].
+    pub untracked: u64,
+    /// The prefixes that sent the most queries, the most active first.
+    pub top: Vec<PrefixQueries>,
+}
+
+impl InboundQueryReport {
+    /// Get the number of queries per second in `counts`, over the window.
+    pub fn rate(&self, counts: &QueryCounts) -> f64 {
+        if self.window.is_zero() {
+            return 0.0;
+        }
+        counts.total() as f64 / self.window.as_secs_f64()
+    }
+}
+
+/// Inbound query counts per kind and source prefix, over fixed windows.
+///
+/// A responder records each query it receives. The report covers the last complete window: the
+/// totals and the most active source prefixes, e.g. a scanner sweeping the DHT from a single
+/// network stands out at the top.
+#[derive(Debug, Clone)]
+pub struct InboundQueryStats {
+    config: InboundQueryConfig,
+    window_start: Instant,
+    queries: QueryCounts,
+    untracked: u64,
+    prefixes: HashMap<IpPrefix, QueryCounts>,
+    last: InboundQueryReport,
+}
+
+impl InboundQueryStats {
+    /// Create the statistics, the first window starts at `now`.
+    pub fn new(config: InboundQueryConfig, now: Instant) -> InboundQueryStats {
+        InboundQueryStats {
+            config,
+            window_start: now,
+            queries: QueryCounts::default(),
+            untracked: 0,
+            prefixes: HashMap::new(),
+            last: InboundQueryReport::default(),
+        }
+    }
+
+    /// Count a query of kind `kind` received from `source` at `now`.
+    pub fn record(&mut self, source: IpAddr, kind: QueryKind, now: Instant) {
+        self.roll(now);
+        self.queries.add(kind);
+        let length = if source.to_canonical().is_ipv4() {
+            self.config.ipv4_prefix
+        } else {
+            self.config.ipv6_prefix
+        };
+        let prefix = IpPrefix::containing(source, length);
+        if let Some(counts) = self.prefixes.get_mut(&prefix) {
+            counts.add(kind);
+        } else if self.prefixes.len() < self.config.max_prefixes {
+            self.prefixes.entry(prefix).or_default().add(kind);
+        } else {
+            self.untracked += 1;
+        }
+    }
+
+    /// Get the report of the last window complete at `now`.
+    pub fn report(&mut self, now: Instant) -> &InboundQueryReport {
+        self.roll(now);
+        &self.last
+    }
+
+    /// End the current window if it is over at `now`.
+    fn roll(&mut self, now: Instant) {
+        let window = self.config.window;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if window.is_zero() || elapsed < window {
+            return;
+        }
+        let windows = (elapsed.as_nanos() / window.as_nanos()) as u32;
+        self.last = if windows == 1 {
+            self.current()
+        } else {
+            // The window just over received nothing.
+            InboundQueryReport {
+                window,
+                ..InboundQueryReport::default()
+            }
+        };
+        self.window_start += window * windows;
+        self.queries = QueryCounts::default();
+        self.untracked = 0;
+        self.prefixes.clear();
+    }
+
+    /// Get the report of the current window, as if complete.
+    fn current(&self) -> InboundQueryReport {
+        let mut top: Vec<PrefixQueries> = self
+            .prefixes
+            .iter()
+            .map(|(prefix, queries)| PrefixQueries {
+                prefix: *prefix,
+                queries: *queries,
+            })
+            .collect();
+        top.sort_unstable_by_key(|entry| (Reverse(entry.queries.total()), entry.prefix));
+        top.truncate(self.config.top);
+        InboundQueryReport {
+            window: self.config.window,
+            queries: self.queries,
+            prefixes: self.prefixes.len(),
+            untracked: self.untracked,
+            top,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_inbound_queries() {
+        let start = Instant::now();
+        let config = InboundQueryConfig {
+            top: 2,
+            max_prefixes: 3,
+            ..InboundQueryConfig::default()
+        };
+        let mut stats = InboundQueryStats::new(config, start);
+        let ip = |a, b, c| IpAddr::from(Ipv4Addr::new(a, b, c, 1));
+        // A scanner sweeping from 203.0.113.0/24, and a few regular nodes.
+        for host in 0..30 {
+            stats.record(ip(203, 0, host), QueryKind::GetPeers, start);
+        }
+        stats.record(ip(198, 51, 100), QueryKind::Ping, start);
+        stats.record(ip(198, 51, 7), QueryKind::FindNode, start);
+        let mapped = Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped();
+        stats.record(mapped.into(), QueryKind::Other, start);
+        stats.record(ip(10, 0, 0), QueryKind::Ping, start);
+        assert_eq!(stats.report(start), &InboundQueryReport::default());
+
+        let report = stats.report(start + Duration::from_secs(60)).clone();
+        assert_eq!(report.queries.total(), 34);
+        assert_eq!(report.queries.get_peers, 30);
+        assert_eq!(report.prefixes, 3);
+        assert_eq!(report.untracked, 1);
+        let top: Vec<(String, u64)> = report
+            .top
+            .iter()
+            .map(|entry| (entry.prefix.to_string(), entry.queries.total()))
+            .collect();
+        assert_eq!(
+            top,
+            [
+                ("203.0.0.0/16".to_string(), 30),
+                ("198.51.0.0/16".to_string(), 2)
+            ]
+        );
+        assert_eq!(report.rate(&report.top[0].queries), 0.5);
+
+        // The next window received nothing.
+        let report = stats.report(start + Duration::from_secs(150));
+        assert_eq!(report.queries, QueryCounts::default());
+        assert_eq!(report.window, Duration::from_secs(60));
+    }
+}
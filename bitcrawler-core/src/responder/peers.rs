@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::SocketAddrV4,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bitcrawler_proto::kademlia::Id160;
+
+/// Settings of a [`PeerStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStoreConfig {
+    /// Time a peer is kept after its last announce. Clients announce again every 15 to 30
+    /// minutes, so 30 minutes by default.
+    pub ttl: Duration,
+    /// Maximum number of peers kept per info hash, the oldest announce is dropped for a new
+    /// one. More than fit in a reply, see [`ReplyShaper`](super::ReplyShaper).
+    pub peers_per_info_hash: usize,
+    /// Maximum number of info hashes, the announces of other ones are dropped.
+    pub info_hashes: usize,
+}
+
+impl Default for PeerStoreConfig {
+    fn default() -> Self {
+        PeerStoreConfig {
+            ttl: Duration::from_secs(30 * 60),
+            peers_per_info_hash: 512,
+            info_hashes: 65536,
+        }
+    }
+}
+
+/// A peer announced to us, see [`PeerStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StoredPeer {
+    address: SocketAddrV4,
+    /// Time of the last announce of the peer.
+    announced: SystemTime,
+}
+
+impl StoredPeer {
+    fn is_expired(&self, ttl: Duration, now: SystemTime) -> bool {
+        now.duration_since(self.announced).unwrap_or_default() >= ttl
+    }
+}
+
+/// The peers announced to a responder (`announce_peer` queries), to return them in the
+/// `get_peers` replies until they expire.
+///
+/// The store can be written to a file and read back, so that a restarted responder serves the
+/// peers announced before the restart rather than starting cold. The times are wall-clock ones
+/// for that reason: the peers age while the responder is down too.
+///
+/// The file is a text file, one peer per line: the info hash in hexadecimal, the address of
+/// the peer and the time of its last announce, in seconds since the Unix epoch. `#` starts a
+/// comment.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStore {
+    config: PeerStoreConfig,
+    peers: HashMap<Id160, Vec<StoredPeer>>,
+}
+
+impl PeerStore {
+    /// Create an empty store.
+    pub fn new(config: PeerStoreConfig) -> PeerStore {
+        PeerStore {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Read the store written at `path` (see [`PeerStore::write`]), without the peers expired
+    /// at `now`. A missing file is an empty store.
+    ///
+    /// The peers keep what is left of their time to live, and the limits of `config` apply.
+    pub fn read<P: AsRef<Path>>(
+        path: P,
+        config: PeerStoreConfig,
+        now: SystemTime,
+    ) -> io::Result<PeerStore> {
+        let mut store = PeerStore::new(config);
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (info_hash, address, announced) = parse_peer(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {} ({:?})", number + 1, e, line),
+                )
+            })?;
+            // An announce from the future (e.g. the clock went back) counts as a fresh one.
+            store.announce(info_hash, address, announced.min(now));
+        }
+        store.expire(now);
+        Ok(store)
+    }
+
+    /// Write the store to `path`, replacing the file only once it is fully written.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(writer, "# info_hash peer announced")?;
+        for (info_hash, peers) in &self.peers {
+            for peer in peers {
+                let announced = peer
+                    .announced
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                writeln!(
+                    writer,
+                    "{} {} {}",
+                    info_hash,
+                    peer.address,
+                    announced.as_secs()
+                )?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Record that `peer` announced itself for `info_hash` at `now`.
+    ///
+    /// Returns false if the announce was dropped: an unknown info hash while the store holds
+    /// [`PeerStoreConfig::info_hashes`] of them.
+    pub fn announce(&mut self, info_hash: Id160, peer: SocketAddrV4, now: SystemTime) -> bool {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= self.config.info_hashes {
+            return false;
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        match peers.iter_mut().find(|stored| stored.address == peer) {
+            Some(stored) => stored.announced = stored.announced.max(now),
+            None => {
+                if peers.len() >= self.config.peers_per_info_hash
+                    && let Some(oldest) = peers
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, stored)| stored.announced)
+                        .map(|(index, _)| index)
+                {
+                    peers.swap_remove(oldest);
+                }
+                if peers.len() < self.config.peers_per_info_hash {
+                    peers.push(StoredPeer {
+                        address: peer,
+                        announced: now,
+                    });
+                }
+            }
+        }
+        true
+    }
+
+    /// Get the peers of `info_hash` not expired at `now`.
+    pub fn peers(&self, info_hash: &Id160, now: SystemTime) -> Vec<SocketAddrV4> {
+        self.peers
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|peer| !peer.is_expired(self.config.ttl, now))
+            .map(|peer| peer.address)
+            .collect()
+    }
+
+    /// Drop the peers expired at `now`, returns how many were dropped.
+    pub fn expire(&mut self, now: SystemTime) -> usize {
+        let ttl = self.config.ttl;
+        let mut expired = 0;
+        self.peers.retain(|_, peers| {
+            let count = peers.len();
+            peers.retain(|peer| !peer.is_expired(ttl, now));
+            expired += count - peers.len();
+            !peers.is_empty()
+        });
+        expired
+    }
+
+    /// Get the number of info hashes with peers (expired ones included until
+    /// [`PeerStore::expire`]).
+    pub fn info_hashes(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Get the number of peers, over all the info hashes.
+    pub fn len(&self) -> usize {
+        self.peers.values().map(Vec::len).sum()
+    }
+
+    /// Check if the store holds no peer.
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+/// Parse a line of a peer store file.
+fn parse_peer(line: &str) -> Result<(Id160, SocketAddrV4, SystemTime), &'static str> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [info_hash, address, announced] = fields[..] else {
+        return Err("expected 3 fields");
+    };
+    let announced: u64 = announced.parse().map_err(|_| "invalid announce time")?;
+    Ok((
+        info_hash.parse()?,
+        address.parse().map_err(|_| "invalid peer address")?,
+        UNIX_EPOCH + Duration::from_secs(announced),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_store() {
+        let config = PeerStoreConfig {
+            peers_per_info_hash: 2,
+            info_hashes: 2,
+            ..PeerStoreConfig::default()
+        };
+        let ttl = config.ttl;
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+        let peer = |port| SocketAddrV4::new([192, 0, 2, 1].into(), port);
+        let (a, b, c) = (Id160([1; 20]), Id160([2; 20]), Id160([3; 20]));
+
+        let mut store = PeerStore::new(config.clone());
+        assert!(store.announce(a, peer(1), minutes(0)));
+        assert!(store.announce(a, peer(2), minutes(10)));
+        // The oldest announce makes room for a new peer, a known peer is refreshed.
+        assert!(store.announce(a, peer(3), minutes(20)));
+        assert!(store.announce(a, peer(2), minutes(25)));
+        assert!(store.announce(b, peer(4), minutes(5)));
+        assert!(!store.announce(c, peer(5), minutes(5)));
+        assert_eq!(store.info_hashes(), 2);
+        assert_eq!(store.len(), 3);
+        let mut peers = store.peers(&a, minutes(30));
+        peers.sort();
+        assert_eq!(peers, [peer(2), peer(3)]);
+        assert_eq!(store.peers(&b, start + ttl + Duration::from_secs(300)), []);
+
+        // Restarted 15 minutes after the last write: the peers age while it is down.
+        let path = std::env::temp_dir().join(format!("peers-{}.txt", std::process::id()));
+        store.write(&path).unwrap();
+        let read = PeerStore::read(&path, config.clone(), minutes(40)).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read.peers(&b, minutes(40)), []);
+        assert_eq!(read.peers(&a, minutes(50)), [peer(2)]);
+        let read = PeerStore::read(&path, config.clone(), minutes(60)).unwrap();
+        assert!(read.is_empty());
+
+        fs::write(&path, "0101 192.0.2.1:1 1700000000\n").unwrap();
+        let error = PeerStore::read(&path, config.clone(), start).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(PeerStore::read(&path, config, start).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,296 @@
+use std::{net::SocketAddrV4, str::FromStr};
+
+use bitcrawler_proto::{
+    bencode::{self, BencodeString},
+    kademlia::Id160,
+    krpc::{GetPeersShape, ResponseType, node_info::BittorrentNodeInfoV4},
+};
+
+use crate::{
+    node::DhtResponse,
+    rng::{Rng, SplitMix64},
+};
+
+/// Default largest size of a reply: the IPv6 minimum MTU (1280 bytes) minus the IPv6 and UDP
+/// headers, so that a reply is never fragmented, whatever the path.
+pub const DEFAULT_MAX_REPLY_SIZE: usize = 1232;
+
+/// Size of a peer in the `values` list: a string of 6 bytes (`6:` and the compact peer info).
+const PEER_SIZE: usize = 8;
+/// Size of the `values` field itself: its key (`6:values`) and the delimiters of the list.
+const VALUES_OVERHEAD: usize = 10;
+
+/// What the `get_peers` replies of a [`ReplyShaper`] hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GetPeersPolicy {
+    /// The peers if any is known, the closest nodes otherwise (BEP 5). When the peers do not
+    /// all fit, the closest nodes come with a subset of them, so that the lookup can go on.
+    #[default]
+    ValuesOnly,
+    /// Only the closest nodes, never the peers.
+    NodesOnly,
+    /// Both the closest nodes and the peers, with an empty `values` when no peer is known, for
+    /// the clients that expect both.
+    Both,
+}
+
+impl FromStr for GetPeersPolicy {
+    type Err = &'static str;
+
+    /// Parse `values`, `nodes` or `both`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "values" => Ok(GetPeersPolicy::ValuesOnly),
+            "nodes" => Ok(GetPeersPolicy::NodesOnly),
+            "both" => Ok(GetPeersPolicy::Both),
+            _ => Err("Invalid get_peers policy"),
+        }
+    }
+}
+
+/// Builds `get_peers` replies that fit in a datagram of a given size.
+///
+/// A node storing many peers for an info hash cannot return them all: a large reply is
+/// fragmented, and many clients and middleboxes drop fragmented datagrams. When the peers do
+/// not fit, the reply carries the closest nodes (so that the lookup can go on) and a random
+/// subset of the peers (so that the nodes asking the same question see different peers).
+///
+/// Which fields a reply holds is set by a [`GetPeersPolicy`]. The random choices are drawn
+/// from an [`Rng`], a [`SplitMix64`] by default.
+#[derive(Debug, Clone)]
+pub struct ReplyShaper<R = SplitMix64> {
+    max_size: usize,
+    policy: GetPeersPolicy,
+    rng: R,
+}
+
+impl ReplyShaper {
+    /// Create a shaper for replies of at most `max_size` bytes, drawing from a [`SplitMix64`]
+    /// seeded with `seed` (e.g. [`NodeConfig::seed`](crate::node::NodeConfig::seed)), or with a
+    /// random seed if `None`.
+    pub fn new(max_size: usize, seed: Option<u64>) -> ReplyShaper {
+        ReplyShaper::with_rng(max_size, SplitMix64::from_seed(seed))
+    }
+}
+
+impl<R: Rng> ReplyShaper<R> {
+    /// Create a shaper for replies of at most `max_size` bytes, drawing from `rng`.
+    pub fn with_rng(max_size: usize, rng: R) -> ReplyShaper<R> {
+        ReplyShaper {
+            max_size,
+            policy: GetPeersPolicy::default(),
+            rng,
+        }
+    }
+
+    /// Set what the replies hold.
+    pub fn with_policy(mut self, policy: GetPeersPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Get the largest size of a reply, in bytes.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Get what the replies hold.
+    pub fn policy(&self) -> GetPeersPolicy {
+        self.policy
+    }
+
+    /// Build the reply to a `get_peers` query, from the `peers` known for the info hash and the
+    /// `nodes` closest to it (closest first).
+    ///
+    /// With [`GetPeersPolicy::ValuesOnly`], all the peers are returned if they fit, without
+    /// nodes. Otherwise (and always with [`GetPeersPolicy::Both`]) the reply holds as many of
+    /// the closest nodes as fit, then as many peers, picked at random, as fit beside them. With
+    /// [`GetPeersPolicy::NodesOnly`] it holds the nodes alone. Only a reply without nodes nor
+    /// peers may exceed the size, if the size is too small for anything.
+    pub fn get_peers(
+        &mut self,
+        transaction_id: BencodeString,
+        id: Id160,
+        token: Option<BencodeString>,
+        mut nodes: Vec<BittorrentNodeInfoV4<Id160>>,
+        peers: &[SocketAddrV4],
+    ) -> DhtResponse {
+        // The fields sent even when empty.
+        let shape = match self.policy {
+            GetPeersPolicy::ValuesOnly => None,
+            GetPeersPolicy::NodesOnly => Some(GetPeersShape::Nodes),
+            GetPeersPolicy::Both => Some(GetPeersShape::Both),
+        };
+        let reply = |nodes: Vec<BittorrentNodeInfoV4<Id160>>, peers: Vec<SocketAddrV4>| {
+            let reply =
+                DhtResponse::new_get_peers(transaction_id.clone(), id, token.clone(), nodes, peers);
+            match (shape, reply.get_response_type()) {
+                (Some(shape), ResponseType::GetPeers(get_peers)) => DhtResponse::new(
+                    transaction_id.clone(),
+                    ResponseType::GetPeers(
+                        get_peers
+                            .clone()
+                            .with_shape(shape)
+                            .expect("the policy keeps the fields that are not empty"),
+                    ),
+                ),
+                _ => reply,
+            }
+        };
+        let size = |reply: &DhtResponse| bencode::encode(&reply.to_bencoded()).len();
+
+        if self.policy == GetPeersPolicy::ValuesOnly && !peers.is_empty() {
+            let all_peers = reply(Vec::new(), peers.to_vec());
+            if size(&all_peers) <= self.max_size {
+                return all_peers;
+            }
+        }
+        let mut with_nodes = reply(nodes.clone(), Vec::new());
+        while !nodes.is_empty() && size(&with_nodes) > self.max_size {
+            nodes.pop();
+            with_nodes = reply(nodes.clone(), Vec::new());
+        }
+        if self.policy == GetPeersPolicy::NodesOnly {
+            return with_nodes;
+        }
+        let room = self.max_size.saturating_sub(size(&with_nodes));
+        // An empty `values` is already in the reply with `Both`.
+        let overhead = if shape.is_some() { 0 } else { VALUES_OVERHEAD };
+        let count = (room.saturating_sub(overhead) / PEER_SIZE).min(peers.len());
+        if count == 0 {
+            return with_nodes;
+        }
+        // Partial Fisher-Yates shuffle: the first `count` peers are a uniform random subset.
+        let mut peers = peers.to_vec();
+        for i in 0..count {
+            let j = i + self.rng.below((peers.len() - i) as u64) as usize;
+            peers.swap(i, j);
+        }
+        peers.truncate(count);
+        reply(nodes, peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use bitcrawler_proto::krpc::ResponseType;
+
+    use super::*;
+
+    fn node(i: u8) -> BittorrentNodeInfoV4<Id160> {
+        BittorrentNodeInfoV4 {
+            node_id: Id160([i; 20]),
+            ip: [192, 0, 2, i],
+            port: 6881,
+        }
+    }
+
+    fn peers(count: u16) -> Vec<SocketAddrV4> {
+        (0..count)
+            .map(|i| SocketAddrV4::new([198, 51, 100, 1].into(), 1024 + i))
+            .collect()
+    }
+
+    fn shape(
+        shaper: &mut ReplyShaper,
+        nodes: usize,
+        peers: &[SocketAddrV4],
+    ) -> (DhtResponse, usize) {
+        let nodes = (1..=nodes as u8).map(node).collect();
+        let reply = shaper.get_peers(
+            "aa".into(),
+            Id160([0; 20]),
+            Some("token".into()),
+            nodes,
+            peers,
+        );
+        let size = bencode::encode(&reply.to_bencoded()).len();
+        (reply, size)
+    }
+
+    fn contents(reply: &DhtResponse) -> (usize, Vec<SocketAddrV4>) {
+        match reply.get_response_type() {
+            ResponseType::GetPeers(get_peers) => {
+                (get_peers.get_nodes().len(), get_peers.get_peers().to_vec())
+            }
+            _ => panic!("not a get_peers reply"),
+        }
+    }
+
+    #[test]
+    fn test_get_peers_reply_size() {
+        let mut shaper = ReplyShaper::with_rng(DEFAULT_MAX_REPLY_SIZE, SplitMix64::with_seed(1));
+
+        // The peers fit: all of them, no nodes.
+        let few = peers(10);
+        let (reply, _) = shape(&mut shaper, 8, &few);
+        assert_eq!(contents(&reply), (0, few));
+        // No peers: the nodes.
+        let (reply, _) = shape(&mut shaper, 8, &[]);
+        assert_eq!(contents(&reply), (8, vec![]));
+
+        // Too many peers: the nodes, and the peers filling the rest exactly.
+        let many = peers(500);
+        let (reply, size) = shape(&mut shaper, 8, &many);
+        let (nodes, first) = contents(&reply);
+        assert_eq!(nodes, 8);
+        assert!(size <= DEFAULT_MAX_REPLY_SIZE && size + PEER_SIZE > DEFAULT_MAX_REPLY_SIZE);
+        let unique: HashSet<_> = first.iter().collect();
+        assert_eq!(unique.len(), first.len());
+        assert!(first.iter().all(|peer| many.contains(peer)));
+        // Another subset for the next reply.
+        let (reply, _) = shape(&mut shaper, 8, &many);
+        assert_ne!(contents(&reply).1, first);
+
+        // A small size leaves out the farthest nodes, then everything.
+        let mut small = ReplyShaper::with_rng(200, SplitMix64::with_seed(1));
+        let (reply, size) = shape(&mut small, 8, &many);
+        assert!(size <= 200);
+        let (nodes, peers) = contents(&reply);
+        assert_eq!((nodes, peers.len()), (4, 1));
+        let mut tiny = ReplyShaper::with_rng(10, SplitMix64::with_seed(1));
+        assert_eq!(contents(&shape(&mut tiny, 8, &many).0), (0, vec![]));
+    }
+
+    #[test]
+    fn test_get_peers_policy() {
+        let shape_of = |reply: &DhtResponse| match reply.get_response_type() {
+            ResponseType::GetPeers(get_peers) => get_peers.get_shape(),
+            _ => panic!("not a get_peers reply"),
+        };
+        let few = peers(10);
+        let many = peers(500);
+
+        let mut nodes_only =
+            ReplyShaper::with_rng(DEFAULT_MAX_REPLY_SIZE, SplitMix64::with_seed(1))
+                .with_policy(GetPeersPolicy::NodesOnly);
+        let (reply, _) = shape(&mut nodes_only, 8, &few);
+        assert_eq!(contents(&reply), (8, vec![]));
+        assert_eq!(shape_of(&reply), Some(GetPeersShape::Nodes));
+
+        let mut both = ReplyShaper::with_rng(DEFAULT_MAX_REPLY_SIZE, SplitMix64::with_seed(1))
+            .with_policy(GetPeersPolicy::Both);
+        let (reply, _) = shape(&mut both, 8, &few);
+        let (nodes, mut returned) = contents(&reply);
+        returned.sort();
+        assert_eq!((nodes, returned), (8, few));
+        assert_eq!(shape_of(&reply), Some(GetPeersShape::Both));
+        // No peer known: an empty `values` beside the nodes.
+        let (reply, _) = shape(&mut both, 8, &[]);
+        assert_eq!(contents(&reply), (8, vec![]));
+        assert_eq!(shape_of(&reply), Some(GetPeersShape::Both));
+        // The peers still fill the rest exactly.
+        let (reply, size) = shape(&mut both, 8, &many);
+        assert_eq!(contents(&reply).0, 8);
+        assert!(size <= DEFAULT_MAX_REPLY_SIZE && size + PEER_SIZE > DEFAULT_MAX_REPLY_SIZE);
+
+        assert_eq!("both".parse(), Ok(GetPeersPolicy::Both));
+        assert_eq!(
+            ReplyShaper::new(DEFAULT_MAX_REPLY_SIZE, None).policy(),
+            GetPeersPolicy::ValuesOnly
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    hash::Hasher,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::consts::TOKEN_MAX_AGE;
+use siphasher::sip::SipHasher24;
+
+use crate::secret::{SecretKey, Token};
+
+/// Interval between two rotations of the token secret.
+///
+/// Tokens stay valid for one more interval after a rotation, so for up to
+/// [`TOKEN_MAX_AGE`] as BEP 5 recommends.
+pub const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(TOKEN_MAX_AGE.as_secs() / 2);
+
+/// Issues the tokens returned by `get_peers` replies, and checks the tokens of `announce_peer`
+/// queries.
+///
+/// A token is a keyed hash (SipHash-2-4) of the IP address of the querying node, with a secret
+/// rotated every [`TOKEN_ROTATION_INTERVAL`], so no state is kept per node. The tokens received
+/// are checked in constant time, and the secrets are wiped on rotation with the `zeroize`
+/// feature.
+#[derive(Debug)]
+pub struct Tokens {
+    current: SecretKey,
+    previous: SecretKey,
+    rotated_at: Instant,
+}
+
+impl Tokens {
+    /// Create a token issuer with a random secret.
+    pub fn new(now: Instant) -> Tokens {
+        Tokens {
+            current: SecretKey::random(),
+            previous: SecretKey::random(),
+            rotated_at: now,
+        }
+    }
+
+    /// Get the token for the node at `ip`.
+    pub fn issue(&mut self, ip: IpAddr, now: Instant) -> Token {
+        self.rotate(now);
+        compute(&self.current, ip)
+    }
+
+    /// Check a token received from the node at `ip`.
+    pub fn verify(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        // Both are checked, not to tell which secret the token was issued with.
+        let current = compute(&self.current, ip) == *token;
+        let previous = compute(&self.previous, ip) == *token;
+        current | previous
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.rotated_at);
+        if elapsed >= TOKEN_ROTATION_INTERVAL * 2 {
+            // Both secrets expired.
+            self.previous = SecretKey::random();
+            self.current = SecretKey::random();
+            self.rotated_at = now;
+        } else if elapsed >= TOKEN_ROTATION_INTERVAL {
+            self.previous = std::mem::replace(&mut self.current, SecretKey::random());
+            self.rotated_at = now;
+        }
+    }
+}
+
+fn compute(secret: &SecretKey, ip: IpAddr) -> Token {
+    let mut hasher = SipHasher24::new_with_key(secret.as_bytes());
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets()),
+    }
+    hasher.finish().to_be_bytes().to_vec().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_token_rotation() {
+        let start = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut tokens = Tokens::new(start);
+        let token = tokens.issue(ip, start);
+        let token = token.as_bytes();
+        assert!(tokens.verify(ip, token, start));
+        assert!(!tokens.verify(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), token, start));
+        // The same node behind its IPv4-mapped address.
+        assert!(tokens.verify(
+            IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped()),
+            token,
+            start
+        ));
+
+        let later = start + TOKEN_ROTATION_INTERVAL;
+        assert!(tokens.verify(ip, token, later));
+        assert!(!tokens.verify(ip, token, later + TOKEN_ROTATION_INTERVAL));
+    }
+}
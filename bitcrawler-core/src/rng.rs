@@ -0,0 +1,134 @@
+//! The random choices of a crawl (lookup targets, node ids, transaction ids) are drawn from an
+//! [`Rng`], which can be seeded to make a crawl, or a test, reproducible.
+//!
+//! The secrets (e.g. the key the responder tokens are derived from) are never drawn from it,
+//! see the [`secret`](crate::secret) module.
+
+use std::hash::{BuildHasher, RandomState};
+
+use bitcrawler_proto::kademlia::Id160;
+
+/// A source of random numbers.
+pub trait Rng {
+    /// Get the next random 64-bit number.
+    fn next_u64(&mut self) -> u64;
+
+    /// Get a random 32-bit number.
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Get a number uniformly distributed in `0..bound`, `0` if `bound` is `0`.
+    fn below(&mut self, bound: u64) -> u64 {
+        if bound == 0 {
+            return 0;
+        }
+        // Multiply-shift, rejecting the low products that would bias the result (Lemire).
+        let threshold = bound.wrapping_neg() % bound;
+        loop {
+            let product = u128::from(self.next_u64()) * u128::from(bound);
+            if product as u64 >= threshold {
+                return (product >> 64) as u64;
+            }
+        }
+    }
+
+    /// Get a number uniformly distributed in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Fill `bytes` with random bytes.
+    fn fill_bytes(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_be_bytes()[..chunk.len()]);
+        }
+    }
+
+    /// Get a random id, uniformly distributed over the whole keyspace.
+    fn random_id(&mut self) -> Id160 {
+        let mut id = [0u8; 20];
+        self.fill_bytes(&mut id);
+        Id160(id)
+    }
+}
+
+impl<R: Rng + ?Sized> Rng for &mut R {
+    fn next_u64(&mut self) -> u64 {
+        (**self).next_u64()
+    }
+}
+
+impl<R: Rng + ?Sized> Rng for Box<R> {
+    fn next_u64(&mut self) -> u64 {
+        (**self).next_u64()
+    }
+}
+
+/// A fast non-cryptographic generator (SplitMix64): the choices it makes only have to be spread
+/// evenly, not unpredictable.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Create a generator with a random seed.
+    pub fn new() -> SplitMix64 {
+        SplitMix64::with_seed(RandomState::new().hash_one(0u64))
+    }
+
+    /// Create a generator producing the same numbers for the same seed.
+    pub fn with_seed(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    /// Create a generator with `seed` if set, with a random seed otherwise.
+    pub fn from_seed(seed: Option<u64>) -> SplitMix64 {
+        seed.map_or_else(SplitMix64::new, SplitMix64::with_seed)
+    }
+}
+
+impl Default for SplitMix64 {
+    fn default() -> Self {
+        SplitMix64::new()
+    }
+}
+
+impl Rng for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_sequences() {
+        let mut a = SplitMix64::with_seed(1234);
+        let mut b = SplitMix64::from_seed(Some(1234));
+        let first: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+        assert_eq!(first, (0..8).map(|_| b.next_u64()).collect::<Vec<_>>());
+        // A target generator can borrow the generator of a node.
+        assert_eq!(
+            a.random_id(),
+            crate::keyspace::TargetGenerator::with_rng(&mut b).random_id()
+        );
+        assert_ne!(SplitMix64::with_seed(1).next_u64(), first[0]);
+
+        let mut rng: Box<dyn Rng> = Box::new(SplitMix64::with_seed(5));
+        let mut counts = [0; 3];
+        for _ in 0..3000 {
+            counts[rng.below(3) as usize] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 900), "{:?}", counts);
+        assert_eq!(rng.below(0), 0);
+        assert_eq!(rng.below(1), 0);
+    }
+}
@@ -0,0 +1,138 @@
+//! Handling of the secret material: the key the responder tokens are derived from, and the
+//! tokens themselves.
+//!
+//! Tokens are only compared in constant time: [`Token`] does not expose a comparison that
+//! returns early, so checking a token received from the network does not leak how much of it
+//! matched. With the `zeroize` feature, the keys and tokens are wiped from memory when dropped.
+
+use std::{fmt, hint::black_box};
+
+/// Compare two byte strings in constant time (for a given length).
+///
+/// The lengths are not secret: strings of different lengths are unequal right away.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b) {
+        diff |= black_box(x ^ y);
+    }
+    black_box(diff) == 0
+}
+
+/// A token (BEP 5), e.g. one issued by a responder, or one received in a `get_peers` reply.
+///
+/// Equality is checked in constant time, with [`ct_eq`]. The bytes are only exposed to be sent,
+/// by [`Token::as_bytes`].
+#[derive(Clone)]
+pub struct Token(Vec<u8>);
+
+impl Token {
+    /// Get the bytes of the token, e.g. to send it.
+    ///
+    /// Compare tokens as [`Token`]s (or with [`ct_eq`]), not as byte slices.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Token {
+    fn from(bytes: Vec<u8>) -> Token {
+        Token(bytes)
+    }
+}
+
+impl From<&[u8]> for Token {
+    fn from(bytes: &[u8]) -> Token {
+        Token(bytes.to_vec())
+    }
+}
+
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        ct_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Token {}
+
+impl PartialEq<[u8]> for Token {
+    fn eq(&self, other: &[u8]) -> bool {
+        ct_eq(&self.0, other)
+    }
+}
+
+/// Only the length is shown, tokens do not end up in logs.
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Token({} bytes)", self.0.len())
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Token {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+/// A random 128-bit key, e.g. the secret of a keyed hash.
+// Only the responder tokens use a key.
+#[cfg_attr(not(feature = "crawler"), allow(dead_code))]
+pub(crate) struct SecretKey([u8; 16]);
+
+#[cfg_attr(not(feature = "crawler"), allow(dead_code))]
+impl SecretKey {
+    /// Draw a key from the random number generator of the OS.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the OS fails to provide random bytes.
+    pub(crate) fn random() -> SecretKey {
+        let mut key = [0; 16];
+        getrandom::fill(&mut key).expect("OS random number generator");
+        SecretKey(key)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_comparison() {
+        assert!(ct_eq(b"token", b"token"));
+        assert!(!ct_eq(b"token", b"tokem"));
+        assert!(!ct_eq(b"token", b"toke"));
+        assert!(ct_eq(b"", b""));
+
+        let token = Token::from(&b"secret"[..]);
+        assert_eq!(token, Token::from(b"secret".to_vec()));
+        assert!(token == *b"secret".as_slice());
+        assert!(token != *b"secreT".as_slice());
+        assert_eq!(format!("{:?}", token), "Token(6 bytes)");
+
+        assert_ne!(
+            SecretKey::random().as_bytes(),
+            SecretKey::random().as_bytes()
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! Network impairments, to check how the node copes with a bad network (timeouts, retries)
+//! without one.
+//!
+//! A [`Link`] decides the fate of each datagram under [`LinkConditions`] (latency, loss,
+//! duplication, reordering), and an [`ImpairedRelay`] applies them to real UDP traffic: the
+//! node sends to the relay instead of the destination, and the datagrams go through the
+//! impaired link both ways.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::rng::{Rng, SplitMix64};
+
+/// Longest time the relay waits for a datagram before delivering the due ones.
+const RELAY_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Distribution of the delay a link adds to each datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Latency {
+    /// No delay.
+    #[default]
+    None,
+    /// The same delay for every datagram.
+    Fixed(Duration),
+    /// A delay uniformly distributed between `min` and `max`.
+    Uniform {
+        /// Shortest delay.
+        min: Duration,
+        /// Longest delay.
+        max: Duration,
+    },
+    /// A delay exponentially distributed around `mean` (a long tail of late datagrams), added
+    /// to `min`.
+    Exponential {
+        /// Shortest delay.
+        min: Duration,
+        /// Mean of the delay added to `min`.
+        mean: Duration,
+    },
+}
+
+impl Latency {
+    fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        match *self {
+            Latency::None => Duration::ZERO,
+            Latency::Fixed(delay) => delay,
+            Latency::Uniform { min, max } => {
+                let spread = max.saturating_sub(min).as_nanos() as f64;
+                min + Duration::from_nanos((spread * rng.unit()) as u64)
+            }
+            Latency::Exponential { min, mean } => {
+                // Inverse transform sampling, `1 - unit` is in (0, 1].
+                let factor = -(1.0 - rng.unit()).ln();
+                min + mean.mul_f64(factor)
+            }
+        }
+    }
+}
+
+/// How a [`Link`] treats the datagrams.
+///
+/// The rates are probabilities, between 0 and 1. The default is a perfect link.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LinkConditions {
+    /// Delay of the datagrams.
+    pub latency: Latency,
+    /// Share of the datagrams lost.
+    pub loss_rate: f64,
+    /// Share of the datagrams delivered twice, the copy with its own delay.
+    pub duplicate_rate: f64,
+    /// Share of the datagrams held back by `reorder_delay` on top of their latency, so that
+    /// the following ones overtake them.
+    pub reorder_rate: f64,
+    /// Extra delay of the reordered datagrams.
+    pub reorder_delay: Duration,
+}
+
+/// Decides the fate of the datagrams sent over a link, see [`Link::transmit`].
+#[derive(Debug, Clone)]
+pub struct Link {
+    conditions: LinkConditions,
+    rng: SplitMix64,
+}
+
+impl Link {
+    /// Create a link, with a random seed if `seed` is `None` (see [`SplitMix64::from_seed`]).
+    pub fn new(conditions: LinkConditions, seed: Option<u64>) -> Link {
+        Link {
+            conditions,
+            rng: SplitMix64::from_seed(seed),
+        }
+    }
+
+    /// Get the conditions of the link.
+    pub fn conditions(&self) -> &LinkConditions {
+        &self.conditions
+    }
+
+    /// Send a datagram over the link: get the delays after which its copies are delivered,
+    /// none if it is lost, two if it is duplicated.
+    pub fn transmit(&mut self) -> Vec<Duration> {
+        if self.rng.unit() < self.conditions.loss_rate {
+            return vec![];
+        }
+        let copies = if self.rng.unit() < self.conditions.duplicate_rate {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut delay = self.conditions.latency.sample(&mut self.rng);
+                if self.rng.unit() < self.conditions.reorder_rate {
+                    delay += self.conditions.reorder_delay;
+                }
+                delay
+            })
+            .collect()
+    }
+}
+
+/// A datagram waiting for its delivery time.
+struct Delayed {
+    due: Instant,
+    // Order of scheduling, to deliver the datagrams due at the same time in order.
+    sequence: u64,
+    destination: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.sequence) == (other.due, other.sequence)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.sequence).cmp(&(other.due, other.sequence))
+    }
+}
+
+/// A UDP relay standing for a destination behind an impaired link.
+///
+/// The datagrams received by the relay are forwarded to the destination through the `outbound`
+/// link, and the datagrams of the destination are forwarded back (to the last address the relay
+/// heard from) through the `inbound` link. The relay runs on its own thread until dropped.
+pub struct ImpairedRelay {
+    address: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ImpairedRelay {
+    /// Bind a relay on `bind_address` (e.g. an ephemeral loopback port) for `destination`.
+    pub fn spawn(
+        bind_address: SocketAddr,
+        destination: SocketAddr,
+        outbound: Link,
+        inbound: Link,
+    ) -> io::Result<ImpairedRelay> {
+        // The destination sees the datagrams coming from the same address for every client.
+        let client_side = UdpSocket::bind(bind_address)?;
+        let destination_side = UdpSocket::bind((bind_address.ip(), 0))?;
+        client_side.set_read_timeout(Some(RELAY_POLL_INTERVAL))?;
+        destination_side.set_nonblocking(true)?;
+        let address = client_side.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let mut relay = Relay {
+            client_side,
+            destination_side,
+            destination,
+            client: None,
+            outbound,
+            inbound,
+            pending: BinaryHeap::new(),
+            sequence: 0,
+        };
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    relay.step();
+                }
+            })
+        };
+        Ok(ImpairedRelay {
+            address,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Get the address to send to instead of the destination.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for ImpairedRelay {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Relay {
+    client_side: UdpSocket,
+    destination_side: UdpSocket,
+    destination: SocketAddr,
+    // Last address heard from on the client side, the replies go to it.
+    client: Option<SocketAddr>,
+    outbound: Link,
+    inbound: Link,
+    pending: BinaryHeap<Reverse<Delayed>>,
+    sequence: u64,
+}
+
+impl Relay {
+    fn step(&mut self) {
+        let mut buffer = [0u8; 65536];
+        let now = Instant::now();
+        // Sockets errors (e.g. ICMP errors surfacing) are losses like the others.
+        if let Ok((size, source)) = self.client_side.recv_from(&mut buffer) {
+            self.client = Some(source);
+            let delays = self.outbound.transmit();
+            self.schedule(now, delays, self.destination, &buffer[..size]);
+        }
+        while let Ok((size, _)) = self.destination_side.recv_from(&mut buffer) {
+            if let Some(client) = self.client {
+                let delays = self.inbound.transmit();
+                self.schedule(now, delays, client, &buffer[..size]);
+            }
+        }
+        let now = Instant::now();
+        while self
+            .pending
+            .peek()
+            .is_some_and(|Reverse(delayed)| delayed.due <= now)
+        {
+            let Some(Reverse(delayed)) = self.pending.pop() else {
+                break;
+            };
+            let socket = if delayed.destination == self.destination {
+                &self.destination_side
+            } else {
+                &self.client_side
+            };
+            let _ = socket.send_to(&delayed.data, delayed.destination);
+        }
+    }
+
+    fn schedule(
+        &mut self,
+        now: Instant,
+        delays: Vec<Duration>,
+        destination: SocketAddr,
+        data: &[u8],
+    ) {
+        for delay in delays {
+            self.sequence += 1;
+            self.pending.push(Reverse(Delayed {
+                due: now + delay,
+                sequence: self.sequence,
+                destination,
+                data: data.to_vec(),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcrawler_proto::{kademlia::Id160, krpc::QueryType};
+
+    use super::*;
+    use crate::{
+        node::{DhtNode, DhtResponse, NodeConfig, NodeEvent},
+        testutil::{FakeNode, ServingNode},
+        transport::SocketConfig,
+    };
+
+    #[test]
+    fn test_link() {
+        let mut perfect = Link::new(LinkConditions::default(), Some(1));
+        assert_eq!(perfect.transmit(), [Duration::ZERO]);
+
+        let conditions = LinkConditions {
+            latency: Latency::Uniform {
+                min: Duration::from_millis(10),
+                max: Duration::from_millis(20),
+            },
+            loss_rate: 0.25,
+            duplicate_rate: 0.1,
+            reorder_rate: 0.1,
+            reorder_delay: Duration::from_millis(100),
+        };
+        let mut link = Link::new(conditions, Some(1));
+        let sent = 10_000;
+        let (mut lost, mut copies, mut reordered) = (0, 0, 0);
+        for _ in 0..sent {
+            let delays = link.transmit();
+            if delays.is_empty() {
+                lost += 1;
+            }
+            copies += delays.len();
+            for delay in delays {
+                assert!(delay >= Duration::from_millis(10));
+                if delay > Duration::from_millis(20) {
+                    assert!(delay >= Duration::from_millis(110));
+                    reordered += 1;
+                }
+            }
+        }
+        let ratio = |count: usize, total: usize| count as f64 / total as f64;
+        assert!((ratio(lost, sent) - 0.25).abs() < 0.02);
+        let delivered = sent - lost;
+        assert!((ratio(copies - delivered, delivered) - 0.1).abs() < 0.02);
+        assert!((ratio(reordered, copies) - 0.1).abs() < 0.02);
+
+        // The same seed gives the same fates.
+        let mut a = Link::new(link.conditions().clone(), Some(7));
+        let mut b = Link::new(link.conditions().clone(), Some(7));
+        for _ in 0..100 {
+            assert_eq!(a.transmit(), b.transmit());
+        }
+
+        let mut exponential = Link::new(
+            LinkConditions {
+                latency: Latency::Exponential {
+                    min: Duration::from_millis(5),
+                    mean: Duration::from_millis(50),
+                },
+                ..LinkConditions::default()
+            },
+            Some(3),
+        );
+        let total: Duration = (0..10_000).map(|_| exponential.transmit()[0]).sum();
+        let mean = total / 10_000;
+        assert!(mean > Duration::from_millis(50) && mean < Duration::from_millis(60));
+    }
+
+    /// Answer the pings received until stopped.
+    fn answer_pings() -> ServingNode {
+        let id = Id160([9; 20]);
+        FakeNode::bind(id).unwrap().serve(move |query| {
+            let tid = query.get_transaction_id().clone();
+            match query.get_query() {
+                QueryType::Ping(_) => Some(DhtResponse::new_ping(tid, id).to_bencoded()),
+                _ => None,
+            }
+        })
+    }
+
+    fn node() -> DhtNode {
+        let mut config = NodeConfig::new(Id160([1; 20]));
+        config.socket = SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        config.poll_timeout = Duration::from_millis(10);
+        config.query_timeout = Duration::from_millis(500);
+        DhtNode::bind(config).unwrap()
+    }
+
+    /// Ping `destination` from `node`, returns the round-trip time if it answered.
+    fn ping(node: &mut DhtNode, destination: SocketAddr) -> Option<Duration> {
+        node.ping(destination).unwrap();
+        let mut events = Vec::new();
+        loop {
+            node.poll(&mut events).unwrap();
+            for event in events.drain(..) {
+                match event {
+                    NodeEvent::Response { rtt, .. } => return Some(rtt),
+                    NodeEvent::Timeout { .. } => return None,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_impaired_relay() {
+        let fake = answer_pings();
+        let destination_address = fake.address();
+        let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let mut node = node();
+
+        // 30ms each way.
+        let slow = LinkConditions {
+            latency: Latency::Fixed(Duration::from_millis(30)),
+            ..LinkConditions::default()
+        };
+        let relay = ImpairedRelay::spawn(
+            loopback,
+            destination_address,
+            Link::new(slow.clone(), Some(1)),
+            Link::new(slow, Some(2)),
+        )
+        .unwrap();
+        let rtt = ping(&mut node, relay.local_addr()).unwrap();
+        assert!(rtt >= Duration::from_millis(60));
+        drop(relay);
+
+        // Every reply is lost: the query times out.
+        let lossy = LinkConditions {
+            loss_rate: 1.0,
+            ..LinkConditions::default()
+        };
+        let relay = ImpairedRelay::spawn(
+            loopback,
+            destination_address,
+            Link::new(LinkConditions::default(), Some(1)),
+            Link::new(lossy, Some(2)),
+        )
+        .unwrap();
+        assert_eq!(ping(&mut node, relay.local_addr()), None);
+        drop(relay);
+        assert_eq!(fake.stop(), 2);
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcrawler_proto::kademlia::{Domain, Id160};
+
+use super::{CrawlEvent, Sink, read_node_list};
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"BCNODES\x01";
+// Magic of the archives of a private overlay, followed by the domain.
+const OVERLAY_ARCHIVE_MAGIC: &[u8; 8] = b"BCNODES\x02";
+const INDEX_MAGIC: &[u8; 8] = b"BCNIDX\0\x02";
+const INDEX_ENTRY_SIZE: usize = 28;
+
+/// Size of a record of a node archive, in bytes.
+pub const RECORD_SIZE: usize = 56;
+
+/// A node of a node archive.
+///
+/// A record is encoded on [`RECORD_SIZE`] bytes: the id, the IP address (IPv4 addresses as
+/// IPv4-mapped IPv6 ones), the port, the first and last times the node was seen (in seconds
+/// since the Unix epoch), the flags, and a reserved byte. Integers are big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeRecord {
+    /// Id of the node, all zeros if unknown (see [`NodeRecord::UNKNOWN_ID`]).
+    pub id: Id160,
+    /// Address of the node.
+    pub address: SocketAddr,
+    /// When the node was first seen, in seconds since the Unix epoch.
+    pub first_seen: u64,
+    /// When the node was last seen, in seconds since the Unix epoch.
+    pub last_seen: u64,
+    /// Flags of the node, see the associated constants.
+    pub flags: u8,
+}
+
+impl NodeRecord {
+    /// The id of the node is unknown, e.g. the record was converted from a text node list.
+    pub const UNKNOWN_ID: u8 = 1;
+
+    /// Encode the record.
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut data = [0; RECORD_SIZE];
+        data[..20].copy_from_slice(&self.id.0);
+        let ip = match self.address.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        data[20..36].copy_from_slice(&ip.octets());
+        data[36..38].copy_from_slice(&self.address.port().to_be_bytes());
+        data[38..46].copy_from_slice(&self.first_seen.to_be_bytes());
+        data[46..54].copy_from_slice(&self.last_seen.to_be_bytes());
+        data[54] = self.flags;
+        data
+    }
+
+    /// Decode a record.
+    pub fn decode(data: &[u8; RECORD_SIZE]) -> NodeRecord {
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&data[20..36]).unwrap());
+        let port = u16::from_be_bytes([data[36], data[37]]);
+        NodeRecord {
+            id: Id160(data[..20].try_into().unwrap()),
+            address: SocketAddr::new(IpAddr::V6(ip).to_canonical(), port),
+            first_seen: u64::from_be_bytes(data[38..46].try_into().unwrap()),
+            last_seen: u64::from_be_bytes(data[46..54].try_into().unwrap()),
+            flags: data[54],
+        }
+    }
+
+    // Merge another sighting of the node into the record.
+    fn merge(&mut self, other: &NodeRecord) {
+        self.first_seen = self.first_seen.min(other.first_seen);
+        if other.last_seen >= self.last_seen {
+            self.last_seen = other.last_seen;
+            if other.flags & NodeRecord::UNKNOWN_ID == 0 {
+                self.id = other.id;
+            }
+        }
+        if other.flags & NodeRecord::UNKNOWN_ID == 0 {
+            self.flags &= !NodeRecord::UNKNOWN_ID;
+        }
+    }
+}
+
+/// The nodes of a crawl, one record per address, in a compact binary file (see
+/// [`NodeRecord`]) replacing the text node list of [`NodeListSink`](super::NodeListSink).
+///
+/// Reading an archive dedupes its records by address, merging their times. Writing it also
+/// writes an index next to it (see [`NodeIndex`]).
+///
+/// The archive of the nodes of a private overlay records its domain in its header (see
+/// [`NodeArchive::domain`]), so that it is not mixed up with the nodes of the public DHT.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeArchive {
+    records: Vec<NodeRecord>,
+    by_address: HashMap<SocketAddr, usize>,
+    domain: Option<Domain>,
+}
+
+impl NodeArchive {
+    /// Create an empty archive.
+    pub fn new() -> NodeArchive {
+        NodeArchive::default()
+    }
+
+    /// Read the archive at `path`. A missing or empty file is an empty archive, a truncated
+    /// last record is skipped.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<NodeArchive> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(NodeArchive::new()),
+            Err(e) => return Err(e),
+        };
+        if file.metadata()?.len() == 0 {
+            return Ok(NodeArchive::new());
+        }
+        let mut reader = BufReader::new(file);
+        let mut archive = NodeArchive::new();
+        archive.domain = read_header(&mut reader)?;
+        let mut data = [0; RECORD_SIZE];
+        loop {
+            match reader.read_exact(&mut data) {
+                Ok(()) => archive.insert(NodeRecord::decode(&data)),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(archive)
+    }
+
+    /// Convert a text node list (see [`read_node_list`]) to an archive, the nodes seen at
+    /// `seen` (in seconds since the Unix epoch) with unknown ids.
+    pub fn from_node_list<P: AsRef<Path>>(path: P, seen: u64) -> io::Result<NodeArchive> {
+        let mut archive = NodeArchive::new();
+        for address in read_node_list(path)? {
+            archive.insert(NodeRecord {
+                id: Id160([0; 20]),
+                address,
+                first_seen: seen,
+                last_seen: seen,
+                flags: NodeRecord::UNKNOWN_ID,
+            });
+        }
+        Ok(archive)
+    }
+
+    /// Get the domain of the DHT the nodes are of, `None` for the public DHT.
+    pub fn domain(&self) -> Option<Domain> {
+        self.domain
+    }
+
+    /// Set the domain of the DHT the nodes are of, `None` for the public DHT.
+    pub fn set_domain(&mut self, domain: Option<Domain>) {
+        self.domain = domain;
+    }
+
+    /// Add a record, merged into the record of the same address if there is one.
+    ///
+    /// Returns `true` if the address is new.
+    pub fn insert(&mut self, record: NodeRecord) -> bool {
+        self.upsert(record).1
+    }
+
+    // Add a record, and return its position and if it is new.
+    fn upsert(&mut self, record: NodeRecord) -> (usize, bool) {
+        match self.by_address.get(&record.address) {
+            Some(&position) => {
+                self.records[position].merge(&record);
+                (position, false)
+            }
+            None => {
+                self.by_address.insert(record.address, self.records.len());
+                self.records.push(record);
+                (self.records.len() - 1, true)
+            }
+        }
+    }
+
+    /// Get the record of `address`.
+    pub fn get(&self, address: &SocketAddr) -> Option<&NodeRecord> {
+        self.by_address
+            .get(address)
+            .map(|&position| &self.records[position])
+    }
+
+    /// Get the records, in insertion order.
+    pub fn records(&self) -> &[NodeRecord] {
+        &self.records
+    }
+
+    /// Get the addresses of the nodes, in insertion order.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.records.iter().map(|record| record.address).collect()
+    }
+
+    /// Get the number of nodes.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Check if the archive is empty.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Write (or overwrite) the archive at `path`, and its index (see [`NodeIndex::path`]).
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        match self.domain {
+            Some(domain) => {
+                writer.write_all(OVERLAY_ARCHIVE_MAGIC)?;
+                writer.write_all(&domain.0.to_be_bytes())?;
+            }
+            None => writer.write_all(ARCHIVE_MAGIC)?,
+        }
+        for record in &self.records {
+            writer.write_all(&record.encode())?;
+        }
+        writer.flush()?;
+        self.write_index(path)
+    }
+
+    fn write_index<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut entries: Vec<(Id160, u64)> = (0..self.records.len())
+            .filter_map(|position| self.index_entry(position))
+            .collect();
+        entries.sort_unstable();
+        let mut writer = BufWriter::new(File::create(NodeIndex::path(path))?);
+        writer.write_all(INDEX_MAGIC)?;
+        write_index_entries(&mut writer, &entries)?;
+        writer.flush()
+    }
+
+    // Get the index entry of the record at `position`, `None` if its id is unknown.
+    fn index_entry(&self, position: usize) -> Option<(Id160, u64)> {
+        let record = &self.records[position];
+        (record.flags & NodeRecord::UNKNOWN_ID == 0).then_some((record.id, position as u64))
+    }
+}
+
+fn write_index_entries<W: Write>(writer: &mut W, entries: &[(Id160, u64)]) -> io::Result<()> {
+    for (id, position) in entries {
+        writer.write_all(&id.0)?;
+        writer.write_all(&position.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// Index of a node archive by node id, to find the records of a node without reading the
+/// whole archive.
+///
+/// The index is a file next to the archive (see [`NodeIndex::path`]): the ids of the records,
+/// each with the position of its record. Records of unknown ids are not indexed. The entries
+/// of the new records, or of the records whose id changed, are appended to it: the last entry
+/// of a position is the one read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeIndex {
+    entries: Vec<(Id160, u64)>,
+}
+
+impl NodeIndex {
+    /// Get the path of the index of the archive at `archive`: the same path with an `.idx`
+    /// extension added.
+    pub fn path<P: AsRef<Path>>(archive: P) -> PathBuf {
+        let mut path = archive.as_ref().as_os_str().to_owned();
+        path.push(".idx");
+        path.into()
+    }
+
+    /// Read the index of the archive at `archive`.
+    pub fn read<P: AsRef<Path>>(archive: P) -> io::Result<NodeIndex> {
+        let data = fs::read(NodeIndex::path(archive))?;
+        let entries = data
+            .strip_prefix(INDEX_MAGIC)
+            .filter(|entries| entries.len() % INDEX_ENTRY_SIZE == 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a node index"))?;
+        let mut ids = HashMap::new();
+        for entry in entries.chunks_exact(INDEX_ENTRY_SIZE) {
+            let id = Id160(entry[..20].try_into().unwrap());
+            ids.insert(u64::from_be_bytes(entry[20..].try_into().unwrap()), id);
+        }
+        let mut entries: Vec<(Id160, u64)> = ids
+            .into_iter()
+            .map(|(position, id)| (id, position))
+            .collect();
+        entries.sort_unstable();
+        Ok(NodeIndex { entries })
+    }
+
+    /// Get the positions of the records of the node `id` (one per address it was seen at).
+    pub fn lookup(&self, id: &Id160) -> Vec<u64> {
+        let start = self.entries.partition_point(|(entry, _)| entry < id);
+        self.entries[start..]
+            .iter()
+            .take_while(|(entry, _)| entry == id)
+            .map(|&(_, position)| position)
+            .collect()
+    }
+
+    /// Get the number of indexed records.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Read the record at `position` of the archive at `path`.
+pub fn read_node_record<P: AsRef<Path>>(path: P, position: u64) -> io::Result<NodeRecord> {
+    let mut file = File::open(path)?;
+    let domain = read_header(&mut file)?;
+    file.seek(SeekFrom::Start(record_offset(domain, position)))?;
+    let mut data = [0; RECORD_SIZE];
+    file.read_exact(&mut data)?;
+    Ok(NodeRecord::decode(&data))
+}
+
+// Read the header of an archive, and return its domain.
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Option<Domain>> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic == ARCHIVE_MAGIC {
+        return Ok(None);
+    }
+    if &magic != OVERLAY_ARCHIVE_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a node archive",
+        ));
+    }
+    let mut domain = [0; 8];
+    reader.read_exact(&mut domain)?;
+    Ok(Some(Domain(u64::from_be_bytes(domain))))
+}
+
+fn record_offset(domain: Option<Domain>, position: u64) -> u64 {
+    let header = ARCHIVE_MAGIC.len() as u64 + domain.map_or(0, |_| 8);
+    header + position * RECORD_SIZE as u64
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Writes the discovered nodes to a [`NodeArchive`], keeping the nodes of the previous runs.
+///
+/// The archive is compacted (deduped) when the sink is opened, if it has duplicated or
+/// truncated records. New nodes are then appended, and the last time the known ones were seen
+/// updated in place, on each flush. The index entries of the new nodes, and of the known ones
+/// whose id changed, are appended to the index.
+pub struct NodeArchiveSink {
+    file: File,
+    index: File,
+    archive: NodeArchive,
+    // Records written to the file.
+    written: usize,
+    // Known records changed since the last flush.
+    changed: Vec<usize>,
+    // Known records whose id changed since the last flush.
+    reindexed: Vec<usize>,
+}
+
+impl NodeArchiveSink {
+    /// Open the archive at `path` of the nodes of the public DHT, created if missing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<NodeArchiveSink> {
+        NodeArchiveSink::open_in(path, None)
+    }
+
+    /// Open the archive at `path` of the nodes of the DHT of `domain` (`None` for the public
+    /// DHT), created if missing. An archive of another DHT is an error.
+    pub fn open_in<P: AsRef<Path>>(path: P, domain: Option<Domain>) -> io::Result<NodeArchiveSink> {
+        let size = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let mut archive = NodeArchive::read(&path)?;
+        if size > 0 && archive.domain() != domain {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the node archive is of another DHT",
+            ));
+        }
+        archive.set_domain(domain);
+        // Rewritten only if missing, empty, or holding duplicated or truncated records.
+        if size != record_offset(domain, archive.len() as u64) {
+            archive.write(&path)?;
+        } else if NodeIndex::read(&path).is_err() {
+            archive.write_index(&path)?;
+        }
+        let file = OpenOptions::new().write(true).open(&path)?;
+        let index = OpenOptions::new()
+            .append(true)
+            .open(NodeIndex::path(&path))?;
+        Ok(NodeArchiveSink {
+            file,
+            index,
+            written: archive.len(),
+            archive,
+            changed: Vec::new(),
+            reindexed: Vec::new(),
+        })
+    }
+
+    /// Get the archive, with the nodes not flushed yet.
+    pub fn archive(&self) -> &NodeArchive {
+        &self.archive
+    }
+
+    /// Record that the node `id` was seen at `address`, at `seen` (in seconds since the Unix
+    /// epoch).
+    pub fn record(&mut self, id: Id160, address: SocketAddr, seen: u64) {
+        let indexed = self
+            .archive
+            .get(&address)
+            .map(|record| (record.id, record.flags));
+        let (position, _) = self.archive.upsert(NodeRecord {
+            id,
+            address,
+            first_seen: seen,
+            last_seen: seen,
+            flags: 0,
+        });
+        if position < self.written {
+            self.changed.push(position);
+            let record = &self.archive.records[position];
+            if indexed != Some((record.id, record.flags)) {
+                self.reindexed.push(position);
+            }
+        }
+    }
+}
+
+impl Sink for NodeArchiveSink {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        if let CrawlEvent::NodeDiscovered { id, address } = event {
+            self.record(*id, *address, unix_time(SystemTime::now()));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let records = self.archive.records();
+        if self.changed.is_empty() && self.written == records.len() {
+            return Ok(());
+        }
+        self.changed.sort_unstable();
+        self.changed.dedup();
+        for &position in &self.changed {
+            let offset = record_offset(self.archive.domain, position as u64);
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&records[position].encode())?;
+        }
+        self.changed.clear();
+        let offset = record_offset(self.archive.domain, self.written as u64);
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut writer = BufWriter::new(&self.file);
+        for record in &records[self.written..] {
+            writer.write_all(&record.encode())?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        self.reindexed.sort_unstable();
+        self.reindexed.dedup();
+        let entries: Vec<(Id160, u64)> = self
+            .reindexed
+            .drain(..)
+            .chain(self.written..records.len())
+            .filter_map(|position| self.archive.index_entry(position))
+            .collect();
+        let mut writer = BufWriter::new(&self.index);
+        write_index_entries(&mut writer, &entries)?;
+        writer.flush()?;
+        drop(writer);
+        self.written = records.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn test_node_archive() {
+        let dir = env::temp_dir();
+        let path = dir.join(format!("bitcrawler-archive-{}", process::id()));
+        let text = dir.join(format!("bitcrawler-archive-text-{}", process::id()));
+        let a: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let b: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let c: SocketAddr = "192.0.2.3:6881".parse().unwrap();
+        let id = |i: u8| Id160([i; 20]);
+
+        // The old text format is converted with unknown ids.
+        fs::write(&text, format!("{}\n{}\nnot an address\n{}\n", a, b, a)).unwrap();
+        let archive = NodeArchive::from_node_list(&text, 100).unwrap();
+        fs::remove_file(&text).unwrap();
+        assert_eq!(archive.addresses(), vec![a, b]);
+        archive.write(&path).unwrap();
+
+        let mut sink = NodeArchiveSink::open(&path).unwrap();
+        sink.record(id(1), a, 200);
+        sink.record(id(3), c, 200);
+        sink.flush().unwrap();
+        sink.record(id(3), c, 300);
+        sink.flush().unwrap();
+        drop(sink);
+
+        let archive = NodeArchive::read(&path).unwrap();
+        assert_eq!(archive.addresses(), vec![a, b, c]);
+        assert_eq!(
+            archive.get(&a),
+            Some(&NodeRecord {
+                id: id(1),
+                address: a,
+                first_seen: 100,
+                last_seen: 200,
+                flags: 0,
+            })
+        );
+        assert_eq!(archive.get(&b).unwrap().flags, NodeRecord::UNKNOWN_ID);
+        assert_eq!(archive.get(&c).unwrap().last_seen, 300);
+
+        let index = NodeIndex::read(&path).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.lookup(&id(3)), vec![2]);
+        assert_eq!(read_node_record(&path, 2).unwrap().address, c);
+        assert!(index.lookup(&id(2)).is_empty());
+
+        // A node seen with another id is indexed under the new one.
+        let mut sink = NodeArchiveSink::open(&path).unwrap();
+        sink.record(id(4), a, 400);
+        sink.flush().unwrap();
+        drop(sink);
+        let index = NodeIndex::read(&path).unwrap();
+        assert!(index.lookup(&id(1)).is_empty());
+        assert_eq!(index.lookup(&id(4)), vec![0]);
+        assert_eq!(index.lookup(&id(3)), vec![2]);
+        let archive = NodeArchive::read(&path).unwrap();
+        assert_eq!(archive.get(&a).unwrap().last_seen, 400);
+
+        // Duplicated records (e.g. concatenated archives) are merged on reload.
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(&archive.get(&c).unwrap().encode());
+        data.extend_from_slice(&[0; 10]);
+        fs::write(&path, data).unwrap();
+        assert_eq!(NodeArchive::read(&path).unwrap(), archive);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(NodeIndex::path(&path)).unwrap();
+        assert!(NodeArchive::read(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_overlay_archive() {
+        let path = env::temp_dir().join(format!("bitcrawler-overlay-{}", process::id()));
+        let a: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let domain = Some(Domain::named("lab"));
+
+        // An empty file is an empty archive, of any DHT.
+        fs::write(&path, b"").unwrap();
+        assert!(NodeArchive::read(&path).unwrap().is_empty());
+        let mut sink = NodeArchiveSink::open_in(&path, domain).unwrap();
+        sink.record(Id160([1; 20]), a, 100);
+        sink.flush().unwrap();
+        sink.record(Id160([1; 20]), a, 200);
+        sink.flush().unwrap();
+        drop(sink);
+        let archive = NodeArchive::read(&path).unwrap();
+        assert_eq!(archive.domain(), domain);
+        assert_eq!(archive.get(&a).unwrap().last_seen, 200);
+        assert_eq!(read_node_record(&path, 0).unwrap().address, a);
+
+        // The nodes of an overlay are not mixed with the nodes of the public DHT, or another
+        // overlay.
+        let error = NodeArchiveSink::open(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(NodeArchiveSink::open_in(&path, Some(Domain::named("other"))).is_err());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(NodeIndex::path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Destinations of the crawl output (and of what a honeypot observes).
+
+mod archive;
+mod node_list;
+mod queued;
+mod stream;
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use bitcrawler_proto::kademlia::Id160;
+
+use crate::{
+    crawler::{NodeCapabilities, PeerConfidence},
+    indexer::ScrapeFilter,
+    pipeline::QueueStats,
+    watchdog::Heartbeat,
+};
+
+pub use archive::*;
+pub use node_list::*;
+pub use queued::*;
+pub use stream::*;
+
+/// A peer returned in the `values` of a `get_peers` reply, with where and when it was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    /// Address of the peer.
+    pub addr: SocketAddr,
+    /// Id of the node that returned the peer.
+    pub source_node: Id160,
+    /// Info hash the peer was returned for.
+    pub info_hash: Id160,
+    /// Time the reply was received.
+    pub seen_at: SystemTime,
+    /// Whether the reply carried a token, i.e. the node accepts an `announce_peer` for the
+    /// info hash.
+    pub token_available: bool,
+    /// Corroboration of the peer when the reply was received, see
+    /// [`PeerCorroboration`](crate::crawler::PeerCorroboration).
+    pub confidence: PeerConfidence,
+}
+
+/// Something discovered by a crawl.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrawlEvent {
+    /// A node id was seen for the first time.
+    NodeDiscovered { id: Id160, address: SocketAddr },
+    /// A node returned peers for an info hash.
+    PeersFound {
+        info_hash: Id160,
+        source: SocketAddr,
+        peers: Vec<DiscoveredPeer>,
+    },
+    /// A node asked us for the peers of an info hash.
+    PeersRequested {
+        info_hash: Id160,
+        source: SocketAddr,
+    },
+    /// A node announced (with a valid token) that `peer` downloads an info hash.
+    PeerAnnounced {
+        info_hash: Id160,
+        source: SocketAddr,
+        peer: SocketAddr,
+    },
+    /// A node sent the Bloom filters of the seeds and peers of an info hash (BEP 33).
+    ScrapeReceived {
+        info_hash: Id160,
+        source: SocketAddr,
+        seeds: ScrapeFilter,
+        peers: ScrapeFilter,
+    },
+    /// A node was probed for the DHT extensions it supports, see
+    /// [`CapabilityProber`](crate::crawler::CapabilityProber).
+    CapabilitiesProbed {
+        id: Id160,
+        address: SocketAddr,
+        capabilities: NodeCapabilities,
+    },
+}
+
+/// Number of [`CrawlEvent`]s of each kind, e.g. handed to the sinks of a crawl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EventCounts {
+    /// [`CrawlEvent::NodeDiscovered`] events.
+    pub nodes_discovered: u64,
+    /// [`CrawlEvent::PeersFound`] events.
+    pub peers_found: u64,
+    /// [`CrawlEvent::PeersRequested`] events.
+    pub peers_requested: u64,
+    /// [`CrawlEvent::PeerAnnounced`] events.
+    pub peers_announced: u64,
+    /// [`CrawlEvent::ScrapeReceived`] events.
+    pub scrapes_received: u64,
+    /// [`CrawlEvent::CapabilitiesProbed`] events.
+    pub capabilities_probed: u64,
+}
+
+impl EventCounts {
+    /// Count `event`.
+    pub fn add(&mut self, event: &CrawlEvent) {
+        let count = match event {
+            CrawlEvent::NodeDiscovered { .. } => &mut self.nodes_discovered,
+            CrawlEvent::PeersFound { .. } => &mut self.peers_found,
+            CrawlEvent::PeersRequested { .. } => &mut self.peers_requested,
+            CrawlEvent::PeerAnnounced { .. } => &mut self.peers_announced,
+            CrawlEvent::ScrapeReceived { .. } => &mut self.scrapes_received,
+            CrawlEvent::CapabilitiesProbed { .. } => &mut self.capabilities_probed,
+        };
+        *count += 1;
+    }
+
+    /// Get the number of events of all kinds.
+    pub fn total(&self) -> u64 {
+        self.nodes_discovered
+            + self.peers_found
+            + self.peers_requested
+            + self.peers_announced
+            + self.scrapes_received
+            + self.capabilities_probed
+    }
+}
+
+/// Receives the [`CrawlEvent`]s of a crawl, e.g. to store them.
+///
+/// An error returned by a sink stops the crawl.
+pub trait Sink: Send {
+    /// Handle an event.
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()>;
+
+    /// Flush the buffered events, called periodically and when the crawl stops.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Get the counters of the queue in front of the sink, if it has one (see [`QueuedSink`]).
+    fn queue_stats(&self) -> Option<QueueStats> {
+        None
+    }
+
+    /// Get the heartbeat of the thread of the sink, if it has one (see [`QueuedSink`]).
+    fn heartbeat(&self) -> Option<Heartbeat> {
+        None
+    }
+}
+
+/// Collects the events in memory.
+impl Sink for Vec<CrawlEvent> {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        self.push(event.clone());
+        Ok(())
+    }
+}
+
+/// Shares a sink with other parts of the program (e.g. to read an [`Indexer`] while crawling).
+///
+/// [`Indexer`]: crate::indexer::Indexer
+impl<S: Sink> Sink for Arc<Mutex<S>> {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        self.lock().expect("sink lock poisoned").handle(event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().expect("sink lock poisoned").flush()
+    }
+
+    fn queue_stats(&self) -> Option<QueueStats> {
+        self.lock().expect("sink lock poisoned").queue_stats()
+    }
+
+    fn heartbeat(&self) -> Option<Heartbeat> {
+        self.lock().expect("sink lock poisoned").heartbeat()
+    }
+}
+
+/// A sink chosen at runtime, e.g. from the options of a program (see
+/// [`CrawlerHandle::replace_sinks`](crate::crawler::CrawlerHandle::replace_sinks)).
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        (**self).handle(event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn queue_stats(&self) -> Option<QueueStats> {
+        (**self).queue_stats()
+    }
+
+    fn heartbeat(&self) -> Option<Heartbeat> {
+        (**self).heartbeat()
+    }
+}
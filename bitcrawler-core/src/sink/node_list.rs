@@ -0,0 +1,84 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+};
+
+use super::{CrawlEvent, Sink};
+
+/// Writes the address of every discovered node to a text file, one `ip:port` per line.
+pub struct NodeListSink {
+    writer: BufWriter<File>,
+}
+
+impl NodeListSink {
+    /// Create (or truncate) the node list at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<NodeListSink> {
+        Ok(NodeListSink {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl Sink for NodeListSink {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        match event {
+            CrawlEvent::NodeDiscovered { address, .. } => writeln!(self.writer, "{}", address),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Read a node list written by [`NodeListSink`], skipping the invalid lines.
+///
+/// A missing file is an empty list.
+pub fn read_node_list<P: AsRef<Path>>(path: P) -> io::Result<Vec<SocketAddr>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut nodes = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(node) = line?.trim().parse() {
+            nodes.push(node);
+        }
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use bitcrawler_proto::kademlia::Id160;
+
+    use super::*;
+
+    #[test]
+    fn test_node_list_roundtrip() {
+        let path = env::temp_dir().join(format!("bitcrawler-node-list-{}", process::id()));
+        let address: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let mut sink = NodeListSink::create(&path).unwrap();
+        sink.handle(&CrawlEvent::NodeDiscovered {
+            id: Id160([1; 20]),
+            address,
+        })
+        .unwrap();
+        sink.flush().unwrap();
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap() + "not an address\n",
+        )
+        .unwrap();
+
+        assert_eq!(read_node_list(&path).unwrap(), vec![address]);
+        fs::remove_file(&path).unwrap();
+        assert!(read_node_list(&path).unwrap().is_empty());
+    }
+}
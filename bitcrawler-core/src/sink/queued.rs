@@ -0,0 +1,266 @@
+use std::{
+    io,
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, SyncSender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use super::{CrawlEvent, Sink};
+use crate::{
+    pipeline::{BoundedQueue, QueueConfig, QueueStats},
+    watchdog::Heartbeat,
+};
+
+/// Longest wait of the sink thread for a message, it beats its heartbeat in between.
+const IDLE_BEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Message {
+    Event(CrawlEvent),
+    // Flush the sink once the events queued before are handled, and report the result.
+    Flush(SyncSender<io::Result<()>>),
+}
+
+/// Runs a sink on its own thread, behind a bounded queue.
+///
+/// The crawl goes on while the sink writes its events, and a sink that falls behind fills the
+/// queue instead of holding the receive loop: the queue then blocks the crawl or drops events,
+/// as set by its [`OverflowPolicy`](crate::pipeline::OverflowPolicy), and its counters are
+/// reported in the snapshots of the crawl.
+///
+/// [`Sink::flush`] waits for the events queued before to be handled. An error of the sink is
+/// returned by the next call, and stops the thread. Dropping the sink handles the events left,
+/// and flushes the sink one last time.
+///
+/// The thread beats the [`Sink::heartbeat`] of the sink while it waits for events and after
+/// each of them, so that a [`Watchdog`](crate::watchdog::Watchdog) notices a blocked sink.
+pub struct QueuedSink {
+    queue: BoundedQueue<Message>,
+    // Error that stopped the thread, not reported yet.
+    error: Arc<Mutex<Option<io::Error>>>,
+    heartbeat: Heartbeat,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl QueuedSink {
+    /// Start the thread of `sink`.
+    pub fn spawn<S: Sink + 'static>(sink: S, config: QueueConfig) -> io::Result<QueuedSink> {
+        let queue = BoundedQueue::new(config);
+        let error = Arc::new(Mutex::new(None));
+        let heartbeat = Heartbeat::new("sink");
+        let thread = {
+            let queue = queue.clone();
+            let error = error.clone();
+            let heartbeat = heartbeat.clone();
+            thread::Builder::new()
+                .name("bitcrawler-sink".to_string())
+                .spawn(move || run_sink(sink, queue, error, heartbeat))?
+        };
+        Ok(QueuedSink {
+            queue,
+            error,
+            heartbeat,
+            thread: Some(thread),
+        })
+    }
+
+    /// Get the counters of the queue.
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Error returned once the thread stopped.
+    fn stopped(&self) -> io::Error {
+        self.error
+            .lock()
+            .expect("sink error lock poisoned")
+            .take()
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "sink thread stopped"))
+    }
+}
+
+impl Sink for QueuedSink {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        if !self.queue.push(Message::Event(event.clone())) {
+            return Err(self.stopped());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        if !self.queue.force_push(Message::Flush(sender)) {
+            return Err(self.stopped());
+        }
+        receiver.recv().unwrap_or_else(|_| Err(self.stopped()))
+    }
+
+    fn queue_stats(&self) -> Option<QueueStats> {
+        Some(self.stats())
+    }
+
+    fn heartbeat(&self) -> Option<Heartbeat> {
+        Some(self.heartbeat.clone())
+    }
+}
+
+impl Drop for QueuedSink {
+    fn drop(&mut self) {
+        self.queue.close();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Body of the sink thread: handle the queued messages until the queue is closed and empty.
+fn run_sink<S: Sink>(
+    mut sink: S,
+    queue: BoundedQueue<Message>,
+    error: Arc<Mutex<Option<io::Error>>>,
+    heartbeat: Heartbeat,
+) {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("sink").entered();
+    let result = loop {
+        let message = match queue.pop(Some(IDLE_BEAT_INTERVAL)) {
+            Some(message) => message,
+            // Closed and empty, unless the queue was closed right after the wait timed out.
+            None if queue.is_closed() => match queue.try_pop() {
+                Some(message) => message,
+                None => break sink.flush(),
+            },
+            None => {
+                heartbeat.beat();
+                continue;
+            }
+        };
+        heartbeat.beat();
+        match message {
+            Message::Event(event) => {
+                if let Err(e) = sink.handle(&event) {
+                    break Err(e);
+                }
+            }
+            Message::Flush(reply) => {
+                #[cfg(feature = "tracing")]
+                let _span = tracing::debug_span!("flush").entered();
+                match sink.flush() {
+                    Ok(()) => {
+                        reply.send(Ok(())).ok();
+                    }
+                    Err(e) => {
+                        // The caller gets this error, the following calls a broken pipe.
+                        reply.send(Err(e)).ok();
+                        break Ok(());
+                    }
+                }
+            }
+        }
+    };
+    heartbeat.stop();
+    if let Err(e) = result {
+        *error.lock().expect("sink error lock poisoned") = Some(e);
+    }
+    // Drop the messages left, so that the callers waiting for a flush are released.
+    queue.close();
+    while queue.try_pop().is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use bitcrawler_proto::kademlia::Id160;
+
+    use super::*;
+    use crate::pipeline::OverflowPolicy;
+
+    /// Shares the events it handles, after waiting for `gate` to open.
+    struct SlowSink {
+        gate: Arc<Mutex<()>>,
+        events: Arc<Mutex<Vec<CrawlEvent>>>,
+        flushes: Arc<Mutex<usize>>,
+    }
+
+    impl Sink for SlowSink {
+        fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+            let _gate = self.gate.lock().unwrap();
+            if event == &event_for(0xff) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.events.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            *self.flushes.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    fn event_for(i: u8) -> CrawlEvent {
+        CrawlEvent::NodeDiscovered {
+            id: Id160([i; 20]),
+            address: SocketAddr::from(([192, 0, 2, i], 6881)),
+        }
+    }
+
+    #[test]
+    fn test_slow_sink_drops() {
+        let gate = Arc::new(Mutex::new(()));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let flushes = Arc::new(Mutex::new(0));
+        let sink = SlowSink {
+            gate: gate.clone(),
+            events: events.clone(),
+            flushes: flushes.clone(),
+        };
+        let mut queued =
+            QueuedSink::spawn(sink, QueueConfig::new(2, OverflowPolicy::DropNewest)).unwrap();
+
+        // The sink is stuck on the first event: two more fit in the queue, the rest is dropped
+        // without holding the caller.
+        let closed = gate.lock().unwrap();
+        queued.handle(&event_for(0)).unwrap();
+        while queued.stats().depth > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for i in 1..6 {
+            queued.handle(&event_for(i)).unwrap();
+        }
+        let stats = queued.stats();
+        assert_eq!((stats.depth, stats.dropped, stats.pushed), (2, 3, 6));
+        drop(closed);
+
+        // A flush waits for the queued events.
+        queued.flush().unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![event_for(0), event_for(1), event_for(2)]
+        );
+        assert_eq!(queued.queue_stats().unwrap().depth, 0);
+        drop(queued);
+        assert_eq!(*flushes.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_sink_error_is_reported() {
+        let sink = SlowSink {
+            gate: Arc::default(),
+            events: Arc::default(),
+            flushes: Arc::default(),
+        };
+        let mut queued =
+            QueuedSink::spawn(sink, QueueConfig::new(4, OverflowPolicy::Block)).unwrap();
+        queued.handle(&event_for(0xff)).unwrap();
+        let error = queued.flush().unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+        assert_eq!(
+            queued.handle(&event_for(1)).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
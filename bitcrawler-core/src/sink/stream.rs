@@ -0,0 +1,190 @@
+use std::{
+    io,
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, SyncSender, TryRecvError},
+    },
+    task::{Context, Poll, Waker},
+};
+
+use super::{CrawlEvent, Sink};
+
+/// Default number of events an [`EventStream`] buffers before the crawl waits for its consumer.
+pub const DEFAULT_STREAM_CAPACITY: usize = 1024;
+
+/// Create a bounded stream of crawl events: add the [`StreamSink`] to a crawler (or a honeypot),
+/// and consume the [`EventStream`] from another thread or task.
+///
+/// When `capacity` events are waiting, the sink blocks until the consumer catches up, so the
+/// crawl goes at the pace of the consumer. Dropping the stream makes the sink fail, which stops
+/// the crawl.
+pub fn event_stream(capacity: usize) -> (StreamSink, EventStream) {
+    let (sender, receiver) = mpsc::sync_channel(capacity.max(1));
+    let waker = Arc::new(Mutex::new(None));
+    (
+        StreamSink {
+            sender,
+            waker: waker.clone(),
+        },
+        EventStream { receiver, waker },
+    )
+}
+
+/// The [`Sink`] end of an [`event_stream`].
+pub struct StreamSink {
+    sender: SyncSender<CrawlEvent>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl Sink for StreamSink {
+    fn handle(&mut self, event: &CrawlEvent) -> io::Result<()> {
+        self.sender
+            .send(event.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event stream dropped"))?;
+        if let Some(waker) = self
+            .waker
+            .lock()
+            .expect("stream waker lock poisoned")
+            .take()
+        {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StreamSink {
+    fn drop(&mut self) {
+        // Let a pending consumer see the end of the stream.
+        if let Some(waker) = self
+            .waker
+            .lock()
+            .expect("stream waker lock poisoned")
+            .take()
+        {
+            waker.wake();
+        }
+    }
+}
+
+/// The consuming end of an [`event_stream`].
+///
+/// The events can be read in a blocking way (the stream is an [`Iterator`]), or polled from an
+/// async task, as a `futures_core::Stream` with the `stream` feature. The stream ends once the
+/// crawl is over and its sinks are dropped.
+pub struct EventStream {
+    receiver: Receiver<CrawlEvent>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl EventStream {
+    /// Get the next event without blocking, `Pending` if none is available yet.
+    ///
+    /// `cx` is woken when the next event is sent (or the crawl is over).
+    pub fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<CrawlEvent>> {
+        match self.receiver.try_recv() {
+            Ok(event) => return Poll::Ready(Some(event)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        *self.waker.lock().expect("stream waker lock poisoned") = Some(cx.waker().clone());
+        // An event sent before the waker was registered would not wake us.
+        match self.receiver.try_recv() {
+            Ok(event) => Poll::Ready(Some(event)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = CrawlEvent;
+
+    /// Wait for the next event, `None` once the crawl is over.
+    fn next(&mut self) -> Option<CrawlEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+#[cfg(feature = "stream")]
+impl futures_core::Stream for EventStream {
+    type Item = CrawlEvent;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<CrawlEvent>> {
+        self.get_mut().poll_event(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Wake,
+        thread,
+        time::Duration,
+    };
+
+    use bitcrawler_proto::kademlia::Id160;
+
+    use super::*;
+
+    /// Counts its wake-ups.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn event(i: u8) -> CrawlEvent {
+        CrawlEvent::NodeDiscovered {
+            id: Id160([i; 20]),
+            address: SocketAddr::from(([192, 0, 2, i], 6881)),
+        }
+    }
+
+    #[test]
+    fn test_poll_and_wake() {
+        let (mut sink, mut stream) = event_stream(4);
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(stream.poll_event(&mut cx), Poll::Pending);
+        sink.handle(&event(1)).unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(stream.poll_event(&mut cx), Poll::Ready(Some(event(1))));
+
+        assert_eq!(stream.poll_event(&mut cx), Poll::Pending);
+        drop(sink);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+        assert_eq!(stream.poll_event(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let (mut sink, stream) = event_stream(2);
+        let producer = thread::spawn(move || {
+            for i in 0..10 {
+                sink.handle(&event(i)).unwrap();
+            }
+        });
+        // The producer is held back by the full buffer until the events are consumed.
+        thread::sleep(Duration::from_millis(50));
+        assert!(!producer.is_finished());
+        let events: Vec<CrawlEvent> = stream.collect();
+        producer.join().unwrap();
+        assert_eq!(events, (0..10).map(event).collect::<Vec<_>>());
+
+        // Without a consumer, the sink fails (and the crawl stops).
+        let (mut sink, stream) = event_stream(2);
+        drop(stream);
+        assert_eq!(
+            sink.handle(&event(0)).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
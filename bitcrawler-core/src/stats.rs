@@ -0,0 +1,327 @@
+//! Statistics of the crawls kept in a SQLite database, see [`StatsDatabase`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcrawler_proto::kademlia::Id160;
+use rusqlite::{Connection, params};
+
+use crate::crawler::CrawlSnapshot;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    node_id TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS minutes (
+    run INTEGER NOT NULL REFERENCES runs (id),
+    minute INTEGER NOT NULL,
+    queries_sent INTEGER NOT NULL,
+    bytes_sent INTEGER NOT NULL,
+    nodes_seen INTEGER NOT NULL,
+    malformed_datagrams INTEGER NOT NULL,
+    icmp_errors INTEGER NOT NULL,
+    frontier_depth INTEGER NOT NULL,
+    in_flight_queries INTEGER NOT NULL,
+    routing_table_nodes INTEGER NOT NULL,
+    queries_per_second REAL NOT NULL,
+    responses_per_second REAL NOT NULL,
+    PRIMARY KEY (run, minute)
+);
+CREATE TABLE IF NOT EXISTS client_versions (
+    run INTEGER NOT NULL REFERENCES runs (id),
+    minute INTEGER NOT NULL,
+    version TEXT NOT NULL,
+    queries INTEGER NOT NULL,
+    PRIMARY KEY (run, minute, version)
+);
+";
+
+/// The statistics of one minute of a run, see [`StatsDatabase::minutes`].
+///
+/// The counters are increments over the minute, the gauges (depths, sizes and rates) are the
+/// last values recorded during the minute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinuteRollup {
+    /// Start of the minute, in seconds since the Unix epoch.
+    pub minute: u64,
+    /// Queries sent during the minute.
+    pub queries_sent: u64,
+    /// Bytes sent (queries and replies) during the minute.
+    pub bytes_sent: u64,
+    /// Distinct node ids seen for the first time during the minute.
+    pub nodes_seen: u64,
+    /// Malformed datagrams received during the minute.
+    pub malformed_datagrams: u64,
+    /// ICMP errors reported during the minute.
+    pub icmp_errors: u64,
+    /// Number of discovered nodes waiting to be contacted.
+    pub frontier_depth: u64,
+    /// Number of queries waiting for an answer.
+    pub in_flight_queries: u64,
+    /// Number of nodes in the routing table.
+    pub routing_table_nodes: u64,
+    /// Queries sent per second, averaged over the last minute.
+    pub queries_per_second: f64,
+    /// Responses received per second, averaged over the last minute.
+    pub responses_per_second: f64,
+}
+
+/// Cumulative counters of the last snapshot recorded.
+#[derive(Debug, Clone, Copy, Default)]
+struct Counters {
+    queries_sent: u64,
+    bytes_sent: u64,
+    nodes_seen: u64,
+    malformed_datagrams: u64,
+    icmp_errors: u64,
+}
+
+impl Counters {
+    fn of(snapshot: &CrawlSnapshot) -> Counters {
+        Counters {
+            queries_sent: snapshot.traffic.queries_sent,
+            bytes_sent: snapshot.traffic.bytes_sent,
+            nodes_seen: snapshot.nodes_seen as u64,
+            malformed_datagrams: snapshot.malformed_datagrams,
+            icmp_errors: snapshot.icmp_errors,
+        }
+    }
+}
+
+/// Records the [`CrawlSnapshot`]s of crawls into a SQLite database, as per-minute rollups of
+/// the crawl metrics and of the [client versions](CrawlSnapshot::client_versions), so that the
+/// runs can be compared over weeks without a monitoring stack.
+///
+/// Each [`StatsDatabase::open`] starts a new run (table `runs`) in the database, created if
+/// needed. The snapshots recorded during a minute are summed into one row of the `minutes` and
+/// `client_versions` tables, so a snapshot may be recorded as often as wanted. The counters are
+/// stored as increments, so the rows of several runs add up, e.g. with SQLite:
+///
+/// ```sql
+/// SELECT date(minute, 'unixepoch') AS day, sum(queries_sent), sum(nodes_seen)
+/// FROM minutes GROUP BY day;
+/// ```
+pub struct StatsDatabase {
+    connection: Connection,
+    run: i64,
+    last: Counters,
+    last_versions: HashMap<String, u64>,
+}
+
+impl StatsDatabase {
+    /// Open (or create) the database at `path`, and start a run of the crawler `node_id`.
+    pub fn open<P: AsRef<Path>>(path: P, node_id: Id160) -> io::Result<StatsDatabase> {
+        let connection = Connection::open(path).map_err(io::Error::other)?;
+        connection.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        connection
+            .execute(
+                "INSERT INTO runs (started_at, node_id) VALUES (?1, ?2)",
+                params![unix_seconds(SystemTime::now()), node_id.to_string()],
+            )
+            .map_err(io::Error::other)?;
+        Ok(StatsDatabase {
+            run: connection.last_insert_rowid(),
+            connection,
+            last: Counters::default(),
+            last_versions: HashMap::new(),
+        })
+    }
+
+    /// Get the id of the run, in the `runs` table.
+    pub fn run(&self) -> i64 {
+        self.run
+    }
+
+    /// Record a snapshot of the crawl taken at `at`, into the rollup of its minute.
+    ///
+    /// The snapshots of a run must come from the same crawl: the increments are computed from
+    /// the previous snapshot recorded.
+    pub fn record(&mut self, snapshot: &CrawlSnapshot, at: SystemTime) -> io::Result<()> {
+        let minute = unix_seconds(at) / 60 * 60;
+        let counters = Counters::of(snapshot);
+        let last = self.last;
+        let transaction = self.connection.transaction().map_err(io::Error::other)?;
+        transaction
+            .execute(
+                "INSERT INTO minutes VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                 ON CONFLICT (run, minute) DO UPDATE SET
+                     queries_sent = queries_sent + excluded.queries_sent,
+                     bytes_sent = bytes_sent + excluded.bytes_sent,
+                     nodes_seen = nodes_seen + excluded.nodes_seen,
+                     malformed_datagrams = malformed_datagrams + excluded.malformed_datagrams,
+                     icmp_errors = icmp_errors + excluded.icmp_errors,
+                     frontier_depth = excluded.frontier_depth,
+                     in_flight_queries = excluded.in_flight_queries,
+                     routing_table_nodes = excluded.routing_table_nodes,
+                     queries_per_second = excluded.queries_per_second,
+                     responses_per_second = excluded.responses_per_second",
+                params![
+                    self.run,
+                    minute,
+                    increment(counters.queries_sent, last.queries_sent),
+                    increment(counters.bytes_sent, last.bytes_sent),
+                    increment(counters.nodes_seen, last.nodes_seen),
+                    increment(counters.malformed_datagrams, last.malformed_datagrams),
+                    increment(counters.icmp_errors, last.icmp_errors),
+                    snapshot.frontier_depth as i64,
+                    snapshot.in_flight_queries as i64,
+                    snapshot.routing_table_nodes as i64,
+                    snapshot.rates.queries_sent,
+                    snapshot.rates.responses_received,
+                ],
+            )
+            .map_err(io::Error::other)?;
+        for (version, &count) in &snapshot.client_versions {
+            let last = self.last_versions.get(version).copied().unwrap_or_default();
+            if count <= last {
+                continue;
+            }
+            transaction
+                .execute(
+                    "INSERT INTO client_versions VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (run, minute, version) DO UPDATE SET
+                         queries = queries + excluded.queries",
+                    params![self.run, minute, version, increment(count, last)],
+                )
+                .map_err(io::Error::other)?;
+        }
+        transaction.commit().map_err(io::Error::other)?;
+        self.last = counters;
+        self.last_versions.extend(
+            snapshot
+                .client_versions
+                .iter()
+                .map(|(version, count)| (version.clone(), *count)),
+        );
+        Ok(())
+    }
+
+    /// Get the rollups of the minutes of `run`, oldest first.
+    pub fn minutes(&self, run: i64) -> io::Result<Vec<MinuteRollup>> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT minute, queries_sent, bytes_sent, nodes_seen, malformed_datagrams,
+                        icmp_errors, frontier_depth, in_flight_queries, routing_table_nodes,
+                        queries_per_second, responses_per_second
+                 FROM minutes WHERE run = ?1 ORDER BY minute",
+            )
+            .map_err(io::Error::other)?;
+        let rows = statement
+            .query_map([run], |row| {
+                Ok(MinuteRollup {
+                    minute: row.get::<_, i64>(0)? as u64,
+                    queries_sent: row.get::<_, i64>(1)? as u64,
+                    bytes_sent: row.get::<_, i64>(2)? as u64,
+                    nodes_seen: row.get::<_, i64>(3)? as u64,
+                    malformed_datagrams: row.get::<_, i64>(4)? as u64,
+                    icmp_errors: row.get::<_, i64>(5)? as u64,
+                    frontier_depth: row.get::<_, i64>(6)? as u64,
+                    in_flight_queries: row.get::<_, i64>(7)? as u64,
+                    routing_table_nodes: row.get::<_, i64>(8)? as u64,
+                    queries_per_second: row.get(9)?,
+                    responses_per_second: row.get(10)?,
+                })
+            })
+            .map_err(io::Error::other)?;
+        rows.collect::<Result<_, _>>().map_err(io::Error::other)
+    }
+
+    /// Get the number of queries received per client version during `run`.
+    pub fn client_versions(&self, run: i64) -> io::Result<BTreeMap<String, u64>> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT version, sum(queries) FROM client_versions WHERE run = ?1
+                 GROUP BY version",
+            )
+            .map_err(io::Error::other)?;
+        let rows = statement
+            .query_map([run], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))
+            .map_err(io::Error::other)?;
+        rows.collect::<Result<_, _>>().map_err(io::Error::other)
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Increment of a cumulative counter, as stored by SQLite.
+fn increment(current: u64, last: u64) -> i64 {
+    current.saturating_sub(last) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_minute_rollups() {
+        let path = env::temp_dir().join(format!("bitcrawler-stats-{}.sqlite", process::id()));
+        let _ = fs::remove_file(&path);
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_040);
+        let mut snapshot = CrawlSnapshot::default();
+        let mut database = StatsDatabase::open(&path, Id160([1; 20])).unwrap();
+
+        // Two snapshots in the first minute, one in the next.
+        snapshot.traffic.queries_sent = 10;
+        snapshot.nodes_seen = 4;
+        snapshot.frontier_depth = 3;
+        snapshot.client_versions.insert("UT0001".to_string(), 2);
+        database.record(&snapshot, start).unwrap();
+        snapshot.traffic.queries_sent = 25;
+        snapshot.frontier_depth = 1;
+        snapshot.client_versions.insert("UT0001".to_string(), 5);
+        database
+            .record(&snapshot, start + Duration::from_secs(10))
+            .unwrap();
+        snapshot.traffic.queries_sent = 30;
+        snapshot.nodes_seen = 6;
+        snapshot.client_versions.insert("LT0102".to_string(), 1);
+        database
+            .record(&snapshot, start + Duration::from_secs(70))
+            .unwrap();
+
+        let minutes = database.minutes(database.run()).unwrap();
+        let summary: Vec<_> = minutes
+            .iter()
+            .map(|rollup| {
+                let counts = (rollup.queries_sent, rollup.nodes_seen);
+                (rollup.minute, counts, rollup.frontier_depth)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [(1_700_000_040, (25, 4), 1), (1_700_000_100, (5, 2), 1)]
+        );
+        let versions = database.client_versions(database.run()).unwrap();
+        assert_eq!(versions["UT0001"], 5);
+        assert_eq!(versions["LT0102"], 1);
+
+        // A second run of the same database starts from zero.
+        let run = database.run();
+        drop(database);
+        let mut database = StatsDatabase::open(&path, Id160([1; 20])).unwrap();
+        assert_ne!(database.run(), run);
+        database
+            .record(&snapshot, start + Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(
+            database.minutes(database.run()).unwrap()[0].queries_sent,
+            30
+        );
+        assert_eq!(database.minutes(run).unwrap().len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,542 @@
+//! Random valid KRPC messages, to fuzz the layers above the parser (routing table, lookups,
+//! sinks) and to check that the parsers and serializers agree across the whole message space.
+//!
+//! A [`MessageGenerator`] draws queries, replies and errors from an [`Rng`], within
+//! [`MessageConstraints`] (sizes, and the ids and info hashes to pick from). The same seed
+//! generates the same messages, so that a failure can be replayed.
+//!
+//! A [`FakeNode`] answers the queries sent to it on the loopback interface, for the tests of
+//! the layers that talk to the network (node, lookups, crawler).
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bitcrawler_proto::{
+    bencode::{self, BencodeString, BencodeValue},
+    consts::SCRAPE_FILTER_LEN,
+    kademlia::Id160,
+    krpc::{
+        ErrorCode, ErrorMessage, GetPeersShape, MessageOptions, Port, Query, QueryType, Response,
+        ResponseType,
+        node_info::BittorrentNodeInfoV4,
+        query::{AnnounceToken, QUERY_TYPE_SAMPLE_INFOHASHES},
+    },
+};
+
+use crate::rng::{Rng, SplitMix64};
+
+/// Replies generated by a [`MessageGenerator`]: IPv4 nodes and peers.
+pub type GeneratedResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+
+/// Bounds of the messages built by a [`MessageGenerator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageConstraints {
+    /// Longest transaction id, in bytes (the ids are at least 1 byte long).
+    pub max_transaction_id: usize,
+    /// Longest token of a `get_peers` reply or an `announce_peer` query, in bytes.
+    pub max_token: usize,
+    /// Largest number of nodes in a reply.
+    pub max_nodes: usize,
+    /// Largest number of peers in a `get_peers` reply.
+    pub max_peers: usize,
+    /// Longest message of an error, in bytes.
+    pub max_error_message: usize,
+    /// Node ids of the messages (the senders and the returned nodes), random if empty.
+    pub ids: Vec<Id160>,
+    /// Info hashes and targets of the queries, random if empty.
+    pub info_hashes: Vec<Id160>,
+}
+
+impl Default for MessageConstraints {
+    fn default() -> Self {
+        MessageConstraints {
+            max_transaction_id: 4,
+            max_token: 20,
+            max_nodes: 8,
+            max_peers: 50,
+            max_error_message: 32,
+            ids: Vec::new(),
+            info_hashes: Vec::new(),
+        }
+    }
+}
+
+/// A message built by a [`MessageGenerator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeneratedMessage {
+    Query(Query<Id160>),
+    Response(GeneratedResponse),
+    Error(ErrorMessage),
+}
+
+impl GeneratedMessage {
+    pub fn to_bencoded(&self) -> BencodeValue {
+        match self {
+            GeneratedMessage::Query(query) => query.to_bencoded(),
+            GeneratedMessage::Response(response) => response.to_bencoded(),
+            GeneratedMessage::Error(error) => error.to_bencoded(),
+        }
+    }
+
+    /// Parse back a message generated as `self`, with the parser of its kind (and of its
+    /// method for a reply, since a reply does not name it).
+    pub fn reparse(&self, bencoded: &BencodeValue) -> Result<GeneratedMessage, &'static str> {
+        Ok(match self {
+            GeneratedMessage::Query(_) => {
+                GeneratedMessage::Query(Query::try_from_bencoded(bencoded)?)
+            }
+            GeneratedMessage::Response(response) => {
+                GeneratedMessage::Response(match response.get_response_type() {
+                    ResponseType::Ping(_) => GeneratedResponse::try_from_ping_bencoded(bencoded)?,
+                    ResponseType::FindNode(_) => {
+                        GeneratedResponse::try_from_findpeer_bencoded(bencoded)?
+                    }
+                    ResponseType::GetPeers(_) => {
+                        GeneratedResponse::try_from_getpeers_bencoded(bencoded)?
+                    }
+                    ResponseType::Raw(_) => GeneratedResponse::try_from_raw_bencoded(bencoded)?,
+                })
+            }
+            GeneratedMessage::Error(_) => {
+                GeneratedMessage::Error(ErrorMessage::try_from_bencoded(bencoded)?)
+            }
+        })
+    }
+}
+
+/// Generates random KRPC messages that follow the protocol, see the [module](self) docs.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_core::{
+///     proto::{bencode, krpc::lint_datagram},
+///     rng::SplitMix64,
+///     testutil::{MessageConstraints, MessageGenerator},
+/// };
+///
+/// let mut generator =
+///     MessageGenerator::with_rng(MessageConstraints::default(), SplitMix64::with_seed(7));
+/// for _ in 0..100 {
+///     let message = generator.message();
+///     let datagram = bencode::encode(&message.to_bencoded());
+///     assert_eq!(lint_datagram(&datagram), Ok(()));
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MessageGenerator<R = SplitMix64> {
+    constraints: MessageConstraints,
+    rng: R,
+}
+
+impl MessageGenerator {
+    /// Create a generator of messages within `constraints`, drawing from a [`SplitMix64`]
+    /// seeded with `seed`, or with a random seed if `None`.
+    pub fn new(constraints: MessageConstraints, seed: Option<u64>) -> MessageGenerator {
+        MessageGenerator::with_rng(constraints, SplitMix64::from_seed(seed))
+    }
+}
+
+impl<R: Rng> MessageGenerator<R> {
+    /// Create a generator of messages within `constraints`, drawing from `rng`.
+    pub fn with_rng(constraints: MessageConstraints, rng: R) -> MessageGenerator<R> {
+        MessageGenerator { constraints, rng }
+    }
+
+    /// Generate a query, a reply or an error.
+    pub fn message(&mut self) -> GeneratedMessage {
+        match self.rng.below(3) {
+            0 => GeneratedMessage::Query(self.query()),
+            1 => GeneratedMessage::Response(self.response()),
+            _ => GeneratedMessage::Error(self.error()),
+        }
+    }
+
+    /// Generate a message, encoded as the bytes of a datagram.
+    pub fn datagram(&mut self) -> Vec<u8> {
+        bencode::encode(&self.message().to_bencoded())
+    }
+
+    /// Generate a query: one of the 4 methods of BEP 5, a scrape (BEP 33) or a
+    /// `sample_infohashes` query (BEP 51), with a client version and the read-only flag now
+    /// and then.
+    pub fn query(&mut self) -> Query<Id160> {
+        let transaction_id = self.transaction_id();
+        let id = self.id();
+        let info_hash = self.info_hash();
+        let query = match self.rng.below(6) {
+            0 => Query::new_ping(transaction_id, id),
+            1 => Query::new_find_node(transaction_id, id, info_hash),
+            2 => Query::new_get_peers(transaction_id, id, info_hash),
+            3 => Query::new_scrape(transaction_id, id, info_hash),
+            4 => {
+                let port = Port::new(self.rng.below(u64::from(u16::MAX)) as u16 + 1).unwrap();
+                let token = AnnounceToken::from_raw(self.bytes(1, self.constraints.max_token));
+                if self.flip() {
+                    Query::new_announce_peer_implied_port(
+                        transaction_id,
+                        id,
+                        info_hash,
+                        port,
+                        token,
+                    )
+                } else {
+                    Query::new_announce_peer(transaction_id, id, info_hash, port, token)
+                }
+            }
+            _ => Query::new(
+                transaction_id,
+                QueryType::Unknown {
+                    name: QUERY_TYPE_SAMPLE_INFOHASHES.into(),
+                    args: vec![
+                        ("id".into(), BencodeValue::ByteString(id.0.to_vec().into())),
+                        (
+                            "target".into(),
+                            BencodeValue::ByteString(info_hash.0.to_vec().into()),
+                        ),
+                    ],
+                },
+            ),
+        };
+        let options = MessageOptions {
+            version: self.flip().then(|| self.bytes(4, 4)),
+            read_only: self.flip(),
+            extra: Vec::new(),
+        };
+        query.with_options(options)
+    }
+
+    /// Generate a reply to a `ping`, `find_node` or `get_peers` query (in any of the
+    /// [`GetPeersShape`]s, with the Bloom filters of a scrape now and then), with the address
+    /// of the requester (BEP 42) now and then.
+    pub fn response(&mut self) -> GeneratedResponse {
+        let transaction_id = self.transaction_id();
+        let id = self.id();
+        let response = match self.rng.below(3) {
+            0 => GeneratedResponse::new_ping(transaction_id, id),
+            1 => {
+                let nodes = self.nodes();
+                GeneratedResponse::new_find_node(transaction_id, id, nodes)
+            }
+            _ => {
+                let token = self
+                    .flip()
+                    .then(|| self.bytes(1, self.constraints.max_token));
+                let nodes = self.nodes();
+                let count = self.rng.below(self.constraints.max_peers as u64 + 1) as usize;
+                let peers: Vec<_> = (0..count).map(|_| self.address()).collect();
+                // The fields may be sent even when empty.
+                let has_nodes = !nodes.is_empty() || self.flip();
+                let shape = GetPeersShape::of(has_nodes, !peers.is_empty() || self.flip());
+                let response = GeneratedResponse::new_get_peers(
+                    transaction_id.clone(),
+                    id,
+                    token,
+                    nodes,
+                    peers,
+                );
+                let ResponseType::GetPeers(mut get_peers) = response.get_response_type().clone()
+                else {
+                    unreachable!()
+                };
+                if let Some(shape) = shape {
+                    get_peers = get_peers
+                        .with_shape(shape)
+                        .expect("the shape holds the fields");
+                }
+                if self.rng.below(8) == 0 {
+                    let seeds = self.bytes(SCRAPE_FILTER_LEN, SCRAPE_FILTER_LEN);
+                    let peers = self.bytes(SCRAPE_FILTER_LEN, SCRAPE_FILTER_LEN);
+                    get_peers = get_peers.with_scrape_filters(seeds, peers);
+                }
+                GeneratedResponse::new(transaction_id, ResponseType::GetPeers(get_peers))
+            }
+        };
+        if self.flip() {
+            let requester = self.address();
+            response.with_requester(requester)
+        } else {
+            response
+        }
+    }
+
+    /// Generate an error, with a code of BEP 5 or another one.
+    pub fn error(&mut self) -> ErrorMessage {
+        let transaction_id = self.transaction_id();
+        let code = ErrorCode::from(200 + self.rng.below(100) as i64);
+        let length = self
+            .rng
+            .below(self.constraints.max_error_message as u64 + 1) as usize;
+        let message = (0..length)
+            .map(|_| char::from(b' ' + self.rng.below(95) as u8))
+            .collect();
+        ErrorMessage::new(transaction_id, code, message)
+    }
+
+    fn transaction_id(&mut self) -> BencodeString {
+        self.bytes(1, self.constraints.max_transaction_id.max(1))
+    }
+
+    fn id(&mut self) -> Id160 {
+        pick(&mut self.rng, &self.constraints.ids)
+    }
+
+    fn info_hash(&mut self) -> Id160 {
+        pick(&mut self.rng, &self.constraints.info_hashes)
+    }
+
+    fn nodes(&mut self) -> Vec<BittorrentNodeInfoV4<Id160>> {
+        let count = self.rng.below(self.constraints.max_nodes as u64 + 1) as usize;
+        (0..count)
+            .map(|_| {
+                let address = self.address();
+                BittorrentNodeInfoV4 {
+                    node_id: self.id(),
+                    ip: address.ip().octets(),
+                    port: address.port(),
+                }
+            })
+            .collect()
+    }
+
+    fn address(&mut self) -> SocketAddrV4 {
+        let ip = Ipv4Addr::from(self.rng.next_u32());
+        SocketAddrV4::new(ip, self.rng.below(u64::from(u16::MAX)) as u16 + 1)
+    }
+
+    /// Get between `min` and `max` random bytes.
+    fn bytes(&mut self, min: usize, max: usize) -> BencodeString {
+        let length = min + self.rng.below((max - min) as u64 + 1) as usize;
+        let mut bytes = vec![0; length];
+        self.rng.fill_bytes(&mut bytes);
+        bytes.into()
+    }
+
+    fn flip(&mut self) -> bool {
+        self.rng.below(2) == 0
+    }
+}
+
+/// Pick one of `ids`, or a random id if there is none.
+fn pick(rng: &mut impl Rng, ids: &[Id160]) -> Id160 {
+    match ids.len() {
+        0 => rng.random_id(),
+        len => ids[rng.below(len as u64) as usize],
+    }
+}
+
+/// A DHT node bound to the loopback interface, answering the queries it receives from a thread
+/// once [`FakeNode::serve`] is called.
+///
+/// The node is bound before it serves, so that the fake nodes of a test can be told about each
+/// other (see [`FakeNode::info`]). Until then, the datagrams can be exchanged one at a time
+/// (see [`FakeNode::recv_from`]), and a node that never serves is a silent one.
+#[derive(Debug)]
+pub struct FakeNode {
+    socket: UdpSocket,
+    id: Id160,
+}
+
+impl FakeNode {
+    /// Bind a node with the given id to an ephemeral loopback port.
+    pub fn bind(id: Id160) -> io::Result<FakeNode> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        // Wake up regularly to see if the node was stopped.
+        socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+        Ok(FakeNode { socket, id })
+    }
+
+    /// Get the id of the node.
+    pub fn id(&self) -> Id160 {
+        self.id
+    }
+
+    /// Get the address of the node.
+    pub fn address(&self) -> SocketAddr {
+        self.socket.local_addr().expect("bound socket")
+    }
+
+    /// Get the node as found in the `nodes` of a reply.
+    pub fn info(&self) -> BittorrentNodeInfoV4<Id160> {
+        BittorrentNodeInfoV4 {
+            node_id: self.id,
+            ip: Ipv4Addr::LOCALHOST.octets(),
+            port: self.address().port(),
+        }
+    }
+
+    /// Send a datagram to `destination`.
+    pub fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(data, destination).map(|_| ())
+    }
+
+    /// Receive a datagram, waiting up to `timeout` for it.
+    ///
+    /// Fails with [`io::ErrorKind::TimedOut`] if none was received in time.
+    pub fn recv_from(&self, timeout: Duration) -> io::Result<(Vec<u8>, SocketAddr)> {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0u8; 1500];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((size, source)) => return Ok((buffer[..size].to_vec(), source)),
+                Err(e)
+                    if !matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Err(e);
+                }
+                Err(_) if Instant::now() >= deadline => {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Receive a bencoded message, waiting up to `timeout` for it, see [`FakeNode::recv_from`].
+    pub fn receive_message(&self, timeout: Duration) -> io::Result<(BencodeValue, SocketAddr)> {
+        let (data, source) = self.recv_from(timeout)?;
+        let (_, message) = bencode::decode(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.message()))?;
+        Ok((message, source))
+    }
+
+    /// Answer each query received with the message built by `answer`, or not at all if it
+    /// returns `None`, until the node is stopped. The datagrams that are not queries are
+    /// ignored.
+    pub fn serve<F>(self, mut answer: F) -> ServingNode
+    where
+        F: FnMut(&Query<Id160>) -> Option<BencodeValue> + Send + 'static,
+    {
+        self.serve_messages(move |message| answer(&Query::try_from_bencoded(message).ok()?))
+    }
+
+    /// Like [`FakeNode::serve`], with the messages as received, e.g. to check the fields the
+    /// queries do not keep. The datagrams that are not bencoded are ignored.
+    pub fn serve_messages<F>(self, mut answer: F) -> ServingNode
+    where
+        F: FnMut(&BencodeValue) -> Option<BencodeValue> + Send + 'static,
+    {
+        let address = self.address();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                let mut buffer = [0u8; 1500];
+                let mut served = 0;
+                while running.load(Ordering::Relaxed) {
+                    let Ok((size, source)) = self.socket.recv_from(&mut buffer) else {
+                        continue;
+                    };
+                    let Ok((_, message)) = bencode::decode(&&buffer[..size]) else {
+                        continue;
+                    };
+                    if let Some(reply) = answer(&message) {
+                        let _ = self.socket.send_to(&bencode::encode(&reply), source);
+                        served += 1;
+                    }
+                }
+                served
+            })
+        };
+        ServingNode {
+            address,
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A [`FakeNode`] answering queries, it stops when dropped.
+#[derive(Debug)]
+pub struct ServingNode {
+    address: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<usize>>,
+}
+
+impl ServingNode {
+    /// Get the address of the node.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stop answering, returns the number of queries answered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if building an answer panicked.
+    pub fn stop(mut self) -> usize {
+        self.running.store(false, Ordering::Relaxed);
+        let thread = self.thread.take().expect("running node");
+        thread.join().expect("fake node panicked")
+    }
+}
+
+impl Drop for ServingNode {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcrawler_proto::krpc::lint_message;
+
+    use super::*;
+
+    #[test]
+    fn test_generated_messages_round_trip() {
+        let mut generator =
+            MessageGenerator::with_rng(MessageConstraints::default(), SplitMix64::with_seed(1));
+        let mut kinds = [0; 3];
+        for _ in 0..2000 {
+            let message = generator.message();
+            let bencoded = message.to_bencoded();
+            assert_eq!(lint_message(&bencoded), Ok(()), "{:?}", message);
+            let datagram = bencode::encode(&bencoded);
+            let (read, decoded) = bencode::decode(&datagram.as_slice()).unwrap();
+            assert_eq!(read, datagram.len());
+            assert_eq!(message.reparse(&decoded).as_ref(), Ok(&message));
+            kinds[match message {
+                GeneratedMessage::Query(_) => 0,
+                GeneratedMessage::Response(_) => 1,
+                GeneratedMessage::Error(_) => 2,
+            }] += 1;
+        }
+        assert!(kinds.iter().all(|&count| count > 500), "{:?}", kinds);
+
+        // The ids are picked from the constraints, and the same seed replays the messages.
+        let id = Id160([7; 20]);
+        let constraints = MessageConstraints {
+            max_nodes: 0,
+            ids: vec![id],
+            ..MessageConstraints::default()
+        };
+        let mut generator =
+            MessageGenerator::with_rng(constraints.clone(), SplitMix64::with_seed(2));
+        let mut replay = MessageGenerator::with_rng(constraints, SplitMix64::with_seed(2));
+        for _ in 0..100 {
+            let query = generator.query();
+            match query.get_query() {
+                QueryType::Ping(ping) => assert_eq!(ping.get_id(), &id),
+                QueryType::FindNode(find_node) => assert_eq!(find_node.get_id(), &id),
+                _ => {}
+            }
+            assert_eq!(replay.query(), query);
+        }
+    }
+}
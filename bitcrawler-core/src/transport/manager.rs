@@ -0,0 +1,433 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use super::{
+    DEFAULT_BATCH_SIZE, Receiver, SocketConfig, SocketError, SocketReport, bind_socket, platform,
+};
+use crate::{
+    pipeline::{BoundedQueue, QueueConfig, QueueStats},
+    watchdog::Heartbeat,
+};
+
+/// Maximum number of remote addresses remembered to route the replies, see
+/// [`SocketManager::reply_socket`].
+const MAX_ROUTES: usize = 1 << 16;
+
+/// The sockets of a node, with the traffic spread over them.
+///
+/// Several sockets (see [`SocketConfig::sockets`]) spread the queries over several source
+/// ports, so that the per-port rate limits of remote nodes apply to a fraction of the traffic
+/// each, and the receive load is shared by several buffers. The queries are sent from each
+/// socket in turn, and the replies to the queries of other nodes go out of the socket the query
+/// came in, which is what their sender expects.
+#[derive(Debug)]
+pub struct SocketManager {
+    sockets: Vec<UdpSocket>,
+    reports: Vec<SocketReport>,
+    // Drop counter of each socket, resolved on bind.
+    drop_counters: Vec<platform::DropCounter>,
+    next: usize,
+    // Socket on which each remote address was last heard, when there are several sockets.
+    routes: HashMap<SocketAddr, usize>,
+    read_timeout: Option<Duration>,
+    receive_thread: Option<ReceiveThread>,
+}
+
+/// A datagram received by the receive thread.
+#[derive(Debug)]
+struct Datagram {
+    data: Vec<u8>,
+    source: SocketAddr,
+    // Index of the socket it was received on.
+    socket: usize,
+}
+
+/// The thread draining the sockets into a queue, see [`SocketManager::spawn_receive_thread`].
+#[derive(Debug)]
+struct ReceiveThread {
+    queue: BoundedQueue<Datagram>,
+    // Error that stopped the thread.
+    error: Arc<Mutex<Option<io::Error>>>,
+    heartbeat: Heartbeat,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ReceiveThread {
+    fn drop(&mut self) {
+        // The thread notices the closed queue within the read timeout of the sockets.
+        self.queue.close();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl SocketManager {
+    /// Bind [`SocketConfig::sockets`] sockets (at least one).
+    ///
+    /// The first one is bound to [`SocketConfig::bind_address`], the following ones to the next
+    /// ports, or to ephemeral ports if the port of the bind address is 0.
+    pub fn bind(config: &SocketConfig) -> io::Result<SocketManager> {
+        let count = config.sockets.max(1);
+        let port = config.bind_address.port();
+        if port != 0 && port as usize + count - 1 > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not enough ports to spread the sockets over",
+            ));
+        }
+        let mut sockets = Vec::with_capacity(count);
+        let mut reports = Vec::with_capacity(count);
+        let mut drop_counters = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut config = config.clone();
+            if port != 0 {
+                config.bind_address.set_port(port + i as u16);
+            }
+            let (socket, report) = bind_socket(&config)?;
+            drop_counters.push(platform::drop_counter(&socket)?);
+            sockets.push(socket);
+            reports.push(report);
+        }
+        Ok(SocketManager {
+            sockets,
+            reports,
+            drop_counters,
+            next: 0,
+            routes: HashMap::new(),
+            read_timeout: None,
+            receive_thread: None,
+        })
+    }
+
+    /// Get the sockets, the first one is bound to the configured address.
+    pub fn sockets(&self) -> &[UdpSocket] {
+        &self.sockets
+    }
+
+    /// Get the settings applied to each socket on bind.
+    pub fn reports(&self) -> &[SocketReport] {
+        &self.reports
+    }
+
+    /// Set the time [`SocketManager::receive`] blocks waiting for a datagram.
+    ///
+    /// The sockets are read one after the other, so the timeout is shared among them.
+    pub fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.read_timeout = Some(timeout);
+        let timeout = (timeout / self.sockets.len() as u32).max(Duration::from_millis(1));
+        for socket in &self.sockets {
+            socket.set_read_timeout(Some(timeout))?;
+        }
+        Ok(())
+    }
+
+    /// Read the sockets from a dedicated thread, which queues the datagrams until
+    /// [`SocketManager::receive`] handles them.
+    ///
+    /// The sockets are then drained as fast as they fill, even while the datagrams already
+    /// received are decoded and handled, and the overflow is handled by the policy of the queue
+    /// (and counted, see [`SocketManager::receive_queue_stats`]) rather than by the kernel. The
+    /// thread stops when the manager is dropped, within the read timeout of the sockets
+    /// (100ms if none is set).
+    pub fn spawn_receive_thread(&mut self, config: QueueConfig) -> io::Result<()> {
+        if self.receive_thread.is_some() {
+            return Ok(());
+        }
+        if self.read_timeout.is_none() {
+            // Without a timeout, the thread would never notice that it must stop; `receive`
+            // still blocks until a datagram comes.
+            self.set_read_timeout(Duration::from_millis(100))?;
+            self.read_timeout = None;
+        }
+        let sockets = self
+            .sockets
+            .iter()
+            .map(UdpSocket::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+        let queue = BoundedQueue::with_size(config, |datagram: &Datagram| datagram.data.capacity());
+        let error = Arc::new(Mutex::new(None));
+        let heartbeat = Heartbeat::new("receive");
+        let thread = {
+            let queue = queue.clone();
+            let error = error.clone();
+            let heartbeat = heartbeat.clone();
+            thread::Builder::new()
+                .name("bitcrawler-receive".to_string())
+                .spawn(move || receive_loop(sockets, queue, error, heartbeat))?
+        };
+        self.receive_thread = Some(ReceiveThread {
+            queue,
+            error,
+            heartbeat,
+            thread: Some(thread),
+        });
+        Ok(())
+    }
+
+    /// Get the counters of the queue filled by the receive thread, if it runs.
+    pub fn receive_queue_stats(&self) -> Option<QueueStats> {
+        self.receive_thread
+            .as_ref()
+            .map(|receive_thread| receive_thread.queue.stats())
+    }
+
+    /// Get the heartbeat of the receive thread, if it runs: beaten on every pass over the
+    /// sockets, see [`Watchdog`](crate::watchdog::Watchdog).
+    pub fn receive_heartbeat(&self) -> Option<Heartbeat> {
+        self.receive_thread
+            .as_ref()
+            .map(|receive_thread| receive_thread.heartbeat.clone())
+    }
+
+    /// Get the socket the next query is sent from: each socket in turn.
+    pub fn query_socket(&mut self) -> &UdpSocket {
+        let index = self.next;
+        self.next = (self.next + 1) % self.sockets.len();
+        &self.sockets[index]
+    }
+
+    /// Get the socket a reply to `destination` is sent from: the one `destination` was last
+    /// heard on, or the first one.
+    pub fn reply_socket(&self, destination: SocketAddr) -> &UdpSocket {
+        let index = self.routes.get(&destination).copied().unwrap_or_default();
+        &self.sockets[index]
+    }
+
+    /// Receive the available datagrams of every socket, and pass each of them to `handler`.
+    ///
+    /// Returns the number of datagrams handled. If none was received, the error of the last
+    /// socket read (e.g. a timeout) is returned, as with [`Receiver::receive`].
+    ///
+    /// With a receive thread, the datagrams it queued are handled instead, waiting up to the
+    /// read timeout for the first one.
+    pub fn receive<F>(&mut self, receiver: &mut Receiver, mut handler: F) -> io::Result<usize>
+    where
+        F: FnMut(&[u8], SocketAddr),
+    {
+        let several = self.sockets.len() > 1;
+        if let Some(receive_thread) = &self.receive_thread {
+            let queue = &receive_thread.queue;
+            let Some(mut datagram) = queue.pop(self.read_timeout) else {
+                if let Some(error) = receive_thread.error.lock().expect("lock poisoned").take() {
+                    return Err(error);
+                }
+                if queue.is_closed() {
+                    return Err(io::Error::other("receive thread stopped"));
+                }
+                return Err(io::ErrorKind::TimedOut.into());
+            };
+            // Handle at most a queue worth of datagrams, the producer may keep filling it.
+            let capacity = queue.stats().capacity;
+            let mut received = 0;
+            loop {
+                if several {
+                    record_route(&mut self.routes, datagram.source, datagram.socket);
+                }
+                handler(&datagram.data, datagram.source);
+                received += 1;
+                if received >= capacity {
+                    break;
+                }
+                match queue.try_pop() {
+                    Some(next) => datagram = next,
+                    None => break,
+                }
+            }
+            return Ok(received);
+        }
+        if !several {
+            return receiver.receive(&self.sockets[0], handler);
+        }
+        let mut received = 0;
+        let mut last_error = None;
+        for (index, socket) in self.sockets.iter().enumerate() {
+            let routes = &mut self.routes;
+            let result = receiver.receive(socket, |data, source| {
+                record_route(routes, source, index);
+                handler(data, source);
+            });
+            match result {
+                Ok(count) => received += count,
+                Err(e) if is_transient(&e) => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        match last_error {
+            Some(error) if received == 0 => Err(error),
+            _ => Ok(received),
+        }
+    }
+
+    /// Get the number of datagrams dropped by the platform over all the sockets, see
+    /// [`dropped_datagrams`](super::dropped_datagrams).
+    pub fn dropped_datagrams(&self) -> io::Result<Option<u64>> {
+        let mut total = None;
+        for counter in &self.drop_counters {
+            if let Some(dropped) = platform::dropped_datagrams(counter)? {
+                *total.get_or_insert(0) += dropped;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Collect the asynchronous errors queued on every socket, see
+    /// [`drain_socket_errors`](super::drain_socket_errors).
+    pub fn drain_socket_errors(&self) -> io::Result<Vec<SocketError>> {
+        let mut errors = Vec::new();
+        for socket in &self.sockets {
+            errors.extend(platform::drain_socket_errors(socket)?);
+        }
+        Ok(errors)
+    }
+}
+
+/// Remember that `source` was heard on the socket at `index`.
+fn record_route(routes: &mut HashMap<SocketAddr, usize>, source: SocketAddr, index: usize) {
+    if routes.len() >= MAX_ROUTES && !routes.contains_key(&source) {
+        routes.clear();
+    }
+    routes.insert(source, index);
+}
+
+/// Check if a receive error is a timeout, or an ICMP error surfaced by IP_RECVERR, rather than a
+/// failure of the socket.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Body of the receive thread: read the sockets in turn until the queue is closed.
+fn receive_loop(
+    sockets: Vec<UdpSocket>,
+    queue: BoundedQueue<Datagram>,
+    error: Arc<Mutex<Option<io::Error>>>,
+    heartbeat: Heartbeat,
+) {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("receive", sockets = sockets.len()).entered();
+    let mut receiver = Receiver::new(DEFAULT_BATCH_SIZE);
+    while !queue.is_closed() {
+        heartbeat.beat();
+        for (index, socket) in sockets.iter().enumerate() {
+            let result = receiver.receive(socket, |data, source| {
+                queue.push(Datagram {
+                    data: data.to_vec(),
+                    source,
+                    socket: index,
+                });
+            });
+            match result {
+                Ok(_) => {}
+                Err(e) if is_transient(&e) => {}
+                Err(e) => {
+                    *error.lock().expect("lock poisoned") = Some(e);
+                    queue.close();
+                    heartbeat.stop();
+                    return;
+                }
+            }
+        }
+    }
+    heartbeat.stop();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcrawler_proto::kademlia::Id160;
+
+    use super::*;
+    use crate::{testutil::FakeNode, transport::DEFAULT_BATCH_SIZE};
+
+    #[test]
+    fn test_queries_spread_and_replies_routed() {
+        let mut config = SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+        config.sockets = 3;
+        let mut manager = SocketManager::bind(&config).unwrap();
+        manager.set_read_timeout(Duration::from_millis(30)).unwrap();
+        let ports: Vec<u16> = manager
+            .reports()
+            .iter()
+            .map(|report| report.local_address.port())
+            .collect();
+        assert_eq!(ports.len(), 3);
+
+        // The remote node sees the queries come from every port in turn.
+        let remote = FakeNode::bind(Id160([2; 20])).unwrap();
+        let remote_address = remote.address();
+        for i in 0..6 {
+            manager
+                .query_socket()
+                .send_to(b"query", remote_address)
+                .unwrap();
+            let (_, source) = remote.recv_from(Duration::from_secs(2)).unwrap();
+            assert_eq!(source.port(), ports[i % 3]);
+        }
+
+        // Datagrams are received on every socket, and replies go out of the socket the remote
+        // address was heard on.
+        remote
+            .send_to(b"hello", manager.reports()[2].local_address)
+            .unwrap();
+        let mut receiver = Receiver::new(DEFAULT_BATCH_SIZE);
+        let mut sources = Vec::new();
+        while sources.is_empty() {
+            manager
+                .receive(&mut receiver, |_, source| sources.push(source))
+                .ok();
+        }
+        assert_eq!(sources, vec![remote_address]);
+        manager
+            .reply_socket(remote_address)
+            .send_to(b"reply", remote_address)
+            .unwrap();
+        let (_, source) = remote.recv_from(Duration::from_secs(2)).unwrap();
+        assert_eq!(source.port(), ports[2]);
+        // Unknown addresses are answered from the first socket.
+        let unknown = (Ipv4Addr::new(192, 0, 2, 1), 6881).into();
+        assert_eq!(
+            manager.reply_socket(unknown).local_addr().unwrap().port(),
+            ports[0]
+        );
+    }
+
+    #[test]
+    fn test_consecutive_ports() {
+        // Find a free port, the next ones are very likely free too.
+        let probe = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+        if port > u16::MAX - 2 {
+            return;
+        }
+        let mut config = SocketConfig::new((Ipv4Addr::LOCALHOST, port).into());
+        config.sockets = 2;
+        if let Ok(manager) = SocketManager::bind(&config) {
+            let ports: Vec<u16> = manager
+                .reports()
+                .iter()
+                .map(|report| report.local_address.port())
+                .collect();
+            assert_eq!(ports, vec![port, port + 1]);
+        }
+
+        config.bind_address.set_port(u16::MAX);
+        assert_eq!(
+            SocketManager::bind(&config).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
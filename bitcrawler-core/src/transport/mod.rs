@@ -0,0 +1,12 @@
+mod manager;
+mod platform;
+mod receive;
+mod send;
+mod socket;
+mod wiretap;
+
+pub use manager::*;
+pub use receive::*;
+pub use send::*;
+pub use socket::*;
+pub use wiretap::*;
@@ -0,0 +1,108 @@
+//! Generic implementation, used on macOS and the BSDs.
+
+use std::{io, net::UdpSocket};
+
+use socket2::Socket;
+
+use super::super::{SocketConfig, SocketError};
+
+/// Smallest buffer size tried when the platform rejects the requested one.
+const MIN_BUFFER_SIZE: usize = 64 << 10;
+
+pub(in super::super) fn before_bind(
+    _socket: &Socket,
+    config: &SocketConfig,
+    _applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    if config.device.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to a network interface is not supported on this platform",
+        ));
+    }
+    Ok(())
+}
+
+pub(in super::super) fn after_bind(
+    _socket: &Socket,
+    _config: &SocketConfig,
+    _applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    Ok(())
+}
+
+pub(in super::super) fn set_recv_buffer_size(
+    socket: &Socket,
+    size: usize,
+    _applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    set_buffer_size(size, |size| socket.set_recv_buffer_size(size))
+}
+
+pub(in super::super) fn set_send_buffer_size(socket: &Socket, size: usize) -> io::Result<()> {
+    set_buffer_size(size, |size| socket.set_send_buffer_size(size))
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+))]
+pub(in super::super) fn set_traffic_class_v6(
+    socket: &Socket,
+    traffic_class: u32,
+) -> io::Result<()> {
+    socket.set_tclass_v6(traffic_class)
+}
+
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+)))]
+pub(in super::super) fn set_traffic_class_v6(
+    _socket: &Socket,
+    _traffic_class: u32,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "marking IPv6 datagrams is not supported on this platform",
+    ))
+}
+
+/// The platform does not expose a per-socket drop counter.
+#[derive(Debug)]
+pub(in super::super) struct DropCounter;
+
+pub(in super::super) fn drop_counter(_socket: &UdpSocket) -> io::Result<DropCounter> {
+    Ok(DropCounter)
+}
+
+pub(in super::super) fn dropped_datagrams(_counter: &DropCounter) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+pub(in super::super) fn drain_socket_errors(_socket: &UdpSocket) -> io::Result<Vec<SocketError>> {
+    Ok(Vec::new())
+}
+
+/// Unlike Linux, macOS and the BSDs reject buffers above `kern.ipc.maxsockbuf` (`ENOBUFS`)
+/// instead of capping them, so halve the request until it is accepted.
+fn set_buffer_size<F>(mut size: usize, set: F) -> io::Result<()>
+where
+    F: Fn(usize) -> io::Result<()>,
+{
+    loop {
+        match set(size) {
+            Ok(()) => return Ok(()),
+            Err(_) if size / 2 >= MIN_BUFFER_SIZE => size /= 2,
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    fs, io,
+    mem::{self, MaybeUninit},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::fd::{AsRawFd, RawFd},
+};
+
+use socket2::Socket;
+
+use super::super::{SocketConfig, SocketError};
+
+pub(in super::super) fn before_bind(
+    socket: &Socket,
+    config: &SocketConfig,
+    applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    if let Some(device) = &config.device {
+        socket.bind_device(Some(device.as_bytes()))?;
+        applied.push("SO_BINDTODEVICE");
+    }
+    Ok(())
+}
+
+pub(in super::super) fn after_bind(
+    socket: &Socket,
+    config: &SocketConfig,
+    applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    if config.report_icmp_errors {
+        if config.bind_address.is_ipv4() {
+            setsockopt_int(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_RECVERR, 1)?;
+            applied.push("IP_RECVERR");
+        } else {
+            setsockopt_int(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_RECVERR,
+                1,
+            )?;
+            applied.push("IPV6_RECVERR");
+        }
+    }
+    Ok(())
+}
+
+pub(in super::super) fn set_recv_buffer_size(
+    socket: &Socket,
+    size: usize,
+    applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    socket.set_recv_buffer_size(size)?;
+    // Linux silently caps SO_RCVBUF to net.core.rmem_max (and reports twice the value),
+    // privileged processes can go past the cap with SO_RCVBUFFORCE.
+    if socket.recv_buffer_size()? / 2 < size {
+        let size = size.min(i32::MAX as usize) as libc::c_int;
+        if setsockopt_int(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUFFORCE,
+            size,
+        )
+        .is_ok()
+        {
+            applied.push("SO_RCVBUFFORCE");
+        }
+    }
+    Ok(())
+}
+
+pub(in super::super) fn set_send_buffer_size(socket: &Socket, size: usize) -> io::Result<()> {
+    socket.set_send_buffer_size(size)
+}
+
+pub(in super::super) fn set_traffic_class_v6(
+    socket: &Socket,
+    traffic_class: u32,
+) -> io::Result<()> {
+    socket.set_tclass_v6(traffic_class)
+}
+
+/// Where to read the per-socket `drops` counter: the line of `/proc/net/udp` (or `udp6`) with
+/// the inode of the socket.
+#[derive(Debug)]
+pub(in super::super) struct DropCounter {
+    // None if the socket has no inode.
+    inode: Option<String>,
+    table: &'static str,
+}
+
+/// Resolve the inode of the socket, once, to read its drop counter later.
+pub(in super::super) fn drop_counter(socket: &UdpSocket) -> io::Result<DropCounter> {
+    let link = fs::read_link(format!("/proc/self/fd/{}", socket.as_raw_fd()))?;
+    let inode = link
+        .to_str()
+        .and_then(|link| link.strip_prefix("socket:["))
+        .and_then(|link| link.strip_suffix(']'))
+        .map(str::to_string);
+    let table = if socket.local_addr()?.is_ipv4() {
+        "/proc/net/udp"
+    } else {
+        "/proc/net/udp6"
+    };
+    Ok(DropCounter { inode, table })
+}
+
+/// Read the `drops` counter of a socket from `/proc/net/udp` (or `udp6`), matched by inode.
+pub(in super::super) fn dropped_datagrams(counter: &DropCounter) -> io::Result<Option<u64>> {
+    let Some(inode) = &counter.inode else {
+        return Ok(None);
+    };
+    let content = match fs::read_to_string(counter.table) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // Columns: sl local rem st tx:rx tr:when retrnsmt uid timeout inode ref pointer drops
+    for line in content.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() >= 13 && fields[9] == *inode {
+            return Ok(fields[12].parse().ok());
+        }
+    }
+    Ok(None)
+}
+
+/// Drain the socket error queue (`MSG_ERRQUEUE`), filled when `IP_RECVERR` is enabled.
+pub(in super::super) fn drain_socket_errors(socket: &UdpSocket) -> io::Result<Vec<SocketError>> {
+    let fd = socket.as_raw_fd();
+    let mut errors = Vec::new();
+    loop {
+        let mut name = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+        // The original payload is returned too, we only need the headers.
+        let mut payload = [0u8; 64];
+        let mut control = [0u8; 256];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr().cast(),
+            iov_len: payload.len(),
+        };
+        // SAFETY: msghdr is a plain C struct, all-zero is a valid (empty) value.
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = name.as_mut_ptr().cast();
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+
+        // SAFETY: every buffer referenced by msg outlives the call.
+        let read = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if read < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock {
+                break;
+            }
+            return Err(error);
+        }
+
+        // SAFETY: recvmsg filled `name` with `msg_namelen` bytes.
+        let destination = match unsafe { sockaddr_to_std(name.as_ptr(), msg.msg_namelen) } {
+            Some(destination) => destination,
+            None => continue,
+        };
+        // SAFETY: the control buffer was filled by recvmsg and the CMSG macros bound-check it.
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let header = &*cmsg;
+                if (header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_RECVERR)
+                    || (header.cmsg_level == libc::IPPROTO_IPV6
+                        && header.cmsg_type == libc::IPV6_RECVERR)
+                {
+                    let extended: libc::sock_extended_err =
+                        std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                    errors.push(SocketError {
+                        destination,
+                        error: io::Error::from_raw_os_error(extended.ee_errno as i32),
+                    });
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+    }
+    Ok(errors)
+}
+
+fn setsockopt_int(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: the option value is a valid c_int for the duration of the call.
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Convert a raw socket address into a std one.
+///
+/// # Safety
+///
+/// `storage` must point to an initialized socket address of at least `length` bytes.
+pub(in super::super) unsafe fn sockaddr_to_std(
+    storage: *const libc::sockaddr_storage,
+    length: libc::socklen_t,
+) -> Option<SocketAddr> {
+    let family = unsafe { (*storage).ss_family } as libc::c_int;
+    if family == libc::AF_INET && length as usize >= mem::size_of::<libc::sockaddr_in>() {
+        let address = unsafe { &*storage.cast::<libc::sockaddr_in>() };
+        Some(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+            u16::from_be(address.sin_port),
+        )))
+    } else if family == libc::AF_INET6 && length as usize >= mem::size_of::<libc::sockaddr_in6>() {
+        let address = unsafe { &*storage.cast::<libc::sockaddr_in6>() };
+        Some(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(address.sin6_addr.s6_addr),
+            u16::from_be(address.sin6_port),
+            address.sin6_flowinfo,
+            address.sin6_scope_id,
+        )))
+    } else {
+        None
+    }
+}
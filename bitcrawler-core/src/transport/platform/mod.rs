@@ -0,0 +1,18 @@
+//! Platform-specific socket tuning.
+//!
+//! Every platform module exposes the same set of functions, used by [`super::socket`].
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub(super) use linux::*;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub(super) use windows::*;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod fallback;
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(super) use fallback::*;
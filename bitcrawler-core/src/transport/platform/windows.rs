@@ -0,0 +1,110 @@
+use std::{io, mem, net::UdpSocket, os::windows::io::AsRawSocket, ptr};
+
+use socket2::Socket;
+use windows_sys::Win32::Networking::WinSock::{
+    SIO_UDP_CONNRESET, SO_EXCLUSIVEADDRUSE, SOCKET, SOCKET_ERROR, SOL_SOCKET, WSAIoctl, setsockopt,
+};
+
+use super::super::{SocketConfig, SocketError};
+
+pub(in super::super) fn before_bind(
+    socket: &Socket,
+    config: &SocketConfig,
+    applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    if config.device.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to a network interface is not supported on this platform",
+        ));
+    }
+    // Without it, another process may bind the same port with SO_REUSEADDR and steal datagrams.
+    let value: i32 = 1;
+    // SAFETY: the option value is a valid i32 for the duration of the call.
+    let result = unsafe {
+        setsockopt(
+            socket.as_raw_socket() as SOCKET,
+            SOL_SOCKET,
+            SO_EXCLUSIVEADDRUSE,
+            (&value as *const i32).cast(),
+            mem::size_of::<i32>() as i32,
+        )
+    };
+    if result == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    applied.push("SO_EXCLUSIVEADDRUSE");
+    Ok(())
+}
+
+pub(in super::super) fn after_bind(
+    socket: &Socket,
+    config: &SocketConfig,
+    applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    if config.report_icmp_errors {
+        return Ok(());
+    }
+    // By default, an ICMP port unreachable makes the next recv_from fail with WSAECONNRESET,
+    // which unconnected DHT sockets have no use for.
+    let enabled: u32 = 0;
+    let mut returned: u32 = 0;
+    // SAFETY: the input buffer and the returned length are valid for the duration of the call.
+    let result = unsafe {
+        WSAIoctl(
+            socket.as_raw_socket() as SOCKET,
+            SIO_UDP_CONNRESET,
+            (&enabled as *const u32).cast(),
+            mem::size_of::<u32>() as u32,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+            None,
+        )
+    };
+    if result == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    applied.push("SIO_UDP_CONNRESET");
+    Ok(())
+}
+
+pub(in super::super) fn set_recv_buffer_size(
+    socket: &Socket,
+    size: usize,
+    _applied: &mut Vec<&'static str>,
+) -> io::Result<()> {
+    socket.set_recv_buffer_size(size)
+}
+
+pub(in super::super) fn set_send_buffer_size(socket: &Socket, size: usize) -> io::Result<()> {
+    socket.set_send_buffer_size(size)
+}
+
+/// Windows leaves the marking of IPv6 datagrams to its QoS APIs, `IPV6_TCLASS` cannot be set.
+pub(in super::super) fn set_traffic_class_v6(
+    _socket: &Socket,
+    _traffic_class: u32,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "marking IPv6 datagrams is not supported on this platform",
+    ))
+}
+
+/// The platform does not expose a per-socket drop counter.
+#[derive(Debug)]
+pub(in super::super) struct DropCounter;
+
+pub(in super::super) fn drop_counter(_socket: &UdpSocket) -> io::Result<DropCounter> {
+    Ok(DropCounter)
+}
+
+pub(in super::super) fn dropped_datagrams(_counter: &DropCounter) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+pub(in super::super) fn drain_socket_errors(_socket: &UdpSocket) -> io::Result<Vec<SocketError>> {
+    Ok(Vec::new())
+}
@@ -0,0 +1,218 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+/// Default size of a receive buffer, enough for any well-behaved DHT message.
+pub const DEFAULT_DATAGRAM_SIZE: usize = 2048;
+/// Default number of datagrams pulled per call by a batched [`Receiver`].
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// Receives datagrams from a UDP socket into reusable buffers.
+///
+/// With the `recvmmsg` feature on Linux, each call to [`Receiver::receive`] pulls up to
+/// `batch_size` datagrams with a single `recvmmsg` syscall. Otherwise, it falls back to a single
+/// `recv_from` per call. Either way, the call blocks (up to the socket read timeout) until at
+/// least one datagram is available, then hands every datagram to the callback.
+pub struct Receiver {
+    #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+    inner: batch::BatchReceiver,
+    #[cfg(not(all(feature = "recvmmsg", target_os = "linux")))]
+    buffer: Vec<u8>,
+}
+
+impl Receiver {
+    /// Create a receiver for datagrams up to [`DEFAULT_DATAGRAM_SIZE`] bytes.
+    pub fn new(batch_size: usize) -> Receiver {
+        Receiver::with_datagram_size(batch_size, DEFAULT_DATAGRAM_SIZE)
+    }
+
+    /// Create a receiver for datagrams up to `datagram_size` bytes.
+    ///
+    /// Larger datagrams are truncated by the platform and skipped by the batched path.
+    pub fn with_datagram_size(batch_size: usize, datagram_size: usize) -> Receiver {
+        // The fallback path receives a single datagram at a time.
+        #[cfg(not(all(feature = "recvmmsg", target_os = "linux")))]
+        let _ = batch_size;
+        Receiver {
+            #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+            inner: batch::BatchReceiver::new(batch_size.max(1), datagram_size),
+            #[cfg(not(all(feature = "recvmmsg", target_os = "linux")))]
+            buffer: vec![0; datagram_size],
+        }
+    }
+
+    /// Check if datagrams are received in batches (one syscall for several datagrams).
+    pub fn is_batched() -> bool {
+        cfg!(all(feature = "recvmmsg", target_os = "linux"))
+    }
+
+    /// Receive the available datagrams and pass each of them to `handler`.
+    ///
+    /// Returns the number of datagrams handled. A read timeout is reported as an error of kind
+    /// `WouldBlock` or `TimedOut`, as with `UdpSocket::recv_from`.
+    pub fn receive<F>(&mut self, socket: &UdpSocket, handler: F) -> io::Result<usize>
+    where
+        F: FnMut(&[u8], SocketAddr),
+    {
+        #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+        {
+            self.inner.receive(socket, handler)
+        }
+        #[cfg(not(all(feature = "recvmmsg", target_os = "linux")))]
+        {
+            let mut handler = handler;
+            let (size, source) = socket.recv_from(&mut self.buffer)?;
+            handler(&self.buffer[..size], source);
+            Ok(1)
+        }
+    }
+}
+
+#[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+mod batch {
+    use std::{
+        io, mem,
+        net::{SocketAddr, UdpSocket},
+        os::fd::AsRawFd,
+        ptr,
+    };
+
+    use super::super::platform::sockaddr_to_std;
+
+    /// `recvmmsg` based receiver with a preallocated buffer pool.
+    pub(super) struct BatchReceiver {
+        datagram_size: usize,
+        buffers: Vec<u8>,
+        names: Vec<libc::sockaddr_storage>,
+        iovecs: Vec<libc::iovec>,
+        headers: Vec<libc::mmsghdr>,
+    }
+
+    // SAFETY: the raw pointers held by `iovecs` and `headers` only point into the heap buffers
+    // owned by the receiver itself, and are rewritten before every syscall.
+    unsafe impl Send for BatchReceiver {}
+
+    impl BatchReceiver {
+        pub(super) fn new(batch_size: usize, datagram_size: usize) -> BatchReceiver {
+            BatchReceiver {
+                datagram_size,
+                buffers: vec![0; batch_size * datagram_size],
+                // SAFETY: sockaddr_storage, iovec and mmsghdr are plain C structs.
+                names: vec![unsafe { mem::zeroed() }; batch_size],
+                iovecs: vec![unsafe { mem::zeroed() }; batch_size],
+                headers: vec![unsafe { mem::zeroed() }; batch_size],
+            }
+        }
+
+        pub(super) fn receive<F>(&mut self, socket: &UdpSocket, mut handler: F) -> io::Result<usize>
+        where
+            F: FnMut(&[u8], SocketAddr),
+        {
+            let batch_size = self.headers.len();
+            for i in 0..batch_size {
+                self.iovecs[i] = libc::iovec {
+                    iov_base: self.buffers[i * self.datagram_size..].as_mut_ptr().cast(),
+                    iov_len: self.datagram_size,
+                };
+                let header = &mut self.headers[i];
+                // SAFETY: mmsghdr is a plain C struct.
+                *header = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name =
+                    (&mut self.names[i] as *mut libc::sockaddr_storage).cast();
+                header.msg_hdr.msg_namelen =
+                    mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                header.msg_hdr.msg_iov = &mut self.iovecs[i];
+                header.msg_hdr.msg_iovlen = 1;
+            }
+
+            // SAFETY: all the headers point to buffers owned by self, valid during the call.
+            // MSG_WAITFORONE blocks (up to SO_RCVTIMEO) for the first datagram only.
+            let received = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    self.headers.as_mut_ptr(),
+                    batch_size as libc::c_uint,
+                    libc::MSG_WAITFORONE,
+                    ptr::null_mut(),
+                )
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut handled = 0;
+            for i in 0..received as usize {
+                let header = &self.headers[i];
+                if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                    continue;
+                }
+                // SAFETY: recvmmsg filled the name with `msg_namelen` bytes.
+                let source =
+                    match unsafe { sockaddr_to_std(&self.names[i], header.msg_hdr.msg_namelen) } {
+                        Some(source) => source,
+                        None => continue,
+                    };
+                let start = i * self.datagram_size;
+                handler(
+                    &self.buffers[start..start + header.msg_len as usize],
+                    source,
+                );
+                handled += 1;
+            }
+            Ok(handled)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_receive_datagrams() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        for i in 0..5u8 {
+            sender
+                .send_to(&[i; 10], socket.local_addr().unwrap())
+                .unwrap();
+        }
+
+        let mut receiver = Receiver::new(8);
+        let mut datagrams = Vec::new();
+        while datagrams.len() < 5 {
+            let count = receiver
+                .receive(&socket, |data, source| {
+                    assert_eq!(source, sender.local_addr().unwrap());
+                    datagrams.push(data.to_vec());
+                })
+                .unwrap();
+            assert!(count >= 1);
+            if Receiver::is_batched() {
+                assert_eq!(count, 5);
+            }
+        }
+        for (i, datagram) in datagrams.iter().enumerate() {
+            assert_eq!(datagram, &[i as u8; 10]);
+        }
+    }
+
+    #[test]
+    fn test_receive_timeout() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let error = Receiver::new(4).receive(&socket, |_, _| {}).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+    }
+}
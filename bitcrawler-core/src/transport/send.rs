@@ -0,0 +1,337 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+/// Something datagrams can be sent through: the socket of a node, or a mock in tests.
+pub trait Transport {
+    /// Send a datagram to `destination`, returns the number of bytes sent.
+    fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<usize>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, data, destination)
+    }
+}
+
+/// Settings of a [`SendGuard`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackoffConfig {
+    /// Time a destination is skipped after its first failure, doubled on each following one.
+    pub initial_backoff: Duration,
+    /// Maximum time a destination is skipped.
+    pub max_backoff: Duration,
+    /// Maximum number of failing destinations remembered. Past it, the new failures are still
+    /// reported but do not back off their destination.
+    pub max_tracked: usize,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(600),
+            max_tracked: 1 << 16,
+        }
+    }
+}
+
+/// Counters of the send failures seen by a [`SendGuard`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SendFailureStats {
+    /// Sends that failed because of their destination (unreachable, refused, forbidden...), or
+    /// asynchronous errors reported for a destination.
+    pub destination_failures: u64,
+    /// Sends that failed for another reason (e.g. send buffer full), the destination was not
+    /// blamed.
+    pub transient_failures: u64,
+    /// Sends skipped because their destination was backing off.
+    pub backed_off: u64,
+}
+
+/// A datagram was not sent, its destination is backing off after failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackingOff {
+    /// When the destination may be tried again.
+    pub retry_at: Instant,
+}
+
+impl Display for BackingOff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("destination backing off after send failures")
+    }
+}
+
+impl Error for BackingOff {}
+
+impl From<BackingOff> for io::Error {
+    fn from(backing_off: BackingOff) -> io::Error {
+        io::Error::other(backing_off)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failure {
+    count: u32,
+    retry_at: Instant,
+}
+
+/// Error handling policy of the sends: a failure is blamed on its destination, which is then
+/// skipped for a while (exponential backoff), and never brings the node down.
+///
+/// A send may fail because of its destination (an ICMP port unreachable surfaced by the
+/// socket, `EPERM` from a firewall or conntrack table, an unroutable address...), or because of
+/// the local host (send buffer full, interrupted call); only the former back off the
+/// destination. A successful send clears the failures of its destination.
+#[derive(Debug, Clone)]
+pub struct SendGuard {
+    config: BackoffConfig,
+    failures: HashMap<SocketAddr, Failure>,
+    stats: SendFailureStats,
+}
+
+impl SendGuard {
+    /// Create a guard with no failure recorded.
+    pub fn new(config: BackoffConfig) -> SendGuard {
+        SendGuard {
+            config,
+            failures: HashMap::new(),
+            stats: SendFailureStats::default(),
+        }
+    }
+
+    /// Send a datagram through `transport`, unless its destination is backing off.
+    ///
+    /// Fails with an error wrapping [`BackingOff`] if the destination is skipped, or with the
+    /// error of the transport, after recording it.
+    pub fn send<T: Transport + ?Sized>(
+        &mut self,
+        transport: &T,
+        data: &[u8],
+        destination: SocketAddr,
+        now: Instant,
+    ) -> io::Result<()> {
+        self.check(destination, now)?;
+        match transport.send_to(data, destination) {
+            Ok(_) => {
+                self.failures.remove(&destination);
+                Ok(())
+            }
+            Err(error) => {
+                if is_destination_error(&error) {
+                    self.record_failure(destination, now);
+                } else {
+                    self.stats.transient_failures += 1;
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Check if a datagram may be sent to `destination`.
+    pub fn check(&mut self, destination: SocketAddr, now: Instant) -> Result<(), BackingOff> {
+        match self.failures.get(&destination) {
+            Some(failure) if now < failure.retry_at => {
+                self.stats.backed_off += 1;
+                Err(BackingOff {
+                    retry_at: failure.retry_at,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a failure of `destination`, e.g. an asynchronous error reported by the socket
+    /// (see [`drain_socket_errors`](super::drain_socket_errors)), and back it off.
+    pub fn record_failure(&mut self, destination: SocketAddr, now: Instant) {
+        self.stats.destination_failures += 1;
+        if self.failures.len() >= self.config.max_tracked
+            && !self.failures.contains_key(&destination)
+        {
+            self.failures.retain(|_, failure| now < failure.retry_at);
+            if self.failures.len() >= self.config.max_tracked {
+                return;
+            }
+        }
+        let failure = self.failures.entry(destination).or_insert(Failure {
+            count: 0,
+            retry_at: now,
+        });
+        failure.count = failure.count.saturating_add(1);
+        let backoff = self
+            .config
+            .initial_backoff
+            .saturating_mul(1 << (failure.count - 1).min(20))
+            .min(self.config.max_backoff);
+        failure.retry_at = now + backoff;
+    }
+
+    /// Check if `destination` is backing off.
+    pub fn is_backing_off(&self, destination: SocketAddr, now: Instant) -> bool {
+        self.failures
+            .get(&destination)
+            .is_some_and(|failure| now < failure.retry_at)
+    }
+
+    /// Get the failure counters.
+    pub fn stats(&self) -> &SendFailureStats {
+        &self.stats
+    }
+}
+
+impl Default for SendGuard {
+    fn default() -> Self {
+        SendGuard::new(BackoffConfig::default())
+    }
+}
+
+/// Check if a send error is caused by its destination rather than by the local host.
+fn is_destination_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::InvalidInput
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// A transport failing with the errors queued for each destination, then succeeding.
+    #[derive(Default)]
+    struct MockTransport {
+        errors: RefCell<HashMap<SocketAddr, Vec<io::ErrorKind>>>,
+        sent: RefCell<Vec<SocketAddr>>,
+    }
+
+    impl MockTransport {
+        fn failing(destination: SocketAddr, errors: &[io::ErrorKind]) -> MockTransport {
+            let errors = errors.iter().rev().copied().collect();
+            MockTransport {
+                errors: RefCell::new([(destination, errors)].into_iter().collect()),
+                ..MockTransport::default()
+            }
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<usize> {
+            let mut errors = self.errors.borrow_mut();
+            match errors.get_mut(&destination).and_then(Vec::pop) {
+                Some(kind) => Err(kind.into()),
+                None => {
+                    self.sent.borrow_mut().push(destination);
+                    Ok(data.len())
+                }
+            }
+        }
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        ([192, 0, 2, 1], port).into()
+    }
+
+    #[test]
+    fn test_failures_back_off() {
+        let a = address(1);
+        let transport = MockTransport::failing(
+            a,
+            &[
+                io::ErrorKind::PermissionDenied,
+                io::ErrorKind::ConnectionRefused,
+            ],
+        );
+        let mut guard = SendGuard::default();
+        let start = Instant::now();
+
+        let error = guard.send(&transport, b"ping", a, start).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(guard.is_backing_off(a, start));
+        // Other destinations are not affected.
+        guard.send(&transport, b"ping", address(2), start).unwrap();
+        // Skipped without reaching the transport.
+        let error = guard.send(&transport, b"ping", a, start).unwrap_err();
+        assert!(
+            error
+                .get_ref()
+                .is_some_and(|error| error.downcast_ref::<BackingOff>().is_some())
+        );
+
+        // The backoff doubles on each failure, and a success clears it.
+        let retry = start + Duration::from_secs(1);
+        let error = guard.send(&transport, b"ping", a, retry).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(guard.is_backing_off(a, retry + Duration::from_millis(1900)));
+        let retry = retry + Duration::from_secs(2);
+        guard.send(&transport, b"ping", a, retry).unwrap();
+        assert!(!guard.is_backing_off(a, retry));
+        assert_eq!(*transport.sent.borrow(), vec![address(2), a]);
+        assert_eq!(
+            *guard.stats(),
+            SendFailureStats {
+                destination_failures: 2,
+                transient_failures: 0,
+                backed_off: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let mut guard = SendGuard::new(BackoffConfig {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            max_tracked: 2,
+        });
+        let start = Instant::now();
+        let a = address(1);
+        guard.record_failure(a, start);
+        guard.record_failure(a, start);
+        assert!(guard.is_backing_off(a, start + Duration::from_millis(1900)));
+        assert!(!guard.is_backing_off(a, start + Duration::from_secs(2)));
+        for _ in 0..40 {
+            guard.record_failure(a, start);
+        }
+        assert!(!guard.is_backing_off(a, start + Duration::from_secs(5)));
+
+        // Beyond max_tracked, new destinations are not backed off.
+        guard.record_failure(address(2), start);
+        guard.record_failure(address(3), start);
+        assert!(!guard.is_backing_off(address(3), start));
+        // Until the expired entries can be dropped.
+        let later = start + Duration::from_secs(10);
+        guard.record_failure(address(3), later);
+        assert!(guard.is_backing_off(address(3), later));
+    }
+
+    #[test]
+    fn test_local_failures_do_not_back_off() {
+        let transport = MockTransport::failing(
+            address(1),
+            &[io::ErrorKind::WouldBlock, io::ErrorKind::Interrupted],
+        );
+        let mut guard = SendGuard::default();
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert!(guard.send(&transport, b"ping", address(1), now).is_err());
+        }
+        assert!(!guard.is_backing_off(address(1), now));
+        assert!(guard.send(&transport, b"ping", address(1), now).is_ok());
+        assert_eq!(guard.stats().transient_failures, 2);
+        assert_eq!(guard.stats().destination_failures, 0);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::platform;
+
+/// Default receive buffer requested for crawling sockets (4 MiB).
+///
+/// High-rate crawling easily overflows the platform defaults (~200 KiB on Linux).
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 4 << 20;
+/// Default send buffer requested for crawling sockets (1 MiB).
+pub const DEFAULT_SEND_BUFFER_SIZE: usize = 1 << 20;
+/// Largest DSCP code point, see [`SocketConfig::dscp`].
+pub const MAX_DSCP: u8 = 63;
+
+/// Configuration of a UDP socket used by the DHT transport.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// Local address to bind to.
+    pub bind_address: SocketAddr,
+    /// Network interface to bind to (e.g. `eth1`), whatever the address of the destination.
+    ///
+    /// Only supported on Linux (`SO_BINDTODEVICE`), binding fails elsewhere.
+    pub device: Option<String>,
+    /// Number of sockets bound by a [`SocketManager`](super::SocketManager), on consecutive
+    /// ports from the port of `bind_address` (or on ephemeral ports if it is 0). The queries are
+    /// spread over them.
+    pub sockets: usize,
+    /// Requested receive buffer size (`SO_RCVBUF`), or `None` to keep the platform default.
+    pub recv_buffer_size: Option<usize>,
+    /// Requested send buffer size (`SO_SNDBUF`), or `None` to keep the platform default.
+    pub send_buffer_size: Option<usize>,
+    /// Ask the platform to report ICMP errors (e.g. port unreachable) for unconnected sockets.
+    ///
+    /// On Linux, this enables `IP_RECVERR` and the errors must be drained with
+    /// [`drain_socket_errors`], otherwise they take up receive buffer space.
+    pub report_icmp_errors: bool,
+    /// DSCP code point (0 to 63) marked on the datagrams sent, e.g. for the network of the
+    /// operator to shape the crawl traffic, or `None` to keep the platform default (0).
+    ///
+    /// Sets `IP_TOS` (or `IPV6_TCLASS` on an IPv6 socket, not supported on Windows), with the
+    /// ECN bits cleared.
+    pub dscp: Option<u8>,
+    /// Time to live (`IP_TTL`), or hop limit on an IPv6 socket (`IPV6_UNICAST_HOPS`), of the
+    /// datagrams sent, e.g. for TTL-based measurements. `None` keeps the platform default.
+    pub ttl: Option<u8>,
+}
+
+/// What was actually applied to a socket by [`bind_socket`].
+///
+/// Platforms are free to cap (or double, on Linux) the requested buffer sizes, so the
+/// effective values are read back from the socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketReport {
+    /// The address the socket is bound to.
+    pub local_address: SocketAddr,
+    /// Effective receive buffer size, as reported by the platform.
+    pub recv_buffer_size: usize,
+    /// Effective send buffer size, as reported by the platform.
+    pub send_buffer_size: usize,
+    /// Platform-specific options that were enabled.
+    pub platform_options: Vec<&'static str>,
+}
+
+/// An error reported asynchronously by the network for a datagram we sent.
+#[derive(Debug)]
+pub struct SocketError {
+    /// The destination of the datagram that triggered the error.
+    pub destination: SocketAddr,
+    /// The error itself (e.g. `ConnectionRefused` for an ICMP port unreachable).
+    pub error: io::Error,
+}
+
+impl SocketConfig {
+    /// Create a configuration binding the given address with the default buffer sizes.
+    pub fn new(bind_address: SocketAddr) -> SocketConfig {
+        SocketConfig {
+            bind_address,
+            device: None,
+            sockets: 1,
+            recv_buffer_size: Some(DEFAULT_RECV_BUFFER_SIZE),
+            send_buffer_size: Some(DEFAULT_SEND_BUFFER_SIZE),
+            report_icmp_errors: true,
+            dscp: None,
+            ttl: None,
+        }
+    }
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig::new((Ipv4Addr::UNSPECIFIED, 6881).into())
+    }
+}
+
+/// Create and bind a UDP socket tuned according to the given configuration.
+///
+/// Options that must be set before binding (e.g. exclusive address use on Windows) are applied
+/// here, which is why the socket is not created through `UdpSocket::bind`. A single socket is
+/// bound, [`SocketConfig::sockets`] is left to the [`SocketManager`](super::SocketManager).
+pub fn bind_socket(config: &SocketConfig) -> io::Result<(UdpSocket, SocketReport)> {
+    if config.dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "DSCP code point above 63",
+        ));
+    }
+    let socket = Socket::new(
+        Domain::for_address(config.bind_address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    let mut platform_options = Vec::new();
+    platform::before_bind(&socket, config, &mut platform_options)?;
+    socket.bind(&config.bind_address.into())?;
+
+    if let Some(size) = config.recv_buffer_size {
+        platform::set_recv_buffer_size(&socket, size, &mut platform_options)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        platform::set_send_buffer_size(&socket, size)?;
+    }
+    let ipv4 = config.bind_address.is_ipv4();
+    if let Some(dscp) = config.dscp {
+        let traffic_class = u32::from(dscp) << 2;
+        if ipv4 {
+            socket.set_tos_v4(traffic_class)?;
+            platform_options.push("IP_TOS");
+        } else {
+            platform::set_traffic_class_v6(&socket, traffic_class)?;
+            platform_options.push("IPV6_TCLASS");
+        }
+    }
+    if let Some(ttl) = config.ttl {
+        if ipv4 {
+            socket.set_ttl_v4(ttl.into())?;
+            platform_options.push("IP_TTL");
+        } else {
+            socket.set_unicast_hops_v6(ttl.into())?;
+            platform_options.push("IPV6_UNICAST_HOPS");
+        }
+    }
+    platform::after_bind(&socket, config, &mut platform_options)?;
+
+    let report = SocketReport {
+        local_address: socket
+            .local_addr()?
+            .as_socket()
+            .expect("UDP socket has an IP address"),
+        recv_buffer_size: socket.recv_buffer_size()?,
+        send_buffer_size: socket.send_buffer_size()?,
+        platform_options,
+    };
+    Ok((socket.into(), report))
+}
+
+/// Get the number of datagrams dropped by the platform for this socket (receive buffer full).
+///
+/// Returns `None` if the platform does not expose this counter.
+pub fn dropped_datagrams(socket: &UdpSocket) -> io::Result<Option<u64>> {
+    platform::dropped_datagrams(&platform::drop_counter(socket)?)
+}
+
+/// Collect the asynchronous errors queued on the socket, without blocking.
+///
+/// Only Linux queues such errors (with [`SocketConfig::report_icmp_errors`]), other platforms
+/// always return an empty list.
+pub fn drain_socket_errors(socket: &UdpSocket) -> io::Result<Vec<SocketError>> {
+    platform::drain_socket_errors(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback_config() -> SocketConfig {
+        SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into())
+    }
+
+    #[test]
+    fn test_bind_socket_reports_buffers() {
+        let (socket, report) = bind_socket(&loopback_config()).unwrap();
+        assert_eq!(socket.local_addr().unwrap(), report.local_address);
+        assert_ne!(report.local_address.port(), 0);
+        assert!(report.recv_buffer_size > 0);
+        assert!(report.send_buffer_size > 0);
+    }
+
+    #[test]
+    fn test_bind_socket_keeps_platform_defaults() {
+        let mut config = loopback_config();
+        config.recv_buffer_size = None;
+        config.send_buffer_size = None;
+        config.report_icmp_errors = false;
+        let (_, report) = bind_socket(&config).unwrap();
+        assert!(report.platform_options.is_empty() || cfg!(windows));
+    }
+
+    #[test]
+    fn test_bind_socket_marks_datagrams() {
+        let mut config = loopback_config();
+        // Expedited forwarding.
+        config.dscp = Some(46);
+        config.ttl = Some(3);
+        let (socket, report) = bind_socket(&config).unwrap();
+        assert!(report.platform_options.contains(&"IP_TOS"));
+        assert!(report.platform_options.contains(&"IP_TTL"));
+        assert_eq!(socket2::SockRef::from(&socket).tos_v4().unwrap(), 46 << 2);
+        assert_eq!(socket.ttl().unwrap(), 3);
+
+        config.dscp = Some(64);
+        let error = bind_socket(&config).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_linux_drops_and_icmp_errors() {
+        let (socket, report) = bind_socket(&loopback_config()).unwrap();
+        assert!(report.platform_options.contains(&"IP_RECVERR"));
+        assert_eq!(dropped_datagrams(&socket).unwrap(), Some(0));
+
+        // Find a closed port, then send to it to trigger an ICMP port unreachable.
+        let closed = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination = closed.local_addr().unwrap();
+        drop(closed);
+        socket.send_to(b"ping", destination).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let errors = drain_socket_errors(&socket).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].destination, destination);
+        assert_eq!(errors[0].error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(drain_socket_errors(&socket).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// First bytes of a wiretap file, followed by the version of the format.
+const WIRETAP_MAGIC: &[u8; 4] = b"bcwt";
+const WIRETAP_VERSION: u8 = 1;
+/// Largest datagram a record can hold (the payload limit of UDP).
+const MAX_RECORD_SIZE: usize = 65535;
+
+/// Whether a recorded datagram was received or sent by the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// A datagram recorded in a wiretap file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WiretapRecord {
+    /// Time since the start of the recording.
+    pub elapsed: Duration,
+    pub direction: Direction,
+    /// Source of a received datagram, destination of a sent one.
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Records the datagrams of a node to a wiretap file, to replay them later (see [`Replay`]).
+///
+/// The file starts with `bcwt` and a version byte (1), followed by the records. A record is
+/// made of the direction (0 received, 1 sent), the time since the start of the recording in
+/// microseconds (u64), the IP version (4 or 6) then the address and port of the peer, and the
+/// length of the datagram (u32) followed by its bytes. Integers are big-endian.
+///
+/// Write errors do not interrupt the node: the first one is kept, and returned by
+/// [`WiretapWriter::flush`].
+#[derive(Debug)]
+pub struct WiretapWriter<W: Write> {
+    writer: W,
+    started: Instant,
+    error: Option<io::Error>,
+}
+
+impl WiretapWriter<BufWriter<File>> {
+    /// Create (or truncate) the wiretap file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        WiretapWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> WiretapWriter<W> {
+    /// Start a recording to `writer`, the records are timed from now.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(WIRETAP_MAGIC)?;
+        writer.write_all(&[WIRETAP_VERSION])?;
+        Ok(WiretapWriter {
+            writer,
+            started: Instant::now(),
+            error: None,
+        })
+    }
+
+    /// Record a datagram received from, or sent to, `peer`.
+    pub fn record(&mut self, direction: Direction, peer: SocketAddr, data: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let record = WiretapRecord {
+            elapsed: self.started.elapsed(),
+            direction,
+            peer,
+            data: data.to_vec(),
+        };
+        if let Err(e) = self.write_record(&record) {
+            self.error = Some(e);
+        }
+    }
+
+    /// Write a record, with its own timing.
+    pub fn write_record(&mut self, record: &WiretapRecord) -> io::Result<()> {
+        if record.data.len() > MAX_RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram too large to be recorded",
+            ));
+        }
+        let mut header = Vec::with_capacity(32);
+        header.push(match record.direction {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        });
+        let micros = u64::try_from(record.elapsed.as_micros()).unwrap_or(u64::MAX);
+        header.extend_from_slice(&micros.to_be_bytes());
+        match record.peer.ip() {
+            IpAddr::V4(ip) => {
+                header.push(4);
+                header.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                header.push(6);
+                header.extend_from_slice(&ip.octets());
+            }
+        }
+        header.extend_from_slice(&record.peer.port().to_be_bytes());
+        header.extend_from_slice(&(record.data.len() as u32).to_be_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&record.data)
+    }
+
+    /// Flush the records written so far, or return the first error met while recording.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()
+    }
+}
+
+/// Reads the records of a wiretap file, see [`WiretapWriter`] for the format.
+#[derive(Debug)]
+pub struct WiretapReader<R: Read> {
+    reader: R,
+}
+
+impl WiretapReader<BufReader<File>> {
+    /// Open the wiretap file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        WiretapReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> WiretapReader<R> {
+    /// Read the header of the recording from `reader`.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != WIRETAP_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a wiretap file",
+            ));
+        }
+        if header[4] != WIRETAP_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported wiretap version",
+            ));
+        }
+        Ok(WiretapReader { reader })
+    }
+
+    /// Read the next record, `None` at the end of the recording.
+    ///
+    /// A record cut short (e.g. by a crash of the recording node) is an error.
+    pub fn read_record(&mut self) -> io::Result<Option<WiretapRecord>> {
+        let mut direction = [0];
+        match self.reader.read_exact(&mut direction) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let direction = match direction[0] {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            _ => return Err(invalid_record("invalid direction")),
+        };
+        let micros = u64::from_be_bytes(self.read_array()?);
+        let ip = match self.read_array::<1>()?[0] {
+            4 => IpAddr::V4(Ipv4Addr::from(self.read_array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(self.read_array::<16>()?)),
+            _ => return Err(invalid_record("invalid IP version")),
+        };
+        let port = u16::from_be_bytes(self.read_array()?);
+        let length = u32::from_be_bytes(self.read_array()?) as usize;
+        if length > MAX_RECORD_SIZE {
+            return Err(invalid_record("datagram too large"));
+        }
+        let mut data = vec![0; length];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(WiretapRecord {
+            elapsed: Duration::from_micros(micros),
+            direction,
+            peer: SocketAddr::new(ip, port),
+            data,
+        }))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl<R: Read> Iterator for WiretapReader<R> {
+    type Item = io::Result<WiretapRecord>;
+
+    fn next(&mut self) -> Option<io::Result<WiretapRecord>> {
+        self.read_record().transpose()
+    }
+}
+
+fn invalid_record(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Pace of a [`Replay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplaySpeed {
+    /// Hand the records out at the pace they were recorded.
+    #[default]
+    Recorded,
+    /// Hand the records out as fast as they are asked for.
+    Fast,
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = &'static str;
+
+    /// Parse `recorded` or `fast`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recorded" => Ok(ReplaySpeed::Recorded),
+            "fast" => Ok(ReplaySpeed::Fast),
+            _ => Err("Invalid replay speed"),
+        }
+    }
+}
+
+/// Settings of a replay, see [`NodeConfig::replay`](crate::node::NodeConfig::replay).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayConfig {
+    /// Wiretap file to replay.
+    pub path: PathBuf,
+    pub speed: ReplaySpeed,
+}
+
+/// Hands out the records of a wiretap file, in place of the traffic of a live socket.
+///
+/// The timing of the recording starts over on the first call to [`Replay::next`].
+pub struct Replay {
+    reader: WiretapReader<Box<dyn Read + Send>>,
+    speed: ReplaySpeed,
+    started: Option<Instant>,
+    // Record read ahead, not due yet.
+    next: Option<WiretapRecord>,
+    finished: bool,
+}
+
+impl Replay {
+    /// Open the wiretap file of `config`.
+    pub fn open(config: &ReplayConfig) -> io::Result<Replay> {
+        let file = BufReader::new(File::open(&config.path)?);
+        Replay::new(file, config.speed)
+    }
+
+    /// Replay the recording read from `reader`.
+    pub fn new<R: Read + Send + 'static>(reader: R, speed: ReplaySpeed) -> io::Result<Replay> {
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        Ok(Replay {
+            reader: WiretapReader::new(reader)?,
+            speed,
+            started: None,
+            next: None,
+            finished: false,
+        })
+    }
+
+    /// Get the next record, waiting up to `timeout` for it to be due.
+    ///
+    /// Returns `None` if no record is due in time, or at the end of the recording.
+    pub fn next(&mut self, timeout: Duration) -> io::Result<Option<WiretapRecord>> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let record = match self.next.take() {
+            Some(record) => record,
+            None => match self.reader.read_record()? {
+                Some(record) => record,
+                None => {
+                    self.finished = true;
+                    return Ok(None);
+                }
+            },
+        };
+        if self.speed == ReplaySpeed::Recorded {
+            let wait = (started + record.elapsed).saturating_duration_since(Instant::now());
+            if wait > timeout {
+                thread::sleep(timeout);
+                self.next = Some(record);
+                return Ok(None);
+            }
+            thread::sleep(wait);
+        }
+        Ok(Some(record))
+    }
+
+    /// Check if every record was handed out.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl fmt::Debug for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Replay")
+            .field("speed", &self.speed)
+            .field("started", &self.started)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn record(millis: u64, direction: Direction, peer: &str, data: &[u8]) -> WiretapRecord {
+        WiretapRecord {
+            elapsed: Duration::from_millis(millis),
+            direction,
+            peer: peer.parse().unwrap(),
+            data: data.to_vec(),
+        }
+    }
+
+    fn recording(records: &[WiretapRecord]) -> Vec<u8> {
+        let mut writer = WiretapWriter::new(Vec::new()).unwrap();
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+        writer.flush().unwrap();
+        writer.writer
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let records = vec![
+            record(0, Direction::Sent, "192.0.2.1:6881", b"d1:y1:qe"),
+            record(
+                1500,
+                Direction::Received,
+                "[2001:db8::1]:51413",
+                b"d1:y1:re",
+            ),
+            record(1501, Direction::Received, "192.0.2.1:6881", b""),
+        ];
+        let bytes = recording(&records);
+        let read: Vec<WiretapRecord> = WiretapReader::new(Cursor::new(bytes.clone()))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read, records);
+
+        // A record cut short is an error, another file is rejected.
+        let mut reader = WiretapReader::new(Cursor::new(&bytes[..bytes.len() - 30])).unwrap();
+        assert!(reader.read_record().unwrap().is_some());
+        assert_eq!(
+            reader.read_record().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            WiretapReader::new(Cursor::new(b"d1:y1:qe".to_vec()))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_replay_timing() {
+        let records = vec![
+            record(0, Direction::Received, "192.0.2.1:6881", b"a"),
+            record(300, Direction::Received, "192.0.2.1:6881", b"b"),
+        ];
+        let bytes = recording(&records);
+
+        let mut replay = Replay::new(Cursor::new(bytes.clone()), ReplaySpeed::Recorded).unwrap();
+        assert_eq!(
+            replay.next(Duration::ZERO).unwrap(),
+            Some(records[0].clone())
+        );
+        // The second record is not due yet.
+        assert_eq!(replay.next(Duration::from_millis(10)).unwrap(), None);
+        assert_eq!(
+            replay.next(Duration::from_secs(5)).unwrap(),
+            Some(records[1].clone())
+        );
+        assert!(replay.started.unwrap().elapsed() >= Duration::from_millis(300));
+        assert!(!replay.is_finished());
+        assert_eq!(replay.next(Duration::ZERO).unwrap(), None);
+        assert!(replay.is_finished());
+
+        let mut replay = Replay::new(Cursor::new(bytes), ReplaySpeed::Fast).unwrap();
+        let start = Instant::now();
+        assert!(replay.next(Duration::ZERO).unwrap().is_some());
+        assert!(replay.next(Duration::ZERO).unwrap().is_some());
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+}
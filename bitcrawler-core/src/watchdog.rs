@@ -0,0 +1,303 @@
+//! Detection of the components of a crawl that stopped making progress, see [`Watchdog`].
+//!
+//! The stages of a crawl hand data to each other through queues and locks: a sink blocked on
+//! a full disk, or a queue that never drains, can stall everything before it without an error.
+//! Each loop (the receive thread, the crawler loop, the sink threads) beats a [`Heartbeat`]
+//! every time around, and the watchdog reports the heartbeats that stopped.
+
+use std::{
+    io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Last beat of a heartbeat that stopped for good, see [`Heartbeat::stop`].
+const STOPPED: u64 = u64::MAX;
+
+struct Inner {
+    name: String,
+    epoch: Instant,
+    // Microseconds since `epoch` of the last beat, or `STOPPED`.
+    last_beat: AtomicU64,
+}
+
+/// Timestamp of the last iteration of a component loop, shared by cloning it.
+///
+/// The loop calls [`Heartbeat::beat`] every time around, including while it waits for work
+/// (waits should then be bounded), and [`Heartbeat::stop`] when it ends.
+#[derive(Clone)]
+pub struct Heartbeat {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Heartbeat")
+            .field("name", &self.inner.name)
+            .field("last_beat", &self.last_beat())
+            .finish()
+    }
+}
+
+impl Heartbeat {
+    /// Create the heartbeat of the component `name`, beaten now.
+    pub fn new(name: impl Into<String>) -> Heartbeat {
+        Heartbeat {
+            inner: Arc::new(Inner {
+                name: name.into(),
+                epoch: Instant::now(),
+                last_beat: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Get the name of the component.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Record that the component made progress now.
+    pub fn beat(&self) {
+        let elapsed = self.inner.epoch.elapsed().as_micros() as u64;
+        // A stopped heartbeat stays stopped.
+        let _ = self
+            .inner
+            .last_beat
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                (last != STOPPED).then_some(elapsed)
+            });
+    }
+
+    /// Record that the component ended: it is not watched anymore.
+    pub fn stop(&self) {
+        self.inner.last_beat.store(STOPPED, Ordering::Relaxed);
+    }
+
+    /// Get the time of the last beat, `None` once stopped.
+    pub fn last_beat(&self) -> Option<Instant> {
+        match self.inner.last_beat.load(Ordering::Relaxed) {
+            STOPPED => None,
+            micros => Some(self.inner.epoch + Duration::from_micros(micros)),
+        }
+    }
+}
+
+/// Settings of a [`Watchdog`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// Time without a beat after which a component is stalled.
+    pub stall_timeout: Duration,
+    /// Interval between two checks of the heartbeats.
+    pub check_interval: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            stall_timeout: Duration::from_secs(30),
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A component found stalled by [`Watchdog::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stall {
+    /// Name of the component, see [`Heartbeat::name`].
+    pub name: String,
+    /// Time since its last beat.
+    pub silent_for: Duration,
+}
+
+struct Watched {
+    heartbeat: Heartbeat,
+    // The current stall was reported already.
+    reported: bool,
+}
+
+/// Watches the [`Heartbeat`]s of the components of a crawl, and reports those which did not
+/// beat for [`WatchdogConfig::stall_timeout`].
+///
+/// A stall is reported once, until the component beats again. What to do about it is left to
+/// the caller of [`Watchdog::spawn`]: a blocked thread cannot be restarted from the outside,
+/// a binary would rather exit for its supervisor to restart the whole crawl. The watchdog is
+/// shared by cloning it, so that components started later (e.g. the sinks of a reload) are
+/// watched too.
+///
+/// ```no_run
+/// # #[cfg(feature = "crawler")]
+/// # fn main() -> std::io::Result<()> {
+/// use bitcrawler_core::{
+///     crawler::{Crawler, CrawlerConfig},
+///     proto::kademlia::Id160,
+///     watchdog::{Watchdog, WatchdogConfig},
+/// };
+///
+/// let crawler = Crawler::bind(CrawlerConfig::new(Id160([1; 20])))?;
+/// let watchdog = Watchdog::new(WatchdogConfig::default());
+/// for heartbeat in crawler.heartbeats() {
+///     watchdog.watch(heartbeat);
+/// }
+/// watchdog.spawn(|stall| {
+///     eprintln!("{} made no progress for {:?}", stall.name, stall.silent_for);
+///     std::process::exit(1);
+/// })?;
+/// let (_handle, thread) = crawler.spawn();
+/// thread.join().unwrap()?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "crawler"))]
+/// # fn main() {}
+/// ```
+#[derive(Clone)]
+pub struct Watchdog {
+    config: WatchdogConfig,
+    watched: Arc<Mutex<Vec<Watched>>>,
+}
+
+impl Watchdog {
+    /// Create a watchdog watching nothing yet.
+    pub fn new(config: WatchdogConfig) -> Watchdog {
+        Watchdog {
+            config,
+            watched: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Watch a component, the components that stopped (see [`Heartbeat::stop`]) are forgotten.
+    pub fn watch(&self, heartbeat: Heartbeat) {
+        let mut watched = self.watched.lock().expect("watchdog lock poisoned");
+        watched.retain(|watched| watched.heartbeat.last_beat().is_some());
+        watched.push(Watched {
+            heartbeat,
+            reported: false,
+        });
+    }
+
+    /// Get the components newly stalled at `now`.
+    pub fn check(&self, now: Instant) -> Vec<Stall> {
+        let mut stalls = Vec::new();
+        let mut watched = self.watched.lock().expect("watchdog lock poisoned");
+        for watched in watched.iter_mut() {
+            let Some(last_beat) = watched.heartbeat.last_beat() else {
+                continue;
+            };
+            let silent_for = now.saturating_duration_since(last_beat);
+            if silent_for < self.config.stall_timeout {
+                watched.reported = false;
+                continue;
+            }
+            if watched.reported {
+                continue;
+            }
+            watched.reported = true;
+            stalls.push(Stall {
+                name: watched.heartbeat.name().to_string(),
+                silent_for,
+            });
+        }
+        stalls
+    }
+
+    /// Check the heartbeats on a new thread, every [`WatchdogConfig::check_interval`], until
+    /// every watched component stopped, and call `on_stall` with each stall.
+    pub fn spawn<F>(&self, mut on_stall: F) -> io::Result<JoinHandle<()>>
+    where
+        F: FnMut(&Stall) + Send + 'static,
+    {
+        let watchdog = self.clone();
+        thread::Builder::new()
+            .name("bitcrawler-watchdog".to_string())
+            .spawn(move || {
+                #[cfg(feature = "tracing")]
+                let _span = tracing::info_span!("watchdog").entered();
+                while watchdog.is_watching() {
+                    thread::sleep(watchdog.config.check_interval);
+                    for stall in watchdog.check(Instant::now()) {
+                        on_stall(&stall);
+                    }
+                }
+            })
+    }
+
+    // Check if a watched component has not stopped.
+    fn is_watching(&self) -> bool {
+        self.watched
+            .lock()
+            .expect("watchdog lock poisoned")
+            .iter()
+            .any(|watched| watched.heartbeat.last_beat().is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_stalls() {
+        let config = WatchdogConfig {
+            stall_timeout: Duration::from_secs(10),
+            ..WatchdogConfig::default()
+        };
+        let watchdog = Watchdog::new(config);
+        let (receive, sink, done) = (
+            Heartbeat::new("receive"),
+            Heartbeat::new("sink"),
+            Heartbeat::new("done"),
+        );
+        watchdog.watch(receive.clone());
+        watchdog.watch(done.clone());
+        done.stop();
+        done.beat();
+        assert_eq!(done.last_beat(), None);
+        // Watched later, through a clone, e.g. the sink of a reload.
+        watchdog.clone().watch(sink.clone());
+        assert_eq!(watchdog.watched.lock().unwrap().len(), 2);
+
+        let start = receive.last_beat().unwrap().max(sink.last_beat().unwrap());
+        assert!(watchdog.check(start + Duration::from_secs(5)).is_empty());
+        let names = |stalls: Vec<Stall>| -> Vec<String> {
+            stalls.into_iter().map(|stall| stall.name).collect()
+        };
+        // Reported once per stall.
+        let later = start + Duration::from_secs(11);
+        assert_eq!(names(watchdog.check(later)), ["receive", "sink"]);
+        assert!(watchdog.check(later).is_empty());
+
+        // Both beat again, then stall again.
+        receive.beat();
+        sink.beat();
+        let beat = receive.last_beat().unwrap().max(sink.last_beat().unwrap());
+        assert!(watchdog.check(beat + Duration::from_secs(1)).is_empty());
+        let much_later = beat + Duration::from_secs(11);
+        assert_eq!(names(watchdog.check(much_later)), ["receive", "sink"]);
+    }
+
+    #[test]
+    fn test_spawn_reports_stalls() {
+        let watchdog = Watchdog::new(WatchdogConfig {
+            stall_timeout: Duration::from_millis(50),
+            check_interval: Duration::from_millis(10),
+        });
+        let stalled = Heartbeat::new("stalled");
+        watchdog.watch(stalled.clone());
+        let (sender, receiver) = mpsc::channel();
+        let thread = watchdog
+            .spawn(move |stall| sender.send(stall.name.clone()).unwrap())
+            .unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "stalled"
+        );
+        // The thread ends once every component stopped.
+        stalled.stop();
+        thread.join().unwrap();
+    }
+}
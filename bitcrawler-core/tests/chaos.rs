@@ -0,0 +1,139 @@
+//! Recovery of the node from a bad network, with faults injected in the datagrams it receives.
+//!
+//! Only runs with the `chaos` and `testutil` features:
+//! `cargo test -p bitcrawler-core --features chaos,testutil --test chaos`.
+//!
+//! Whatever happens to the replies, every query must end (answered, failed or timed out) without
+//! leaving its transaction in flight, and a lookup must finish.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_core::{
+    node::{DhtNode, DhtResponse, LookupOptions, NodeConfig, NodeEvent, lookup_peers},
+    proto::{
+        kademlia::Id160,
+        krpc::{QueryType, node_info::BittorrentNodeInfoV4},
+    },
+    testutil::{FakeNode, ServingNode},
+    transport::SocketConfig,
+};
+
+const QUERY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Spawn `count` fake nodes knowing each other, answering `ping` and `get_peers` queries: with
+/// one peer, and the other fake nodes as the closest nodes.
+fn fake_nodes(count: u8) -> Vec<ServingNode> {
+    let fakes: Vec<FakeNode> = (1..=count)
+        .map(|i| FakeNode::bind(Id160([i; 20])).unwrap())
+        .collect();
+    let infos: Vec<BittorrentNodeInfoV4<Id160>> = fakes.iter().map(FakeNode::info).collect();
+    fakes
+        .into_iter()
+        .map(|fake| {
+            let id = fake.id();
+            let others = infos.clone();
+            fake.serve(move |query| {
+                let tid = query.get_transaction_id().clone();
+                let reply = match query.get_query() {
+                    QueryType::GetPeers(_) => DhtResponse::new_get_peers(
+                        tid,
+                        id,
+                        Some("token".into()),
+                        others.clone(),
+                        vec![SocketAddrV4::new([198, 51, 100, 1].into(), 51413)],
+                    ),
+                    _ => DhtResponse::new_ping(tid, id),
+                };
+                Some(reply.to_bencoded())
+            })
+        })
+        .collect()
+}
+
+fn node() -> DhtNode {
+    let mut config = NodeConfig::new(Id160([0xff; 20]));
+    config.socket = SocketConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+    config.poll_timeout = Duration::from_millis(10);
+    config.query_timeout = QUERY_TIMEOUT;
+    config.seed = Some(7);
+    DhtNode::bind(config).unwrap()
+}
+
+/// Poll until no query is in flight, returns the events.
+fn settle(node: &mut DhtNode) -> Vec<NodeEvent> {
+    let deadline = Instant::now() + QUERY_TIMEOUT * 5;
+    let mut events = Vec::new();
+    while node.in_flight() > 0 {
+        assert!(Instant::now() < deadline, "queries left in flight");
+        node.poll(&mut events).unwrap();
+    }
+    events
+}
+
+#[test]
+fn test_queries_end_under_faults() {
+    let fakes = fake_nodes(1);
+    let destination = fakes[0].address();
+    let mut node = node();
+
+    // A lost reply times out.
+    node.faults().drop_next(1);
+    node.ping(destination).unwrap();
+    let events = settle(&mut node);
+    assert!(matches!(events[..], [NodeEvent::Timeout { .. }]));
+
+    // Corrupted replies are malformed, unmatched or still valid: each query ends once.
+    node.faults().set_corrupt_rate(1.0);
+    for _ in 0..10 {
+        node.ping(destination).unwrap();
+    }
+    assert_eq!(settle(&mut node).len(), 10);
+    assert_eq!(node.faults().stats().corrupted, 10);
+    node.faults().clear();
+
+    // A reply later than the timeout is ignored once the query timed out.
+    node.faults().set_delay(QUERY_TIMEOUT * 2);
+    node.ping(destination).unwrap();
+    let events = settle(&mut node);
+    assert!(matches!(events[..], [NodeEvent::Timeout { .. }]));
+    thread::sleep(QUERY_TIMEOUT * 2);
+    let mut events = Vec::new();
+    node.poll(&mut events).unwrap();
+    assert!(events.is_empty());
+
+    // A reply within the timeout is only late.
+    let delay = QUERY_TIMEOUT / 4;
+    node.faults().set_delay(delay);
+    node.ping(destination).unwrap();
+    let events = settle(&mut node);
+    assert!(matches!(events[..], [NodeEvent::Response { rtt, .. }] if rtt >= delay));
+    assert_eq!(node.faults().stats().delayed, 2);
+}
+
+#[test]
+fn test_lookup_finishes_under_faults() {
+    let fakes = fake_nodes(6);
+    let contacts: Vec<SocketAddr> = fakes.iter().map(ServingNode::address).collect();
+    let mut node = node();
+    node.faults().drop_next(3);
+    node.faults().set_corrupt_rate(0.3);
+    node.faults().set_delay(QUERY_TIMEOUT / 10);
+
+    let deadline = Duration::from_secs(5);
+    let options = LookupOptions {
+        deadline: Some(deadline),
+        ..LookupOptions::default()
+    };
+    let started = Instant::now();
+    let result = lookup_peers(&mut node, Id160([1; 20]), &contacts, &options).unwrap();
+    assert!(started.elapsed() < deadline);
+    assert!(result.queried >= result.answered);
+    settle(&mut node);
+    let stats = node.faults().stats();
+    assert_eq!(stats.dropped, 3);
+    assert!(stats.corrupted > 0);
+}
@@ -0,0 +1,85 @@
+//! Smoke test of the protocol stack against the live BitTorrent DHT.
+//!
+//! It needs network access, so it only runs with the `live-dht` feature:
+//! `cargo test -p bitcrawler-core --features live-dht --test live_dht`.
+//!
+//! The test bootstraps from public routers, then runs a find_node lookup toward a random id and
+//! checks that enough distinct nodes are learned before the deadline.
+
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use bitcrawler_core::{
+    keyspace::TargetGenerator,
+    node::{DhtNode, NodeConfig, NodeEvent},
+    proto::krpc::ResponseType,
+    transport::SocketConfig,
+};
+
+const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+    "dht.libtorrent.org:25401",
+];
+/// Number of distinct nodes the lookup must learn.
+const K: usize = 8;
+const DEADLINE: Duration = Duration::from_secs(30);
+
+#[test]
+fn test_find_node_learns_nodes() {
+    let mut generator = TargetGenerator::new();
+    let mut config = NodeConfig::new(generator.random_id());
+    config.socket = SocketConfig::new((Ipv4Addr::UNSPECIFIED, 0).into());
+    config.query_timeout = Duration::from_secs(5);
+    let mut node = DhtNode::bind(config).unwrap();
+    let target = generator.random_id();
+
+    let bootstrap: Vec<SocketAddr> = BOOTSTRAP_NODES
+        .iter()
+        .filter_map(|address| address.to_socket_addrs().ok())
+        .flatten()
+        .filter(SocketAddr::is_ipv4)
+        .collect();
+    assert!(!bootstrap.is_empty(), "no bootstrap node could be resolved");
+    for address in bootstrap {
+        node.find_node(address, target).unwrap();
+    }
+
+    let mut learned = HashSet::new();
+    let mut queried = HashSet::new();
+    let mut events = Vec::new();
+    let deadline = Instant::now() + DEADLINE;
+    while learned.len() < K && Instant::now() < deadline {
+        events.clear();
+        node.poll(&mut events).unwrap();
+        for event in events.drain(..) {
+            let response = match event {
+                NodeEvent::Response { response, .. } => response,
+                _ => continue,
+            };
+            let nodes = match response.get_response_type() {
+                ResponseType::FindNode(find_node) => find_node.get_nodes().to_vec(),
+                ResponseType::GetPeers(get_peers) => get_peers.get_nodes().to_vec(),
+                _ => continue,
+            };
+            for info in nodes {
+                learned.insert(info.node_id);
+                let address = SocketAddr::from((info.ip, info.port));
+                if queried.insert(address) {
+                    node.find_node(address, target).unwrap();
+                }
+            }
+        }
+    }
+
+    assert!(
+        learned.len() >= K,
+        "only {} nodes learned in {:?}",
+        learned.len(),
+        DEADLINE
+    );
+}
@@ -0,0 +1,303 @@
+//! Interoperability test against a reference DHT implementation (e.g. libtorrent), run in a
+//! container or any other external process.
+//!
+//! It needs a reference node, so it only runs with the `reference-node` feature, and the node is
+//! set with environment variables:
+//!
+//! - `BITCRAWLER_REFERENCE_ADDRESS`: UDP address of the node (IPv4), required.
+//! - `BITCRAWLER_REFERENCE_COMMAND`: shell command starting the node, killed at the end of the
+//!   test. Without it, the node must already run.
+//!
+//! ```sh
+//! BITCRAWLER_REFERENCE_ADDRESS=127.0.0.1:6881 \
+//! BITCRAWLER_REFERENCE_COMMAND='exec docker run --rm -p 6881:6881/udp libtorrent-dht' \
+//! cargo test -p bitcrawler-core --features reference-node --test reference_node
+//! ```
+//!
+//! The test checks that the node answers each of our queries with a reply of the expected
+//! shape, that it stores our announce, and that it accepts our replies to its own queries: it
+//! then lists our node in its routing table, which it only does for the nodes that answer. The
+//! `responder` example is not a reference node: it never queries the nodes it hears from.
+
+use std::{
+    env,
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcrawler_core::{
+    keyspace::TargetGenerator,
+    node::{DhtNode, DhtResponse, NodeConfig, NodeEvent},
+    proto::{
+        bencode,
+        kademlia::Id160,
+        krpc::{Port, Query, QueryType, ResponseType},
+    },
+    transport::SocketConfig,
+};
+
+const ADDRESS_VARIABLE: &str = "BITCRAWLER_REFERENCE_ADDRESS";
+const COMMAND_VARIABLE: &str = "BITCRAWLER_REFERENCE_COMMAND";
+/// Time the reference node has to answer its first ping, e.g. to pull a container image.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
+/// Time the reference node has to query our node and list it in its routing table.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Port of our announces.
+const PEER_PORT: u16 = 51413;
+
+/// A reference node the test talks to. Another way to run one (e.g. an embedded library) plugs
+/// in by implementing this trait, and stopping the node when dropped.
+trait ReferenceNode {
+    /// Get the UDP address the node listens on.
+    fn address(&self) -> SocketAddr;
+}
+
+/// A node started by a shell command, killed when dropped.
+struct ExternalNode {
+    address: SocketAddr,
+    child: Child,
+}
+
+impl ExternalNode {
+    fn spawn(command: &str, address: SocketAddr) -> ExternalNode {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .spawn()
+            .unwrap_or_else(|e| panic!("failed to run {:?}: {}", command, e));
+        ExternalNode { address, child }
+    }
+}
+
+impl ReferenceNode for ExternalNode {
+    fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for ExternalNode {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A node already running.
+struct RunningNode {
+    address: SocketAddr,
+}
+
+impl ReferenceNode for RunningNode {
+    fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+/// Get the reference node set by the environment variables.
+fn reference_node() -> Box<dyn ReferenceNode> {
+    let address = env::var(ADDRESS_VARIABLE)
+        .unwrap_or_else(|_| panic!("{} must be set to the reference node", ADDRESS_VARIABLE));
+    let address = address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.find(SocketAddr::is_ipv4))
+        .unwrap_or_else(|| panic!("invalid {} {:?}", ADDRESS_VARIABLE, address));
+    match env::var(COMMAND_VARIABLE) {
+        Ok(command) => Box::new(ExternalNode::spawn(&command, address)),
+        Err(_) => Box::new(RunningNode { address }),
+    }
+}
+
+/// Our node, querying the reference node one query at a time and answering its queries.
+struct Harness {
+    node: DhtNode,
+    id: Id160,
+    reference: SocketAddr,
+    // Number of queries of the reference node answered.
+    answered: usize,
+}
+
+impl Harness {
+    fn bind(id: Id160, reference: SocketAddr) -> Harness {
+        let mut config = NodeConfig::new(id);
+        config.socket = SocketConfig::new((Ipv4Addr::UNSPECIFIED, 0).into());
+        config.query_timeout = QUERY_TIMEOUT;
+        Harness {
+            node: DhtNode::bind(config).unwrap(),
+            id,
+            reference,
+            answered: 0,
+        }
+    }
+
+    /// Poll until the query in flight ends, returns the reply if it was answered.
+    fn reply(&mut self, method: &str) -> Option<DhtResponse> {
+        let mut events = Vec::new();
+        let mut reply = None;
+        while self.node.in_flight() > 0 {
+            self.node.poll(&mut events).unwrap();
+            for event in events.drain(..) {
+                match event {
+                    NodeEvent::Query { source, query } => self.answer(source, query),
+                    NodeEvent::Response { response, .. } => reply = Some(response),
+                    NodeEvent::Error { error, .. } => panic!("{} failed: {:?}", method, error),
+                    NodeEvent::Timeout { .. } | NodeEvent::Duplicate { .. } => {}
+                }
+            }
+        }
+        reply
+    }
+
+    /// Poll until the query in flight ends, the reply is required.
+    fn expect_reply(&mut self, method: &str) -> DhtResponse {
+        self.reply(method)
+            .unwrap_or_else(|| panic!("{} not answered", method))
+    }
+
+    /// Answer the queries of the reference node for `duration`.
+    fn serve(&mut self, duration: Duration) {
+        let end = Instant::now() + duration;
+        let mut events = Vec::new();
+        while Instant::now() < end {
+            self.node.poll(&mut events).unwrap();
+            for event in events.drain(..) {
+                if let NodeEvent::Query { source, query } = event {
+                    self.answer(source, query);
+                }
+            }
+        }
+    }
+
+    fn answer(&mut self, source: SocketAddr, query: Query<Id160>) {
+        let tid = query.get_transaction_id().clone();
+        let reply = match query.get_query() {
+            QueryType::FindNode(_) => DhtResponse::new_find_node(tid, self.id, Vec::new()),
+            QueryType::GetPeers(_) => DhtResponse::new_get_peers(
+                tid,
+                self.id,
+                Some("token".into()),
+                Vec::new(),
+                Vec::new(),
+            ),
+            _ => DhtResponse::new_ping(tid, self.id),
+        };
+        self.node
+            .send_to(&bencode::encode(&reply.to_bencoded()), source)
+            .unwrap();
+        self.answered += 1;
+    }
+
+    /// Ping the reference node until it answers, returns its id.
+    fn wait_until_ready(&mut self) -> Id160 {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            // Sends fail while the node is not listening yet (ICMP errors), then back off.
+            let sent = self.node.ping(self.reference).is_ok();
+            if sent && let Some(reply) = self.reply("ping") {
+                let ResponseType::Ping(ping) = reply.get_response_type() else {
+                    panic!("unexpected ping reply {:?}", reply);
+                };
+                return *ping.get_id();
+            }
+            assert!(
+                Instant::now() < deadline,
+                "the reference node did not answer in {:?}",
+                STARTUP_TIMEOUT
+            );
+            if !sent {
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_reference_node() {
+    let reference = reference_node();
+    let mut generator = TargetGenerator::new();
+    let mut harness = Harness::bind(generator.random_id(), reference.address());
+    let reference_id = harness.wait_until_ready();
+
+    // Our queries are answered, with the shape of their method.
+    harness
+        .node
+        .find_node(reference.address(), generator.random_id())
+        .unwrap();
+    let reply = harness.expect_reply("find_node");
+    assert!(
+        matches!(reply.get_response_type(), ResponseType::FindNode(_)),
+        "unexpected find_node reply {:?}",
+        reply
+    );
+
+    let info_hash = generator.random_id();
+    harness
+        .node
+        .get_peers(reference.address(), info_hash)
+        .unwrap();
+    let reply = harness.expect_reply("get_peers");
+    let ResponseType::GetPeers(get_peers) = reply.get_response_type() else {
+        panic!("unexpected get_peers reply {:?}", reply);
+    };
+    assert!(get_peers.get_token().is_some(), "get_peers without token");
+
+    // The announce is stored: the next get_peers returns it (from the address the reference
+    // node sees, which may be translated, so only the port is checked).
+    harness
+        .node
+        .announce_peer(
+            reference.address(),
+            reference_id,
+            info_hash,
+            Port::new(PEER_PORT),
+        )
+        .unwrap();
+    harness.expect_reply("announce_peer");
+    harness
+        .node
+        .get_peers(reference.address(), info_hash)
+        .unwrap();
+    let reply = harness.expect_reply("get_peers");
+    let ResponseType::GetPeers(get_peers) = reply.get_response_type() else {
+        panic!("unexpected get_peers reply {:?}", reply);
+    };
+    assert!(
+        get_peers
+            .get_peers()
+            .iter()
+            .any(|peer| peer.port() == PEER_PORT),
+        "announce not stored: {:?}",
+        get_peers.get_peers()
+    );
+
+    // Our replies are accepted: having heard from us, the reference node queries our node,
+    // then lists it among the nodes closest to our id (a node listed without being queried
+    // proves nothing).
+    let deadline = Instant::now() + ACCEPT_TIMEOUT;
+    loop {
+        harness
+            .node
+            .find_node(reference.address(), harness.id)
+            .unwrap();
+        if let Some(reply) = harness.reply("find_node")
+            && harness.answered > 0
+            && let ResponseType::FindNode(find_node) = reply.get_response_type()
+            && find_node
+                .get_nodes()
+                .iter()
+                .any(|node| node.node_id == harness.id)
+        {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "not in the routing table of the reference node after {:?} ({} queries answered)",
+            ACCEPT_TIMEOUT,
+            harness.answered
+        );
+        harness.serve(Duration::from_secs(5));
+    }
+}
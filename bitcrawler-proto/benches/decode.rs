@@ -0,0 +1,94 @@
+//! Measure the time and the allocations of decoding typical KRPC messages, in full and for
+//! their transaction id only (with a visitor, see `bencode::dict_string`).
+//!
+//! Run with `cargo bench -p bitcrawler-proto`. The allocations are counted by a global allocator
+//! wrapping the system one, so each message is decoded the same number of times on a single
+//! thread.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    net::SocketAddrV4,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use bitcrawler_proto::{
+    bencode::{self, BencodeValue},
+    kademlia::Id160,
+    krpc::{Query, Response, node_info::BittorrentNodeInfoV4},
+};
+
+const ITERATIONS: usize = 200_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+type DhtResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+
+fn bench(name: &str, message: &BencodeValue) {
+    let message = bencode::encode(message);
+    measure(name, "decode", &message, |message| {
+        black_box(bencode::decode(&message).unwrap());
+    });
+    measure(name, "tid", &message, |message| {
+        black_box(bencode::dict_string(message, b"t").unwrap());
+    });
+}
+
+fn measure(name: &str, method: &str, message: &[u8], parse: impl Fn(&[u8])) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        parse(black_box(message));
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{:<20} {:<6} {:>4} bytes {:>8.0} ns/message {:>6.1} allocations/message",
+        name,
+        method,
+        message.len(),
+        elapsed.as_nanos() as f64 / ITERATIONS as f64,
+        allocations as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    let id = Id160(*b"abcdefghij0123456789");
+    let nodes: Vec<_> = (0..8)
+        .map(|i| BittorrentNodeInfoV4 {
+            node_id: Id160([i; 20]),
+            ip: [192, 0, 2, i],
+            port: 6881,
+        })
+        .collect();
+    let peers: Vec<_> = (0..8)
+        .map(|i| SocketAddrV4::new([198, 51, 100, i].into(), 51413))
+        .collect();
+
+    let ping = Query::new_ping("aaaa", id);
+    bench("ping query", &ping.to_bencoded());
+    let find_node = Query::new_find_node("aaaa", id, Id160([7; 20]));
+    bench("find_node query", &find_node.to_bencoded());
+    let find_node = DhtResponse::new_find_node("aaaa", id, nodes);
+    bench("find_node reply", &find_node.to_bencoded());
+    let get_peers = DhtResponse::new_get_peers("aaaa", id, Some("token".into()), vec![], peers);
+    bench("get_peers reply", &get_peers.to_bencoded());
+}
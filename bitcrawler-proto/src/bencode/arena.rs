@@ -0,0 +1,174 @@
+use std::ops::Range;
+
+use super::{BencodeString, BencodeValue};
+
+/// A node of a [`BencodeArena`], in depth-first order: a list or a dictionary is followed by
+/// its items (for a dictionary, each key node then its value).
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ArenaNode {
+    /// A string, as a range of the bytes of the arena.
+    String(Range<usize>),
+    Integer(i128),
+    List(usize),
+    Dict(usize),
+}
+
+/// A deep copy of a [`BencodeValue`] held in two buffers, whatever its depth: one for the
+/// bytes of all its strings, one for the structure.
+///
+/// A decoded message is a tree of small allocations, one per string, list and dictionary. Its
+/// arena copy takes two allocations of known size, which makes it cheap to keep around (e.g.
+/// in a queue) and its memory easy to account for with [`BencodeArena::deep_size`].
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::bencode::{BencodeArena, BencodeValue, decode};
+///
+/// let (_, value) = decode(&&b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"[..])
+///     .unwrap();
+/// let arena = BencodeArena::new(&value);
+/// assert_eq!(arena.to_value(), value);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BencodeArena {
+    bytes: Vec<u8>,
+    nodes: Vec<ArenaNode>,
+}
+
+impl BencodeArena {
+    /// Copy `value` into an arena, allocating each buffer once.
+    pub fn new(value: &BencodeValue) -> BencodeArena {
+        let (nodes, bytes) = count(value);
+        let mut arena = BencodeArena {
+            bytes: Vec::with_capacity(bytes),
+            nodes: Vec::with_capacity(nodes),
+        };
+        arena.push(value);
+        arena
+    }
+
+    fn push(&mut self, value: &BencodeValue) {
+        match value {
+            BencodeValue::ByteString(string) => self.push_string(string),
+            BencodeValue::Integer(integer) => self.nodes.push(ArenaNode::Integer(*integer)),
+            BencodeValue::List(list) => {
+                self.nodes.push(ArenaNode::List(list.len()));
+                for item in list {
+                    self.push(item);
+                }
+            }
+            BencodeValue::Dict(dict) => {
+                self.nodes.push(ArenaNode::Dict(dict.len()));
+                for (key, item) in dict {
+                    self.push_string(key);
+                    self.push(item);
+                }
+            }
+        }
+    }
+
+    fn push_string(&mut self, string: &BencodeString) {
+        let start = self.bytes.len();
+        self.bytes.extend_from_slice(string.as_ref());
+        self.nodes.push(ArenaNode::String(start..self.bytes.len()));
+    }
+
+    /// Rebuild the value the arena was copied from.
+    pub fn to_value(&self) -> BencodeValue {
+        let mut nodes = self.nodes.iter();
+        let value = self.read(&mut nodes);
+        debug_assert!(nodes.next().is_none(), "arena nodes left");
+        value
+    }
+
+    fn read<'a>(&self, nodes: &mut impl Iterator<Item = &'a ArenaNode>) -> BencodeValue {
+        match nodes.next().expect("arena truncated") {
+            ArenaNode::String(range) => BencodeValue::ByteString(self.bytes[range.clone()].into()),
+            ArenaNode::Integer(integer) => BencodeValue::Integer(*integer),
+            ArenaNode::List(len) => {
+                BencodeValue::List((0..*len).map(|_| self.read(nodes)).collect())
+            }
+            ArenaNode::Dict(len) => BencodeValue::Dict(
+                (0..*len)
+                    .map(|_| {
+                        let key = match self.read(nodes) {
+                            BencodeValue::ByteString(key) => key,
+                            _ => unreachable!("arena key not a string"),
+                        };
+                        (key, self.read(nodes))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Get the heap memory held by the arena, its two buffers.
+    pub fn deep_size(&self) -> usize {
+        self.bytes.capacity() + self.nodes.capacity() * size_of::<ArenaNode>()
+    }
+}
+
+impl From<&BencodeValue> for BencodeArena {
+    fn from(value: &BencodeValue) -> Self {
+        BencodeArena::new(value)
+    }
+}
+
+/// Count the nodes and the string bytes of `value`.
+fn count(value: &BencodeValue) -> (usize, usize) {
+    match value {
+        BencodeValue::ByteString(string) => (1, string.as_ref().len()),
+        BencodeValue::Integer(_) => (1, 0),
+        BencodeValue::List(list) => list.iter().map(count).fold((1, 0), add),
+        BencodeValue::Dict(dict) => dict
+            .iter()
+            .map(|(key, value)| add((1, key.as_ref().len()), count(value)))
+            .fold((1, 0), add),
+    }
+}
+
+fn add(a: (usize, usize), b: (usize, usize)) -> (usize, usize) {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena_round_trip() {
+        let value = BencodeValue::from_dict(vec![
+            ("a", BencodeValue::from_list(vec![])),
+            (
+                "r",
+                BencodeValue::from_dict(vec![
+                    (
+                        "id",
+                        BencodeValue::ByteString(b"0123456789abcdefghij".to_vec().into()),
+                    ),
+                    (
+                        "values",
+                        BencodeValue::from_list(vec![
+                            BencodeValue::ByteString(b"abcdef".to_vec().into()),
+                            BencodeValue::Integer(-1),
+                            BencodeValue::from_dict::<&str>(vec![]),
+                        ]),
+                    ),
+                ]),
+            ),
+            ("t", BencodeValue::ByteString(b"".to_vec().into())),
+        ]);
+        let arena = BencodeArena::new(&value);
+        assert_eq!(arena.to_value(), value);
+        // Every byte and node is allocated at once.
+        assert_eq!(arena.bytes.capacity(), arena.bytes.len());
+        assert_eq!(arena.bytes.len(), 1 + 1 + 2 + 20 + 6 + 6 + 1);
+        assert_eq!(arena.nodes.capacity(), arena.nodes.len());
+        assert_eq!(arena.nodes.len(), 14);
+        assert_eq!(arena.deep_size(), 37 + 14 * size_of::<ArenaNode>());
+
+        let integer = BencodeValue::Integer(42);
+        assert_eq!(BencodeArena::from(&integer).to_value(), integer);
+    }
+}
@@ -5,7 +5,9 @@ use std::borrow::Cow;
 /// # Variants
 ///
 /// - `ByteString(BencodeString)`: Represents a Bencoded string, even if most of the time, it represents binary data and not a printable string.
-/// - `Integer(i64)`: Represents a Bencoded integer.
+/// - `Integer(i128)`: Represents a Bencoded integer. The range is wider than what most fields
+///   take (ports, sizes, timestamps): read them with the checked accessors, e.g.
+///   [`BencodeValue::as_u64_checked`], rather than casting.
 /// - `List(BencodeList)`: Represents a Bencoded list, which is a collection of other Bencoded values.
 /// - `Dict(BencodeDict)`: Represents a Bencoded dictionary, which is a collection of key-value pairs where keys are strings and values are other Bencoded values.
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -17,8 +19,11 @@ pub enum BencodeValue {
 }
 
 /// Represents a Bencoded (byte) string.
+///
+/// The decoder shares the common dictionary keys (e.g. `id`, `nodes`) between the strings
+/// instead of allocating them, read the bytes with [`BencodeString::as_bytes`].
 #[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Hash)]
-pub struct BencodeString(pub Vec<u8>);
+pub struct BencodeString(Cow<'static, [u8]>);
 
 /// Represents a Bencoded dictionary, which is a collection of key-value pairs where keys are strings and values are other Bencoded values.
 /// The keys are sorted to ensure consistent serialization (expected by the spec).
@@ -30,7 +35,7 @@ pub type BencodeList = Vec<BencodeValue>;
 
 impl BencodeValue {
     pub fn from_string(input: String) -> Self {
-        BencodeValue::ByteString(BencodeString(input.into_bytes()))
+        BencodeValue::ByteString(input.into())
     }
 
     pub fn from_integer<I>(input: I) -> Self
@@ -53,41 +58,175 @@ impl BencodeValue {
         )
     }
 
+    /// Get the value of `key`, if the value is a dictionary holding it.
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeValue> {
+        match self {
+            BencodeValue::Dict(dict) => dict
+                .iter()
+                .find(|(k, _)| k.as_ref() == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Get the integer, if the value is one.
+    pub fn as_integer(&self) -> Option<i128> {
+        match self {
+            BencodeValue::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Get the integer as an `i64`, e.g. a timestamp. Fails if the value is not an integer or
+    /// does not fit.
+    pub fn as_i64_checked(&self) -> Result<i64, &'static str> {
+        let value = self.as_integer().ok_or("not an integer")?;
+        i64::try_from(value).or(Err("integer out of the i64 range"))
+    }
+
+    /// Get the integer as a `u64`, e.g. a size. Fails if the value is not an integer, is
+    /// negative or does not fit.
+    pub fn as_u64_checked(&self) -> Result<u64, &'static str> {
+        let value = self.as_integer().ok_or("not an integer")?;
+        if value < 0 {
+            return Err("negative integer");
+        }
+        u64::try_from(value).or(Err("integer out of the u64 range"))
+    }
+
+    /// Get the integer as a port. Fails if the value is not an integer or is not between 0 and
+    /// 65535; port 0 is accepted, e.g. for the `port` of an `announce_peer` with
+    /// `implied_port`.
+    ///
+    /// ```
+    /// # use bitcrawler_proto::bencode::BencodeValue;
+    /// assert_eq!(BencodeValue::Integer(6881).as_u16_port(), Ok(6881));
+    /// assert_eq!(BencodeValue::Integer(70000).as_u16_port(), Err("port out of range"));
+    /// ```
+    pub fn as_u16_port(&self) -> Result<u16, &'static str> {
+        let value = self.as_integer().ok_or("port is not an integer")?;
+        u16::try_from(value).or(Err("port out of range"))
+    }
+
+    /// Get the heap memory held by the value, nested values included: the buffers of its
+    /// strings, lists and dictionaries (by capacity, not length). The strings borrowed from
+    /// the decoder keys take none.
+    ///
+    /// Add `size_of::<BencodeValue>()` for the value itself, see also
+    /// [`BencodeArena::deep_size`](super::BencodeArena::deep_size).
+    pub fn deep_size(&self) -> usize {
+        match self {
+            BencodeValue::ByteString(string) => string.deep_size(),
+            BencodeValue::Integer(_) => 0,
+            BencodeValue::List(list) => {
+                list.capacity() * size_of::<BencodeValue>()
+                    + list.iter().map(BencodeValue::deep_size).sum::<usize>()
+            }
+            BencodeValue::Dict(dict) => {
+                dict.capacity() * size_of::<(BencodeString, BencodeValue)>()
+                    + dict
+                        .iter()
+                        .map(|(key, value)| key.deep_size() + value.deep_size())
+                        .sum::<usize>()
+            }
+        }
+    }
+
     /// Sort the keys of all dictionaries to ensure consistent serialization (expected by the spec).
     pub fn sort_keys(&mut self) {
+        if let BencodeValue::Dict(dict) = self {
+            dict.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (_, value) in dict {
+                value.sort_keys();
+            }
+        }
+    }
+
+    /// Check if the value is in canonical form: the keys of every dictionary, nested ones
+    /// included, are sorted and unique.
+    ///
+    /// A canonical value is encoded back to the same bytes it was decoded from, as long as
+    /// these bytes were canonical themselves, see
+    /// [`is_canonical_encoding`](super::is_canonical_encoding).
+    pub fn is_canonical(&self) -> bool {
+        match self {
+            BencodeValue::ByteString(_) | BencodeValue::Integer(_) => true,
+            BencodeValue::List(list) => list.iter().all(BencodeValue::is_canonical),
+            BencodeValue::Dict(dict) => {
+                dict.windows(2).all(|pair| pair[0].0 < pair[1].0)
+                    && dict.iter().all(|(_, value)| value.is_canonical())
+            }
+        }
+    }
+
+    /// Put the value in canonical form (see [`BencodeValue::is_canonical`]): sort the keys of
+    /// every dictionary, nested ones included, and keep the first entry of the duplicate keys.
+    pub fn canonicalize(&mut self) {
         match self {
+            BencodeValue::ByteString(_) | BencodeValue::Integer(_) => {}
+            BencodeValue::List(list) => {
+                for value in list {
+                    value.canonicalize();
+                }
+            }
             BencodeValue::Dict(dict) => {
+                // The sort is stable: the first of the duplicates stays first.
                 dict.sort_by(|(a, _), (b, _)| a.cmp(b));
+                dict.dedup_by(|(b, _), (a, _)| a == b);
                 for (_, value) in dict {
-                    value.sort_keys();
+                    value.canonicalize();
                 }
             }
-            _ => {}
+        }
+    }
+}
+
+impl BencodeString {
+    /// Create a string sharing static bytes, without allocating.
+    pub(crate) const fn from_static(bytes: &'static [u8]) -> BencodeString {
+        BencodeString(Cow::Borrowed(bytes))
+    }
+
+    /// Get the bytes of the string.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Get the bytes of the string, copied if they are shared.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0.into_owned()
+    }
+
+    /// Get the heap memory held by the string, 0 if it is shared.
+    pub fn deep_size(&self) -> usize {
+        match &self.0 {
+            Cow::Borrowed(_) => 0,
+            Cow::Owned(bytes) => bytes.capacity(),
         }
     }
 }
 
 impl From<String> for BencodeString {
     fn from(input: String) -> Self {
-        BencodeString(input.into_bytes())
+        BencodeString(Cow::Owned(input.into_bytes()))
     }
 }
 
 impl From<&str> for BencodeString {
     fn from(input: &str) -> Self {
-        BencodeString(input.as_bytes().to_vec())
+        BencodeString(Cow::Owned(input.as_bytes().to_vec()))
     }
 }
 
 impl From<&[u8]> for BencodeString {
     fn from(input: &[u8]) -> Self {
-        BencodeString(input.to_vec())
+        BencodeString(Cow::Owned(input.to_vec()))
     }
 }
 
 impl From<Vec<u8>> for BencodeString {
     fn from(input: Vec<u8>) -> Self {
-        BencodeString(input)
+        BencodeString(Cow::Owned(input))
     }
 }
 
@@ -105,7 +244,7 @@ impl<'a> From<&'a BencodeString> for Cow<'a, BencodeString> {
 
 impl From<BencodeString> for Vec<u8> {
     fn from(input: BencodeString) -> Self {
-        input.0
+        input.0.into_owned()
     }
 }
 
@@ -113,7 +252,7 @@ impl TryFrom<BencodeString> for String {
     type Error = std::string::FromUtf8Error;
 
     fn try_from(input: BencodeString) -> Result<Self, Self::Error> {
-        String::from_utf8(input.0)
+        String::from_utf8(input.0.into_owned())
     }
 }
 
@@ -134,3 +273,77 @@ impl<T: Into<BencodeString>> From<Vec<(T, BencodeValue)>> for BencodeValue {
         BencodeValue::from_dict(input)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integer_accessors() {
+        let value = |i: i128| BencodeValue::Integer(i);
+        let string = BencodeValue::ByteString("42".into());
+        assert_eq!(value(-1).as_integer(), Some(-1));
+        assert_eq!(string.as_integer(), None);
+
+        assert_eq!(value(-1).as_i64_checked(), Ok(-1));
+        let too_large = value(i64::MAX as i128 + 1);
+        assert_eq!(
+            too_large.as_i64_checked().unwrap_err(),
+            "integer out of the i64 range"
+        );
+        assert_eq!(string.as_i64_checked().unwrap_err(), "not an integer");
+
+        assert_eq!(value(u64::MAX as i128).as_u64_checked(), Ok(u64::MAX));
+        assert_eq!(value(-1).as_u64_checked().unwrap_err(), "negative integer");
+        assert_eq!(
+            value(1 << 64).as_u64_checked().unwrap_err(),
+            "integer out of the u64 range"
+        );
+
+        assert_eq!(value(0).as_u16_port(), Ok(0));
+        assert_eq!(value(65535).as_u16_port(), Ok(65535));
+        assert_eq!(value(-1).as_u16_port().unwrap_err(), "port out of range");
+        assert_eq!(string.as_u16_port().unwrap_err(), "port is not an integer");
+    }
+
+    #[test]
+    fn test_deep_size() {
+        let string = |s: &str| BencodeValue::ByteString(s.into());
+        assert_eq!(BencodeValue::Integer(1).deep_size(), 0);
+        assert_eq!(string("abcd").deep_size(), 4);
+        assert_eq!(BencodeString(Cow::Borrowed(b"id")).deep_size(), 0);
+
+        let list = BencodeValue::from_list(vec![string("ab"), BencodeValue::Integer(1)]);
+        assert_eq!(list.deep_size(), 2 * size_of::<BencodeValue>() + 2);
+        let dict = BencodeValue::from_dict(vec![("key", list.clone())]);
+        assert_eq!(
+            dict.deep_size(),
+            size_of::<(BencodeString, BencodeValue)>() + 3 + list.deep_size()
+        );
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let mut value = BencodeValue::from_list(vec![
+            BencodeValue::from_integer(-3),
+            BencodeValue::from_dict(vec![
+                ("b", BencodeValue::from_integer(1)),
+                ("a", BencodeValue::from_integer(2)),
+                ("b", BencodeValue::from_integer(3)),
+            ]),
+        ]);
+        assert!(!value.is_canonical());
+        value.canonicalize();
+        assert!(value.is_canonical());
+        assert_eq!(
+            value,
+            BencodeValue::from_list(vec![
+                BencodeValue::from_integer(-3),
+                BencodeValue::from_dict(vec![
+                    ("a", BencodeValue::from_integer(2)),
+                    ("b", BencodeValue::from_integer(1)),
+                ]),
+            ])
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::num::IntErrorKind;
+
 use super::{BencodeString, BencodeValue, Error};
 
 /// Decodes a bencoded string from the given input.
@@ -36,8 +38,69 @@ pub fn decode_string<T>(input: &T) -> Result<(usize, BencodeString), Error>
 where
     T: AsRef<[u8]>,
 {
-    let input = input.as_ref();
+    let (read, string) = read_string(input.as_ref())?;
+    Ok((read, string.to_vec().into()))
+}
+
+/// Get the shared static string of a dictionary key of the KRPC messages (BEP 5 and its common
+/// extensions) or of the extension protocol messages (BEP 9, 10 and 11), so that these keys are
+/// not allocated for every message.
+///
+/// The candidate is picked by the length, first and last byte of the key, then compared.
+fn interned_key(key: &[u8]) -> Option<&'static [u8]> {
+    let (&first, &last) = (key.first()?, key.last()?);
+    let interned: &'static [u8] = match (key.len(), first, last) {
+        (1, b'a', _) => b"a",
+        (1, b'e', _) => b"e",
+        (1, b'm', _) => b"m",
+        (1, b'p', _) => b"p",
+        (1, b'q', _) => b"q",
+        (1, b'r', _) => b"r",
+        (1, b't', _) => b"t",
+        (1, b'v', _) => b"v",
+        (1, b'y', _) => b"y",
+        (2, b'i', b'd') => b"id",
+        (2, b'i', b'p') => b"ip",
+        (2, b'r', _) => b"ro",
+        (4, b'B', b'e') => b"BFpe",
+        (4, b'B', b'd') => b"BFsd",
+        (4, b'p', _) => b"port",
+        (4, b'r', _) => b"reqq",
+        (4, b'w', _) => b"want",
+        (5, b'a', _) => b"added",
+        (5, b'n', _) => b"nodes",
+        (5, b'p', _) => b"piece",
+        (5, b't', _) => b"token",
+        (6, b'n', b'6') => b"nodes6",
+        (6, b'n', b'd') => b"noseed",
+        (6, b's', _) => b"scrape",
+        (6, b't', _) => b"target",
+        (6, b'v', _) => b"values",
+        (6, b'y', _) => b"yourip",
+        (7, b'a', _) => b"added.f",
+        (7, b'd', _) => b"dropped",
+        (8, b'm', _) => b"msg_type",
+        (9, b'i', _) => b"info_hash",
+        (10, b't', _) => b"total_size",
+        (12, b'i', _) => b"implied_port",
+        (13, b'm', _) => b"metadata_size",
+        _ => return None,
+    };
+    (interned == key).then_some(interned)
+}
+
+/// Decode a dictionary key, without allocating it if it is interned (see [`interned_key`]).
+fn decode_key(input: &[u8]) -> Result<(usize, BencodeString), Error> {
+    let (read, key) = read_string(input)?;
+    let key = match interned_key(key) {
+        Some(interned) => BencodeString::from_static(interned),
+        None => key.to_vec().into(),
+    };
+    Ok((read, key))
+}
 
+/// Read a bencoded string, returns the number of bytes read and the content of the string.
+pub(super) fn read_string(input: &[u8]) -> Result<(usize, &[u8]), Error> {
     // Find the separator index and parse the length.
     let separator_index = input
         .iter()
@@ -47,7 +110,7 @@ where
         let length_str = &input[0..separator_index];
         let mut value = 0;
         for &c in length_str {
-            if c < b'0' || c > b'9' {
+            if !c.is_ascii_digit() {
                 return Err(Error::InvalidString);
             }
             value = value * 10 + (c - b'0') as usize;
@@ -56,19 +119,15 @@ where
     };
 
     // Return the decoded string if the length is valid.
-    if length == 0 {
-        return Ok((separator_index + 1, BencodeString(vec![])));
-    } else if length > input.len() - separator_index - 1 {
-        return Err(Error::InvalidString);
+    if length > input.len() - separator_index - 1 {
+        Err(Error::InvalidString)
     } else {
         // Note that all indices on string are in bytes, so we need to add 1 to the separator index to skip the separator.
         // The length is the number of bytes to read a fortiori.
-        return Ok((
+        Ok((
             separator_index + length + 1,
-            input[separator_index + 1..separator_index + 1 + length]
-                .to_vec()
-                .into(),
-        ));
+            &input[separator_index + 1..separator_index + 1 + length],
+        ))
     }
 }
 
@@ -90,7 +149,10 @@ where
 /// - The input does not start with the `i` character.
 /// - The input does not contain the `e` character.
 /// - The integer is not a valid signed integer.
-/// - The integer is not within the range of `i128` (spec does not specify a maximum size).
+///
+/// It will return an `Error::IntegerOverflow` if the integer is not within the range of `i128`
+/// (spec does not specify a maximum size), or of `i64` with the `i64-integers` feature: most
+/// storages (SQLite, JSON numbers) cannot hold more, and an error beats a silent truncation.
 ///
 /// This function will not return an error if the integer is prefixed with zeros (e.g., `i000e`).
 pub fn decode_integer<T>(input: &T) -> Result<(usize, i128), Error>
@@ -113,9 +175,14 @@ where
 
     // Parse the integer.
     let integer_string = String::from_utf8_lossy(&input[1..end_index]);
-    let integer = integer_string
-        .parse::<i128>()
-        .map_err(|_| Error::InvalidInteger)?;
+    let integer = integer_string.parse::<i128>().map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => Error::IntegerOverflow,
+        _ => Error::InvalidInteger,
+    })?;
+    #[cfg(feature = "i64-integers")]
+    if i64::try_from(integer).is_err() {
+        return Err(Error::IntegerOverflow);
+    }
 
     // Return the decoded integer.
     Ok((end_index + 1, integer))
@@ -141,7 +208,40 @@ enum DecodeState {
 ///
 /// * `Ok(usize, BencodedValue)` - The decoded value if the input is valid and the number of characters read.
 /// * `Err(_)` - If the input is not a valid bencoded value.
+///
+/// The input must hold a single value: trailing bytes are an error, see [`decode_prefix`] to
+/// decode a value followed by other data.
 pub fn decode<T>(input: &T) -> Result<(usize, BencodeValue), Error>
+where
+    T: AsRef<[u8]>,
+{
+    let input = input.as_ref();
+    let (read, value) = decode_prefix(&input)?;
+    if read != input.len() {
+        return Err(Error::InvalidValue);
+    }
+    Ok((read, value))
+}
+
+/// Decodes the bencoded value at the start of the given input, as [`decode`], but stops at the
+/// end of this value: the bytes following it (e.g. the raw data after the header of a BEP 9
+/// `ut_metadata` message) are not read.
+///
+/// Returns the decoded value and the number of characters read, where the following data
+/// starts.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::bencode::{decode, decode_prefix, BencodeValue};
+///
+/// let input = b"d8:msg_typei1ee<raw data>";
+/// let (read, value) = decode_prefix(&input).unwrap();
+/// assert_eq!(&input[read..], b"<raw data>");
+/// assert!(matches!(value, BencodeValue::Dict(_)));
+/// assert!(decode(&input).is_err());
+/// ```
+pub fn decode_prefix<T>(input: &T) -> Result<(usize, BencodeValue), Error>
 where
     T: AsRef<[u8]>,
 {
@@ -152,6 +252,9 @@ where
 
     let mut cursor = 0;
     while cursor < len {
+        if let [DecodeState::Start, DecodeState::Value(_)] = stack.as_slice() {
+            break;
+        }
         let char = input[cursor] as char;
         let input_ = &input[cursor..];
         match char {
@@ -186,12 +289,8 @@ where
                         match state {
                             DecodeState::ListStart => {
                                 let mut list = Vec::new();
-                                loop {
-                                    if let Some(DecodeState::Value(value)) = values.pop() {
-                                        list.push(value);
-                                    } else {
-                                        break;
-                                    }
+                                while let Some(DecodeState::Value(value)) = values.pop() {
+                                    list.push(value);
                                 }
                                 if !values.is_empty() {
                                     return Err(Error::InvalidValue);
@@ -217,12 +316,8 @@ where
                             }
                             DecodeState::DictStart => {
                                 let mut dict = Vec::new();
-                                loop {
-                                    if let Some(DecodeState::DictEntry(key, value)) = values.pop() {
-                                        dict.push((key, value));
-                                    } else {
-                                        break;
-                                    }
+                                while let Some(DecodeState::DictEntry(key, value)) = values.pop() {
+                                    dict.push((key, value));
                                 }
                                 if !values.is_empty() {
                                     return Err(Error::InvalidValue);
@@ -262,8 +357,11 @@ where
                 }
             }
             _ => {
-                let value = decode_string(&input_)?;
                 let state = stack.pop().expect("Invalid stack state");
+                let value = match state {
+                    DecodeState::DictStart | DecodeState::DictEntry(_, _) => decode_key(input_)?,
+                    _ => decode_string(&input_)?,
+                };
                 cursor += value.0;
                 match state {
                     DecodeState::DictKey(key) => {
@@ -298,6 +396,37 @@ where
     }
 }
 
+/// Check if the input is the canonical encoding of a bencoded value: a single value, without
+/// trailing bytes, encoded back to the same bytes.
+///
+/// The decoder is lenient: it accepts integers with leading zeros or a negative zero
+/// (`i03e`, `i-0e`), string lengths with leading zeros (`03:abc`), and unsorted or duplicate
+/// dictionary keys. These inputs lose their exact bytes once decoded, so anything hashed over
+/// them (e.g. the `info` dictionary of a torrent, hashed into its info hash) must be hashed over
+/// the original bytes, not over a re-encoding of the decoded value.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::bencode::is_canonical_encoding;
+///
+/// assert!(is_canonical_encoding(b"d1:ai1e1:bi2ee"));
+/// assert!(!is_canonical_encoding(b"d1:bi2e1:ai1ee"));
+/// assert!(!is_canonical_encoding(b"i03e"));
+/// ```
+pub fn is_canonical_encoding<T>(input: &T) -> bool
+where
+    T: AsRef<[u8]> + ?Sized,
+{
+    let input = input.as_ref();
+    match decode(&input) {
+        Ok((read, value)) => {
+            read == input.len() && value.is_canonical() && super::encode(&value) == input
+        }
+        Err(_) => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -421,6 +550,25 @@ mod tests {
         assert_eq!(result, Ok((3, 0)));
     }
 
+    #[test]
+    fn test_integer_overflow() {
+        let max = format!("i{}e", i64::MIN);
+        assert_eq!(decode_integer(&max), Ok((max.len(), i64::MIN as i128)));
+        let above = format!("i{}e", i64::MAX as i128 + 1);
+        let expected = if cfg!(feature = "i64-integers") {
+            Err(Error::IntegerOverflow)
+        } else {
+            Ok((above.len(), i64::MAX as i128 + 1))
+        };
+        assert_eq!(decode_integer(&above), expected);
+        let huge = format!("i{}0e", i128::MIN);
+        assert_eq!(decode_integer(&huge), Err(Error::IntegerOverflow));
+        assert_eq!(
+            decode(&format!("li1ei{}0ee", i128::MAX)),
+            Err(Error::IntegerOverflow)
+        );
+    }
+
     #[test]
     fn test_valid_bencoded_list() {
         let input = b"l4:spam4:eggse";
@@ -487,6 +635,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_trailing_data_is_not_read() {
+        let input = b"d8:msg_typei1e5:piecei0eeraw data";
+        let (read, value) = decode_prefix(&input).unwrap();
+        assert_eq!(read, 25);
+        assert!(matches!(value, BencodeValue::Dict(_)));
+        let spam = BencodeValue::ByteString("spam".into());
+        assert_eq!(decode_prefix(&b"4:spam4:eggs"), Ok((6, spam)));
+
+        // The trailing data is an error for decode.
+        assert_eq!(decode(&input), Err(Error::InvalidValue));
+        assert_eq!(decode(&b"4:spam4:eggs"), Err(Error::InvalidValue));
+        assert_eq!(decode(&b"i1ee"), Err(Error::InvalidValue));
+    }
+
     #[test]
     fn test_valid_bencoded_list_in_list() {
         let input = b"lli4ei-4ei0eee";
@@ -535,4 +698,108 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_is_canonical_encoding() {
+        let canonical: [&[u8]; 6] = [
+            b"i0e",
+            b"i-3e",
+            b"0:",
+            b"4:spam",
+            b"l4:spami42ee",
+            b"d1:ai1e1:bli2eee",
+        ];
+        for input in canonical {
+            assert!(is_canonical_encoding(input), "{:?}", input);
+        }
+        // Leading zeros, negative zero, unsorted or duplicate keys, trailing bytes, truncated.
+        let lenient: [&[u8]; 7] = [
+            b"i03e",
+            b"i-0e",
+            b"04:spam",
+            b"d1:bi1e1:ai2ee",
+            b"d1:ai1e1:ai2ee",
+            b"i1ei2e",
+            b"l",
+        ];
+        for input in lenient {
+            assert!(!is_canonical_encoding(input), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_interned_keys() {
+        let input = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e\
+                      1:q9:find_node1:t2:aa1:y1:q3:zzz0:e";
+        let (_, value) = decode(input).unwrap();
+        let BencodeValue::Dict(dict) = &value else {
+            panic!("Invalid value");
+        };
+        // The known keys are shared, the other keys and the values are owned.
+        let borrowed = |string: &BencodeString| string.deep_size() == 0;
+        let keys: Vec<_> = dict.iter().map(|(key, _)| borrowed(key)).collect();
+        assert_eq!(keys, [true, true, true, true, false]);
+        let Some((_, BencodeValue::Dict(arguments))) = dict.first() else {
+            panic!("Invalid value");
+        };
+        assert!(arguments.iter().all(|(key, _)| borrowed(key)));
+        assert!(matches!(&dict[1].1, BencodeValue::ByteString(method) if !borrowed(method)));
+        assert_eq!(dict[4].0, b"zzz".as_ref().into());
+        assert_eq!(crate::bencode::encode(&value), input);
+    }
+
+    #[test]
+    fn test_interned_key_lookup() {
+        let keys: &[&[u8]] = &[
+            b"a",
+            b"e",
+            b"q",
+            b"r",
+            b"t",
+            b"v",
+            b"y",
+            b"id",
+            b"ip",
+            b"ro",
+            b"info_hash",
+            b"implied_port",
+            b"nodes",
+            b"nodes6",
+            b"port",
+            b"scrape",
+            b"noseed",
+            b"target",
+            b"token",
+            b"values",
+            b"want",
+            b"BFpe",
+            b"BFsd",
+            b"m",
+            b"p",
+            b"reqq",
+            b"yourip",
+            b"metadata_size",
+            b"msg_type",
+            b"piece",
+            b"total_size",
+            b"added",
+            b"added.f",
+            b"dropped",
+        ];
+        for key in keys {
+            assert_eq!(interned_key(key), Some(*key));
+        }
+        // Same length, first and last byte as interned keys, but other keys.
+        for key in [
+            b"" as &[u8],
+            b"b",
+            b"ix",
+            b"BFxe",
+            b"nodez",
+            b"naaaa6",
+            b"tokens",
+        ] {
+            assert_eq!(interned_key(key), None);
+        }
+    }
 }
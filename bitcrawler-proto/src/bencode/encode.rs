@@ -14,11 +14,11 @@ where
     W: std::io::Write,
 {
     let input = input.into();
-    let length = input.0.len();
+    let length = input.as_bytes().len();
     let length_str = length.to_string();
     output.write_all(length_str.as_bytes()).unwrap();
     output.write_all(b":").unwrap();
-    output.write_all(&input.0).unwrap();
+    output.write_all(input.as_bytes()).unwrap();
 }
 
 /// Write an integer (as bencode) to the output.
@@ -65,12 +65,12 @@ where
 /// *Reference:* [BEP 3](https://www.bittorrent.org/beps/bep_0003.html)
 pub fn encode_string<T: Into<BencodeString>>(input: T) -> Vec<u8> {
     let input: BencodeString = input.into();
-    let length = input.0.len();
+    let length = input.as_bytes().len();
     let length_str = length.to_string();
     let mut result = Vec::new();
     result.extend_from_slice(length_str.as_bytes());
     result.push(b':');
-    result.extend_from_slice(&input.0);
+    result.extend_from_slice(input.as_bytes());
     result
 }
 
@@ -154,7 +154,7 @@ pub fn encode(input: &BencodeValue) -> Vec<u8> {
             }
         }
     }
-    return token_stack;
+    token_stack
 }
 
 #[cfg(test)]
@@ -214,7 +214,7 @@ mod tests {
 
     #[test]
     fn encode_test_string() {
-        let result = encode(&&BencodeValue::ByteString("hello".into()));
+        let result = encode(&BencodeValue::ByteString("hello".into()));
         assert_eq!(result, b"5:hello");
     }
 
@@ -3,6 +3,8 @@ use std::fmt::{self, Debug, Display, Formatter};
 #[derive(PartialEq)]
 pub enum Error {
     InvalidInteger,
+    /// The integer does not fit in an `i128`, or in an `i64` with the `i64-integers` feature.
+    IntegerOverflow,
     InvalidString,
     InvalidList,
     InvalidDict,
@@ -10,9 +12,10 @@ pub enum Error {
 }
 
 impl Error {
-    pub fn message(&self) -> &str {
+    pub fn message(&self) -> &'static str {
         match self {
             Error::InvalidInteger => "Invalid integer",
+            Error::IntegerOverflow => "Integer out of range",
             Error::InvalidString => "Invalid string",
             Error::InvalidList => "Invalid list",
             Error::InvalidDict => "Invalid dictionary",
@@ -1,9 +1,13 @@
+mod arena;
 mod common;
 mod decode;
 mod encode;
 mod error;
+mod visit;
 
+pub use arena::*;
 pub use common::*;
 pub use decode::*;
 pub use encode::*;
 pub use error::*;
+pub use visit::*;
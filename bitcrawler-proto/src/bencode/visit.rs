@@ -0,0 +1,356 @@
+use super::{Error, decode::read_string, decode_integer};
+
+/// What a [`BencodeVisitor`] wants after a callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    /// Go on with the next token.
+    Continue,
+    /// Stop parsing: [`parse_with`] returns at once, the rest of the input is not read (nor
+    /// checked).
+    Stop,
+}
+
+/// Callbacks of [`parse_with`], one per token of the input, in depth-first order.
+///
+/// Every callback continues by default, so a visitor only implements the ones it needs. The
+/// strings are borrowed from the input, a visitor may keep them.
+pub trait BencodeVisitor<'a> {
+    /// An integer, as a dictionary value or a list item.
+    fn integer(&mut self, _value: i128) -> Visit {
+        Visit::Continue
+    }
+
+    /// A string, as a dictionary value or a list item (the keys go to
+    /// [`BencodeVisitor::dict_key`]).
+    fn string(&mut self, _value: &'a [u8]) -> Visit {
+        Visit::Continue
+    }
+
+    /// The start of a list, its items follow until [`BencodeVisitor::list_end`].
+    fn list_start(&mut self) -> Visit {
+        Visit::Continue
+    }
+
+    fn list_end(&mut self) -> Visit {
+        Visit::Continue
+    }
+
+    /// The start of a dictionary, its keys and values follow until
+    /// [`BencodeVisitor::dict_end`].
+    fn dict_start(&mut self) -> Visit {
+        Visit::Continue
+    }
+
+    /// A dictionary key, its value follows.
+    fn dict_key(&mut self, _key: &'a [u8]) -> Visit {
+        Visit::Continue
+    }
+
+    fn dict_end(&mut self) -> Visit {
+        Visit::Continue
+    }
+}
+
+/// A container being parsed by [`parse_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Container {
+    List,
+    /// A dictionary, expecting a key if `true`, a value otherwise.
+    Dict(bool),
+}
+
+/// Parse the first bencoded value of `input` token by token, calling `visitor` for each
+/// instead of building a [`BencodeValue`](super::BencodeValue).
+///
+/// Meant for the consumers that only need a field or two of a message (e.g. its transaction
+/// id, to dispatch it): nothing is allocated but the stack of the open containers, and the
+/// parsing stops as soon as the visitor has what it needs. The input is checked as
+/// [`decode`](super::decode) does, up to where the visitor stopped.
+///
+/// Returns the number of bytes read: up to the end of the value, or of the token the visitor
+/// stopped at.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::bencode::{BencodeVisitor, Visit, parse_with};
+///
+/// // Count the integers, stopping at the first dictionary key `stop`.
+/// struct Integers(usize);
+///
+/// impl BencodeVisitor<'_> for Integers {
+///     fn integer(&mut self, _value: i128) -> Visit {
+///         self.0 += 1;
+///         Visit::Continue
+///     }
+///
+///     fn dict_key(&mut self, key: &[u8]) -> Visit {
+///         if key == b"stop" { Visit::Stop } else { Visit::Continue }
+///     }
+/// }
+///
+/// let mut integers = Integers(0);
+/// assert_eq!(parse_with(b"li1eli2ei3eee", &mut integers), Ok(13));
+/// assert_eq!(integers.0, 3);
+/// let mut integers = Integers(0);
+/// assert_eq!(parse_with(b"d1:ai1e4:stopi2ee", &mut integers), Ok(13));
+/// assert_eq!(integers.0, 1);
+/// ```
+pub fn parse_with<'a, T, V>(input: &'a T, visitor: &mut V) -> Result<usize, Error>
+where
+    T: AsRef<[u8]> + ?Sized,
+    V: BencodeVisitor<'a>,
+{
+    let input = input.as_ref();
+    let mut stack: Vec<Container> = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let Some(&token) = input.get(cursor) else {
+            return Err(Error::InvalidValue);
+        };
+        let expecting_key = stack.last() == Some(&Container::Dict(true));
+        let visit = match token {
+            b'e' => {
+                cursor += 1;
+                let visit = match stack.pop() {
+                    Some(Container::List) => visitor.list_end(),
+                    Some(Container::Dict(true)) => visitor.dict_end(),
+                    // A key without its value, or no container to end.
+                    Some(Container::Dict(false)) | None => return Err(Error::InvalidValue),
+                };
+                value_read(&mut stack);
+                visit
+            }
+            b'i' | b'l' | b'd' if expecting_key => return Err(Error::InvalidDict),
+            b'i' => {
+                let (read, value) = decode_integer(&&input[cursor..])?;
+                cursor += read;
+                value_read(&mut stack);
+                visitor.integer(value)
+            }
+            b'l' => {
+                cursor += 1;
+                stack.push(Container::List);
+                visitor.list_start()
+            }
+            b'd' => {
+                cursor += 1;
+                stack.push(Container::Dict(true));
+                visitor.dict_start()
+            }
+            _ => {
+                let (read, string) = read_string(&input[cursor..])?;
+                cursor += read;
+                if let Some(dict) = stack.last_mut().filter(|_| expecting_key) {
+                    // Its value follows.
+                    *dict = Container::Dict(false);
+                    visitor.dict_key(string)
+                } else {
+                    value_read(&mut stack);
+                    visitor.string(string)
+                }
+            }
+        };
+        if visit == Visit::Stop || stack.is_empty() {
+            return Ok(cursor);
+        }
+    }
+}
+
+/// Account for a complete value in the innermost container: a dictionary expects its next key.
+fn value_read(stack: &mut [Container]) {
+    if let Some(container @ Container::Dict(false)) = stack.last_mut() {
+        *container = Container::Dict(true);
+    }
+}
+
+/// Get the string value of `key` in the dictionary `input` starts with, reading the input only
+/// up to it, e.g. the transaction id (`t`) of a KRPC message to dispatch it before decoding
+/// the message. `None` if the input is invalid up to the key, or has no such string.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::bencode::dict_string;
+///
+/// let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+/// assert_eq!(dict_string(ping, b"t"), Some(&b"aa"[..]));
+/// // Only the keys of the outer dictionary are looked up.
+/// assert_eq!(dict_string(ping, b"id"), None);
+/// ```
+pub fn dict_string<'a>(input: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    struct Finder<'k, 'a> {
+        key: &'k [u8],
+        depth: usize,
+        matched: bool,
+        found: Option<&'a [u8]>,
+    }
+
+    impl<'a> BencodeVisitor<'a> for Finder<'_, 'a> {
+        fn string(&mut self, value: &'a [u8]) -> Visit {
+            if self.depth == 1 && self.matched {
+                self.found = Some(value);
+                return Visit::Stop;
+            }
+            Visit::Continue
+        }
+
+        fn list_start(&mut self) -> Visit {
+            self.depth += 1;
+            Visit::Continue
+        }
+
+        fn list_end(&mut self) -> Visit {
+            self.depth -= 1;
+            Visit::Continue
+        }
+
+        fn dict_start(&mut self) -> Visit {
+            self.depth += 1;
+            Visit::Continue
+        }
+
+        fn dict_key(&mut self, key: &'a [u8]) -> Visit {
+            if self.depth == 1 {
+                self.matched = key == self.key;
+            }
+            Visit::Continue
+        }
+
+        fn dict_end(&mut self) -> Visit {
+            self.depth -= 1;
+            Visit::Continue
+        }
+    }
+
+    if input.first() != Some(&b'd') {
+        return None;
+    }
+    let mut finder = Finder {
+        key,
+        depth: 0,
+        matched: false,
+        found: None,
+    };
+    parse_with(input, &mut finder).ok()?;
+    finder.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{BencodeString, BencodeValue, decode, decode_prefix};
+
+    /// Rebuilds the value from the callbacks, to compare with [`decode`].
+    #[derive(Default)]
+    struct Builder {
+        stack: Vec<(BencodeValue, Option<BencodeString>)>,
+        key: Option<BencodeString>,
+        value: Option<BencodeValue>,
+    }
+
+    impl Builder {
+        fn push(&mut self, value: BencodeValue) {
+            match self.stack.last_mut() {
+                Some((BencodeValue::List(list), _)) => list.push(value),
+                Some((BencodeValue::Dict(dict), _)) => dict.push((self.key.take().unwrap(), value)),
+                _ => self.value = Some(value),
+            }
+        }
+
+        fn start(&mut self, container: BencodeValue) -> Visit {
+            self.stack.push((container, self.key.take()));
+            Visit::Continue
+        }
+
+        fn end(&mut self) -> Visit {
+            let (container, key) = self.stack.pop().unwrap();
+            self.key = key;
+            self.push(container);
+            Visit::Continue
+        }
+    }
+
+    impl<'a> BencodeVisitor<'a> for Builder {
+        fn integer(&mut self, value: i128) -> Visit {
+            self.push(BencodeValue::Integer(value));
+            Visit::Continue
+        }
+
+        fn string(&mut self, value: &'a [u8]) -> Visit {
+            self.push(BencodeValue::ByteString(value.to_vec().into()));
+            Visit::Continue
+        }
+
+        fn list_start(&mut self) -> Visit {
+            self.start(BencodeValue::List(Vec::new()))
+        }
+
+        fn list_end(&mut self) -> Visit {
+            self.end()
+        }
+
+        fn dict_start(&mut self) -> Visit {
+            self.start(BencodeValue::Dict(Vec::new()))
+        }
+
+        fn dict_key(&mut self, key: &'a [u8]) -> Visit {
+            self.key = Some(key.to_vec().into());
+            Visit::Continue
+        }
+
+        fn dict_end(&mut self) -> Visit {
+            self.end()
+        }
+    }
+
+    #[test]
+    fn test_parse_with_matches_decode() {
+        for input in [
+            &b"i-42e"[..],
+            b"4:spam",
+            b"le",
+            b"de",
+            b"li1e3:abcld1:ai2eeee",
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qetrailing",
+            b"d1:rd2:id20:abcdefghij01234567895:nodes0:6:valuesl6:abcdefee1:t0:1:y1:re",
+        ] {
+            let mut builder = Builder::default();
+            let read = parse_with(input, &mut builder);
+            let (decoded_read, value) = decode_prefix(&input).unwrap();
+            assert_eq!(read, Ok(decoded_read), "{:?}", input);
+            assert_eq!(builder.value, Some(value));
+        }
+        for input in [
+            &b""[..],
+            b"e",
+            b"l",
+            b"li1e",
+            b"di1ei2ee",
+            b"d1:ae",
+            b"d1:ai1e",
+            b"4:abc",
+            b"ixe",
+        ] {
+            assert!(
+                parse_with(input, &mut Builder::default()).is_err(),
+                "{:?}",
+                input
+            );
+            assert!(decode(&input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_dict_string() {
+        let reply = b"d1:rd2:id20:abcdefghij01234567891:t1:xe1:t2:aa1:y1:re";
+        assert_eq!(dict_string(reply, b"t"), Some(&b"aa"[..]));
+        assert_eq!(dict_string(reply, b"y"), Some(&b"r"[..]));
+        assert_eq!(dict_string(reply, b"r"), None);
+        assert_eq!(dict_string(b"d1:ti1ee", b"t"), None);
+        assert_eq!(dict_string(b"l1:t2:aae", b"t"), None);
+        // The input is not read past the value.
+        assert_eq!(dict_string(b"d1:t2:aa1:y", b"t"), Some(&b"aa"[..]));
+        assert_eq!(dict_string(b"d1:y1:q1:t", b"t"), None);
+    }
+}
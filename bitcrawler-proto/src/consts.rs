@@ -0,0 +1,41 @@
+//! Magic values of the DHT protocol (BEP 5) and of its extensions, in one place rather than
+//! as literals in the parsers and validators.
+
+use std::time::Duration;
+
+/// Length of the node ids, info hashes and targets, in bytes.
+pub const ID_LEN: usize = 20;
+
+/// Number of nodes in a bucket, and in the `nodes` of a reply, as BEP 5 defines it. Routing
+/// tables may hold larger buckets (see `RoutingTable::new`).
+pub const K: usize = 8;
+
+/// Length of a compact IPv4 node info (`nodes`): id, address and port.
+pub const COMPACT_NODE_V4_LEN: usize = ID_LEN + COMPACT_PEER_V4_LEN;
+/// Length of a compact IPv6 node info (`nodes6`, BEP 32): id, address and port.
+pub const COMPACT_NODE_V6_LEN: usize = ID_LEN + COMPACT_PEER_V6_LEN;
+/// Length of a compact IPv4 peer info (`values`): address and port.
+pub const COMPACT_PEER_V4_LEN: usize = 4 + 2;
+/// Length of a compact IPv6 peer info (`values`, BEP 32): address and port.
+pub const COMPACT_PEER_V6_LEN: usize = 16 + 2;
+
+/// Age up to which BEP 5 recommends accepting the tokens of `announce_peer` queries.
+pub const TOKEN_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Port the BitTorrent clients listen on by default, for the DHT as for the peers.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// Error code of a generic error (BEP 5).
+pub const ERROR_GENERIC: i64 = 201;
+/// Error code of a server error (BEP 5).
+pub const ERROR_SERVER: i64 = 202;
+/// Error code of a protocol error, e.g. a malformed packet or a bad token (BEP 5).
+pub const ERROR_PROTOCOL: i64 = 203;
+/// Error code of an unknown method (BEP 5).
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// Size of the Bloom filters of a scrape reply (BEP 33), in bytes.
+pub const SCRAPE_FILTER_LEN: usize = 256;
+
+/// Highest `interval` of a `sample_infohashes` reply (BEP 51), in seconds.
+pub const MAX_SAMPLE_INTERVAL: u32 = 6 * 60 * 60;
@@ -0,0 +1,117 @@
+//! Text forms of binary strings (ids, info hashes, transaction ids): lowercase hexadecimal, and
+//! the RFC 4648 base32 of the magnet links.
+
+use std::fmt::{self, Display};
+
+/// Alphabet of RFC 4648 base32, without padding.
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Displays bytes as lowercase hexadecimal, without allocating.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::hex::Hex;
+///
+/// assert_eq!(format!("{}", Hex(b"\x00\xab")), "00ab");
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Encode bytes as lowercase hexadecimal.
+pub fn hexify(bytes: &[u8]) -> String {
+    Hex(bytes).to_string()
+}
+
+/// Decode hexadecimal, in either case.
+///
+/// Returns `None` if the text has an odd length or a non hexadecimal digit.
+pub fn unhexify(text: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` alone would accept a sign.
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Encode bytes as uppercase RFC 4648 base32, without padding.
+pub fn base32(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    text
+}
+
+/// Decode RFC 4648 base32, in either case, without padding.
+///
+/// Returns `None` if the text has a character out of the alphabet.
+pub fn unbase32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_and_base32() {
+        assert_eq!(hexify(b"\x01\x23\xab\xff"), "0123abff");
+        assert_eq!(unhexify("0123ABff"), Some(b"\x01\x23\xab\xff".to_vec()));
+        assert_eq!(unhexify("012"), None);
+        assert_eq!(unhexify("0g"), None);
+        assert_eq!(unhexify("+f"), None);
+
+        // RFC 4648 test vectors, without the padding.
+        for (bytes, text) in [
+            (&b""[..], ""),
+            (b"f", "MY"),
+            (b"fo", "MZXQ"),
+            (b"foo", "MZXW6"),
+            (b"foob", "MZXW6YQ"),
+            (b"fooba", "MZXW6YTB"),
+            (b"foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32(bytes), text);
+            assert_eq!(unbase32(text).as_deref(), Some(bytes));
+        }
+        assert_eq!(unbase32("mzxw6ytb").as_deref(), Some(&b"fooba"[..]));
+        assert_eq!(unbase32("MZXW1"), None);
+    }
+}
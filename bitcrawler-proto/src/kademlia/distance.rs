@@ -0,0 +1,173 @@
+use std::fmt::{self, Display};
+
+use super::{Id160, Xorable};
+
+/// Number of log2 distances between two `Id160`: 0 for the same id, then 1 to 160.
+const LOG2_DISTANCES: usize = 161;
+
+impl Id160 {
+    /// Get the log2 of the XOR distance to `other`, rounded up: 0 for the same id, 160 for an
+    /// id differing in the first bit. It is the number of bits after the prefix both ids share.
+    pub fn log2_distance(&self, other: &Id160) -> u32 {
+        (LOG2_DISTANCES - 1 - self.bucket_index(other)) as u32
+    }
+}
+
+/// Distribution of the XOR distances between a target and a set of ids, by their
+/// [log2](Id160::log2_distance).
+///
+/// Meant to check that a lookup converges: the nodes it reaches should get closer to the
+/// target, so that most of the results end up in the lowest distances.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::kademlia::{DistanceHistogram, Id160};
+///
+/// let target = Id160([0; 20]);
+/// let mut close = target;
+/// close.0[19] = 0b0000_0100;
+/// let far = Id160([0xff; 20]);
+/// let histogram = DistanceHistogram::new(&target, [&target, &close, &far]);
+/// assert_eq!(histogram.len(), 3);
+/// assert_eq!(histogram.min(), Some(0));
+/// assert_eq!(histogram.count(3), 1);
+/// assert_eq!(histogram.max(), Some(160));
+/// assert_eq!(histogram.within(3), 2);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DistanceHistogram {
+    counts: [usize; LOG2_DISTANCES],
+    len: usize,
+}
+
+impl DistanceHistogram {
+    /// Build the histogram of the distances between `target` and `ids`.
+    pub fn new<'a>(target: &Id160, ids: impl IntoIterator<Item = &'a Id160>) -> Self {
+        let mut histogram = DistanceHistogram {
+            counts: [0; LOG2_DISTANCES],
+            len: 0,
+        };
+        for id in ids {
+            histogram.add(target, id);
+        }
+        histogram
+    }
+
+    /// Count the distance between `target` and `id`.
+    pub fn add(&mut self, target: &Id160, id: &Id160) {
+        self.counts[target.log2_distance(id) as usize] += 1;
+        self.len += 1;
+    }
+
+    /// Get the number of ids counted.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if no id was counted.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the number of ids at the log2 distance `log2` (0 to 160).
+    pub fn count(&self, log2: u32) -> usize {
+        self.counts.get(log2 as usize).copied().unwrap_or(0)
+    }
+
+    /// Get the number of ids at a log2 distance of at most `log2`.
+    pub fn within(&self, log2: u32) -> usize {
+        self.counts.iter().take(log2 as usize + 1).sum()
+    }
+
+    /// Get the lowest log2 distance, `None` if the histogram is empty.
+    pub fn min(&self) -> Option<u32> {
+        self.iter().next().map(|(log2, _)| log2)
+    }
+
+    /// Get the highest log2 distance, `None` if the histogram is empty.
+    pub fn max(&self) -> Option<u32> {
+        self.iter().last().map(|(log2, _)| log2)
+    }
+
+    /// Get the median log2 distance (the lower one for an even count), `None` if the histogram
+    /// is empty.
+    pub fn median(&self) -> Option<u32> {
+        let rank = self.len.checked_sub(1)? / 2;
+        let mut seen = 0;
+        for (log2, count) in self.iter() {
+            seen += count;
+            if seen > rank {
+                return Some(log2);
+            }
+        }
+        None
+    }
+
+    /// Iterate over the log2 distances and their number of ids, closest first, skipping the
+    /// distances without ids.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, usize)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(log2, count)| (log2 as u32, *count))
+    }
+}
+
+impl Display for DistanceHistogram {
+    /// Format the non-empty distances as `log2:count` pairs, closest first.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (log2, count)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}:{}", log2, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance_histogram() {
+        let target = Id160([0x55; 20]);
+        assert_eq!(target.log2_distance(&target), 0);
+        assert_eq!(target.log2_distance(&Id160([0xd5; 20])), 160);
+        let mut last_bit = target;
+        last_bit.0[19] ^= 1;
+        assert_eq!(target.log2_distance(&last_bit), 1);
+
+        let empty = DistanceHistogram::new(&target, []);
+        assert!(empty.is_empty());
+        assert_eq!(
+            (empty.min(), empty.max(), empty.median()),
+            (None, None, None)
+        );
+
+        // Ids at log2 distances 1, 9, 9, 152 and 160.
+        let ids: Vec<Id160> = [(19, 0x01), (18, 0x01), (18, 0x01), (1, 0x80), (0, 0x80)]
+            .into_iter()
+            .map(|(byte, bit)| {
+                let mut id = target;
+                id.0[byte] ^= bit;
+                id
+            })
+            .collect();
+        let histogram = DistanceHistogram::new(&target, &ids);
+        assert_eq!(histogram.len(), 5);
+        assert_eq!(
+            histogram.iter().collect::<Vec<_>>(),
+            [(1, 1), (9, 2), (152, 1), (160, 1)]
+        );
+        assert_eq!((histogram.min(), histogram.max()), (Some(1), Some(160)));
+        assert_eq!(histogram.median(), Some(9));
+        assert_eq!((histogram.within(0), histogram.within(9)), (0, 3));
+        assert_eq!(histogram.within(160), 5);
+        assert_eq!(histogram.count(200), 0);
+        assert_eq!(histogram.to_string(), "1:1 9:2 152:1 160:1");
+    }
+}
@@ -0,0 +1,183 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use super::{NodeId, Xorable};
+use crate::{
+    consts::ID_LEN,
+    hex::{self, Hex},
+};
+
+/// A 160-bit identifier, as used by the BitTorrent DHT for node ids and info hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Id160(pub [u8; 20]);
+
+impl Id160 {
+    /// Length of the identifier in bytes.
+    pub const LEN: usize = ID_LEN;
+
+    /// Get the bytes of the identifier.
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Compute the XOR distance between two identifiers.
+    pub fn distance(&self, other: &Id160) -> Id160 {
+        let mut distance = [0u8; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        Id160(distance)
+    }
+
+    /// Build an identifier sharing its first `bits` bits with `prefix`, the others are taken
+    /// from `self`.
+    ///
+    /// Useful to pick a node id close to a target (e.g. an info hash) while keeping the low bits
+    /// stable.
+    pub fn with_prefix(&self, prefix: &Id160, bits: u32) -> Id160 {
+        let mut id = self.0;
+        for (i, byte) in id.iter_mut().enumerate() {
+            let byte_bits = bits.saturating_sub(i as u32 * 8).min(8);
+            // Mask of the leading `byte_bits` bits of the byte.
+            let mask = !(0xffu16 >> byte_bits) as u8;
+            *byte = (prefix.0[i] & mask) | (*byte & !mask);
+        }
+        Id160(id)
+    }
+}
+
+impl Xorable for Id160 {
+    fn cmp_distance(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+
+    fn bucket_index(&self, other: &Self) -> usize {
+        for i in 0..self.0.len() {
+            let diff = self.0[i] ^ other.0[i];
+            if diff != 0 {
+                return i * 8 + diff.leading_zeros() as usize;
+            }
+        }
+        self.0.len() * 8
+    }
+
+    fn distance(&self, other: &Self) -> Self {
+        Id160::distance(self, other)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for Id160 {
+    type Error = &'static str;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        let id: [u8; 20] = value.try_into().map_err(|_| "Invalid length for Id160")?;
+        Ok(Id160(id))
+    }
+}
+
+impl From<[u8; 20]> for Id160 {
+    fn from(value: [u8; 20]) -> Self {
+        Id160(value)
+    }
+}
+
+impl From<Id160> for Vec<u8> {
+    fn from(val: Id160) -> Self {
+        val.0.to_vec()
+    }
+}
+
+impl Display for Id160 {
+    /// Format the identifier as 40 lowercase hexadecimal digits.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Hex(&self.0).fmt(f)
+    }
+}
+
+impl FromStr for Id160 {
+    type Err = &'static str;
+
+    /// Parse 40 hexadecimal digits, or 32 base32 digits (the other form of the info hash of
+    /// a magnet link).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = match s.len() {
+            40 => hex::unhexify(s).ok_or("Invalid hexadecimal Id160")?,
+            32 => hex::unbase32(s).ok_or("Invalid base32 Id160")?,
+            _ => return Err("Invalid length for Id160"),
+        };
+        Id160::try_from(bytes.as_slice())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Id160 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Id160 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl NodeId for Id160 {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_index_counts_common_bits() {
+        let a = Id160([0; 20]);
+        let mut b = a;
+        assert_eq!(a.bucket_index(&b), 160);
+        b.0[2] = 0b0001_0000;
+        assert_eq!(a.bucket_index(&b), 19);
+        assert_eq!(a.distance(&b), b);
+    }
+
+    #[test]
+    fn test_display_hex() {
+        let mut id = Id160([0; 20]);
+        id.0[0] = 0xab;
+        id.0[19] = 0x01;
+        assert_eq!(id.to_string(), "ab00000000000000000000000000000000000001");
+        assert_eq!(Id160::try_from(&id.0[..]), Ok(id));
+        assert!(Id160::try_from(&id.0[1..]).is_err());
+
+        assert_eq!(id.to_string().parse(), Ok(id));
+        assert_eq!("AB00000000000000000000000000000000000001".parse(), Ok(id));
+        assert_eq!(hex::base32(&id.0).parse(), Ok(id));
+        assert!("ab".parse::<Id160>().is_err());
+        assert!("zz".repeat(20).parse::<Id160>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_hex() {
+        use serde::{Deserialize, de::IntoDeserializer, de::value::Error};
+
+        let id = Id160([0x42; 20]);
+        let text = id.to_string();
+        let deserializer = IntoDeserializer::<Error>::into_deserializer(text.as_str());
+        assert_eq!(Id160::deserialize(deserializer), Ok(id));
+        let deserializer = IntoDeserializer::<Error>::into_deserializer("42");
+        assert!(Id160::deserialize(deserializer).is_err());
+    }
+
+    #[test]
+    fn test_with_prefix() {
+        let base = Id160([0; 20]);
+        let target = Id160([0xff; 20]);
+        assert_eq!(base.with_prefix(&target, 0), base);
+        assert_eq!(base.with_prefix(&target, 160), target);
+        let id = base.with_prefix(&target, 12);
+        assert_eq!(id.0[..3], [0xff, 0xf0, 0x00]);
+        assert_eq!(id.bucket_index(&target), 12);
+    }
+}
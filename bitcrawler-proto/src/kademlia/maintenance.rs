@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use super::{Address, NodeId, RoutingTable};
+
+/// Something the runtime of a node should do to keep its `RoutingTable` healthy, see
+/// [`MaintenancePlanner::plan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MaintenanceAction<N: NodeId> {
+    /// Look up a random id in the range of the bucket at this index of
+    /// [`RoutingTable::buckets`], to find new nodes for it.
+    RefreshBucket(usize),
+    /// Ping the node, it did not answer for a while.
+    PingNode(N),
+    /// Remove the node (or replace it, see [`RoutingTable::replace_at`]), it failed to answer
+    /// too many queries in a row.
+    EvictNode(N),
+}
+
+/// Settings of a [`MaintenancePlanner`].
+///
+/// The defaults are the intervals of BEP 5.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceConfig {
+    /// A bucket with no node inserted or replaced for this long is refreshed.
+    pub refresh_interval: Duration,
+    /// A node that did not answer for this long (or never did) is pinged.
+    pub ping_interval: Duration,
+    /// A node that failed to answer this many queries in a row is evicted.
+    pub max_failures: u32,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            refresh_interval: Duration::from_secs(15 * 60),
+            ping_interval: Duration::from_secs(15 * 60),
+            max_failures: 2,
+        }
+    }
+}
+
+/// Decides the upkeep of a `RoutingTable` (Kademlia bucket refreshes, pings of the questionable
+/// nodes, evictions of the bad ones), leaving the queries to the runtime.
+///
+/// The planner keeps no state: the runtime calls [`MaintenancePlanner::plan`] periodically,
+/// executes the actions, and reports the outcome of the queries on the nodes with
+/// [`RoutingTable::mark_seen`] and [`RoutingTable::mark_failed`].
+/// A ping still in flight is planned again, the runtime skips the nodes it already queries.
+#[derive(Debug, Clone, Default)]
+pub struct MaintenancePlanner {
+    config: MaintenanceConfig,
+}
+
+impl MaintenancePlanner {
+    /// Create a planner with the given settings.
+    pub fn new(config: MaintenanceConfig) -> MaintenancePlanner {
+        MaintenancePlanner { config }
+    }
+
+    /// Get the settings of the planner.
+    pub fn config(&self) -> &MaintenanceConfig {
+        &self.config
+    }
+
+    /// List the actions due at `now` on `table`: the evictions first, then the pings, then the
+    /// bucket refreshes.
+    pub fn plan<A: Address, N: NodeId>(
+        &self,
+        table: &RoutingTable<A, N>,
+        now: Instant,
+    ) -> Vec<MaintenanceAction<N>> {
+        let mut evictions = Vec::new();
+        let mut pings = Vec::new();
+        let mut refreshes = Vec::new();
+        for (index, bucket) in table.buckets().iter().enumerate() {
+            for node in bucket.iter() {
+                if node.failures() >= self.config.max_failures {
+                    evictions.push(MaintenanceAction::EvictNode(node.id().clone()));
+                } else if node.last_seen().is_none_or(|seen| {
+                    now.saturating_duration_since(seen) >= self.config.ping_interval
+                }) {
+                    pings.push(MaintenanceAction::PingNode(node.id().clone()));
+                }
+            }
+            let unchanged = now.saturating_duration_since(bucket.last_changed());
+            if unchanged >= self.config.refresh_interval {
+                refreshes.push(MaintenanceAction::RefreshBucket(index));
+            }
+        }
+        evictions.extend(pings);
+        evictions.extend(refreshes);
+        evictions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::MaintenanceAction::{EvictNode, PingNode, RefreshBucket};
+    use super::*;
+    use crate::kademlia::Node;
+    use crate::krpc::tests::MockNodeId;
+
+    fn node(id: u64) -> Node<SocketAddr, MockNodeId> {
+        let address = SocketAddr::from((Ipv4Addr::new(192, 0, 2, id as u8), 6881));
+        Node::new(MockNodeId(id), vec![address])
+    }
+
+    #[test]
+    fn test_plan() {
+        let start = Instant::now();
+        let minutes = |n: u64| start + Duration::from_secs(n * 60);
+        let mut table = RoutingTable::new(MockNodeId(0));
+        assert!(table.insert_at(node(1), start));
+        assert!(table.insert_at(node(2), start));
+        assert_eq!(table.buckets()[0].last_changed(), start);
+        let planner = MaintenancePlanner::default();
+
+        // Nodes never heard from are pinged right away.
+        assert_eq!(
+            planner.plan(&table, start),
+            vec![PingNode(MockNodeId(1)), PingNode(MockNodeId(2))]
+        );
+        assert!(table.mark_seen(&MockNodeId(1), start));
+        assert!(table.mark_seen(&MockNodeId(2), minutes(5)));
+        assert_eq!(planner.plan(&table, minutes(10)), vec![]);
+
+        // Node 1 turns questionable, then bad, and the bucket is due for a refresh.
+        assert_eq!(
+            planner.plan(&table, minutes(16)),
+            vec![PingNode(MockNodeId(1)), RefreshBucket(0)]
+        );
+        assert!(table.mark_failed(&MockNodeId(1)));
+        assert!(table.mark_failed(&MockNodeId(1)));
+        assert_eq!(
+            planner.plan(&table, minutes(16)),
+            vec![EvictNode(MockNodeId(1)), RefreshBucket(0)]
+        );
+
+        // Replacing the bad node changes the bucket, which is not refreshed anymore.
+        assert!(!table.replace_at(&MockNodeId(1), node(2), minutes(17)));
+        assert!(table.replace_at(&MockNodeId(1), node(3), minutes(17)));
+        assert!(table.mark_seen(&MockNodeId(3), minutes(17)));
+        assert!(table.get(&MockNodeId(1)).is_none());
+        assert_eq!(table.buckets()[0].last_changed(), minutes(17));
+        assert_eq!(
+            planner.plan(&table, minutes(20)),
+            vec![PingNode(MockNodeId(2))]
+        );
+
+        // An answer clears the failures.
+        assert!(table.mark_failed(&MockNodeId(3)));
+        assert!(table.mark_seen(&MockNodeId(3), minutes(21)));
+        assert_eq!(table.get(&MockNodeId(3)).unwrap().failures(), 0);
+    }
+}
@@ -1,3 +1,11 @@
+mod distance;
+mod id;
+mod maintenance;
 mod routing_table;
+mod target;
 
+pub use distance::*;
+pub use id::*;
+pub use maintenance::*;
 pub use routing_table::*;
+pub use target::*;
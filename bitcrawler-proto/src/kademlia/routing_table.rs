@@ -1,10 +1,72 @@
-use std::cmp::{Ordering, min};
+use std::cmp::{Ordering, Reverse, min};
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use super::{Id160, Target};
+use crate::consts::{COMPACT_NODE_V4_LEN, COMPACT_NODE_V6_LEN};
+use crate::krpc::node_info::{BittorrentNodeInfoV4, BittorrentNodeInfoV6, CompactNodeInfo};
+
+/// Maximum number of distinct node ids per host suited to a `RoutingTable` of the public DHT,
+/// see [`RoutingTable::with_max_nodes_per_host`].
+pub const PUBLIC_MAX_NODES_PER_HOST: usize = 1;
+
+/// Replacement of the slow nodes of a full bucket by faster ones, see
+/// [`RoutingTable::set_rtt_replacement`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RttReplacement {
+    /// A new node replaces the good node with the highest round-trip time of a full bucket if
+    /// its own round-trip time is at most this fraction of it.
+    pub max_ratio: f64,
+}
+
+impl Default for RttReplacement {
+    fn default() -> Self {
+        RttReplacement { max_ratio: 0.5 }
+    }
+}
+
+/// Routing domain of a node: the DHT it belongs to, e.g. a private overlay running the same
+/// protocol apart from the public network. See [`RoutingTable::set_domain`].
+///
+/// The nodes of the public DHT have no domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Domain(pub u64);
+
+impl Domain {
+    /// Get the domain of the overlay named `name`: a 64-bit FNV-1a hash of the name, so that
+    /// every node of the overlay derives the same domain from its configuration.
+    pub fn named(name: &str) -> Domain {
+        let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        Domain(hash)
+    }
+}
+
 /// An `Address` is a type that represents a network address that can be used to
 /// contact a node in a distributed system. This trait is intended to be
 /// implemented by types that represent network addresses, such as IP addresses
 /// or URLs.
-pub trait Address: PartialEq + Debug {}
+pub trait Address: PartialEq + Debug {
+    /// Get the IP address of the host of the address, if it has one.
+    ///
+    /// A `RoutingTable` may limit the number of distinct node ids sharing a host (see
+    /// [`RoutingTable::with_max_nodes_per_host`]), the addresses without a host are never
+    /// limited. None by default.
+    fn host(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Address for SocketAddr {
+    /// IPv4-mapped IPv6 addresses are reported as IPv4, so that a node seen on both sides of a
+    /// dual-stack socket counts once.
+    fn host(&self) -> Option<IpAddr> {
+        Some(self.ip().to_canonical())
+    }
+}
 
 /// A `NodeId` is a type that represents a unique identifier for a node in a
 /// distributed system. This trait is intended to be implemented by types that
@@ -33,6 +95,7 @@ pub trait NodeId:
 /// - `bucket_index`: Calculates the bucket index for `other` relative to `self`,
 ///   which is typically used to determine the appropriate bucket in a routing
 ///   table (number of leading bits that are identical).
+/// - `distance`: Computes the XOR distance between `self` and `other`.
 ///
 /// This trait is intended to be implemented by types that represent keys or
 /// identifiers in a distributed hash table (DHT) or similar systems.
@@ -45,6 +108,11 @@ pub trait Xorable {
     /// typically used to determine the appropriate bucket in a routing table
     /// (number of leading bits that are identical).
     fn bucket_index(&self, other: &Self) -> usize;
+
+    /// Computes the XOR distance between `self` and `other`, ordered as the distances are.
+    fn distance(&self, other: &Self) -> Self
+    where
+        Self: Sized;
 }
 
 /// A `Bucket` is a collection of `Node`s that are sorted by their `NodeId`.
@@ -53,6 +121,12 @@ pub trait Xorable {
 pub struct Bucket<A: Address, N: NodeId> {
     // The nodes are sorted by node id.
     nodes: Vec<Node<A, N>>,
+    // The range of the bucket: the ids starting with the first `depth` bits of `prefix`, whose
+    // other bits are 0.
+    prefix: Vec<u8>,
+    depth: usize,
+    // Last time a node was inserted or replaced in the bucket.
+    last_changed: Instant,
 }
 
 /// A `Node` is a representation of a node in a distributed system. It contains
@@ -61,6 +135,11 @@ pub struct Bucket<A: Address, N: NodeId> {
 pub struct Node<A: Address, N: NodeId> {
     id: N,
     addresses: Vec<A>,
+    last_seen: Option<Instant>,
+    failures: u32,
+    // Smoothed round-trip time of the queries to the node.
+    rtt: Option<Duration>,
+    domain: Option<Domain>,
 }
 
 /// A `RoutingTable` stores a collection of `Bucket`s that contain `Node`s. The
@@ -70,9 +149,24 @@ pub struct RoutingTable<A: Address, N: NodeId> {
     buckets: Vec<Bucket<A, N>>,
     local_id: N,
     bucket_size: usize,
+    max_nodes_per_host: Option<usize>,
+    // Number of nodes with an address on each host.
+    hosts: HashMap<IpAddr, usize>,
+    rtt_replacement: Option<RttReplacement>,
+    domain: Option<Domain>,
 }
 
 impl<A: Address, N: NodeId> Bucket<A, N> {
+    /// Get the last time a node was inserted or replaced in the bucket (by the `RoutingTable`).
+    pub fn last_changed(&self) -> Instant {
+        self.last_changed
+    }
+
+    /// Iterate over the nodes of the bucket, sorted by node id.
+    pub fn iter(&self) -> impl Iterator<Item = &Node<A, N>> {
+        self.nodes.iter()
+    }
+
     /// Get the first node in the bucket.
     pub fn first(&self) -> Option<&Node<A, N>> {
         self.nodes.first()
@@ -88,11 +182,6 @@ impl<A: Address, N: NodeId> Bucket<A, N> {
         self.nodes.get(index)
     }
 
-    /// Get a mutable reference to the node at the given index.
-    pub fn get_mut(&mut self, index: usize) -> Option<&mut Node<A, N>> {
-        self.nodes.get_mut(index)
-    }
-
     /// Find the index of the node with the given id.
     fn find(&self, id: &N) -> Result<usize, usize> {
         self.nodes.binary_search_by(|node| node.id.cmp(id))
@@ -128,22 +217,43 @@ impl<A: Address, N: NodeId> Bucket<A, N> {
         self.find(id).is_ok()
     }
 
-    /// Check if the node with the given id is within the range of the bucket.
-    ///
-    /// Returns true if the node is within the range, otherwise false.
+    /// Check if the given id is within the range of the bucket: if it starts with the prefix of
+    /// the bucket (see [`Bucket::depth`]).
     ///
-    /// TODO: Might not work as wanted, need to test.
+    /// The ranges of the buckets of a `RoutingTable` cover the whole keyspace, those of the
+    /// empty buckets included.
     pub fn range_contains(&self, id: &N) -> bool {
-        let first = self.first().expect("Bucket is empty");
-        let last = self.last().expect("Bucket is empty");
-        id.cmp_distance(&first.id) != Ordering::Greater
-            && id.cmp_distance(&last.id) != Ordering::Less
+        has_prefix(&id.clone().into(), &self.prefix, self.depth)
+    }
+
+    /// Get the length in bits of the prefix shared by the ids in the range of the bucket: a
+    /// bucket of depth `d` covers `2^-d` of the keyspace.
+    pub fn depth(&self) -> usize {
+        self.depth
     }
 
     /// Get the number of nodes in the bucket.
     pub fn len(&self) -> usize {
         self.nodes.len()
     }
+
+    /// Check if the bucket has no node.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Find the good node (one that did not fail its last query) with the highest round-trip
+    /// time that a node answering in `rtt` replaces under `policy`.
+    fn slowest_replaceable(&self, rtt: Duration, policy: RttReplacement) -> Option<usize> {
+        let (index, slowest) = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.failures == 0)
+            .filter_map(|(index, node)| node.rtt.map(|rtt| (index, rtt)))
+            .max_by_key(|(_, rtt)| *rtt)?;
+        (rtt.as_secs_f64() <= slowest.as_secs_f64() * policy.max_ratio).then_some(index)
+    }
 }
 
 impl<A: Address, N: NodeId> RoutingTable<A, N> {
@@ -153,38 +263,168 @@ impl<A: Address, N: NodeId> RoutingTable<A, N> {
     ///
     /// The `bucket_size` is the maximum number of nodes that can be stored in a bucket. By default,
     /// the bucket size is set to 20.
+    ///
+    /// The number of node ids sharing a host is not limited, see
+    /// [`RoutingTable::with_max_nodes_per_host`].
     pub fn new(local_id: N) -> RoutingTable<A, N> {
         RoutingTable {
             buckets: vec![],
-            local_id: local_id,
+            local_id,
             bucket_size: 20,
+            max_nodes_per_host: None,
+            hosts: HashMap::new(),
+            rtt_replacement: None,
+            domain: None,
         }
     }
 
-    /// Find the index of the bucket that contains the node with the given id.
+    /// Set the routing domain of the table: only the nodes of this domain (see
+    /// [`Node::with_domain`]) are inserted, so that a node of a private overlay and a node of
+    /// the public DHT (`None`, the default) never end up in the same table.
     ///
-    /// Returns the index of the bucket if it is found, otherwise None.
-    fn find_bucket_index(&self, id: &N) -> Option<usize> {
-        if self.buckets.len() == 0 {
-            return None;
-        }
-
-        let mut bucket_index = 0;
-        let mut bucket_length = 0;
-        for (i, bucket) in self.buckets.iter().enumerate() {
-            if bucket.nodes.len() > 0 {
-                let first = bucket.first().expect("Bucket is empty");
-                let last = bucket.last().expect("Bucket is empty");
-                let lbindex = id.bucket_index(&first.id);
-                let rbindex = id.bucket_index(&last.id);
-                let bindex = min(lbindex, rbindex);
-                if bindex >= bucket_length {
-                    bucket_index = i;
-                    bucket_length = bindex;
+    /// The nodes already in the table are kept.
+    pub fn set_domain(&mut self, domain: Option<Domain>) {
+        self.domain = domain;
+    }
+
+    /// Get the routing domain of the table, see [`RoutingTable::set_domain`].
+    pub fn domain(&self) -> Option<Domain> {
+        self.domain
+    }
+
+    /// Let the nodes with a low round-trip time replace the slow nodes of the full buckets,
+    /// to make the lookups faster. Disabled (`None`) by default.
+    ///
+    /// Only the nodes with a known round-trip time take part, see [`Node::record_rtt`] and
+    /// [`RoutingTable::record_rtt`].
+    pub fn set_rtt_replacement(&mut self, policy: Option<RttReplacement>) {
+        self.rtt_replacement = policy;
+    }
+
+    /// Limit the number of distinct node ids that may share a host (see [`Address::host`]),
+    /// e.g. to [`PUBLIC_MAX_NODES_PER_HOST`].
+    ///
+    /// This bounds how much of the table a single machine can occupy by making up node ids.
+    /// Not limited by default: the nodes behind a NAT, or a test cluster on one host, share
+    /// their host.
+    pub fn with_max_nodes_per_host(mut self, max_nodes_per_host: usize) -> RoutingTable<A, N> {
+        self.max_nodes_per_host = Some(max_nodes_per_host);
+        self
+    }
+
+    /// Set the maximum number of distinct node ids that may share a host, `None` for no
+    /// limit, see [`RoutingTable::with_max_nodes_per_host`].
+    ///
+    /// The limit applies to future insertions only.
+    pub fn set_max_nodes_per_host(&mut self, max_nodes_per_host: Option<usize>) {
+        self.max_nodes_per_host = max_nodes_per_host;
+    }
+
+    /// Get the node with the given id.
+    pub fn get(&self, id: &N) -> Option<&Node<A, N>> {
+        let bucket = &self.buckets[self.find_bucket_index(id)?];
+        bucket.find(id).ok().map(|index| &bucket.nodes[index])
+    }
+
+    /// Get a mutable reference to the node with the given id.
+    ///
+    /// Not public: the hosts of the table are counted from the addresses of its nodes, which
+    /// only the table changes. See [`RoutingTable::mark_seen`], [`RoutingTable::mark_failed`]
+    /// and [`RoutingTable::record_rtt`].
+    fn get_mut(&mut self, id: &N) -> Option<&mut Node<A, N>> {
+        let index = self.find_bucket_index(id)?;
+        let bucket = &mut self.buckets[index];
+        bucket.find(id).ok().map(|index| &mut bucket.nodes[index])
+    }
+
+    /// Get the number of nodes in the routing table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Bucket::len).sum()
+    }
+
+    /// Record that the node `id` answered one of our queries at `now`, see
+    /// [`Node::mark_seen`].
+    ///
+    /// Returns false if the node is not in the table.
+    pub fn mark_seen(&mut self, id: &N, now: Instant) -> bool {
+        self.get_mut(id).map(|node| node.mark_seen(now)).is_some()
+    }
+
+    /// Record that the node `id` failed to answer one of our queries, see
+    /// [`Node::mark_failed`].
+    ///
+    /// Returns false if the node is not in the table.
+    pub fn mark_failed(&mut self, id: &N) -> bool {
+        self.get_mut(id).map(Node::mark_failed).is_some()
+    }
+
+    /// Record a round-trip time of the node `id` (e.g. measured on the reply to one of our
+    /// queries), see [`Node::record_rtt`].
+    ///
+    /// Returns false if the node is not in the table.
+    pub fn record_rtt(&mut self, id: &N, rtt: Duration) -> bool {
+        self.get_mut(id).map(|node| node.record_rtt(rtt)).is_some()
+    }
+
+    /// Get the buckets of the routing table.
+    pub fn buckets(&self) -> &[Bucket<A, N>] {
+        &self.buckets
+    }
+
+    /// Get the maximum number of nodes in a bucket.
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
+    /// Check if the routing table has no node.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(Bucket::is_empty)
+    }
+
+    /// Get the (at most) `count` nodes closest to `target`, closest first.
+    ///
+    /// The target is either a node id or an info hash (see [`Target`]), the distance is the
+    /// same for both.
+    pub fn closest<T: Into<Target<N>>>(&self, target: T, count: usize) -> Vec<&Node<A, N>> {
+        let target = target.into();
+        let mut nodes: Vec<(N, &Node<A, N>)> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .map(|node| (node.id.distance(target.id()), node))
+            .collect();
+        nodes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        nodes
+            .into_iter()
+            .take(count)
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    /// Count a node in the given hosts (see [`distinct_hosts`]), or take it out of them if
+    /// `added` is false.
+    fn count_hosts(&mut self, hosts: Vec<IpAddr>, added: bool) {
+        for host in hosts {
+            if added {
+                *self.hosts.entry(host).or_default() += 1;
+            } else if let Some(count) = self.hosts.get_mut(&host) {
+                *count -= 1;
+                if *count == 0 {
+                    self.hosts.remove(&host);
                 }
             }
         }
-        return Some(bucket_index);
+    }
+
+    /// Find the index of the bucket whose range contains the given id.
+    ///
+    /// Returns None if the table has no bucket.
+    fn find_bucket_index(&self, id: &N) -> Option<usize> {
+        let id: Vec<u8> = id.clone().into();
+        // The buckets are sorted by prefix and cover the keyspace: the last one starting at or
+        // before the id.
+        let index = self.buckets.partition_point(|bucket| bucket.prefix <= id);
+        index.checked_sub(1)
     }
 
     /// Find the bucket that contains the node with the given id.
@@ -195,105 +435,455 @@ impl<A: Address, N: NodeId> RoutingTable<A, N> {
         }
     }
 
-    /// Find the mutable reference to the bucket that contains the node with the given id.
-    fn find_bucket_mut(&mut self, id: &N) -> Option<&mut Bucket<A, N>> {
-        match self.find_bucket_index(id) {
-            Some(index) => Some(&mut self.buckets[index]),
-            None => None,
-        }
-    }
-
     /// Insert a node into the routing table.
     ///
-    /// Returns true if the node was inserted, otherwise false.
+    /// Returns true if the node was inserted, or if new addresses were merged into an existing
+    /// node, otherwise false.
+    ///
+    /// A node may be reachable from several addresses (e.g. multi-homed nodes with an IPv4 and
+    /// an IPv6 address, BEP 45), so inserting a node id already present merges the new addresses
+    /// into the existing node. Addresses whose host already has the maximum number of other node
+    /// ids are dropped, and the node is not inserted if none of its addresses are left. The
+    /// local node itself is never inserted, nor a node of another domain than the table (see
+    /// [`RoutingTable::set_domain`]).
     ///
     /// If the bucket that contains the node is full, it will be split into two new buckets
-    /// if the local id is within the range of the bucket. Otherwise, the node will not be inserted.
+    /// if the local id is within the range of the bucket. Otherwise, the node will not be inserted,
+    /// unless it replaces a slower node (see [`RoutingTable::set_rtt_replacement`]).
+    ///
+    /// The bucket of a new node is marked as changed now, see [`RoutingTable::insert_at`].
     pub fn insert(&mut self, node: Node<A, N>) -> bool {
-        let bucket_size = self.bucket_size;
-        let local_id = self.local_id.clone();
-        let node_id = node.id.clone();
-        let bucket = self.find_bucket_mut(&node.id);
-        let must_split;
-        match bucket {
-            Some(bucket) => {
-                if bucket.nodes.len() >= bucket_size {
-                    // TODO: Not sure if this is correct
-                    if bucket.range_contains(&local_id) {
-                        bucket.insert(node);
-                        must_split = true;
-                    } else {
-                        return false;
-                    }
-                } else {
-                    return bucket.insert(node);
+        self.insert_at(node, Instant::now())
+    }
+
+    /// Insert a node into the routing table, as [`RoutingTable::insert`], with the clock given.
+    ///
+    /// The bucket the node lands in is marked as changed at `now` (see
+    /// [`Bucket::last_changed`]), unless the node was already known.
+    pub fn insert_at(&mut self, node: Node<A, N>, now: Instant) -> bool {
+        let inserted = self.insert_node(node, now);
+        debug_assert_eq!(self.validate(), Ok(()), "insert broke the routing table");
+        inserted
+    }
+
+    fn insert_node(&mut self, mut node: Node<A, N>, now: Instant) -> bool {
+        if node.id == self.local_id || node.domain != self.domain {
+            return false;
+        }
+        // The hosts the node is already counted in.
+        let known_hosts: Vec<IpAddr> = match self.get(&node.id) {
+            Some(existing) => existing
+                .addresses
+                .iter()
+                .filter_map(Address::host)
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut addresses = Vec::with_capacity(node.addresses.len());
+        for address in node.addresses.drain(..) {
+            let full = match (self.max_nodes_per_host, address.host()) {
+                (Some(max), Some(host)) if !known_hosts.contains(&host) => {
+                    self.hosts.get(&host).copied().unwrap_or(0) >= max
                 }
+                _ => false,
+            };
+            if !full && !addresses.contains(&address) {
+                addresses.push(address);
             }
-            None => {
-                let new_bucket = Bucket { nodes: vec![node] };
-                self.buckets.push(new_bucket);
-                must_split = false;
+        }
+        if addresses.is_empty() {
+            return false;
+        }
+        node.addresses = addresses;
+
+        if let Some(existing) = self.get_mut(&node.id) {
+            let known = existing.addresses.len();
+            for address in node.addresses {
+                if !existing.addresses.contains(&address) {
+                    existing.addresses.push(address);
+                }
             }
+            let added = existing.addresses.len() > known;
+            let mut hosts = distinct_hosts(&existing.addresses);
+            hosts.retain(|host| !known_hosts.contains(host));
+            self.count_hosts(hosts, true);
+            return added;
+        }
+
+        if self.buckets.is_empty() {
+            let id: Vec<u8> = self.local_id.clone().into();
+            self.buckets.push(Bucket {
+                nodes: vec![],
+                prefix: vec![0; id.len()],
+                depth: 0,
+                last_changed: now,
+            });
         }
-        if must_split {
-            self.split_bucket(self.find_bucket_index(&node_id).expect("Bucket not found"));
+        let mut index = self.find_bucket_index(&node.id).expect("Bucket not found");
+        // A full bucket holding the local id splits until the node finds room, or its bucket no
+        // longer holds the local id.
+        while self.buckets[index].len() >= self.bucket_size
+            && self.buckets[index].range_contains(&self.local_id)
+            && self.split_bucket(index)
+        {
+            index = self.find_bucket_index(&node.id).expect("Bucket not found");
         }
-        return true;
+        let hosts = distinct_hosts(&node.addresses);
+        let bucket = &mut self.buckets[index];
+        let mut replaced = None;
+        if bucket.nodes.len() >= self.bucket_size {
+            match (self.rtt_replacement, node.rtt) {
+                (Some(policy), Some(rtt)) => match bucket.slowest_replaceable(rtt, policy) {
+                    Some(index) => replaced = Some(bucket.nodes.remove(index)),
+                    None => return false,
+                },
+                _ => return false,
+            }
+        }
+        bucket.insert(node);
+        bucket.last_changed = now;
+        if let Some(replaced) = replaced {
+            self.count_hosts(distinct_hosts(&replaced.addresses), false);
+        }
+        self.count_hosts(hosts, true);
+        true
     }
 
-    /// Split the bucket at the given index into two new buckets.
+    /// Replace the node `old` by `node`, e.g. a node that stopped answering by a new one of
+    /// the same bucket.
     ///
-    /// The bucket will be split into two new buckets based on the range of the node ids.
-    /// The new buckets will be inserted into the routing table, and the old bucket will be removed.
-    fn split_bucket(&mut self, index: usize) {
-        let bucket = self.buckets.remove(index);
-        if bucket.nodes.len() < self.bucket_size {
-            self.buckets.push(bucket);
-            return;
+    /// Returns false, leaving the table untouched, if `old` is not in the table, `node` is
+    /// already in it, is the local node or is of another domain than the table, or `node`
+    /// falls out of the range of the bucket of `old`.
+    /// Otherwise `node` takes the place of `old` in its bucket, which is marked as changed at
+    /// `now`.
+    pub fn replace_at(&mut self, old: &N, node: Node<A, N>, now: Instant) -> bool {
+        if node.id == self.local_id || node.domain != self.domain || self.get(&node.id).is_some() {
+            return false;
+        }
+        let Some(index) = self.buckets.iter().position(|bucket| bucket.contains(old)) else {
+            return false;
+        };
+        if !self.buckets[index].range_contains(&node.id) {
+            return false;
         }
+        let hosts = distinct_hosts(&node.addresses);
+        let bucket = &mut self.buckets[index];
+        let old = bucket.remove(old).expect("Node not found");
+        bucket.insert(node);
+        bucket.last_changed = now;
+        self.count_hosts(distinct_hosts(&old.addresses), false);
+        self.count_hosts(hosts, true);
+        debug_assert_eq!(self.validate(), Ok(()), "replace broke the routing table");
+        true
+    }
 
-        let mut left = Bucket { nodes: vec![] };
-        let mut right = Bucket { nodes: vec![] };
-        let first_id = bucket.first().expect("Bucket is empty").id.clone();
-        let last_id = bucket.last().expect("Bucket is empty").id.clone();
-        let bucket_index = first_id.bucket_index(&last_id);
-        for node in bucket.nodes {
-            let index = first_id.bucket_index(&node.id);
-            if index >= bucket_index {
-                left.insert(node);
+    /// Split the bucket at the given index in two halves, at the first bit after its prefix:
+    /// the nodes with this bit set move to a new bucket, right after it.
+    ///
+    /// Returns false, leaving the bucket as is, if its prefix is already a whole id.
+    fn split_bucket(&mut self, index: usize) -> bool {
+        let bucket = &mut self.buckets[index];
+        let bit = bucket.depth;
+        if bit >= bucket.prefix.len() * 8 {
+            return false;
+        }
+        // The nodes are sorted, and all share the bits before this one.
+        let split = bucket
+            .nodes
+            .partition_point(|node| !bit_is_set(&node.id.clone().into(), bit));
+        let mut prefix = bucket.prefix.clone();
+        prefix[bit / 8] |= 0x80 >> (bit % 8);
+        bucket.depth += 1;
+        let right = Bucket {
+            nodes: bucket.nodes.split_off(split),
+            prefix,
+            depth: bucket.depth,
+            last_changed: bucket.last_changed,
+        };
+        self.buckets.insert(index + 1, right);
+        true
+    }
+
+    /// Merge the empty bucket at the given index back into its sibling (the other half of the
+    /// bucket they were split from), as long as the sibling was not split further.
+    ///
+    /// The last bucket is dropped once empty, so that an empty table has no bucket.
+    fn merge_bucket(&mut self, mut index: usize) {
+        while self.buckets[index].is_empty() {
+            let depth = self.buckets[index].depth;
+            if depth == 0 {
+                self.buckets.clear();
+                return;
+            }
+            // The lower half of a bucket does not have the last bit of its prefix set.
+            let sibling = if bit_is_set(&self.buckets[index].prefix, depth - 1) {
+                index - 1
             } else {
-                right.insert(node);
+                index + 1
+            };
+            if self.buckets[sibling].depth != depth {
+                return;
             }
+            let empty = self.buckets.remove(index);
+            index = min(index, sibling);
+            let bucket = &mut self.buckets[index];
+            bucket.prefix = empty.prefix.min(std::mem::take(&mut bucket.prefix));
+            bucket.depth -= 1;
         }
-        self.buckets.push(left);
-        self.buckets.push(right);
     }
 
     /// Remove the node with the given id from the routing table.
     ///
     /// Returns the removed node if it was found, otherwise None.
     ///
-    /// If the bucket that contains the node is empty after removing the node, it will be removed.
+    /// If the bucket that contains the node is empty after removing the node, it is merged back
+    /// into the other half of the bucket it was split from (when that one was not split
+    /// further).
     pub fn remove(&mut self, id: &N) -> Option<Node<A, N>> {
         let bucket_index = self.find_bucket_index(id);
-        match bucket_index {
+        let node = match bucket_index {
             Some(index) => {
-                let bucket = &mut self.buckets[index];
-                let node = bucket.remove(id);
-                if bucket.len() == 0 {
-                    self.buckets.remove(index);
+                let node = self.buckets[index].remove(id);
+                if node.is_some() {
+                    self.merge_bucket(index);
                 }
                 node
             }
             None => None,
+        };
+        if let Some(node) = &node {
+            self.count_hosts(distinct_hosts(&node.addresses), false);
         }
+        debug_assert_eq!(self.validate(), Ok(()), "remove broke the routing table");
+        node
+    }
+
+    /// Check the invariants of the routing table, returning the first one broken.
+    ///
+    /// - no bucket holds more than [`RoutingTable::bucket_size`] nodes;
+    /// - the nodes of a bucket are sorted by id, without duplicates, and are all within the
+    ///   range of the bucket;
+    /// - the buckets are sorted by range, and their ranges cover the keyspace without
+    ///   overlapping (so a node id is in exactly one bucket, and so is the local id);
+    /// - the local node is not in the table.
+    ///
+    /// The table keeps them on its own, and checks them after every change in debug builds:
+    /// a table breaking them has a bug, see [`RoutingTable::repair`].
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let local_id: Vec<u8> = self.local_id.clone().into();
+        // The start of the range of the next bucket, None past the end of the keyspace.
+        let mut next = Some(vec![0; local_id.len()]);
+        for bucket in &self.buckets {
+            if bucket.len() > self.bucket_size {
+                return Err("bucket holding more nodes than the bucket size");
+            }
+            if bucket.nodes.windows(2).any(|pair| pair[0].id >= pair[1].id) {
+                return Err("bucket nodes not sorted by id");
+            }
+            if bucket
+                .nodes
+                .iter()
+                .any(|node| !bucket.range_contains(&node.id))
+            {
+                return Err("node out of the range of its bucket");
+            }
+            if next.as_ref() != Some(&bucket.prefix)
+                || bucket.depth > bucket.prefix.len() * 8
+                || range_bound(&bucket.prefix, bucket.depth, false) != bucket.prefix
+            {
+                return Err("bucket ranges not covering the keyspace in order");
+            }
+            if bucket.contains(&self.local_id) {
+                return Err("local node in the routing table");
+            }
+            next = next_id(range_bound(&bucket.prefix, bucket.depth, true));
+        }
+        if !self.buckets.is_empty() && next.is_some() {
+            return Err("bucket ranges not covering the keyspace in order");
+        }
+        Ok(())
+    }
+
+    /// Rebuild the routing table if it is not valid (see [`RoutingTable::validate`]), by
+    /// inserting its nodes again into empty buckets.
+    ///
+    /// The most recently seen nodes are inserted first, so they are the ones kept when a bucket
+    /// overflows. The new buckets are marked as changed at the oldest change of the previous
+    /// ones, so that they get refreshed soon. Returns the number of nodes dropped, the
+    /// duplicates merged into another node included.
+    pub fn repair(&mut self) -> usize {
+        if self.validate().is_ok() {
+            return 0;
+        }
+        let count = self.len();
+        let buckets = std::mem::take(&mut self.buckets);
+        self.hosts.clear();
+        let Some(changed) = buckets.iter().map(Bucket::last_changed).min() else {
+            return 0;
+        };
+        let mut nodes: Vec<Node<A, N>> = buckets.into_iter().flat_map(|b| b.nodes).collect();
+        nodes.sort_by_key(|node| Reverse(node.last_seen));
+        for node in nodes {
+            self.insert_at(node, changed);
+        }
+        count - self.len()
+    }
+}
+
+/// Get the hosts of `addresses` (see [`Address::host`]), without duplicates.
+fn distinct_hosts<A: Address>(addresses: &[A]) -> Vec<IpAddr> {
+    let mut hosts: Vec<IpAddr> = addresses.iter().filter_map(Address::host).collect();
+    hosts.sort_unstable();
+    hosts.dedup();
+    hosts
+}
+
+/// Get the index of the first bit (from the most significant one) that differs between `a`
+/// and `b`, None if they are equal.
+fn first_different_bit(a: &[u8], b: &[u8]) -> Option<usize> {
+    let index = a.iter().zip(b).position(|(a, b)| a != b)?;
+    Some(index * 8 + (a[index] ^ b[index]).leading_zeros() as usize)
+}
+
+/// Check if the bit `index` (from the most significant one) of `id` is set.
+fn bit_is_set(id: &[u8], index: usize) -> bool {
+    id.get(index / 8)
+        .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+/// Check if `id` starts with the first `depth` bits of `prefix`.
+fn has_prefix(id: &[u8], prefix: &[u8], depth: usize) -> bool {
+    first_different_bit(id, prefix).is_none_or(|bit| bit >= depth)
+}
+
+/// Get the first (or the last, if `end`) id of the range of the ids starting with the first
+/// `depth` bits of `prefix`.
+fn range_bound(prefix: &[u8], depth: usize, end: bool) -> Vec<u8> {
+    let bound = prefix.iter().enumerate().map(|(index, byte)| {
+        // The bits of the byte past the prefix.
+        let free = 0xffu8
+            .checked_shr(depth.saturating_sub(index * 8) as u32)
+            .unwrap_or(0);
+        if end { byte | free } else { byte & !free }
+    });
+    bound.collect()
+}
+
+/// Get the id right after `id`, None if it is the last one.
+fn next_id(mut id: Vec<u8>) -> Option<Vec<u8>> {
+    for byte in id.iter_mut().rev() {
+        let (next, carry) = byte.overflowing_add(1);
+        *byte = next;
+        if !carry {
+            return Some(id);
+        }
+    }
+    None
+}
+
+impl RoutingTable<SocketAddr, Id160> {
+    /// Export the nodes with an IPv4 address as a compact node list: 26 bytes per node
+    /// (`<node_id:20><ip:4><port:2>`), the format of the `nodes` field of BEP 5 replies that
+    /// other clients and tools read.
+    ///
+    /// The nodes which failed their last queries are left out.
+    pub fn export_compact(&self) -> Vec<u8> {
+        self.export::<BittorrentNodeInfoV4<Id160>, _>(|address| match address.ip().to_canonical() {
+            IpAddr::V4(ip) => Some(SocketAddrV4::new(ip, address.port())),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    /// Export the nodes with an IPv6 address as a compact node list: 38 bytes per node, the
+    /// format of the `nodes6` field of BEP 32, see [`RoutingTable::export_compact`].
+    pub fn export_compact6(&self) -> Vec<u8> {
+        self.export::<BittorrentNodeInfoV6<Id160>, _>(|address| match address {
+            SocketAddr::V6(address) if address.ip().to_ipv4_mapped().is_none() => Some(*address),
+            _ => None,
+        })
+    }
+
+    /// Insert the nodes of an IPv4 compact node list, as written by
+    /// [`RoutingTable::export_compact`] (or by another client).
+    ///
+    /// The nodes go through [`RoutingTable::insert`], unverified: they should be pinged before
+    /// being trusted, and are taken to be of the domain of the table. The nodes without a usable
+    /// address (port 0, unspecified address) are skipped. Returns the number of nodes inserted
+    /// (or known nodes given a new address), or an error if the length of the list is not a
+    /// multiple of 26 bytes.
+    pub fn import_compact(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        self.import::<BittorrentNodeInfoV4<Id160>>(data, COMPACT_NODE_V4_LEN)
+    }
+
+    /// Insert the nodes of an IPv6 compact node list (38 bytes per node), as
+    /// [`RoutingTable::import_compact`].
+    pub fn import_compact6(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        self.import::<BittorrentNodeInfoV6<Id160>>(data, COMPACT_NODE_V6_LEN)
+    }
+
+    fn export<I, F>(&self, address: F) -> Vec<u8>
+    where
+        I: CompactNodeInfo<NodeId = Id160>,
+        F: Fn(&SocketAddr) -> Option<I::Address>,
+    {
+        let mut data = Vec::new();
+        for node in self.buckets.iter().flat_map(Bucket::iter) {
+            if node.failures > 0 {
+                continue;
+            }
+            // The first address of the family, if any.
+            if let Some(address) = node.addresses.iter().find_map(&address) {
+                let info = I::new_with_address(node.id, address);
+                data.extend_from_slice(&info.write_compact_node_info());
+            }
+        }
+        data
+    }
+
+    fn import<I>(&mut self, data: &[u8], len: usize) -> Result<usize, &'static str>
+    where
+        I: CompactNodeInfo<NodeId = Id160, Error = &'static str>,
+        I::Address: Into<SocketAddr>,
+    {
+        if !data.len().is_multiple_of(len) {
+            return Err("Invalid length for compact node list");
+        }
+        let mut inserted = 0;
+        for chunk in data.chunks(len) {
+            let (_, info) = I::try_read_compact_node_info(chunk)?;
+            let address: SocketAddr = info.to_address().into();
+            if address.port() == 0 || address.ip().is_unspecified() {
+                continue;
+            }
+            let mut node = Node::new(*info.get_node_id(), vec![address]);
+            node.domain = self.domain;
+            if self.insert(node) {
+                inserted += 1;
+            }
+        }
+        Ok(inserted)
     }
 }
 
 impl<A: Address, N: NodeId> Node<A, N> {
     /// Create a new `Node` with the given id and addresses.
     pub fn new(id: N, addresses: Vec<A>) -> Node<A, N> {
-        Node { id, addresses }
+        Node {
+            id,
+            addresses,
+            last_seen: None,
+            failures: 0,
+            rtt: None,
+            domain: None,
+        }
+    }
+
+    /// Tag the node with the routing domain it was learned in, see
+    /// [`RoutingTable::set_domain`].
+    pub fn with_domain(mut self, domain: Domain) -> Node<A, N> {
+        self.domain = Some(domain);
+        self
     }
 
     /// Get the id of the node.
@@ -301,6 +891,48 @@ impl<A: Address, N: NodeId> Node<A, N> {
         &self.id
     }
 
+    /// Get the routing domain of the node, `None` for a node of the public DHT.
+    pub fn domain(&self) -> Option<Domain> {
+        self.domain
+    }
+
+    /// Get the last time the node answered one of our queries, `None` if it never did.
+    pub fn last_seen(&self) -> Option<Instant> {
+        self.last_seen
+    }
+
+    /// Get the number of queries the node failed to answer in a row.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Record that the node answered one of our queries at `now`.
+    pub fn mark_seen(&mut self, now: Instant) {
+        self.last_seen = Some(now);
+        self.failures = 0;
+    }
+
+    /// Get the smoothed round-trip time of the queries to the node, if one was recorded.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Record the round-trip time of a query to the node.
+    ///
+    /// The samples are smoothed as TCP does (7/8 of the previous value, 1/8 of the sample), so
+    /// a single slow reply does not make a node look slow.
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    /// Record that the node failed to answer one of our queries.
+    pub fn mark_failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
     /// Get the addresses of the node.
     ///
     /// Returns a reference to the list of addresses.
@@ -345,3 +977,299 @@ impl<A: Address, N: NodeId> Node<A, N> {
         self.addresses.extend(iter);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::krpc::tests::MockNodeId;
+
+    fn address(ip: impl Into<IpAddr>, port: u16) -> SocketAddr {
+        SocketAddr::new(ip.into(), port)
+    }
+
+    #[test]
+    fn test_merge_multi_homed_node() {
+        let mut table = RoutingTable::new(MockNodeId(0));
+        let v4 = address(Ipv4Addr::new(192, 0, 2, 1), 6881);
+        let v6 = address(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 6882);
+
+        assert!(table.insert(Node::new(MockNodeId(42), vec![v4])));
+        assert!(table.insert(Node::new(MockNodeId(42), vec![v6])));
+        assert!(!table.insert(Node::new(MockNodeId(42), vec![v4])));
+
+        assert_eq!(table.len(), 1);
+        assert_eq!(
+            table.get(&MockNodeId(42)).unwrap().addresses(),
+            &vec![v4, v6]
+        );
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new(MockNodeId(0));
+        for i in 1..=5u8 {
+            let host = Ipv4Addr::new(192, 0, 2, i);
+            assert!(table.insert(Node::new(MockNodeId(i as u64), vec![address(host, 6881)])));
+        }
+        let ids = |nodes: Vec<&Node<SocketAddr, MockNodeId>>| {
+            nodes
+                .into_iter()
+                .map(|node| node.id().0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(table.closest(MockNodeId(4), 2)), [4, 5]);
+        assert_eq!(
+            ids(table.closest(Target::InfoHash(MockNodeId(2)), 3)),
+            [2, 3, 1]
+        );
+        assert_eq!(table.closest(MockNodeId(4), 10).len(), 5);
+    }
+
+    #[test]
+    fn test_domains() {
+        let overlay = Domain::named("lab");
+        assert_eq!(overlay, Domain::named("lab"));
+        assert_ne!(overlay, Domain::named("lab2"));
+        let node = |id: u64| {
+            let host = Ipv4Addr::new(192, 0, 2, id as u8);
+            Node::new(MockNodeId(id), vec![address(host, 6881)])
+        };
+
+        let mut public = RoutingTable::new(MockNodeId(0));
+        assert!(public.insert(node(1)));
+        assert!(!public.insert(node(2).with_domain(overlay)));
+
+        let mut table = RoutingTable::new(MockNodeId(0));
+        table.set_domain(Some(overlay));
+        assert_eq!(table.domain(), Some(overlay));
+        assert!(!table.insert(node(1)));
+        assert!(!table.insert(node(2).with_domain(Domain::named("other"))));
+        assert!(table.insert(node(2).with_domain(overlay)));
+        assert_eq!(table.get(&MockNodeId(2)).unwrap().domain(), Some(overlay));
+        let now = Instant::now();
+        assert!(!table.replace_at(&MockNodeId(2), node(3), now));
+        assert!(table.replace_at(&MockNodeId(2), node(3).with_domain(overlay), now));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_max_nodes_per_host() {
+        let host = Ipv4Addr::new(192, 0, 2, 1);
+        // Not limited by default.
+        let mut table = RoutingTable::new(MockNodeId(0));
+        assert!(table.insert(Node::new(MockNodeId(1), vec![address(host, 6881)])));
+        assert!(table.insert(Node::new(MockNodeId(2), vec![address(host, 6882)])));
+
+        let mut table = RoutingTable::new(MockNodeId(0)).with_max_nodes_per_host(1);
+        assert!(table.insert(Node::new(MockNodeId(1), vec![address(host, 6881)])));
+        // Another port of a host already in use by a different node id.
+        assert!(!table.insert(Node::new(MockNodeId(2), vec![address(host, 6882)])));
+        // IPv4-mapped addresses count as the same host.
+        let mapped = address(host.to_ipv6_mapped(), 6883);
+        assert!(!table.insert(Node::new(MockNodeId(3), vec![mapped])));
+        // The same node id may use several ports of its own host.
+        assert!(table.insert(Node::new(MockNodeId(1), vec![address(host, 6882)])));
+
+        // Only the addresses within the limit are kept.
+        let other = address(Ipv4Addr::new(192, 0, 2, 2), 6881);
+        assert!(table.insert(Node::new(MockNodeId(4), vec![address(host, 6881), other])));
+        assert_eq!(table.get(&MockNodeId(4)).unwrap().addresses(), &vec![other]);
+
+        table.set_max_nodes_per_host(Some(2));
+        assert!(table.insert(Node::new(MockNodeId(2), vec![address(host, 6882)])));
+
+        assert!(table.remove(&MockNodeId(1)).is_some());
+        table.set_max_nodes_per_host(Some(1));
+        assert!(!table.insert(Node::new(MockNodeId(5), vec![address(host, 6881)])));
+        assert_eq!(table.len(), 2);
+        // A node removed frees its host.
+        assert!(table.remove(&MockNodeId(2)).is_some());
+        assert!(table.insert(Node::new(MockNodeId(5), vec![address(host, 6881)])));
+    }
+
+    #[test]
+    fn test_rtt_replacement() {
+        let node = |id: u64, rtt_millis: Option<u64>| {
+            let host = Ipv4Addr::new(192, 0, 2, id as u8);
+            let mut node = Node::new(MockNodeId(id), vec![address(host, 6881)]);
+            if let Some(rtt) = rtt_millis {
+                node.record_rtt(Duration::from_millis(rtt));
+            }
+            node
+        };
+        // A full bucket that cannot be split (the local id is out of its range).
+        let mut table = RoutingTable::new(MockNodeId(u64::MAX));
+        for id in 1..=20 {
+            assert!(table.insert(node(id, Some(100))));
+        }
+        assert!(table.record_rtt(&MockNodeId(7), Duration::from_millis(1900)));
+        assert_eq!(
+            table.get(&MockNodeId(7)).unwrap().rtt(),
+            Some(Duration::from_millis(325))
+        );
+        // A node that failed its last query is not a good node, whatever its RTT.
+        assert!(table.record_rtt(&MockNodeId(5), Duration::from_secs(10)));
+        assert!(table.mark_failed(&MockNodeId(5)));
+        assert!(!table.insert(node(21, Some(10))));
+
+        table.set_rtt_replacement(Some(RttReplacement::default()));
+        // Not fast enough, or no RTT known.
+        assert!(!table.insert(node(21, Some(200))));
+        assert!(!table.insert(node(21, None)));
+        assert!(table.insert(node(21, Some(150))));
+        assert!(table.get(&MockNodeId(7)).is_none());
+        assert!(table.get(&MockNodeId(5)).is_some());
+        assert_eq!(table.len(), 20);
+        // The slowest good node is now the new one, at 150 ms.
+        assert!(!table.insert(node(22, Some(80))));
+        assert!(table.insert(node(22, Some(70))));
+        assert!(table.get(&MockNodeId(21)).is_none());
+    }
+
+    #[test]
+    fn test_split_buckets() {
+        // Spread ids: a multiplicative hash of the index in the first bytes.
+        let id = |i: u32| {
+            let mut id = [0x55; 20];
+            id[..4].copy_from_slice(&i.wrapping_mul(0x9e37_79b9).to_be_bytes());
+            Id160(id)
+        };
+        for local in [0x00, 0x80, 0xff] {
+            let local_id = Id160([local; 20]);
+            let mut table = RoutingTable::new(local_id);
+            for i in 0..2000u32 {
+                let host = Ipv4Addr::from(0x0a00_0000 + i);
+                table.insert(Node::new(id(i), vec![address(host, 6881)]));
+                assert_eq!(table.validate(), Ok(()));
+            }
+            assert!(!table.insert(Node::new(local_id, vec![address(Ipv4Addr::LOCALHOST, 1)])));
+            // A bucket per bit shared with the local id, until the nodes around it fit in one.
+            assert!(table.buckets().len() > 5, "local id {local:#x}");
+            assert!(table.len() > 5 * table.bucket_size(), "local id {local:#x}");
+            assert!(table.len() < 2000);
+            // The far buckets stay full, only the deepest one holds the local id.
+            let holding = table.find_bucket(&local_id).unwrap();
+            for bucket in table.buckets() {
+                assert!(bucket.len() == 20 || bucket.depth() == holding.depth());
+                for node in bucket.iter() {
+                    assert!(std::ptr::eq(table.find_bucket(node.id()).unwrap(), bucket));
+                }
+            }
+            assert_eq!(holding.depth(), table.buckets().len() - 1);
+        }
+
+        // A bucket emptied merges back into its sibling.
+        let mut table = RoutingTable::new(Id160([0; 20]));
+        for i in 0..2000u32 {
+            let host = Ipv4Addr::from(0x0a00_0000 + i);
+            table.insert(Node::new(id(i), vec![address(host, 6881)]));
+        }
+        let count = table.buckets().len();
+        let ids: Vec<Id160> = table.buckets()[0].iter().map(|node| *node.id()).collect();
+        for id in &ids {
+            assert!(table.remove(id).is_some());
+        }
+        assert_eq!(table.buckets().len(), count - 1);
+        assert_eq!(table.buckets()[0].depth(), count - 2);
+        assert_eq!(table.validate(), Ok(()));
+        // The last node removed drops the last bucket.
+        let nodes = table.buckets().iter().flat_map(Bucket::iter);
+        let ids: Vec<Id160> = nodes.map(|node| *node.id()).collect();
+        for id in &ids {
+            assert!(table.remove(id).is_some());
+        }
+        assert!(table.buckets().is_empty());
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_repair() {
+        let node = |id: u64, seen: Option<Instant>| {
+            let host = Ipv4Addr::new(192, 0, 2, id as u8);
+            let mut node = Node::new(MockNodeId(id), vec![address(host, 6881)]);
+            if let Some(seen) = seen {
+                node.mark_seen(seen);
+            }
+            node
+        };
+        let now = Instant::now();
+        let local_id = u64::MAX;
+        let mut table = RoutingTable::new(MockNodeId(local_id));
+        assert_eq!(table.repair(), 0);
+        for id in 1..=20 {
+            assert!(table.insert(node(id, None)));
+        }
+        assert_eq!(table.repair(), 0);
+
+        // A bucket overlapping the other, holding the local node and an extra node.
+        let extra = [node(5, None), node(21, Some(now)), node(local_id, None)];
+        table.buckets.push(Bucket {
+            nodes: extra.into_iter().collect(),
+            prefix: vec![0; 8],
+            depth: 0,
+            last_changed: now,
+        });
+        assert_eq!(
+            table.validate(),
+            Err("bucket ranges not covering the keyspace in order")
+        );
+        // The local node, the duplicate and one node of the full bucket are dropped.
+        assert_eq!(table.repair(), 3);
+        assert_eq!(table.validate(), Ok(()));
+        assert_eq!(table.len(), 20);
+        assert!(table.get(&MockNodeId(21)).is_some());
+        assert!(table.get(&MockNodeId(local_id)).is_none());
+
+        table.buckets[0].nodes.swap(0, 1);
+        assert_eq!(table.validate(), Err("bucket nodes not sorted by id"));
+        assert_eq!(table.repair(), 0);
+        assert_eq!(table.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_compact_export() {
+        let mut table = RoutingTable::new(Id160([0; 20]));
+        let v4 = address(Ipv4Addr::new(192, 0, 2, 1), 6881);
+        let v6 = address(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 6881);
+        let mapped = address(Ipv4Addr::new(192, 0, 2, 2).to_ipv6_mapped(), 6882);
+        assert!(table.insert(Node::new(Id160([1; 20]), vec![v4, v6])));
+        assert!(table.insert(Node::new(Id160([2; 20]), vec![mapped])));
+        let failed = address(Ipv4Addr::new(192, 0, 2, 3), 6883);
+        assert!(table.insert(Node::new(Id160([3; 20]), vec![failed])));
+        assert!(table.mark_failed(&Id160([3; 20])));
+
+        // The failed node is left out, the IPv4-mapped address is exported as IPv4.
+        let nodes = table.export_compact();
+        assert_eq!(nodes.len(), 2 * 26);
+        assert_eq!(nodes[..20], [1; 20]);
+        assert_eq!(nodes[20..26], [192, 0, 2, 1, 0x1a, 0xe1]);
+        let nodes6 = table.export_compact6();
+        assert_eq!(nodes6.len(), 38);
+
+        let mut other = RoutingTable::new(Id160([0xff; 20]));
+        assert_eq!(other.import_compact(&nodes), Ok(2));
+        // Already known through its IPv4 address, the node gets its IPv6 one.
+        assert_eq!(other.import_compact6(&nodes6), Ok(1));
+        assert_eq!(
+            other.get(&Id160([1; 20])).unwrap().addresses(),
+            &vec![v4, v6]
+        );
+        let unmapped = address(Ipv4Addr::new(192, 0, 2, 2), 6882);
+        assert_eq!(
+            other.get(&Id160([2; 20])).unwrap().addresses(),
+            &vec![unmapped]
+        );
+        assert!(other.get(&Id160([1; 20])).unwrap().last_seen().is_none());
+
+        // A truncated list, and a node without a port.
+        assert!(other.import_compact(&nodes[..30]).is_err());
+        let mut portless = [0u8; 26];
+        portless[..20].copy_from_slice(&[4; 20]);
+        portless[20..24].copy_from_slice(&[192, 0, 2, 4]);
+        assert_eq!(other.import_compact(&portless), Ok(0));
+        assert_eq!(other.len(), 2);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use super::{Id160, NodeId};
+
+/// The info hash of a torrent: the key of its swarm in the DHT, in the same key space as the
+/// node ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct InfoHash(pub Id160);
+
+impl InfoHash {
+    /// Get the info hash as an identifier of the key space.
+    pub fn as_id(&self) -> &Id160 {
+        &self.0
+    }
+}
+
+impl From<Id160> for InfoHash {
+    fn from(value: Id160) -> Self {
+        InfoHash(value)
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(value: [u8; 20]) -> Self {
+        InfoHash(Id160(value))
+    }
+}
+
+impl From<InfoHash> for Id160 {
+    fn from(value: InfoHash) -> Self {
+        value.0
+    }
+}
+
+impl Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for InfoHash {
+    type Err = &'static str;
+
+    /// Parse an info hash as an [`Id160`]: in hexadecimal or in base32.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(InfoHash)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for InfoHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for InfoHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Id160::deserialize(deserializer).map(InfoHash)
+    }
+}
+
+/// What a lookup walks toward: a node id (with `find_node` queries) or an info hash (with
+/// `get_peers` queries).
+///
+/// Both live in the same key space, so the distances are the same, but the queries and what the
+/// lookup finds (nodes, or peers) differ. A node id converts into a [`Target::Node`], an
+/// [`InfoHash`] into a [`Target::InfoHash`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target<N: NodeId = Id160> {
+    /// The nodes closest to a node id.
+    Node(N),
+    /// The peers of a torrent, and the nodes closest to its info hash.
+    InfoHash(N),
+}
+
+impl<N: NodeId> Target<N> {
+    /// Get the identifier of the target in the key space.
+    pub fn id(&self) -> &N {
+        match self {
+            Target::Node(id) | Target::InfoHash(id) => id,
+        }
+    }
+
+    /// Check if the target is an info hash.
+    pub fn is_info_hash(&self) -> bool {
+        matches!(self, Target::InfoHash(_))
+    }
+}
+
+impl<N: NodeId> From<N> for Target<N> {
+    fn from(value: N) -> Self {
+        Target::Node(value)
+    }
+}
+
+impl From<InfoHash> for Target {
+    fn from(value: InfoHash) -> Self {
+        Target::InfoHash(value.0)
+    }
+}
+
+impl<N: NodeId + Display> Display for Target<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Node(id) => write!(f, "node {}", id),
+            Target::InfoHash(id) => write!(f, "info hash {}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let id = Id160([7; 20]);
+        assert_eq!(Target::from(id), Target::Node(id));
+        assert_eq!(Target::from(InfoHash(id)), Target::InfoHash(id));
+        assert_eq!(Target::from(InfoHash::from(id)).id(), &id);
+        assert!(Target::from(InfoHash(id)).is_info_hash());
+        assert!(!Target::from(id).is_info_hash());
+        assert_eq!(Id160::from(InfoHash(id)), id);
+        assert_eq!(
+            Target::from(InfoHash(id)).to_string(),
+            format!("info hash {}", id)
+        );
+        assert_eq!(InfoHash(id).to_string().parse(), Ok(InfoHash(id)));
+    }
+}
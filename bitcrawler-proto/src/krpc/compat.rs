@@ -0,0 +1,220 @@
+use std::ops::BitOr;
+
+use crate::bencode::{BencodeString, BencodeValue};
+
+/// Set of known deviations of a client from BEP 5, see [`QuirkDatabase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Quirks(pub u8);
+
+impl Quirks {
+    /// The `port` of an `announce_peer` query is a string of digits (`4:6881`) instead of an
+    /// integer.
+    pub const PORT_AS_STRING: Quirks = Quirks(1 << 0);
+    /// The `y` field is missing, the kind of the message is given by its `q`, `r` or `e` field.
+    pub const MISSING_MESSAGE_TYPE: Quirks = Quirks(1 << 1);
+    /// The `values` of a `get_peers` reply are one string of concatenated compact peer infos
+    /// instead of a list of strings.
+    pub const VALUES_AS_BLOB: Quirks = Quirks(1 << 2);
+
+    /// Check if no quirk is set.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check if every quirk of `other` is set.
+    pub fn contains(self, other: Quirks) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Quirks {
+    type Output = Quirks;
+
+    fn bitor(self, other: Quirks) -> Quirks {
+        Quirks(self.0 | other.0)
+    }
+}
+
+/// The quirks of the clients, keyed by the prefix of their version (the `v` field: 2 bytes of
+/// client id, then 2 bytes of version by convention).
+///
+/// Rather than accepting every deviation from every node, a parser looks up the quirks of the
+/// sender of a message and only undoes those with [`apply_shims`]: the messages of the other
+/// clients are parsed as strictly as before. The entries come from what the malformed messages
+/// of the clients show, the database is empty by default.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::krpc::{QuirkDatabase, Quirks};
+///
+/// let database = QuirkDatabase::new()
+///     .with("XY", Quirks::PORT_AS_STRING)
+///     .with("XY\x00\x01", Quirks::VALUES_AS_BLOB);
+/// assert_eq!(
+///     database.lookup(b"XY\x00\x01"),
+///     Quirks::PORT_AS_STRING | Quirks::VALUES_AS_BLOB
+/// );
+/// assert_eq!(database.lookup(b"XY\x00\x02"), Quirks::PORT_AS_STRING);
+/// assert!(database.lookup(b"UT\x01\x02").is_empty());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct QuirkDatabase {
+    entries: Vec<(BencodeString, Quirks)>,
+}
+
+impl QuirkDatabase {
+    /// Create an empty database.
+    pub fn new() -> QuirkDatabase {
+        QuirkDatabase::default()
+    }
+
+    /// Add the `quirks` of the clients whose version starts with `fingerprint`.
+    pub fn with(mut self, fingerprint: impl Into<BencodeString>, quirks: Quirks) -> QuirkDatabase {
+        self.entries.push((fingerprint.into(), quirks));
+        self
+    }
+
+    /// Check if the database has no entry.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the quirks of a client from its version: the quirks of every matching fingerprint.
+    pub fn lookup(&self, version: &[u8]) -> Quirks {
+        self.entries
+            .iter()
+            .filter(|(fingerprint, _)| version.starts_with(fingerprint.as_ref()))
+            .fold(Quirks::default(), |quirks, (_, entry)| quirks | *entry)
+    }
+
+    /// Get the quirks of the sender of a bencoded message, from its `v` field.
+    pub fn lookup_message(&self, message: &BencodeValue) -> Quirks {
+        match message.get(b"v") {
+            Some(BencodeValue::ByteString(version)) => self.lookup(version.as_ref()),
+            _ => Quirks::default(),
+        }
+    }
+}
+
+/// Rewrite a bencoded message into its BEP 5 form, undoing only the given `quirks`.
+///
+/// Returns the quirks actually found in the message. A message without them is left untouched.
+pub fn apply_shims(message: &mut BencodeValue, quirks: Quirks) -> Quirks {
+    let mut applied = Quirks::default();
+    let BencodeValue::Dict(dict) = message else {
+        return applied;
+    };
+    if quirks.contains(Quirks::MISSING_MESSAGE_TYPE)
+        && !dict.iter().any(|(key, _)| key.as_ref() == b"y")
+        && let Some(kind) = dict.iter().find_map(|(key, _)| match key.as_ref() {
+            b"q" => Some("q"),
+            b"r" => Some("r"),
+            b"e" => Some("e"),
+            _ => None,
+        })
+    {
+        dict.push(("y".into(), BencodeValue::ByteString(kind.into())));
+        applied = applied | Quirks::MISSING_MESSAGE_TYPE;
+    }
+    for (key, value) in dict.iter_mut() {
+        let BencodeValue::Dict(arguments) = value else {
+            continue;
+        };
+        for (name, argument) in arguments.iter_mut() {
+            match (key.as_ref(), name.as_ref(), &*argument) {
+                (b"a", b"port", BencodeValue::ByteString(port))
+                    if quirks.contains(Quirks::PORT_AS_STRING) =>
+                {
+                    let port = std::str::from_utf8(port.as_ref())
+                        .ok()
+                        .and_then(|port| port.parse::<u16>().ok());
+                    if let Some(port) = port {
+                        *argument = BencodeValue::Integer(port.into());
+                        applied = applied | Quirks::PORT_AS_STRING;
+                    }
+                }
+                (b"r", b"values", BencodeValue::ByteString(blob))
+                    if quirks.contains(Quirks::VALUES_AS_BLOB)
+                        && !blob.as_ref().is_empty()
+                        && blob.as_ref().len() % COMPACT_PEER_V4_LEN == 0 =>
+                {
+                    let values = blob
+                        .as_ref()
+                        .chunks(COMPACT_PEER_V4_LEN)
+                        .map(|peer| BencodeValue::ByteString(peer.into()))
+                        .collect();
+                    *argument = BencodeValue::List(values);
+                    applied = applied | Quirks::VALUES_AS_BLOB;
+                }
+                _ => {}
+            }
+        }
+    }
+    // The message type was pushed last, keep the keys in order.
+    if applied.contains(Quirks::MISSING_MESSAGE_TYPE) {
+        message.sort_keys();
+    }
+    applied
+}
+
+/// Length of an IPv4 compact peer info, the peers of a `values` blob.
+const COMPACT_PEER_V4_LEN: usize = 6;
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use super::*;
+    use crate::{
+        bencode::decode,
+        kademlia::Id160,
+        krpc::{Query, QueryType, Response, ResponseType, node_info::BittorrentNodeInfoV4},
+    };
+
+    fn bencoded(message: &[u8]) -> BencodeValue {
+        decode(&message).unwrap().1
+    }
+
+    #[test]
+    fn test_shims() {
+        let database = QuirkDatabase::new()
+            .with("XY", Quirks::PORT_AS_STRING | Quirks::MISSING_MESSAGE_TYPE)
+            .with("ZZ", Quirks::VALUES_AS_BLOB);
+
+        let announce = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456\
+                         4:port4:68815:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:v4:XY01e";
+        // Without the quirk, the message is rejected.
+        let mut message = bencoded(announce);
+        assert!(Query::<Id160>::try_from_bencoded(&message).is_err());
+        let quirks = database.lookup_message(&message);
+        assert_eq!(
+            apply_shims(&mut message, quirks),
+            Quirks::PORT_AS_STRING | Quirks::MISSING_MESSAGE_TYPE
+        );
+        assert!(message.is_canonical());
+        let query = Query::<Id160>::try_from_bencoded(&message).unwrap();
+        let QueryType::AnnouncePeer(announce_peer) = query.get_query() else {
+            panic!("not an announce_peer query");
+        };
+        assert_eq!(announce_peer.get_port().map(u16::from), Some(6881));
+
+        let reply = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth\
+                      6:values12:axje.uidhtnme1:t2:aa1:v4:ZZ011:y1:re";
+        let mut message = bencoded(reply);
+        // The quirks of another client are not undone.
+        assert!(apply_shims(&mut message, Quirks::PORT_AS_STRING).is_empty());
+        assert_eq!(message, bencoded(reply));
+        let quirks = database.lookup_message(&message);
+        assert_eq!(apply_shims(&mut message, quirks), Quirks::VALUES_AS_BLOB);
+        let reply =
+            Response::<BittorrentNodeInfoV4<Id160>, SocketAddrV4>::try_from_getpeers_bencoded(
+                &message,
+            )
+            .unwrap();
+        let ResponseType::GetPeers(get_peers) = reply.get_response_type() else {
+            panic!("not a get_peers reply");
+        };
+        assert_eq!(get_peers.get_peers().len(), 2);
+    }
+}
@@ -0,0 +1,207 @@
+//! Conformance with the example messages of [BEP 5](https://www.bittorrent.org/beps/bep_0005.html).
+//!
+//! Every example must be decoded, and encoded back byte for byte.
+
+use std::net::SocketAddrV4;
+
+use crate::bencode::{BencodeValue, decode, encode};
+use crate::kademlia::Id160;
+
+use super::node_info::BittorrentNodeInfoV4;
+use super::query::AnnounceToken;
+use super::{
+    BencodedMessage, ErrorCode, ErrorMessage, Message, Port, Query, QueryType, Response,
+    ResponseType,
+};
+
+type SpecResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+
+const PING_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+const PING_RESPONSE: &[u8] = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+const FIND_NODE_QUERY: &[u8] =
+    b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
+// The example of the spec holds a shortened 9-byte `nodes` ("def456..."), a whole compact node
+// info is used instead.
+const FIND_NODE_RESPONSE: &[u8] =
+    b"d1:rd2:id20:0123456789abcdefghij5:nodes26:def456def456def456de123456e1:t2:aa1:y1:re";
+const GET_PEERS_QUERY: &[u8] =
+    b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+const GET_PEERS_RESPONSE_PEERS: &[u8] =
+    b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+const GET_PEERS_RESPONSE_NODES: &[u8] =
+    b"d1:rd2:id20:abcdefghij01234567895:nodes26:def456def456def456de1234565:token8:aoeusnthe1:t2:aa1:y1:re";
+const ANNOUNCE_PEER_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+const ANNOUNCE_PEER_RESPONSE: &[u8] = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+const ERROR: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+fn id(value: &[u8; 20]) -> Id160 {
+    Id160(*value)
+}
+
+fn bencoded(message: &[u8]) -> BencodeValue {
+    let (read, bencoded) = decode(&message).unwrap();
+    assert_eq!(read, message.len());
+    bencoded
+}
+
+fn parse_query(message: &[u8]) -> Query<Id160> {
+    let query = Query::<Id160>::try_from_bencoded(&bencoded(message)).unwrap();
+    assert_eq!(encode(&query.to_bencoded()), message);
+    query
+}
+
+/// The node info of the `nodes` examples.
+fn spec_node() -> BittorrentNodeInfoV4<Id160> {
+    BittorrentNodeInfoV4 {
+        node_id: id(b"def456def456def456de"),
+        ip: *b"1234",
+        port: u16::from_be_bytes(*b"56"),
+    }
+}
+
+#[test]
+fn test_ping() {
+    let query = parse_query(PING_QUERY);
+    assert_eq!(query, Query::new_ping("aa", id(b"abcdefghij0123456789")));
+
+    let response = SpecResponse::try_from_ping_bencoded(&bencoded(PING_RESPONSE)).unwrap();
+    assert_eq!(
+        response,
+        SpecResponse::new_ping("aa", id(b"mnopqrstuvwxyz123456"))
+    );
+    assert_eq!(encode(&response.to_bencoded()), PING_RESPONSE);
+}
+
+#[test]
+fn test_find_node() {
+    let query = parse_query(FIND_NODE_QUERY);
+    assert_eq!(
+        query,
+        Query::new_find_node(
+            "aa",
+            id(b"abcdefghij0123456789"),
+            id(b"mnopqrstuvwxyz123456")
+        )
+    );
+
+    let response = SpecResponse::try_from_findpeer_bencoded(&bencoded(FIND_NODE_RESPONSE)).unwrap();
+    assert_eq!(
+        response,
+        SpecResponse::new_find_node("aa", id(b"0123456789abcdefghij"), vec![spec_node()])
+    );
+    assert_eq!(encode(&response.to_bencoded()), FIND_NODE_RESPONSE);
+}
+
+#[test]
+fn test_get_peers() {
+    let query = parse_query(GET_PEERS_QUERY);
+    assert_eq!(
+        query,
+        Query::new_get_peers(
+            "aa",
+            id(b"abcdefghij0123456789"),
+            id(b"mnopqrstuvwxyz123456")
+        )
+    );
+
+    let response =
+        SpecResponse::try_from_getpeers_bencoded(&bencoded(GET_PEERS_RESPONSE_PEERS)).unwrap();
+    let peer = |compact: &[u8; 6]| {
+        SocketAddrV4::new(
+            [compact[0], compact[1], compact[2], compact[3]].into(),
+            u16::from_be_bytes([compact[4], compact[5]]),
+        )
+    };
+    assert_eq!(
+        response,
+        SpecResponse::new_get_peers(
+            "aa",
+            id(b"abcdefghij0123456789"),
+            Some("aoeusnth".into()),
+            vec![],
+            vec![peer(b"axje.u"), peer(b"idhtnm")],
+        )
+    );
+    assert_eq!(encode(&response.to_bencoded()), GET_PEERS_RESPONSE_PEERS);
+
+    let response =
+        SpecResponse::try_from_getpeers_bencoded(&bencoded(GET_PEERS_RESPONSE_NODES)).unwrap();
+    assert_eq!(
+        response,
+        SpecResponse::new_get_peers(
+            "aa",
+            id(b"abcdefghij0123456789"),
+            Some("aoeusnth".into()),
+            vec![spec_node()],
+            vec![],
+        )
+    );
+    assert_eq!(encode(&response.to_bencoded()), GET_PEERS_RESPONSE_NODES);
+}
+
+#[test]
+fn test_announce_peer() {
+    // The token of the announce is the one of the get_peers reply.
+    let reply =
+        SpecResponse::try_from_getpeers_bencoded(&bencoded(GET_PEERS_RESPONSE_NODES)).unwrap();
+    let token = match reply.get_response_type() {
+        ResponseType::GetPeers(get_peers) => get_peers.announce_token().unwrap(),
+        other => panic!("unexpected response {:?}", other),
+    };
+    assert_eq!(token, AnnounceToken::from_raw("aoeusnth"));
+
+    let query = parse_query(ANNOUNCE_PEER_QUERY);
+    assert_eq!(
+        query,
+        Query::new_announce_peer_implied_port(
+            "aa",
+            id(b"abcdefghij0123456789"),
+            id(b"mnopqrstuvwxyz123456"),
+            Port::new(6881).unwrap(),
+            token,
+        )
+    );
+    match query.get_query() {
+        QueryType::AnnouncePeer(announce) => assert!(announce.get_implied_port()),
+        other => panic!("unexpected query {:?}", other),
+    }
+
+    // The reply to announce_peer only holds the id of the node, like a ping reply.
+    let response = SpecResponse::try_from_ping_bencoded(&bencoded(ANNOUNCE_PEER_RESPONSE)).unwrap();
+    assert!(matches!(
+        response.get_response_type(),
+        ResponseType::Ping(_)
+    ));
+    assert_eq!(encode(&response.to_bencoded()), ANNOUNCE_PEER_RESPONSE);
+}
+
+#[test]
+fn test_error() {
+    let error = ErrorMessage::try_from_bencoded(&bencoded(ERROR)).unwrap();
+    assert_eq!(
+        error,
+        ErrorMessage::new(
+            "aa",
+            ErrorCode::GenericError,
+            "A Generic Error Ocurred".to_string()
+        )
+    );
+    assert_eq!(encode(&error.to_bencoded()), ERROR);
+}
+
+#[test]
+fn test_message_dispatch() {
+    let cases: [(&[u8], bool); 5] = [
+        (PING_QUERY, true),
+        (FIND_NODE_QUERY, true),
+        (GET_PEERS_QUERY, true),
+        (ANNOUNCE_PEER_QUERY, true),
+        (ERROR, false),
+    ];
+    for (message, is_query) in cases {
+        match Message::<Id160>::try_from_bencoded(&bencoded(message)).unwrap() {
+            Message::Query(_) => assert!(is_query),
+            Message::Error(_) => assert!(!is_query),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    bencode::{BencodeString, BencodeValue},
+    kademlia::NodeId,
+};
+
+use super::{
+    ErrorCode, ErrorMessage, MessageExtensions, MessageOptions, Query, Response,
+    node_info::CompactNodeInfo, peer_info::CompactPeerInfo,
+};
+
+/// Length of the transaction ids assigned by a [`MessageEnvelope`].
+pub const ENVELOPE_TRANSACTION_ID_LEN: usize = size_of::<u32>();
+
+/// Protocol-level fields of the messages a node sends, so that its call sites only give the
+/// body of each message and cannot forget the rest.
+///
+/// The queries get a new transaction id (`t`), from a counter starting where given to
+/// [`MessageEnvelope::new`], and the [`MessageOptions`]: client version (`v`), read-only flag
+/// (`ro`) and extra fields. The replies and the errors echo the transaction id of the query
+/// they answer and get the client version, but never the read-only flag, which BEP 43 only
+/// defines for queries. The [`MessageExtensions`] of the envelope, if any, run last on every
+/// message sealed (see [`MessageEnvelope::seal`]), and on the messages received through
+/// [`MessageEnvelope::verify`].
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::{
+///     bencode,
+///     kademlia::Id160,
+///     krpc::{ErrorCode, MessageEnvelope, MessageOptions, Query, Response},
+///     krpc::node_info::BittorrentNodeInfoV4,
+/// };
+/// use std::net::SocketAddrV4;
+///
+/// let options = MessageOptions {
+///     version: Some("BC01".into()),
+///     read_only: true,
+///     ..MessageOptions::default()
+/// };
+/// let mut envelope = MessageEnvelope::new(options, 0x61616161);
+/// let id = Id160(*b"abcdefghij0123456789");
+///
+/// let ping = envelope.query(|tid| Query::new_ping(tid, id));
+/// assert_eq!(
+///     bencode::encode(&ping.to_bencoded()),
+///     b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t4:aaaa1:v4:BC011:y1:qe"
+/// );
+///
+/// let reply = envelope.reply(&ping, |tid| {
+///     Response::<BittorrentNodeInfoV4<Id160>, SocketAddrV4>::new_ping(tid, id)
+/// });
+/// assert_eq!(
+///     bencode::encode(&reply),
+///     b"d1:rd2:id20:abcdefghij0123456789e1:t4:aaaa1:v4:BC011:y1:re"
+/// );
+///
+/// let error = envelope.error(&ping, ErrorCode::ServerError, "busy");
+/// assert_eq!(bencode::encode(&error), b"d1:eli202e4:busye1:t4:aaaa1:v4:BC011:y1:ee");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageEnvelope {
+    options: MessageOptions,
+    extensions: MessageExtensions,
+    next_transaction_id: u32,
+}
+
+impl MessageEnvelope {
+    /// Create an envelope adding `options` to the queries, whose transaction ids start at
+    /// `first_transaction_id`.
+    ///
+    /// Replies to transaction ids guessed from a counter starting at 0 are easy to spoof: the
+    /// first transaction id should be random.
+    pub fn new(options: MessageOptions, first_transaction_id: u32) -> Self {
+        MessageEnvelope {
+            options,
+            extensions: MessageExtensions::new(),
+            next_transaction_id: first_transaction_id,
+        }
+    }
+
+    /// Run `extensions` on the messages sealed and verified.
+    pub fn with_extensions(mut self, extensions: MessageExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Get the options added to the queries.
+    pub fn options(&self) -> &MessageOptions {
+        &self.options
+    }
+
+    /// Get the extensions run on the messages sealed and verified.
+    pub fn extensions(&self) -> &MessageExtensions {
+        &self.extensions
+    }
+
+    /// Assign a new transaction id, e.g. to patch it into a
+    /// [`QueryTemplate`](super::QueryTemplate) of [`ENVELOPE_TRANSACTION_ID_LEN`] bytes.
+    ///
+    /// The transaction ids are consecutive, and wrap around after 2^32 queries.
+    pub fn next_transaction_id(&mut self) -> [u8; ENVELOPE_TRANSACTION_ID_LEN] {
+        let transaction_id = self.next_transaction_id.to_be_bytes();
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+        transaction_id
+    }
+
+    /// Build a query with `build`, given a new transaction id, and add the options to it.
+    pub fn query<N, F>(&mut self, build: F) -> Query<N>
+    where
+        N: NodeId,
+        F: FnOnce(BencodeString) -> Query<N>,
+    {
+        let transaction_id = self.next_transaction_id().to_vec().into();
+        build(transaction_id).with_options(self.options.clone())
+    }
+
+    /// Build the reply to `query` with `build`, given the transaction id of the query, and add
+    /// the client version to it.
+    pub fn reply<N, I, P, F>(&self, query: &Query<N>, build: F) -> BencodeValue
+    where
+        N: NodeId,
+        I: CompactNodeInfo,
+        P: CompactPeerInfo,
+        F: FnOnce(BencodeString) -> Response<I, P>,
+    {
+        self.seal(build(query.get_transaction_id().clone()).to_bencoded())
+    }
+
+    /// Build an error answering `query`, with the client version.
+    pub fn error<N: NodeId>(
+        &self,
+        query: &Query<N>,
+        code: ErrorCode,
+        message: impl Into<String>,
+    ) -> BencodeValue {
+        let transaction_id = query.get_transaction_id().clone();
+        self.seal(ErrorMessage::new(transaction_id, code, message.into()).to_bencoded())
+    }
+
+    /// Add the client version to an already built `message`, and the read-only flag if it is a
+    /// query. The fields already in the message are kept, then the extensions run, and the
+    /// keys are sorted.
+    ///
+    /// A `message` that is not a dictionary is returned as-is.
+    pub fn seal(&self, mut message: BencodeValue) -> BencodeValue {
+        let BencodeValue::Dict(dict) = &mut message else {
+            return message;
+        };
+        let has = |dict: &[(BencodeString, BencodeValue)], key: &[u8]| {
+            dict.iter().any(|(k, _)| k.as_ref() == key)
+        };
+        let is_query = dict.iter().any(|(key, value)| {
+            key.as_ref() == b"y"
+                && matches!(value, BencodeValue::ByteString(kind) if kind.as_ref() == b"q")
+        });
+        if let Some(version) = &self.options.version
+            && !has(dict, b"v")
+        {
+            dict.push(("v".into(), BencodeValue::ByteString(version.clone())));
+        }
+        if is_query && self.options.read_only && !has(dict, b"ro") {
+            dict.push(("ro".into(), BencodeValue::Integer(1)));
+        }
+        message.sort_keys();
+        self.extensions.encode(&mut message);
+        message
+    }
+
+    /// Check a decoded `message` received with the extensions, before parsing it: an error
+    /// rejects it, see [`MessageExtensions::parse`].
+    pub fn verify(&self, message: &BencodeValue) -> Result<(), &'static str> {
+        self.extensions.parse(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use super::*;
+    use crate::{bencode, kademlia::Id160, krpc::node_info::BittorrentNodeInfoV4};
+
+    type TestResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+
+    #[test]
+    fn test_transaction_ids() {
+        let mut envelope = MessageEnvelope::new(MessageOptions::default(), u32::MAX);
+        let id = Id160([1; 20]);
+        let first = envelope.query(|tid| Query::new_ping(tid, id));
+        let second = envelope.query(|tid| Query::new_find_node(tid, id, id));
+        assert_eq!(first.get_transaction_id().as_ref(), &[0xff; 4]);
+        assert_eq!(second.get_transaction_id().as_ref(), &[0; 4]);
+        assert_eq!(envelope.next_transaction_id(), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_seal() {
+        let options = MessageOptions {
+            version: Some("BC01".into()),
+            read_only: true,
+            ..MessageOptions::default()
+        };
+        let envelope = MessageEnvelope::new(options, 0);
+        let id = Id160([1; 20]);
+
+        // Replies get the version, never the read-only flag.
+        let reply = envelope.seal(TestResponse::new_ping("aa", id).to_bencoded());
+        let encoded = bencode::encode(&reply);
+        assert!(encoded.ends_with(b"1:t2:aa1:v4:BC011:y1:re"));
+        assert!(!encoded.windows(4).any(|w| w == b"2:ro"));
+
+        // Queries built elsewhere get both, and the fields already set are kept.
+        let query = Query::new_ping("aa", id).with_options(MessageOptions {
+            version: Some("UT01".into()),
+            ..MessageOptions::default()
+        });
+        let sealed = bencode::encode(&envelope.seal(query.to_bencoded()));
+        assert!(sealed.ends_with(b"2:roi1e1:t2:aa1:v4:UT011:y1:qe"));
+
+        let not_a_dict = BencodeValue::Integer(1);
+        assert_eq!(envelope.seal(not_a_dict.clone()), not_a_dict);
+    }
+}
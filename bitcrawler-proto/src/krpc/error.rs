@@ -1,4 +1,9 @@
-use crate::bencode::{BencodeString, BencodeValue};
+use std::fmt::{self, Display};
+
+use crate::{
+    bencode::{BencodeString, BencodeValue},
+    consts::{ERROR_GENERIC, ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL, ERROR_SERVER},
+};
 
 /// Represents an error message in a KRPC response.
 ///
@@ -18,17 +23,56 @@ pub struct ErrorMessage {
 }
 
 /// Represents an error code in a KRPC error message.
+///
+/// The codes of BEP 5 have their own variant, the others (e.g. the vendor-specific 301 of some
+/// clients) are kept as [`ErrorCode::Unknown`]. With the `serde` feature, a code is serialized
+/// as its number.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::krpc::ErrorCode;
+///
+/// assert_eq!(ErrorCode::from(203), ErrorCode::ProtocolError);
+/// assert_eq!(ErrorCode::from(301).to_string(), "301 Unknown Error");
+/// ```
 #[non_exhaustive]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ErrorCode {
-    /// The generic error code.
-    GenericError = 201,
-    /// The server error code.
-    ServerError = 202,
-    /// The protocol error code.
-    ProtocolError = 203,
-    /// The method unknown error code.
-    MethodUnknown = 204,
+    /// The generic error code (201).
+    GenericError,
+    /// The server error code (202).
+    ServerError,
+    /// The protocol error code (203), e.g. a malformed packet or a bad token.
+    ProtocolError,
+    /// The method unknown error code (204).
+    MethodUnknown,
+    /// A code not defined by BEP 5.
+    Unknown(i64),
+}
+
+impl ErrorCode {
+    /// Get the number of the code.
+    pub fn code(self) -> i64 {
+        match self {
+            ErrorCode::GenericError => ERROR_GENERIC,
+            ErrorCode::ServerError => ERROR_SERVER,
+            ErrorCode::ProtocolError => ERROR_PROTOCOL,
+            ErrorCode::MethodUnknown => ERROR_METHOD_UNKNOWN,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+
+    /// Get the description of the code, `Unknown Error` for the codes not defined by BEP 5.
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::GenericError => "Generic Error",
+            ErrorCode::ServerError => "Server Error",
+            ErrorCode::ProtocolError => "Protocol Error",
+            ErrorCode::MethodUnknown => "Method Unknown",
+            ErrorCode::Unknown(_) => "Unknown Error",
+        }
+    }
 }
 
 impl ErrorMessage {
@@ -53,19 +97,20 @@ impl ErrorMessage {
 
     /// Converts the `ErrorMessage` into a `BencodedValue`.
     pub fn to_bencoded(&self) -> BencodeValue {
-        let mut dict = Vec::new();
-        dict.push((
-            "t".into(),
-            BencodeValue::ByteString(self.transaction_id.clone()),
-        ));
-        dict.push(("y".into(), BencodeValue::ByteString("e".into())));
-        dict.push((
-            "e".into(),
-            BencodeValue::List(vec![
-                BencodeValue::Integer(self.code as i128),
-                BencodeValue::ByteString(BencodeString::from(self.message.as_str())),
-            ]),
-        ));
+        let dict = vec![
+            (
+                "t".into(),
+                BencodeValue::ByteString(self.transaction_id.clone()),
+            ),
+            ("y".into(), BencodeValue::ByteString("e".into())),
+            (
+                "e".into(),
+                BencodeValue::List(vec![
+                    BencodeValue::Integer(self.code.code() as i128),
+                    BencodeValue::ByteString(BencodeString::from(self.message.as_str())),
+                ]),
+            ),
+        ];
         BencodeValue::Dict(dict)
     }
 
@@ -98,14 +143,8 @@ impl ErrorMessage {
                         return Err("expected list of length 2");
                     }
 
-                    let code_ = match &list[0] {
-                        BencodeValue::Integer(i) => *i,
-                        _ => return Err("expected integer"),
-                    };
-                    code = match ErrorCode::try_from(code_) {
-                        Ok(code) => Some(code),
-                        Err(_) => return Err("invalid error code"),
-                    };
+                    let code_ = list[0].as_integer().ok_or("expected integer")?;
+                    code = Some(ErrorCode::try_from(code_).or(Err("error code out of range"))?);
 
                     message = match &list[1] {
                         BencodeValue::ByteString(s) => Some(s.clone()),
@@ -137,17 +176,50 @@ impl PartialEq for ErrorMessage {
     }
 }
 
+impl From<i64> for ErrorCode {
+    fn from(value: i64) -> Self {
+        match value {
+            ERROR_GENERIC => Self::GenericError,
+            ERROR_SERVER => Self::ServerError,
+            ERROR_PROTOCOL => Self::ProtocolError,
+            ERROR_METHOD_UNKNOWN => Self::MethodUnknown,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
 impl TryFrom<i128> for ErrorCode {
     type Error = ();
 
+    /// Fails only if the code does not fit in an `i64`.
     fn try_from(value: i128) -> Result<Self, Self::Error> {
-        match value {
-            201 => Ok(Self::GenericError),
-            202 => Ok(Self::ServerError),
-            203 => Ok(Self::ProtocolError),
-            204 => Ok(Self::MethodUnknown),
-            _ => Err(()),
-        }
+        i64::try_from(value).map(ErrorCode::from).map_err(|_| ())
+    }
+}
+
+impl From<ErrorCode> for i64 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.description())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.code())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ErrorCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        i64::deserialize(deserializer).map(ErrorCode::from)
     }
 }
 
@@ -199,4 +271,45 @@ mod tests {
             ErrorMessage::new("123", ErrorCode::GenericError, "error message".to_string())
         );
     }
+
+    #[test]
+    fn test_unknown_error_code() {
+        let bencoded = BencodeValue::Dict(vec![
+            ("t".into(), BencodeValue::ByteString("aa".into())),
+            ("y".into(), BencodeValue::ByteString("e".into())),
+            (
+                "e".into(),
+                BencodeValue::List(vec![
+                    BencodeValue::Integer(301),
+                    BencodeValue::ByteString("announce failed".into()),
+                ]),
+            ),
+        ]);
+        let error = ErrorMessage::try_from_bencoded(&bencoded).unwrap();
+        assert_eq!(error.code, ErrorCode::Unknown(301));
+        assert_eq!(error.code.to_string(), "301 Unknown Error");
+        // Kept as received when encoded again.
+        assert_eq!(error.to_bencoded(), bencoded);
+
+        assert_eq!(ErrorCode::MethodUnknown.to_string(), "204 Method Unknown");
+        assert_eq!(i64::from(ErrorCode::from(202)), 202);
+        assert_eq!(ErrorCode::try_from(i128::MAX), Err(()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_error_code() {
+        use serde::{Deserialize, de::IntoDeserializer, de::value::Error};
+
+        let deserializer = IntoDeserializer::<Error>::into_deserializer(203i64);
+        assert_eq!(
+            ErrorCode::deserialize(deserializer),
+            Ok(ErrorCode::ProtocolError)
+        );
+        let deserializer = IntoDeserializer::<Error>::into_deserializer(301i64);
+        assert_eq!(
+            ErrorCode::deserialize(deserializer),
+            Ok(ErrorCode::Unknown(301))
+        );
+    }
 }
@@ -0,0 +1,225 @@
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+};
+
+use crate::bencode::{BencodeDict, BencodeValue};
+
+/// A hook on the messages a node encodes and parses, e.g. to sign the replies and check the
+/// signature of the replies received in a private overlay using the KRPC framing.
+///
+/// Both hooks see the whole top-level dictionary of the message (`t`, `y`, the body and the
+/// other fields), and do nothing by default. The extensions of a node are kept in
+/// [`MessageExtensions`].
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::{
+///     bencode::{self, BencodeDict, BencodeValue},
+///     krpc::{MessageExtension, MessageExtensions},
+/// };
+///
+/// /// Adds a `sig` field to the replies, a checksum standing for a real signature.
+/// struct Checksum;
+///
+/// fn checksum(message: &BencodeDict) -> i128 {
+///     let unsigned = message.iter().filter(|(key, _)| key.as_ref() != b"sig").cloned();
+///     let encoded = bencode::encode(&BencodeValue::Dict(unsigned.collect()));
+///     encoded.iter().map(|&byte| i128::from(byte)).sum()
+/// }
+///
+/// fn is_reply(message: &BencodeDict) -> bool {
+///     message.contains(&("y".into(), BencodeValue::ByteString("r".into())))
+/// }
+///
+/// impl MessageExtension for Checksum {
+///     fn on_encode(&self, message: &mut BencodeDict) {
+///         if is_reply(message) {
+///             let sig = checksum(message);
+///             message.push(("sig".into(), BencodeValue::Integer(sig)));
+///         }
+///     }
+///
+///     fn on_parse(&self, message: &BencodeDict) -> Result<(), &'static str> {
+///         let sig = message.iter().find(|(key, _)| key.as_ref() == b"sig");
+///         match sig {
+///             _ if !is_reply(message) => Ok(()),
+///             Some((_, BencodeValue::Integer(sig))) if *sig == checksum(message) => Ok(()),
+///             Some(_) => Err("Invalid 'sig' field"),
+///             None => Err("Missing 'sig' field"),
+///         }
+///     }
+/// }
+///
+/// let extensions = MessageExtensions::new().with(Checksum);
+/// let mut reply = BencodeValue::from_dict(vec![
+///     ("t", BencodeValue::from_string("aa".into())),
+///     ("y", BencodeValue::from_string("r".into())),
+/// ]);
+/// extensions.encode(&mut reply);
+/// assert_eq!(extensions.parse(&reply), Ok(()));
+/// // Tampered with.
+/// if let BencodeValue::Dict(dict) = &mut reply
+///     && let Some((_, t)) = dict.iter_mut().find(|(key, _)| key.as_ref() == b"t")
+/// {
+///     *t = BencodeValue::from_string("bb".into());
+/// }
+/// assert_eq!(extensions.parse(&reply), Err("Invalid 'sig' field"));
+/// ```
+pub trait MessageExtension: Send + Sync {
+    /// Called on every message about to be encoded, once its other fields are set: the
+    /// extension may add or change fields. The keys are sorted afterwards.
+    fn on_encode(&self, _message: &mut BencodeDict) {}
+
+    /// Called on every message received, before it is parsed: an error rejects the message
+    /// as malformed.
+    fn on_parse(&self, _message: &BencodeDict) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// The [`MessageExtension`]s of a node, invoked in the order they were added.
+///
+/// Two lists are equal when they hold the same extensions (the same allocations), so that
+/// the configurations holding them can still be compared.
+#[derive(Clone, Default)]
+pub struct MessageExtensions {
+    extensions: Vec<Arc<dyn MessageExtension>>,
+}
+
+impl MessageExtensions {
+    /// Create an empty list.
+    pub fn new() -> Self {
+        MessageExtensions::default()
+    }
+
+    /// Add `extension`, invoked after the ones already added.
+    pub fn with<E: MessageExtension + 'static>(mut self, extension: E) -> Self {
+        self.push(Arc::new(extension));
+        self
+    }
+
+    /// Add an extension, invoked after the ones already added.
+    pub fn push(&mut self, extension: Arc<dyn MessageExtension>) {
+        self.extensions.push(extension);
+    }
+
+    /// Get the number of extensions.
+    pub fn len(&self) -> usize {
+        self.extensions.len()
+    }
+
+    /// Check if there is no extension.
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    /// Run the [`MessageExtension::on_encode`] hooks on `message`, then sort its keys. A
+    /// `message` that is not a dictionary is left as-is.
+    pub fn encode(&self, message: &mut BencodeValue) {
+        if self.is_empty() {
+            return;
+        }
+        let BencodeValue::Dict(dict) = message else {
+            return;
+        };
+        for extension in &self.extensions {
+            extension.on_encode(dict);
+        }
+        message.sort_keys();
+    }
+
+    /// Run the [`MessageExtension::on_parse`] hooks on `message`, up to the first error. A
+    /// `message` that is not a dictionary is left to the parser to reject.
+    pub fn parse(&self, message: &BencodeValue) -> Result<(), &'static str> {
+        let BencodeValue::Dict(dict) = message else {
+            return Ok(());
+        };
+        self.extensions
+            .iter()
+            .try_for_each(|extension| extension.on_parse(dict))
+    }
+}
+
+impl Debug for MessageExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MessageExtensions({})", self.extensions.len())
+    }
+}
+
+impl PartialEq for MessageExtensions {
+    fn eq(&self, other: &Self) -> bool {
+        self.extensions.len() == other.extensions.len()
+            && self
+                .extensions
+                .iter()
+                .zip(&other.extensions)
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+impl Eq for MessageExtensions {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> BencodeValue {
+        BencodeValue::ByteString(value.into())
+    }
+
+    /// Adds its tag to the `tags` field of the messages, and rejects the messages without it.
+    struct Tag(&'static str);
+
+    impl MessageExtension for Tag {
+        fn on_encode(&self, message: &mut BencodeDict) {
+            match message.iter_mut().find(|(key, _)| key.as_ref() == b"tags") {
+                Some((_, BencodeValue::List(tags))) => tags.push(string(self.0)),
+                _ => message.push(("tags".into(), BencodeValue::from_list(vec![string(self.0)]))),
+            }
+        }
+
+        fn on_parse(&self, message: &BencodeDict) -> Result<(), &'static str> {
+            match message.iter().find(|(key, _)| key.as_ref() == b"tags") {
+                Some((_, BencodeValue::List(tags))) if tags.contains(&string(self.0)) => Ok(()),
+                _ => Err("Missing tag"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_extensions() {
+        let empty = MessageExtensions::new();
+        let mut message = BencodeValue::from_dict(vec![("y", string("r"))]);
+        empty.encode(&mut message);
+        assert_eq!(empty.parse(&message), Ok(()));
+
+        let extensions = MessageExtensions::new().with(Tag("a")).with(Tag("b"));
+        assert_eq!(extensions.parse(&message), Err("Missing tag"));
+        extensions.encode(&mut message);
+        // Invoked in order, and the keys sorted.
+        assert_eq!(
+            message,
+            BencodeValue::from_dict(vec![
+                (
+                    "tags",
+                    BencodeValue::from_list(vec![string("a"), string("b")])
+                ),
+                ("y", string("r")),
+            ])
+        );
+        assert_eq!(extensions.parse(&message), Ok(()));
+        let other = MessageExtensions::new().with(Tag("c"));
+        assert_eq!(other.parse(&message), Err("Missing tag"));
+        assert_eq!(extensions.parse(&BencodeValue::Integer(1)), Ok(()));
+
+        // Equal when they share the same extensions.
+        assert_eq!(extensions.clone(), extensions);
+        assert_ne!(
+            MessageExtensions::new().with(Tag("a")).with(Tag("b")),
+            extensions
+        );
+        assert_eq!(format!("{:?}", extensions), "MessageExtensions(2)");
+    }
+}
@@ -0,0 +1,194 @@
+use crate::bencode::{BencodeVisitor, Visit, parse_with};
+
+use super::MessageKind;
+
+/// The fields of a KRPC message needed to dispatch it, see [`peek_header`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeader<'a> {
+    /// Transaction id (`t`).
+    pub transaction_id: &'a [u8],
+    /// Kind of the message (`y`).
+    pub kind: MessageKind,
+    /// Method of a query (`q`), `None` for a reply or an error.
+    pub query: Option<&'a [u8]>,
+}
+
+/// A field of the header, while the message is visited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field<'a> {
+    Missing,
+    String(&'a [u8]),
+    /// Not a string.
+    Invalid,
+}
+
+impl<'a> Field<'a> {
+    fn get(self, missing: &'static str, invalid: &'static str) -> Result<&'a [u8], &'static str> {
+        match self {
+            Field::String(value) => Ok(value),
+            Field::Missing => Err(missing),
+            Field::Invalid => Err(invalid),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HeaderVisitor<'a> {
+    depth: usize,
+    // Index in `fields` of the value that follows, if its key is one of the header.
+    current: Option<usize>,
+    // `t`, `y` and `q`.
+    fields: [Field<'a>; 3],
+}
+
+impl<'a> HeaderVisitor<'a> {
+    /// Record the value of the current key, if it is a field of the header.
+    fn value(&mut self, field: Field<'a>) -> Visit {
+        if self.depth != 1 {
+            return Visit::Continue;
+        }
+        if let Some(index) = self.current.take() {
+            self.fields[index] = field;
+        }
+        let [transaction_id, kind, query] = self.fields;
+        let complete = transaction_id != Field::Missing
+            && kind != Field::Missing
+            && (kind != Field::String(b"q") || query != Field::Missing);
+        if complete {
+            Visit::Stop
+        } else {
+            Visit::Continue
+        }
+    }
+}
+
+impl<'a> BencodeVisitor<'a> for HeaderVisitor<'a> {
+    fn integer(&mut self, _value: i128) -> Visit {
+        self.value(Field::Invalid)
+    }
+
+    fn string(&mut self, value: &'a [u8]) -> Visit {
+        self.value(Field::String(value))
+    }
+
+    fn list_start(&mut self) -> Visit {
+        let visit = self.value(Field::Invalid);
+        self.depth += 1;
+        visit
+    }
+
+    fn list_end(&mut self) -> Visit {
+        self.depth -= 1;
+        Visit::Continue
+    }
+
+    fn dict_start(&mut self) -> Visit {
+        let visit = self.value(Field::Invalid);
+        self.depth += 1;
+        visit
+    }
+
+    fn dict_key(&mut self, key: &'a [u8]) -> Visit {
+        if self.depth == 1 {
+            self.current = match key {
+                b"t" => Some(0),
+                b"y" => Some(1),
+                b"q" => Some(2),
+                _ => None,
+            };
+        }
+        Visit::Continue
+    }
+
+    fn dict_end(&mut self) -> Visit {
+        self.depth -= 1;
+        Visit::Continue
+    }
+}
+
+/// Read the transaction id, the kind and the query method of a KRPC message without decoding
+/// it, to decide whether it is worth decoding (e.g. a reply matching none of our queries is
+/// not).
+///
+/// The message is only read up to the last of these fields, on top of the [`BencodeVisitor`]
+/// API: the rest of it is not checked, a message with a valid header may still fail to decode.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::krpc::{MessageKind, peek_header};
+///
+/// let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+/// let header = peek_header(ping).unwrap();
+/// assert_eq!(header.transaction_id, b"aa");
+/// assert_eq!(header.kind, MessageKind::Query);
+/// assert_eq!(header.query, Some(&b"ping"[..]));
+/// assert_eq!(peek_header(b"d1:t2:aae"), Err("Missing 'y' field"));
+/// ```
+pub fn peek_header(datagram: &[u8]) -> Result<MessageHeader<'_>, &'static str> {
+    if datagram.first() != Some(&b'd') {
+        return Err("Message not a dictionary");
+    }
+    let mut visitor = HeaderVisitor {
+        depth: 0,
+        current: None,
+        fields: [Field::Missing; 3],
+    };
+    parse_with(datagram, &mut visitor).map_err(|e| e.message())?;
+    let [transaction_id, kind, query] = visitor.fields;
+    let transaction_id = transaction_id.get("Missing 't' field", "Invalid 't' field")?;
+    let (kind, query) = match kind.get("Missing 'y' field", "Invalid 'y' field")? {
+        b"q" => (
+            MessageKind::Query,
+            Some(query.get("Missing 'q' field", "Invalid 'q' field")?),
+        ),
+        b"r" => (MessageKind::Response, None),
+        b"e" => (MessageKind::Error, None),
+        _ => return Err("Invalid message type"),
+    };
+    Ok(MessageHeader {
+        transaction_id,
+        kind,
+        query,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peek_header() {
+        let reply = b"d1:rd2:id20:abcdefghij01234567891:t1:x1:y1:qe1:t2:aa1:y1:re";
+        assert_eq!(
+            peek_header(reply),
+            Ok(MessageHeader {
+                transaction_id: b"aa",
+                kind: MessageKind::Response,
+                query: None,
+            })
+        );
+        let error = b"d1:eli201e4:oopse1:t1:b1:y1:ee";
+        assert_eq!(
+            peek_header(error).map(|header| header.kind),
+            Ok(MessageKind::Error)
+        );
+        // The message is not read past the header.
+        assert_eq!(
+            peek_header(b"d1:q4:ping1:t2:aa1:y1:q1:z").map(|header| header.query),
+            Ok(Some(&b"ping"[..]))
+        );
+
+        for (datagram, error) in [
+            (&b"le"[..], "Message not a dictionary"),
+            (b"d1:y1:re", "Missing 't' field"),
+            (b"d1:ti1e1:y1:re", "Invalid 't' field"),
+            (b"d1:t2:aa1:yli1eee", "Invalid 'y' field"),
+            (b"d1:t2:aa1:y1:xe", "Invalid message type"),
+            (b"d1:t2:aa1:y1:qe", "Missing 'q' field"),
+            (b"d1:t2:aa1:y", "Invalid value"),
+        ] {
+            assert_eq!(peek_header(datagram), Err(error), "{:?}", datagram);
+        }
+    }
+}
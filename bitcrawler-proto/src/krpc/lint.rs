@@ -0,0 +1,252 @@
+use crate::{
+    bencode::{BencodeValue, decode_prefix},
+    consts::{
+        COMPACT_NODE_V4_LEN, COMPACT_NODE_V6_LEN, COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN, ID_LEN,
+    },
+};
+
+/// Lengths of a compact IPv4 and IPv6 peer info in `values`.
+const COMPACT_PEER_LENS: [usize; 2] = [COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN];
+
+/// Check that an encoded KRPC message follows the protocol, see [`lint_message`].
+pub fn lint_datagram(datagram: &[u8]) -> Result<(), &'static str> {
+    let (read, message) = decode_prefix(&datagram).map_err(|e| e.message())?;
+    if read != datagram.len() {
+        return Err("trailing bytes after the message");
+    }
+    lint_message(&message)
+}
+
+/// Check that a KRPC message follows the protocol (BEP 5), meant for the messages we send so
+/// that an encoder regression is caught before other nodes see it.
+///
+/// The checks are the ones every implementation relies on: the required keys are present
+/// with their types, the transaction id is not empty, the ids are 20 bytes long, and the
+/// compact node and peer infos have a valid length. Unknown keys are allowed.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::krpc::lint_datagram;
+///
+/// let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+/// assert_eq!(lint_datagram(ping), Ok(()));
+/// let no_tid = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t0:1:y1:qe";
+/// assert_eq!(lint_datagram(no_tid), Err("empty transaction id"));
+/// ```
+pub fn lint_message(message: &BencodeValue) -> Result<(), &'static str> {
+    if !matches!(message, BencodeValue::Dict(_)) {
+        return Err("message not a dictionary");
+    }
+    let transaction_id = bytes(message, b"t").ok_or("missing transaction id")?;
+    if transaction_id.is_empty() {
+        return Err("empty transaction id");
+    }
+    if message.get(b"v").is_some() && bytes(message, b"v").is_none() {
+        return Err("version not a string");
+    }
+    match bytes(message, b"y").ok_or("missing message type")? {
+        b"q" => lint_query(message),
+        b"r" => lint_response(message),
+        b"e" => lint_error(message),
+        _ => Err("unknown message type"),
+    }
+}
+
+fn lint_query(message: &BencodeValue) -> Result<(), &'static str> {
+    let method = bytes(message, b"q").ok_or("missing query method")?;
+    let arguments = message.get(b"a").ok_or("missing query arguments")?;
+    if !matches!(arguments, BencodeValue::Dict(_)) {
+        return Err("query arguments not a dictionary");
+    }
+    lint_id(
+        arguments,
+        b"id",
+        "missing node id",
+        "node id not 20 bytes long",
+    )?;
+    match method {
+        b"find_node" => lint_id(
+            arguments,
+            b"target",
+            "missing target",
+            "target not 20 bytes long",
+        ),
+        b"get_peers" => lint_id(
+            arguments,
+            b"info_hash",
+            "missing info hash",
+            "info hash not 20 bytes long",
+        ),
+        b"announce_peer" => {
+            lint_id(
+                arguments,
+                b"info_hash",
+                "missing info hash",
+                "info hash not 20 bytes long",
+            )?;
+            bytes(arguments, b"token").ok_or("missing announce token")?;
+            let implied_port = arguments
+                .get(b"implied_port")
+                .and_then(BencodeValue::as_integer)
+                .is_some_and(|implied| implied != 0);
+            match arguments.get(b"port") {
+                Some(port) => port.as_u16_port().map(drop),
+                None if implied_port => Ok(()),
+                None => Err("missing announce port"),
+            }
+        }
+        b"" => Err("empty query method"),
+        _ => Ok(()),
+    }
+}
+
+fn lint_response(message: &BencodeValue) -> Result<(), &'static str> {
+    let values = message.get(b"r").ok_or("missing response values")?;
+    if !matches!(values, BencodeValue::Dict(_)) {
+        return Err("response values not a dictionary");
+    }
+    lint_id(
+        values,
+        b"id",
+        "missing node id",
+        "node id not 20 bytes long",
+    )?;
+    if let Some(nodes) = values.get(b"nodes") {
+        let nodes = bytes_of(nodes).ok_or("nodes not a string")?;
+        if nodes.len() % COMPACT_NODE_V4_LEN != 0 {
+            return Err("nodes length not a multiple of 26");
+        }
+    }
+    if let Some(nodes) = values.get(b"nodes6") {
+        let nodes = bytes_of(nodes).ok_or("nodes6 not a string")?;
+        if nodes.len() % COMPACT_NODE_V6_LEN != 0 {
+            return Err("nodes6 length not a multiple of 38");
+        }
+    }
+    if let Some(peers) = values.get(b"values") {
+        let BencodeValue::List(peers) = peers else {
+            return Err("values not a list");
+        };
+        for peer in peers {
+            let peer = bytes_of(peer).ok_or("peer not a string")?;
+            if !COMPACT_PEER_LENS.contains(&peer.len()) {
+                return Err("peer not 6 or 18 bytes long");
+            }
+        }
+    }
+    if values.get(b"token").is_some() && bytes(values, b"token").is_none() {
+        return Err("token not a string");
+    }
+    Ok(())
+}
+
+fn lint_error(message: &BencodeValue) -> Result<(), &'static str> {
+    match message.get(b"e") {
+        Some(BencodeValue::List(error)) => match error.as_slice() {
+            [BencodeValue::Integer(_), BencodeValue::ByteString(_)] => Ok(()),
+            _ => Err("error not a code and a message"),
+        },
+        Some(_) => Err("error not a list"),
+        None => Err("missing error"),
+    }
+}
+
+/// Check the 20-byte id under `key`, with the errors to return if it is missing or invalid.
+fn lint_id(
+    dict: &BencodeValue,
+    key: &[u8],
+    missing: &'static str,
+    invalid: &'static str,
+) -> Result<(), &'static str> {
+    match bytes(dict, key) {
+        Some(id) if id.len() == ID_LEN => Ok(()),
+        Some(_) => Err(invalid),
+        None if dict.get(key).is_some() => Err(invalid),
+        None => Err(missing),
+    }
+}
+
+fn bytes<'a>(dict: &'a BencodeValue, key: &[u8]) -> Option<&'a [u8]> {
+    dict.get(key).and_then(bytes_of)
+}
+
+fn bytes_of(value: &BencodeValue) -> Option<&[u8]> {
+    match value {
+        BencodeValue::ByteString(string) => Some(string.as_ref()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use super::*;
+    use crate::{
+        bencode::encode,
+        kademlia::Id160,
+        krpc::{ErrorCode, ErrorMessage, Query, Response, node_info::BittorrentNodeInfoV4},
+    };
+
+    type TestResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+
+    #[test]
+    fn test_lint_built_messages() {
+        let id = Id160([1; 20]);
+        let node = BittorrentNodeInfoV4 {
+            node_id: id,
+            ip: [192, 0, 2, 1],
+            port: 6881,
+        };
+        let peer = SocketAddrV4::new([192, 0, 2, 2].into(), 6881);
+        let messages = [
+            Query::new_ping("aa", id).to_bencoded(),
+            Query::new_find_node("aa", id, id).to_bencoded(),
+            Query::new_get_peers("aa", id, id).to_bencoded(),
+            TestResponse::new_find_node("aa", id, vec![node.clone()]).to_bencoded(),
+            TestResponse::new_get_peers("aa", id, Some("tk".into()), vec![node], vec![peer])
+                .to_bencoded(),
+            ErrorMessage::new("aa", ErrorCode::ServerError, "busy".into()).to_bencoded(),
+        ];
+        for message in &messages {
+            assert_eq!(lint_message(message), Ok(()), "{:?}", message);
+            assert_eq!(lint_datagram(&encode(message)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_lint_errors() {
+        for (datagram, error) in [
+            (&b"le"[..], "message not a dictionary"),
+            (b"d1:y1:qe", "missing transaction id"),
+            (b"d1:t2:aa1:y1:xe", "unknown message type"),
+            (
+                b"d1:ad2:id2:abe1:q4:ping1:t2:aa1:y1:qe",
+                "node id not 20 bytes long",
+            ),
+            (
+                b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:aa1:y1:qe",
+                "missing target",
+            ),
+            (
+                b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re",
+                "nodes length not a multiple of 26",
+            ),
+            (
+                b"d1:rd2:id20:abcdefghij01234567896:valuesl4:abcdee1:t2:aa1:y1:re",
+                "peer not 6 or 18 bytes long",
+            ),
+            (
+                b"d1:eli201ee1:t2:aa1:y1:ee",
+                "error not a code and a message",
+            ),
+            (
+                b"d1:eli201e0:e1:t2:aa1:y1:eei1e",
+                "trailing bytes after the message",
+            ),
+        ] {
+            assert_eq!(lint_datagram(datagram), Err(error), "{:?}", datagram);
+        }
+    }
+}
@@ -1,8 +1,19 @@
+mod compat;
+#[cfg(test)]
+mod conformance;
+mod envelope;
 mod error;
+mod extension;
+mod header;
+mod lint;
 pub mod node_info;
 pub mod peer_info;
+mod port;
 pub mod query;
+mod record;
 pub mod response;
+mod samples;
+mod template;
 
 use std::collections::HashMap;
 
@@ -10,9 +21,18 @@ use crate::{
     bencode::{BencodeDict, BencodeString, BencodeValue},
     kademlia::NodeId,
 };
+pub use compat::*;
+pub use envelope::*;
 pub use error::*;
-pub use query::{Query, QueryType};
-pub use response::{Response, ResponseType};
+pub use extension::*;
+pub use header::*;
+pub use lint::*;
+pub use port::*;
+pub use query::{MessageOptions, Query, QueryType};
+pub use record::*;
+pub use response::{GetPeersShape, Response, ResponseType};
+pub use samples::*;
+pub use template::*;
 
 /// Represents a KRPC message that can be either a query, a response, or an error.
 ///
@@ -101,7 +121,7 @@ pub trait TryFromArguments {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::{node_info::CompactNodeInfo, peer_info::CompactPeerInfo, *};
 
     use crate::kademlia::Xorable;
@@ -186,9 +206,9 @@ mod tests {
         }
     }
 
-    impl Into<Vec<u8>> for MockNodeId {
-        fn into(self) -> Vec<u8> {
-            self.0.to_be_bytes().to_vec()
+    impl From<MockNodeId> for Vec<u8> {
+        fn from(val: MockNodeId) -> Self {
+            val.0.to_be_bytes().to_vec()
         }
     }
 
@@ -203,7 +223,11 @@ mod tests {
             while (x >> count) > 1 {
                 count += 1;
             }
-            return count;
+            count
+        }
+
+        fn distance(&self, other: &Self) -> Self {
+            MockNodeId(self.0 ^ other.0)
         }
     }
 
@@ -216,13 +240,7 @@ mod tests {
             }
             let ip = [data[0], data[1], data[2], data[3]];
             let port = u16::from_be_bytes([data[4], data[5]]);
-            Ok((
-                6,
-                MockAddress {
-                    ip,
-                    port,
-                },
-            ))
+            Ok((6, MockAddress { ip, port }))
         }
 
         fn write_compact_peer_info(&self) -> Vec<u8> {
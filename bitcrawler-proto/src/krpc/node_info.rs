@@ -1,4 +1,11 @@
-use crate::kademlia::NodeId;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use crate::{
+    consts::{COMPACT_NODE_V4_LEN, COMPACT_NODE_V6_LEN, ID_LEN},
+    kademlia::{Id160, NodeId},
+};
+
+use super::Port;
 
 /// Node Info represents a discovered node (id, address, port) in the network.
 pub trait NodeInfo: PartialEq + Eq + Clone {
@@ -69,3 +76,148 @@ pub struct BittorrentNodeInfoV6<N: NodeId> {
     pub ip: [u8; 16],
     pub port: u16,
 }
+
+impl<N: NodeId> BittorrentNodeInfoV4<N> {
+    /// Get the port of the node, `None` if it is 0: the node cannot be contacted.
+    pub fn get_port(&self) -> Option<Port> {
+        Port::new(self.port)
+    }
+}
+
+impl<N: NodeId> BittorrentNodeInfoV6<N> {
+    /// Get the port of the node, `None` if it is 0: the node cannot be contacted.
+    pub fn get_port(&self) -> Option<Port> {
+        Port::new(self.port)
+    }
+}
+
+impl NodeInfo for BittorrentNodeInfoV4<Id160> {
+    type NodeId = Id160;
+    type Address = SocketAddrV4;
+
+    fn get_node_id(&self) -> &Self::NodeId {
+        &self.node_id
+    }
+
+    fn to_address(&self) -> Self::Address {
+        SocketAddrV4::new(Ipv4Addr::from(self.ip), self.port)
+    }
+
+    fn new_with_address(node_id: Self::NodeId, address: Self::Address) -> Self {
+        BittorrentNodeInfoV4 {
+            node_id,
+            ip: address.ip().octets(),
+            port: address.port(),
+        }
+    }
+}
+
+impl CompactNodeInfo for BittorrentNodeInfoV4<Id160> {
+    type Error = &'static str;
+
+    /// Reads a 26-byte compact node info (`<node_id:20><ip:4><port:2>`).
+    fn try_read_compact_node_info(data: &[u8]) -> Result<(usize, Self), Self::Error> {
+        if data.len() < COMPACT_NODE_V4_LEN {
+            return Err("Invalid length for compact node info");
+        }
+        let node_id = Id160::try_from(&data[0..ID_LEN])?;
+        let ip = [data[20], data[21], data[22], data[23]];
+        let port = u16::from_be_bytes([data[24], data[25]]);
+        Ok((
+            COMPACT_NODE_V4_LEN,
+            BittorrentNodeInfoV4 { node_id, ip, port },
+        ))
+    }
+
+    fn write_compact_node_info(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(COMPACT_NODE_V4_LEN);
+        data.extend_from_slice(&self.node_id.0);
+        data.extend_from_slice(&self.ip);
+        data.extend_from_slice(&self.port.to_be_bytes());
+        data
+    }
+}
+
+impl NodeInfo for BittorrentNodeInfoV6<Id160> {
+    type NodeId = Id160;
+    type Address = SocketAddrV6;
+
+    fn get_node_id(&self) -> &Self::NodeId {
+        &self.node_id
+    }
+
+    fn to_address(&self) -> Self::Address {
+        SocketAddrV6::new(Ipv6Addr::from(self.ip), self.port, 0, 0)
+    }
+
+    fn new_with_address(node_id: Self::NodeId, address: Self::Address) -> Self {
+        BittorrentNodeInfoV6 {
+            node_id,
+            ip: address.ip().octets(),
+            port: address.port(),
+        }
+    }
+}
+
+impl CompactNodeInfo for BittorrentNodeInfoV6<Id160> {
+    type Error = &'static str;
+
+    /// Reads a 38-byte compact node info (`<node_id:20><ip:16><port:2>`).
+    fn try_read_compact_node_info(data: &[u8]) -> Result<(usize, Self), Self::Error> {
+        if data.len() < COMPACT_NODE_V6_LEN {
+            return Err("Invalid length for compact node info");
+        }
+        let node_id = Id160::try_from(&data[0..ID_LEN])?;
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&data[20..36]);
+        let port = u16::from_be_bytes([data[36], data[37]]);
+        Ok((
+            COMPACT_NODE_V6_LEN,
+            BittorrentNodeInfoV6 { node_id, ip, port },
+        ))
+    }
+
+    fn write_compact_node_info(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(COMPACT_NODE_V6_LEN);
+        data.extend_from_slice(&self.node_id.0);
+        data.extend_from_slice(&self.ip);
+        data.extend_from_slice(&self.port.to_be_bytes());
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_node_info_v4_roundtrip() {
+        let node = BittorrentNodeInfoV4 {
+            node_id: Id160([7; 20]),
+            ip: [192, 0, 2, 1],
+            port: 6881,
+        };
+        let compact = node.write_compact_node_info();
+        assert_eq!(compact.len(), 26);
+        assert_eq!(
+            BittorrentNodeInfoV4::<Id160>::try_read_compact_node_info(&compact),
+            Ok((26, node.clone()))
+        );
+        assert_eq!(node.to_address(), "192.0.2.1:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn test_compact_node_info_v6_roundtrip() {
+        let node = BittorrentNodeInfoV6::new_with_address(
+            Id160([7; 20]),
+            "[2001:db8::1]:6881".parse().unwrap(),
+        );
+        let compact = node.write_compact_node_info();
+        assert_eq!(compact.len(), 38);
+        assert_eq!(
+            BittorrentNodeInfoV6::<Id160>::try_read_compact_node_info(&compact),
+            Ok((38, node))
+        );
+        assert!(BittorrentNodeInfoV6::<Id160>::try_read_compact_node_info(&compact[..26]).is_err());
+    }
+}
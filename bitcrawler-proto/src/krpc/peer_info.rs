@@ -1,4 +1,8 @@
-pub trait CompactPeerInfo : PartialEq + Eq + Clone {
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use crate::consts::{COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN};
+
+pub trait CompactPeerInfo: PartialEq + Eq + Clone {
     /// The type of the peer id.
     type Error;
 
@@ -19,9 +23,55 @@ pub trait CompactPeerInfo : PartialEq + Eq + Clone {
     fn try_read_compact_peer_info(data: &[u8]) -> Result<(usize, Self), Self::Error>;
 
     /// Produces a compact peer info from the given peer info.
-    /// 
+    ///
     /// # Returns
-    /// 
+    ///
     /// A string (CoW) containing the compact peer info.
     fn write_compact_peer_info(&self) -> Vec<u8>;
-}
\ No newline at end of file
+}
+
+impl CompactPeerInfo for SocketAddrV4 {
+    type Error = &'static str;
+
+    /// Reads a 6-byte compact peer info (`<ip:4><port:2>`).
+    fn try_read_compact_peer_info(data: &[u8]) -> Result<(usize, Self), Self::Error> {
+        if data.len() < COMPACT_PEER_V4_LEN {
+            return Err("Invalid length for compact peer info");
+        }
+        let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+        let port = u16::from_be_bytes([data[4], data[5]]);
+        Ok((COMPACT_PEER_V4_LEN, SocketAddrV4::new(ip, port)))
+    }
+
+    fn write_compact_peer_info(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(COMPACT_PEER_V4_LEN);
+        data.extend_from_slice(&self.ip().octets());
+        data.extend_from_slice(&self.port().to_be_bytes());
+        data
+    }
+}
+
+impl CompactPeerInfo for SocketAddrV6 {
+    type Error = &'static str;
+
+    /// Reads an 18-byte compact peer info (`<ip:16><port:2>`).
+    fn try_read_compact_peer_info(data: &[u8]) -> Result<(usize, Self), Self::Error> {
+        if data.len() < COMPACT_PEER_V6_LEN {
+            return Err("Invalid length for compact peer info");
+        }
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&data[0..16]);
+        let port = u16::from_be_bytes([data[16], data[17]]);
+        Ok((
+            COMPACT_PEER_V6_LEN,
+            SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0),
+        ))
+    }
+
+    fn write_compact_peer_info(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(COMPACT_PEER_V6_LEN);
+        data.extend_from_slice(&self.ip().octets());
+        data.extend_from_slice(&self.port().to_be_bytes());
+        data
+    }
+}
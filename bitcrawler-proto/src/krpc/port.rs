@@ -0,0 +1,101 @@
+use std::{
+    fmt::{self, Display},
+    num::NonZeroU16,
+};
+
+/// Ports below this one are privileged: only the system binds them on most platforms.
+pub const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// The port of a node or a peer, never 0: no one can be reached on port 0.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::krpc::Port;
+///
+/// assert_eq!(Port::new(6881).map(Port::get), Some(6881));
+/// assert_eq!(Port::new(0), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Port(NonZeroU16);
+
+impl Port {
+    /// Get the port `port`, `None` if it is 0.
+    pub const fn new(port: u16) -> Option<Port> {
+        match NonZeroU16::new(port) {
+            Some(port) => Some(Port(port)),
+            None => None,
+        }
+    }
+
+    /// Get the port number.
+    pub const fn get(self) -> u16 {
+        self.0.get()
+    }
+
+    /// Check if the port is privileged, see [`FIRST_UNPRIVILEGED_PORT`].
+    pub const fn is_privileged(self) -> bool {
+        self.get() < FIRST_UNPRIVILEGED_PORT
+    }
+}
+
+impl TryFrom<u16> for Port {
+    type Error = &'static str;
+
+    fn try_from(port: u16) -> Result<Self, Self::Error> {
+        Port::new(port).ok_or("Port 0")
+    }
+}
+
+impl From<Port> for u16 {
+    fn from(port: Port) -> Self {
+        port.get()
+    }
+}
+
+impl Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The ports accepted for the peers, e.g. those returned by `get_peers` or announced with
+/// `announce_peer`, on top of the non-zero check of [`Port`].
+///
+/// BitTorrent clients do not listen on privileged ports, a peer announced on one is more likely
+/// garbage or an attempt to direct traffic at a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PortPolicy {
+    /// Reject the privileged ports, see [`Port::is_privileged`].
+    pub reject_privileged: bool,
+}
+
+impl PortPolicy {
+    /// Get the port `port` if the policy accepts it.
+    pub fn check(&self, port: u16) -> Option<Port> {
+        Port::new(port).filter(|port| !(self.reject_privileged && port.is_privileged()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_policy() {
+        assert_eq!(Port::try_from(0), Err("Port 0"));
+        let port = Port::try_from(80).unwrap();
+        assert!(port.is_privileged());
+        assert_eq!(port.to_string(), "80");
+        assert!(!Port::new(FIRST_UNPRIVILEGED_PORT).unwrap().is_privileged());
+
+        let lenient = PortPolicy::default();
+        assert_eq!(lenient.check(0), None);
+        assert_eq!(lenient.check(80), Some(port));
+        let strict = PortPolicy {
+            reject_privileged: true,
+        };
+        assert_eq!(strict.check(80), None);
+        assert_eq!(strict.check(6881).map(u16::from), Some(6881));
+    }
+}
@@ -5,7 +5,7 @@ use crate::{
     kademlia::NodeId,
 };
 
-use super::{ToArguments, TryFromArguments, TryFromArgumentsError};
+use super::{Port, ToArguments, TryFromArguments, TryFromArgumentsError};
 
 /// Query type associated for the `ping` query.
 pub const QUERY_TYPE_PING: &[u8] = b"ping";
@@ -15,19 +15,87 @@ pub const QUERY_TYPE_FIND_NODE: &[u8] = b"find_node";
 pub const QUERY_TYPE_GET_PEERS: &[u8] = b"get_peers";
 /// Query type associated for the `announce_peer` query.
 pub const QUERY_TYPE_ANNOUNCE_PEER: &[u8] = b"announce_peer";
+/// Query type of the `sample_infohashes` query (BEP 51), sent with [`Query::custom`].
+pub const QUERY_TYPE_SAMPLE_INFOHASHES: &[u8] = b"sample_infohashes";
+/// Query type of the `get` query of the storage of arbitrary data (BEP 44), sent with
+/// [`Query::custom`].
+pub const QUERY_TYPE_GET: &[u8] = b"get";
 
 /// Represents a query message in the KRPC protocol.
 ///
 /// More information about the KRPC protocol can be found in the [specification](https://www.bittorrent.org/beps/bep_0005.html).
+///
+/// # Examples
+///
+/// Build each query type of BEP 5 and encode it to the bytes sent on the wire:
+///
+/// ```rust
+/// use bitcrawler_proto::{
+///     bencode,
+///     kademlia::Id160,
+///     krpc::{Port, Query, query::AnnounceToken},
+/// };
+///
+/// let id = Id160(*b"abcdefghij0123456789");
+/// let info_hash = Id160(*b"mnopqrstuvwxyz123456");
+///
+/// let ping = Query::new_ping("aa", id);
+/// assert_eq!(
+///     bencode::encode(&ping.to_bencoded()),
+///     b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+/// );
+///
+/// let find_node = Query::new_find_node("aa", id, info_hash);
+/// assert_eq!(
+///     bencode::encode(&find_node.to_bencoded()),
+///     &b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e\
+///        1:q9:find_node1:t2:aa1:y1:qe"[..]
+/// );
+///
+/// let get_peers = Query::new_get_peers("aa", id, info_hash);
+/// assert_eq!(
+///     bencode::encode(&get_peers.to_bencoded()),
+///     &b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e\
+///        1:q9:get_peers1:t2:aa1:y1:qe"[..]
+/// );
+///
+/// // The token comes from the reply of the node to a previous `get_peers` query.
+/// let token = AnnounceToken::from_raw("aoeusnth");
+/// let port = Port::new(6881).unwrap();
+/// let announce = Query::new_announce_peer("aa", id, info_hash, port, token);
+/// assert_eq!(
+///     bencode::encode(&announce.to_bencoded()),
+///     &b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456\
+///        4:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe"[..]
+/// );
+/// ```
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Query<N: NodeId> {
     transaction_id: BencodeString,
     query: QueryType<N>,
+    options: MessageOptions,
+}
+
+/// Top-level fields attached to a query, besides the query itself.
+///
+/// A node usually sets them once for all its queries, see [`Query::with_options`].
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct MessageOptions {
+    /// Client version (`v`), by convention 2 letters identifying the client and 2 bytes of
+    /// version, see BEP 20 for the client letters.
+    pub version: Option<BencodeString>,
+    /// Read-only node (`ro`): other nodes should not add us to their routing tables, as we may
+    /// not answer their queries. See BEP 43.
+    pub read_only: bool,
+    /// Other top-level fields (e.g. vendor hints). Fields already written by the query are
+    /// not overwritten.
+    pub extra: BencodeDict,
 }
 
 /// Represents a query type in the KRPC protocol.
 ///
-/// Only 4 query types are supported: `ping`, `find_node`, `get_peers`, and `announce_peer`.
+/// The 4 query types of BEP 5 are supported: `ping`, `find_node`, `get_peers`, and `announce_peer`.
+/// Any other method name (e.g. a vendor extension) is kept as-is in [`QueryType::Unknown`].
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum QueryType<N: NodeId> {
     /// Represents a `ping` query.
@@ -38,6 +106,11 @@ pub enum QueryType<N: NodeId> {
     GetPeers(GetPeers<N>),
     /// Represents an `announce_peer` query.
     AnnouncePeer(AnnouncePeer<N>),
+    /// Represents a query with an unsupported method name, with its raw arguments.
+    Unknown {
+        name: BencodeString,
+        args: BencodeDict,
+    },
 }
 
 /// Represents a `ping` query in the KRPC protocol.
@@ -66,10 +139,13 @@ pub struct FindNode<N: NodeId> {
 /// The arguments required for a `get_peers` query are the `id` of the node and the `info_hash` of the torrent.
 /// The `info_hash` is the SHA-1 hash of the metadata of the torrent.
 /// The response to a `get_peers` query will contain a list of peers that are downloading the torrent.
+/// With `scrape` set (BEP 33), the response also holds Bloom filters of the seeds and peers known
+/// to the node, to estimate the size of the swarm.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct GetPeers<N: NodeId> {
     id: N,
     info_hash: N,
+    scrape: bool,
 }
 
 /// Represents an `announce_peer` query in the KRPC protocol.
@@ -77,12 +153,39 @@ pub struct GetPeers<N: NodeId> {
 /// The `announce_peer` query is used to announce that the node is downloading a specific torrent.
 /// The arguments required for an `announce_peer` query are the `id` of the node, the `info_hash` of the torrent,
 /// the `port` on which the node is downloading the torrent, and a `token` received from a previous `get_peers` query.
+/// With `implied_port`, the port of the peer is the source port of the query instead (for peers behind a NAT).
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct AnnouncePeer<N: NodeId> {
     id: N,
     info_hash: N,
-    port: u16,
+    // Only `None` with `implied_port`.
+    port: Option<Port>,
     token: BencodeString,
+    implied_port: bool,
+}
+
+/// A token to present in an `announce_peer` query.
+///
+/// A node only accepts an announce carrying the token it sent in its reply to a `get_peers` query,
+/// so the token is taken from such a reply, see
+/// [`GetPeers::announce_token`](super::response::GetPeers::announce_token). Tokens obtained another
+/// way (e.g. kept from an earlier reply) go through [`AnnounceToken::from_raw`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AnnounceToken(pub(crate) BencodeString);
+
+impl AnnounceToken {
+    /// Use bytes received as a token by other means than a parsed `get_peers` reply.
+    ///
+    /// Nothing checks that the bytes are a token the node issued: prefer
+    /// [`GetPeers::announce_token`](super::response::GetPeers::announce_token).
+    pub fn from_raw(token: impl Into<BencodeString>) -> Self {
+        AnnounceToken(token.into())
+    }
+
+    /// Get the bytes of the token.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
 }
 
 impl<N: NodeId> Query<N> {
@@ -90,48 +193,113 @@ impl<N: NodeId> Query<N> {
         Query {
             transaction_id: transaction_id.into(),
             query,
+            options: MessageOptions::default(),
         }
     }
 
+    /// Attach top-level fields to the query, see [`MessageOptions`].
+    pub fn with_options(mut self, options: MessageOptions) -> Self {
+        self.options = options;
+        self
+    }
+
     pub fn new_ping(transaction_id: impl Into<BencodeString>, id: N) -> Self {
         Query::new(transaction_id, QueryType::Ping(Ping { id }))
     }
 
-    pub fn new_find_node(
-        transaction_id: impl Into<BencodeString>,
-        id: N,
-        target: N,
-    ) -> Self {
+    pub fn new_find_node(transaction_id: impl Into<BencodeString>, id: N, target: N) -> Self {
         Query::new(transaction_id, QueryType::FindNode(FindNode { id, target }))
     }
 
     pub fn new_get_peers(transaction_id: impl Into<BencodeString>, id: N, info_hash: N) -> Self {
-        Query::new(transaction_id, QueryType::GetPeers(GetPeers { id, info_hash }))
+        Query::new(
+            transaction_id,
+            QueryType::GetPeers(GetPeers {
+                id,
+                info_hash,
+                scrape: false,
+            }),
+        )
+    }
+
+    /// Create a `get_peers` query asking for the swarm size Bloom filters of BEP 33.
+    pub fn new_scrape(transaction_id: impl Into<BencodeString>, id: N, info_hash: N) -> Self {
+        Query::new(
+            transaction_id,
+            QueryType::GetPeers(GetPeers {
+                id,
+                info_hash,
+                scrape: true,
+            }),
+        )
     }
 
     pub fn new_announce_peer(
         transaction_id: impl Into<BencodeString>,
         id: N,
         info_hash: N,
-        port: u16,
-        token: BencodeString,
+        port: Port,
+        token: AnnounceToken,
     ) -> Self {
         Query::new(
             transaction_id,
-            QueryType::AnnouncePeer(AnnouncePeer {
-                id,
-                info_hash,
-                port,
-                token,
-            }),
+            QueryType::AnnouncePeer(AnnouncePeer::new(id, info_hash, port, token)),
+        )
+    }
+
+    /// Build an `announce_peer` query asking to use its source port as the peer port
+    /// (`implied_port`), `port` is only a fallback.
+    pub fn new_announce_peer_implied_port(
+        transaction_id: impl Into<BencodeString>,
+        id: N,
+        info_hash: N,
+        port: Port,
+        token: AnnounceToken,
+    ) -> Self {
+        Query::new(
+            transaction_id,
+            QueryType::AnnouncePeer(
+                AnnouncePeer::new(id, info_hash, port, token).with_implied_port(),
+            ),
+        )
+    }
+
+    /// Build a query for an arbitrary method name, e.g. a vendor extension.
+    ///
+    /// The arguments are sent as-is. Note that a query built with one of the standard method
+    /// names is parsed back as the matching typed variant, not as [`QueryType::Unknown`].
+    pub fn custom(
+        transaction_id: impl Into<BencodeString>,
+        name: &[u8],
+        args: BencodeDict,
+    ) -> Self {
+        Query::new(
+            transaction_id,
+            QueryType::Unknown {
+                name: name.to_vec().into(),
+                args,
+            },
         )
     }
 
+    pub fn get_transaction_id(&self) -> &BencodeString {
+        &self.transaction_id
+    }
+
+    pub fn get_query(&self) -> &QueryType<N> {
+        &self.query
+    }
+
+    /// Get the top-level fields of the query. Only `v` and `ro` are read from received queries.
+    pub fn get_options(&self) -> &MessageOptions {
+        &self.options
+    }
+
     pub fn to_bencoded(&self) -> BencodeValue {
         let mut dictionary = HashMap::new();
         dictionary.insert(
             "t".into(),
-            BencodeValue::ByteString(self.transaction_id.clone().into()),
+            BencodeValue::ByteString(self.transaction_id.clone()),
         );
         dictionary.insert("y".into(), BencodeValue::ByteString("q".into()));
         dictionary.insert(
@@ -142,11 +310,47 @@ impl<N: NodeId> Query<N> {
             "a".into(),
             BencodeValue::Dict(self.query.to_arguments().into_iter().collect()),
         );
+        if let Some(version) = &self.options.version {
+            dictionary.insert("v".into(), BencodeValue::ByteString(version.clone()));
+        }
+        if self.options.read_only {
+            dictionary.insert("ro".into(), BencodeValue::Integer(1));
+        }
+        for (key, value) in &self.options.extra {
+            dictionary
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
         let mut bencode = BencodeValue::Dict(dictionary.into_iter().collect());
         bencode.sort_keys();
         bencode
     }
 
+    /// Parse a query, e.g. one received by a node.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use bitcrawler_proto::{
+    ///     bencode,
+    ///     kademlia::Id160,
+    ///     krpc::{Query, QueryType},
+    /// };
+    ///
+    /// let datagram = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e\
+    ///                  1:q9:find_node1:t2:aa1:v4:UT\x01\x021:y1:qe";
+    /// let (_, message) = bencode::decode(&datagram).unwrap();
+    /// let query = Query::<Id160>::try_from_bencoded(&message).unwrap();
+    ///
+    /// assert_eq!(query.get_transaction_id().as_ref(), b"aa");
+    /// assert_eq!(query.get_options().version, Some(b"UT\x01\x02".to_vec().into()));
+    /// match query.get_query() {
+    ///     QueryType::FindNode(find_node) => {
+    ///         assert_eq!(find_node.get_target(), &Id160(*b"mnopqrstuvwxyz123456"));
+    ///     }
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
     pub fn try_from_bencoded(input: &BencodeValue) -> Result<Self, TryFromArgumentsError> {
         let dict = match input {
             BencodeValue::Dict(dict) => dict,
@@ -173,10 +377,25 @@ impl<N: NodeId> Query<N> {
             QUERY_TYPE_ANNOUNCE_PEER => {
                 QueryType::AnnouncePeer(AnnouncePeer::try_from_arguments(arguments)?)
             }
-            _ => return Err("Invalid query type"),
+            _ => QueryType::Unknown {
+                name: query_type.clone(),
+                args: arguments.clone(),
+            },
+        };
+
+        let options = MessageOptions {
+            version: match dict.iter().find(|(key, _)| key.as_ref() == b"v") {
+                Some((_, BencodeValue::ByteString(version))) => Some(version.clone()),
+                _ => None,
+            },
+            read_only: matches!(
+                dict.iter().find(|(key, _)| key.as_ref() == b"ro"),
+                Some((_, BencodeValue::Integer(1)))
+            ),
+            extra: Vec::new(),
         };
 
-        Ok(Query::new(transaction_id, query))
+        Ok(Query::new(transaction_id, query).with_options(options))
     }
 }
 
@@ -187,6 +406,7 @@ impl<N: NodeId> QueryType<N> {
             QueryType::FindNode(find_node) => find_node.to_arguments(),
             QueryType::GetPeers(get_peers) => get_peers.to_arguments(),
             QueryType::AnnouncePeer(announce_peer) => announce_peer.to_arguments(),
+            QueryType::Unknown { args, .. } => args.iter().cloned().collect(),
         }
     }
 
@@ -196,10 +416,95 @@ impl<N: NodeId> QueryType<N> {
             QueryType::FindNode(_) => QUERY_TYPE_FIND_NODE,
             QueryType::GetPeers(_) => QUERY_TYPE_GET_PEERS,
             QueryType::AnnouncePeer(_) => QUERY_TYPE_ANNOUNCE_PEER,
+            QueryType::Unknown { name, .. } => name.as_ref(),
         }
     }
 }
 
+impl<N: NodeId> Ping<N> {
+    pub fn get_id(&self) -> &N {
+        &self.id
+    }
+}
+
+impl<N: NodeId> FindNode<N> {
+    pub fn get_id(&self) -> &N {
+        &self.id
+    }
+
+    pub fn get_target(&self) -> &N {
+        &self.target
+    }
+}
+
+impl<N: NodeId> GetPeers<N> {
+    pub fn get_id(&self) -> &N {
+        &self.id
+    }
+
+    pub fn get_info_hash(&self) -> &N {
+        &self.info_hash
+    }
+
+    /// Check if the query asks for the Bloom filters of BEP 33 (`scrape`).
+    pub fn is_scrape(&self) -> bool {
+        self.scrape
+    }
+}
+
+impl<N: NodeId> AnnouncePeer<N> {
+    /// Announce that we download `info_hash` on `port`, with the token of the node the query is
+    /// sent to.
+    pub fn new(id: N, info_hash: N, port: Port, token: AnnounceToken) -> Self {
+        AnnouncePeer {
+            id,
+            info_hash,
+            port: Some(port),
+            token: token.0,
+            implied_port: false,
+        }
+    }
+
+    /// Ask to use the source port of the query as the peer port (`implied_port`), `port` is
+    /// only a fallback.
+    pub fn with_implied_port(mut self) -> Self {
+        self.implied_port = true;
+        self
+    }
+
+    pub fn get_id(&self) -> &N {
+        &self.id
+    }
+
+    pub fn get_info_hash(&self) -> &N {
+        &self.info_hash
+    }
+
+    /// Get the `port` argument, `None` if it is 0, which is only accepted with `implied_port`.
+    pub fn get_port(&self) -> Option<Port> {
+        self.port
+    }
+
+    /// Get the port of the peer announced by a query sent from `source_port`: the source port
+    /// with `implied_port`, otherwise the `port` argument.
+    pub fn peer_port(&self, source_port: u16) -> Option<Port> {
+        if self.implied_port {
+            Port::new(source_port)
+        } else {
+            self.port
+        }
+    }
+
+    pub fn get_token(&self) -> &BencodeString {
+        &self.token
+    }
+
+    /// Check if the peer port is the source port of the query, rather than `port`.
+    pub fn get_implied_port(&self) -> bool {
+        self.implied_port
+    }
+}
+
 impl<N: NodeId> ToArguments for Ping<N> {
     fn to_arguments(&self) -> HashMap<BencodeString, BencodeValue> {
         let mut arguments = HashMap::new();
@@ -230,6 +535,9 @@ impl<N: NodeId> ToArguments for GetPeers<N> {
             "info_hash".into(),
             BencodeValue::ByteString(info_hash.into()),
         );
+        if self.scrape {
+            arguments.insert("scrape".into(), BencodeValue::Integer(1));
+        }
         arguments
     }
 }
@@ -244,8 +552,12 @@ impl<N: NodeId> ToArguments for AnnouncePeer<N> {
             "info_hash".into(),
             BencodeValue::ByteString(info_hash.into()),
         );
-        arguments.insert("port".into(), BencodeValue::Integer(self.port as i128));
+        let port = self.port.map_or(0, Port::get);
+        arguments.insert("port".into(), BencodeValue::Integer(port as i128));
         arguments.insert("token".into(), BencodeValue::ByteString(self.token.clone()));
+        if self.implied_port {
+            arguments.insert("implied_port".into(), BencodeValue::Integer(1));
+        }
         arguments
     }
 }
@@ -297,11 +609,15 @@ impl<N: NodeId> TryFromArguments for GetPeers<N> {
             .iter()
             .find(|(key, _)| key.as_ref() == b"info_hash")
             .ok_or("Missing 'info_hash' field")?;
+        let scrape = arguments
+            .iter()
+            .any(|(key, value)| key.as_ref() == b"scrape" && *value == BencodeValue::Integer(1));
         if let (BencodeValue::ByteString(id), BencodeValue::ByteString(info_hash)) = (id, info_hash)
         {
             Ok(GetPeers {
                 id: N::try_from(id.as_ref()).or(Err("Invalid NodeId"))?,
                 info_hash: N::try_from(info_hash.as_ref()).or(Err("Invalid NodeId/InfoHash"))?,
+                scrape,
             })
         } else {
             Err("Invalid 'id' or 'info_hash' field")
@@ -312,6 +628,7 @@ impl<N: NodeId> TryFromArguments for GetPeers<N> {
 impl<N: NodeId> TryFromArguments for AnnouncePeer<N> {
     fn try_from_arguments(arguments: &BencodeDict) -> Result<Self, TryFromArgumentsError> {
         let (mut id, mut info_hash, mut port, mut token) = (None, None, None, None);
+        let mut implied_port = false;
         for (key, value) in arguments {
             match key.as_ref() {
                 b"id" => {
@@ -329,16 +646,7 @@ impl<N: NodeId> TryFromArguments for AnnouncePeer<N> {
                         return Err("Invalid 'info_hash' field");
                     }
                 }
-                b"port" => {
-                    if let BencodeValue::Integer(port_) = value {
-                        if *port_ < 0 || *port_ > u16::MAX as i128 {
-                            return Err("Invalid 'port' field");
-                        }
-                        port = Some(*port_ as u16);
-                    } else {
-                        return Err("Invalid 'port' field");
-                    }
-                }
+                b"port" => port = Some(value.as_u16_port()?),
                 b"token" => {
                     if let BencodeValue::ByteString(token_) = value {
                         token = Some(token_.clone());
@@ -346,6 +654,9 @@ impl<N: NodeId> TryFromArguments for AnnouncePeer<N> {
                         return Err("Invalid 'token' field");
                     }
                 }
+                b"implied_port" => {
+                    implied_port = value.as_integer().ok_or("Invalid 'implied_port' field")? != 0;
+                }
                 _ => { /* Ignore */ }
             }
         }
@@ -353,8 +664,13 @@ impl<N: NodeId> TryFromArguments for AnnouncePeer<N> {
             (Some(id), Some(info_hash), Some(port), Some(token)) => Ok(AnnouncePeer {
                 id,
                 info_hash,
-                port,
+                // The port is ignored with implied_port, where some clients send 0.
+                port: match Port::new(port) {
+                    None if !implied_port => return Err("Invalid 'port' field"),
+                    port => port,
+                },
                 token,
+                implied_port,
             }),
             _ => Err("Missing required field(s)"),
         }
@@ -403,4 +719,113 @@ mod tests {
         expected.sort_keys();
         assert_eq!(bencoded, expected);
     }
+
+    #[test]
+    fn test_custom_query_roundtrip() {
+        let args: BencodeDict = vec![
+            ("id".into(), BencodeValue::ByteString("25000000".into())),
+            ("v".into(), BencodeValue::Integer(2)),
+        ];
+        let query = Query::<MockNodeId>::custom("aa", b"vendor_info", args.clone());
+        assert_eq!(query.get_query().get_query_type(), b"vendor_info");
+
+        let bencoded = query.to_bencoded();
+        let parsed = Query::<MockNodeId>::try_from_bencoded(&bencoded).unwrap();
+        match parsed.get_query() {
+            QueryType::Unknown {
+                name,
+                args: parsed_args,
+            } => {
+                assert_eq!(name.as_ref(), b"vendor_info");
+                // Keys are sorted on encoding.
+                assert_eq!(parsed_args, &args);
+            }
+            other => panic!("unexpected query {:?}", other),
+        }
+        assert_eq!(parsed.get_transaction_id().as_ref(), b"aa");
+    }
+
+    #[test]
+    fn test_scrape_query_roundtrip() {
+        let id = MockNodeId::try_from(&b"25000000"[..]).unwrap();
+        let query = Query::new_scrape("aa", id.clone(), id.clone());
+        let parsed = Query::<MockNodeId>::try_from_bencoded(&query.to_bencoded()).unwrap();
+        assert_eq!(parsed, query);
+        match parsed.get_query() {
+            QueryType::GetPeers(get_peers) => assert!(get_peers.is_scrape()),
+            other => panic!("unexpected query {:?}", other),
+        }
+        let plain = Query::new_get_peers("aa", id.clone(), id);
+        assert!(
+            !plain
+                .get_query()
+                .to_arguments()
+                .contains_key(&BencodeString::from("scrape"))
+        );
+    }
+
+    #[test]
+    fn test_announce_peer_port() {
+        let announce = |port: i128, implied_port: i128| {
+            let args: BencodeDict = vec![
+                ("id".into(), BencodeValue::ByteString("25000000".into())),
+                ("implied_port".into(), BencodeValue::Integer(implied_port)),
+                (
+                    "info_hash".into(),
+                    BencodeValue::ByteString("25000001".into()),
+                ),
+                ("port".into(), BencodeValue::Integer(port)),
+                ("token".into(), BencodeValue::ByteString("aoeusnth".into())),
+            ];
+            AnnouncePeer::<MockNodeId>::try_from_arguments(&args)
+        };
+        assert_eq!(announce(70000, 0).unwrap_err(), "port out of range");
+        // Port 0 is only accepted when the source port is used instead.
+        assert_eq!(announce(0, 0).unwrap_err(), "Invalid 'port' field");
+        let implied = announce(0, 1).unwrap();
+        assert_eq!(implied.get_port(), None);
+        assert_eq!(implied.peer_port(51413), Port::new(51413));
+        assert_eq!(implied.peer_port(0), None);
+        let explicit = announce(6881, 0).unwrap();
+        assert_eq!(explicit.peer_port(51413), Port::new(6881));
+    }
+
+    #[test]
+    fn test_custom_query_with_standard_name() {
+        let args: BencodeDict = vec![("id".into(), BencodeValue::ByteString("25000000".into()))];
+        let query = Query::<MockNodeId>::custom("aa", QUERY_TYPE_PING, args);
+        let parsed = Query::<MockNodeId>::try_from_bencoded(&query.to_bencoded()).unwrap();
+        assert!(matches!(parsed.get_query(), QueryType::Ping(_)));
+    }
+
+    #[test]
+    fn test_message_options() {
+        let options = MessageOptions {
+            version: Some(b"bc\x00\x01".as_ref().into()),
+            read_only: true,
+            extra: vec![
+                ("p".into(), BencodeValue::Integer(6881)),
+                // Already written by the query, ignored.
+                ("y".into(), BencodeValue::ByteString("e".into())),
+            ],
+        };
+        let query = Query::new_ping("aa", MockNodeId(1)).with_options(options);
+        assert_eq!(
+            crate::bencode::encode(&query.to_bencoded()),
+            b"d1:ad2:id8:\x00\x00\x00\x00\x00\x00\x00\x01e1:pi6881e1:q4:ping2:roi1e1:t2:aa1:v4:bc\x00\x011:y1:qe"
+        );
+
+        let parsed = Query::<MockNodeId>::try_from_bencoded(&query.to_bencoded()).unwrap();
+        assert_eq!(
+            parsed.get_options().version.as_ref().unwrap().as_ref(),
+            b"bc\x00\x01"
+        );
+        assert!(parsed.get_options().read_only);
+        // Without options, nothing is added.
+        let plain = Query::new_ping("aa", MockNodeId(1)).to_bencoded();
+        assert_eq!(
+            crate::bencode::encode(&plain),
+            b"d1:ad2:id8:\x00\x00\x00\x00\x00\x00\x00\x01e1:q4:ping1:t2:aa1:y1:qe"
+        );
+    }
 }
@@ -0,0 +1,349 @@
+use std::ops::BitOr;
+
+use crate::{
+    bencode::{BencodeString, BencodeValue},
+    kademlia::NodeId,
+};
+
+use super::{
+    ErrorCode, ErrorMessage, Message, Query, QueryType, Response, ResponseType,
+    node_info::CompactNodeInfo, peer_info::CompactPeerInfo,
+};
+
+/// Kind of a KRPC message, its `y` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Query,
+    Response,
+    Error,
+}
+
+/// Set of the optional fields present in a message, see [`MessageRecord::fields`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MessageFields(pub u16);
+
+impl MessageFields {
+    /// Client version (`v`).
+    pub const VERSION: MessageFields = MessageFields(1 << 0);
+    /// Read-only flag (`ro`, BEP 43).
+    pub const READ_ONLY: MessageFields = MessageFields(1 << 1);
+    /// Target of a `find_node` query, or info hash of a `get_peers` or `announce_peer` query.
+    pub const TARGET: MessageFields = MessageFields(1 << 2);
+    /// Token of a `get_peers` reply or of an `announce_peer` query.
+    pub const TOKEN: MessageFields = MessageFields(1 << 3);
+    /// Compact node infos (`nodes`).
+    pub const NODES: MessageFields = MessageFields(1 << 4);
+    /// Peers (`values`).
+    pub const VALUES: MessageFields = MessageFields(1 << 5);
+    /// Scrape flag of a `get_peers` query, or Bloom filters of its reply (BEP 33).
+    pub const SCRAPE: MessageFields = MessageFields(1 << 6);
+    /// Address of the requester as seen by the responder (`ip`, BEP 42).
+    pub const REQUESTER: MessageFields = MessageFields(1 << 7);
+    /// `implied_port` flag of an `announce_peer` query.
+    pub const IMPLIED_PORT: MessageFields = MessageFields(1 << 8);
+    /// Fields outside of BEP 5 and the extensions above (e.g. vendor fields).
+    pub const EXTRA: MessageFields = MessageFields(1 << 9);
+
+    /// Check if every field of `other` is present.
+    pub fn contains(self, other: MessageFields) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Mark the fields of `other` as present if `present` holds.
+    pub fn set(&mut self, other: MessageFields, present: bool) {
+        if present {
+            self.0 |= other.0;
+        }
+    }
+}
+
+impl BitOr for MessageFields {
+    type Output = MessageFields;
+
+    fn bitor(self, other: MessageFields) -> MessageFields {
+        MessageFields(self.0 | other.0)
+    }
+}
+
+/// A KRPC message flattened into a row, for statistics over many messages (e.g. a capture or a
+/// live crawl) without matching on every query and reply type.
+///
+/// Built with `From` from a parsed [`Query`], [`Response`], [`ErrorMessage`] or [`Message`].
+/// Replies and errors do not keep their `v` field once parsed, see
+/// [`MessageRecord::with_version_from`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageRecord {
+    pub kind: MessageKind,
+    /// Method of a query, or method a reply answers (empty for a [`ResponseType::Raw`] reply,
+    /// and for an error).
+    pub method: Vec<u8>,
+    /// Length of the transaction id, in bytes.
+    pub transaction_id_len: usize,
+    pub fields: MessageFields,
+    /// Number of nodes of the message.
+    pub nodes: usize,
+    /// Number of peers of the message.
+    pub values: usize,
+    /// Client version (`v`).
+    pub version: Option<BencodeString>,
+    /// Code of an error.
+    pub error_code: Option<ErrorCode>,
+}
+
+impl MessageRecord {
+    fn new(kind: MessageKind, method: &[u8], transaction_id: &BencodeString) -> MessageRecord {
+        MessageRecord {
+            kind,
+            method: method.to_vec(),
+            transaction_id_len: transaction_id.as_ref().len(),
+            fields: MessageFields::default(),
+            nodes: 0,
+            values: 0,
+            version: None,
+            error_code: None,
+        }
+    }
+
+    /// Take the client version from the bencoded message the record was parsed from.
+    pub fn with_version_from(mut self, message: &BencodeValue) -> MessageRecord {
+        if let BencodeValue::Dict(dict) = message
+            && let Some((_, BencodeValue::ByteString(version))) =
+                dict.iter().find(|(key, _)| key.as_ref() == b"v")
+        {
+            self.version = Some(version.clone());
+            self.fields.set(MessageFields::VERSION, true);
+        }
+        self
+    }
+}
+
+impl<N: NodeId> From<&Query<N>> for MessageRecord {
+    fn from(query: &Query<N>) -> MessageRecord {
+        let mut record = MessageRecord::new(
+            MessageKind::Query,
+            query.get_query().get_query_type(),
+            query.get_transaction_id(),
+        );
+        let options = query.get_options();
+        record.version = options.version.clone();
+        record
+            .fields
+            .set(MessageFields::VERSION, options.version.is_some());
+        record
+            .fields
+            .set(MessageFields::READ_ONLY, options.read_only);
+        record
+            .fields
+            .set(MessageFields::EXTRA, !options.extra.is_empty());
+        match query.get_query() {
+            QueryType::Ping(_) => {}
+            QueryType::FindNode(_) => record.fields.set(MessageFields::TARGET, true),
+            QueryType::GetPeers(get_peers) => {
+                record.fields.set(MessageFields::TARGET, true);
+                record
+                    .fields
+                    .set(MessageFields::SCRAPE, get_peers.is_scrape());
+            }
+            QueryType::AnnouncePeer(announce_peer) => {
+                record
+                    .fields
+                    .set(MessageFields::TARGET | MessageFields::TOKEN, true);
+                record.fields.set(
+                    MessageFields::IMPLIED_PORT,
+                    announce_peer.get_implied_port(),
+                );
+            }
+            QueryType::Unknown { .. } => record.fields.set(MessageFields::EXTRA, true),
+        }
+        record
+    }
+}
+
+impl<I: CompactNodeInfo, P: CompactPeerInfo> From<&Response<I, P>> for MessageRecord {
+    fn from(response: &Response<I, P>) -> MessageRecord {
+        let response_type = response.get_response_type();
+        let mut record = MessageRecord::new(
+            MessageKind::Response,
+            response_type.get_query_type(),
+            response.get_transaction_id(),
+        );
+        record
+            .fields
+            .set(MessageFields::REQUESTER, response.get_requester().is_some());
+        match response_type {
+            ResponseType::Ping(ping) => {
+                record.nodes = ping.get_nodes::<I>().len();
+                record.fields.set(MessageFields::NODES, record.nodes > 0);
+                record.fields.set(
+                    MessageFields::EXTRA,
+                    ping.get_extra()
+                        .iter()
+                        .any(|(key, _)| key.as_ref() != b"nodes"),
+                );
+            }
+            ResponseType::FindNode(find_node) => {
+                record.nodes = find_node.get_nodes().len();
+                record.fields.set(MessageFields::NODES, true);
+            }
+            ResponseType::GetPeers(get_peers) => {
+                record.nodes = get_peers.get_nodes().len();
+                record.values = get_peers.get_peers().len();
+                record.fields.set(MessageFields::NODES, record.nodes > 0);
+                record.fields.set(MessageFields::VALUES, record.values > 0);
+                record
+                    .fields
+                    .set(MessageFields::TOKEN, get_peers.get_token().is_some());
+                let filters = get_peers
+                    .get_seeds_filter()
+                    .or(get_peers.get_peers_filter());
+                record.fields.set(MessageFields::SCRAPE, filters.is_some());
+            }
+            // Only the presence of the fields is known, and the number of peers.
+            ResponseType::Raw(args) => {
+                for (key, value) in args {
+                    let field = match key.as_ref() {
+                        b"id" => continue,
+                        b"nodes" | b"nodes6" => MessageFields::NODES,
+                        b"values" => {
+                            if let BencodeValue::List(values) = value {
+                                record.values += values.len();
+                            }
+                            MessageFields::VALUES
+                        }
+                        b"token" => MessageFields::TOKEN,
+                        b"BFsd" | b"BFpe" => MessageFields::SCRAPE,
+                        _ => MessageFields::EXTRA,
+                    };
+                    record.fields.set(field, true);
+                }
+            }
+        }
+        record
+    }
+}
+
+impl From<&ErrorMessage> for MessageRecord {
+    fn from(error: &ErrorMessage) -> MessageRecord {
+        let mut record = MessageRecord::new(MessageKind::Error, b"", &error.transaction_id);
+        record.error_code = Some(error.code);
+        record
+    }
+}
+
+impl<N: NodeId> From<&Message<N>> for MessageRecord {
+    fn from(message: &Message<N>) -> MessageRecord {
+        match message {
+            Message::Query(query) => query.into(),
+            Message::Error(error) => error.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+    use crate::{
+        kademlia::Id160,
+        krpc::{MessageOptions, Port, node_info::BittorrentNodeInfoV4, query::AnnounceToken},
+    };
+
+    type DhtResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+
+    #[test]
+    fn test_query_record() {
+        let options = MessageOptions {
+            version: Some(b"UT\x01\x02".as_ref().into()),
+            read_only: true,
+            ..MessageOptions::default()
+        };
+        let token = AnnounceToken::from_raw("aoeusnth");
+        let port = Port::new(6881).unwrap();
+        let query = Query::new_announce_peer("aa", Id160([1; 20]), Id160([2; 20]), port, token)
+            .with_options(options.clone());
+        let record = MessageRecord::from(&query);
+        assert_eq!(record.kind, MessageKind::Query);
+        assert_eq!(record.method, b"announce_peer");
+        assert_eq!(record.transaction_id_len, 2);
+        assert_eq!(record.version, options.version);
+        assert_eq!(
+            record.fields,
+            MessageFields::VERSION
+                | MessageFields::READ_ONLY
+                | MessageFields::TARGET
+                | MessageFields::TOKEN
+        );
+
+        let record = MessageRecord::from(&Message::Query(Query::new_ping("abcd", Id160([1; 20]))));
+        assert_eq!(record.method, b"ping");
+        assert_eq!(record.transaction_id_len, 4);
+        assert_eq!(record.fields, MessageFields::default());
+    }
+
+    #[test]
+    fn test_response_record() {
+        let node = BittorrentNodeInfoV4 {
+            node_id: Id160([3; 20]),
+            ip: [192, 0, 2, 1],
+            port: 6881,
+        };
+        let peer = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 6881);
+        let reply = DhtResponse::new_get_peers(
+            "aa",
+            Id160([1; 20]),
+            Some("token".into()),
+            vec![node.clone(), node],
+            vec![peer],
+        );
+        let record = MessageRecord::from(&reply);
+        assert_eq!(record.kind, MessageKind::Response);
+        assert_eq!(record.method, b"get_peers");
+        assert_eq!((record.nodes, record.values), (2, 1));
+        assert_eq!(
+            record.fields,
+            MessageFields::NODES | MessageFields::VALUES | MessageFields::TOKEN
+        );
+        assert!(!record.fields.contains(MessageFields::VERSION));
+
+        // The version is read from the message the reply was parsed from.
+        let mut bencoded = reply.to_bencoded();
+        if let BencodeValue::Dict(dict) = &mut bencoded {
+            dict.push(("v".into(), BencodeValue::ByteString("LT01".into())));
+        }
+        let parsed = DhtResponse::try_from_getpeers_bencoded(&bencoded).unwrap();
+        let record = MessageRecord::from(&parsed).with_version_from(&bencoded);
+        assert_eq!(record.version, Some("LT01".into()));
+        assert!(
+            record
+                .fields
+                .contains(MessageFields::VERSION | MessageFields::TOKEN)
+        );
+
+        let raw = DhtResponse::custom(
+            "aa",
+            vec![
+                ("id".into(), BencodeValue::ByteString(vec![1; 20].into())),
+                (
+                    "values".into(),
+                    BencodeValue::List(vec![BencodeValue::ByteString("x".into())]),
+                ),
+                ("vendor".into(), BencodeValue::Integer(1)),
+            ],
+        );
+        let record = MessageRecord::from(&raw);
+        assert_eq!(record.method, b"");
+        assert_eq!(record.values, 1);
+        assert_eq!(record.fields, MessageFields::VALUES | MessageFields::EXTRA);
+    }
+
+    #[test]
+    fn test_error_record() {
+        let error = ErrorMessage::new("aa", ErrorCode::ProtocolError, "bad".to_string());
+        let bencoded = error.to_bencoded();
+        let record = MessageRecord::from(&error).with_version_from(&bencoded);
+        assert_eq!(record.kind, MessageKind::Error);
+        assert_eq!(record.error_code, Some(ErrorCode::ProtocolError));
+        assert_eq!(record.version, None);
+    }
+}
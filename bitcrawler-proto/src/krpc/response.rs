@@ -2,27 +2,94 @@ use std::collections::HashMap;
 
 use crate::{
     bencode::{BencodeDict, BencodeString, BencodeValue},
+    consts::{COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN, SCRAPE_FILTER_LEN},
     kademlia::NodeId,
 };
 
-use super::{peer_info::CompactPeerInfo, query::{QUERY_TYPE_FIND_NODE, QUERY_TYPE_GET_PEERS}};
 use super::{
     ToArguments, TryFromArguments, TryFromArgumentsError, node_info::CompactNodeInfo,
     query::QUERY_TYPE_PING,
 };
+use super::{
+    peer_info::CompactPeerInfo,
+    query::{AnnounceToken, QUERY_TYPE_FIND_NODE, QUERY_TYPE_GET_PEERS},
+};
 
 /// Represents a response message in the KRPC protocol.
 ///
 /// More information about the KRPC protocol can be found in the [specification](https://www.bittorrent.org/beps/bep_0005.html).
+///
+/// # Examples
+///
+/// Decode a captured `find_node` reply and extract the nodes it returns:
+///
+/// ```rust
+/// use std::net::SocketAddrV4;
+///
+/// use bitcrawler_proto::{
+///     bencode,
+///     kademlia::Id160,
+///     krpc::{Response, ResponseType, node_info::BittorrentNodeInfoV4},
+/// };
+///
+/// type DhtResponse = Response<BittorrentNodeInfoV4<Id160>, SocketAddrV4>;
+///
+/// // One node, 192.0.2.1:6881 (0x1ae1).
+/// let datagram = b"d1:rd2:id20:0123456789abcdefghij5:nodes26:\
+///                  mnopqrstuvwxyz123456\xc0\x00\x02\x01\x1a\xe1e1:t2:aa1:y1:re";
+/// let (_, message) = bencode::decode(&datagram).unwrap();
+///
+/// // The method of the query is normally known from the transaction id, it can be guessed too.
+/// let (method, transaction_id) = DhtResponse::try_guess_type_from_bencoded(&message).unwrap();
+/// assert_eq!((method, transaction_id.as_ref()), (&b"find_node"[..], &b"aa"[..]));
+///
+/// let response = DhtResponse::try_from_findpeer_bencoded(&message).unwrap();
+/// let ResponseType::FindNode(find_node) = response.get_response_type() else {
+///     unreachable!()
+/// };
+/// assert_eq!(find_node.get_id(), &Id160(*b"0123456789abcdefghij"));
+/// let node = &find_node.get_nodes()[0];
+/// assert_eq!(node.node_id, Id160(*b"mnopqrstuvwxyz123456"));
+/// assert_eq!((node.ip, node.port), ([192, 0, 2, 1], 6881));
+/// ```
+///
+/// Build a `get_peers` reply, as a node answering queries would:
+///
+/// ```rust
+/// use std::net::{Ipv4Addr, SocketAddrV4};
+///
+/// use bitcrawler_proto::{
+///     bencode,
+///     kademlia::Id160,
+///     krpc::{Response, node_info::BittorrentNodeInfoV4},
+/// };
+///
+/// let peer = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881);
+/// let reply = Response::<BittorrentNodeInfoV4<Id160>, _>::new_get_peers(
+///     "aa",
+///     Id160(*b"abcdefghij0123456789"),
+///     Some("aoeusnth".into()),
+///     vec![],
+///     vec![peer],
+/// );
+/// assert_eq!(
+///     bencode::encode(&reply.to_bencoded()),
+///     &b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth\
+///        6:valuesl6:\xc0\x00\x02\x01\x1a\xe1ee1:t2:aa1:y1:re"[..]
+/// );
+/// ```
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Response<I: CompactNodeInfo, P: CompactPeerInfo> {
     transaction_id: BencodeString,
     response: ResponseType<I, P>,
+    // (Optional) address of the requester as seen by the responder, see BEP 42.
+    requester: Option<P>,
 }
 
 /// Represents a response type in the KRPC protocol.
 ///
 /// Only 4 response types are supported: `ping`, `find_node`, `get_peers`, and `announce_peer`.
+/// Replies to other methods (e.g. vendor extensions) are kept as-is in [`ResponseType::Raw`].
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ResponseType<I: CompactNodeInfo, P: CompactPeerInfo> {
     /// Represents a `ping` query.
@@ -31,6 +98,8 @@ pub enum ResponseType<I: CompactNodeInfo, P: CompactPeerInfo> {
     FindNode(FindNode<I>),
     /// Represents a `get_peers` query.
     GetPeers(GetPeers<I, P>),
+    /// Represents a reply of unknown shape, with its raw `r` dictionary.
+    Raw(BencodeDict),
     /*
     /// Represents an `announce_peer` query.
     AnnouncePeer(AnnouncePeer<N>),
@@ -42,8 +111,13 @@ pub enum ResponseType<I: CompactNodeInfo, P: CompactPeerInfo> {
 ///
 /// The `ping` query is used to test the liveness of a node.
 /// See [Ping query](super::query::Ping) for more information.
+///
+/// `announce_peer` replies have the same shape. Some clients attach other fields to these
+/// replies (e.g. `nodes`, `ip` or `p`): they are kept, see [`Ping::get_extra`].
 pub struct Ping<N: NodeId> {
     id: N,
+    // Arguments other than `id`, sorted by key.
+    extra: BencodeDict,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -61,10 +135,11 @@ where
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 /// Represents a `get_peers` response.
-/// 
+///
 /// The `get_peers` query is used to find the `k` nodes closest to a given `target` info_hash.
 /// See [GetPeers query](super::query::GetPeers) for more information.
-/// The `peers` field contains either a list of compact peer info or a list of compact nodes to contact.
+/// The reply holds the peers of the torrent, the closest nodes to contact, or both, see
+/// [`GetPeersShape`].
 pub struct GetPeers<I: CompactNodeInfo, P: CompactPeerInfo> {
     id: I::NodeId,
     // (Optional) token used to broadcast an announce_peer query
@@ -72,6 +147,49 @@ pub struct GetPeers<I: CompactNodeInfo, P: CompactPeerInfo> {
     token: Option<BencodeString>,
     nodes: Vec<I>,
     peers: Vec<P>,
+    // The fields the reply holds, even when empty, `None` for neither.
+    shape: Option<GetPeersShape>,
+    // (Optional) Bloom filters of the seeds (`BFsd`) and peers (`BFpe`) of the swarm, sent
+    // in reply to a scrape (BEP 33).
+    seeds_filter: Option<BencodeString>,
+    peers_filter: Option<BencodeString>,
+}
+
+/// The fields of a `get_peers` reply.
+///
+/// Per BEP 5 a node replies with the peers (`values`) if it knows some, and with the closest
+/// nodes (`nodes`) otherwise, but some nodes send both and some clients expect both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GetPeersShape {
+    /// Only the peers, in `values`.
+    Values,
+    /// Only the closest nodes, in `nodes`.
+    Nodes,
+    /// Both the peers and the closest nodes.
+    Both,
+}
+
+impl GetPeersShape {
+    /// Get the shape of a reply holding the given fields, `None` for neither.
+    pub fn of(nodes: bool, values: bool) -> Option<GetPeersShape> {
+        match (nodes, values) {
+            (true, true) => Some(GetPeersShape::Both),
+            (true, false) => Some(GetPeersShape::Nodes),
+            (false, true) => Some(GetPeersShape::Values),
+            (false, false) => None,
+        }
+    }
+
+    /// Check if the reply holds the `nodes` field.
+    pub fn has_nodes(self) -> bool {
+        matches!(self, GetPeersShape::Nodes | GetPeersShape::Both)
+    }
+
+    /// Check if the reply holds the `values` field.
+    pub fn has_values(self) -> bool {
+        matches!(self, GetPeersShape::Values | GetPeersShape::Both)
+    }
 }
 
 impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
@@ -79,9 +197,61 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
         Response {
             transaction_id: transaction_id.into(),
             response,
+            requester: None,
         }
     }
 
+    /// Set the address of the requester as seen by the responder, sent in the `ip` field
+    /// (BEP 42).
+    pub fn with_requester(mut self, requester: P) -> Self {
+        self.requester = Some(requester);
+        self
+    }
+
+    pub fn new_ping(transaction_id: impl Into<BencodeString>, id: I::NodeId) -> Self {
+        Response::new(transaction_id, ResponseType::Ping(Ping::new(id)))
+    }
+
+    pub fn new_find_node(
+        transaction_id: impl Into<BencodeString>,
+        id: I::NodeId,
+        nodes: Vec<I>,
+    ) -> Self {
+        Response::new(
+            transaction_id,
+            ResponseType::FindNode(FindNode { id, nodes }),
+        )
+    }
+
+    /// Build a `get_peers` reply, holding the fields that are not empty (see
+    /// [`GetPeers::with_shape`] to send an empty one).
+    pub fn new_get_peers(
+        transaction_id: impl Into<BencodeString>,
+        id: I::NodeId,
+        token: Option<BencodeString>,
+        nodes: Vec<I>,
+        peers: Vec<P>,
+    ) -> Self {
+        let shape = GetPeersShape::of(!nodes.is_empty(), !peers.is_empty());
+        Response::new(
+            transaction_id,
+            ResponseType::GetPeers(GetPeers {
+                id,
+                token,
+                nodes,
+                peers,
+                shape,
+                seeds_filter: None,
+                peers_filter: None,
+            }),
+        )
+    }
+
+    /// Build a reply with arbitrary arguments, e.g. to answer a vendor-specific query.
+    pub fn custom(transaction_id: impl Into<BencodeString>, args: BencodeDict) -> Self {
+        Response::new(transaction_id, ResponseType::Raw(args))
+    }
+
     pub fn to_bencoded(&self) -> BencodeValue {
         let mut dictionary = HashMap::new();
         dictionary.insert(
@@ -93,10 +263,47 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
             "r".into(),
             BencodeValue::Dict(self.response.to_arguments().into_iter().collect()),
         );
+        if let Some(requester) = &self.requester {
+            dictionary.insert(
+                "ip".into(),
+                BencodeValue::ByteString(requester.write_compact_peer_info().into()),
+            );
+        }
         BencodeValue::Dict(dictionary.into_iter().collect())
     }
 
-    fn try_from_bencoded_internal(bencoded: &BencodeValue) -> Result<(BencodeString, Vec<(BencodeString, BencodeValue)>), TryFromArgumentsError> {
+    /// Read the `ip` field of a reply, ignored if it does not hold exactly one `P`.
+    fn requester_from_bencoded(bencoded: &BencodeValue) -> Option<P> {
+        let dict = match bencoded {
+            BencodeValue::Dict(dict) => dict,
+            _ => return None,
+        };
+        match dict.iter().find(|(key, _)| key.as_ref() == b"ip") {
+            Some((_, BencodeValue::ByteString(ip))) => {
+                match P::try_read_compact_peer_info(ip.as_ref()) {
+                    Ok((bytes_read, requester)) if bytes_read == ip.as_ref().len() => {
+                        Some(requester)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn from_parts(
+        bencoded: &BencodeValue,
+        transaction_id: BencodeString,
+        response: ResponseType<I, P>,
+    ) -> Self {
+        let mut response = Response::new(transaction_id, response);
+        response.requester = Self::requester_from_bencoded(bencoded);
+        response
+    }
+
+    fn try_from_bencoded_internal(
+        bencoded: &BencodeValue,
+    ) -> Result<(BencodeString, Vec<(BencodeString, BencodeValue)>), TryFromArgumentsError> {
         let bencoded = match bencoded {
             BencodeValue::Dict(bencoded) => bencoded,
             _ => return Err("Invalid response format"),
@@ -130,21 +337,32 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
 
         match response {
             BencodeValue::Dict(response) => Ok((transaction_id.clone(), response.clone())),
-            _ => return Err("Invalid 'r' field"),
+            _ => Err("Invalid 'r' field"),
         }
     }
 
+    /// Guess the method a reply answers from its fields, when the query is not known.
+    ///
+    /// Only the fields that identify a method, with the expected type, are considered: the
+    /// other ones (`ip`, `p`, a `nodes` field that does not hold compact node infos...) are
+    /// extra fields that do not change the guess. A `ping` or `announce_peer` reply with an
+    /// extra `nodes` field still looks like a `find_node` one, so parse the replies by the
+    /// method of their query when it is known: every parser accepts extra fields.
     pub fn try_guess_type_from_bencoded(
         bencoded: &BencodeValue,
     ) -> Result<(&'static [u8], BencodeString), TryFromArgumentsError> {
         let (transaction_id, response) = Self::try_from_bencoded_internal(bencoded)?;
-        
-        let (mut has_values_field,mut has_token_field, mut has_nodes_field) = (false, false, false);
+
+        let (mut has_values_field, mut has_token_field, mut has_nodes_field) =
+            (false, false, false);
         for (key, value) in response {
-            match key.as_ref() {
-                b"values" => has_values_field = true,
-                b"token" => has_token_field = true,
-                b"nodes" => has_nodes_field = true,
+            match (key.as_ref(), value) {
+                (b"values", BencodeValue::List(_)) => has_values_field = true,
+                (b"token", BencodeValue::ByteString(_)) => has_token_field = true,
+                (b"nodes", BencodeValue::ByteString(nodes)) => {
+                    has_nodes_field =
+                        !nodes.as_ref().is_empty() && decode_nodes::<I>(nodes.as_ref()).is_ok()
+                }
                 _ => {}
             }
         }
@@ -161,7 +379,7 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
         match Self::try_from_bencoded_internal(bencoded) {
             Ok((transaction_id, response)) => {
                 let response_type = ResponseType::Ping(Ping::try_from_arguments(&response)?);
-                Ok(Response::new(transaction_id, response_type))
+                Ok(Self::from_parts(bencoded, transaction_id, response_type))
             }
             Err(e) => Err(e),
         }
@@ -172,8 +390,9 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
     ) -> Result<Self, TryFromArgumentsError> {
         match Self::try_from_bencoded_internal(bencoded) {
             Ok((transaction_id, response)) => {
-                let response_type = ResponseType::FindNode(FindNode::try_from_arguments(&response)?);
-                Ok(Response::new(transaction_id, response_type))
+                let response_type =
+                    ResponseType::FindNode(FindNode::try_from_arguments(&response)?);
+                Ok(Self::from_parts(bencoded, transaction_id, response_type))
             }
             Err(e) => Err(e),
         }
@@ -184,13 +403,24 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
     ) -> Result<Self, TryFromArgumentsError> {
         match Self::try_from_bencoded_internal(bencoded) {
             Ok((transaction_id, response)) => {
-                let response_type = ResponseType::GetPeers(GetPeers::try_from_arguments(&response)?);
-                Ok(Response::new(transaction_id, response_type))
+                let response_type =
+                    ResponseType::GetPeers(GetPeers::try_from_arguments(&response)?);
+                Ok(Self::from_parts(bencoded, transaction_id, response_type))
             }
             Err(e) => Err(e),
         }
     }
 
+    /// Parse a reply without interpreting its arguments, see [`ResponseType::Raw`].
+    pub fn try_from_raw_bencoded(bencoded: &BencodeValue) -> Result<Self, TryFromArgumentsError> {
+        let (transaction_id, response) = Self::try_from_bencoded_internal(bencoded)?;
+        Ok(Self::from_parts(
+            bencoded,
+            transaction_id,
+            ResponseType::Raw(response),
+        ))
+    }
+
     pub fn get_transaction_id(&self) -> &BencodeString {
         &self.transaction_id
     }
@@ -198,6 +428,11 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> Response<I, P> {
     pub fn get_response_type(&self) -> &ResponseType<I, P> {
         &self.response
     }
+
+    /// Get the address of the requester as seen by the responder, if reported (BEP 42).
+    pub fn get_requester(&self) -> Option<&P> {
+        self.requester.as_ref()
+    }
 }
 
 impl<I: CompactNodeInfo, P: CompactPeerInfo> ResponseType<I, P> {
@@ -206,27 +441,104 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> ResponseType<I, P> {
             ResponseType::Ping(ping) => ping.to_arguments(),
             ResponseType::FindNode(find_node) => find_node.to_arguments(),
             ResponseType::GetPeers(get_peers) => get_peers.to_arguments(),
+            ResponseType::Raw(args) => args.iter().cloned().collect(),
         }
     }
 
+    /// Get the method of the query answered.
+    ///
+    /// Raw replies do not tell which method they answer, an empty name is returned.
     pub fn get_query_type(&self) -> &[u8] {
         match self {
             ResponseType::Ping(_) => QUERY_TYPE_PING,
             ResponseType::FindNode(_) => QUERY_TYPE_FIND_NODE,
-            ResponseType::GetPeers(_) => QUERY_TYPE_FIND_NODE,
+            ResponseType::GetPeers(_) => QUERY_TYPE_GET_PEERS,
+            ResponseType::Raw(_) => b"",
         }
     }
 }
 
 impl<N: NodeId> Ping<N> {
+    /// Build a reply holding only the node id.
+    pub fn new(id: N) -> Self {
+        Ping {
+            id,
+            extra: Vec::new(),
+        }
+    }
+
+    /// Attach another field to the reply, replacing the previous one with the same key.
+    ///
+    /// The `id` field cannot be replaced this way.
+    pub fn with_extra(mut self, key: impl Into<BencodeString>, value: BencodeValue) -> Self {
+        let key = key.into();
+        if key.as_ref() == b"id" {
+            return self;
+        }
+        match self
+            .extra
+            .binary_search_by(|(k, _)| k.as_ref().cmp(key.as_ref()))
+        {
+            Ok(index) => self.extra[index].1 = value,
+            Err(index) => self.extra.insert(index, (key, value)),
+        }
+        self
+    }
+
+    /// Attach a `nodes` field holding the given nodes, as some clients do.
+    pub fn with_nodes<I: CompactNodeInfo<NodeId = N>>(self, nodes: &[I]) -> Self {
+        let mut compact = Vec::new();
+        for node in nodes {
+            compact.extend(node.write_compact_node_info());
+        }
+        self.with_extra("nodes", BencodeValue::ByteString(compact.into()))
+    }
+
     pub fn get_id(&self) -> &N {
         &self.id
     }
+
+    /// Get the fields of the reply other than `id`, sorted by key.
+    pub fn get_extra(&self) -> &BencodeDict {
+        &self.extra
+    }
+
+    /// Get a field of the reply other than `id`.
+    pub fn get_extra_field(&self, key: &[u8]) -> Option<&BencodeValue> {
+        self.extra
+            .iter()
+            .find(|(k, _)| k.as_ref() == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Get the nodes of an extra `nodes` field, if any.
+    ///
+    /// The field is decoded leniently: decoding stops at the first invalid node info, the nodes
+    /// before it are returned.
+    pub fn get_nodes<I: CompactNodeInfo<NodeId = N>>(&self) -> Vec<I> {
+        let nodes = match self.get_extra_field(b"nodes") {
+            Some(BencodeValue::ByteString(nodes)) => nodes.as_ref(),
+            _ => return Vec::new(),
+        };
+        let mut decoded = Vec::new();
+        let mut i = 0;
+        while i < nodes.len() {
+            match I::try_read_compact_node_info(&nodes[i..]) {
+                Ok((bytes_read, node)) if bytes_read > 0 => {
+                    decoded.push(node);
+                    i += bytes_read;
+                }
+                _ => break,
+            }
+        }
+        decoded
+    }
 }
 
 impl<N: NodeId> ToArguments for Ping<N> {
     fn to_arguments(&self) -> HashMap<BencodeString, BencodeValue> {
-        let mut arguments = HashMap::new();
+        let mut arguments: HashMap<BencodeString, BencodeValue> =
+            self.extra.iter().cloned().collect();
         let id: Vec<u8> = self.id.clone().into();
         arguments.insert("id".into(), BencodeValue::ByteString(id.into()));
         arguments
@@ -240,8 +552,16 @@ impl<N: NodeId> TryFromArguments for Ping<N> {
             .find(|(key, _)| key.as_ref() == b"id")
             .ok_or("Missing 'id' field")?;
         if let BencodeValue::ByteString(id) = id {
+            // Other fields are kept as-is, whatever their content.
+            let mut extra: BencodeDict = arguments
+                .iter()
+                .filter(|(key, _)| key.as_ref() != b"id")
+                .cloned()
+                .collect();
+            extra.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
             Ok(Ping {
                 id: N::try_from(id.as_ref()).or(Err("Invalid NodeId"))?,
+                extra,
             })
         } else {
             Err("Invalid 'id' field")
@@ -249,6 +569,32 @@ impl<N: NodeId> TryFromArguments for Ping<N> {
     }
 }
 
+/// Decode a string of compact node infos, failing on any invalid one.
+fn decode_nodes<I: CompactNodeInfo>(nodes: &[u8]) -> Result<Vec<I>, TryFromArgumentsError> {
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < nodes.len() {
+        match I::try_read_compact_node_info(&nodes[i..]) {
+            Ok((bytes_read, node)) => {
+                decoded.push(node);
+                i += bytes_read;
+            }
+            Err(_) => return Err("Invalid node info"),
+        }
+    }
+    Ok(decoded)
+}
+
+impl<I: CompactNodeInfo> FindNode<I> {
+    pub fn get_id(&self) -> &I::NodeId {
+        &self.id
+    }
+
+    pub fn get_nodes(&self) -> &[I] {
+        &self.nodes
+    }
+}
+
 impl<I> ToArguments for FindNode<I>
 where
     I: CompactNodeInfo,
@@ -316,6 +662,11 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> GetPeers<I, P> {
         &self.token
     }
 
+    /// Get the token of the reply, to announce to the node that sent it.
+    pub fn announce_token(&self) -> Option<AnnounceToken> {
+        self.token.clone().map(AnnounceToken)
+    }
+
     pub fn get_nodes(&self) -> &[I] {
         &self.nodes
     }
@@ -323,6 +674,50 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> GetPeers<I, P> {
     pub fn get_peers(&self) -> &[P] {
         &self.peers
     }
+
+    /// Get the fields the reply holds, even when empty, `None` if it holds neither.
+    pub fn get_shape(&self) -> Option<GetPeersShape> {
+        self.shape
+    }
+
+    /// Set the fields the reply holds: an empty one is still sent, e.g. an empty `values`
+    /// beside the nodes for the clients that expect both.
+    ///
+    /// The shape must hold the fields that are not empty, a shape dropping some nodes or
+    /// peers is an error.
+    pub fn with_shape(mut self, shape: GetPeersShape) -> Result<Self, &'static str> {
+        if !self.nodes.is_empty() && !shape.has_nodes() {
+            return Err("Shape without the nodes of the reply");
+        }
+        if !self.peers.is_empty() && !shape.has_values() {
+            return Err("Shape without the peers of the reply");
+        }
+        self.shape = Some(shape);
+        Ok(self)
+    }
+
+    /// Get the Bloom filter of the seeds of the swarm (`BFsd`, BEP 33), if the node sent a
+    /// well-formed one.
+    pub fn get_seeds_filter(&self) -> Option<&[u8]> {
+        self.seeds_filter.as_ref().map(|filter| filter.as_ref())
+    }
+
+    /// Get the Bloom filter of the peers of the swarm (`BFpe`, BEP 33), if the node sent a
+    /// well-formed one.
+    pub fn get_peers_filter(&self) -> Option<&[u8]> {
+        self.peers_filter.as_ref().map(|filter| filter.as_ref())
+    }
+
+    /// Attach the Bloom filters of the seeds and peers of the swarm, to reply to a scrape.
+    pub fn with_scrape_filters(
+        mut self,
+        seeds: impl Into<BencodeString>,
+        peers: impl Into<BencodeString>,
+    ) -> Self {
+        self.seeds_filter = Some(seeds.into());
+        self.peers_filter = Some(peers.into());
+        self
+    }
 }
 
 impl<I: CompactNodeInfo, P: CompactPeerInfo> ToArguments for GetPeers<I, P> {
@@ -333,18 +728,36 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> ToArguments for GetPeers<I, P> {
         if let Some(token) = &self.token {
             arguments.insert("token".into(), BencodeValue::ByteString(token.clone()));
         }
-        let mut nodes = Vec::new();
-        for node in &self.nodes {
-            nodes.extend(node.write_compact_node_info());
+        // Both fields are optional: a reply holds the peers, the closest nodes, or both.
+        if self.shape.is_some_and(GetPeersShape::has_nodes) {
+            let mut nodes = Vec::new();
+            for node in &self.nodes {
+                nodes.extend(node.write_compact_node_info());
+            }
+            arguments.insert("nodes".into(), BencodeValue::ByteString(nodes.into()));
         }
-        arguments.insert("nodes".into(), BencodeValue::ByteString(nodes.into()));
-        let mut peers = Vec::new();
-        for peer in &self.peers {
-            peers.extend(peer.write_compact_peer_info());
+        if self.shape.is_some_and(GetPeersShape::has_values) {
+            // NOTE: The peers field is actually named "values" in the KRPC protocol
+            // but we use "peers" for clarity. It is a list of compact peer infos.
+            let peers = self
+                .peers
+                .iter()
+                .map(|peer| BencodeValue::ByteString(peer.write_compact_peer_info().into()))
+                .collect();
+            arguments.insert("values".into(), BencodeValue::List(peers));
+        }
+        if let Some(seeds_filter) = &self.seeds_filter {
+            arguments.insert(
+                "BFsd".into(),
+                BencodeValue::ByteString(seeds_filter.clone()),
+            );
+        }
+        if let Some(peers_filter) = &self.peers_filter {
+            arguments.insert(
+                "BFpe".into(),
+                BencodeValue::ByteString(peers_filter.clone()),
+            );
         }
-        // NOTE: The peers field is actually named "values" in the KRPC protocol
-        // but we use "peers" for clarity.
-        arguments.insert("values".into(), BencodeValue::ByteString(peers.into()));
         arguments
     }
 }
@@ -372,6 +785,7 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> TryFromArguments for GetPeers<I, P>
         };
 
         // The nodes field is optional, so we need to check if it exists
+        let has_nodes = arguments.iter().any(|(key, _)| key.as_ref() == b"nodes");
         let node_list = {
             match arguments.iter().find(|(key, _)| key.as_ref() == b"nodes") {
                 Some((_, node_bencoded)) => match node_bencoded {
@@ -384,12 +798,12 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> TryFromArguments for GetPeers<I, P>
                                 Ok((bytes_read, node)) => {
                                     nodes.push(node);
                                     i += bytes_read;
-                                },
+                                }
                                 Err(_) => return Err("Invalid node info"),
                             }
                         }
                         nodes
-                    },
+                    }
                     _ => return Err("Invalid 'nodes' field"),
                 },
                 None => Vec::new(),
@@ -397,6 +811,7 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> TryFromArguments for GetPeers<I, P>
         };
 
         // The peers field is optional, so we need to check if it exists
+        let has_values = arguments.iter().any(|(key, _)| key.as_ref() == b"values");
         let peer_list = {
             match arguments.iter().find(|(key, _)| key.as_ref() == b"values") {
                 Some((_, peer_bencoded)) => match peer_bencoded {
@@ -406,32 +821,64 @@ impl<I: CompactNodeInfo, P: CompactPeerInfo> TryFromArguments for GetPeers<I, P>
                         for peer_info in peer_infos {
                             match peer_info {
                                 BencodeValue::ByteString(peer_info) => {
-                                    peers.push(P::try_read_compact_peer_info(peer_info.as_ref())
-                                        .map(|(è, peer)| peer)
-                                        .map_err(|_| "Invalid peer info")?);
-                                },
+                                    let peer_info = peer_info.as_ref();
+                                    if peer_info.len() != COMPACT_PEER_V4_LEN
+                                        && peer_info.len() != COMPACT_PEER_V6_LEN
+                                    {
+                                        return Err("Invalid peer info");
+                                    }
+                                    // Multi-homed nodes may return peers of both address families
+                                    // (6 and 18 bytes), skip the ones of the other family: too
+                                    // short for P, or not fully consumed by it.
+                                    match P::try_read_compact_peer_info(peer_info) {
+                                        Ok((read, peer)) if read == peer_info.len() => {
+                                            peers.push(peer)
+                                        }
+                                        _ => {}
+                                    }
+                                }
                                 _ => return Err("Invalid peer info"),
                             }
                         }
                         peers
-                    },
-                    _ => return Err("Invalid 'peers' field"),
+                    }
+                    _ => return Err("Invalid 'values' field"),
                 },
                 None => Vec::new(),
             }
         };
 
+        // The Bloom filters of BEP 33 are only sent in reply to a scrape. A malformed filter
+        // is skipped, the rest of the reply is still of use.
+        let mut filters = [None, None];
+        for (filter, name) in filters.iter_mut().zip([b"BFsd", b"BFpe"]) {
+            *filter = match arguments.iter().find(|(key, _)| key.as_ref() == name) {
+                Some((_, BencodeValue::ByteString(bytes)))
+                    if bytes.as_ref().len() == SCRAPE_FILTER_LEN =>
+                {
+                    Some(bytes.clone())
+                }
+                _ => None,
+            };
+        }
+        let [seeds_filter, peers_filter] = filters;
+
         Ok(GetPeers {
             id: I::NodeId::try_from(id.as_ref()).or(Err("Invalid NodeId"))?,
             token,
             nodes: node_list,
             peers: peer_list,
+            shape: GetPeersShape::of(has_nodes, has_values),
+            seeds_filter,
+            peers_filter,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::{Ipv6Addr, SocketAddrV6};
+
     use crate::krpc::tests::MockAddress;
 
     use super::super::tests::{MockNodeId, MockNodeInfo};
@@ -443,6 +890,7 @@ mod tests {
             "123",
             ResponseType::Ping(Ping {
                 id: MockNodeId(123),
+                extra: Vec::new(),
             }),
         );
         let mut bencoded = response.to_bencoded();
@@ -475,13 +923,15 @@ mod tests {
                 )]),
             ),
         ]);
-        let response = Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&bencoded).unwrap();
+        let response =
+            Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&bencoded).unwrap();
         assert_eq!(
             response,
             Response::new(
                 "123".to_string(),
                 ResponseType::Ping(Ping {
                     id: MockNodeId::try_from(b"12345678".as_ref()).unwrap(),
+                    extra: Vec::new(),
                 }),
             )
         );
@@ -491,18 +941,126 @@ mod tests {
     fn test_ping_response_from_spec_bencoded() {
         let bencoded_string = "d1:rd2:id8:12345678e1:t2:aa1:y1:re";
         let (_, bencoded) = crate::bencode::decode(&bencoded_string).unwrap();
-        let response = Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&bencoded).unwrap();
+        let response =
+            Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&bencoded).unwrap();
         assert_eq!(
             response,
             Response::new(
                 "aa".to_string(),
                 ResponseType::Ping(Ping {
                     id: MockNodeId::try_from(b"12345678".as_ref()).unwrap(),
+                    extra: Vec::new(),
                 }),
             )
         );
     }
 
+    #[test]
+    fn test_ping_response_extra_fields() {
+        let node = MockNodeInfo {
+            node_id: MockNodeId(128),
+            ip: [1, 2, 3, 4],
+            port: 1234,
+        };
+        // A reply with `nodes`, `p` and a top-level `ip`, as some clients send.
+        let bencoded_string: &[u8] = b"d2:ip6:\x01\x02\x03\x04\x04\xd21:rd2:id8:123456785:nodes14:\0\0\0\0\0\0\0\x80\x01\x02\x03\x04\x04\xd21:pi6881ee1:t2:aa1:y1:re";
+        let (_, bencoded) = crate::bencode::decode(&bencoded_string).unwrap();
+        let response =
+            Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&bencoded).unwrap();
+        let ping = match response.get_response_type() {
+            ResponseType::Ping(ping) => ping,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(ping.get_nodes::<MockNodeInfo>(), vec![node.clone()]);
+        assert_eq!(
+            ping.get_extra_field(b"p"),
+            Some(&BencodeValue::Integer(6881))
+        );
+        assert_eq!(ping.get_extra().len(), 2);
+        assert!(response.get_requester().is_some());
+
+        // Emitted back as they were received.
+        let emitted = Response::<MockNodeInfo, MockAddress>::new(
+            "aa",
+            ResponseType::Ping(
+                Ping::new(MockNodeId::try_from(b"12345678".as_ref()).unwrap())
+                    .with_extra("p", BencodeValue::Integer(6881))
+                    .with_nodes(&[node]),
+            ),
+        );
+        let reparsed =
+            Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&emitted.to_bencoded())
+                .unwrap();
+        assert_eq!(reparsed.get_response_type(), response.get_response_type());
+    }
+
+    #[test]
+    fn test_guess_type_ignores_extra_fields() {
+        let reply = |args: Vec<(BencodeString, BencodeValue)>| {
+            let mut r = vec![("id".into(), BencodeValue::ByteString("12345678".into()))];
+            r.extend(args);
+            BencodeValue::Dict(vec![
+                ("t".into(), BencodeValue::ByteString("aa".into())),
+                ("y".into(), BencodeValue::ByteString("r".into())),
+                ("r".into(), BencodeValue::Dict(r)),
+            ])
+        };
+        let guess = |args| {
+            Response::<MockNodeInfo, MockAddress>::try_guess_type_from_bencoded(&reply(args))
+                .unwrap()
+                .0
+        };
+        assert_eq!(
+            guess(vec![("p".into(), BencodeValue::Integer(6881))]),
+            QUERY_TYPE_PING
+        );
+        // Fields of the wrong type or shape are not taken for the ones of another method.
+        assert_eq!(
+            guess(vec![("nodes".into(), BencodeValue::ByteString("".into()))]),
+            QUERY_TYPE_PING
+        );
+        assert_eq!(
+            guess(vec![(
+                "nodes".into(),
+                BencodeValue::ByteString("abc".into())
+            )]),
+            QUERY_TYPE_PING
+        );
+        assert_eq!(
+            guess(vec![("values".into(), BencodeValue::Integer(1))]),
+            QUERY_TYPE_PING
+        );
+        assert_eq!(
+            guess(vec![(
+                "nodes".into(),
+                BencodeValue::ByteString(vec![0; 14].into())
+            )]),
+            QUERY_TYPE_FIND_NODE
+        );
+        assert_eq!(
+            guess(vec![(
+                "token".into(),
+                BencodeValue::ByteString("tk".into())
+            )]),
+            QUERY_TYPE_GET_PEERS
+        );
+    }
+
+    #[test]
+    fn test_get_peers_query_type() {
+        let response = Response::<MockNodeInfo, MockAddress>::new_get_peers(
+            "aa",
+            MockNodeId(1),
+            None,
+            Vec::new(),
+            Vec::new(),
+        );
+        assert_eq!(
+            response.get_response_type().get_query_type(),
+            QUERY_TYPE_GET_PEERS
+        );
+    }
+
     #[test]
     fn test_findpeer_response_to_bencoded() {
         let response = Response::<MockNodeInfo, MockAddress>::new(
@@ -566,17 +1124,18 @@ mod tests {
                     ),
                     (
                         "token".into(),
-                        BencodeValue::ByteString(vec![0,1,2,3].into()),
+                        BencodeValue::ByteString(vec![0, 1, 2, 3].into()),
                     ),
                     (
                         "nodes".into(),
-                        BencodeValue::ByteString( vec![
-                            /* Node 1 */
-                            0, 0, 0, 0, 0, 0, 0, 128, 1, 2, 3, 4, 4, 210, 
-                            /* Node 2 */
-                            0, 0, 0, 0, 0, 0, 0, 129, 5, 6, 7, 8, 22, 46,
-                        ]
-                        .into()),
+                        BencodeValue::ByteString(
+                            vec![
+                                /* Node 1 */
+                                0, 0, 0, 0, 0, 0, 0, 128, 1, 2, 3, 4, 4, 210, /* Node 2 */
+                                0, 0, 0, 0, 0, 0, 0, 129, 5, 6, 7, 8, 22, 46,
+                            ]
+                            .into(),
+                        ),
                     ),
                     (
                         "values".into(),
@@ -588,7 +1147,8 @@ mod tests {
                 ]),
             ),
         ]);
-        let response = Response::<MockNodeInfo, MockAddress>::try_from_getpeers_bencoded(&bencoded).unwrap();
+        let response =
+            Response::<MockNodeInfo, MockAddress>::try_from_getpeers_bencoded(&bencoded).unwrap();
         assert_eq!(
             response,
             Response::new(
@@ -618,8 +1178,266 @@ mod tests {
                             port: 5678,
                         },
                     ],
+                    shape: Some(GetPeersShape::Both),
+                    seeds_filter: None,
+                    peers_filter: None,
                 }),
             )
         );
     }
+
+    #[test]
+    fn test_scrape_filters_roundtrip() {
+        let response = Response::<MockNodeInfo, MockAddress>::new_get_peers(
+            "t1",
+            MockNodeId(1),
+            Some(b"tok".as_ref().into()),
+            Vec::new(),
+            Vec::new(),
+        );
+        let response = match response.get_response_type() {
+            ResponseType::GetPeers(get_peers) => Response::new(
+                "t1",
+                ResponseType::GetPeers(
+                    get_peers
+                        .clone()
+                        .with_scrape_filters(vec![1u8; 256], vec![2u8; 256]),
+                ),
+            ),
+            _ => unreachable!(),
+        };
+        let parsed = Response::<MockNodeInfo, MockAddress>::try_from_getpeers_bencoded(
+            &response.to_bencoded(),
+        )
+        .unwrap();
+        assert_eq!(parsed, response);
+        match parsed.get_response_type() {
+            ResponseType::GetPeers(get_peers) => {
+                assert_eq!(get_peers.get_seeds_filter(), Some(&[1u8; 256][..]));
+                assert_eq!(get_peers.get_peers_filter(), Some(&[2u8; 256][..]));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_malformed_scrape_filters_are_skipped() {
+        let bencoded = BencodeValue::Dict(vec![
+            ("t".into(), BencodeValue::ByteString("t1".into())),
+            ("y".into(), BencodeValue::ByteString("r".into())),
+            (
+                "r".into(),
+                BencodeValue::Dict(vec![
+                    ("BFpe".into(), BencodeValue::ByteString(vec![2; 12].into())),
+                    ("BFsd".into(), BencodeValue::Integer(1)),
+                    (
+                        "id".into(),
+                        BencodeValue::ByteString(vec![0, 0, 0, 0, 0, 0, 0, 1].into()),
+                    ),
+                    ("token".into(), BencodeValue::ByteString("tok".into())),
+                ]),
+            ),
+        ]);
+        let parsed =
+            Response::<MockNodeInfo, MockAddress>::try_from_getpeers_bencoded(&bencoded).unwrap();
+        match parsed.get_response_type() {
+            ResponseType::GetPeers(get_peers) => {
+                assert_eq!(get_peers.get_id(), &MockNodeId(1));
+                assert!(get_peers.get_token().is_some());
+                assert_eq!(get_peers.get_seeds_filter(), None);
+                assert_eq!(get_peers.get_peers_filter(), None);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_get_peers_skips_other_address_family() {
+        let bencoded = BencodeValue::Dict(vec![
+            ("t".into(), BencodeValue::ByteString("123".into())),
+            ("y".into(), BencodeValue::ByteString("r".into())),
+            (
+                "r".into(),
+                BencodeValue::Dict(vec![
+                    (
+                        "id".into(),
+                        BencodeValue::ByteString(vec![0, 0, 0, 0, 0, 0, 0, 123].into()),
+                    ),
+                    (
+                        "token".into(),
+                        BencodeValue::ByteString(vec![0, 1, 2, 3].into()),
+                    ),
+                    (
+                        "values".into(),
+                        BencodeValue::List(vec![
+                            BencodeValue::ByteString(vec![1, 2, 3, 4, 4, 210].into()),
+                            /* IPv6 peer, 2001:db8::1 port 1234 */
+                            BencodeValue::ByteString(
+                                vec![
+                                    0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4,
+                                    210,
+                                ]
+                                .into(),
+                            ),
+                        ]),
+                    ),
+                ]),
+            ),
+        ]);
+        let response =
+            Response::<MockNodeInfo, MockAddress>::try_from_getpeers_bencoded(&bencoded).unwrap();
+        match response.get_response_type() {
+            ResponseType::GetPeers(get_peers) => assert_eq!(
+                get_peers.get_peers(),
+                &[MockAddress {
+                    ip: [1, 2, 3, 4],
+                    port: 1234,
+                }]
+            ),
+            _ => panic!("Expected a get_peers response"),
+        }
+
+        let response =
+            Response::<MockNodeInfo, SocketAddrV6>::try_from_getpeers_bencoded(&bencoded).unwrap();
+        match response.get_response_type() {
+            ResponseType::GetPeers(get_peers) => assert_eq!(
+                get_peers.get_peers(),
+                &[SocketAddrV6::new(
+                    Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+                    1234,
+                    0,
+                    0
+                )]
+            ),
+            _ => panic!("Expected a get_peers response"),
+        }
+    }
+
+    #[test]
+    fn test_get_peers_invalid_values() {
+        let get_peers = |values: BencodeValue| {
+            BencodeValue::Dict(vec![
+                ("t".into(), BencodeValue::ByteString("123".into())),
+                ("y".into(), BencodeValue::ByteString("r".into())),
+                (
+                    "r".into(),
+                    BencodeValue::Dict(vec![
+                        (
+                            "id".into(),
+                            BencodeValue::ByteString(vec![0, 0, 0, 0, 0, 0, 0, 123].into()),
+                        ),
+                        ("values".into(), values),
+                    ]),
+                ),
+            ])
+        };
+        let truncated = get_peers(BencodeValue::List(vec![BencodeValue::ByteString(
+            vec![1, 2, 3, 4, 4, 210, 0].into(),
+        )]));
+        assert_eq!(
+            Response::<MockNodeInfo, MockAddress>::try_from_getpeers_bencoded(&truncated).err(),
+            Some("Invalid peer info")
+        );
+        let not_a_list = get_peers(BencodeValue::ByteString(vec![1, 2, 3, 4, 4, 210].into()));
+        assert_eq!(
+            Response::<MockNodeInfo, MockAddress>::try_from_getpeers_bencoded(&not_a_list).err(),
+            Some("Invalid 'values' field")
+        );
+    }
+
+    #[test]
+    fn test_get_peers_shapes() {
+        type TestResponse = Response<MockNodeInfo, MockAddress>;
+        let peer = MockAddress {
+            ip: [1, 2, 3, 4],
+            port: 1234,
+        };
+        let shape_of = |response: &TestResponse| match response.get_response_type() {
+            ResponseType::GetPeers(get_peers) => get_peers.get_shape(),
+            _ => panic!("Expected a get_peers response"),
+        };
+        let values = TestResponse::new_get_peers("t", MockNodeId(1), None, vec![], vec![peer]);
+        let nodes = TestResponse::new_get_peers("t", MockNodeId(1), None, vec![], vec![]);
+        assert_eq!(shape_of(&values), Some(GetPeersShape::Values));
+        assert_eq!(shape_of(&nodes), None);
+
+        // An empty field is still sent, and told apart from a missing one when parsed.
+        for shape in [
+            GetPeersShape::Values,
+            GetPeersShape::Nodes,
+            GetPeersShape::Both,
+        ] {
+            let response = match nodes.get_response_type() {
+                ResponseType::GetPeers(get_peers) => Response::new(
+                    "t",
+                    ResponseType::GetPeers(get_peers.clone().with_shape(shape).unwrap()),
+                ),
+                _ => unreachable!(),
+            };
+            let bencoded = response.to_bencoded();
+            let parsed = TestResponse::try_from_getpeers_bencoded(&bencoded).unwrap();
+            assert_eq!(shape_of(&parsed), Some(shape));
+            assert_eq!(parsed, response);
+        }
+        let parsed = TestResponse::try_from_getpeers_bencoded(&nodes.to_bencoded()).unwrap();
+        assert_eq!(shape_of(&parsed), None);
+
+        // A shape cannot drop the peers of the reply.
+        match values.get_response_type() {
+            ResponseType::GetPeers(get_peers) => {
+                assert!(get_peers.clone().with_shape(GetPeersShape::Nodes).is_err());
+                assert!(get_peers.clone().with_shape(GetPeersShape::Both).is_ok());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_custom_response_roundtrip() {
+        let args: BencodeDict = vec![
+            ("id".into(), BencodeValue::ByteString("12345678".into())),
+            ("v".into(), BencodeValue::ByteString("XX01".into())),
+        ];
+        let response = Response::<MockNodeInfo, MockAddress>::custom("aa", args.clone());
+        let bencoded = response.to_bencoded();
+        let parsed =
+            Response::<MockNodeInfo, MockAddress>::try_from_raw_bencoded(&bencoded).unwrap();
+        assert_eq!(parsed.get_transaction_id().as_ref(), b"aa");
+        match parsed.get_response_type() {
+            ResponseType::Raw(parsed_args) => {
+                let mut parsed_args = parsed_args.clone();
+                parsed_args.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+                assert_eq!(parsed_args, args);
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_requester_ip_field() {
+        let requester = MockAddress {
+            ip: [192, 0, 2, 1],
+            port: 6881,
+        };
+        let response = Response::<MockNodeInfo, MockAddress>::new_ping("aa", MockNodeId(1))
+            .with_requester(requester.clone());
+        let bencoded = response.to_bencoded();
+        let parsed =
+            Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&bencoded).unwrap();
+        assert_eq!(parsed.get_requester(), Some(&requester));
+        assert_eq!(parsed, response);
+
+        // An address of another family is ignored.
+        let mut bencoded = bencoded;
+        if let BencodeValue::Dict(dict) = &mut bencoded {
+            for (key, value) in dict.iter_mut() {
+                if key.as_ref() == b"ip" {
+                    *value = BencodeValue::ByteString(vec![0; 18].into());
+                }
+            }
+        }
+        let parsed =
+            Response::<MockNodeInfo, MockAddress>::try_from_ping_bencoded(&bencoded).unwrap();
+        assert_eq!(parsed.get_requester(), None);
+    }
 }
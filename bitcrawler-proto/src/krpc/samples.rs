@@ -0,0 +1,318 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    bencode::{BencodeDict, BencodeValue},
+    consts::{ID_LEN, MAX_SAMPLE_INTERVAL},
+    kademlia::NodeId,
+};
+
+use super::{TryFromArguments, TryFromArgumentsError};
+
+/// A deviation from BEP 51 found while parsing a [`SampleInfohashes`] reply.
+///
+/// The parser works around each of them, they tell what was salvaged and which clients send
+/// what.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SampleDeviation {
+    /// No `interval` field.
+    MissingInterval,
+    /// An `interval` other than an integer of 0 to 21600 seconds.
+    InvalidInterval,
+    /// No `num` field.
+    MissingNum,
+    /// A `num` other than a non-negative integer.
+    InvalidNum,
+    /// A `num` lower than the number of samples in the reply.
+    NumBelowSamples,
+    /// No `samples` field.
+    MissingSamples,
+    /// `samples` neither a string nor a list.
+    InvalidSamples,
+    /// `samples` is a list of strings instead of one string of concatenated info hashes.
+    SamplesAsList,
+    /// `samples` has bytes past its last complete 20-byte info hash (or list items of another
+    /// length), dropped.
+    TruncatedSamples,
+}
+
+impl SampleDeviation {
+    /// Every deviation, in declaration order.
+    pub const ALL: [SampleDeviation; 9] = [
+        SampleDeviation::MissingInterval,
+        SampleDeviation::InvalidInterval,
+        SampleDeviation::MissingNum,
+        SampleDeviation::InvalidNum,
+        SampleDeviation::NumBelowSamples,
+        SampleDeviation::MissingSamples,
+        SampleDeviation::InvalidSamples,
+        SampleDeviation::SamplesAsList,
+        SampleDeviation::TruncatedSamples,
+    ];
+
+    /// Get the name of the deviation, in kebab case.
+    pub fn name(self) -> &'static str {
+        match self {
+            SampleDeviation::MissingInterval => "missing-interval",
+            SampleDeviation::InvalidInterval => "invalid-interval",
+            SampleDeviation::MissingNum => "missing-num",
+            SampleDeviation::InvalidNum => "invalid-num",
+            SampleDeviation::NumBelowSamples => "num-below-samples",
+            SampleDeviation::MissingSamples => "missing-samples",
+            SampleDeviation::InvalidSamples => "invalid-samples",
+            SampleDeviation::SamplesAsList => "samples-as-list",
+            SampleDeviation::TruncatedSamples => "truncated-samples",
+        }
+    }
+}
+
+impl Display for SampleDeviation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A reply to a `sample_infohashes` query (BEP 51), parsed tolerantly.
+///
+/// The replies seen on the network do not all follow the BEP: the `interval` or `num` may be
+/// missing, and `samples` may end with a partial info hash or come as a list. Only a missing
+/// or invalid `id` fails the parsing: every other deviation is worked around, the complete
+/// info hashes salvaged, and the deviation recorded in [`SampleInfohashes::deviations`].
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::{
+///     bencode::BencodeValue,
+///     kademlia::Id160,
+///     krpc::{SampleDeviation, SampleInfohashes, TryFromArguments},
+/// };
+///
+/// // Two info hashes and 5 trailing bytes, without `interval`.
+/// let arguments = vec![
+///     ("id".into(), BencodeValue::ByteString(vec![1; 20].into())),
+///     ("num".into(), BencodeValue::Integer(10)),
+///     ("samples".into(), BencodeValue::ByteString(vec![2; 45].into())),
+/// ];
+/// let reply = SampleInfohashes::<Id160>::try_from_arguments(&arguments).unwrap();
+/// assert_eq!(reply.get_samples(), [Id160([2; 20]); 2]);
+/// assert_eq!(reply.get_num(), Some(10));
+/// assert_eq!(
+///     reply.deviations(),
+///     [SampleDeviation::MissingInterval, SampleDeviation::TruncatedSamples]
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleInfohashes<N: NodeId> {
+    id: N,
+    interval: Option<u32>,
+    num: Option<u64>,
+    samples: Vec<N>,
+    deviations: Vec<SampleDeviation>,
+}
+
+impl<N: NodeId> SampleInfohashes<N> {
+    pub fn get_id(&self) -> &N {
+        &self.id
+    }
+
+    /// Get the time to wait before querying the node again, in seconds, `None` if missing or
+    /// invalid.
+    pub fn get_interval(&self) -> Option<u32> {
+        self.interval
+    }
+
+    /// Get the number of info hashes the node stores, `None` if missing or invalid.
+    pub fn get_num(&self) -> Option<u64> {
+        self.num
+    }
+
+    /// Get the complete info hashes of the reply.
+    pub fn get_samples(&self) -> &[N] {
+        &self.samples
+    }
+
+    /// Get the deviations from BEP 51 worked around, in the order of the fields, empty for a
+    /// reply following it.
+    pub fn deviations(&self) -> &[SampleDeviation] {
+        &self.deviations
+    }
+
+    /// Check if the reply follows BEP 51.
+    pub fn is_conforming(&self) -> bool {
+        self.deviations.is_empty()
+    }
+}
+
+impl<N: NodeId> TryFromArguments for SampleInfohashes<N> {
+    fn try_from_arguments(arguments: &BencodeDict) -> Result<Self, TryFromArgumentsError> {
+        let field = |name: &[u8]| {
+            arguments
+                .iter()
+                .find(|(key, _)| key.as_ref() == name)
+                .map(|(_, value)| value)
+        };
+        let id = match field(b"id").ok_or("Missing 'id' field")? {
+            BencodeValue::ByteString(id) => N::try_from(id.as_ref()).or(Err("Invalid NodeId"))?,
+            _ => return Err("Invalid 'id' field"),
+        };
+        let mut deviations = Vec::new();
+
+        let interval = match field(b"interval").map(BencodeValue::as_integer) {
+            Some(Some(interval)) if (0..=i128::from(MAX_SAMPLE_INTERVAL)).contains(&interval) => {
+                Some(interval as u32)
+            }
+            Some(_) => {
+                deviations.push(SampleDeviation::InvalidInterval);
+                None
+            }
+            None => {
+                deviations.push(SampleDeviation::MissingInterval);
+                None
+            }
+        };
+
+        let num = match field(b"num").map(BencodeValue::as_integer) {
+            Some(Some(num)) if num >= 0 => Some(u64::try_from(num).unwrap_or(u64::MAX)),
+            Some(_) => {
+                deviations.push(SampleDeviation::InvalidNum);
+                None
+            }
+            None => {
+                deviations.push(SampleDeviation::MissingNum);
+                None
+            }
+        };
+
+        let mut truncated = false;
+        let samples = match field(b"samples") {
+            Some(BencodeValue::ByteString(samples)) => {
+                let chunks = samples.as_ref().chunks_exact(ID_LEN);
+                truncated = !chunks.remainder().is_empty();
+                chunks
+                    .filter_map(|sample| N::try_from(sample).ok())
+                    .collect()
+            }
+            Some(BencodeValue::List(samples)) => {
+                deviations.push(SampleDeviation::SamplesAsList);
+                samples
+                    .iter()
+                    .filter_map(|sample| match sample {
+                        BencodeValue::ByteString(sample) if sample.as_ref().len() == ID_LEN => {
+                            N::try_from(sample.as_ref()).ok()
+                        }
+                        _ => {
+                            truncated = true;
+                            None
+                        }
+                    })
+                    .collect()
+            }
+            Some(_) => {
+                deviations.push(SampleDeviation::InvalidSamples);
+                Vec::new()
+            }
+            None => {
+                deviations.push(SampleDeviation::MissingSamples);
+                Vec::new()
+            }
+        };
+        if truncated {
+            deviations.push(SampleDeviation::TruncatedSamples);
+        }
+        if num.is_some_and(|num| num < samples.len() as u64) {
+            deviations.push(SampleDeviation::NumBelowSamples);
+        }
+
+        Ok(SampleInfohashes {
+            id,
+            interval,
+            num,
+            samples,
+            deviations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kademlia::Id160;
+
+    fn parse(fields: Vec<(&str, BencodeValue)>) -> Result<SampleInfohashes<Id160>, &'static str> {
+        let mut arguments: BencodeDict = vec![("id".into(), string(&[1; 20]))];
+        arguments.extend(fields.into_iter().map(|(key, value)| (key.into(), value)));
+        SampleInfohashes::try_from_arguments(&arguments)
+    }
+
+    fn string(bytes: &[u8]) -> BencodeValue {
+        BencodeValue::ByteString(bytes.to_vec().into())
+    }
+
+    #[test]
+    fn test_sample_infohashes_deviations() {
+        use SampleDeviation::*;
+
+        let conforming = parse(vec![
+            ("interval", BencodeValue::Integer(60)),
+            ("num", BencodeValue::Integer(2)),
+            ("samples", string(&[[2; 20], [3; 20]].concat())),
+        ])
+        .unwrap();
+        assert!(conforming.is_conforming());
+        assert_eq!(conforming.get_id(), &Id160([1; 20]));
+        assert_eq!(conforming.get_interval(), Some(60));
+        assert_eq!(conforming.get_samples(), [Id160([2; 20]), Id160([3; 20])]);
+
+        // Every field missing but the id.
+        let empty = parse(vec![]).unwrap();
+        assert_eq!(
+            empty.deviations(),
+            [MissingInterval, MissingNum, MissingSamples]
+        );
+        assert!(empty.get_samples().is_empty());
+
+        // Invalid fields, a partial sample and fewer stored than sampled.
+        let sloppy = parse(vec![
+            ("interval", BencodeValue::Integer(100_000)),
+            ("num", BencodeValue::Integer(1)),
+            ("samples", string(&[[2; 20], [3; 20]].concat()[..39])),
+        ])
+        .unwrap();
+        assert_eq!(sloppy.get_interval(), None);
+        assert_eq!(sloppy.get_samples(), [Id160([2; 20])]);
+        assert_eq!(sloppy.deviations(), [InvalidInterval, TruncatedSamples]);
+        let sloppy = parse(vec![
+            ("interval", string(b"60")),
+            ("num", BencodeValue::Integer(-1)),
+            ("samples", BencodeValue::Integer(0)),
+        ])
+        .unwrap();
+        assert_eq!(
+            sloppy.deviations(),
+            [InvalidInterval, InvalidNum, InvalidSamples]
+        );
+
+        // A list of samples, with an item too short.
+        let list = parse(vec![
+            ("interval", BencodeValue::Integer(0)),
+            ("num", BencodeValue::Integer(1)),
+            (
+                "samples",
+                BencodeValue::from_list(vec![string(&[2; 20]), string(&[3; 19]), string(&[4; 20])]),
+            ),
+        ])
+        .unwrap();
+        assert_eq!(list.get_samples(), [Id160([2; 20]), Id160([4; 20])]);
+        assert_eq!(
+            list.deviations(),
+            [SamplesAsList, TruncatedSamples, NumBelowSamples]
+        );
+        assert_eq!(list.deviations()[2].to_string(), "num-below-samples");
+
+        // The id is the only required field.
+        let arguments = vec![("samples".into(), string(&[2; 20]))];
+        assert!(SampleInfohashes::<Id160>::try_from_arguments(&arguments).is_err());
+        let arguments = vec![("id".into(), string(&[1; 19]))];
+        assert!(SampleInfohashes::<Id160>::try_from_arguments(&arguments).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::ops::Range;
+
+use crate::{bencode, kademlia::NodeId};
+
+use super::{MessageOptions, Query};
+
+/// A query encoded once, whose transaction id and target are patched in place for each packet.
+///
+/// Most of the bytes of the queries a node sends are the same from one query to the next (its
+/// id, its options, the method): only the transaction id and the target change. Patching them
+/// into the pre-encoded bytes avoids building and bencoding a [`Query`] for every packet, e.g.
+/// when pinging a large number of nodes.
+///
+/// The transaction ids must all have the length given to the template.
+///
+/// # Examples
+///
+/// ```rust
+/// use bitcrawler_proto::{
+///     bencode,
+///     kademlia::Id160,
+///     krpc::{MessageOptions, Query, QueryTemplate},
+/// };
+///
+/// let id = Id160([1; 20]);
+/// let mut template = QueryTemplate::find_node(id, 2, &MessageOptions::default());
+/// template.set_transaction_id(b"aa");
+/// template.set_target(&[2; 20]);
+/// let query = Query::new_find_node("aa", id, Id160([2; 20]));
+/// assert_eq!(template.as_bytes(), bencode::encode(&query.to_bencoded()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryTemplate {
+    buffer: Vec<u8>,
+    transaction_id: Range<usize>,
+    target: Option<Range<usize>>,
+}
+
+impl QueryTemplate {
+    /// Template of the `ping` queries of `id`, with transaction ids of `transaction_id_len`
+    /// bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `transaction_id_len` is 0.
+    pub fn ping<N: NodeId>(id: N, transaction_id_len: usize, options: &MessageOptions) -> Self {
+        QueryTemplate::new(id, transaction_id_len, options, |tid, id, _| {
+            Query::new_ping(tid, id)
+        })
+    }
+
+    /// Template of the `find_node` queries of `id`, with transaction ids of `transaction_id_len`
+    /// bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `transaction_id_len` is 0.
+    pub fn find_node<N: NodeId>(
+        id: N,
+        transaction_id_len: usize,
+        options: &MessageOptions,
+    ) -> Self {
+        QueryTemplate::new(id, transaction_id_len, options, Query::new_find_node)
+    }
+
+    /// Template of the `get_peers` queries of `id`, with transaction ids of `transaction_id_len`
+    /// bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `transaction_id_len` is 0.
+    pub fn get_peers<N: NodeId>(
+        id: N,
+        transaction_id_len: usize,
+        options: &MessageOptions,
+    ) -> Self {
+        QueryTemplate::new(id, transaction_id_len, options, Query::new_get_peers)
+    }
+
+    /// Encode the query with placeholders, and locate them by changing them one at a time: the
+    /// bytes that change are the ones to patch.
+    fn new<N, F>(id: N, transaction_id_len: usize, options: &MessageOptions, build: F) -> Self
+    where
+        N: NodeId,
+        F: Fn(Vec<u8>, N, N) -> Query<N>,
+    {
+        assert!(transaction_id_len > 0, "empty transaction ids");
+        let id_len = Into::<Vec<u8>>::into(id.clone()).len();
+        let placeholder = |byte: u8| match N::try_from(&vec![byte; id_len][..]) {
+            Ok(target) => target,
+            Err(_) => unreachable!("ids of the length of the node id are valid"),
+        };
+        let encode = |tid: u8, target: u8| {
+            let query = build(
+                vec![tid; transaction_id_len],
+                id.clone(),
+                placeholder(target),
+            );
+            bencode::encode(&query.with_options(options.clone()).to_bencoded())
+        };
+        let buffer = encode(0x00, 0x00);
+        let transaction_id = changed(&buffer, &encode(0xff, 0x00))
+            .filter(|range| range.len() == transaction_id_len)
+            .expect("the transaction id is encoded as is");
+        let target = changed(&buffer, &encode(0x00, 0xff));
+        if let Some(target) = &target {
+            assert_eq!(target.len(), id_len, "the target is encoded as is");
+        }
+        QueryTemplate {
+            buffer,
+            transaction_id,
+            target,
+        }
+    }
+
+    /// Get the length of the transaction ids of the template.
+    pub fn transaction_id_len(&self) -> usize {
+        self.transaction_id.len()
+    }
+
+    /// Check if the query has a target (`find_node`) or an info hash (`get_peers`) to patch.
+    pub fn has_target(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Set the transaction id of the query.
+    ///
+    /// # Panics
+    ///
+    /// If `transaction_id` is not of the length of the template.
+    pub fn set_transaction_id(&mut self, transaction_id: &[u8]) {
+        self.buffer[self.transaction_id.clone()].copy_from_slice(transaction_id);
+    }
+
+    /// Set the target of a `find_node` query, or the info hash of a `get_peers` query.
+    ///
+    /// # Panics
+    ///
+    /// If the query has no target, or if `target` is not of the length of the node id.
+    pub fn set_target(&mut self, target: &[u8]) {
+        let range = self.target.clone().expect("query without target");
+        self.buffer[range].copy_from_slice(target);
+    }
+
+    /// Get the encoded query.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+/// Get the range of the bytes that differ between two buffers of the same length.
+fn changed(a: &[u8], b: &[u8]) -> Option<Range<usize>> {
+    assert_eq!(a.len(), b.len(), "placeholders of the same length");
+    let start = a.iter().zip(b).position(|(a, b)| a != b)?;
+    let end = a.len()
+        - a.iter()
+            .rev()
+            .zip(b.iter().rev())
+            .position(|(a, b)| a != b)?;
+    Some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bencode::BencodeValue, kademlia::Id160};
+
+    #[test]
+    fn test_template_matches_encoder() {
+        let id = Id160(*b"abcdefghij0123456789");
+        let options = MessageOptions {
+            version: Some(b"bc\x00\x01".as_ref().into()),
+            read_only: true,
+            extra: vec![("zz".into(), BencodeValue::Integer(1))],
+        };
+        let mut ping = QueryTemplate::ping(id, 4, &options);
+        let mut find_node = QueryTemplate::find_node(id, 4, &options);
+        let mut get_peers = QueryTemplate::get_peers(id, 4, &MessageOptions::default());
+        assert!(!ping.has_target());
+        assert!(find_node.has_target() && get_peers.has_target());
+        assert_eq!(ping.transaction_id_len(), 4);
+
+        // Transaction ids and targets made of the bytes of the other fields.
+        let tids: [&[u8; 4]; 3] = [b"1:t4", b"\0\0\0\0", b"abcd"];
+        let targets = [Id160([0xff; 20]), id, Id160(*b"e1:q4:ping1:t4:aaaae")];
+        for (tid, target) in tids.into_iter().zip(targets) {
+            let expected = |query: Query<Id160>, options: &MessageOptions| {
+                bencode::encode(&query.with_options(options.clone()).to_bencoded())
+            };
+            ping.set_transaction_id(tid);
+            assert_eq!(
+                ping.as_bytes(),
+                expected(Query::new_ping(tid.to_vec(), id), &options)
+            );
+            find_node.set_transaction_id(tid);
+            find_node.set_target(target.as_bytes());
+            assert_eq!(
+                find_node.as_bytes(),
+                expected(Query::new_find_node(tid.to_vec(), id, target), &options)
+            );
+            get_peers.set_transaction_id(tid);
+            get_peers.set_target(target.as_bytes());
+            assert_eq!(
+                get_peers.as_bytes(),
+                expected(
+                    Query::new_get_peers(tid.to_vec(), id, target),
+                    &MessageOptions::default()
+                )
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_transaction_id_length() {
+        let mut template = QueryTemplate::ping(Id160([0; 20]), 2, &MessageOptions::default());
+        template.set_transaction_id(b"abc");
+    }
+}
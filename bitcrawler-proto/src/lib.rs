@@ -1,3 +1,7 @@
 pub mod bencode;
+pub mod consts;
+pub mod hex;
+#[cfg(feature = "krpc")]
 pub mod kademlia;
+#[cfg(feature = "krpc")]
 pub mod krpc;
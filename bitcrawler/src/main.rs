@@ -1,334 +1,1030 @@
 use std::{
-    collections::HashSet,
-    fmt::Display,
-    fs::File,
-    io::{BufRead, BufReader, Write},
-    net::{Ipv4Addr, UdpSocket},
+    env, fs, io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
     thread::sleep,
-    time::{Duration, Instant, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use bitcrawler_proto::{
-    bencode,
-    kademlia::{NodeId, Xorable},
-    krpc::{
-        Query, Response, ResponseType, node_info,
-        peer_info::CompactPeerInfo,
-        query::{Ping, QUERY_TYPE_FIND_NODE, QUERY_TYPE_GET_PEERS, QUERY_TYPE_PING},
+use anyhow::{Context, anyhow, bail};
+use bitcrawler_core::{
+    admin::AdminServer,
+    crawler::{
+        CrawlSummary, Crawler, CrawlerConfig, CrawlerHandle, DEFAULT_BOOTSTRAP_NODES,
+        MAX_PEER_REPORTERS, OverlayConfig, OverlaySecret, PeerConfidenceConfig, ProbeConfig,
+        SpoofingConfig, import_dht_state,
     },
+    limits::{OptOutList, TrafficLimits},
+    node::{DhtNode, DuplicatePolicy, NodeConfig, ReachabilityConfig, reachability_test},
+    pipeline::{OverflowPolicy, QueueConfig},
+    proto::kademlia::{Domain, Id160},
+    sink::{NodeArchive, NodeArchiveSink, QueuedSink, Sink},
+    stats::StatsDatabase,
+    transport::{MAX_DSCP, ReplayConfig, ReplaySpeed, SocketConfig},
+    watchdog::{Watchdog, WatchdogConfig},
 };
 
-const DHT_BOOTSTRAP: (&str, u16) = ("77.234.80.66", 29822);
-const DHT_PORT: u16 = 6881;
-const NODE_ID: BittorrentNodeId = BittorrentNodeId([
+const NODE_ID: Id160 = Id160([
     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 99, 98, 97, 96, 95, 94, 93, 92, 91, 90,
 ]);
+const DEFAULT_BIND: &str = "0.0.0.0:6881";
+const DEFAULT_NODE_LIST: &str = "/tmp/nodes.bin";
+const USAGE: &str =
+    "Usage: bitcrawler [--bind <ip:port>] [--node-list <path>] [--malformed-dump <path>] [--self-test]
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct BittorrentNodeId(pub [u8; 20]);
+Crawls the BitTorrent DHT, printing progress every few seconds.
 
-impl Xorable for BittorrentNodeId {
-    fn cmp_distance(&self, other: &Self) -> std::cmp::Ordering {
-        return self.0.cmp(&other.0);
-    }
+On SIGHUP, the options (and the files they name, e.g. the --opt-out list) are read again,
+and the traffic limits, the opt-out list, the stop conditions and the node list are applied
+without restarting the crawl. On SIGTERM or SIGINT, the crawl stops cleanly (a second
+signal exits at once).
 
-    fn bucket_index(&self, other: &Self) -> usize {
-        for i in 0..self.0.len() {
-            if self.0[i] != other.0[i] {
-                return i;
-            }
-        }
-        return self.0.len();
-    }
-}
+Options:
+  --config <path>       Read options from this file, one per line as on the command line
+                        without the leading dashes (e.g. `max-bandwidth = 100000`, or
+                        `self-test` for a flag); `#` starts a comment
+  --bind <ip:port>      Address to listen on (default: 0.0.0.0:6881)
+  --interface <name>    Send and receive through this network interface only (Linux)
+  --sockets <n>         Spread the queries over n sockets, bound to consecutive ports
+                        from the --bind port (default: 1)
+  --dscp <n>            Mark the datagrams sent with this DSCP code point (0 to 63)
+  --ttl <n>             Send the datagrams with this time to live (hop limit in IPv6)
+  --node-list <path>    Archive of the nodes to start from, updated with the discovered
+                        nodes (default: /tmp/nodes.bin)
+  --convert-node-list <path>
+                        Add the nodes of this text node list (one ip:port per line) to
+                        the --node-list archive, then exit
+  --import-state <path> Start from the nodes of the DHT state file of another client too
+                        (libtorrent's DHT state or Transmission's dht.dat, detected from
+                        the content); repeat for several files
+  --malformed-dump <path>
+                        Write the last malformed datagrams received to this file
+  --bootstrap-history <path>
+                        Keep the success of the bootstrap nodes across runs in this
+                        file, to ping the reliable and fast ones first
+  --identity-history <path>
+                        Keep the node ids each address reported across runs in this
+                        file, to keep distrusting the addresses changing ids too often
+  --overlay <name>      Crawl the private DHT of this name instead of the public one,
+                        from its own bootstrap nodes; requires a --node-list of its own
+                        (the archive records the overlay), and no --import-state
+  --overlay-bootstrap <host:port>
+                        Bootstrap node of the --overlay; repeat for several nodes
+  --overlay-secret-file <path>
+                        Secret shared by the nodes of the --overlay, read from this file:
+                        the messages are tagged with it, and the nodes without it ignored
+  --stats-db <path>     Record per-minute statistics of the crawl (traffic, discoveries,
+                        client versions) in this SQLite database, one run per crawl
+  --opt-out <path>      Never contact the hosts of these prefixes (one per line)
+  --max-queries <n>     Stop the crawl after sending n queries
+  --max-queries-per-node <n>
+                        Send at most n queries per host and per day (24 hours
+                        counted from the start of the crawl)
+  --max-bandwidth <bytes/s>
+                        Cap the outgoing traffic
+  --family-shares <v4:v6>
+                        Split the bandwidth cap into an IPv4 and an IPv6 budget with
+                        these nonzero weights (e.g. 3:1), so that IPv6 is not crowded
+                        out
+  --max-in-flight <n>   Keep at most n queries waiting for an answer
+  --max-in-flight-per-node <n>
+                        Keep at most n queries waiting for an answer from a node
+  --duration <seconds>  Stop the crawl after this time
+  --max-nodes <n>       Stop the crawl once n distinct nodes were seen
+  --idle-timeout <seconds>
+                        Stop the crawl once no contact is left to ping and no node was
+                        discovered for this time
+  --receive-queue <n>   Read the sockets from a dedicated thread, queuing up to n
+                        datagrams for the crawler
+  --receive-queue-memory <bytes>
+                        Also cap the memory of the datagrams in the receive queue
+                        (requires --receive-queue)
+  --sink-queue <n>      Write the node list from a dedicated thread, queuing up to n
+                        events
+  --queue-policy <block|drop-newest|drop-oldest>
+                        What a full queue does with new items (default: block)
+  --record <path>       Record the traffic of the crawl to this wiretap file
+  --replay <path>       Dry run: replay the traffic recorded in this wiretap file
+                        instead of using the network
+  --replay-speed <recorded|fast>
+                        Pace of the replay (default: recorded)
+  --admin <ip:port>     Serve the admin HTTP API (snapshot, routing table, pause/resume,
+                        lookups) on this address. It has no authentication: keep it on a
+                        loopback or private address
+  --probe <nodes/s>     Probe the discovered nodes, at this rate, for the DHT extensions
+                        they support (BEP 51 sample_infohashes, BEP 33 scrape, BEP 44
+                        storage), and report their adoption
+  --spoof-bits <n>      Count the node ids sharing at least n leading bits with the target
+                        of the query as spoofed (default: 40)
+  --spoof-score <n>     Flag the nodes that returned n spoofed node ids (default: 8)
+  --drop-spoofed        Drop the nodes returned by the flagged nodes, instead of only
+                        counting them
+  --min-peer-reporters <n>
+                        Only report the peers of an info hash returned by n distinct
+                        nodes (at most 16), or that a handshake succeeded with (default: 1)
+  --summary <path>      On exit, write a JSON summary of the crawl (traffic, unique nodes
+                        and info hashes, records, errors by category) to this file, or to
+                        the standard output for -
+  --watchdog <seconds>  Exit with status 1 when the crawler loop, the receive thread or
+                        the node list writer makes no progress for this time, e.g. to be
+                        restarted by a supervisor
+  --seed <n>            Seed of the random choices (transaction ids), to reproduce a
+                        crawl
+  --duplicates <ignore|report|refine-rtt>
+                        What to do with the second replies to a query: drop them,
+                        report them, or also take the round-trip time of the first
+                        reply as one more sample (default: ignore)
+  --self-test           Check how the DHT reaches this node (NAT detection), then
+                        exit without crawling
+  -h, --help            Print this help";
 
-impl<'a> TryFrom<&'a [u8]> for BittorrentNodeId {
-    type Error = &'static str;
+/// Set by the SIGHUP handler, the options are read again on the next progress report.
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set by the SIGTERM and SIGINT handler, the crawl stops on the next progress report.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
 
-    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
-        if value.len() != 20 {
-            return Err("Invalid length for BittorrentNodeId");
-        }
-        let mut node_id = [0u8; 20];
-        node_id.copy_from_slice(value);
-        Ok(BittorrentNodeId(node_id))
-    }
+/// Command line options.
+struct Options {
+    bind: SocketAddr,
+    interface: Option<String>,
+    sockets: usize,
+    dscp: Option<u8>,
+    ttl: Option<u8>,
+    node_list: PathBuf,
+    node_list_given: bool,
+    convert_node_list: Option<PathBuf>,
+    import_states: Vec<PathBuf>,
+    malformed_dump: Option<PathBuf>,
+    bootstrap_history: Option<PathBuf>,
+    identity_history: Option<PathBuf>,
+    overlay: Option<String>,
+    overlay_bootstrap: Vec<String>,
+    overlay_secret: Option<OverlaySecret>,
+    stats_db: Option<PathBuf>,
+    limits: TrafficLimits,
+    duration: Option<Duration>,
+    max_nodes: Option<usize>,
+    idle_timeout: Option<Duration>,
+    receive_queue: Option<usize>,
+    receive_queue_memory: Option<usize>,
+    sink_queue: Option<usize>,
+    queue_policy: OverflowPolicy,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    replay_speed: ReplaySpeed,
+    seed: Option<u64>,
+    duplicates: DuplicatePolicy,
+    admin: Option<SocketAddr>,
+    probe: Option<f64>,
+    spoofing: SpoofingConfig,
+    peer_confidence: PeerConfidenceConfig,
+    summary: Option<PathBuf>,
+    watchdog: Option<Duration>,
+    self_test: bool,
 }
 
-impl Into<Vec<u8>> for BittorrentNodeId {
-    fn into(self) -> Vec<u8> {
-        self.0.to_vec()
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<Options> {
+        let mut options = Options {
+            bind: DEFAULT_BIND.parse().expect("invalid default bind address"),
+            interface: None,
+            sockets: 1,
+            dscp: None,
+            ttl: None,
+            node_list: DEFAULT_NODE_LIST.into(),
+            node_list_given: false,
+            convert_node_list: None,
+            import_states: Vec::new(),
+            malformed_dump: None,
+            bootstrap_history: None,
+            identity_history: None,
+            overlay: None,
+            overlay_bootstrap: Vec::new(),
+            overlay_secret: None,
+            stats_db: None,
+            limits: TrafficLimits::default(),
+            duration: None,
+            max_nodes: None,
+            idle_timeout: None,
+            receive_queue: None,
+            receive_queue_memory: None,
+            sink_queue: None,
+            queue_policy: OverflowPolicy::default(),
+            record: None,
+            replay: None,
+            replay_speed: ReplaySpeed::default(),
+            seed: None,
+            duplicates: DuplicatePolicy::default(),
+            admin: None,
+            probe: None,
+            spoofing: SpoofingConfig::default(),
+            peer_confidence: PeerConfidenceConfig::default(),
+            summary: None,
+            watchdog: None,
+            self_test: false,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind" => {
+                    let value = args.next().context("--bind requires a value")?;
+                    options.bind = value
+                        .parse()
+                        .with_context(|| format!("invalid bind address {:?}", value))?;
+                }
+                "--interface" => {
+                    options.interface = Some(args.next().context("--interface requires a value")?);
+                }
+                "--sockets" => {
+                    options.sockets = parse_value(&arg, args.next())?;
+                    if options.sockets == 0 {
+                        bail!("--sockets must be at least 1");
+                    }
+                }
+                "--dscp" => {
+                    let dscp = parse_value(&arg, args.next())?;
+                    if dscp > MAX_DSCP {
+                        bail!("--dscp must be at most {}", MAX_DSCP);
+                    }
+                    options.dscp = Some(dscp);
+                }
+                "--ttl" => {
+                    let ttl = parse_value(&arg, args.next())?;
+                    if ttl == 0 {
+                        bail!("--ttl must be at least 1");
+                    }
+                    options.ttl = Some(ttl);
+                }
+                "--node-list" => {
+                    options.node_list = args.next().context("--node-list requires a value")?.into();
+                    options.node_list_given = true;
+                }
+                "--convert-node-list" => {
+                    options.convert_node_list = Some(
+                        args.next()
+                            .context("--convert-node-list requires a value")?
+                            .into(),
+                    );
+                }
+                "--import-state" => {
+                    options.import_states.push(
+                        args.next()
+                            .context("--import-state requires a value")?
+                            .into(),
+                    );
+                }
+                "--malformed-dump" => {
+                    options.malformed_dump = Some(
+                        args.next()
+                            .context("--malformed-dump requires a value")?
+                            .into(),
+                    );
+                }
+                "--bootstrap-history" => {
+                    options.bootstrap_history = Some(
+                        args.next()
+                            .context("--bootstrap-history requires a value")?
+                            .into(),
+                    );
+                }
+                "--identity-history" => {
+                    options.identity_history = Some(
+                        args.next()
+                            .context("--identity-history requires a value")?
+                            .into(),
+                    );
+                }
+                "--overlay" => {
+                    options.overlay = Some(args.next().context("--overlay requires a value")?);
+                }
+                "--overlay-bootstrap" => {
+                    options.overlay_bootstrap.push(
+                        args.next()
+                            .context("--overlay-bootstrap requires a value")?,
+                    );
+                }
+                "--overlay-secret-file" => {
+                    let path = args
+                        .next()
+                        .context("--overlay-secret-file requires a value")?;
+                    let secret = fs::read_to_string(&path)
+                        .with_context(|| format!("failed to read the overlay secret {:?}", path))?;
+                    options.overlay_secret = Some(secret.trim_end_matches(['\r', '\n']).into());
+                }
+                "--stats-db" => {
+                    options.stats_db =
+                        Some(args.next().context("--stats-db requires a value")?.into());
+                }
+                "--opt-out" => {
+                    let path = args.next().context("--opt-out requires a value")?;
+                    options.limits.opt_out = OptOutList::read(&path)
+                        .with_context(|| format!("failed to read the opt-out list {:?}", path))?;
+                }
+                "--max-queries" => {
+                    options.limits.max_queries = Some(parse_value(&arg, args.next())?);
+                }
+                "--max-queries-per-node" => {
+                    options.limits.max_queries_per_node_per_day =
+                        Some(parse_value(&arg, args.next())?);
+                }
+                "--max-bandwidth" => {
+                    options.limits.max_bytes_per_second = Some(parse_value(&arg, args.next())?);
+                }
+                "--family-shares" => {
+                    options.limits.family_shares = Some(parse_value(&arg, args.next())?);
+                }
+                "--max-in-flight" => {
+                    options.limits.max_in_flight = Some(parse_value(&arg, args.next())?);
+                }
+                "--max-in-flight-per-node" => {
+                    options.limits.max_in_flight_per_destination =
+                        Some(parse_value(&arg, args.next())?);
+                }
+                "--duration" => {
+                    options.duration = Some(Duration::from_secs(parse_value(&arg, args.next())?));
+                }
+                "--max-nodes" => {
+                    options.max_nodes = Some(parse_value(&arg, args.next())?);
+                }
+                "--idle-timeout" => {
+                    let seconds = parse_value(&arg, args.next())?;
+                    options.idle_timeout = Some(Duration::from_secs(seconds));
+                }
+                "--receive-queue" => {
+                    options.receive_queue = Some(parse_value(&arg, args.next())?);
+                }
+                "--receive-queue-memory" => {
+                    options.receive_queue_memory = Some(parse_value(&arg, args.next())?);
+                }
+                "--sink-queue" => {
+                    options.sink_queue = Some(parse_value(&arg, args.next())?);
+                }
+                "--queue-policy" => {
+                    options.queue_policy = parse_value(&arg, args.next())?;
+                }
+                "--record" => {
+                    options.record = Some(args.next().context("--record requires a value")?.into());
+                }
+                "--replay" => {
+                    options.replay = Some(args.next().context("--replay requires a value")?.into());
+                }
+                "--replay-speed" => {
+                    options.replay_speed = parse_value(&arg, args.next())?;
+                }
+                "--seed" => {
+                    options.seed = Some(parse_value(&arg, args.next())?);
+                }
+                "--duplicates" => {
+                    options.duplicates = parse_value(&arg, args.next())?;
+                }
+                "--admin" => {
+                    options.admin = Some(parse_value(&arg, args.next())?);
+                }
+                "--probe" => {
+                    let rate: f64 = parse_value(&arg, args.next())?;
+                    if !rate.is_finite() || rate <= 0.0 {
+                        bail!("--probe must be greater than 0");
+                    }
+                    options.probe = Some(rate);
+                }
+                "--spoof-bits" => {
+                    let bits = parse_value(&arg, args.next())?;
+                    if !(1..=160).contains(&bits) {
+                        bail!("--spoof-bits must be between 1 and 160");
+                    }
+                    options.spoofing.min_shared_bits = bits;
+                }
+                "--spoof-score" => {
+                    let score = parse_value(&arg, args.next())?;
+                    if score == 0 {
+                        bail!("--spoof-score must be at least 1");
+                    }
+                    options.spoofing.flag_score = score;
+                }
+                "--drop-spoofed" => options.spoofing.drop_flagged = true,
+                "--min-peer-reporters" => {
+                    let reporters = parse_value(&arg, args.next())?;
+                    if !(1..=MAX_PEER_REPORTERS).contains(&reporters) {
+                        bail!(
+                            "--min-peer-reporters must be between 1 and {}",
+                            MAX_PEER_REPORTERS
+                        );
+                    }
+                    options.peer_confidence.min_reporters = reporters;
+                }
+                "--summary" => {
+                    options.summary =
+                        Some(args.next().context("--summary requires a value")?.into());
+                }
+                "--watchdog" => {
+                    let seconds: u64 = parse_value(&arg, args.next())?;
+                    if seconds == 0 {
+                        bail!("--watchdog must be at least 1");
+                    }
+                    options.watchdog = Some(Duration::from_secs(seconds));
+                }
+                "--self-test" => options.self_test = true,
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ => bail!("unknown argument {:?}\n\n{}", arg, USAGE),
+            }
+        }
+        if options.receive_queue_memory.is_some() && options.receive_queue.is_none() {
+            bail!("--receive-queue-memory requires --receive-queue");
+        }
+        if options.overlay.is_none()
+            && (!options.overlay_bootstrap.is_empty() || options.overlay_secret.is_some())
+        {
+            bail!("--overlay-bootstrap and --overlay-secret-file require --overlay");
+        }
+        if options.overlay.is_some() {
+            if options.overlay_bootstrap.is_empty() {
+                bail!("--overlay requires --overlay-bootstrap");
+            }
+            // The nodes of the public DHT would leak into the overlay.
+            if !options.node_list_given {
+                bail!("--overlay requires a --node-list of its own");
+            }
+            if !options.import_states.is_empty() {
+                bail!("--import-state cannot be used with --overlay");
+            }
+        }
+        Ok(options)
     }
-}
 
-impl Display for BittorrentNodeId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // Convert the node ID to a hexadecimal string
-        let hex_string = self
-            .0
-            .iter()
-            .map(|byte| format!("{:02x}", byte))
-            .collect::<String>();
-        // Write the hexadecimal string to the formatter
-        write!(f, "{}", hex_string)
+    /// Socket settings of the node.
+    fn socket_config(&self) -> SocketConfig {
+        let mut config = SocketConfig::new(self.bind);
+        config.device = self.interface.clone();
+        config.sockets = self.sockets;
+        config.dscp = self.dscp;
+        config.ttl = self.ttl;
+        config
     }
-}
-
-impl NodeId for BittorrentNodeId {}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct BittorrentNodeInfoV4 {
-    pub node_id: BittorrentNodeId,
-    pub address: IPv4Address,
-}
+    /// Settings of the crawler.
+    fn crawler_config(&self) -> CrawlerConfig {
+        let mut config = CrawlerConfig::new(NODE_ID);
+        config.node.socket = self.socket_config();
+        config.malformed_dump = self.malformed_dump.clone();
+        config.bootstrap.history = self.bootstrap_history.clone();
+        config.identities.history = self.identity_history.clone();
+        config.overlay = self.overlay.as_ref().map(|name| OverlayConfig {
+            name: name.clone(),
+            bootstrap_nodes: self.overlay_bootstrap.clone(),
+            secret: self.overlay_secret.clone(),
+        });
+        config.node.limits = self.limits.clone();
+        config.max_duration = self.duration;
+        config.max_nodes = self.max_nodes;
+        config.idle_timeout = self.idle_timeout;
+        config.node.receive_queue = self.receive_queue.map(|capacity| {
+            let queue = QueueConfig::new(capacity, self.queue_policy);
+            match self.receive_queue_memory {
+                Some(bytes) => queue.with_memory_budget(bytes),
+                None => queue,
+            }
+        });
+        config.node.wiretap = self.record.clone();
+        config.node.replay = self.replay.clone().map(|path| ReplayConfig {
+            path,
+            speed: self.replay_speed,
+        });
+        config.node.seed = self.seed;
+        config.node.duplicates = self.duplicates;
+        config.spoofing = self.spoofing.clone();
+        config.peer_confidence = self.peer_confidence.clone();
+        config.probe = self.probe.map(|rate| ProbeConfig {
+            rate,
+            ..ProbeConfig::default()
+        });
+        config
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct IPv4Address {
-    pub ip: [u8; 4],
-    pub port: u16,
+    /// Open the node list the discovered nodes are written to, behind a queue if asked to.
+    fn node_list_sink(&self) -> anyhow::Result<(Box<dyn Sink>, Vec<SocketAddr>)> {
+        let domain = self.overlay.as_deref().map(Domain::named);
+        let node_list = NodeArchiveSink::open_in(&self.node_list, domain)
+            .context("failed to open the node list")?;
+        let contacts = node_list.archive().addresses();
+        let sink: Box<dyn Sink> = match self.sink_queue {
+            Some(capacity) => Box::new(
+                QueuedSink::spawn(node_list, QueueConfig::new(capacity, self.queue_policy))
+                    .context("failed to start the sink thread")?,
+            ),
+            None => Box::new(node_list),
+        };
+        Ok((sink, contacts))
+    }
 }
 
-impl TryFrom<&str> for IPv4Address {
-    type Error = &'static str;
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let parts: Vec<&str> = value.split(':').collect();
-        if parts.len() != 2 {
-            return Err("Invalid IPv4 address format");
+/// Replace the `--config <path>` options by the options of their file.
+fn expand_config_files(args: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut expanded = Vec::with_capacity(args.len());
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--config" {
+            expanded.push(arg.clone());
+            continue;
         }
-        let ip_parts: Vec<u8> = parts[0]
-            .split('.')
-            .map(|s| s.parse::<u8>().unwrap_or(0))
-            .collect();
-        if ip_parts.len() != 4 {
-            return Err("Invalid IPv4 address format");
+        let path = args.next().context("--config requires a value")?;
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read the configuration file {:?}", path))?;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (line, None),
+            };
+            if name == "config" {
+                bail!(
+                    "{}:{}: configuration files cannot be nested",
+                    path,
+                    number + 1
+                );
+            }
+            expanded.push(format!("--{}", name));
+            expanded.extend(value.map(str::to_string));
         }
-        let port = parts[1].parse::<u16>().unwrap_or(0);
-        Ok(IPv4Address {
-            ip: [ip_parts[0], ip_parts[1], ip_parts[2], ip_parts[3]],
-            port,
-        })
     }
+    Ok(expanded)
 }
 
-impl CompactPeerInfo for IPv4Address {
-    type Error = &'static str;
+/// Read the options again and apply them to the running crawl, printing what changed.
+///
+/// `options` are the options in effect, updated with the node list settings if the node list
+/// is replaced.
+fn reload(
+    args: &[String],
+    options: &mut Options,
+    handle: &CrawlerHandle,
+    watchdog: Option<&Watchdog>,
+) -> anyhow::Result<()> {
+    let reloaded = Options::parse(expand_config_files(args)?.into_iter())?;
+    let config = reloaded.crawler_config();
+    let ignored: Vec<_> = handle
+        .config()
+        .diff(&config)
+        .into_iter()
+        .map(|change| change.field)
+        .collect();
+    let changes = handle.reload(config)?;
+    for change in &changes {
+        println!("Reloaded {}", change);
+    }
+    let ignored: Vec<_> = ignored
+        .into_iter()
+        .filter(|field| changes.iter().all(|change| change.field != *field))
+        .collect();
+    if !ignored.is_empty() {
+        println!("Not reloaded, restart to apply: {}", ignored.join(", "));
+    }
 
-    fn try_read_compact_peer_info(data: &[u8]) -> Result<(usize, Self), Self::Error> {
-        if data.len() < 6 {
-            return Err("Invalid length for compact peer info");
+    // The sink of the node list is kept while it writes to the same file: another one would
+    // rewrite the records the current one has not flushed yet.
+    if reloaded.node_list == options.node_list {
+        if reloaded.sink_queue != options.sink_queue
+            || reloaded.queue_policy != options.queue_policy
+        {
+            println!("Not reloaded, restart to apply: sink-queue, queue-policy");
         }
-        let ip = [data[0], data[1], data[2], data[3]];
-        let port = u16::from_be_bytes([data[4], data[5]]);
-        Ok((6, IPv4Address { ip, port }))
+        return Ok(());
     }
+    println!("Writing the discovered nodes to {:?}", reloaded.node_list);
+    options.node_list = reloaded.node_list.clone();
+    options.sink_queue = reloaded.sink_queue;
+    options.queue_policy = reloaded.queue_policy;
+    // Opened once the current sink is flushed and closed, and watched as the previous one.
+    let watchdog = watchdog.cloned();
+    handle.replace_sinks_with(move || {
+        let (sink, _) = reloaded
+            .node_list_sink()
+            .map_err(|e| io::Error::other(format!("{:#}", e)))?;
+        if let (Some(watchdog), Some(heartbeat)) = (&watchdog, sink.heartbeat()) {
+            watchdog.watch(heartbeat);
+        }
+        Ok(vec![sink])
+    });
+    Ok(())
+}
 
-    fn write_compact_peer_info(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(6);
-        data.extend_from_slice(&self.ip);
-        data.extend_from_slice(&self.port.to_be_bytes());
-        data
+/// Request a reload of the options on SIGHUP, see [`RELOAD_REQUESTED`].
+#[cfg(unix)]
+fn handle_sighup() {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+    }
+    let handler: extern "C" fn(libc::c_int) = on_sighup;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
     }
 }
 
-impl node_info::NodeInfo for BittorrentNodeInfoV4 {
-    type NodeId = BittorrentNodeId;
-    type Address = IPv4Address;
+#[cfg(not(unix))]
+fn handle_sighup() {}
 
-    fn get_node_id(&self) -> &Self::NodeId {
-        &self.node_id
-    }
-
-    fn to_address(&self) -> Self::Address {
-        IPv4Address {
-            ip: self.address.ip,
-            port: self.address.port,
+/// Stop the crawl on SIGTERM and SIGINT, see [`STOP_REQUESTED`].
+#[cfg(unix)]
+fn handle_termination() {
+    extern "C" fn on_termination(signal: libc::c_int) {
+        STOP_REQUESTED.store(true, Ordering::Relaxed);
+        // SAFETY: signal(2) is async-signal-safe. The next signal kills the process, in case the
+        // crawl does not stop.
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
         }
     }
-
-    fn new_with_address(node_id: Self::NodeId, address: Self::Address) -> Self {
-        BittorrentNodeInfoV4 { node_id, address }
+    let handler: extern "C" fn(libc::c_int) = on_termination;
+    // SAFETY: the handler only stores to an atomic and restores the default action, both
+    // async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
     }
 }
 
-impl node_info::CompactNodeInfo for BittorrentNodeInfoV4 {
-    type Error = &'static str;
+#[cfg(not(unix))]
+fn handle_termination() {}
 
-    fn try_read_compact_node_info(data: &[u8]) -> Result<(usize, Self), Self::Error> {
-        if data.len() < 26 {
-            return Err("Invalid length for compact node info");
-        }
-        let mut node_id = [0u8; 20];
-        node_id.copy_from_slice(&data[0..20]);
-        let ip = [data[20], data[21], data[22], data[23]];
-        let port = u16::from_be_bytes([data[24], data[25]]);
-        Ok((
-            26,
-            BittorrentNodeInfoV4 {
-                node_id: BittorrentNodeId(node_id),
-                address: IPv4Address { ip, port },
-            },
-        ))
-    }
-
-    fn write_compact_node_info(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(26);
-        data.extend_from_slice(&self.node_id.0);
-        data.extend_from_slice(&self.address.ip);
-        data.extend_from_slice(&self.address.port.to_be_bytes());
-        data
+/// Write the summary of the crawl as JSON to `path`, or to the standard output for `-`.
+fn write_summary(path: &Path, summary: &CrawlSummary) -> anyhow::Result<()> {
+    let json = serde_json::to_string(summary).context("failed to serialize the summary")?;
+    if path == Path::new("-") {
+        println!("{}", json);
+        return Ok(());
     }
+    fs::write(path, json + "\n")
+        .with_context(|| format!("failed to write the summary to {:?}", path))
 }
 
-fn main() {
-    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DHT_PORT)).unwrap();
-    println!("Listening on {:?}", socket.local_addr().unwrap());
-    socket.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
-
-    let reference_zero = Instant::now();
-    let lookup_hash = BittorrentNodeId([
-        0x00, 0xab, 0xb5, 0xd1, 0x2f, 0xb0, 0x3c, 0x7e, 0xe2, 0x88, 0x76, 0x78, 0x9c, 0x43, 0xeb, 0xe2, 0x6d, 0x36, 0xe0, 0xa1
-    ]);
+/// Parse the value of a numeric option.
+fn parse_value<T: FromStr>(option: &str, value: Option<String>) -> anyhow::Result<T> {
+    let value = value.with_context(|| format!("{} requires a value", option))?;
+    value
+        .parse()
+        .map_err(|_| anyhow!("invalid value {:?} for {}", value, option))
+}
 
+/// Ask known nodes for our external address, and report how the DHT reaches this node.
+fn self_test(options: Options) -> anyhow::Result<()> {
+    let mut config = NodeConfig::new(NODE_ID);
+    config.socket = options.socket_config();
+    config.limits = options.limits;
+    config.seed = options.seed;
+    let mut node = DhtNode::bind(config).context("failed to start the node")?;
 
-    let mut contacts: Vec<IPv4Address> = Vec::new();
-    let mut seen = HashSet::new();
-    let mut sent = Instant::now();
-    let mut buf = [0; 1024];
+    let mut contacts = NodeArchive::read(&options.node_list)
+        .context("failed to read the node list")?
+        .addresses();
+    contacts.extend(
+        DEFAULT_BOOTSTRAP_NODES
+            .iter()
+            .filter_map(|node| node.to_socket_addrs().ok())
+            .flatten(),
+    );
+    let config = ReachabilityConfig::default();
+    println!(
+        "Probing {} nodes, then listening for {}s...",
+        contacts.len().min(config.max_probes),
+        config.listen_duration.as_secs()
+    );
+    let report = reachability_test(&mut node, &contacts, &config).context("self-test failed")?;
 
-    // Load previously discovered nodes from the file
-    if let Ok(node_list_file) = File::open("/tmp/node_list.txt") {
-        let reader = BufReader::new(&node_list_file);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                if let Ok(contact) = IPv4Address::try_from(line.as_str()) {
-                    contacts.push(contact);
-                }
-            }
-        }
-        println!("Loaded {} nodes from file", contacts.len());
+    println!(
+        "Local address: {}, {} of {} nodes answered",
+        report.local_address, report.answered, report.probed
+    );
+    for (address, count) in &report.external_addresses {
+        println!("External address {} (reported by {} nodes)", address, count);
+    }
+    if let Some(preserved) = report.port_preserved() {
+        println!("Local port preserved: {}", preserved);
     }
-    // Open and truncate the file for writing
-    let mut node_list_file = File::create("/tmp/node_list.txt").unwrap();
+    println!(
+        "Unsolicited queries from {} hosts",
+        report.unsolicited_sources
+    );
+    println!(
+        "NAT: {:?}, responder mode {}",
+        report.nat,
+        if report.responder_worthwhile() {
+            "is worthwhile"
+        } else {
+            "is unlikely to be reached"
+        }
+    );
+    Ok(())
+}
 
+/// Add the nodes of a text node list to the node archive.
+fn convert_node_list(text: &Path, archive: &Path) -> anyhow::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let converted =
+        NodeArchive::from_node_list(text, now).context("failed to read the text node list")?;
+    let mut nodes = NodeArchive::read(archive).context("failed to read the node list")?;
+    let added = converted
+        .records()
+        .iter()
+        .filter(|&&record| nodes.insert(record))
+        .count();
+    nodes
+        .write(archive)
+        .context("failed to write the node list")?;
+    println!(
+        "Converted {} nodes ({} new), the archive holds {} nodes",
+        converted.len(),
+        added,
+        nodes.len()
+    );
+    Ok(())
+}
 
-    loop {
-        if let Ok((size, src)) = socket.recv_from(&mut buf) {
-            let data = &buf[..size];
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = Options::parse(expand_config_files(&args)?.into_iter())?;
+    if options.self_test {
+        return self_test(options);
+    }
+    if let Some(path) = &options.convert_node_list {
+        return convert_node_list(path, &options.node_list);
+    }
 
-            if let Ok((_, response)) = bencode::decode(&data) {
-                let response__ = match Response::<BittorrentNodeInfoV4, IPv4Address>::try_guess_type_from_bencoded(&response) {
-                    Ok((query_type, _)) => match query_type {
-                        QUERY_TYPE_PING => {
-                            Response::try_from_ping_bencoded(&response).unwrap()
-                        }
-                        QUERY_TYPE_GET_PEERS | QUERY_TYPE_FIND_NODE => {
-                            Response::try_from_getpeers_bencoded(&response).unwrap()
-                        }
-                        _ => continue,
-                    },
-                    Err(_) => {
-                        println!("Failed to decode response");
-                        continue;
-                    }
-                };
+    let config = options.crawler_config();
+    config.validate()?;
+    if let Some(overlay) = &config.overlay {
+        println!(
+            "Crawling the private overlay {:?} from {} bootstrap nodes ({})",
+            overlay.name,
+            overlay.bootstrap_nodes.len(),
+            if overlay.secret.is_some() {
+                "shared secret"
+            } else {
+                "no shared secret"
+            }
+        );
+    }
+    let mut crawler = Crawler::bind(config).context("failed to start the crawler")?;
+    let socket_report = crawler.socket_report();
+    println!(
+        "Listening on {:?} (recv buffer: {} bytes, send buffer: {} bytes, options: {:?})",
+        socket_report.local_address,
+        socket_report.recv_buffer_size,
+        socket_report.send_buffer_size,
+        socket_report.platform_options
+    );
+    let reports = crawler.socket_reports();
+    if reports.len() > 1 {
+        let addresses: Vec<SocketAddr> =
+            reports.iter().map(|report| report.local_address).collect();
+        println!(
+            "Spreading queries over {} sockets: {:?}",
+            addresses.len(),
+            addresses
+        );
+    }
 
-                match response__.get_response_type() {
-                    ResponseType::Ping(ping) => {
-                        let tid = response__.get_transaction_id();
-                        let node_id: &BittorrentNodeId = ping.get_id();
-                        let sent_time_ns = String::try_from(tid.to_owned())
-                            .unwrap()
-                            .parse::<u128>()
-                            .unwrap();
-                        let received_time_ns = reference_zero.elapsed().as_nanos();
-                        let round_trip_time = (received_time_ns - sent_time_ns) as f64 / 1000000.0;
-                        /*println!(
-                            "Ping response from {}/{:?}: RTT = {} ms",
-                            node_id, src, round_trip_time
-                        );*/
+    // The nodes of the previous runs are kept, the archive is updated with this run.
+    let (node_list, contacts) = options.node_list_sink()?;
+    println!("Loaded {} nodes from file", contacts.len());
+    crawler.add_contacts(contacts);
+    for path in &options.import_states {
+        let state = import_dht_state(path, None)
+            .with_context(|| format!("failed to import the DHT state {:?}", path))?;
+        println!(
+            "Imported {} nodes from the {} DHT state {:?} ({} invalid entries skipped)",
+            state.nodes.len(),
+            state.format,
+            path,
+            state.skipped
+        );
+        crawler.add_contacts(state.nodes);
+    }
+    crawler.add_sink(node_list);
 
-                        seen.insert(node_id.clone());
+    let mut stats = match &options.stats_db {
+        Some(path) => Some(
+            StatsDatabase::open(path, NODE_ID).context("failed to open the statistics database")?,
+        ),
+        None => None,
+    };
 
-                        // Node is available, asked for other nodes for lookup_hash
-                        let lookup_query = Query::new_get_peers(
-                            received_time_ns.to_string(),
-                            NODE_ID,
-                            lookup_hash.clone(),
-                        );
-                        let lookup_bencoded = bencode::encode(&lookup_query.to_bencoded());
-                        socket.send_to(&lookup_bencoded, src).unwrap();
-                        //println!("Sent lookup query to {:?}", src);
-                    }
-                    ResponseType::GetPeers(getpeers) => {
-                        let tid = response__.get_transaction_id();
-                        let node_id = getpeers.get_id();
-                        let peers: &[IPv4Address] = getpeers.get_peers();
-                        let nodes: &[BittorrentNodeInfoV4] = getpeers.get_nodes();
-                        println!(
-                            "GetPeers response from {}/{:?}: {} peers, {} nodes",
-                            node_id,
-                            src,
-                            peers.len(),
-                            nodes.len()
-                        );
-                        for node in nodes {
-                            if (node.node_id != NODE_ID) && (&node.node_id != node_id) {
-                                if seen.insert(node.node_id.clone()) {
-                                    contacts.push(IPv4Address {
-                                        ip: node.address.ip,
-                                        port: node.address.port,
-                                    });
-                                    node_list_file
-                                        .write_all(
-                                            format!(
-                                                "{}.{}.{}.{}:{}\n",
-                                                node.address.ip[0],
-                                                node.address.ip[1],
-                                                node.address.ip[2],
-                                                node.address.ip[3],
-                                                node.address.port
-                                            )
-                                            .as_bytes(),
-                                        )
-                                        .unwrap();
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        continue;
-                    }
-                }
-            }
+    let watchdog = options.watchdog.map(|stall_timeout| {
+        Watchdog::new(WatchdogConfig {
+            stall_timeout,
+            ..WatchdogConfig::default()
+        })
+    });
+    if let Some(watchdog) = &watchdog {
+        for heartbeat in crawler.heartbeats() {
+            watchdog.watch(heartbeat);
         }
+        watchdog
+            .spawn(|stall| {
+                eprintln!(
+                    "watchdog: {} made no progress for {:.1}s, exiting",
+                    stall.name,
+                    stall.silent_for.as_secs_f64()
+                );
+                process::exit(1);
+            })
+            .context("failed to start the watchdog")?;
+    }
 
-        if sent.elapsed().as_secs() > 2 {
-            sent = Instant::now();
-            let current_time = sent.duration_since(reference_zero).as_nanos();
-            let ping_query = Query::new_ping(current_time.to_string(), NODE_ID);
-            let ping_bencoded = bencode::encode(&ping_query.to_bencoded());
-            if contacts.is_empty() {
-                socket.send_to(&ping_bencoded, DHT_BOOTSTRAP).unwrap();
-                println!("Sent ping to {:?}", DHT_BOOTSTRAP);
-            } else {
-                let mut i = 0;
-                while let Some(contact) = contacts.pop() {
-                    let addr = format!(
-                        "{}.{}.{}.{}",
-                        contact.ip[0], contact.ip[1], contact.ip[2], contact.ip[3]
-                    );
-                    let port = contact.port;
-                    socket.send_to(&ping_bencoded, (addr.as_str(), port)).unwrap();
-                    i+=1;
-                    if i >= 40 {
-                        break;
-                    }
-                }
-                println!("Sent ping to {} nodes", i);
+    handle_sighup();
+    handle_termination();
+    let (handle, crawler) = crawler.spawn();
+    if let Some(address) = options.admin {
+        let server = AdminServer::bind(address, handle.clone())
+            .context("failed to start the admin server")?;
+        println!("Admin API on http://{}", server.local_addr()?);
+        server.spawn();
+    }
+    while !crawler.is_finished() {
+        sleep(Duration::from_secs(2));
+        if STOP_REQUESTED.swap(false, Ordering::Relaxed) {
+            println!("Stopping the crawl");
+            handle.stop();
+        }
+        if RELOAD_REQUESTED.swap(false, Ordering::Relaxed)
+            && let Err(e) = reload(&args, &mut options, &handle, watchdog.as_ref())
+        {
+            println!("Reload failed, the current options are kept: {:#}", e);
+        }
+        let snapshot = handle.snapshot();
+        if let Some(stats) = &mut stats
+            && let Err(e) = stats.record(&snapshot, SystemTime::now())
+        {
+            println!("Failed to record the statistics: {}", e);
+        }
+        println!(
+            "Discovered {} nodes (waiting contact: {}, in flight: {}, {:.1} queries/s, {:.1} responses/s)",
+            snapshot.nodes_seen,
+            snapshot.frontier_depth,
+            snapshot.in_flight_queries,
+            snapshot.rates.queries_sent,
+            snapshot.rates.responses_received
+        );
+        if snapshot.paused {
+            println!("Paused, no contact is pinged until resumed");
+        }
+        let seen = snapshot.nodes_seen_estimate;
+        if seen.upper > seen.counted as f64 {
+            println!(
+                "Seen-set over its memory budget: about {:.0} distinct nodes (at most {:.0})",
+                seen.estimate, seen.upper
+            );
+        }
+        let traffic = snapshot.traffic;
+        let refused = traffic.opted_out
+            + traffic.node_cap_reached
+            + traffic.bandwidth_exceeded
+            + traffic.query_cap_reached;
+        if refused > 0 {
+            println!(
+                "Limits: {} queries sent, {} refused (opt-out: {}, per node: {}, bandwidth: {}, total: {})",
+                traffic.queries_sent,
+                refused,
+                traffic.opted_out,
+                traffic.node_cap_reached,
+                traffic.bandwidth_exceeded,
+                traffic.query_cap_reached
+            );
+        }
+        if traffic.ipv6.datagrams_sent + traffic.ipv6.bandwidth_exceeded > 0 {
+            println!(
+                "Traffic: IPv4 {:.0} B/s ({} over budget), IPv6 {:.0} B/s ({} over budget)",
+                traffic.ipv4.throughput(snapshot.uptime),
+                traffic.ipv4.bandwidth_exceeded,
+                traffic.ipv6.throughput(snapshot.uptime),
+                traffic.ipv6.bandwidth_exceeded
+            );
+        }
+        let failures = snapshot.send_failures;
+        if failures.destination_failures + failures.transient_failures > 0 {
+            println!(
+                "Send failures: {} blamed on their destination, {} local, {} sends skipped while backing off",
+                failures.destination_failures, failures.transient_failures, failures.backed_off
+            );
+        }
+        let identities = snapshot.identities;
+        if identities.flagged_replies > 0 {
+            println!(
+                "Identities: {} id changes over {} endpoints, {} replies from endpoints changing ids too often",
+                identities.id_changes, identities.endpoints, identities.flagged_replies
+            );
+        }
+        let spoofing = snapshot.spoofing;
+        if spoofing.suspicious_replies > 0 {
+            println!(
+                "Spoofing: {}/{} replies with node ids next to their target ({} ids), {} senders scored, {} replies from flagged senders ({} dropped)",
+                spoofing.suspicious_replies,
+                spoofing.replies,
+                spoofing.close_nodes,
+                spoofing.senders,
+                spoofing.flagged_replies,
+                spoofing.dropped_replies
+            );
+        }
+        let peers = snapshot.peer_confidence;
+        if peers.suppressed > 0 {
+            println!(
+                "Peers: {}/{} returned peers held back for lack of corroboration ({} peers tracked, {} handshakes)",
+                peers.suppressed, peers.observed, peers.peers, peers.confirmed
+            );
+        }
+        let rewrites = snapshot.port_rewrites;
+        if rewrites.compared > 0 {
+            println!(
+                "Node addresses: {} comparisons over {} nodes, {:.1}% port rewrites, {} other addresses",
+                rewrites.compared,
+                rewrites.nodes,
+                rewrites.port_rewrite_ratio() * 100.0,
+                rewrites.other_addresses
+            );
+        }
+        let inbound = &snapshot.inbound_queries;
+        if let Some(busiest) = inbound.top.first() {
+            println!(
+                "Inbound queries: {:.1}/s from {} prefixes, busiest {} at {:.1}/s",
+                inbound.rate(&inbound.queries),
+                inbound.prefixes,
+                busiest.prefix,
+                inbound.rate(&busiest.queries)
+            );
+        }
+        if let Some(capabilities) = snapshot.capabilities
+            && capabilities.nodes_probed > 0
+        {
+            println!(
+                "Extensions: {} nodes probed, BEP 51 {:.1}%, BEP 33 {:.1}%, BEP 44 {:.1}% of the answers",
+                capabilities.nodes_probed,
+                capabilities.sample_infohashes.adoption() * 100.0,
+                capabilities.scrape.adoption() * 100.0,
+                capabilities.storage.adoption() * 100.0
+            );
+            let deviations = &capabilities.sample_deviations;
+            if deviations.nonconforming > 0 {
+                let kinds: Vec<String> = deviations
+                    .iter()
+                    .map(|(deviation, count)| format!("{} {}", deviation, count))
+                    .collect();
+                println!(
+                    "BEP 51 replies: {}/{} off the spec ({})",
+                    deviations.nonconforming,
+                    deviations.replies,
+                    kinds.join(", ")
+                );
             }
-            println!("Discovered {} nodes (waiting contact: {})", seen.len(), contacts.len());
         }
-        sleep(Duration::from_millis(100));
+        let queues = snapshot
+            .receive_queue
+            .iter()
+            .map(|stats| ("receive", stats))
+            .chain(snapshot.sink_queues.iter().map(|stats| ("sink", stats)));
+        for (name, stats) in queues {
+            println!(
+                "Queue {}: {}/{} (max {}), {} dropped, {} blocked",
+                name, stats.depth, stats.capacity, stats.max_depth, stats.dropped, stats.blocked
+            );
+        }
+        if let Some(dropped) = snapshot.dropped_datagrams {
+            println!(
+                "Socket: {} datagrams dropped, {} ICMP errors, {} malformed",
+                dropped, snapshot.icmp_errors, snapshot.malformed_datagrams
+            );
+        }
+        let unmatched = snapshot.unmatched_replies;
+        if unmatched.duplicates + unmatched.unsolicited > 0 {
+            println!(
+                "Unmatched replies: {} duplicates, {} unsolicited",
+                unmatched.duplicates, unmatched.unsolicited
+            );
+        }
+    }
+    // The counters of the last seconds of the crawl.
+    let result = crawler.join().expect("crawler thread panicked");
+    let snapshot = handle.snapshot();
+    if let Some(reason) = snapshot.stop_reason {
+        println!("Crawl stopped: {}", reason);
+    }
+    if let Some(stats) = &mut stats
+        && let Err(e) = stats.record(&snapshot, SystemTime::now())
+    {
+        println!("Failed to record the statistics: {}", e);
+    }
+    if let Some(path) = &options.summary {
+        write_summary(path, &CrawlSummary::from(&snapshot))?;
     }
+    result.context("crawler failed")
 }